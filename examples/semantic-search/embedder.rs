@@ -0,0 +1,54 @@
+// Turns text into vectors. Anything producing fixed-size vectors plugs in here, a model
+// server or a local network; the example ships a dependency-free one.
+pub trait Embedder {
+    fn dim(&self) -> usize;
+    fn embed(&self, text: &str) -> Vec<f64>;
+}
+
+// words too common to say anything about a text
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "how", "does", "with", "for", "until", "when", "should", "every", "after",
+    "before", "that", "this", "are", "can", "has", "have", "you", "your", "into", "its",
+    "while", "without", "such", "whether", "might", "keep", "find",
+];
+
+// Feature hashing over lowercased words and pairs of neighboring words: every token
+// adds +1 or -1 to the slot its hash picks. No training, but texts sharing words end up
+// close by cosine.
+pub struct HashingEmbedder {
+    dim: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dim: usize) -> HashingEmbedder {
+        HashingEmbedder { dim }
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn embed(&self, text: &str) -> Vec<f64> {
+        let words: Vec<String> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .map(str::to_lowercase)
+            .filter(|w| w.len() > 2 && !STOP_WORDS.contains(&w.as_str()))
+            // a crude stem, so "tires" and "tire" count as one word
+            .map(|w| w.strip_suffix('s').map(str::to_string).unwrap_or(w))
+            .collect();
+        let mut vector = vec![0.0; self.dim];
+        let bigrams = words.windows(2).map(|pair| format!("{} {}", pair[0], pair[1]));
+        for token in words.iter().cloned().chain(bigrams) {
+            let hash = fnv1a(token.as_bytes());
+            let sign = if hash & 1 == 0 { 1.0 } else { -1.0 };
+            vector[(hash >> 1) as usize % self.dim] += sign;
+        }
+        vector
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
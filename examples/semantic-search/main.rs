@@ -0,0 +1,142 @@
+// Semantic search over a small document corpus: documents are embedded, stored with
+// their title and topic as metadata, indexed with HNSW under the cosine metric, and
+// queried with and without a metadata filter. A snapshot shows reads pinned to a
+// point in time while writes go on.
+//
+//     cargo run --example semantic-search -- [data dir] [corpus dir]
+//
+// The corpus dir holds `.txt` files, one document each, titled by the file name.
+// Without one a built-in corpus is used.
+
+mod embedder;
+
+use embedder::{Embedder, HashingEmbedder};
+use lsm::db::batch::WriteBatch;
+use lsm::db::error::LsmError;
+use lsm::db::filter::Filter;
+use lsm::db::index::hnsw::HnswOptions;
+use lsm::db::lsm::LSMTree;
+use lsm::db::options::Options;
+use lsm::db::search::DistanceMetric;
+use lsm::db::vector::{MetadataValue, Vector};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CORPUS: &[(&str, &str, &str)] = &[
+    ("Sourdough starter", "cooking", "Feed the sourdough starter with flour and water every day until the starter doubles and smells sour."),
+    ("Roasting vegetables", "cooking", "Roast the vegetables in a hot oven with olive oil and salt until the edges brown."),
+    ("Knife skills", "cooking", "Keep the knife sharp and curl your fingers when you chop onions and vegetables."),
+    ("Bread crust", "cooking", "Bake the bread with steam in the oven for a crisp crust and an open crumb."),
+    ("Tuning a bicycle", "cycling", "Adjust the derailleur cable tension until the bicycle chain shifts cleanly across the gears."),
+    ("Fixing a flat tire", "cycling", "Remove the wheel, find the puncture in the inner tube, patch the tube and pump the tire."),
+    ("Climbing hills", "cycling", "Shift to an easy gear before the hill and keep a steady cadence while climbing."),
+    ("Compaction", "databases", "Compaction merges sorted tables so reads touch fewer files and deleted keys are dropped."),
+    ("Write ahead log", "databases", "A write ahead log records every change before it is applied so a crash can replay it."),
+    ("Vector indexes", "databases", "Approximate nearest neighbor indexes such as HNSW find similar vectors without a full scan."),
+    ("Bloom filters", "databases", "A bloom filter answers whether a key might be in a table, saving reads for missing keys."),
+    ("Watering tomatoes", "gardening", "Water tomato plants deeply at the roots in the morning and keep the leaves dry."),
+    ("Composting", "gardening", "Mix green kitchen scraps with brown leaves and turn the compost pile every week."),
+];
+
+// the hashing embedder matches words, not meaning, so the queries share some with
+// the documents they should find
+const QUERIES: &[&str] = &[
+    "how do I bake bread with a crisp crust",
+    "patch the puncture in my bicycle tire",
+    "replay the log after a crash",
+    "when should I water the tomato plants",
+];
+
+struct Document {
+    title: String,
+    topic: String,
+    text: String,
+}
+
+fn main() -> io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let directory = args.next().map(PathBuf::from).unwrap_or_else(|| std::env::temp_dir().join("lsm-semantic-search"));
+    let corpus = match args.next() {
+        Some(dir) => read_corpus(Path::new(&dir))?,
+        None => CORPUS.iter().map(|&(title, topic, text)| Document { title: title.to_string(), topic: topic.to_string(), text: text.to_string() }).collect(),
+    };
+
+    // start from an empty tree so the metric can be chosen
+    let _ = std::fs::remove_dir_all(&directory);
+    let embedder = HashingEmbedder::new(256);
+    let options = Options { hnsw: Some(HnswOptions::default()), ..Options::default() };
+    let lsm = LSMTree::open(&directory, options)?;
+    lsm.set_metric(DistanceMetric::Cosine)?;
+
+    ingest(&lsm, &embedder, &corpus)?;
+    println!("indexed {} documents as {}-dimensional vectors in {}", corpus.len(), embedder.dim(), directory.display());
+
+    for query in QUERIES {
+        println!("\n{}", query);
+        print_hits(&lsm, &lsm.search(&embedder.embed(query), 3, 64)?)?;
+    }
+
+    let query = "clean the vegetables and the bicycle chain";
+    println!("\n{} (cycling only)", query);
+    print_hits(&lsm, &lsm.search_filtered(&embedder.embed(query), 3, &Filter::eq("topic", "cycling"))?)?;
+
+    // a snapshot keeps reading the tree as it was, while new documents go in
+    let snapshot = lsm.snapshot();
+    let late = Document { title: "Pruning roses".to_string(), topic: "gardening".to_string(), text: "Prune roses in late winter, cutting above an outward facing bud.".to_string() };
+    let late_key = corpus.len() as u64;
+    ingest_at(&lsm, &embedder, late_key, &late)?;
+    println!("\nafter adding '{}': the tree has {} documents, the snapshot still {}", late.title, lsm.len()?, snapshot.iter().count());
+    assert!(snapshot.get(late_key)?.is_none() && lsm.get(late_key)?.is_some());
+
+    lsm.flush()?;
+    drop(snapshot);
+    lsm.close()?;
+
+    // everything, the index parameters included, is back after a reopen
+    let lsm = LSMTree::open(&directory, Options { hnsw: Some(HnswOptions::default()), ..Options::default() })?;
+    let query = "when to prune roses";
+    println!("\nreopened with the {:?} metric\n{}", lsm.metric(), query);
+    print_hits(&lsm, &lsm.search(&embedder.embed(query), 1, 64)?)?;
+    Ok(())
+}
+
+fn ingest(lsm: &LSMTree, embedder: &impl Embedder, corpus: &[Document]) -> Result<(), LsmError> {
+    let mut batch = WriteBatch::new();
+    for (key, document) in corpus.iter().enumerate() {
+        batch.put(key as u64, to_vector(embedder, key as u64, document));
+    }
+    lsm.write(batch)
+}
+
+fn ingest_at(lsm: &LSMTree, embedder: &impl Embedder, key: u64, document: &Document) -> Result<(), LsmError> {
+    lsm.insert(key, to_vector(embedder, key, document))
+}
+
+fn to_vector(embedder: &impl Embedder, key: u64, document: &Document) -> Vector {
+    Vector::new(key, embedder.embed(&format!("{} {}", document.title, document.text)))
+        .with_metadata("title", document.title.as_str())
+        .with_metadata("topic", document.topic.as_str())
+}
+
+fn print_hits(lsm: &LSMTree, hits: &[(u64, f64)]) -> io::Result<()> {
+    for &(key, distance) in hits {
+        let title = match lsm.get(key)?.as_ref().and_then(|v| v.metadata().get("title").cloned()) {
+            Some(MetadataValue::String(title)) => title,
+            _ => format!("#{}", key),
+        };
+        println!("  {:.3}  {}", distance, title);
+    }
+    Ok(())
+}
+
+fn read_corpus(dir: &Path) -> io::Result<Vec<Document>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|p| p.extension().is_some_and(|ext| ext == "txt"));
+    paths.sort();
+    paths.iter().map(|path| {
+        let title = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(Document { title, topic: "file".to_string(), text: std::fs::read_to_string(path)? })
+    }).collect()
+}
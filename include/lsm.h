@@ -0,0 +1,49 @@
+/* The C API of the lsm crate, built as a shared library with `cargo build --release
+ * --features cdylib`. See src/db/ffi.rs for the ownership and error conventions. */
+#ifndef LSM_H
+#define LSM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LSM_OK 0
+#define LSM_NOT_FOUND 1
+#define LSM_INVALID_ARGUMENT 2
+#define LSM_CORRUPTION 3
+#define LSM_BUSY 4
+#define LSM_IO_ERROR 5
+#define LSM_PANIC 6
+
+typedef struct LsmTree lsm_t;
+
+/* The message of this thread's last failed call, or NULL after one that succeeded. Valid
+ * until the thread's next call. */
+const char *lsm_last_error(void);
+
+int lsm_open(const char *path, lsm_t **out);
+
+int lsm_put(const lsm_t *tree, uint64_t key, const double *data, size_t dimension);
+
+/* On LSM_OK `*data` holds `*dimension` values, to be freed with lsm_free_vector */
+int lsm_get(const lsm_t *tree, uint64_t key, double **data, size_t *dimension);
+
+void lsm_free_vector(double *data, size_t dimension);
+
+/* Unless NULL, `*deleted` is set to whether the key was live; a missing key is LSM_OK */
+int lsm_delete(const lsm_t *tree, uint64_t key, int *deleted);
+
+/* `keys` and `distances` have room for `k` values each; `*found` is how many were written */
+int lsm_search(const lsm_t *tree, const double *query, size_t dimension, size_t k, uint64_t *keys, double *distances, size_t *found);
+
+/* Frees the tree, also when closing it fails */
+int lsm_close(lsm_t *tree);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,183 @@
+// Pokes at a tree's data directory from the shell:
+//
+//     lsm-cli <dir> put <key> <x,y,...> [field=value ...]
+//     lsm-cli <dir> get <key>
+//     lsm-cli <dir> delete <key>
+//     lsm-cli <dir> scan [start] [end]
+//     lsm-cli <dir> search <x,y,...> [k]
+//     lsm-cli <dir> import <file.npy|file.fvecs> [first id | ids.npy]
+//     lsm-cli <dir> flush | compact | stats
+//     lsm-cli <dir> check [--repair]
+//
+// Metadata values that parse as an integer, a float or a bool are stored as one.
+
+use lsm::db::embeddings::EmbeddingIds;
+use lsm::db::error::LsmError;
+use lsm::db::lsm::LSMTree;
+use lsm::db::vector::{MetadataValue, Vector};
+use std::io;
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: lsm-cli <dir> <put|get|delete|scan|search|import|flush|compact|stats|check> [args]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [directory, command, rest @ ..] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    match run(Path::new(directory), command, rest) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage(msg)) => {
+            eprintln!("{}\n{}", msg, USAGE);
+            ExitCode::from(2)
+        }
+        Err(Error::Io(e)) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+enum Error {
+    Usage(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<LsmError> for Error {
+    fn from(e: LsmError) -> Error {
+        Error::Io(e.into())
+    }
+}
+
+fn run(directory: &Path, command: &str, args: &[String]) -> Result<(), Error> {
+    let lsm = LSMTree::new(directory)?;
+    match (command, args) {
+        ("put", [key, data, fields @ ..]) => {
+            let key = parse_key(key)?;
+            let mut value = Vector::new(key, parse_vector(data)?);
+            for field in fields {
+                let Some((name, v)) = field.split_once('=') else {
+                    return Err(usage(format!("metadata '{}' is not field=value", field)));
+                };
+                value = value.with_metadata(name, parse_metadata(v));
+            }
+            lsm.insert(key, value)?;
+        }
+        ("get", [key]) => {
+            let key = parse_key(key)?;
+            let Some(value) = lsm.get(key)? else {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, format!("key {} not found", key))));
+            };
+            println!("{}", format_entry(key, &value));
+        }
+        ("delete", [key]) => {
+            let key = parse_key(key)?;
+            if !lsm.delete(key)? {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, format!("key {} not found", key))));
+            }
+        }
+        ("scan", bounds) if bounds.len() <= 2 => {
+            let start = bounds.first().map(|k| parse_key(k)).transpose()?.unwrap_or(0);
+            let end = bounds.get(1).map(|k| parse_key(k)).transpose()?;
+            let entries = match end {
+                Some(end) => lsm.range(start..end)?,
+                None => lsm.range(start..)?,
+            };
+            for (key, value) in entries {
+                println!("{}", format_entry(key, &value));
+            }
+        }
+        ("search", [query, k @ ..]) if k.len() <= 1 => {
+            let k = k.first().map(|k| k.parse().map_err(|_| usage(format!("'{}' is not a count", k)))).transpose()?.unwrap_or(10);
+            for (key, distance) in lsm.knn(&parse_vector(query)?, k)? {
+                println!("{}\t{}", key, distance);
+            }
+        }
+        ("import", [file, ids @ ..]) if ids.len() <= 1 => {
+            let ids = match ids.first() {
+                Some(ids) if ids.ends_with(".npy") => EmbeddingIds::Npy(ids.into()),
+                Some(first) => EmbeddingIds::Sequential(parse_key(first)?),
+                None => EmbeddingIds::Sequential(0),
+            };
+            println!("imported {} vectors", lsm.import_embeddings(Path::new(file), ids)?);
+        }
+        ("flush", []) => lsm.flush()?,
+        ("compact", []) => lsm.compact()?,
+        ("check", flags) if flags.is_empty() || flags == ["--repair"] => {
+            let report = if flags.is_empty() { lsm.verify()? } else { lsm.repair()? };
+            for table in &report.tables {
+                match (&table.errors[..], table.repaired_as) {
+                    ([], _) => println!("table {}: ok, {} entries", table.file_number, table.entries),
+                    (errors, repaired_as) => {
+                        println!("table {}: {} errors", table.file_number, errors.len());
+                        for error in errors {
+                            println!("\t{}", error);
+                        }
+                        if let Some(number) = repaired_as {
+                            println!("\trepaired as table {}, {} of {} entries lost", number, table.lost_entries, table.entries);
+                        }
+                    }
+                }
+            }
+            let damaged = report.damaged().filter(|table| table.repaired_as.is_none()).count();
+            if damaged > 0 {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("{} damaged tables", damaged))));
+            }
+        }
+        ("stats", []) => {
+            println!("{:#?}", lsm.stats());
+            println!("live keys: {}", lsm.len()?);
+        }
+        _ => return Err(usage(format!("bad arguments to '{}'", command))),
+    }
+    Ok(lsm.close()?)
+}
+
+fn format_entry(key: u64, value: &Vector) -> String {
+    let mut line = format!("{}\t{:?}", key, value.data());
+    for (field, v) in value.metadata() {
+        let v = match v {
+            MetadataValue::Bool(b) => b.to_string(),
+            MetadataValue::Int(i) => i.to_string(),
+            MetadataValue::Float(f) => f.to_string(),
+            MetadataValue::String(s) => format!("{:?}", s),
+        };
+        line.push_str(&format!("\t{}={}", field, v));
+    }
+    if let Some(at) = value.expires_at() {
+        line.push_str(&format!("\texpires_at={}", at));
+    }
+    line
+}
+
+fn parse_key(key: &str) -> Result<u64, Error> {
+    key.parse().map_err(|_| usage(format!("'{}' is not a key", key)))
+}
+
+fn parse_vector(data: &str) -> Result<Vec<f64>, Error> {
+    data.split(',').map(|x| x.trim().parse().map_err(|_| usage(format!("'{}' is not a number", x)))).collect()
+}
+
+fn parse_metadata(v: &str) -> MetadataValue {
+    if let Ok(i) = v.parse::<i64>() {
+        MetadataValue::Int(i)
+    } else if let Ok(f) = v.parse::<f64>() {
+        MetadataValue::Float(f)
+    } else if let Ok(b) = v.parse::<bool>() {
+        MetadataValue::Bool(b)
+    } else {
+        MetadataValue::String(v.to_string())
+    }
+}
+
+fn usage(msg: String) -> Error {
+    Error::Usage(msg)
+}
@@ -1,2 +1,57 @@
+#[cfg(feature = "async")]
+pub mod async_tree;
+pub mod batch;
+pub mod binary;
+pub mod bloom;
+pub mod bulk;
+pub(crate) mod cache;
+pub(crate) mod checksum;
+pub mod codec;
+pub mod compaction;
+pub mod config;
+pub mod database;
+pub(crate) mod direct;
+pub mod embeddings;
+pub mod entry;
+pub mod error;
+pub mod events;
+pub mod executor;
+pub mod export;
+#[cfg(feature = "failpoints")]
+pub mod failpoint;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
+pub mod filter;
+pub(crate) mod half;
+pub mod index;
+pub mod listener;
 pub mod lsm;
+pub mod manifest;
+pub(crate) mod memory;
+pub(crate) mod memtable;
+pub mod merge;
+pub mod metrics;
+pub mod options;
+pub mod pca;
+pub mod pipeline;
+pub(crate) mod platform;
+pub mod prefix;
+pub mod preflight;
+pub(crate) mod ratelimit;
+#[cfg(feature = "replication")]
+pub mod replication;
+pub mod search;
+#[cfg(any(feature = "http", feature = "resp", feature = "replication"))]
+pub mod server;
+pub mod shard;
+pub mod simd;
+pub mod sstable;
+pub mod stats;
+pub mod storage;
+pub mod stream;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod transaction;
 pub mod vector;
+pub mod verify;
+pub mod wal;
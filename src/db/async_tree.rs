@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::ops::RangeBounds;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use crate::db::error::LsmError;
+use crate::db::lsm::LSMTree;
+use crate::db::vector::Vector;
+
+const DEFAULT_THREADS: usize = 4;
+
+// Runtime-agnostic async wrapper: every call runs on a small pool of blocking threads
+// and completes a future that any executor can await. Clones share the tree and pool.
+#[derive(Clone)]
+pub struct AsyncLSMTree {
+    tree: Arc<LSMTree>,
+    pool: Arc<BlockingPool>,
+}
+
+impl AsyncLSMTree {
+    pub fn new(tree: LSMTree) -> Result<AsyncLSMTree, LsmError> {
+        AsyncLSMTree::with_threads(tree, DEFAULT_THREADS)
+    }
+
+    pub fn with_threads(tree: LSMTree, threads: usize) -> Result<AsyncLSMTree, LsmError> {
+        if threads == 0 {
+            return Err(LsmError::InvalidArgument("the blocking pool needs at least one thread".to_string()));
+        }
+        Ok(AsyncLSMTree { tree: Arc::new(tree), pool: Arc::new(BlockingPool::new(threads)?) })
+    }
+
+    // The wrapped tree, for calls that don't need to leave the current thread
+    pub fn tree(&self) -> &LSMTree {
+        &self.tree
+    }
+
+    pub async fn get(&self, key: u64) -> Result<Option<Vector>, LsmError> {
+        self.run(move |tree| tree.get(key)).await
+    }
+
+    pub async fn insert(&self, key: u64, value: Vector) -> Result<(), LsmError> {
+        self.run(move |tree| tree.insert(key, value)).await
+    }
+
+    pub async fn delete(&self, key: u64) -> Result<bool, LsmError> {
+        self.run(move |tree| tree.delete(key)).await
+    }
+
+    pub async fn range<R: RangeBounds<u64> + Send + 'static>(&self, range: R) -> Result<Vec<(u64, Vector)>, LsmError> {
+        self.run(move |tree| tree.range(range)).await
+    }
+
+    pub async fn flush(&self) -> Result<(), LsmError> {
+        self.run(|tree| tree.flush()).await
+    }
+
+    fn run<T, F>(&self, f: F) -> Task<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&LSMTree) -> T + Send + 'static,
+    {
+        let tree = self.tree.clone();
+        self.pool.spawn(move || f(&tree))
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct BlockingPool {
+    queue: Arc<(Mutex<PoolQueue>, Condvar)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct PoolQueue {
+    jobs: VecDeque<Job>,
+    shutdown: bool,
+}
+
+impl BlockingPool {
+    fn new(threads: usize) -> io::Result<BlockingPool> {
+        let queue = Arc::new((Mutex::new(PoolQueue::default()), Condvar::new()));
+        let mut handles = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let queue = queue.clone();
+            handles.push(thread::Builder::new()
+                .name(format!("lsm-blocking-{}", i))
+                .spawn(move || worker(&queue))?);
+        }
+        Ok(BlockingPool { queue, threads: handles })
+    }
+
+    fn spawn<T, F>(&self, f: F) -> Task<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+        let completed = slot.clone();
+        let job = Box::new(move || {
+            // a panic is handed to the awaiting task instead of killing the worker
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut slot = completed.lock().unwrap();
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+
+        let (queue, available) = &*self.queue;
+        queue.lock().unwrap().jobs.push_back(job);
+        available.notify_one();
+        Task { slot }
+    }
+}
+
+impl Drop for BlockingPool {
+    // Queued jobs still run so no awaiting task is left hanging
+    fn drop(&mut self) {
+        let (queue, available) = &*self.queue;
+        queue.lock().unwrap().shutdown = true;
+        available.notify_all();
+        for handle in self.threads.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn worker(queue: &(Mutex<PoolQueue>, Condvar)) {
+    let (queue, available) = queue;
+    loop {
+        let mut guard = queue.lock().unwrap();
+        let job = loop {
+            if let Some(job) = guard.jobs.pop_front() {
+                break job;
+            }
+            if guard.shutdown {
+                return;
+            }
+            guard = available.wait(guard).unwrap();
+        };
+        drop(guard);
+        job();
+    }
+}
+
+struct Slot<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+// Completes once the pool has run the job
+struct Task<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Task<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+    use std::task::Wake;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                return value;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_async_operations() {
+        let path = test_dir("async_operations");
+        let tree = AsyncLSMTree::new(LSMTree::new(&path).unwrap()).unwrap();
+        block_on(async {
+            for i in 0..20 {
+                tree.insert(i, Vector::new(i, vec![i as f64])).await.unwrap();
+            }
+            assert_eq!(tree.get(7).await.unwrap().unwrap().data(), &vec![7.0]);
+            assert!(tree.delete(7).await.unwrap());
+            assert!(tree.get(7).await.unwrap().is_none());
+            assert!(!tree.delete(1000).await.unwrap());
+
+            let keys: Vec<u64> = tree.range(5..10).await.unwrap().into_iter().map(|(k, _)| k).collect();
+            assert_eq!(keys, vec![5, 6, 8, 9]);
+        });
+    }
+
+    #[test]
+    fn test_concurrent_awaiters() {
+        let path = test_dir("async_concurrent_awaiters");
+        let tree = AsyncLSMTree::with_threads(LSMTree::new(&path).unwrap(), 2).unwrap();
+        let handles: Vec<_> = (0..8u64).map(|t| {
+            let tree = tree.clone();
+            thread::spawn(move || block_on(async {
+                for i in 0..10 {
+                    let key = t * 100 + i;
+                    tree.insert(key, Vector::new(key, vec![key as f64])).await.unwrap();
+                }
+            }))
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(tree.tree().range(..).unwrap().len(), 80);
+    }
+}
@@ -0,0 +1,185 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read};
+use std::ops::Range;
+use crate::db::entry;
+use crate::db::vector::Vector;
+use crate::db::wal::RecordType;
+
+// logs written before entry flags existed still hold this
+const PUT: u8 = 1;
+const DELETE: u8 = 2;
+const MERGE: u8 = 3;
+const DELETE_RANGE: u8 = 4;
+// a put with a flags byte before the value
+const PUT_WITH_FLAGS: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BatchOp {
+    Put(u64, Vector),
+    Delete(u64),
+    Merge(u64, Vec<u8>),
+    DeleteRange(Range<u64>),
+}
+
+// A group of writes applied atomically by `LSMTree::write`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, key: u64, value: Vector) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Put(key, value));
+        self
+    }
+
+    pub fn delete(&mut self, key: u64) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Delete(key));
+        self
+    }
+
+    // Buffers an operand to be folded into the key's value by the merge operator
+    pub fn merge(&mut self, key: u64, operand: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Merge(key, operand));
+        self
+    }
+
+    // Deletes every key in `start..end`
+    pub fn delete_range(&mut self, start: u64, end: u64) -> &mut WriteBatch {
+        self.ops.push(BatchOp::DeleteRange(start..end));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn record_type(&self) -> RecordType {
+        match self.ops.as_slice() {
+            [BatchOp::Put(..)] => RecordType::Put,
+            [BatchOp::Delete(_)] => RecordType::Delete,
+            [BatchOp::DeleteRange(_)] => RecordType::RangeDelete,
+            _ => RecordType::BatchCommit,
+        }
+    }
+
+    // WAL payload: first sequence (u64), op count (u32), then each op
+    pub(crate) fn encode(&self, sequence: u64) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.write_u64::<LittleEndian>(sequence)?;
+        buf.write_u32::<LittleEndian>(self.ops.len() as u32)?;
+        for op in self.ops.iter() {
+            match op {
+                BatchOp::Put(key, value) => {
+                    buf.write_u8(PUT_WITH_FLAGS)?;
+                    buf.write_u64::<LittleEndian>(*key)?;
+                    let (flags, serialized) = entry::encode(value)?;
+                    buf.write_u8(flags)?;
+                    buf.write_u32::<LittleEndian>(serialized.len() as u32)?;
+                    buf.extend_from_slice(&serialized);
+                }
+                BatchOp::Delete(key) => {
+                    buf.write_u8(DELETE)?;
+                    buf.write_u64::<LittleEndian>(*key)?;
+                }
+                BatchOp::Merge(key, operand) => {
+                    buf.write_u8(MERGE)?;
+                    buf.write_u64::<LittleEndian>(*key)?;
+                    buf.write_u32::<LittleEndian>(operand.len() as u32)?;
+                    buf.extend_from_slice(operand);
+                }
+                BatchOp::DeleteRange(range) => {
+                    buf.write_u8(DELETE_RANGE)?;
+                    buf.write_u64::<LittleEndian>(range.start)?;
+                    buf.write_u64::<LittleEndian>(range.end)?;
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    pub(crate) fn decode(payload: &[u8]) -> io::Result<(u64, WriteBatch)> {
+        let mut cursor = io::Cursor::new(payload);
+        let sequence = cursor.read_u64::<LittleEndian>()?;
+        let count = cursor.read_u32::<LittleEndian>()?;
+        let mut batch = WriteBatch::new();
+        for _ in 0..count {
+            match cursor.read_u8()? {
+                tag @ (PUT | PUT_WITH_FLAGS) => {
+                    let key = cursor.read_u64::<LittleEndian>()?;
+                    let flags = if tag == PUT_WITH_FLAGS { cursor.read_u8()? } else { entry::VALUE_TYPE_BSON };
+                    let len = cursor.read_u32::<LittleEndian>()? as usize;
+                    let mut serialized = vec![0u8; len];
+                    cursor.read_exact(&mut serialized)?;
+                    batch.put(key, entry::decode(flags, &serialized)?);
+                }
+                DELETE => {
+                    batch.delete(cursor.read_u64::<LittleEndian>()?);
+                }
+                MERGE => {
+                    let key = cursor.read_u64::<LittleEndian>()?;
+                    let len = cursor.read_u32::<LittleEndian>()? as usize;
+                    let mut operand = vec![0u8; len];
+                    cursor.read_exact(&mut operand)?;
+                    batch.merge(key, operand);
+                }
+                DELETE_RANGE => {
+                    let start = cursor.read_u64::<LittleEndian>()?;
+                    batch.delete_range(start, cursor.read_u64::<LittleEndian>()?);
+                }
+                tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown batch op {}", tag))),
+            }
+        }
+        Ok((sequence, batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut batch = WriteBatch::new();
+        batch.put(1, Vector::new(1, vec![1.0, 2.0])).delete(7).put(3, Vector::new(3, vec![])).merge(1, vec![9, 8]).delete_range(10, 20);
+        let bytes = batch.encode(42).unwrap();
+
+        let (sequence, decoded) = WriteBatch::decode(&bytes).unwrap();
+        assert_eq!(sequence, 42);
+        assert_eq!(decoded, batch);
+        assert_eq!(decoded.len(), 5);
+    }
+
+    #[test]
+    fn test_decode_rejects_truncated_payload() {
+        let mut batch = WriteBatch::new();
+        batch.put(1, Vector::new(1, vec![1.0, 2.0]));
+        let bytes = batch.encode(1).unwrap();
+        assert!(WriteBatch::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_decodes_puts_without_flags() {
+        // a record from before entry flags: one unflagged put
+        let serialized = bson::to_vec(&Vector::new(4, vec![4.0])).unwrap();
+        let mut bytes = Vec::new();
+        bytes.write_u64::<LittleEndian>(9).unwrap();
+        bytes.write_u32::<LittleEndian>(1).unwrap();
+        bytes.write_u8(PUT).unwrap();
+        bytes.write_u64::<LittleEndian>(4).unwrap();
+        bytes.write_u32::<LittleEndian>(serialized.len() as u32).unwrap();
+        bytes.extend_from_slice(&serialized);
+
+        let (sequence, decoded) = WriteBatch::decode(&bytes).unwrap();
+        assert_eq!(sequence, 9);
+        assert_eq!(decoded.ops, vec![BatchOp::Put(4, Vector::new(4, vec![4.0]))]);
+    }
+}
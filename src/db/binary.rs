@@ -0,0 +1,157 @@
+// Packed bit vectors for binarized embeddings: one bit per dimension, set where the
+// component is positive, so a 1024 dimension embedding takes 128 bytes and two of them
+// are compared with a few xors and popcounts. A tree stores them with
+// `ElementType::Binary` and ranks them by `DistanceMetric::Hamming` or `Jaccard`, which
+// take the same bits of float queries, so a binary tree can gather candidates for
+// reranking against the full vectors. Bit i is bit i % 64 of word i / 64, which written
+// little endian is bit i % 8 of byte i / 8, how tables store them. Vectors of different
+// lengths are compared over the shorter one, like `zip`.
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BinaryVector {
+    dimension: usize,
+    words: Vec<u64>,
+}
+
+impl BinaryVector {
+    // Sign quantization: a bit for each component, set where it is positive
+    pub fn from_signs(data: &[f64]) -> BinaryVector {
+        BinaryVector { dimension: data.len(), words: data.chunks(64).map(pack_word).collect() }
+    }
+
+    pub fn from_bits(bits: &[bool]) -> BinaryVector {
+        let words = bits.chunks(64).map(|chunk| chunk.iter().enumerate().fold(0, |word, (i, &bit)| word | (bit as u64) << i)).collect();
+        BinaryVector { dimension: bits.len(), words }
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn get(&self, i: usize) -> Option<bool> {
+        (i < self.dimension).then(|| self.words[i / 64] >> (i % 64) & 1 == 1)
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    pub fn count_ones(&self) -> u32 {
+        self.words.iter().map(|word| word.count_ones()).sum()
+    }
+
+    // The number of dimensions whose bits differ
+    pub fn hamming(&self, other: &BinaryVector) -> u32 {
+        self.pairs(other).map(|(a, b)| (a ^ b).count_ones()).sum()
+    }
+
+    // 1 - |a ∧ b| / |a ∨ b|, 0 between two vectors without a bit set
+    pub fn jaccard(&self, other: &BinaryVector) -> f64 {
+        let (both, either) = self.pairs(other).fold((0, 0), |(both, either), (a, b)| (both + (a & b).count_ones(), either + (a | b).count_ones()));
+        jaccard_distance(both, either)
+    }
+
+    // The components a tree stores the vector as, 1.0 for a set bit and 0.0 otherwise
+    pub fn to_data(&self) -> Vec<f64> {
+        (0..self.dimension).map(|i| if self.words[i / 64] >> (i % 64) & 1 == 1 { 1.0 } else { 0.0 }).collect()
+    }
+
+    // The words of both over the shorter one's dimension, bits past it masked off
+    fn pairs<'a>(&'a self, other: &'a BinaryVector) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let n = self.dimension.min(other.dimension);
+        self.words.iter().zip(other.words.iter()).take(n.div_ceil(64)).enumerate().map(move |(i, (&a, &b))| {
+            let mask = match n - i * 64 {
+                left if left >= 64 => u64::MAX,
+                left => (1 << left) - 1,
+            };
+            (a & mask, b & mask)
+        })
+    }
+}
+
+// Hamming distance between the sign bits of two float vectors, packing 64 components at a
+// time rather than allocating
+pub(crate) fn hamming(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len().min(b.len());
+    let words = a[..n].chunks(64).zip(b[..n].chunks(64)).map(|(a, b)| (pack_word(a), pack_word(b)));
+    words.map(|(a, b)| (a ^ b).count_ones()).sum::<u32>() as f64
+}
+
+// Like `hamming`, for the Jaccard distance
+pub(crate) fn jaccard(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len().min(b.len());
+    let words = a[..n].chunks(64).zip(b[..n].chunks(64)).map(|(a, b)| (pack_word(a), pack_word(b)));
+    let (both, either) = words.fold((0, 0), |(both, either), (a, b)| (both + (a & b).count_ones(), either + (a | b).count_ones()));
+    jaccard_distance(both, either)
+}
+
+// The sign bits of `data` as tables store them, the last byte padded with zeros
+pub(crate) fn pack(data: &[f64]) -> Vec<u8> {
+    data.chunks(8).map(|chunk| pack_word(chunk) as u8).collect()
+}
+
+// `hamming` and `jaccard` over the packed bits of two vectors of the same dimension,
+// whose padding bits are zero
+pub(crate) fn packed_hamming(a: &[u8], b: &[u8]) -> f64 {
+    packed_words(a, b).map(|(a, b)| (a ^ b).count_ones()).sum::<u32>() as f64
+}
+
+pub(crate) fn packed_jaccard(a: &[u8], b: &[u8]) -> f64 {
+    let (both, either) = packed_words(a, b).fold((0, 0), |(both, either), (a, b)| (both + (a & b).count_ones(), either + (a | b).count_ones()));
+    jaccard_distance(both, either)
+}
+
+// Eight bytes at a time, the rest zero extended
+fn packed_words<'a>(a: &'a [u8], b: &'a [u8]) -> impl Iterator<Item = (u64, u64)> + 'a {
+    let word = |chunk: &[u8]| {
+        let mut bytes = [0; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        u64::from_le_bytes(bytes)
+    };
+    a.chunks(8).zip(b.chunks(8)).map(move |(a, b)| (word(a), word(b)))
+}
+
+fn pack_word(chunk: &[f64]) -> u64 {
+    chunk.iter().enumerate().fold(0, |word, (i, &x)| word | ((x > 0.0) as u64) << i)
+}
+
+fn jaccard_distance(both: u32, either: u32) -> f64 {
+    if either == 0 { 0.0 } else { 1.0 - both as f64 / either as f64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distances() {
+        let a: Vec<f64> = (0..100).map(|i| if i % 3 == 0 { 0.5 } else { -1.0 }).collect();
+        let b: Vec<f64> = (0..100).map(|i| if i % 2 == 0 { 2.0 } else { 0.0 }).collect();
+        let (x, y) = (BinaryVector::from_signs(&a), BinaryVector::from_signs(&b));
+        assert_eq!((x.dimension(), x.count_ones(), y.count_ones()), (100, 34, 50));
+        assert_eq!(x.get(3), Some(true));
+        assert_eq!(x.get(4), Some(false));
+        assert_eq!(x.get(100), None);
+
+        let bits: Vec<bool> = a.iter().map(|&x| x > 0.0).collect();
+        assert_eq!(BinaryVector::from_bits(&bits), x);
+
+        // both set on multiples of 6, either set on 34 + 50 - 17
+        assert_eq!(x.hamming(&y), 67 - 17);
+        assert_eq!(x.jaccard(&y), 1.0 - 17.0 / 67.0);
+
+        // every form agrees
+        assert_eq!(hamming(&a, &b), 50.0);
+        assert_eq!(jaccard(&a, &b), x.jaccard(&y));
+        assert_eq!(packed_hamming(&pack(&a), &pack(&b)), 50.0);
+        assert_eq!(packed_jaccard(&pack(&a), &pack(&b)), x.jaccard(&y));
+        assert_eq!(BinaryVector::from_signs(&x.to_data()), x);
+        assert_eq!(pack(&a).len(), 13);
+
+        // the longer vector's extra bits don't count
+        let short = BinaryVector::from_signs(&a[..70]);
+        assert_eq!(short.hamming(&y), hamming(&a[..70], &b) as u32);
+        assert_eq!(y.hamming(&short), short.hamming(&y));
+        assert_eq!(BinaryVector::default().jaccard(&BinaryVector::from_signs(&[0.0, -1.0])), 0.0);
+    }
+}
@@ -0,0 +1,154 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read};
+use std::ops::RangeInclusive;
+
+// about a 1% false positive rate
+pub(crate) const DEFAULT_BITS_PER_PREFIX: usize = 10;
+// the most probes a filter makes, reached at about 43 bits per prefix
+const MAX_HASHES: f64 = 30.0;
+
+// Bloom filter over the top `prefix_bits` bits of a table's keys, kept in the table's
+// filter block so lookups and prefix scans can pass over tables holding none of a
+// prefix. Block layout: prefix bits (u8), hash count (u8), bit count (u32), the bits.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PrefixBloom {
+    prefix_bits: u32,
+    hashes: u8,
+    bits: Vec<u8>,
+}
+
+impl PrefixBloom {
+    // `keys` ascending, so equal prefixes are adjacent and counted once. Each distinct
+    // prefix gets `bits_per_prefix` bits, with the hash count that minimizes false
+    // positives for it: 10 bits is about 1%, every 5 more divide that by about 10.
+    pub(crate) fn build(prefix_bits: u32, bits_per_prefix: usize, keys: impl IntoIterator<Item = u64>) -> PrefixBloom {
+        let mut prefixes: Vec<u64> = keys.into_iter().map(|k| prefix_of(k, prefix_bits)).collect();
+        prefixes.dedup();
+        let bit_count = (prefixes.len() * bits_per_prefix).max(64);
+        let hashes = (bits_per_prefix as f64 * std::f64::consts::LN_2).round().clamp(1.0, MAX_HASHES) as u8;
+        let mut bloom = PrefixBloom { prefix_bits, hashes, bits: vec![0; bit_count.div_ceil(8)] };
+        for prefix in prefixes {
+            for bit in bloom.probes(prefix) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    // bytes of the bit array
+    pub(crate) fn bytes(&self) -> usize {
+        self.bits.len()
+    }
+
+    // False only if no key of the table has this key's prefix
+    pub(crate) fn may_contain_key(&self, key: u64) -> bool {
+        self.may_contain(prefix_of(key, self.prefix_bits))
+    }
+
+    // Whether keys starting with the top `bits` bits `prefix` may be in the table. A
+    // prefix shorter than the filter's can't be answered, so it always may.
+    pub(crate) fn may_contain_prefix(&self, prefix: u64, bits: u32) -> bool {
+        match bits.checked_sub(self.prefix_bits) {
+            Some(extra) => self.may_contain(prefix.checked_shr(extra).unwrap_or(0)),
+            None => true,
+        }
+    }
+
+    pub(crate) fn prefix_bits(&self) -> u32 {
+        self.prefix_bits
+    }
+
+    fn may_contain(&self, prefix: u64) -> bool {
+        self.probes(prefix).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Double hashing: the i-th probe is h1 + i * h2
+    fn probes(&self, prefix: u64) -> impl Iterator<Item = usize> + use<> {
+        let hash = mix(prefix);
+        let (h1, h2) = (hash as u32 as u64, (hash >> 32) | 1);
+        let bit_count = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.write_u8(self.prefix_bits as u8)?;
+        buf.write_u8(self.hashes)?;
+        buf.write_u32::<LittleEndian>(self.bits.len() as u32 * 8)?;
+        buf.extend_from_slice(&self.bits);
+        Ok(())
+    }
+
+    pub(crate) fn decode(block: &[u8]) -> io::Result<PrefixBloom> {
+        let mut cursor = io::Cursor::new(block);
+        let prefix_bits = cursor.read_u8()? as u32;
+        let hashes = cursor.read_u8()?;
+        let bit_count = cursor.read_u32::<LittleEndian>()? as usize;
+        if prefix_bits == 0 || prefix_bits > 64 || hashes == 0 || bit_count == 0 || !bit_count.is_multiple_of(8) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed prefix filter"));
+        }
+        let mut bits = vec![0; bit_count / 8];
+        cursor.read_exact(&mut bits)?;
+        Ok(PrefixBloom { prefix_bits, hashes, bits })
+    }
+}
+
+// The top `bits` bits of the key
+pub(crate) fn prefix_of(key: u64, bits: u32) -> u64 {
+    key.checked_shr(64 - bits).unwrap_or(0)
+}
+
+// The keys sharing the top `bits` bits with `key`
+pub(crate) fn prefix_range(key: u64, bits: u32) -> RangeInclusive<u64> {
+    let rest = u64::MAX.checked_shr(bits).unwrap_or(0);
+    (key & !rest)..=(key | rest)
+}
+
+// splitmix64's finalizer
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_bloom() {
+        let tenant = |t: u64, i: u64| (t << 48) | i;
+        let keys: Vec<u64> = (0..100).flat_map(|t| (0..5).map(move |i| tenant(t * 2, i))).collect();
+        let bloom = PrefixBloom::build(16, 10, keys.iter().copied());
+
+        assert!(keys.iter().all(|&k| bloom.may_contain_key(k)));
+        let rejected = (0..1000).filter(|t| !bloom.may_contain_key(tenant(t * 2 + 1, 0))).count();
+        assert!(rejected > 950, "{}", rejected);
+        assert_eq!(prefix_range(tenant(4, 7), 16), tenant(4, 0)..=tenant(4, (1 << 48) - 1));
+        assert_eq!(prefix_range(9, 64), 9..=9);
+
+        // a longer prefix is checked by its first 16 bits, a shorter one can't be
+        assert!(bloom.may_contain_prefix(tenant(4, 0xff) >> 40, 24));
+        assert!(bloom.may_contain_prefix(0, 8));
+
+        let mut block = Vec::new();
+        bloom.encode(&mut block).unwrap();
+        assert_eq!(PrefixBloom::decode(&block).unwrap(), bloom);
+        assert!(PrefixBloom::decode(&block[..4]).is_err());
+    }
+
+    #[test]
+    fn test_bits_per_prefix() {
+        let keys: Vec<u64> = (0..2000).map(|i| i << 32).collect();
+        let false_positives = |bits_per_prefix| {
+            let bloom = PrefixBloom::build(32, bits_per_prefix, keys.iter().copied());
+            (2000..22000u64).filter(|&i| bloom.may_contain_key(i << 32)).count()
+        };
+        // about 1%, 0.1% and 15% of the 20000 absent prefixes
+        let (ten, fifteen, four) = (false_positives(10), false_positives(15), false_positives(4));
+        assert!((100..400).contains(&ten), "{}", ten);
+        assert!(fifteen < 60, "{}", fifteen);
+        assert!(four > 2000, "{}", four);
+        assert_eq!(PrefixBloom::build(8, 1, [1]).hashes, 1);
+        assert_eq!(PrefixBloom::build(8, 100, [1]).hashes, 30);
+    }
+}
@@ -0,0 +1,174 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use crate::db::sstable;
+use crate::db::vector::Vector;
+
+// External merge sort for bulk loads: entries are buffered up to `run_size`, spilled as
+// sorted runs to temp files in the tree's directory, then k-way merged back in key order.
+// When a key repeats, the entry added last wins.
+pub(crate) struct ExternalSorter {
+    directory: PathBuf,
+    run_size: usize,
+    buffer: BTreeMap<u64, Vector>,
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    pub(crate) fn new(directory: &Path, run_size: usize) -> ExternalSorter {
+        ExternalSorter { directory: directory.to_path_buf(), run_size: run_size.max(1), buffer: BTreeMap::new(), runs: Vec::new() }
+    }
+
+    pub(crate) fn add(&mut self, key: u64, value: Vector) -> io::Result<()> {
+        self.buffer.insert(key, value);
+        if self.buffer.len() >= self.run_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let path = self.directory.join(format!("bulk_{}.run.tmp", self.runs.len()));
+        let mut buf = BufWriter::new(File::create(&path)?);
+        for (&key, value) in std::mem::take(&mut self.buffer).iter() {
+            sstable::write_entry(&mut buf, key, value)?;
+        }
+        buf.flush()?;
+        self.runs.push(path);
+        Ok(())
+    }
+
+    // Everything added so far in key order. The run files are removed once it is dropped.
+    pub(crate) fn finish(mut self) -> io::Result<SortedRuns> {
+        if !self.buffer.is_empty() && !self.runs.is_empty() {
+            self.spill()?;
+        }
+        let mut readers = Vec::with_capacity(self.runs.len());
+        for path in self.runs.iter() {
+            readers.push(RunReader::open(path)?);
+        }
+        let mut runs = SortedRuns { memory: std::mem::take(&mut self.buffer).into_iter(), readers, heap: BinaryHeap::new(), paths: std::mem::take(&mut self.runs) };
+        for i in 0..runs.readers.len() {
+            runs.advance(i)?;
+        }
+        Ok(runs)
+    }
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+    current: Option<(u64, Vector)>,
+}
+
+impl RunReader {
+    fn open(path: &Path) -> io::Result<RunReader> {
+        Ok(RunReader { reader: BufReader::new(File::open(path)?), current: None })
+    }
+
+    fn next(&mut self) -> io::Result<Option<(u64, Vector)>> {
+        match sstable::read_entry(&mut self.reader) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub(crate) struct SortedRuns {
+    // used when everything fit in one run and nothing was spilled
+    memory: std::collections::btree_map::IntoIter<u64, Vector>,
+    readers: Vec<RunReader>,
+    // smallest key first, ties broken towards the newest run
+    heap: BinaryHeap<Reverse<(u64, Reverse<usize>)>>,
+    paths: Vec<PathBuf>,
+}
+
+impl SortedRuns {
+    fn advance(&mut self, run: usize) -> io::Result<()> {
+        let reader = &mut self.readers[run];
+        reader.current = reader.next()?;
+        if let Some((key, _)) = &reader.current {
+            self.heap.push(Reverse((*key, Reverse(run))));
+        }
+        Ok(())
+    }
+
+    fn next_merged(&mut self) -> io::Result<Option<(u64, Vector)>> {
+        let Some(Reverse((key, Reverse(run)))) = self.heap.pop() else { return Ok(None) };
+        let (_, value) = self.readers[run].current.take().unwrap();
+        self.advance(run)?;
+        // older runs holding the same key are skipped
+        while let Some(&Reverse((next, Reverse(older)))) = self.heap.peek() {
+            if next != key {
+                break;
+            }
+            self.heap.pop();
+            self.readers[older].current = None;
+            self.advance(older)?;
+        }
+        Ok(Some((key, value)))
+    }
+}
+
+impl Iterator for SortedRuns {
+    type Item = io::Result<(u64, Vector)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.readers.is_empty() {
+            return self.memory.next().map(Ok);
+        }
+        self.next_merged().transpose()
+    }
+}
+
+impl Drop for SortedRuns {
+    fn drop(&mut self) {
+        for path in self.paths.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+
+    #[test]
+    fn test_sorts_across_runs() {
+        let path = empty_test_dir("bulk_sorts_across_runs");
+        let mut sorter = ExternalSorter::new(&path, 7);
+        let keys: Vec<u64> = (0..100).map(|i| (i * 37) % 100).collect();
+        for &key in keys.iter() {
+            sorter.add(key, Vector::new(key, vec![key as f64])).unwrap();
+        }
+        // later duplicates win, whichever run they land in
+        sorter.add(5, Vector::new(5, vec![-5.0])).unwrap();
+        sorter.add(99, Vector::new(99, vec![-99.0])).unwrap();
+
+        let runs = sorter.finish().unwrap();
+        assert!(std::fs::read_dir(&path).unwrap().count() > 1);
+        let sorted: Vec<(u64, Vector)> = runs.collect::<io::Result<_>>().unwrap();
+        assert_eq!(sorted.iter().map(|(k, _)| *k).collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+        assert_eq!(sorted[5].1.data(), &vec![-5.0]);
+        assert_eq!(sorted[99].1.data(), &vec![-99.0]);
+        assert_eq!(sorted[6].1.data(), &vec![6.0]);
+
+        // run files are cleaned up once the merge is dropped
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_small_input_stays_in_memory() {
+        let path = empty_test_dir("bulk_small_input_stays_in_memory");
+        let mut sorter = ExternalSorter::new(&path, 100);
+        sorter.add(3, Vector::new(3, vec![])).unwrap();
+        sorter.add(1, Vector::new(1, vec![])).unwrap();
+        let runs = sorter.finish().unwrap();
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+        let keys: Vec<u64> = runs.map(|e| e.unwrap().0).collect();
+        assert_eq!(keys, vec![1, 3]);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::db::vector::Vector;
+
+// (table file number, entry offset)
+type BlockKey = (u64, usize);
+
+// Least recently used SSTable entries, up to a byte budget, shared by all of a tree's
+// tables. Entries are the unit a table is read in; they are never rewritten in place and
+// file numbers aren't reused, so nothing needs invalidating: a compacted table's entries
+// just age out. The budget can shrink and grow back while the cache is in use, see
+// `Options::memory_budget_bytes`.
+pub(crate) struct BlockCache {
+    capacity: AtomicUsize,
+    lru: Mutex<Lru<BlockKey, Arc<[u8]>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Lru<K, V> {
+    items: HashMap<K, (V, usize, u64)>,
+    // last use of every item, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    // sum of the weights the items were inserted with
+    weight: usize,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize) -> BlockCache {
+        BlockCache { capacity: AtomicUsize::new(capacity), lru: Mutex::new(Lru::new()), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    // The cached block, or `read`'s, which is cached unless it alone is over the budget
+    pub(crate) fn get_or_read<E>(&self, key: BlockKey, read: impl FnOnce() -> Result<Vec<u8>, E>) -> Result<Arc<[u8]>, E> {
+        if let Some(block) = self.lru.lock().unwrap().get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(block);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // read without the lock; a racing read of the same block just replaces it
+        let block: Arc<[u8]> = read()?.into();
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if block.len() <= capacity {
+            let len = block.len();
+            self.lru.lock().unwrap().insert(key, block.clone(), len, capacity);
+        }
+        Ok(block)
+    }
+
+    // Evicts the least recently used blocks until the cache is within the new budget
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        if self.capacity.swap(capacity, Ordering::Relaxed) > capacity {
+            self.lru.lock().unwrap().shrink(capacity);
+        }
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.lru.lock().unwrap().weight
+    }
+}
+
+// The most recently read values by key, up to `capacity` of them, in front of the lookups
+// of `LSMTree::get`. Writes invalidate their keys while holding the state lock, and reads
+// fill the cache holding it too, so a value read before a write can't land after the
+// write's invalidation. Values are cached as stored, so expiry is still checked on a hit.
+pub(crate) struct RowCache {
+    capacity: usize,
+    lru: Mutex<Lru<u64, Vector>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RowCache {
+    pub(crate) fn new(capacity: usize) -> RowCache {
+        RowCache { capacity, lru: Mutex::new(Lru::new()), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    pub(crate) fn get(&self, key: u64) -> Option<Vector> {
+        let value = self.lru.lock().unwrap().get(key);
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub(crate) fn insert(&self, key: u64, value: Vector) {
+        self.lru.lock().unwrap().insert(key, value, 1, self.capacity);
+    }
+
+    pub(crate) fn remove(&self, key: u64) {
+        self.lru.lock().unwrap().remove(key);
+    }
+
+    pub(crate) fn remove_range(&self, range: &Range<u64>) {
+        self.lru.lock().unwrap().retain(|key| !range.contains(key));
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.lru.lock().unwrap() = Lru::new();
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl<K: Copy + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new() -> Lru<K, V> {
+        Lru { items: HashMap::new(), order: BTreeMap::new(), tick: 0, weight: 0 }
+    }
+
+    fn get(&mut self, key: K) -> Option<V> {
+        self.tick += 1;
+        let (value, _, used) = self.items.get_mut(&key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    // Evicts the least recently used items until the total weight is within `capacity`
+    fn insert(&mut self, key: K, value: V, weight: usize, capacity: usize) {
+        self.remove(key);
+        self.tick += 1;
+        self.weight += weight;
+        self.items.insert(key, (value, weight, self.tick));
+        self.order.insert(self.tick, key);
+        self.shrink(capacity);
+    }
+
+    fn shrink(&mut self, capacity: usize) {
+        while self.weight > capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            let (_, evicted, _) = self.items.remove(&oldest).unwrap();
+            self.weight -= evicted;
+        }
+    }
+
+    fn remove(&mut self, key: K) {
+        if let Some((_, weight, used)) = self.items.remove(&key) {
+            self.weight -= weight;
+            self.order.remove(&used);
+        }
+    }
+
+    fn retain(&mut self, keep: impl Fn(&K) -> bool) {
+        let removed: Vec<K> = self.items.keys().filter(|key| !keep(key)).copied().collect();
+        for key in removed {
+            self.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Result<Vec<u8>, ()> {
+        Ok(vec![0; len])
+    }
+
+    fn cached() -> Result<Vec<u8>, ()> {
+        panic!("should have been cached")
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = BlockCache::new(100);
+        cache.get_or_read((1, 0), || block(40)).unwrap();
+        cache.get_or_read((1, 40), || block(40)).unwrap();
+        // using the first block makes the second the one to go
+        cache.get_or_read((1, 0), cached).unwrap();
+        cache.get_or_read((2, 0), || block(40)).unwrap();
+        assert_eq!((cache.hits(), cache.misses(), cache.bytes()), (1, 3, 80));
+        cache.get_or_read((1, 0), cached).unwrap();
+        assert_eq!(cache.get_or_read((1, 40), || block(40)).unwrap().len(), 40);
+        assert_eq!(cache.misses(), 4);
+
+        // too big to keep, and errors aren't cached
+        cache.get_or_read((3, 0), || block(101)).unwrap();
+        assert!(cache.get_or_read((3, 8), || Err(())).is_err());
+        assert_eq!(cache.bytes(), 80);
+
+        // shrinking evicts the oldest until it fits, and growing again keeps more
+        cache.set_capacity(50);
+        assert_eq!(cache.bytes(), 40);
+        cache.get_or_read((1, 40), cached).unwrap();
+        cache.get_or_read((4, 0), || block(60)).unwrap();
+        assert_eq!(cache.bytes(), 40);
+        cache.set_capacity(100);
+        cache.get_or_read((4, 0), || block(60)).unwrap();
+        assert_eq!(cache.bytes(), 100);
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let cache = RowCache::new(3);
+        for key in 0..4 {
+            cache.insert(key, Vector::new(key, vec![key as f64]));
+        }
+        assert!(cache.get(0).is_none());
+        assert_eq!(cache.get(1).unwrap().id(), 1);
+
+        cache.remove(1);
+        cache.insert(1, Vector::new(1, vec![10.0]));
+        assert_eq!(cache.get(1).unwrap().data(), &vec![10.0]);
+        cache.remove_range(&(2..3));
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(3).is_some());
+        cache.clear();
+        assert!(cache.get(3).is_none());
+        assert_eq!((cache.hits(), cache.misses()), (3, 3));
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::io;
+use crate::db::entry::{self, PayloadCodec};
+use crate::db::vector::{ElementType, Vector};
+
+// How a table lays out the values of its entries, see `Options::codec`. The id of the
+// codec a table was written with is kept in its footer and the table is read back with
+// the built-in codec of that id, see `by_id`, so changing it leaves older tables readable
+// and compaction rewrites them in the new one. Each entry's flags still say how that
+// entry is laid out, which is how a codec tells its own layouts apart.
+pub trait Codec: Send + Sync {
+    // recorded in the footer of every table written with the codec
+    fn id(&self) -> u32;
+    fn name(&self) -> &'static str;
+    // The flags and serialized form of `value`, to be written at offset `at` of its table,
+    // with its data as `element`s and its metadata in `payload`'s layout where the codec
+    // keeps them apart
+    fn encode(&self, value: &Vector, at: usize, element: ElementType, payload: PayloadCodec) -> io::Result<(u8, Vec<u8>)>;
+    fn decode(&self, flags: u8, serialized: &[u8]) -> io::Result<Vector>;
+    // whether an entry may point at the data of another, see `Options::dedup_vectors`
+    fn shares_data(&self) -> bool;
+}
+
+impl fmt::Debug for dyn Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// what tables before version 10, which don't name theirs, were written with
+pub const RAW_ID: u32 = 0;
+pub const BSON_ID: u32 = 1;
+
+// The id, dimension and element type, then the data in a fixed little endian layout
+// aligned for reading in place and the metadata after it, see `entry::encode_at`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawCodec;
+
+impl Codec for RawCodec {
+    fn id(&self) -> u32 {
+        RAW_ID
+    }
+
+    fn name(&self) -> &'static str {
+        "raw"
+    }
+
+    fn encode(&self, value: &Vector, at: usize, element: ElementType, payload: PayloadCodec) -> io::Result<(u8, Vec<u8>)> {
+        entry::encode_at(value, at, element, payload)
+    }
+
+    // entries written before dense values are bson documents, which their flags say
+    fn decode(&self, flags: u8, serialized: &[u8]) -> io::Result<Vector> {
+        entry::decode(flags, serialized)
+    }
+
+    fn shares_data(&self) -> bool {
+        true
+    }
+}
+
+// The whole vector as one bson document, readable by any bson tool. Larger than the raw
+// layout and decoded before every read, data as f64s whatever the element type.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BsonCodec;
+
+impl Codec for BsonCodec {
+    fn id(&self) -> u32 {
+        BSON_ID
+    }
+
+    fn name(&self) -> &'static str {
+        "bson"
+    }
+
+    fn encode(&self, value: &Vector, _at: usize, _element: ElementType, _payload: PayloadCodec) -> io::Result<(u8, Vec<u8>)> {
+        let mut flags = entry::VALUE_TYPE_BSON;
+        if value.expires_at().is_some() {
+            flags |= entry::HAS_TTL;
+        }
+        if !value.metadata().is_empty() {
+            flags |= entry::HAS_PAYLOAD;
+        }
+        Ok((flags, bson::to_vec(value).map_err(io::Error::other)?))
+    }
+
+    fn decode(&self, flags: u8, serialized: &[u8]) -> io::Result<Vector> {
+        entry::decode(flags, serialized)
+    }
+
+    fn shares_data(&self) -> bool {
+        false
+    }
+}
+
+// The built-in codec with this id, the only ones tables are read back with
+pub fn by_id(id: u32) -> Option<&'static dyn Codec> {
+    match id {
+        RAW_ID => Some(&RawCodec),
+        BSON_ID => Some(&BsonCodec),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs_roundtrip() {
+        let mut value = Vector::new(7, vec![0.5, -1.0, 2.0]);
+        value.set_expires_at(1234);
+        value.metadata_mut().insert("lang".to_string(), "en".into());
+        for id in [RAW_ID, BSON_ID] {
+            let codec = by_id(id).unwrap();
+            assert_eq!(codec.id(), id);
+            let (flags, serialized) = codec.encode(&value, 3, ElementType::F64, PayloadCodec::Compact).unwrap();
+            assert_eq!(codec.decode(flags, &serialized).unwrap(), value);
+            assert_eq!(entry::expires_at(flags, &serialized).unwrap(), Some(1234));
+        }
+        let (flags, _) = BsonCodec.encode(&value, 0, ElementType::F64, PayloadCodec::Bson).unwrap();
+        assert_eq!(flags & entry::VALUE_TYPE_MASK, entry::VALUE_TYPE_BSON);
+        assert!(by_id(2).is_none());
+        assert_eq!(format!("{:?}", &BsonCodec as &dyn Codec), "bson");
+    }
+}
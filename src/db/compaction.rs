@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::ops::{Bound, Range};
+use std::sync::{Mutex, OnceLock};
+use crate::db::ratelimit::{Meter, RateLimiter};
+use crate::db::sstable::SSTable;
+use crate::db::vector::Vector;
+
+// How background compactions pick the runs they merge, see `Options::compaction_style`.
+// An open tree switches with `LSMTree::set_compaction_style`, say from `Universal` while
+// a dataset is loaded to `Leveled` once it serves reads, and the next compaction picks
+// by the new style.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionStyle {
+    // once there are `compaction_trigger` sorted runs, merges the `compaction_trigger`
+    // adjacent runs with the fewest entries between them, so fresh small flushes get
+    // merged before big outputs are rewritten
+    #[default]
+    Tiered,
+    // rewrites as little as it can, for write-heavy loads. Once there are
+    // `compaction_trigger` runs: everything is merged when the runs newer than the oldest
+    // have grown past `max_space_amplification_percent` of its bytes; otherwise the newest
+    // runs are merged while each next older one is at most `size_ratio_percent` bigger
+    // than them all together; otherwise just enough of the newest runs to get under the
+    // trigger.
+    Universal { size_ratio_percent: u64, max_space_amplification_percent: u64 },
+    // keeps few runs, for reads: runs grow `fanout` times from newest to oldest, and
+    // whenever the runs newer than one add up to more than its bytes over `fanout` they
+    // are merged into it. `compaction_trigger` only has to be set to enable compaction.
+    Leveled { fanout: u64 },
+}
+
+// Which adjacent tables to merge next by `style`, given that compactions are on with a
+// `trigger` of at least 2. Windows holding a `busy` table (an input to a running
+// compaction) are skipped. Returns positions in the oldest-first table list.
+pub(crate) fn pick(tables: &[SSTable], trigger: usize, style: CompactionStyle, busy: &BTreeSet<u64>) -> Option<Range<usize>> {
+    let runs = runs(tables);
+    if trigger < 2 {
+        return None;
+    }
+    let free = |window: &Range<usize>| !tables[runs[window.start].start..runs[window.end - 1].end].iter().any(|t| busy.contains(&t.file_number));
+    let window = match style {
+        CompactionStyle::Tiered => pick_tiered(tables, &runs, trigger, &free),
+        CompactionStyle::Universal { size_ratio_percent, max_space_amplification_percent } => {
+            pick_universal(&run_bytes(tables, &runs), trigger, size_ratio_percent, max_space_amplification_percent, &free)
+        }
+        CompactionStyle::Leveled { fanout } => pick_leveled(&run_bytes(tables, &runs), fanout, &free),
+    }?;
+    Some(runs[window.start].start..runs[window.end - 1].end)
+}
+
+// The windows below are positions in the oldest-first list of runs
+fn pick_tiered(tables: &[SSTable], runs: &[Range<usize>], trigger: usize, free: &dyn Fn(&Range<usize>) -> bool) -> Option<Range<usize>> {
+    if runs.len() < trigger {
+        return None;
+    }
+    (0..=runs.len() - trigger)
+        .map(|start| start..start + trigger)
+        .filter(|window| free(window))
+        .min_by_key(|window| tables[runs[window.start].start..runs[window.end - 1].end].iter().map(|t| t.len()).sum::<usize>())
+}
+
+fn pick_universal(bytes: &[u64], trigger: usize, size_ratio_percent: u64, max_space_amplification_percent: u64, free: &dyn Fn(&Range<usize>) -> bool) -> Option<Range<usize>> {
+    let runs = bytes.len();
+    if runs < trigger {
+        return None;
+    }
+    let newer: u64 = bytes[1..].iter().sum();
+    if newer * 100 >= bytes[0] * max_space_amplification_percent && free(&(0..runs)) {
+        return Some(0..runs);
+    }
+    for end in (2..=runs).rev() {
+        let mut start = end - 1;
+        let mut merged = bytes[start];
+        while start > 0 && bytes[start - 1] * 100 <= merged * (100 + size_ratio_percent) {
+            start -= 1;
+            merged += bytes[start];
+        }
+        if end - start >= 2 && free(&(start..end)) {
+            return Some(start..end);
+        }
+    }
+    Some(trigger - 2..runs).filter(|window| free(window))
+}
+
+fn pick_leveled(bytes: &[u64], fanout: u64, free: &dyn Fn(&Range<usize>) -> bool) -> Option<Range<usize>> {
+    (0..bytes.len().saturating_sub(1))
+        .map(|level| level..bytes.len())
+        .find(|window| bytes[window.start + 1..].iter().sum::<u64>() * fanout > bytes[window.start] && free(window))
+}
+
+fn run_bytes(tables: &[SSTable], runs: &[Range<usize>]) -> Vec<u64> {
+    runs.iter().map(|run| tables[run.clone()].iter().map(|t| t.file_size()).sum()).collect()
+}
+
+// Positions of the sorted runs in the oldest-first table list. A table is a run of its
+// own unless a compaction split into sub-compactions wrote it and the tables beside it,
+// which hold disjoint key ranges and so are as good as one table.
+pub(crate) fn runs(tables: &[SSTable]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for (i, table) in tables.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if table.continues_run => run.end = i + 1,
+            _ => runs.push(i..i + 1),
+        }
+    }
+    runs
+}
+
+// Positions of the whole runs holding keys or deletes in `range`, and of the runs
+// between them: only adjacent tables can be merged without reordering writes, and only
+// whole runs, so a run's tables keep their key ranges apart
+pub(crate) fn covering(tables: &[SSTable], range: &Range<u64>) -> Option<Range<usize>> {
+    let first = tables.iter().position(|t| t.overlaps(range))?;
+    let last = tables.iter().rposition(|t| t.overlaps(range))?;
+    let runs = runs(tables);
+    let first = runs.iter().find(|run| run.contains(&first)).unwrap().start;
+    let last = runs.iter().find(|run| run.contains(&last)).unwrap().end;
+    Some(first..last)
+}
+
+// hints kept at most, the oldest are dropped past it
+const MAX_READ_HINTS: usize = 64;
+
+// Key ranges iterators found crowded with entries they had to step over, see
+// `Options::read_compaction_skips`, for compactions to merge before what the style picks
+#[derive(Default)]
+pub(crate) struct ReadHints {
+    hints: Mutex<Vec<ReadHint>>,
+    // wakes the tree's compaction workers once a hint is recorded
+    wake: OnceLock<Box<dyn Fn() + Send + Sync>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ReadHint {
+    pub(crate) range: Range<u64>,
+    // the tables the iterator read; once compactions have replaced them all, the entries
+    // it stepped over are gone and the hint is dropped
+    pub(crate) tables: BTreeSet<u64>,
+}
+
+pub(crate) enum HintClaim<T> {
+    Take(T),
+    Keep,
+    Drop,
+}
+
+impl ReadHints {
+    pub(crate) fn set_wake(&self, wake: impl Fn() + Send + Sync + 'static) {
+        let _ = self.wake.set(Box::new(wake));
+    }
+
+    // Hints overlapping the new one are folded into it
+    pub(crate) fn record(&self, mut hint: ReadHint) {
+        let mut hints = self.hints.lock().unwrap();
+        hints.retain(|other| {
+            let overlaps = other.range.start < hint.range.end && hint.range.start < other.range.end;
+            if overlaps {
+                hint.range = hint.range.start.min(other.range.start)..hint.range.end.max(other.range.end);
+                hint.tables.extend(&other.tables);
+            }
+            !overlaps
+        });
+        hints.push(hint);
+        if hints.len() > MAX_READ_HINTS {
+            hints.remove(0);
+        }
+        drop(hints);
+        if let Some(wake) = self.wake.get() {
+            wake();
+        }
+    }
+
+    // What the first hint `claim` takes turns into, dropping the ones it has no more use for
+    // and leaving the others for later, oldest first
+    pub(crate) fn take<T>(&self, mut claim: impl FnMut(&ReadHint) -> HintClaim<T>) -> Option<T> {
+        let mut hints = self.hints.lock().unwrap();
+        let mut i = 0;
+        while i < hints.len() {
+            match claim(&hints[i]) {
+                HintClaim::Take(claimed) => {
+                    hints.remove(i);
+                    return Some(claimed);
+                }
+                HintClaim::Keep => i += 1,
+                HintClaim::Drop => {
+                    hints.remove(i);
+                }
+            }
+        }
+        None
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pending(&self) -> Vec<ReadHint> {
+        self.hints.lock().unwrap().clone()
+    }
+}
+
+// Keys splitting the inputs into up to `max_parts` key ranges of about as many distinct
+// keys each, and at least `min_entries`, ascending. Each range starts at a split key.
+pub(crate) fn split_points(inputs: &[SSTable], max_parts: usize, min_entries: usize) -> io::Result<Vec<u64>> {
+    let mut keys = Vec::new();
+    for table in inputs {
+        for entry in table.entries(..) {
+            keys.push(entry?.0);
+        }
+    }
+    keys.sort_unstable();
+    keys.dedup();
+    let parts = max_parts.min(keys.len() / min_entries.max(1)).max(1);
+    Ok((1..parts).map(|i| keys[keys.len() * i / parts]).collect())
+}
+
+pub(crate) struct Merged {
+    pub(crate) entries: BTreeMap<u64, Vector>,
+    // tombstones that still have to mask tables older than the inputs
+    pub(crate) tombstones: BTreeSet<u64>,
+    pub(crate) range_tombstones: Vec<Range<u64>>,
+}
+
+// Merges adjacent tables, oldest first, keeping what falls within `bounds`: a sub-compaction's share of the key range,
+// with range tombstones cut to it. `bottommost` is set to the current time when the oldest
+// table is among the inputs: nothing older can reappear, so tombstones and entries expired
+// by then are dropped.
+pub(crate) fn merge(inputs: &[SSTable], bounds: (Bound<u64>, Bound<u64>), bottommost: Option<u64>, limiter: Option<&RateLimiter>) -> io::Result<Merged> {
+    let mut meter = Meter::new(limiter);
+    let mut entries = BTreeMap::new();
+    let mut tombstones = BTreeSet::new();
+    let mut range_tombstones = Vec::new();
+    for table in inputs {
+        // a table's range tombstones only hide older tables' entries
+        for range in table.range_tombstones.iter().map(|r| clip(r, bounds)).filter(|r| !r.is_empty()) {
+            let covered: Vec<u64> = entries.range(range.clone()).map(|(&k, _)| k).collect();
+            for key in covered {
+                entries.remove(&key);
+            }
+            range_tombstones.push(range);
+        }
+        let _hint = table.scan_hint(bounds);
+        for entry in table.entries(bounds) {
+            let (key, offset) = entry?;
+            if table.tombstones.contains(&key) {
+                continue;
+            }
+            meter.charge(table.entry_size(offset)? as u64);
+            let (_, value) = table.read_value(offset)?;
+            entries.insert(key, value);
+            tombstones.remove(&key);
+        }
+        // a tombstone hides the key in its own table and every older one
+        for &key in table.tombstones.range(bounds) {
+            entries.remove(&key);
+            tombstones.insert(key);
+        }
+    }
+    if let Some(now) = bottommost {
+        entries.retain(|_, value| !value.is_expired(now));
+        tombstones.clear();
+        range_tombstones.clear();
+    }
+    Ok(Merged { entries, tombstones, range_tombstones })
+}
+
+// The part of `range` within `bounds`, maybe empty
+fn clip(range: &Range<u64>, bounds: (Bound<u64>, Bound<u64>)) -> Range<u64> {
+    let start = match bounds.0 {
+        Bound::Included(k) => range.start.max(k),
+        Bound::Excluded(k) => range.start.max(k.saturating_add(1)),
+        Bound::Unbounded => range.start,
+    };
+    let end = match bounds.1 {
+        Bound::Included(k) => range.end.min(k.saturating_add(1)),
+        Bound::Excluded(k) => range.end.min(k),
+        Bound::Unbounded => range.end,
+    };
+    start..end.max(start)
+}
+
+// Drops the tombstones that mask nothing in `older`, the tables older than the inputs, so
+// deletes don't have to wait for a compaction of the oldest table to go. A newer table
+// hides nothing from an older one, so what no older table holds needs no masking.
+// A tombstone over a table whose index can't be read is kept.
+pub(crate) fn drop_unneeded_tombstones(merged: &mut Merged, older: &[SSTable]) {
+    merged.tombstones.retain(|&key| older.iter().any(|t| t.contains_key(key).unwrap_or(true)));
+    let holds_keys_in = |t: &SSTable, range: &Range<u64>| !matches!(t.first_key((Bound::Included(range.start), Bound::Excluded(range.end)), false), Ok(None));
+    merged.range_tombstones.retain(|range| older.iter().any(|t| holds_keys_in(t, range)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+    use std::fs::File;
+    use std::io::BufWriter;
+    use std::path::Path;
+    use crate::db::sstable::{self, ReadPath, TableFormat};
+
+    const ALL: (Bound<u64>, Bound<u64>) = (Bound::Unbounded, Bound::Unbounded);
+
+    fn table(dir: &Path, number: u64, entries: &[(u64, f64)]) -> SSTable {
+        tombstoned(dir, number, entries, &[])
+    }
+
+    fn tombstoned(dir: &Path, number: u64, entries: &[(u64, f64)], tombstones: &[u64]) -> SSTable {
+        let path = dir.join(format!("{}.sdb", number));
+        let entries: BTreeMap<u64, Vector> = entries.iter().map(|&(k, x)| (k, Vector::new(k, vec![x]))).collect();
+        let tombstones: BTreeSet<u64> = tombstones.iter().copied().collect();
+        let mut buf = BufWriter::new(File::create(&path).unwrap());
+        sstable::write_table(&mut buf, entries.iter(), &tombstones, &[], &TableFormat::default()).unwrap();
+        drop(buf);
+        SSTable::open(&path, number, ReadPath::Mmap).unwrap()
+    }
+
+    #[test]
+    fn test_pick_smallest_window() {
+        let dir = empty_test_dir("compaction_pick_smallest_window");
+        let tables = vec![
+            table(&dir, 1, &[(1, 0.0), (2, 0.0), (3, 0.0), (4, 0.0)]),
+            table(&dir, 2, &[(1, 0.0)]),
+            table(&dir, 3, &[(1, 0.0)]),
+            table(&dir, 4, &[(1, 0.0), (2, 0.0)]),
+        ];
+        let none = BTreeSet::new();
+        assert_eq!(pick(&tables, 2, CompactionStyle::Tiered, &none), Some(1..3));
+        assert_eq!(pick(&tables, 4, CompactionStyle::Tiered, &none), Some(0..4));
+        assert_eq!(pick(&tables, 5, CompactionStyle::Tiered, &none), None);
+        assert_eq!(pick(&tables, 0, CompactionStyle::Tiered, &none), None);
+        // a running compaction's inputs can't be picked again
+        assert_eq!(pick(&tables, 2, CompactionStyle::Tiered, &BTreeSet::from([2])), Some(2..4));
+    }
+
+    #[test]
+    fn test_pick_counts_runs() {
+        let dir = empty_test_dir("compaction_pick_counts_runs");
+        let mut tables = vec![
+            table(&dir, 1, &[(1, 0.0)]),
+            table(&dir, 2, &[(1, 0.0), (2, 0.0)]),
+            table(&dir, 3, &[(3, 0.0), (4, 0.0)]),
+            table(&dir, 4, &[(1, 0.0)]),
+        ];
+        tables[2].continues_run = true;
+        assert_eq!(runs(&tables), vec![0..1, 1..3, 3..4]);
+        let none = BTreeSet::new();
+        assert_eq!(pick(&tables, 2, CompactionStyle::Tiered, &none), Some(0..3));
+        assert_eq!(pick(&tables, 3, CompactionStyle::Tiered, &none), Some(0..4));
+        assert_eq!(pick(&tables, 4, CompactionStyle::Tiered, &none), None);
+        // a run is picked whole or not at all
+        assert_eq!(pick(&tables, 2, CompactionStyle::Tiered, &BTreeSet::from([1])), Some(1..4));
+        assert_eq!(pick(&tables, 2, CompactionStyle::Tiered, &BTreeSet::from([3])), None);
+    }
+
+    #[test]
+    fn test_pick_universal() {
+        let all = |_: &Range<usize>| true;
+        let pick = |bytes: &[u64], trigger| pick_universal(bytes, trigger, 20, 200, &all);
+        assert_eq!(pick(&[100, 10, 10], 4), None);
+        // the newer runs have grown to twice the oldest
+        assert_eq!(pick(&[100, 100, 60, 40], 3), Some(0..4));
+        // 10 and 11 merge, 30 is more than 20% bigger than them
+        assert_eq!(pick(&[1000, 30, 11, 10], 4), Some(2..4));
+        assert_eq!(pick(&[1000, 24, 11, 10], 4), Some(1..4));
+        // no ratio fits, so just enough of the newest runs to get under the trigger
+        assert_eq!(pick(&[1000, 300, 40, 5], 4), Some(2..4));
+        assert_eq!(pick(&[1000, 300, 40, 5], 3), Some(1..4));
+        // a window holding a running compaction's input is passed over
+        let busy = |window: &Range<usize>| window.end < 4;
+        assert_eq!(pick_universal(&[1000, 30, 11, 10], 4, 20, 200, &busy), None);
+        assert_eq!(pick_universal(&[1000, 100, 90, 1], 2, 20, 200, &busy), Some(1..3));
+    }
+
+    #[test]
+    fn test_pick_leveled() {
+        let all = |_: &Range<usize>| true;
+        assert_eq!(pick_leveled(&[1000], 10, &all), None);
+        // each level holds over ten times the bytes of all the newer ones
+        assert_eq!(pick_leveled(&[1000, 90, 8], 10, &all), None);
+        // 11 bytes over the newest level's 10 merge into it, and 101 over the next into it
+        assert_eq!(pick_leveled(&[1000, 10, 6, 5], 10, &all), Some(1..4));
+        assert_eq!(pick_leveled(&[1000, 100, 60, 41], 10, &all), Some(0..4));
+        assert_eq!(pick_leveled(&[1000, 100, 10, 2], 10, &|window| window.start != 0), Some(1..4));
+    }
+
+    #[test]
+    fn test_pick_maps_runs_to_tables() {
+        let dir = empty_test_dir("compaction_pick_maps_runs_to_tables");
+        let many: Vec<(u64, f64)> = (0..100).map(|k| (k, 0.0)).collect();
+        let mut tables = vec![table(&dir, 1, &many), table(&dir, 2, &many), table(&dir, 3, &many[..1]), table(&dir, 4, &many[..1])];
+        tables[1].continues_run = true;
+        let none = BTreeSet::new();
+        let universal = CompactionStyle::Universal { size_ratio_percent: 1, max_space_amplification_percent: 200 };
+        assert_eq!(pick(&tables, 3, universal, &none), Some(2..4));
+        assert_eq!(pick(&tables, 2, CompactionStyle::Leveled { fanout: 2 }, &none), Some(2..4));
+        // the tables of a run go together
+        assert_eq!(pick(&tables, 2, CompactionStyle::Leveled { fanout: 1000 }, &none), Some(0..4));
+        assert_eq!(pick(&tables, 0, CompactionStyle::Leveled { fanout: 2 }, &none), None);
+    }
+
+    #[test]
+    fn test_split_points() {
+        let dir = empty_test_dir("compaction_split_points");
+        let evens: Vec<(u64, f64)> = (0..50).map(|k| (k * 2, 0.0)).collect();
+        let inputs = vec![table(&dir, 1, &evens), table(&dir, 2, &evens[..10])];
+        assert_eq!(split_points(&inputs, 4, 10).unwrap(), vec![24, 50, 74]);
+        // each range keeps at least `min_entries` keys
+        assert_eq!(split_points(&inputs, 4, 20).unwrap(), vec![50]);
+        assert!(split_points(&inputs, 4, 60).unwrap().is_empty());
+        assert!(split_points(&inputs, 1, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_hints() {
+        let hints = ReadHints::default();
+        let hint = |range: Range<u64>, tables: &[u64]| ReadHint { range, tables: tables.iter().copied().collect() };
+        hints.record(hint(10..20, &[1]));
+        hints.record(hint(30..40, &[2]));
+        // overlapping ones are folded together, touching ones aren't
+        hints.record(hint(15..25, &[3]));
+        hints.record(hint(40..50, &[2]));
+        assert_eq!(hints.pending(), vec![hint(30..40, &[2]), hint(10..25, &[1, 3]), hint(40..50, &[2])]);
+
+        let taken = hints.take(|h| match h.range.start {
+            30 => HintClaim::Drop,
+            10 => HintClaim::Keep,
+            _ => HintClaim::Take(h.range.clone()),
+        });
+        assert_eq!(taken, Some(40..50));
+        assert_eq!(hints.pending(), vec![hint(10..25, &[1, 3])]);
+        assert_eq!(hints.take(|_| HintClaim::<()>::Keep), None);
+
+        for start in 0..MAX_READ_HINTS as u64 + 1 {
+            hints.record(hint(100 + 2 * start..101 + 2 * start, &[]));
+        }
+        // the oldest go first, the one left above and then the first of these
+        assert_eq!(hints.pending().len(), MAX_READ_HINTS);
+        assert_eq!(hints.pending()[0].range, 102..103);
+    }
+
+    #[test]
+    fn test_merge_within_bounds() {
+        let dir = empty_test_dir("compaction_merge_within_bounds");
+        let older = tombstoned(&dir, 1, &[(1, 1.0), (5, 1.0), (9, 1.0), (12, 1.0)], &[12]);
+        let path = dir.join("2.sdb");
+        let entries = BTreeMap::from([(6, Vector::new(6, vec![2.0]))]);
+        let deleted = 4..11;
+        let mut buf = BufWriter::new(File::create(&path).unwrap());
+        sstable::write_table(&mut buf, entries.iter(), &BTreeSet::new(), std::slice::from_ref(&deleted), &TableFormat::default()).unwrap();
+        drop(buf);
+        let newer = SSTable::open(&path, 2, ReadPath::Mmap).unwrap();
+        let inputs = vec![older, newer];
+
+        // the two halves between them hold what merging everything does
+        let low = merge(&inputs, (Bound::Unbounded, Bound::Excluded(8)), None, None).unwrap();
+        let high = merge(&inputs, (Bound::Included(8), Bound::Unbounded), None, None).unwrap();
+        assert_eq!(low.entries.keys().copied().collect::<Vec<_>>(), vec![1, 6]);
+        let (low_part, high_part) = (deleted.start..8, 8..deleted.end);
+        assert_eq!((low.range_tombstones, low.tombstones), (vec![low_part], BTreeSet::new()));
+        assert!(high.entries.is_empty());
+        assert_eq!((high.range_tombstones, high.tombstones), (vec![high_part], BTreeSet::from([12])));
+        assert_eq!(clip(&(4..11), (Bound::Excluded(3), Bound::Included(20))), 4..11);
+        assert!(clip(&(4..11), (Bound::Included(11), Bound::Unbounded)).is_empty());
+    }
+
+    #[test]
+    fn test_merge_newest_wins() {
+        let dir = empty_test_dir("compaction_merge_newest_wins");
+        let inputs = vec![
+            tombstoned(&dir, 1, &[(1, 1.0), (2, 1.0), (3, 1.0)], &[3]),
+            tombstoned(&dir, 2, &[(2, 2.0), (4, 2.0), (5, 2.0), (9, 2.0)], &[5, 9]),
+            table(&dir, 3, &[(9, 3.0)]),
+        ];
+        let merged = merge(&inputs, ALL, None, None).unwrap();
+        let entries: Vec<(u64, f64)> = merged.entries.iter().map(|(&k, v)| (k, v.data()[0])).collect();
+        assert_eq!(entries, vec![(1, 1.0), (2, 2.0), (4, 2.0), (9, 3.0)]);
+        // 9 was written again after its delete, so only 3 and 5 still mask older tables
+        assert_eq!(merged.tombstones, BTreeSet::from([3, 5]));
+        assert!(merge(&inputs, ALL, Some(0), None).unwrap().tombstones.is_empty());
+    }
+
+    #[test]
+    fn test_merge_drops_expired() {
+        let dir = empty_test_dir("compaction_merge_drops_expired");
+        let path = dir.join("1.sdb");
+        let mut expired = Vector::new(1, vec![1.0]);
+        expired.set_expires_at(100);
+        let mut live = Vector::new(2, vec![2.0]);
+        live.set_expires_at(300);
+        let entries = BTreeMap::from([(1, expired), (2, live)]);
+        let mut buf = BufWriter::new(File::create(&path).unwrap());
+        sstable::write_table(&mut buf, entries.iter(), &BTreeSet::new(), &[], &TableFormat::default()).unwrap();
+        drop(buf);
+        let inputs = vec![SSTable::open(&path, 1, ReadPath::Mmap).unwrap()];
+
+        assert_eq!(merge(&inputs, ALL, None, None).unwrap().entries.len(), 2);
+        let merged = merge(&inputs, ALL, Some(200), None).unwrap();
+        assert_eq!(merged.entries.keys().copied().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_merge_applies_range_tombstones() {
+        let dir = empty_test_dir("compaction_merge_applies_range_tombstones");
+        let older = table(&dir, 1, &[(1, 1.0), (5, 1.0), (9, 1.0)]);
+        let path = dir.join("2.sdb");
+        let entries = BTreeMap::from([(6, Vector::new(6, vec![2.0]))]);
+        let deleted = 4..8;
+        let mut buf = BufWriter::new(File::create(&path).unwrap());
+        sstable::write_table(&mut buf, entries.iter(), &BTreeSet::new(), std::slice::from_ref(&deleted), &TableFormat::default()).unwrap();
+        drop(buf);
+        let newer = SSTable::open(&path, 2, ReadPath::Mmap).unwrap();
+        let inputs = vec![older, newer];
+
+        // the newer table's own entry survives its range tombstone
+        let merged = merge(&inputs, ALL, None, None).unwrap();
+        assert_eq!(merged.entries.keys().copied().collect::<Vec<_>>(), vec![1, 6, 9]);
+        assert_eq!(merged.range_tombstones, [deleted]);
+        assert!(merge(&inputs, ALL, Some(0), None).unwrap().range_tombstones.is_empty());
+    }
+
+    #[test]
+    fn test_drop_unneeded_tombstones() {
+        let dir = empty_test_dir("compaction_drop_unneeded_tombstones");
+        let older = [table(&dir, 1, &[(1, 1.0), (20, 1.0)])];
+        let mut merged = Merged {
+            entries: BTreeMap::new(),
+            tombstones: BTreeSet::from([1, 2]),
+            range_tombstones: vec![0..10, 10..20, 15..25],
+        };
+        drop_unneeded_tombstones(&mut merged, &older);
+        assert_eq!(merged.tombstones, BTreeSet::from([1]));
+        assert_eq!(merged.range_tombstones, vec![0..10, 15..25]);
+
+        drop_unneeded_tombstones(&mut merged, &[]);
+        assert!(merged.tombstones.is_empty() && merged.range_tombstones.is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use crate::db::lsm::LSMTree;
+use crate::db::options::Options;
+use crate::db::platform;
+
+// Options read from a TOML file, so a server can be tuned by editing the file rather than
+// rebuilding it, and retuned while running with `LSMTree::reload_config`:
+//
+//     # pace an ingest job
+//     write_bytes_per_sec = 50_000_000
+//     compaction_bytes_per_sec = 20_000_000
+//     block_cache_bytes = 1_073_741_824
+//     paranoid_checks = true
+//     thread_name_prefix = "vectors"
+//
+// Only top-level keys naming one of the integer, bool or string fields of `Options` are
+// taken; tables, arrays, floats and negative numbers are refused with the line they're on.
+
+// The keys a reload changes while the tree is open, through `set_write_rate_limit` and
+// `set_compaction_rate_limit`; the others take effect when the tree is reopened
+const RUNTIME_KEYS: [&str; 3] = ["write_bytes_per_sec", "write_ops_per_sec", "compaction_bytes_per_sec"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    Integer(u64),
+    Bool(bool),
+    String(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    // in the order the file has them
+    values: Vec<(String, ConfigValue)>,
+}
+
+// What `LSMTree::reload_config` did with a configuration
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigReload {
+    // keys whose new values the tree now runs with
+    pub applied: Vec<String>,
+    // keys whose values differ from the ones the tree was opened with, and that only take
+    // effect once it's reopened
+    pub needs_restart: Vec<String>,
+}
+
+impl ConfigReload {
+    pub fn is_unchanged(&self) -> bool {
+        self.applied.is_empty() && self.needs_restart.is_empty()
+    }
+}
+
+impl fmt::Display for ConfigReload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_unchanged() {
+            return write!(f, "configuration unchanged");
+        }
+        let mut parts = Vec::new();
+        if !self.applied.is_empty() {
+            parts.push(format!("applied {}", self.applied.join(", ")));
+        }
+        if !self.needs_restart.is_empty() {
+            parts.push(format!("restart required for {}", self.needs_restart.join(", ")));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path)?;
+        Config::parse(&text).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    // Fails with InvalidData on anything but `key = value` lines, comments and blank
+    // lines, and on keys `Options` has no field of that type for
+    pub fn parse(text: &str) -> io::Result<Config> {
+        let mut config = Config::default();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, msg));
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                return Err(invalid("tables aren't supported, options are top-level keys".to_string()));
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(invalid(format!("'{}' is not key = value", line)));
+            };
+            let key = key.trim().to_string();
+            let value = parse_value(value.trim()).map_err(invalid)?;
+            if config.get(&key).is_some() {
+                return Err(invalid(format!("'{}' is set twice", key)));
+            }
+            let mut options = Options::default();
+            set(&mut options, &key, &value).map_err(invalid)?;
+            config.values.push((key, value));
+        }
+        Ok(config)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ConfigValue> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.iter().map(|(k, _)| k.as_str())
+    }
+
+    // Overrides the fields of `options` the configuration sets, leaving the others be
+    pub fn apply(&self, options: &mut Options) {
+        for (key, value) in &self.values {
+            // every key was checked against `Options` by `parse`
+            set(options, key, value).expect("configuration key was validated when parsed");
+        }
+    }
+
+    // Whether a reload changes `key` in a running tree, see `RUNTIME_KEYS`
+    pub fn is_runtime_key(key: &str) -> bool {
+        RUNTIME_KEYS.contains(&key)
+    }
+}
+
+// Reloads the file at `path` into `lsm` whenever the process receives SIGHUP, on platforms
+// with signals, or the file's modification time changes, checked every `interval`. What
+// each reload did, or why the file couldn't be read or parsed, is handed to `report`, for
+// a server to log. Runs on an `lsm-config` thread until the watcher is dropped.
+pub fn watch_config<F>(path: &Path, lsm: Arc<LSMTree>, interval: Duration, report: F) -> io::Result<ConfigWatcher>
+where
+    F: Fn(io::Result<ConfigReload>) + Send + 'static,
+{
+    platform::catch_hangups();
+    let path = path.to_path_buf();
+    let (stop, stopped) = mpsc::channel::<()>();
+    let mut seen = (platform::hangups(), modified(&path));
+    let thread = thread::Builder::new().name("lsm-config".to_string()).spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            let now = (platform::hangups(), modified(&path));
+            if now == seen {
+                continue;
+            }
+            seen = now;
+            report(Config::load(&path).map(|config| lsm.reload_config(&config)));
+        }
+    })?;
+    Ok(ConfigWatcher { stop: Some(stop), thread: Some(thread) })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Stops watching when dropped, after a reload in progress finishes
+pub struct ConfigWatcher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        // hanging up the channel wakes the thread
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The value `key` has in `options`, None for a key that isn't an option
+pub(crate) fn get(options: &Options, key: &str) -> Option<ConfigValue> {
+    Some(match field(&mut options.clone(), key)? {
+        Field::Usize(v) => ConfigValue::Integer(*v as u64),
+        Field::U64(v) => ConfigValue::Integer(*v),
+        Field::U32(v) => ConfigValue::Integer(*v as u64),
+        Field::Bool(v) => ConfigValue::Bool(*v),
+        Field::String(v) => ConfigValue::String(v.clone()),
+    })
+}
+
+fn set(options: &mut Options, key: &str, value: &ConfigValue) -> Result<(), String> {
+    let Some(field) = field(options, key) else {
+        return Err(format!("'{}' is not an option", key));
+    };
+    let out_of_range = |n: u64| format!("{} is out of range for '{}'", n, key);
+    match (field, value) {
+        (Field::Usize(f), &ConfigValue::Integer(n)) => *f = usize::try_from(n).map_err(|_| out_of_range(n))?,
+        (Field::U64(f), &ConfigValue::Integer(n)) => *f = n,
+        (Field::U32(f), &ConfigValue::Integer(n)) => *f = u32::try_from(n).map_err(|_| out_of_range(n))?,
+        (Field::Bool(f), &ConfigValue::Bool(b)) => *f = b,
+        (Field::String(f), ConfigValue::String(s)) => *f = s.clone(),
+        (field, _) => return Err(format!("'{}' takes {}", key, field.kind())),
+    }
+    Ok(())
+}
+
+enum Field<'a> {
+    Usize(&'a mut usize),
+    U64(&'a mut u64),
+    U32(&'a mut u32),
+    Bool(&'a mut bool),
+    String(&'a mut String),
+}
+
+impl Field<'_> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Field::Usize(_) | Field::U64(_) | Field::U32(_) => "an integer",
+            Field::Bool(_) => "true or false",
+            Field::String(_) => "a string",
+        }
+    }
+}
+
+// The options a file can set: the ones plain values describe
+fn field<'a>(options: &'a mut Options, key: &str) -> Option<Field<'a>> {
+    Some(match key {
+        "create_if_missing" => Field::Bool(&mut options.create_if_missing),
+        "error_if_exists" => Field::Bool(&mut options.error_if_exists),
+        "sstable_size" => Field::Usize(&mut options.sstable_size),
+        "memtable_bytes" => Field::Usize(&mut options.memtable_bytes),
+        "flush_interval_millis" => Field::U64(&mut options.flush_interval_millis),
+        "compaction_trigger" => Field::Usize(&mut options.compaction_trigger),
+        "read_compaction_skips" => Field::Usize(&mut options.read_compaction_skips),
+        "compaction_bytes_per_sec" => Field::U64(&mut options.compaction_bytes_per_sec),
+        "write_bytes_per_sec" => Field::U64(&mut options.write_bytes_per_sec),
+        "write_ops_per_sec" => Field::U64(&mut options.write_ops_per_sec),
+        "min_free_bytes" => Field::U64(&mut options.min_free_bytes),
+        "min_open_files" => Field::U64(&mut options.min_open_files),
+        "max_scan_bytes" => Field::Usize(&mut options.max_scan_bytes),
+        "bulk_run_size" => Field::Usize(&mut options.bulk_run_size),
+        "max_flush_threads" => Field::Usize(&mut options.max_flush_threads),
+        "max_compaction_threads" => Field::Usize(&mut options.max_compaction_threads),
+        "max_subcompactions" => Field::Usize(&mut options.max_subcompactions),
+        "index_build_threads" => Field::Usize(&mut options.index_build_threads),
+        "search_threads" => Field::Usize(&mut options.search_threads),
+        "thread_name_prefix" => Field::String(&mut options.thread_name_prefix),
+        "index_queue" => Field::Bool(&mut options.index_queue),
+        "slowdown_immutables" => Field::Usize(&mut options.slowdown_immutables),
+        "stop_immutables" => Field::Usize(&mut options.stop_immutables),
+        "slowdown_tables" => Field::Usize(&mut options.slowdown_tables),
+        "stop_tables" => Field::Usize(&mut options.stop_tables),
+        "slowdown_write_micros" => Field::U64(&mut options.slowdown_write_micros),
+        "commit_window_micros" => Field::U64(&mut options.commit_window_micros),
+        "retained_wals" => Field::Usize(&mut options.retained_wals),
+        "history_retention_millis" => Field::U64(&mut options.history_retention_millis),
+        "max_value_bytes" => Field::Usize(&mut options.max_value_bytes),
+        "max_dimension" => Field::Usize(&mut options.max_dimension),
+        "fix_dimension" => Field::Bool(&mut options.fix_dimension),
+        "max_payload_bytes" => Field::Usize(&mut options.max_payload_bytes),
+        "prefix_bloom_bits" => Field::U32(&mut options.prefix_bloom_bits),
+        "bloom_bits_per_key" => Field::Usize(&mut options.bloom_bits_per_key),
+        "direct_io_writes" => Field::Bool(&mut options.direct_io_writes),
+        "index_partition_entries" => Field::Usize(&mut options.index_partition_entries),
+        "index_restart_interval" => Field::Usize(&mut options.index_restart_interval),
+        "columnar_block_vectors" => Field::Usize(&mut options.columnar_block_vectors),
+        "dedup_vectors" => Field::Bool(&mut options.dedup_vectors),
+        "pq_codes" => Field::Bool(&mut options.pq_codes),
+        "access_hints" => Field::Bool(&mut options.access_hints),
+        "block_cache_bytes" => Field::Usize(&mut options.block_cache_bytes),
+        "row_cache_entries" => Field::Usize(&mut options.row_cache_entries),
+        "memory_budget_bytes" => Field::Usize(&mut options.memory_budget_bytes),
+        "paranoid_checks" => Field::Bool(&mut options.paranoid_checks),
+        "latency_histograms" => Field::Bool(&mut options.latency_histograms),
+        _ => return None,
+    })
+}
+
+// The line up to a `#` outside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Result<ConfigValue, String> {
+    match value {
+        "true" => return Ok(ConfigValue::Bool(true)),
+        "false" => return Ok(ConfigValue::Bool(false)),
+        _ => {}
+    }
+    if let Some(quoted) = value.strip_prefix('"') {
+        return parse_string(quoted).map(ConfigValue::String);
+    }
+    // TOML allows underscores between digits
+    let digits = value.strip_prefix('+').unwrap_or(value);
+    if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit() || b == b'_') && !digits.starts_with('_') && !digits.ends_with('_') && !digits.contains("__") {
+        return digits.replace('_', "").parse().map(ConfigValue::Integer).map_err(|_| format!("{} is too large", value));
+    }
+    Err(format!("'{}' is not an unsigned integer, true, false or a \"string\"", value))
+}
+
+// A basic string's contents and escapes, given what follows its opening quote
+fn parse_string(quoted: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                return match chars.as_str().trim() {
+                    "" => Ok(out),
+                    rest => Err(format!("unexpected '{}' after the string", rest)),
+                };
+            }
+            '\\' => out.push(match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('t') => '\t',
+                other => return Err(format!("unsupported escape '\\{}'", other.map(String::from).unwrap_or_default())),
+            }),
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::{empty_test_dir, test_dir};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(r#"
+            # tuning for the ingest job
+            write_bytes_per_sec = 50_000_000   # bytes
+            paranoid_checks = true
+            thread_name_prefix = "vec # \"a\""
+            sstable_size = +1000
+        "#).unwrap();
+        assert_eq!(config.keys().collect::<Vec<_>>(), vec!["write_bytes_per_sec", "paranoid_checks", "thread_name_prefix", "sstable_size"]);
+        assert_eq!(config.get("write_bytes_per_sec"), Some(&ConfigValue::Integer(50_000_000)));
+        assert_eq!(config.get("thread_name_prefix"), Some(&ConfigValue::String("vec # \"a\"".to_string())));
+        assert_eq!(config.get("row_cache_entries"), None);
+
+        let mut options = Options::default();
+        config.apply(&mut options);
+        assert_eq!(options.write_bytes_per_sec, 50_000_000);
+        assert!(options.paranoid_checks);
+        assert_eq!(options.thread_name_prefix, "vec # \"a\"");
+        assert_eq!(options.sstable_size, 1000);
+        assert_eq!(options.compaction_trigger, Options::default().compaction_trigger);
+        assert_eq!(get(&options, "sstable_size"), Some(ConfigValue::Integer(1000)));
+        assert_eq!(get(&options, "hnsw"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (text, error) in [
+            ("[tree]\nsstable_size = 10", "line 1: tables aren't supported"),
+            ("sstable_size = 10\nsstable_size", "line 2: 'sstable_size' is not key = value"),
+            ("hnsw = true", "line 1: 'hnsw' is not an option"),
+            ("sstable_size = true", "line 1: 'sstable_size' takes an integer"),
+            ("paranoid_checks = 1", "line 1: 'paranoid_checks' takes true or false"),
+            ("sstable_size = -1", "line 1: '-1' is not an unsigned integer"),
+            ("sstable_size = 1.5", "line 1: '1.5' is not an unsigned integer"),
+            ("prefix_bloom_bits = 5_000_000_000", "line 1: 5000000000 is out of range for 'prefix_bloom_bits'"),
+            ("sstable_size = 1\nsstable_size = 2", "line 2: 'sstable_size' is set twice"),
+            ("thread_name_prefix = \"lsm", "line 1: unterminated string"),
+            ("thread_name_prefix = \"lsm\" x", "line 1: unexpected 'x' after the string"),
+        ] {
+            let e = Config::parse(text).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().starts_with(error), "{}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let path = empty_test_dir("config_load").join("lsm.toml");
+        fs::write(&path, "sstable_size = 20\n").unwrap();
+        assert_eq!(Config::load(&path).unwrap().get("sstable_size"), Some(&ConfigValue::Integer(20)));
+        fs::write(&path, "sstable_size = x\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().to_string().contains("lsm.toml: line 1"));
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let lsm = LSMTree::new(&test_dir("config_reload")).unwrap();
+        let config = Config::parse("write_bytes_per_sec = 1000\ncompaction_bytes_per_sec = 500\nsstable_size = 10\nblock_cache_bytes = 4096").unwrap();
+        let reload = lsm.reload_config(&config);
+        // sstable_size is what the tree already has
+        assert_eq!(reload.applied, vec!["write_bytes_per_sec", "compaction_bytes_per_sec"]);
+        assert_eq!(reload.needs_restart, vec!["block_cache_bytes"]);
+        assert_eq!(reload.to_string(), "applied write_bytes_per_sec, compaction_bytes_per_sec; restart required for block_cache_bytes");
+        assert_eq!(lsm.write_rate_limit(), (1000, 0));
+        assert_eq!(lsm.compaction_rate_limit(), 500);
+
+        let reload = lsm.reload_config(&Config::parse("write_bytes_per_sec = 1000").unwrap());
+        assert!(reload.is_unchanged());
+        assert_eq!(reload.to_string(), "configuration unchanged");
+        lsm.reload_config(&Config::parse("write_bytes_per_sec = 0").unwrap());
+        assert_eq!(lsm.write_rate_limit(), (0, 0));
+    }
+
+    #[test]
+    fn test_watch_config() {
+        let dir = empty_test_dir("config_watch");
+        let path = dir.join("lsm.toml");
+        fs::write(&path, "write_ops_per_sec = 100\n").unwrap();
+        let lsm = Arc::new(LSMTree::new(&dir.join("tree")).unwrap());
+        let (sender, reports) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let watcher = watch_config(&path, Arc::clone(&lsm), Duration::from_millis(10), move |reload| {
+            sender.lock().unwrap().send(reload.map_err(|e| e.to_string())).unwrap();
+        }).unwrap();
+        let next = || reports.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // SIGHUP reloads the file as it is, even unchanged
+        #[cfg(unix)]
+        {
+            unsafe { libc::raise(libc::SIGHUP) };
+            assert_eq!(next().unwrap().applied, vec!["write_ops_per_sec"]);
+            assert_eq!(lsm.write_rate_limit(), (0, 100));
+        }
+
+        // so does editing it, and a file that doesn't parse leaves the tree as it was.
+        // Edits are renamed into place so the watcher never reads one half written.
+        let edit = |text: &str| {
+            let before = modified(&path);
+            while modified(&path) == before {
+                thread::sleep(Duration::from_millis(10));
+                fs::write(dir.join("lsm.toml.tmp"), text).unwrap();
+                fs::rename(dir.join("lsm.toml.tmp"), &path).unwrap();
+            }
+        };
+        edit("write_ops_per_sec = 200\nsstable_size = 99\n");
+        let reload = next().unwrap();
+        assert_eq!((reload.applied, reload.needs_restart), (vec!["write_ops_per_sec".to_string()], vec!["sstable_size".to_string()]));
+        assert_eq!(lsm.write_rate_limit(), (0, 200));
+        edit("write_ops_per_sec = lots\n");
+        assert!(next().unwrap_err().contains("line 1"));
+        assert_eq!(lsm.write_rate_limit(), (0, 200));
+        drop(watcher);
+    }
+}
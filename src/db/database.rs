@@ -0,0 +1,492 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::db::error::LsmError;
+use crate::db::index::hnsw::HnswOptions;
+use crate::db::lsm::LSMTree;
+use crate::db::metrics;
+use crate::db::options::Options;
+use crate::db::pipeline::{Pipeline, Transform};
+use crate::db::platform;
+use crate::db::search::DistanceMetric;
+use crate::db::vector::ElementType;
+
+pub const COLLECTIONS_DIR: &str = "collections";
+pub const COLLECTION_OPTIONS_FILE: &str = "OPTIONS";
+const OPTIONS_MAGIC: [u8; 8] = *b"LSMCOLL1";
+// drops used to rename the collection to this before deleting it
+const DROPPED_SUFFIX: &str = ".dropped";
+const MAX_NAME_LEN: usize = 64;
+
+// The options a collection keeps across opens. Everything else comes from the
+// database's base options.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollectionOptions {
+    pub sstable_size: usize,
+    pub compaction_trigger: usize,
+    pub max_dimension: usize,
+    // every vector and query must have this many dimensions, 0 to take it from the
+    // first insert
+    pub dimension: usize,
+    pub hnsw: Option<HnswOptions>,
+    // fixed when the collection is created; `LSMTree::set_query_metrics` declares others
+    // a query may rank by
+    pub metric: DistanceMetric,
+    pub element_type: ElementType,
+    // scale every vector and query to unit length, refusing zero vectors; searches by
+    // cosine distance then skip computing norms
+    pub normalize: bool,
+    // tables store product quantization codes for `LSMTree::pq_search`, see
+    // `Options::pq_codes`
+    pub pq_codes: bool,
+}
+
+impl Default for CollectionOptions {
+    fn default() -> CollectionOptions {
+        let options = Options::default();
+        CollectionOptions {
+            sstable_size: options.sstable_size,
+            compaction_trigger: options.compaction_trigger,
+            max_dimension: options.max_dimension,
+            dimension: 0,
+            hnsw: options.hnsw,
+            metric: DistanceMetric::default(),
+            element_type: ElementType::default(),
+            normalize: false,
+            pq_codes: options.pq_codes,
+        }
+    }
+}
+
+impl CollectionOptions {
+    fn apply(&self, base: &Options) -> Options {
+        Options {
+            sstable_size: self.sstable_size,
+            compaction_trigger: self.compaction_trigger,
+            max_dimension: self.max_dimension,
+            fix_dimension: true,
+            hnsw: self.hnsw,
+            pq_codes: self.pq_codes,
+            ..base.clone()
+        }
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&OPTIONS_MAGIC)?;
+        out.write_u64::<LittleEndian>(self.sstable_size as u64)?;
+        out.write_u64::<LittleEndian>(self.compaction_trigger as u64)?;
+        out.write_u64::<LittleEndian>(self.max_dimension as u64)?;
+        out.write_u8(self.metric.to_u8())?;
+        match self.hnsw {
+            Some(hnsw) => {
+                out.write_u8(1)?;
+                out.write_u64::<LittleEndian>(hnsw.m as u64)?;
+                out.write_u64::<LittleEndian>(hnsw.ef_construction as u64)?;
+            }
+            None => out.write_u8(0)?,
+        }
+        out.write_u8(self.element_type.to_u8())?;
+        out.write_u8(self.normalize as u8)?;
+        out.write_u64::<LittleEndian>(self.dimension as u64)?;
+        out.write_u8(self.pq_codes as u8)?;
+        Ok(())
+    }
+
+    fn read<R: Read>(input: &mut R) -> io::Result<CollectionOptions> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if magic != OPTIONS_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad magic number, not a collection options file"));
+        }
+        let sstable_size = input.read_u64::<LittleEndian>()? as usize;
+        let compaction_trigger = input.read_u64::<LittleEndian>()? as usize;
+        let max_dimension = input.read_u64::<LittleEndian>()? as usize;
+        let metric = DistanceMetric::from_u8(input.read_u8()?)?;
+        let hnsw = match input.read_u8()? {
+            0 => None,
+            _ => {
+                let m = input.read_u64::<LittleEndian>()? as usize;
+                Some(HnswOptions { m, ef_construction: input.read_u64::<LittleEndian>()? as usize })
+            }
+        };
+        // files from before element types, normalizing, dimensions or codes end before them
+        let element_type = match read_optional_u8(input)? {
+            Some(tag) => ElementType::from_u8(tag)?,
+            None => ElementType::F64,
+        };
+        let normalize = read_optional_u8(input)?.is_some_and(|normalize| normalize != 0);
+        let dimension = match input.read_u64::<LittleEndian>() {
+            Ok(dimension) => dimension as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e),
+        };
+        let pq_codes = read_optional_u8(input)?.is_some_and(|pq_codes| pq_codes != 0);
+        Ok(CollectionOptions { sstable_size, compaction_trigger, max_dimension, dimension, hnsw, metric, element_type, normalize, pq_codes })
+    }
+}
+
+fn read_optional_u8<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    match input.read_u8() {
+        Ok(byte) => Ok(Some(byte)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Named collections under one directory, each an independent tree in
+// `collections/<name>` with its own options, dimensions and metric. A collection is
+// opened on first use and stays open until it is dropped or the database is.
+//
+// A collection's options file is its entry in the catalog: a create writes it last, once
+// the tree is complete, and a drop removes it first, so a crash either way leaves a
+// directory without one, which the next open deletes.
+pub struct Database {
+    directory: PathBuf,
+    base: Options,
+    open: Mutex<BTreeMap<String, Arc<LSMTree>>>,
+    // collections dropped while handles to them were held, deleted once the last goes
+    dropping: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Database {
+    pub fn open(directory: &Path, base: Options) -> Result<Database, LsmError> {
+        let collections = directory.join(COLLECTIONS_DIR);
+        std::fs::create_dir_all(&collections)?;
+        // finish drops and creates a crash interrupted, or that waited on handles
+        for entry in std::fs::read_dir(&collections)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.ends_with(DROPPED_SUFFIX) || (path.is_dir() && !path.join(COLLECTION_OPTIONS_FILE).exists()) {
+                std::fs::remove_dir_all(&path)?;
+            }
+        }
+        Ok(Database { directory: directory.to_path_buf(), base, open: Mutex::new(BTreeMap::new()), dropping: Arc::default() })
+    }
+
+    pub fn create_collection(&self, name: &str, options: CollectionOptions) -> Result<Arc<LSMTree>, LsmError> {
+        check_name(name)?;
+        let mut open = self.open.lock().unwrap();
+        let path = self.prepare_collection_path(name)?;
+        let tree = LSMTree::open(&path, options.apply(&self.base))?;
+        tree.set_metric(options.metric)?;
+        tree.set_element_type(options.element_type)?;
+        if options.dimension != 0 {
+            tree.set_dimension(options.dimension)?;
+        }
+        if options.normalize {
+            tree.set_pipeline(Pipeline::new(vec![Transform::Normalize])?)?;
+        }
+        self.complete_collection(&path, &options)?;
+
+        let tree = Arc::new(tree);
+        open.insert(name.to_string(), tree.clone());
+        Ok(tree)
+    }
+
+    // A new collection holding what `source` holds now, with its options, see
+    // `LSMTree::checkpoint`. Tables are hard linked rather than copied where the
+    // filesystem allows, so a large collection clones in about the time it takes to link
+    // its files. Tables are never written to once complete, so each collection goes on
+    // writing tables of its own, and the space of a shared one is freed once neither
+    // holds it any more.
+    pub fn clone_collection(&self, source: &str, destination: &str) -> Result<Arc<LSMTree>, LsmError> {
+        check_name(source)?;
+        check_name(destination)?;
+        let mut open = self.open.lock().unwrap();
+        let tree = self.open_collection(&mut open, source)?;
+        let options = self.collection_options(source)?;
+        let path = self.prepare_collection_path(destination)?;
+        tree.checkpoint(&path)?;
+        self.complete_collection(&path, &options)?;
+
+        let tree = Arc::new(LSMTree::open(&path, options.apply(&self.base))?);
+        open.insert(destination.to_string(), tree.clone());
+        Ok(tree)
+    }
+
+    pub fn collection(&self, name: &str) -> Result<Arc<LSMTree>, LsmError> {
+        check_name(name)?;
+        let mut open = self.open.lock().unwrap();
+        Ok(self.open_collection(&mut open, name)?)
+    }
+
+    fn open_collection(&self, open: &mut BTreeMap<String, Arc<LSMTree>>, name: &str) -> io::Result<Arc<LSMTree>> {
+        if let Some(tree) = open.get(name) {
+            return Ok(tree.clone());
+        }
+        let options = self.collection_options(name)?;
+        let tree = Arc::new(LSMTree::open(&self.collection_path(name), options.apply(&self.base))?);
+        open.insert(name.to_string(), tree.clone());
+        Ok(tree)
+    }
+
+    // Where a new collection goes, once it is sure the name is free
+    fn prepare_collection_path(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.collection_path(name);
+        if path.join(COLLECTION_OPTIONS_FILE).exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("collection '{}' already exists", name)));
+        }
+        if self.dropping.lock().unwrap().contains(name) {
+            return Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("collection '{}' is still being dropped", name)));
+        }
+        if path.exists() {
+            // left by a create that failed part way
+            std::fs::remove_dir_all(&path)?;
+        }
+        Ok(path)
+    }
+
+    // Writes the options file, which goes in last as it marks the collection as complete
+    fn complete_collection(&self, path: &Path, options: &CollectionOptions) -> io::Result<()> {
+        let temp_path = path.join(format!("{}.tmp", COLLECTION_OPTIONS_FILE));
+        let mut file = File::create(&temp_path)?;
+        options.write(&mut file)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path.join(COLLECTION_OPTIONS_FILE))?;
+        platform::sync_dir(path)?;
+        platform::sync_dir(&self.directory.join(COLLECTIONS_DIR))
+    }
+
+    pub fn collection_options(&self, name: &str) -> Result<CollectionOptions, LsmError> {
+        check_name(name)?;
+        match File::open(self.collection_path(name).join(COLLECTION_OPTIONS_FILE)) {
+            Ok(mut file) => Ok(CollectionOptions::read(&mut file)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(not_found(name)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Deletes the collection and everything in it. It is gone from the database at once;
+    // handles from `collection` still held elsewhere can go on reading it, but not
+    // writing, and its files are deleted once the last of them is dropped. Its name
+    // can't be taken by a new collection until then.
+    pub fn drop_collection(&self, name: &str) -> Result<(), LsmError> {
+        check_name(name)?;
+        let mut open = self.open.lock().unwrap();
+        let path = self.collection_path(name);
+        if !path.join(COLLECTION_OPTIONS_FILE).exists() {
+            return Err(not_found(name));
+        }
+        std::fs::remove_file(path.join(COLLECTION_OPTIONS_FILE))?;
+        platform::sync_dir(&path)?;
+        let Some(tree) = open.remove(name) else {
+            return Ok(std::fs::remove_dir_all(&path)?);
+        };
+        self.dropping.lock().unwrap().insert(name.to_string());
+        let (dropping, name) = (self.dropping.clone(), name.to_string());
+        tree.retire(move || {
+            // a directory left behind has no options file, so the next open deletes it
+            let _ = std::fs::remove_dir_all(&path);
+            dropping.lock().unwrap().remove(&name);
+        });
+        Ok(())
+    }
+
+    // Names of every collection, sorted
+    pub fn list_collections(&self) -> Result<Vec<String>, LsmError> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(self.directory.join(COLLECTIONS_DIR))? {
+            let path = entry?.path();
+            if path.join(COLLECTION_OPTIONS_FILE).exists() {
+                names.push(path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // Statistics of every open collection in the Prometheus text format, see
+    // `db::metrics`. Collections not opened since the database was have served nothing
+    // yet and are left out.
+    pub fn metrics(&self) -> String {
+        let open = self.open.lock().unwrap().clone();
+        metrics::prometheus(open.iter().map(|(name, tree)| (name.as_str(), tree.as_ref())))
+    }
+
+    fn collection_path(&self, name: &str) -> PathBuf {
+        self.directory.join(COLLECTIONS_DIR).join(name)
+    }
+}
+
+// Names become directory names, so they are kept to a portable set of characters
+fn check_name(name: &str) -> Result<(), LsmError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    if !valid {
+        return Err(LsmError::InvalidArgument(format!("invalid collection name '{}', use up to {} letters, digits, '_' or '-'", name, MAX_NAME_LEN)));
+    }
+    Ok(())
+}
+
+fn not_found(name: &str) -> LsmError {
+    LsmError::NotFound(format!("no collection named '{}'", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+    use crate::db::vector::Vector;
+
+    #[test]
+    fn test_collections() {
+        let path = empty_test_dir("database_collections");
+        let db = Database::open(&path, Options::default()).unwrap();
+        assert!(db.list_collections().unwrap().is_empty());
+
+        let passages = db.create_collection("passages", CollectionOptions { max_dimension: 4, ..CollectionOptions::default() }).unwrap();
+        let images_options = CollectionOptions {
+            metric: DistanceMetric::Cosine,
+            hnsw: Some(HnswOptions { m: 4, ef_construction: 16 }),
+            element_type: ElementType::F32,
+            normalize: true,
+            dimension: 8,
+            pq_codes: true,
+            ..CollectionOptions::default()
+        };
+        let images = db.create_collection("images", images_options).unwrap();
+        assert!(matches!(db.create_collection("images", CollectionOptions::default()), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists));
+        assert!(matches!(db.create_collection("../escape", CollectionOptions::default()), Err(LsmError::InvalidArgument(_))));
+
+        // the same key lives on independently in each collection
+        passages.insert(1, Vector::new(1, vec![1.0, 2.0])).unwrap();
+        images.insert(1, Vector::new(1, vec![0.5; 8])).unwrap();
+        assert!(passages.insert(2, Vector::new(2, vec![0.0; 8])).is_err());
+        assert_eq!(images.metric(), DistanceMetric::Cosine);
+        assert_eq!(images.element_type(), ElementType::F32);
+        assert!(images.is_normalized() && !passages.is_normalized());
+        assert!(matches!(images.insert(2, Vector::new(2, vec![0.0; 8])), Err(LsmError::InvalidArgument(_))));
+        assert!(Arc::ptr_eq(&db.collection("images").unwrap(), &images));
+        // passages took its dimension from the first insert
+        assert_eq!((passages.dimension(), images.dimension()), (Some(2), Some(8)));
+        assert!(matches!(passages.insert(2, Vector::new(2, vec![1.0])), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(images.knn(&[0.5; 4], 1), Err(LsmError::InvalidArgument(_))));
+        drop((passages, images));
+        drop(db);
+
+        let db = Database::open(&path, Options::default()).unwrap();
+        assert_eq!(db.list_collections().unwrap(), vec!["images", "passages"]);
+        assert_eq!(db.collection_options("images").unwrap(), images_options);
+        let images = db.collection("images").unwrap();
+        // normalized, then rounded to f32
+        let mut unit = vec![0.5 / 2f64.sqrt(); 8];
+        ElementType::F32.round(&mut unit);
+        assert_eq!(images.get(1).unwrap().unwrap().data(), &unit);
+        assert_eq!(images.search(&[0.5; 8], 1, 16).unwrap()[0].0, 1);
+        assert_eq!(db.collection("passages").unwrap().get(1).unwrap().unwrap().data(), &vec![1.0, 2.0]);
+        assert_eq!(db.collection("passages").unwrap().dimension(), Some(2));
+        assert!(matches!(db.collection("missing"), Err(LsmError::NotFound(_))));
+
+        // a collection in use is gone at once, but its files stay for the handle to read
+        db.drop_collection("images").unwrap();
+        assert_eq!(db.list_collections().unwrap(), vec!["passages"]);
+        assert!(matches!(db.collection("images"), Err(LsmError::NotFound(_))));
+        assert!(matches!(db.drop_collection("images"), Err(LsmError::NotFound(_))));
+        assert_eq!(images.get(1).unwrap().unwrap().data(), &unit);
+        assert!(matches!(images.insert(3, Vector::new(3, vec![0.5; 8])), Err(LsmError::NotFound(_))));
+        assert!(matches!(db.create_collection("images", CollectionOptions::default()), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::ResourceBusy));
+        drop(images);
+        assert!(!path.join(COLLECTIONS_DIR).join("images").exists());
+        let images = db.create_collection("images", CollectionOptions::default()).unwrap();
+        assert!(images.get(1).unwrap().is_none());
+
+        // one never opened is deleted right away
+        db.create_collection("scratch", CollectionOptions::default()).unwrap();
+        drop(db);
+        let db = Database::open(&path, Options::default()).unwrap();
+        db.drop_collection("scratch").unwrap();
+        assert!(!path.join(COLLECTIONS_DIR).join("scratch").exists());
+    }
+
+    #[test]
+    fn test_open_finishes_interrupted_operations() {
+        let path = empty_test_dir("database_open_finishes_interrupted_operations");
+        let db = Database::open(&path, Options::default()).unwrap();
+        db.create_collection("kept", CollectionOptions::default()).unwrap();
+        drop(db);
+
+        // a create that crashed before its options file, and a drop after its rename
+        let collections = path.join(COLLECTIONS_DIR);
+        std::fs::create_dir_all(collections.join("half")).unwrap();
+        std::fs::create_dir_all(collections.join(format!("gone{}", DROPPED_SUFFIX))).unwrap();
+
+        let db = Database::open(&path, Options::default()).unwrap();
+        assert_eq!(db.list_collections().unwrap(), vec!["kept"]);
+        assert!(!collections.join("half").exists());
+        assert!(!collections.join(format!("gone{}", DROPPED_SUFFIX)).exists());
+    }
+
+    #[test]
+    fn test_clone_collection() {
+        let path = empty_test_dir("database_clone_collection");
+        let db = Database::open(&path, Options::default()).unwrap();
+        let options = CollectionOptions { sstable_size: 20, hnsw: Some(HnswOptions { m: 4, ef_construction: 16 }), metric: DistanceMetric::Cosine, ..CollectionOptions::default() };
+        let base = db.create_collection("base", options).unwrap();
+        for i in 0..50u64 {
+            base.insert(i, Vector::new(i, vec![i as f64, 1.0])).unwrap();
+        }
+        base.flush().unwrap();
+        // still in the log
+        base.insert(50, Vector::new(50, vec![50.0, 1.0])).unwrap();
+
+        let variant = db.clone_collection("base", "variant").unwrap();
+        assert_eq!(db.list_collections().unwrap(), vec!["base", "variant"]);
+        assert_eq!(db.collection_options("variant").unwrap(), options);
+        assert_eq!(variant.metric(), DistanceMetric::Cosine);
+        assert_eq!(variant.range(..).unwrap(), base.range(..).unwrap());
+        // the graph came along and matched
+        assert_eq!(variant.startup_report().reconciled_vectors, 0);
+        assert_eq!(variant.search(&[50.0, 1.0], 1, 16).unwrap()[0].0, 50);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let table = std::fs::read_dir(path.join(COLLECTIONS_DIR).join("variant")).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| path.extension().is_some_and(|extension| extension == "sdb"))
+                .unwrap();
+            assert_eq!(std::fs::metadata(table).unwrap().nlink(), 2);
+        }
+
+        // each goes its own way, down to compacting the tables they shared
+        variant.delete(3).unwrap();
+        variant.insert(60, Vector::new(60, vec![60.0, 1.0])).unwrap();
+        base.insert(70, Vector::new(70, vec![70.0, 1.0])).unwrap();
+        variant.compact().unwrap();
+        assert!(base.get(3).unwrap().is_some() && base.get(60).unwrap().is_none());
+        assert!(variant.get(3).unwrap().is_none() && variant.get(70).unwrap().is_none());
+        drop(base);
+        db.drop_collection("base").unwrap();
+        assert_eq!(variant.len().unwrap(), 51);
+
+        assert!(matches!(db.clone_collection("variant", "variant"), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists));
+        assert!(matches!(db.clone_collection("base", "other"), Err(LsmError::NotFound(_))));
+        assert!(!path.join(COLLECTIONS_DIR).join("other").exists());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let path = empty_test_dir("database_metrics");
+        let db = Database::open(&path, Options::default()).unwrap();
+        let busy = db.create_collection("busy", CollectionOptions::default()).unwrap();
+        db.create_collection("idle", CollectionOptions::default()).unwrap();
+        busy.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        busy.flush().unwrap();
+        busy.knn(&[1.0], 1).unwrap();
+
+        let metrics = db.metrics();
+        assert!(metrics.contains("lsm_searches_total{collection=\"busy\"} 1\nlsm_searches_total{collection=\"idle\"} 0\n"));
+        assert!(metrics.contains("lsm_tables{collection=\"busy\"} 1\nlsm_tables{collection=\"idle\"} 0\n"));
+
+        // reopened, only the collections used since are reported
+        drop((busy, db));
+        let db = Database::open(&path, Options::default()).unwrap();
+        db.collection("idle").unwrap();
+        let metrics = db.metrics();
+        assert!(metrics.contains("lsm_tables{collection=\"idle\"} 0"));
+        assert!(!metrics.contains("busy"));
+    }
+}
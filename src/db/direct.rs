@@ -0,0 +1,104 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::db::platform;
+
+// O_DIRECT wants the buffer address, file offset and length of every write to be multiples
+// of the logical block size. 4 KiB covers the devices we run on.
+pub(crate) const BLOCK_SIZE: usize = 4096;
+const BUFFER_SIZE: usize = 256 * BLOCK_SIZE;
+
+// Opens `path` for writing past the page cache where the platform and filesystem allow it,
+// and as a plain file where they don't (tmpfs refuses O_DIRECT with EINVAL).
+pub(crate) fn create(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    platform::open_direct(&options, path).unwrap_or_else(|| options.open(path))
+}
+
+// Streams a file out in whole aligned blocks. Writes are staged in a block-aligned buffer;
+// `finish` pads the last block with zeros and truncates the file back to what was written.
+pub(crate) struct DirectWriter {
+    file: File,
+    // over-allocated by a block so an aligned window of BUFFER_SIZE bytes fits in it
+    buffer: Vec<u8>,
+    start: usize,
+    buffered: usize,
+    position: u64,
+}
+
+impl DirectWriter {
+    pub(crate) fn create(path: &Path) -> io::Result<DirectWriter> {
+        let file = create(path)?;
+        let buffer = vec![0; BUFFER_SIZE + BLOCK_SIZE];
+        let start = buffer.as_ptr().align_offset(BLOCK_SIZE);
+        Ok(DirectWriter { file, buffer, start, buffered: 0, position: 0 })
+    }
+
+    // Writes out what is left and returns the file, still to be synced
+    pub(crate) fn finish(mut self) -> io::Result<File> {
+        let padded = self.buffered.next_multiple_of(BLOCK_SIZE);
+        self.buffer[self.start + self.buffered..self.start + padded].fill(0);
+        self.buffered = padded;
+        self.write_buffer()?;
+        self.file.set_len(self.position)?;
+        Ok(self.file)
+    }
+
+    fn write_buffer(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.buffer[self.start..self.start + self.buffered])?;
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BUFFER_SIZE - self.buffered);
+        let at = self.start + self.buffered;
+        self.buffer[at..at + n].copy_from_slice(&buf[..n]);
+        self.buffered += n;
+        self.position += n as u64;
+        if self.buffered == BUFFER_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(n)
+    }
+
+    // Only whole buffers go out before `finish`, so there is nothing to do here
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Tables are written front to back, so only the position can be asked for
+impl Seek for DirectWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "a direct writer only appends")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+
+    #[test]
+    fn test_direct_writer() {
+        let path = empty_test_dir("direct_writer").join("out");
+        let data: Vec<u8> = (0..BUFFER_SIZE * 2 + 1000).map(|i| (i % 251) as u8).collect();
+        let mut writer = DirectWriter::create(&path).unwrap();
+        // odd sizes, so writes straddle blocks and buffers
+        for chunk in data.chunks(4099) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.stream_position().unwrap(), data.len() as u64);
+        assert!(writer.seek(SeekFrom::Start(0)).is_err());
+
+        writer.finish().unwrap().sync_all().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+}
@@ -0,0 +1,293 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Embedding files as written by numpy and the ANN benchmarks, memory mapped and read
+// one vector at a time for `LSMTree::import_embeddings`:
+// - .npy: a 2-D little-endian float32 or float64 array in C order
+// - .fvecs: per vector an int32 dimension followed by that many float32s
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmbeddingFormat {
+    Npy,
+    Fvecs,
+}
+
+impl EmbeddingFormat {
+    // By extension
+    pub fn from_path(path: &Path) -> Option<EmbeddingFormat> {
+        match path.extension()?.to_str()? {
+            "npy" => Some(EmbeddingFormat::Npy),
+            "fvecs" => Some(EmbeddingFormat::Fvecs),
+            _ => None,
+        }
+    }
+}
+
+// Where imported vectors get their ids
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmbeddingIds {
+    // the file's vectors take consecutive ids from this one
+    Sequential(u64),
+    // a 1-D .npy array of non-negative integers, one per vector
+    Npy(PathBuf),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Element {
+    F32,
+    F64,
+}
+
+pub(crate) struct EmbeddingFile {
+    mmap: Mmap,
+    format: EmbeddingFormat,
+    // where the first vector starts
+    offset: usize,
+    len: usize,
+    dim: usize,
+    element: Element,
+}
+
+impl EmbeddingFile {
+    pub(crate) fn open(path: &Path, format: EmbeddingFormat) -> io::Result<EmbeddingFile> {
+        let mmap = unsafe { Mmap::map(&File::open(path)?)? };
+        match format {
+            EmbeddingFormat::Npy => {
+                let header = NpyHeader::parse(&mmap)?;
+                let element = match header.descr.as_str() {
+                    "<f4" => Element::F32,
+                    "<f8" => Element::F64,
+                    descr => return Err(unsupported(format!("vectors must be float32 or float64, not '{}'", descr))),
+                };
+                let [len, dim] = header.shape[..] else {
+                    return Err(unsupported(format!("expected a 2-D array, the shape is {:?}", header.shape)));
+                };
+                header.check_size(mmap.len(), len.checked_mul(dim).and_then(|n| n.checked_mul(element.size())))?;
+                Ok(EmbeddingFile { mmap, format, offset: header.data_offset, len, dim, element })
+            }
+            EmbeddingFormat::Fvecs => {
+                let dim = match mmap.get(..4) {
+                    Some(bytes) => i32::from_le_bytes(bytes.try_into().unwrap()),
+                    None if mmap.is_empty() => 0,
+                    None => return Err(invalid("file too short for a dimension")),
+                };
+                let dim = usize::try_from(dim).map_err(|_| invalid(format!("negative dimension {}", dim)))?;
+                let record = 4 + dim * 4;
+                if mmap.len() % record != 0 {
+                    return Err(invalid(format!("{} bytes is not a whole number of {}-dimensional records", mmap.len(), dim)));
+                }
+                Ok(EmbeddingFile { len: mmap.len() / record, mmap, format, offset: 0, dim, element: Element::F32 })
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    // Vector `i`, which must be below `len`
+    pub(crate) fn get(&self, i: usize) -> io::Result<Vec<f64>> {
+        let size = self.element.size();
+        let start = match self.format {
+            EmbeddingFormat::Npy => self.offset + i * self.dim * size,
+            EmbeddingFormat::Fvecs => {
+                let start = i * (4 + self.dim * 4);
+                let dim = i32::from_le_bytes(self.mmap[start..start + 4].try_into().unwrap());
+                if dim as usize != self.dim {
+                    return Err(invalid(format!("vector {} has dimension {}, the first has {}", i, dim, self.dim)));
+                }
+                start + 4
+            }
+        };
+        let bytes = &self.mmap[start..start + self.dim * size];
+        Ok(match self.element {
+            Element::F32 => bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64).collect(),
+            Element::F64 => bytes.chunks_exact(8).map(|b| f64::from_le_bytes(b.try_into().unwrap())).collect(),
+        })
+    }
+}
+
+impl Element {
+    fn size(self) -> usize {
+        match self {
+            Element::F32 => 4,
+            Element::F64 => 8,
+        }
+    }
+}
+
+// Reads a .npy array of ids in full
+pub(crate) fn read_ids(path: &Path) -> io::Result<Vec<u64>> {
+    let mmap = unsafe { Mmap::map(&File::open(path)?)? };
+    let header = NpyHeader::parse(&mmap)?;
+    let [len] = header.shape[..] else {
+        return Err(unsupported(format!("ids must be a 1-D array, the shape is {:?}", header.shape)));
+    };
+    let size = match header.descr.as_str() {
+        "<i8" | "<u8" => 8,
+        "<i4" | "<u4" => 4,
+        descr => return Err(unsupported(format!("ids must be 32 or 64-bit integers, not '{}'", descr))),
+    };
+    header.check_size(mmap.len(), len.checked_mul(size))?;
+    let signed = header.descr.starts_with("<i");
+    mmap[header.data_offset..header.data_offset + len * size].chunks_exact(size).map(|b| {
+        let id = match (size, signed) {
+            (8, true) => i64::from_le_bytes(b.try_into().unwrap()),
+            (8, false) => return Ok(u64::from_le_bytes(b.try_into().unwrap())),
+            (_, true) => i32::from_le_bytes(b.try_into().unwrap()) as i64,
+            (_, false) => return Ok(u32::from_le_bytes(b.try_into().unwrap()) as u64),
+        };
+        u64::try_from(id).map_err(|_| invalid(format!("negative id {}", id)))
+    }).collect()
+}
+
+// The part of the .npy header we use: the magic, a version, the header length, then a
+// Python dict literal such as {'descr': '<f4', 'fortran_order': False, 'shape': (10, 3), }
+struct NpyHeader {
+    descr: String,
+    shape: Vec<usize>,
+    data_offset: usize,
+}
+
+impl NpyHeader {
+    fn parse(bytes: &[u8]) -> io::Result<NpyHeader> {
+        if bytes.len() < 10 || &bytes[..6] != b"\x93NUMPY" {
+            return Err(invalid("not a .npy file"));
+        }
+        let (len, start) = match bytes[6] {
+            1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+            2 | 3 if bytes.len() >= 12 => (u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize, 12),
+            major => return Err(unsupported(format!(".npy version {} is not supported", major))),
+        };
+        let dict = bytes.get(start..start + len).ok_or_else(|| invalid("truncated .npy header"))?;
+        let dict = std::str::from_utf8(dict).map_err(|_| invalid(".npy header is not text"))?;
+
+        let descr = dict_value(dict, "descr").and_then(|v| v.strip_prefix('\'')?.split('\'').next()).ok_or_else(|| invalid(".npy header has no descr"))?;
+        if dict_value(dict, "fortran_order").is_some_and(|v| v.starts_with("True")) {
+            return Err(unsupported("Fortran-ordered arrays are not supported"));
+        }
+        let shape = dict_value(dict, "shape").and_then(|v| v.strip_prefix('(')?.split(')').next()).ok_or_else(|| invalid(".npy header has no shape"))?;
+        let shape = shape.split(',').map(str::trim).filter(|d| !d.is_empty())
+            .map(|d| d.parse().map_err(|_| invalid(format!("bad .npy dimension '{}'", d))))
+            .collect::<io::Result<_>>()?;
+        Ok(NpyHeader { descr: descr.to_string(), shape, data_offset: start + len })
+    }
+
+    // `data_len` is None when computing it overflowed
+    fn check_size(&self, file_len: usize, data_len: Option<usize>) -> io::Result<()> {
+        match (file_len.checked_sub(self.data_offset), data_len) {
+            (Some(available), Some(data_len)) if available >= data_len => Ok(()),
+            _ => Err(invalid(format!("array of shape {:?} doesn't fit in the file", self.shape))),
+        }
+    }
+}
+
+// The text after `'key':` in the dict
+fn dict_value<'a>(dict: &'a str, key: &str) -> Option<&'a str> {
+    let at = dict.find(&format!("'{}'", key))?;
+    Some(dict[at + key.len() + 2..].trim_start().strip_prefix(':')?.trim_start())
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn unsupported(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::error::LsmError;
+    use crate::db::test_util::empty_test_dir;
+    use crate::db::lsm::LSMTree;
+    use crate::db::options::Options;
+
+    // A version 1 .npy file, its header padded like numpy pads it
+    fn npy(descr: &str, shape: &str, data: &[u8]) -> Vec<u8> {
+        let mut dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+        while (10 + dict.len() + 1) % 64 != 0 {
+            dict.push(' ');
+        }
+        dict.push('\n');
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(dict.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn test_npy() {
+        let dir = empty_test_dir("embeddings_npy");
+        let path = dir.join("v.npy");
+        let data: Vec<u8> = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(&path, npy("<f4", "(3, 2)", &data)).unwrap();
+        let file = EmbeddingFile::open(&path, EmbeddingFormat::Npy).unwrap();
+        assert_eq!((file.len(), file.dim), (3, 2));
+        assert_eq!(file.get(2).unwrap(), vec![5.0, 6.0]);
+
+        let data: Vec<u8> = [0.5f64, -0.25].iter().flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(&path, npy("<f8", "(1, 2)", &data)).unwrap();
+        assert_eq!(EmbeddingFile::open(&path, EmbeddingFormat::Npy).unwrap().get(0).unwrap(), vec![0.5, -0.25]);
+
+        std::fs::write(&path, npy("<f8", "(2, 2)", &data)).unwrap();
+        assert_eq!(EmbeddingFile::open(&path, EmbeddingFormat::Npy).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        std::fs::write(&path, npy("<i2", "(1, 1)", &[0, 0])).unwrap();
+        assert_eq!(EmbeddingFile::open(&path, EmbeddingFormat::Npy).err().unwrap().kind(), io::ErrorKind::Unsupported);
+
+        let ids: Vec<u8> = [7i64, 9].iter().flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(&path, npy("<i8", "(2,)", &ids)).unwrap();
+        assert_eq!(read_ids(&path).unwrap(), vec![7, 9]);
+        let ids: Vec<u8> = [7i32, -1].iter().flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(&path, npy("<i4", "(2,)", &ids)).unwrap();
+        assert!(read_ids(&path).is_err());
+    }
+
+    #[test]
+    fn test_fvecs() {
+        let dir = empty_test_dir("embeddings_fvecs");
+        let path = dir.join("v.fvecs");
+        let mut bytes = Vec::new();
+        for v in [[1.0f32, 2.0, 3.0], [4.0, 5.0, 6.0]] {
+            bytes.extend_from_slice(&3i32.to_le_bytes());
+            bytes.extend(v.iter().flat_map(|x| x.to_le_bytes()));
+        }
+        std::fs::write(&path, &bytes).unwrap();
+        let file = EmbeddingFile::open(&path, EmbeddingFormat::Fvecs).unwrap();
+        assert_eq!((file.len(), file.dim), (2, 3));
+        assert_eq!(file.get(1).unwrap(), vec![4.0, 5.0, 6.0]);
+
+        bytes[16..20].copy_from_slice(&2i32.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(EmbeddingFile::open(&path, EmbeddingFormat::Fvecs).unwrap().get(1).is_err());
+        std::fs::write(&path, &bytes[..30]).unwrap();
+        assert!(EmbeddingFile::open(&path, EmbeddingFormat::Fvecs).is_err());
+        assert_eq!(EmbeddingFormat::from_path(Path::new("a/b.fvecs")), Some(EmbeddingFormat::Fvecs));
+    }
+
+    #[test]
+    fn test_import_embeddings() {
+        let dir = empty_test_dir("embeddings_import");
+        let vectors = dir.join("v.npy");
+        let data: Vec<u8> = (0..40).flat_map(|x| (x as f32).to_le_bytes()).collect();
+        std::fs::write(&vectors, npy("<f4", "(20, 2)", &data)).unwrap();
+        let lsm = LSMTree::open(&dir.join("tree"), Options { bulk_run_size: 8, ..Options::default() }).unwrap();
+
+        assert_eq!(lsm.import_embeddings(&vectors, EmbeddingIds::Sequential(1000)).unwrap(), 20);
+        assert_eq!(lsm.get(1003).unwrap().unwrap().data(), &vec![6.0, 7.0]);
+
+        let ids = dir.join("ids.npy");
+        let data: Vec<u8> = (0..20u64).map(|i| i * 10).flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(&ids, npy("<u8", "(20,)", &data)).unwrap();
+        assert_eq!(lsm.import_embeddings(&vectors, EmbeddingIds::Npy(ids.clone())).unwrap(), 20);
+        assert_eq!(lsm.get(190).unwrap().unwrap().data(), &vec![38.0, 39.0]);
+        assert_eq!(lsm.len().unwrap(), 40);
+
+        std::fs::write(&ids, npy("<u8", "(1,)", &0u64.to_le_bytes())).unwrap();
+        assert!(matches!(lsm.import_embeddings(&vectors, EmbeddingIds::Npy(ids)), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(lsm.import_embeddings(&dir.join("v.txt"), EmbeddingIds::Sequential(0)), Err(LsmError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,435 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use crate::db::vector::{ElementType, MetadataValue, Vector};
+
+// Flags byte stored in front of every value in SSTables and the WAL, so a feature can
+// change how a single entry is stored without another format migration. Readers refuse
+// bits they don't know rather than misread the entry.
+
+// reserved, nothing writes compressed values yet
+pub(crate) const COMPRESSED: u8 = 1;
+// the record carries metadata
+pub(crate) const HAS_PAYLOAD: u8 = 1 << 1;
+// the record carries an expiry time
+pub(crate) const HAS_TTL: u8 = 1 << 2;
+// bits 3 and 4 say how the value is encoded
+pub(crate) const VALUE_TYPE_MASK: u8 = 0b11 << 3;
+// the whole vector as one bson document, what entries were before dense values
+pub(crate) const VALUE_TYPE_BSON: u8 = 0;
+// see `encode_at`
+pub(crate) const VALUE_TYPE_DENSE: u8 = 1 << 3;
+// a dense value's metadata is in the `PayloadCodec::Compact` layout rather than bson
+pub(crate) const COMPACT_PAYLOAD: u8 = 1 << 5;
+// a dense value in a table whose data is another entry's, see `encode_shared`
+pub(crate) const SHARED_DATA: u8 = 1 << 6;
+
+// id, dimension, element type and padding length, the fixed start of a dense value
+const DENSE_PREFIX: usize = 8 + 4 + 1 + 1;
+
+const KNOWN_FLAGS: u8 = COMPRESSED | HAS_PAYLOAD | HAS_TTL | VALUE_TYPE_MASK | COMPACT_PAYLOAD | SHARED_DATA;
+
+// Compact payload tags
+const BOOL: u8 = 0;
+const INT: u8 = 1;
+const FLOAT: u8 = 2;
+const STRING: u8 = 3;
+
+// How tables store a value's metadata, see `Options::payload_codec`. Each entry's flags
+// say which it was written with, so changing it leaves older tables readable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PayloadCodec {
+    // a bson document
+    #[default]
+    Bson,
+    // the field count, then per field its name's length and bytes, a tag byte and the
+    // value: a byte for a bool, a zigzag varint for an int, 8 little endian bytes for a
+    // float, or the length and bytes of a string, with counts and lengths as varints.
+    // Smaller than bson's fixed size lengths and terminators, and decoded without serde.
+    Compact,
+}
+
+pub(crate) fn flags(value: &Vector, codec: PayloadCodec) -> u8 {
+    let mut flags = VALUE_TYPE_DENSE;
+    if value.expires_at().is_some() {
+        flags |= HAS_TTL;
+    }
+    if !value.metadata().is_empty() {
+        flags |= HAS_PAYLOAD;
+        if codec == PayloadCodec::Compact {
+            flags |= COMPACT_PAYLOAD;
+        }
+    }
+    flags
+}
+
+// The flags and serialized form of a value as f64s, for records whose position doesn't
+// matter
+pub(crate) fn encode(value: &Vector) -> io::Result<(u8, Vec<u8>)> {
+    encode_at(value, 0, ElementType::F64, PayloadCodec::Bson)
+}
+
+// The flags and serialized form of a value to be written at offset `at` of its file. A
+// dense value is the id (u64), the dimension (u32), the element type (u8), a padding
+// length (u8) and that many zero bytes, the expiry (u64) if the flags say there is one,
+// the data as little endian elements, or bits packed eight to a byte for binary data,
+// and the metadata as a bson document if there is any, in `codec`'s layout. The padding puts the data on an 8 byte boundary in the file,
+// so a mapped table can lend it out as a slice.
+pub(crate) fn encode_at(value: &Vector, at: usize, element: ElementType, codec: PayloadCodec) -> io::Result<(u8, Vec<u8>)> {
+    encode_dense(value, (8 - (at + DENSE_PREFIX) % 8) % 8, element, codec, None)
+}
+
+// Like `encode_at` for a value whose data is the same as that of the entry at offset
+// `owner` of the same table, which is written in the data's place. Readers put the
+// owner's data back with `unshare`.
+pub(crate) fn encode_shared(value: &Vector, owner: u64, element: ElementType, codec: PayloadCodec) -> io::Result<(u8, Vec<u8>)> {
+    encode_dense(value, 0, element, codec, Some(owner))
+}
+
+fn encode_dense(value: &Vector, padding: usize, element: ElementType, codec: PayloadCodec, owner: Option<u64>) -> io::Result<(u8, Vec<u8>)> {
+    let mut flags = flags(value, codec);
+    let mut out = Vec::with_capacity(DENSE_PREFIX + padding + 8 + element.data_len(value.data().len()));
+    out.write_u64::<LittleEndian>(value.id())?;
+    out.write_u32::<LittleEndian>(value.data().len() as u32)?;
+    out.write_u8(element.to_u8())?;
+    out.write_u8(padding as u8)?;
+    out.resize(out.len() + padding, 0);
+    if let Some(expires_at) = value.expires_at() {
+        out.write_u64::<LittleEndian>(expires_at)?;
+    }
+    match owner {
+        Some(owner) => {
+            flags |= SHARED_DATA;
+            out.write_u64::<LittleEndian>(owner)?;
+        }
+        None => element.write(&mut out, value.data())?,
+    }
+    match codec {
+        _ if value.metadata().is_empty() => {}
+        PayloadCodec::Bson => out.extend(bson::to_vec(value.metadata()).map_err(io::Error::other)?),
+        PayloadCodec::Compact => encode_compact(value.metadata(), &mut out)?,
+    }
+    Ok((flags, out))
+}
+
+fn encode_compact(metadata: &BTreeMap<String, MetadataValue>, out: &mut Vec<u8>) -> io::Result<()> {
+    write_varint(out, metadata.len() as u64);
+    for (name, value) in metadata {
+        write_varint(out, name.len() as u64);
+        out.extend_from_slice(name.as_bytes());
+        match value {
+            MetadataValue::Bool(b) => {
+                out.write_u8(BOOL)?;
+                out.write_u8(*b as u8)?;
+            }
+            MetadataValue::Int(i) => {
+                out.write_u8(INT)?;
+                // zigzag, so small negative ints stay short too
+                write_varint(out, ((*i << 1) ^ (*i >> 63)) as u64);
+            }
+            MetadataValue::Float(f) => {
+                out.write_u8(FLOAT)?;
+                out.write_f64::<LittleEndian>(*f)?;
+            }
+            MetadataValue::String(s) => {
+                out.write_u8(STRING)?;
+                write_varint(out, s.len() as u64);
+                out.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_compact(mut input: &[u8]) -> io::Result<BTreeMap<String, MetadataValue>> {
+    // reads from a slice only fail by running out of it
+    let truncated = |_| invalid("compact metadata is truncated");
+    let string = |input: &mut &[u8]| -> io::Result<String> {
+        let len = read_varint(input)? as usize;
+        if input.len() < len {
+            return Err(invalid("compact metadata is truncated"));
+        }
+        let (bytes, rest) = input.split_at(len);
+        *input = rest;
+        String::from_utf8(bytes.to_vec()).map_err(invalid)
+    };
+    let mut metadata = BTreeMap::new();
+    for _ in 0..read_varint(&mut input)? {
+        let name = string(&mut input)?;
+        let value = match input.read_u8().map_err(truncated)? {
+            BOOL => MetadataValue::Bool(input.read_u8().map_err(truncated)? != 0),
+            INT => {
+                let zigzag = read_varint(&mut input)?;
+                MetadataValue::Int((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+            }
+            FLOAT => MetadataValue::Float(input.read_f64::<LittleEndian>().map_err(truncated)?),
+            STRING => MetadataValue::String(string(&mut input)?),
+            tag => return Err(invalid(format!("unknown metadata tag {} for field {}", tag, name))),
+        };
+        metadata.insert(name, value);
+    }
+    if !input.is_empty() {
+        return Err(invalid(format!("{} bytes after compact metadata", input.len())));
+    }
+    Ok(metadata)
+}
+
+// LEB128: 7 bits a byte, low bits first, the top bit set on all but the last
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+pub(crate) fn read_varint(input: &mut &[u8]) -> io::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some((&byte, rest)) = input.split_first() else {
+            return Err(invalid("compact metadata is truncated"));
+        };
+        *input = rest;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("compact metadata has a varint over 64 bits"))
+}
+
+pub(crate) fn decode(flags: u8, serialized: &[u8]) -> io::Result<Vector> {
+    check(flags)?;
+    if flags & VALUE_TYPE_MASK == VALUE_TYPE_BSON {
+        return bson::from_slice(serialized).map_err(invalid);
+    }
+    let dense = Dense::split(flags, serialized)?;
+    if dense.owner.is_some() {
+        return Err(invalid(format!("dense value for id {} shares its data, which only its table has", dense.id)));
+    }
+    let data = (0..dense.dimension).map(|i| dense.element.read(dense.data, i).unwrap()).collect();
+    let mut value = Vector::new(dense.id, data);
+    if let Some(expires_at) = dense.expires_at {
+        value.set_expires_at(expires_at);
+    }
+    if flags & HAS_PAYLOAD != 0 {
+        *value.metadata_mut() = match flags & COMPACT_PAYLOAD {
+            0 => bson::from_slice::<BTreeMap<String, MetadataValue>>(dense.metadata).map_err(invalid)?,
+            _ => decode_compact(dense.metadata)?,
+        };
+    }
+    Ok(value)
+}
+
+// When the value expires, found without decoding its data
+pub(crate) fn expires_at(flags: u8, serialized: &[u8]) -> io::Result<Option<u64>> {
+    check(flags)?;
+    if flags & HAS_TTL == 0 {
+        return Ok(None);
+    }
+    if flags & VALUE_TYPE_MASK == VALUE_TYPE_DENSE {
+        return Ok(Dense::split(flags, serialized)?.expires_at);
+    }
+    let document = bson::RawDocument::from_bytes(serialized).map_err(invalid)?;
+    match document.get("expires_at").map_err(invalid)? {
+        Some(bson::RawBsonRef::Int64(at)) => Ok(Some(at as u64)),
+        _ => Ok(None),
+    }
+}
+
+// The fields of a dense value, borrowed from its serialized form
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Dense<'a> {
+    pub(crate) id: u64,
+    pub(crate) expires_at: Option<u64>,
+    pub(crate) element: ElementType,
+    // the little endian elements, empty for a value sharing another entry's
+    pub(crate) data: &'a [u8],
+    // the offset of the table entry holding the data, for a value sharing it
+    pub(crate) owner: Option<u64>,
+    pub(crate) dimension: usize,
+    metadata: &'a [u8],
+}
+
+impl<'a> Dense<'a> {
+    pub(crate) fn split(flags: u8, serialized: &'a [u8]) -> io::Result<Dense<'a>> {
+        // reads from a slice only fail by running out of it
+        let truncated = |_| invalid(format!("dense value of {} bytes is truncated", serialized.len()));
+        let mut input = serialized;
+        let id = input.read_u64::<LittleEndian>().map_err(truncated)?;
+        let dimension = input.read_u32::<LittleEndian>().map_err(truncated)? as usize;
+        let element = ElementType::from_u8(input.read_u8().map_err(truncated)?)?;
+        let padding = input.read_u8().map_err(truncated)? as usize;
+        if padding >= 8 {
+            return Err(invalid(format!("dense value for id {} has {} bytes of padding", id, padding)));
+        }
+        input.read_exact(&mut [0u8; 8][..padding]).map_err(truncated)?;
+        let expires_at = match flags & HAS_TTL {
+            0 => None,
+            _ => Some(input.read_u64::<LittleEndian>().map_err(truncated)?),
+        };
+        let owner = match flags & SHARED_DATA {
+            0 => None,
+            _ => Some(input.read_u64::<LittleEndian>().map_err(truncated)?),
+        };
+        let data_len = if owner.is_some() { 0 } else { element.data_len(dimension) };
+        if input.len() < data_len {
+            return Err(invalid(format!("dense value for id {} is too short for {} dimensions", id, dimension)));
+        }
+        let (data, metadata) = input.split_at(data_len);
+        Ok(Dense { id, expires_at, element, data, owner, dimension, metadata })
+    }
+}
+
+// The flags and serialized form of `shared`, a value sharing its data, with the data of
+// `owner`, the entry it points at, in place. The data is padded to an 8 byte boundary of
+// the returned buffer.
+pub(crate) fn unshare(flags: u8, shared: &Dense, owner: &Dense) -> io::Result<(u8, Vec<u8>)> {
+    if owner.owner.is_some() || owner.element != shared.element || owner.dimension != shared.dimension {
+        return Err(invalid(format!("dense value for id {} shares data it doesn't fit", shared.id)));
+    }
+    let padding = (8 - DENSE_PREFIX % 8) % 8;
+    let mut out = Vec::with_capacity(DENSE_PREFIX + padding + 8 + owner.data.len() + shared.metadata.len());
+    out.write_u64::<LittleEndian>(shared.id)?;
+    out.write_u32::<LittleEndian>(shared.dimension as u32)?;
+    out.write_u8(shared.element.to_u8())?;
+    out.write_u8(padding as u8)?;
+    out.resize(out.len() + padding, 0);
+    if let Some(expires_at) = shared.expires_at {
+        out.write_u64::<LittleEndian>(expires_at)?;
+    }
+    out.extend_from_slice(owner.data);
+    out.extend_from_slice(shared.metadata);
+    Ok((flags & !SHARED_DATA, out))
+}
+
+// An error for flags written by a newer version than this one
+pub(crate) fn check(flags: u8) -> io::Result<()> {
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(unsupported(format!("unknown entry flags {:#04x}", flags & !KNOWN_FLAGS)));
+    }
+    if flags & COMPRESSED != 0 {
+        return Err(unsupported("compressed entries are not supported".to_string()));
+    }
+    if !matches!(flags & VALUE_TYPE_MASK, VALUE_TYPE_BSON | VALUE_TYPE_DENSE) {
+        return Err(unsupported(format!("unknown value type {}", (flags & VALUE_TYPE_MASK) >> 3)));
+    }
+    if flags & SHARED_DATA != 0 && flags & VALUE_TYPE_MASK != VALUE_TYPE_DENSE {
+        return Err(invalid("only dense values share their data"));
+    }
+    Ok(())
+}
+
+fn unsupported(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        let mut value = Vector::new(1, vec![1.0]);
+        assert_eq!(flags(&value, PayloadCodec::Compact), VALUE_TYPE_DENSE);
+        value.set_expires_at(10);
+        assert_eq!(flags(&value, PayloadCodec::Bson), VALUE_TYPE_DENSE | HAS_TTL);
+        let value = value.with_metadata("tag", "a");
+        assert_eq!(flags(&value, PayloadCodec::Bson), VALUE_TYPE_DENSE | HAS_TTL | HAS_PAYLOAD);
+        assert_eq!(flags(&value, PayloadCodec::Compact), VALUE_TYPE_DENSE | HAS_TTL | HAS_PAYLOAD | COMPACT_PAYLOAD);
+
+        let (flags, serialized) = encode(&value).unwrap();
+        assert_eq!(decode(flags, &serialized).unwrap(), value);
+    }
+
+    #[test]
+    fn test_dense_values() {
+        let mut value = Vector::new(9, vec![1.5, -2.0, 0.25]).with_metadata("tag", "a");
+        value.set_expires_at(77);
+        for (element, size) in [(ElementType::F64, 24), (ElementType::F32, 12), (ElementType::F16, 6), (ElementType::BF16, 6)] {
+            for at in 0..8 {
+                let (flags, serialized) = encode_at(&value, at, element, PayloadCodec::Bson).unwrap();
+                let dense = Dense::split(flags, &serialized).unwrap();
+                // the data starts on an 8 byte boundary of the file
+                assert_eq!((at + dense.data.as_ptr() as usize - serialized.as_ptr() as usize) % 8, 0);
+                assert_eq!((dense.id, dense.expires_at, dense.element, dense.data.len()), (9, Some(77), element, size));
+                assert_eq!(decode(flags, &serialized).unwrap(), value);
+                assert_eq!(expires_at(flags, &serialized).unwrap(), Some(77));
+            }
+        }
+        // f32 rounds
+        let (flags, serialized) = encode_at(&Vector::new(1, vec![0.1]), 0, ElementType::F32, PayloadCodec::Bson).unwrap();
+        assert_eq!(decode(flags, &serialized).unwrap().data(), &vec![0.1f32 as f64]);
+        // binary keeps the sign bits, packed into a byte
+        let (flags, serialized) = encode_at(&value, 0, ElementType::Binary, PayloadCodec::Compact).unwrap();
+        assert_eq!(Dense::split(flags, &serialized).unwrap().data, &[0b101]);
+        assert_eq!(decode(flags, &serialized).unwrap().data(), &vec![1.0, 0.0, 1.0]);
+
+        // values written before dense values still decode
+        let serialized = bson::to_vec(&value).unwrap();
+        let flags = VALUE_TYPE_BSON | HAS_TTL | HAS_PAYLOAD;
+        assert_eq!(decode(flags, &serialized).unwrap(), value);
+        assert_eq!(expires_at(flags, &serialized).unwrap(), Some(77));
+
+        let (flags, serialized) = encode(&value).unwrap();
+        assert_eq!(decode(flags, &serialized[..20]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_shared_data() {
+        let owner = Vector::new(1, vec![1.5, -2.0, 0.25]);
+        let mut shared = Vector::new(2, owner.data().clone()).with_metadata("tag", "b");
+        shared.set_expires_at(77);
+        let (owner_flags, owner_serialized) = encode_at(&owner, 3, ElementType::F32, PayloadCodec::Bson).unwrap();
+        let (flags, serialized) = encode_shared(&shared, 40, ElementType::F32, PayloadCodec::Compact).unwrap();
+        assert_eq!(flags & SHARED_DATA, SHARED_DATA);
+        let dense = Dense::split(flags, &serialized).unwrap();
+        assert_eq!((dense.owner, dense.expires_at, dense.data.len()), (Some(40), Some(77), 0));
+        assert_eq!(expires_at(flags, &serialized).unwrap(), Some(77));
+        // only with the owner's data does it decode
+        assert_eq!(decode(flags, &serialized).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (flags, unshared) = unshare(flags, &dense, &Dense::split(owner_flags, &owner_serialized).unwrap()).unwrap();
+        assert_eq!(decode(flags, &unshared).unwrap(), shared);
+
+        let other = encode_at(&Vector::new(3, vec![1.0]), 0, ElementType::F32, PayloadCodec::Bson).unwrap();
+        assert!(unshare(flags | SHARED_DATA, &dense, &Dense::split(other.0, &other.1).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_compact_payload() {
+        let mut value = Vector::new(3, vec![1.0, 2.0])
+            .with_metadata("flag", true)
+            .with_metadata("count", -7i64)
+            .with_metadata("rank", i64::MIN)
+            .with_metadata("score", 0.5)
+            .with_metadata("name", "héllo");
+        value.set_expires_at(5);
+        let (flags, compact) = encode_at(&value, 0, ElementType::F64, PayloadCodec::Compact).unwrap();
+        assert_eq!(decode(flags, &compact).unwrap(), value);
+        assert_eq!(expires_at(flags, &compact).unwrap(), Some(5));
+        let (_, bson) = encode_at(&value, 0, ElementType::F64, PayloadCodec::Bson).unwrap();
+        assert!(compact.len() < bson.len());
+
+        let truncated = &compact[..compact.len() - 1];
+        assert_eq!(decode(flags, truncated).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut metadata = Vec::new();
+        encode_compact(value.metadata(), &mut metadata).unwrap();
+        let mut bad_tag = compact.clone();
+        // the tag of the first field, "count", after the field count and its name
+        bad_tag[compact.len() - metadata.len() + 1 + 1 + "count".len()] = 9;
+        assert_eq!(decode(flags, &bad_tag).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_check_rejects_unknown_flags() {
+        assert!(check(HAS_TTL | HAS_PAYLOAD | COMPACT_PAYLOAD).is_ok());
+        for flags in [COMPRESSED, 2 << 3, 3 << 3, 1 << 7] {
+            assert_eq!(check(flags).unwrap_err().kind(), io::ErrorKind::Unsupported, "flags {:#04x}", flags);
+        }
+        assert_eq!(check(VALUE_TYPE_BSON | SHARED_DATA).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
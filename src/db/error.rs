@@ -0,0 +1,146 @@
+use std::fmt;
+use std::io;
+
+// What a call on the tree failed with, for callers to match on rather than read from an
+// io::Error's kind and message. It converts both ways, so `?` works between it and the
+// io::Result used inside the crate: from an io::Error, InvalidData is corruption, NotFound
+// and InvalidInput keep their meaning, a bson or JSON error is a serialization failure and
+// the rest, like anything the OS returned, is Io.
+#[derive(Debug)]
+pub enum LsmError {
+    Io(io::Error),
+    // stored data failed a check or couldn't be decoded, in `file` at byte `offset` where
+    // those are known
+    Corruption { file: Option<String>, offset: Option<u64>, message: String },
+    // a value or its metadata couldn't be encoded or decoded
+    Serialization(String),
+    // what was asked for isn't there, like a dropped collection or a sequence number the
+    // log no longer holds. A missing key is Ok(None), not this.
+    NotFound(String),
+    // an argument or option the tree can't use
+    InvalidArgument(String),
+}
+
+impl fmt::Display for LsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LsmError::Io(e) => e.fmt(f),
+            LsmError::Corruption { file, offset, message } => write_corruption(f, file.as_deref(), *offset, message),
+            LsmError::Serialization(msg) | LsmError::NotFound(msg) | LsmError::InvalidArgument(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for LsmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LsmError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LsmError {
+    fn from(e: io::Error) -> LsmError {
+        if e.raw_os_error().is_some() {
+            return LsmError::Io(e);
+        }
+        if e.get_ref().is_some_and(|inner| inner.is::<bson::ser::Error>() || inner.is::<bson::de::Error>() || inner.is::<serde_json::Error>()) {
+            return LsmError::Serialization(e.to_string());
+        }
+        match e.kind() {
+            io::ErrorKind::InvalidData => match e.into_inner().map(|inner| inner.downcast::<Corrupt>()) {
+                Some(Ok(corrupt)) => LsmError::Corruption { file: corrupt.file, offset: corrupt.offset, message: corrupt.message },
+                Some(Err(inner)) => LsmError::Corruption { file: None, offset: None, message: inner.to_string() },
+                None => LsmError::Corruption { file: None, offset: None, message: io::Error::from(io::ErrorKind::InvalidData).to_string() },
+            },
+            io::ErrorKind::NotFound => LsmError::NotFound(e.to_string()),
+            io::ErrorKind::InvalidInput => LsmError::InvalidArgument(e.to_string()),
+            _ => LsmError::Io(e),
+        }
+    }
+}
+
+impl From<LsmError> for io::Error {
+    fn from(e: LsmError) -> io::Error {
+        match e {
+            LsmError::Io(e) => e,
+            LsmError::Corruption { file, offset, message } => io::Error::new(io::ErrorKind::InvalidData, Corrupt { file, offset, message }),
+            LsmError::Serialization(msg) => io::Error::other(msg),
+            LsmError::NotFound(msg) => io::Error::new(io::ErrorKind::NotFound, msg),
+            LsmError::InvalidArgument(msg) => io::Error::new(io::ErrorKind::InvalidInput, msg),
+        }
+    }
+}
+
+// Where corrupt data was found, carried inside an InvalidData io::Error through the
+// io::Result paths until it becomes `LsmError::Corruption`
+#[derive(Debug)]
+pub(crate) struct Corrupt {
+    file: Option<String>,
+    offset: Option<u64>,
+    message: String,
+}
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_corruption(f, self.file.as_deref(), self.offset, &self.message)
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+// Corruption in `file` at `offset`, as an io::Error
+pub(crate) fn corruption_at(file: String, offset: Option<u64>, message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Corrupt { file: Some(file), offset, message })
+}
+
+// `e` placed in `file` at `offset` if it is corruption that doesn't say where it was found
+pub(crate) fn locate(e: io::Error, offset: Option<u64>, file: impl FnOnce() -> String) -> io::Error {
+    if e.kind() != io::ErrorKind::InvalidData || e.get_ref().is_some_and(|inner| inner.is::<Corrupt>()) {
+        return e;
+    }
+    corruption_at(file(), offset, e.to_string())
+}
+
+fn write_corruption(f: &mut fmt::Formatter<'_>, file: Option<&str>, offset: Option<u64>, message: &str) -> fmt::Result {
+    match (file, offset) {
+        (Some(file), Some(offset)) => write!(f, "{} at offset {}: {}", file, offset, message),
+        (Some(file), None) => write!(f, "{}: {}", file, message),
+        (None, _) => f.write_str(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io_error() {
+        let corrupt = LsmError::from(corruption_at("sstable_3.sdb".to_string(), Some(40), "entry checksum mismatch".to_string()));
+        assert!(matches!(&corrupt, LsmError::Corruption { file: Some(file), offset: Some(40), message } if file == "sstable_3.sdb" && message == "entry checksum mismatch"));
+        assert_eq!(corrupt.to_string(), "sstable_3.sdb at offset 40: entry checksum mismatch");
+        let unplaced = LsmError::from(io::Error::new(io::ErrorKind::InvalidData, "bad magic number"));
+        assert!(matches!(unplaced, LsmError::Corruption { file: None, offset: None, .. }));
+        assert!(matches!(LsmError::from(io::Error::new(io::ErrorKind::NotFound, "no such collection")), LsmError::NotFound(_)));
+        assert!(matches!(LsmError::from(io::Error::new(io::ErrorKind::InvalidInput, "bad dimension")), LsmError::InvalidArgument(_)));
+        let bson = bson::from_slice::<bson::Document>(&[1, 2]).unwrap_err();
+        assert!(matches!(LsmError::from(io::Error::other(bson)), LsmError::Serialization(_)));
+        // what the OS returned stays an I/O error whatever its kind
+        assert!(matches!(LsmError::from(io::Error::from_raw_os_error(2)), LsmError::Io(e) if e.kind() == io::ErrorKind::NotFound));
+
+        // and goes back to the io::Error it came from, still saying where
+        let e = io::Error::from(corrupt);
+        assert_eq!((e.kind(), e.to_string()), (io::ErrorKind::InvalidData, "sstable_3.sdb at offset 40: entry checksum mismatch".to_string()));
+        assert!(matches!(LsmError::from(e), LsmError::Corruption { offset: Some(40), .. }));
+        assert_eq!(io::Error::from(LsmError::from(io::Error::from_raw_os_error(2))).raw_os_error(), Some(2));
+
+        // a located error keeps its place, an unplaced one gets the file
+        let placed = locate(corruption_at("sstable_3.sdb".to_string(), Some(40), "x".to_string()), Some(8), || "sstable_4.sdb".to_string());
+        assert_eq!(placed.to_string(), "sstable_3.sdb at offset 40: x");
+        let placed = locate(io::Error::new(io::ErrorKind::InvalidData, "bad magic number"), None, || "sstable_4.sdb".to_string());
+        assert!(matches!(LsmError::from(placed), LsmError::Corruption { file: Some(file), offset: None, .. } if file == "sstable_4.sdb"));
+        let other = locate(io::Error::new(io::ErrorKind::UnexpectedEof, "short read"), None, || "sstable_4.sdb".to_string());
+        assert_eq!(other.to_string(), "short read");
+    }
+}
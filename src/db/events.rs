@@ -0,0 +1,84 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use crate::db::batch::{BatchOp, WriteBatch};
+use crate::db::vector::Vector;
+
+// One committed operation, numbered with its sequence. A batch is one event per
+// operation, in batch order. Values are as stored, after the tree's pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Put { sequence: u64, key: u64, value: Vector },
+    Delete { sequence: u64, key: u64 },
+    DeleteRange { sequence: u64, start: u64, end: u64 },
+    Merge { sequence: u64, key: u64, operand: Vec<u8> },
+}
+
+impl Event {
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Event::Put { sequence, .. }
+            | Event::Delete { sequence, .. }
+            | Event::DeleteRange { sequence, .. }
+            | Event::Merge { sequence, .. } => *sequence,
+        }
+    }
+}
+
+// The events of a batch whose first operation is numbered `first`
+pub(crate) fn from_batch(first: u64, batch: &WriteBatch) -> impl Iterator<Item = Event> + '_ {
+    batch.ops.iter().zip(first..).map(|(op, sequence)| match op {
+        BatchOp::Put(key, value) => Event::Put { sequence, key: *key, value: value.clone() },
+        BatchOp::Delete(key) => Event::Delete { sequence, key: *key },
+        BatchOp::DeleteRange(range) => Event::DeleteRange { sequence, start: range.start, end: range.end },
+        BatchOp::Merge(key, operand) => Event::Merge { sequence, key: *key, operand: operand.clone() },
+    })
+}
+
+// Channels of `LSMTree::subscribe`. A subscriber that drops its receiver is forgotten on
+// the next publish.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: Mutex<Vec<Sender<Event>>>,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub(crate) fn publish(&self, first: u64, batch: &WriteBatch) {
+        let mut senders = self.senders.lock().unwrap();
+        if senders.is_empty() {
+            return;
+        }
+        for event in from_batch(first, batch) {
+            senders.retain(|sender| sender.send(event.clone()).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let subscribers = Subscribers::default();
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+        let mut batch = WriteBatch::new();
+        batch.put(1, Vector::new(1, vec![1.0])).delete(2);
+        subscribers.publish(5, &batch);
+
+        let events: Vec<Event> = first.try_iter().collect();
+        assert_eq!(events, vec![Event::Put { sequence: 5, key: 1, value: Vector::new(1, vec![1.0]) }, Event::Delete { sequence: 6, key: 2 }]);
+        assert_eq!(second.try_iter().count(), 2);
+
+        drop(second);
+        subscribers.publish(7, &batch);
+        assert_eq!(subscribers.senders.lock().unwrap().len(), 1);
+        assert_eq!(first.try_iter().map(|e| e.sequence()).collect::<Vec<_>>(), vec![7, 8]);
+    }
+}
@@ -0,0 +1,12 @@
+// Where background jobs such as memtable flushes run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Executor {
+    // Flush and compaction threads owned by the tree pick up jobs as they are queued
+    #[default]
+    Threaded,
+    // No worker threads: jobs stay queued until `LSMTree::run_pending_job` is called, and
+    // run one at a time on the calling thread. Lets tests stop between a job being
+    // scheduled and its result being installed without sleeping.
+    #[cfg(feature = "deterministic")]
+    Manual,
+}
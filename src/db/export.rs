@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use serde::{Deserialize, Serialize};
+use crate::db::vector::{MetadataValue, Vector};
+
+// Text formats for `LSMTree::export` and `LSMTree::import`, one record per line.
+//
+// JSON Lines: each line is the record's fields plus its key, `{"key": 7, "id": 7,
+// "data": [0.5, 1.0], "metadata": {"lang": "en"}, "expires_at": 1700000000000}`, with
+// metadata and expires_at left out when empty. The key defaults to the id on import.
+//
+// CSV: a `key,id,expires_at,data,metadata` header, then one row per record with the
+// numbers of `data` separated by spaces and `metadata` as a JSON object. Empty
+// expires_at and metadata fields mean none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    JsonLines,
+    Csv,
+}
+
+const CSV_HEADER: &str = "key,id,expires_at,data,metadata";
+
+#[derive(Serialize)]
+struct Record<'a> {
+    key: u64,
+    #[serde(flatten)]
+    value: &'a Vector,
+}
+
+#[derive(Deserialize)]
+struct OwnedRecord {
+    key: Option<u64>,
+    #[serde(flatten)]
+    value: Vector,
+}
+
+pub(crate) fn write_header(out: &mut impl Write, format: ExportFormat) -> io::Result<()> {
+    match format {
+        ExportFormat::JsonLines => Ok(()),
+        ExportFormat::Csv => writeln!(out, "{}", CSV_HEADER),
+    }
+}
+
+pub(crate) fn write_record(out: &mut impl Write, format: ExportFormat, key: u64, value: &Vector) -> io::Result<()> {
+    match format {
+        ExportFormat::JsonLines => {
+            serde_json::to_writer(&mut *out, &Record { key, value }).map_err(io::Error::other)?;
+        }
+        ExportFormat::Csv => {
+            let data: Vec<String> = value.data().iter().map(f64::to_string).collect();
+            let expires_at = value.expires_at().map(|at| at.to_string()).unwrap_or_default();
+            let metadata = match value.metadata().is_empty() {
+                true => String::new(),
+                false => quote(&serde_json::to_string(value.metadata()).map_err(io::Error::other)?),
+            };
+            write!(out, "{},{},{},{},{}", key, value.id(), expires_at, data.join(" "), metadata)?;
+        }
+    }
+    writeln!(out)
+}
+
+// The records in `input`, each malformed line an InvalidData error naming it
+pub(crate) fn read_records(input: impl BufRead, format: ExportFormat) -> impl Iterator<Item = io::Result<(u64, Vector)>> {
+    let mut header = format == ExportFormat::Csv;
+    input.lines().enumerate().filter_map(move |(i, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        if std::mem::take(&mut header) {
+            return match line.trim_end() == CSV_HEADER {
+                true => None,
+                false => Some(Err(malformed(i, format!("expected the header '{}'", CSV_HEADER)))),
+            };
+        }
+        if line.trim().is_empty() {
+            return None;
+        }
+        let record = match format {
+            ExportFormat::JsonLines => serde_json::from_str::<OwnedRecord>(&line).map(|r| (r.key.unwrap_or(r.value.id()), r.value)).map_err(|e| e.to_string()),
+            ExportFormat::Csv => parse_csv_record(&line),
+        };
+        Some(record.map_err(|e| malformed(i, e)))
+    })
+}
+
+fn parse_csv_record(line: &str) -> Result<(u64, Vector), String> {
+    let fields = split_csv(line)?;
+    let [key, id, expires_at, data, metadata] = fields.as_slice() else {
+        return Err(format!("expected 5 fields, found {}", fields.len()));
+    };
+    let key = key.parse().map_err(|_| format!("bad key '{}'", key))?;
+    let id = id.parse().map_err(|_| format!("bad id '{}'", id))?;
+    let data = data.split_whitespace().map(|x| x.parse().map_err(|_| format!("'{}' is not a number", x))).collect::<Result<_, _>>()?;
+    let mut value = Vector::new(id, data);
+    if !expires_at.is_empty() {
+        value.set_expires_at(expires_at.parse().map_err(|_| format!("bad expires_at '{}'", expires_at))?);
+    }
+    if !metadata.is_empty() {
+        *value.metadata_mut() = serde_json::from_str::<BTreeMap<String, MetadataValue>>(metadata).map_err(|e| format!("bad metadata: {}", e))?;
+    }
+    Ok((key, value))
+}
+
+// Fields may be quoted, with quotes inside doubled
+fn split_csv(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.trim_end_matches(['\r', '\n']).chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => fields.push(String::new()),
+            (c, _) => field.push(c),
+        }
+    }
+    match quoted {
+        true => Err("unterminated quoted field".to_string()),
+        false => Ok(fields),
+    }
+}
+
+fn quote(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+fn malformed(line: usize, reason: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line + 1, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let records = vec![
+            (1, Vector::new(1, vec![0.5, -1.25e-7])),
+            (2, Vector::new(9, vec![]).with_metadata("title", "a \"quoted\", comma").with_metadata("rank", 3i64).with_metadata("score", 0.5)),
+        ];
+        let mut expiring = Vector::new(3, vec![1.0]);
+        expiring.set_expires_at(1_700_000_000_000);
+        let records: Vec<(u64, Vector)> = records.into_iter().chain([(3, expiring)]).collect();
+
+        for format in [ExportFormat::JsonLines, ExportFormat::Csv] {
+            let mut out = Vec::new();
+            write_header(&mut out, format).unwrap();
+            for (key, value) in &records {
+                write_record(&mut out, format, *key, value).unwrap();
+            }
+            let read: Vec<(u64, Vector)> = read_records(out.as_slice(), format).collect::<io::Result<_>>().unwrap();
+            assert_eq!(read, records, "{:?}", format);
+        }
+
+        let read: Vec<(u64, Vector)> = read_records(&b"{\"id\": 4, \"data\": [1]}\n\n"[..], ExportFormat::JsonLines).collect::<io::Result<_>>().unwrap();
+        assert_eq!(read, vec![(4, Vector::new(4, vec![1.0]))]);
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let errors = |input: &str, format| read_records(input.as_bytes(), format).filter_map(Result::err).map(|e| e.to_string()).collect::<Vec<_>>();
+        assert!(errors("key,id\n", ExportFormat::Csv)[0].starts_with("line 1: expected the header"));
+        assert!(errors(&format!("{}\n1,1,,1 x,\n", CSV_HEADER), ExportFormat::Csv)[0].starts_with("line 2: 'x' is not a number"));
+        assert!(errors(&format!("{}\n1,1,,1\n", CSV_HEADER), ExportFormat::Csv)[0].contains("expected 5 fields"));
+        assert!(errors(&format!("{}\n1,1,,1,\"{{\n", CSV_HEADER), ExportFormat::Csv)[0].contains("unterminated"));
+        assert_eq!(errors("{\"id\": 1, \"data\": [1]}\n{\"data\": [1]}\n", ExportFormat::JsonLines).len(), 1);
+    }
+}
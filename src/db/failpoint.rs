@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+
+// Moments the durability guarantees hinge on, where a test can make a tree stop as if its
+// process died there and then check what recovery makes of the files left behind. Armed
+// per tree through `Options::failpoints`, so trees in other tests of the same process go
+// on undisturbed. Once one fires every later write of the tree fails the same way, as
+// nothing more would have reached the disk, until `LSMTree::crash_and_reopen` opens the
+// directory again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailPoint {
+    // a write batch is in the WAL's file but not yet synced, and the write hasn't returned
+    WalAppend,
+    // a flush or compaction has written half of a table under its temporary name
+    TableWrite,
+    // a manifest edit, installing a table, rotating the log or recording a setting, is
+    // about to be appended
+    ManifestCommit,
+}
+
+#[derive(Debug, Default)]
+pub struct FailPoints {
+    state: Mutex<Armed>,
+}
+
+#[derive(Debug, Default)]
+struct Armed {
+    // how many more times each point passes before it fires
+    points: BTreeMap<FailPoint, u64>,
+    fired: Option<FailPoint>,
+}
+
+impl FailPoints {
+    pub fn new() -> FailPoints {
+        FailPoints::default()
+    }
+
+    // `point` fires once it has been passed `skip` more times: 0 fires it the next time
+    // it is reached
+    pub fn arm(&self, point: FailPoint, skip: u64) {
+        self.state.lock().unwrap().points.insert(point, skip);
+    }
+
+    pub fn disarm(&self, point: FailPoint) {
+        self.state.lock().unwrap().points.remove(&point);
+    }
+
+    // The point that fired, which every write since has failed at
+    pub fn fired(&self) -> Option<FailPoint> {
+        self.state.lock().unwrap().fired
+    }
+
+    // Forgets the crash and disarms every point
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = Armed::default();
+    }
+
+    // Err once `point` or another has fired
+    pub(crate) fn check(&self, point: FailPoint) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.fired.is_none() {
+            match state.points.get_mut(&point) {
+                Some(0) => {
+                    state.points.remove(&point);
+                    state.fired = Some(point);
+                }
+                Some(skip) => *skip -= 1,
+                None => {}
+            }
+        }
+        match state.fired {
+            Some(fired) => Err(crashed(fired)),
+            None => Ok(()),
+        }
+    }
+
+    // Err once a point has fired, for writes that reach no point on their way to the disk
+    pub(crate) fn check_crashed(&self) -> io::Result<()> {
+        match self.state.lock().unwrap().fired {
+            Some(fired) => Err(crashed(fired)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn crashed(point: FailPoint) -> io::Error {
+    io::Error::other(format!("the tree crashed at failpoint {:?}", point))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arm() {
+        let failpoints = FailPoints::new();
+        failpoints.check(FailPoint::WalAppend).unwrap();
+        failpoints.arm(FailPoint::WalAppend, 2);
+        failpoints.arm(FailPoint::TableWrite, 5);
+        failpoints.check(FailPoint::WalAppend).unwrap();
+        failpoints.check(FailPoint::ManifestCommit).unwrap();
+        failpoints.check(FailPoint::WalAppend).unwrap();
+        assert_eq!(failpoints.fired(), None);
+
+        let e = failpoints.check(FailPoint::WalAppend).err().unwrap();
+        assert_eq!(e.to_string(), "the tree crashed at failpoint WalAppend");
+        assert_eq!(failpoints.fired(), Some(FailPoint::WalAppend));
+        // after the crash nothing passes, armed or not
+        assert!(failpoints.check(FailPoint::ManifestCommit).is_err());
+        assert!(failpoints.check_crashed().is_err());
+
+        failpoints.reset();
+        failpoints.check(FailPoint::TableWrite).unwrap();
+        failpoints.check_crashed().unwrap();
+        failpoints.arm(FailPoint::TableWrite, 0);
+        failpoints.disarm(FailPoint::TableWrite);
+        failpoints.check(FailPoint::TableWrite).unwrap();
+    }
+}
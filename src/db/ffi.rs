@@ -0,0 +1,240 @@
+// what each function expects of its pointers is spelled out in the comments on it and in
+// include/lsm.h, C's side of the contract, rather than in rustdoc
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use crate::db::error::LsmError;
+use crate::db::lsm::LSMTree;
+use crate::db::vector::Vector;
+
+// A C API over a tree, declared in include/lsm.h, for embedding it from C or anything
+// that can call C, like Go's cgo or Python's ctypes. Every function returns one of the
+// codes below, and on failure the message stays readable through `lsm_last_error` until
+// the thread's next call. A tree is owned by the caller from `lsm_open` until
+// `lsm_close`, and may be shared between threads meanwhile. Vectors handed out by
+// `lsm_get` are the caller's to free with `lsm_free_vector`; everything else is written
+// into memory the caller provides. A panic is caught at the boundary and returned as
+// LSM_PANIC instead of unwinding into the caller.
+pub const LSM_OK: c_int = 0;
+pub const LSM_NOT_FOUND: c_int = 1;
+pub const LSM_INVALID_ARGUMENT: c_int = 2;
+pub const LSM_CORRUPTION: c_int = 3;
+pub const LSM_BUSY: c_int = 4;
+pub const LSM_IO_ERROR: c_int = 5;
+pub const LSM_PANIC: c_int = 6;
+
+// What `lsm_open` hands out, opaque to the caller
+pub struct LsmTree(LSMTree);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn code(e: &LsmError) -> c_int {
+    match e {
+        LsmError::NotFound(_) => LSM_NOT_FOUND,
+        LsmError::InvalidArgument(_) => LSM_INVALID_ARGUMENT,
+        LsmError::Corruption { .. } => LSM_CORRUPTION,
+        LsmError::Io(e) if e.kind() == io::ErrorKind::ResourceBusy => LSM_BUSY,
+        LsmError::Io(_) | LsmError::Serialization(_) => LSM_IO_ERROR,
+    }
+}
+
+fn invalid(message: &str) -> LsmError {
+    LsmError::InvalidArgument(message.to_string())
+}
+
+// Runs `f`, turning its error or panic into a code and the thread's last error
+fn guard(f: impl FnOnce() -> Result<(), LsmError>) -> c_int {
+    let (code, message) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => (LSM_OK, None),
+        Ok(Err(e)) => (code(&e), Some(e.to_string())),
+        Err(_) => (LSM_PANIC, Some("the tree panicked".to_string())),
+    };
+    // a message can't hold a nul in C
+    let message = message.map(|m| CString::new(m.replace('\0', " ")).unwrap());
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    code
+}
+
+unsafe fn tree<'a>(tree: *const LsmTree) -> Result<&'a LSMTree, LsmError> {
+    unsafe { tree.as_ref() }.map(|tree| &tree.0).ok_or_else(|| invalid("tree is null"))
+}
+
+unsafe fn floats<'a>(data: *const f64, len: usize) -> Result<&'a [f64], LsmError> {
+    match (data.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(invalid("vector is null")),
+        (false, _) => Ok(unsafe { std::slice::from_raw_parts(data, len) }),
+    }
+}
+
+// The message of this thread's last failed call, or null after one that succeeded. It
+// is freed by the thread's next call.
+#[unsafe(no_mangle)]
+pub extern "C" fn lsm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(std::ptr::null(), |message| message.as_ptr()))
+}
+
+// Opens or creates the tree in the directory `path`, a nul-terminated UTF-8 string, with
+// the default options, storing it in `*out`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lsm_open(path: *const c_char, out: *mut *mut LsmTree) -> c_int {
+    guard(|| {
+        if path.is_null() || out.is_null() {
+            return Err(invalid("path and out must not be null"));
+        }
+        let path = unsafe { CStr::from_ptr(path) }.to_str().map_err(|_| invalid("path isn't UTF-8"))?;
+        let tree = LSMTree::new(Path::new(path))?;
+        unsafe { *out = Box::into_raw(Box::new(LsmTree(tree))) };
+        Ok(())
+    })
+}
+
+// Stores the `dimension` values at `data` under `key`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lsm_put(tree: *const LsmTree, key: u64, data: *const f64, dimension: usize) -> c_int {
+    guard(|| {
+        let tree = unsafe { self::tree(tree) }?;
+        let data = unsafe { floats(data, dimension) }?;
+        tree.insert(key, Vector::new(key, data.to_vec()))
+    })
+}
+
+// Stores a copy of the values under `key` in `*data` and their count in `*dimension`, or
+// returns LSM_NOT_FOUND
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lsm_get(tree: *const LsmTree, key: u64, data: *mut *mut f64, dimension: *mut usize) -> c_int {
+    guard(|| {
+        let tree = unsafe { self::tree(tree) }?;
+        if data.is_null() || dimension.is_null() {
+            return Err(invalid("data and dimension must not be null"));
+        }
+        let Some(value) = tree.get(key)? else {
+            return Err(LsmError::NotFound(format!("no key {}", key)));
+        };
+        let values = value.data().clone().into_boxed_slice();
+        unsafe {
+            *dimension = values.len();
+            *data = Box::into_raw(values) as *mut f64;
+        }
+        Ok(())
+    })
+}
+
+// Frees what `lsm_get` stored in `data`, given the dimension it stored alongside
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lsm_free_vector(data: *mut f64, dimension: usize) {
+    if !data.is_null() {
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, dimension)) });
+    }
+}
+
+// Deletes `key`, setting `*deleted` to whether it was live unless `deleted` is null. A
+// missing key is left alone rather than failing.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lsm_delete(tree: *const LsmTree, key: u64, deleted: *mut c_int) -> c_int {
+    guard(|| {
+        let found = unsafe { self::tree(tree) }?.delete(key)?;
+        if let Some(deleted) = unsafe { deleted.as_mut() } {
+            *deleted = found as c_int;
+        }
+        Ok(())
+    })
+}
+
+// The `k` stored vectors closest to the query, closest first, as `LSMTree::knn` finds
+// them. `keys` and `distances` must each have room for `k` values; `*found` is set to how
+// many were written, fewer than `k` when the tree holds fewer vectors.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lsm_search(tree: *const LsmTree, query: *const f64, dimension: usize, k: usize, keys: *mut u64, distances: *mut f64, found: *mut usize) -> c_int {
+    guard(|| {
+        let tree = unsafe { self::tree(tree) }?;
+        let query = unsafe { floats(query, dimension) }?;
+        if (k != 0 && (keys.is_null() || distances.is_null())) || found.is_null() {
+            return Err(invalid("keys, distances and found must not be null"));
+        }
+        let results = tree.knn(query, k)?;
+        for (i, (key, distance)) in results.iter().take(k).enumerate() {
+            unsafe {
+                *keys.add(i) = *key;
+                *distances.add(i) = *distance;
+            }
+        }
+        unsafe { *found = results.len().min(k) };
+        Ok(())
+    })
+}
+
+// Flushes and closes the tree, which is freed whether or not that succeeds
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lsm_close(tree: *mut LsmTree) -> c_int {
+    guard(|| {
+        if tree.is_null() {
+            return Err(invalid("tree is null"));
+        }
+        unsafe { Box::from_raw(tree) }.0.close()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(lsm_last_error()) }.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_c_api() {
+        let path = test_dir("ffi_c_api");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let mut tree = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(lsm_open(c_path.as_ptr(), &mut tree), LSM_OK);
+            assert!(lsm_last_error().is_null());
+            for key in 0..5u64 {
+                let data = [key as f64, 0.0];
+                assert_eq!(lsm_put(tree, key, data.as_ptr(), data.len()), LSM_OK);
+            }
+            let mut deleted = 0;
+            assert_eq!(lsm_delete(tree, 4, &mut deleted), LSM_OK);
+            assert_eq!(deleted, 1);
+            assert_eq!(lsm_delete(tree, 4, &mut deleted), LSM_OK);
+            assert_eq!(deleted, 0);
+            assert_eq!(lsm_delete(tree, 9, std::ptr::null_mut()), LSM_OK);
+
+            let (mut data, mut dimension) = (std::ptr::null_mut(), 0);
+            assert_eq!(lsm_get(tree, 3, &mut data, &mut dimension), LSM_OK);
+            assert_eq!(std::slice::from_raw_parts(data, dimension), &[3.0, 0.0]);
+            lsm_free_vector(data, dimension);
+            assert_eq!(lsm_get(tree, 4, &mut data, &mut dimension), LSM_NOT_FOUND);
+            assert_eq!(last_error(), "no key 4");
+
+            let query = [2.2, 0.0];
+            let (mut keys, mut distances, mut found) = ([0u64; 8], [0.0; 8], 0);
+            assert_eq!(lsm_search(tree, query.as_ptr(), 2, 2, keys.as_mut_ptr(), distances.as_mut_ptr(), &mut found), LSM_OK);
+            assert_eq!((found, &keys[..2]), (2, &[2, 3][..]));
+            assert_eq!(lsm_search(tree, query.as_ptr(), 2, 8, keys.as_mut_ptr(), distances.as_mut_ptr(), &mut found), LSM_OK);
+            assert_eq!(found, 4);
+
+            // the directory is locked while the tree is open
+            let mut second = std::ptr::null_mut();
+            assert_eq!(lsm_open(c_path.as_ptr(), &mut second), LSM_BUSY);
+            assert!(second.is_null());
+            assert_eq!(lsm_put(std::ptr::null(), 1, query.as_ptr(), 2), LSM_INVALID_ARGUMENT);
+            assert_eq!(lsm_put(tree, 1, std::ptr::null(), 2), LSM_INVALID_ARGUMENT);
+            assert_eq!(lsm_close(tree), LSM_OK);
+
+            assert_eq!(lsm_open(c_path.as_ptr(), &mut tree), LSM_OK);
+            assert_eq!(lsm_get(tree, 0, &mut data, &mut dimension), LSM_OK);
+            lsm_free_vector(data, dimension);
+            assert_eq!(lsm_close(tree), LSM_OK);
+        }
+    }
+}
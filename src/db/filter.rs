@@ -0,0 +1,114 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use crate::db::vector::MetadataValue;
+
+// Predicate over a vector's metadata for `LSMTree::search_filtered`. A field that is
+// missing, or holds a value that doesn't compare with the one given, doesn't match.
+// Ints and floats compare by value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Eq(String, MetadataValue),
+    // inclusive bounds, either side open when None
+    Range(String, Option<MetadataValue>, Option<MetadataValue>),
+    In(String, Vec<MetadataValue>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn eq(field: impl Into<String>, value: impl Into<MetadataValue>) -> Filter {
+        Filter::Eq(field.into(), value.into())
+    }
+
+    pub fn range(field: impl Into<String>, min: Option<MetadataValue>, max: Option<MetadataValue>) -> Filter {
+        Filter::Range(field.into(), min, max)
+    }
+
+    pub fn is_in<V: Into<MetadataValue>>(field: impl Into<String>, values: impl IntoIterator<Item = V>) -> Filter {
+        Filter::In(field.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    pub fn and(self, other: Filter) -> Filter {
+        match self {
+            Filter::And(mut all) => {
+                all.push(other);
+                Filter::And(all)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter) -> Filter {
+        match self {
+            Filter::Or(mut any) => {
+                any.push(other);
+                Filter::Or(any)
+            }
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    pub fn negate(self) -> Filter {
+        Filter::Not(Box::new(self))
+    }
+
+    pub fn matches(&self, metadata: &BTreeMap<String, MetadataValue>) -> bool {
+        match self {
+            Filter::Eq(field, value) => metadata.get(field).is_some_and(|v| compare(v, value) == Some(Ordering::Equal)),
+            Filter::Range(field, min, max) => metadata.get(field).is_some_and(|v| {
+                let above = min.as_ref().is_none_or(|min| compare(v, min).is_some_and(Ordering::is_ge));
+                let below = max.as_ref().is_none_or(|max| compare(v, max).is_some_and(Ordering::is_le));
+                above && below
+            }),
+            Filter::In(field, values) => metadata.get(field).is_some_and(|v| values.iter().any(|value| compare(v, value) == Some(Ordering::Equal))),
+            Filter::And(all) => all.iter().all(|f| f.matches(metadata)),
+            Filter::Or(any) => any.iter().any(|f| f.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata),
+        }
+    }
+}
+
+// None for values of different kinds, and for NaN
+fn compare(a: &MetadataValue, b: &MetadataValue) -> Option<Ordering> {
+    match (a, b) {
+        (MetadataValue::Bool(a), MetadataValue::Bool(b)) => Some(a.cmp(b)),
+        (MetadataValue::Int(a), MetadataValue::Int(b)) => Some(a.cmp(b)),
+        (MetadataValue::String(a), MetadataValue::String(b)) => Some(a.cmp(b)),
+        (MetadataValue::Float(a), MetadataValue::Float(b)) => a.partial_cmp(b),
+        (MetadataValue::Int(a), MetadataValue::Float(b)) => (*a as f64).partial_cmp(b),
+        (MetadataValue::Float(a), MetadataValue::Int(b)) => a.partial_cmp(&(*b as f64)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::vector::Vector;
+
+    #[test]
+    fn test_matches() {
+        let value = Vector::new(1, vec![]).with_metadata("lang", "en").with_metadata("ts", 100i64).with_metadata("score", 0.5).with_metadata("public", true);
+        let metadata = value.metadata();
+
+        assert!(Filter::eq("lang", "en").matches(metadata));
+        assert!(!Filter::eq("lang", "de").matches(metadata));
+        assert!(!Filter::eq("missing", "en").matches(metadata));
+        assert!(!Filter::eq("lang", 1i64).matches(metadata));
+        assert!(Filter::eq("ts", 100.0).matches(metadata));
+
+        assert!(Filter::range("ts", Some(100i64.into()), Some(200i64.into())).matches(metadata));
+        assert!(Filter::range("ts", None, Some(99.5.into())).negate().matches(metadata));
+        assert!(Filter::range("score", Some(0.25.into()), None).matches(metadata));
+        assert!(!Filter::range("lang", Some(1i64.into()), None).matches(metadata));
+
+        assert!(Filter::is_in("lang", ["fr", "en"]).matches(metadata));
+        assert!(!Filter::is_in("lang", ["fr", "de"]).matches(metadata));
+
+        let tenant = Filter::eq("public", true).and(Filter::is_in("lang", ["en"]));
+        assert!(tenant.matches(metadata));
+        assert!(!tenant.clone().and(Filter::eq("lang", "de")).matches(metadata));
+        assert!(Filter::eq("lang", "de").or(tenant).matches(metadata));
+    }
+}
@@ -0,0 +1,108 @@
+// 16 bit floats for `ElementType::F16` (IEEE binary16) and `ElementType::BF16` (the top
+// half of an f32), converted straight from and to f64 so nothing is rounded twice
+const F16: (u32, u32) = (5, 10);
+const BF16: (u32, u32) = (8, 7);
+
+pub(crate) fn f16_from_f64(x: f64) -> u16 {
+    narrow(x, F16)
+}
+
+pub(crate) fn f16_to_f64(h: u16) -> f64 {
+    widen(h, F16)
+}
+
+pub(crate) fn bf16_from_f64(x: f64) -> u16 {
+    narrow(x, BF16)
+}
+
+pub(crate) fn bf16_to_f64(h: u16) -> f64 {
+    widen(h, BF16)
+}
+
+// To the nearest float with the given exponent and mantissa bits, ties to even. Too
+// large is infinite, too small is zero, and NaN stays NaN.
+fn narrow(x: f64, (exponent_bits, mantissa_bits): (u32, u32)) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 63) as u16) << 15;
+    let exponent = ((bits >> 52) & 0x7ff) as i64;
+    let mantissa = bits & ((1 << 52) - 1);
+    let max_exponent = (1i64 << exponent_bits) - 1;
+    let infinity = (max_exponent as u16) << mantissa_bits;
+    if exponent == 0x7ff {
+        let quiet = if mantissa != 0 { 1 << (mantissa_bits - 1) } else { 0 };
+        return sign | infinity | quiet;
+    }
+
+    let biased = exponent - 1023 + (max_exponent >> 1);
+    let dropped = 52 - mantissa_bits;
+    if biased >= max_exponent {
+        return sign | infinity;
+    }
+    if biased > 0 {
+        // a mantissa that rounds up carries into the exponent, up to infinity
+        return sign | round_shift(((biased as u64) << 52) | mantissa, dropped) as u16;
+    }
+    // below the smallest normal the implicit bit shifts down into the mantissa
+    let shift = dropped as i64 + 1 - biased;
+    if shift > 53 {
+        return sign;
+    }
+    sign | round_shift(mantissa | (1 << 52), shift as u32) as u16
+}
+
+fn widen(h: u16, (exponent_bits, mantissa_bits): (u32, u32)) -> f64 {
+    let sign = if h >> 15 == 1 { -1.0 } else { 1.0 };
+    let max_exponent = (1i32 << exponent_bits) - 1;
+    let bias = max_exponent >> 1;
+    let exponent = (h as i32 >> mantissa_bits) & max_exponent;
+    let mantissa = (h & ((1 << mantissa_bits) - 1)) as f64;
+    let scale = (1u32 << mantissa_bits) as f64;
+    match exponent {
+        0 => sign * mantissa * 2f64.powi(1 - bias - mantissa_bits as i32),
+        e if e == max_exponent && mantissa == 0.0 => sign * f64::INFINITY,
+        e if e == max_exponent => f64::NAN,
+        e => sign * (1.0 + mantissa / scale) * 2f64.powi(e - bias),
+    }
+}
+
+// `value >> shift`, rounded to nearest with ties to even
+fn round_shift(value: u64, shift: u32) -> u64 {
+    let kept = value >> shift;
+    let rest = value & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+    if rest > half || (rest == half && kept & 1 == 1) { kept + 1 } else { kept }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f16() {
+        for (x, h) in [(1.0, 0x3c00), (-2.0, 0xc000), (0.1, 0x2e66), (65504.0, 0x7bff), (65520.0, 0x7c00), (1e10, 0x7c00), (-0.0, 0x8000)] {
+            assert_eq!(f16_from_f64(x), h, "{}", x);
+        }
+        // the smallest subnormal, and half of it which ties to even zero
+        assert_eq!(f16_from_f64(2f64.powi(-24)), 1);
+        assert_eq!(f16_from_f64(2f64.powi(-25)), 0);
+        assert_eq!(f16_from_f64(1.5 * 2f64.powi(-25)), 1);
+        assert!(f16_to_f64(f16_from_f64(f64::NAN)).is_nan());
+        assert_eq!(f16_to_f64(0x7c00), f64::INFINITY);
+        // every f16 is an f64 that converts back to it
+        for h in (0..=u16::MAX).filter(|&h| h & 0x7c00 != 0x7c00 || h & 0x3ff == 0) {
+            assert_eq!(f16_from_f64(f16_to_f64(h)), h, "{:#06x}", h);
+        }
+    }
+
+    #[test]
+    fn test_bf16() {
+        for (x, h) in [(1.0, 0x3f80), (0.1, 0x3dcd), (-3.0, 0xc040), (f64::MAX, 0x7f80)] {
+            assert_eq!(bf16_from_f64(x), h, "{}", x);
+        }
+        for h in (0..=u16::MAX).filter(|&h| h & 0x7f80 != 0x7f80 || h & 0x7f == 0) {
+            assert_eq!(bf16_from_f64(bf16_to_f64(h)), h, "{:#06x}", h);
+            // the top half of the f32 with the same value
+            assert_eq!(bf16_to_f64(h), f32::from_bits((h as u32) << 16) as f64);
+        }
+    }
+}
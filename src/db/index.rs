@@ -0,0 +1,7 @@
+// Approximate nearest neighbor indexes, and secondary indexes over metadata fields, kept
+// in step with the tree's writes
+pub(crate) mod field;
+pub mod hnsw;
+pub mod ivf;
+pub mod pq;
+pub(crate) mod queue;
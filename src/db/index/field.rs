@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::ops::{Bound, Range};
+use crate::db::checksum;
+use crate::db::filter::Filter;
+use crate::db::vector::MetadataValue;
+
+const MAGIC: [u8; 8] = *b"LSMFIELD";
+// field tables the manifest may list before they are merged into one
+pub(crate) const TABLE_LIMIT: usize = 8;
+
+// Secondary indexes over the metadata fields named in `Options::indexed_fields`: for each
+// field, the keys holding each value. Ints and floats are filed together by their value as
+// a float, so an int past 2^53 may share its list with its neighbours; lookups check the
+// candidates against the filter.
+//
+// The lists are kept on disk as field tables of entries, each a field, a value and a key
+// (see `encode_entry`), that the tree reads back when opened. What a memtable's writes
+// filed goes into a table flushed with it, so the tables and the log replayed on top of
+// them hold every entry. Nothing is taken out of a table: an entry a key no longer has
+// lingers until the tables are merged into one holding what the index files then, and
+// until then costs a lookup a read that the filter turns away.
+pub(crate) struct FieldIndex {
+    fields: Vec<String>,
+    // for each field, same order as `fields`, the keys holding each value
+    lists: Vec<BTreeMap<FieldKey, BTreeSet<u64>>>,
+    // the indexed values of every key, to find it again on removal
+    indexed: BTreeMap<u64, Vec<(usize, FieldKey)>>,
+    // entries filed since the last `take_added`, for the next field table
+    added: BTreeSet<Vec<u8>>,
+}
+
+// A value as the lists order it: values of different kinds never compare, so each kind
+// sorts apart from the others
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum FieldKey {
+    Bool(bool),
+    // a float's bits, flipped so they sort like its value
+    Number(u64),
+    String(String),
+}
+
+impl FieldKey {
+    // None for NaN, which matches no filter
+    fn of(value: &MetadataValue) -> Option<FieldKey> {
+        match value {
+            MetadataValue::Bool(b) => Some(FieldKey::Bool(*b)),
+            MetadataValue::Int(i) => Some(FieldKey::number(*i as f64)),
+            MetadataValue::Float(f) if f.is_nan() => None,
+            MetadataValue::Float(f) => Some(FieldKey::number(*f)),
+            MetadataValue::String(s) => Some(FieldKey::String(s.clone())),
+        }
+    }
+
+    fn number(f: f64) -> FieldKey {
+        // -0.0 equals 0.0, so both are filed as 0.0
+        let bits = (f + 0.0).to_bits();
+        FieldKey::Number(if bits >> 63 == 1 { !bits } else { bits | 1 << 63 })
+    }
+
+    // The smallest key of the value's kind
+    fn kind_start(&self) -> Bound<FieldKey> {
+        Bound::Included(match self {
+            FieldKey::Bool(_) => FieldKey::Bool(false),
+            FieldKey::Number(_) => FieldKey::Number(0),
+            FieldKey::String(_) => FieldKey::String(String::new()),
+        })
+    }
+
+    // The largest key of the value's kind, strings sorting last
+    fn kind_end(&self) -> Bound<FieldKey> {
+        match self {
+            FieldKey::Bool(_) => Bound::Included(FieldKey::Bool(true)),
+            FieldKey::Number(_) => Bound::Included(FieldKey::Number(u64::MAX)),
+            FieldKey::String(_) => Bound::Unbounded,
+        }
+    }
+
+    // A kind tag (u8) then the value: a bool as a byte, a number's bits big endian, a
+    // string's bytes to the end
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            FieldKey::Bool(b) => out.extend_from_slice(&[0, *b as u8]),
+            FieldKey::Number(bits) => {
+                out.push(1);
+                out.extend_from_slice(&bits.to_be_bytes());
+            }
+            FieldKey::String(s) => {
+                out.push(2);
+                out.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<FieldKey> {
+        match bytes.split_first()? {
+            (0, [b @ (0 | 1)]) => Some(FieldKey::Bool(*b == 1)),
+            (1, bits) => Some(FieldKey::Number(u64::from_be_bytes(bits.try_into().ok()?))),
+            (2, s) => Some(FieldKey::String(String::from_utf8(s.to_vec()).ok()?)),
+            _ => None,
+        }
+    }
+}
+
+// A field table entry: the field's name after its length (u16), the value, then the key
+// (u64), big endian throughout
+fn encode_entry(field: &str, value: &FieldKey, key: u64) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(2 + field.len() + 9 + 8);
+    entry.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    entry.extend_from_slice(field.as_bytes());
+    value.encode(&mut entry);
+    entry.extend_from_slice(&key.to_be_bytes());
+    entry
+}
+
+fn decode_entry(entry: &[u8]) -> Option<(&str, FieldKey, u64)> {
+    let (len, rest) = entry.split_first_chunk::<2>()?;
+    let len = u16::from_be_bytes(*len) as usize;
+    let field = std::str::from_utf8(rest.get(..len)?).ok()?;
+    let (value, key) = rest[len..].split_last_chunk::<8>()?;
+    Some((field, FieldKey::decode(value)?, u64::from_be_bytes(*key)))
+}
+
+// A field table: MAGIC, each entry after its length (u32), then a CRC-32 of everything
+// before it
+pub(crate) fn encode_table<'a>(entries: impl IntoIterator<Item = &'a Vec<u8>>) -> Vec<u8> {
+    let mut table = MAGIC.to_vec();
+    for entry in entries {
+        table.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        table.extend_from_slice(entry);
+    }
+    let crc = checksum::crc32(&table);
+    table.extend_from_slice(&crc.to_le_bytes());
+    table
+}
+
+fn decode_table(table: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let corruption = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let Some((body, crc)) = table.split_last_chunk::<4>().filter(|(body, _)| body.starts_with(&MAGIC)) else {
+        return Err(corruption("not a field table"));
+    };
+    if checksum::crc32(body) != u32::from_le_bytes(*crc) {
+        return Err(corruption("field table checksum mismatch"));
+    }
+    let mut entries = Vec::new();
+    let mut rest = &body[MAGIC.len()..];
+    while let Some((len, after)) = rest.split_first_chunk::<4>() {
+        let len = u32::from_le_bytes(*len) as usize;
+        if len > after.len() {
+            return Err(corruption("field table entry runs past the end of the table"));
+        }
+        entries.push(&after[..len]);
+        rest = &after[len..];
+    }
+    if !rest.is_empty() {
+        return Err(corruption("field table ends inside an entry length"));
+    }
+    Ok(entries)
+}
+
+impl FieldIndex {
+    pub(crate) fn new(fields: &[String]) -> FieldIndex {
+        FieldIndex { fields: fields.to_vec(), lists: vec![BTreeMap::new(); fields.len()], indexed: BTreeMap::new(), added: BTreeSet::new() }
+    }
+
+    // Files the entries of a field table along with what is filed already, skipping ones
+    // for fields the index doesn't keep
+    pub(crate) fn load(&mut self, table: &[u8]) -> io::Result<()> {
+        for entry in decode_table(table)? {
+            let Some((field, value, key)) = decode_entry(entry) else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed field table entry"));
+            };
+            let Some(i) = self.fields.iter().position(|f| f == field) else { continue };
+            let values = self.indexed.entry(key).or_default();
+            if !values.contains(&(i, value.clone())) {
+                self.lists[i].entry(value.clone()).or_default().insert(key);
+                values.push((i, value));
+            }
+        }
+        Ok(())
+    }
+
+    // The entries filed since the last call, leaving the next ones to be collected afresh
+    pub(crate) fn take_added(&mut self) -> BTreeSet<Vec<u8>> {
+        std::mem::take(&mut self.added)
+    }
+
+    // Puts back what `take_added` returned when it couldn't be written out
+    pub(crate) fn restore_added(&mut self, entries: BTreeSet<Vec<u8>>) {
+        self.added.extend(entries);
+    }
+
+    // Every entry the index files now, for a table to replace all the others
+    pub(crate) fn entries(&self) -> BTreeSet<Vec<u8>> {
+        let entries = self.indexed.iter().flat_map(|(&key, values)| values.iter().map(move |(i, value)| (key, *i, value)));
+        entries.map(|(key, i, value)| encode_entry(&self.fields[i], value, key)).collect()
+    }
+
+    // The entries `insert` would file for the key, without filing them
+    pub(crate) fn entries_of(&self, key: u64, metadata: &BTreeMap<String, MetadataValue>) -> Vec<Vec<u8>> {
+        self.fields.iter()
+            .filter_map(|field| Some(encode_entry(field, &FieldKey::of(metadata.get(field)?)?, key)))
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.indexed.len()
+    }
+
+    // Files the key under each indexed field it has, replacing what it was filed under
+    pub(crate) fn insert(&mut self, key: u64, metadata: &BTreeMap<String, MetadataValue>) {
+        self.remove(key);
+        let values: Vec<(usize, FieldKey)> = self.fields.iter().enumerate()
+            .filter_map(|(i, field)| Some((i, FieldKey::of(metadata.get(field)?)?)))
+            .collect();
+        if values.is_empty() {
+            return;
+        }
+        for (i, value) in values.iter() {
+            self.lists[*i].entry(value.clone()).or_default().insert(key);
+            self.added.insert(encode_entry(&self.fields[*i], value, key));
+        }
+        self.indexed.insert(key, values);
+    }
+
+    pub(crate) fn remove(&mut self, key: u64) {
+        for (i, value) in self.indexed.remove(&key).into_iter().flatten() {
+            if let Some(keys) = self.lists[i].get_mut(&value) {
+                keys.remove(&key);
+                if keys.is_empty() {
+                    self.lists[i].remove(&value);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn remove_range(&mut self, range: Range<u64>) {
+        let keys: Vec<u64> = self.indexed.range(range).map(|(&k, _)| k).collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    // Every key the filter may match, from the lists of the indexed fields it tests, or
+    // None when it tests some other way: a field without an index, or a negation
+    pub(crate) fn candidates(&self, filter: &Filter) -> Option<BTreeSet<u64>> {
+        match filter {
+            Filter::Eq(field, value) => Some(self.keys(field, std::slice::from_ref(value))?),
+            Filter::In(field, values) => Some(self.keys(field, values)?),
+            Filter::Range(field, min, max) => self.range(field, min.as_ref(), max.as_ref()),
+            // the narrowest of the parts that can be answered, checked against the rest
+            Filter::And(all) => all.iter().filter_map(|f| self.candidates(f)).min_by_key(BTreeSet::len),
+            Filter::Or(any) => any.iter().map(|f| self.candidates(f)).try_fold(BTreeSet::new(), |mut keys, found| {
+                keys.extend(found?);
+                Some(keys)
+            }),
+            Filter::Not(_) => None,
+        }
+    }
+
+    fn list(&self, field: &str) -> Option<&BTreeMap<FieldKey, BTreeSet<u64>>> {
+        self.fields.iter().position(|f| f == field).map(|i| &self.lists[i])
+    }
+
+    fn keys(&self, field: &str, values: &[MetadataValue]) -> Option<BTreeSet<u64>> {
+        let list = self.list(field)?;
+        let lists = values.iter().filter_map(FieldKey::of).filter_map(|value| list.get(&value));
+        Some(lists.flatten().copied().collect())
+    }
+
+    fn range(&self, field: &str, min: Option<&MetadataValue>, max: Option<&MetadataValue>) -> Option<BTreeSet<u64>> {
+        let list = self.list(field)?;
+        let bounds = match (min.map(FieldKey::of), max.map(FieldKey::of)) {
+            // a NaN bound matches nothing
+            (Some(None), _) | (_, Some(None)) => return Some(BTreeSet::new()),
+            (Some(Some(min)), Some(Some(max))) => {
+                if std::mem::discriminant(&min) != std::mem::discriminant(&max) || min > max {
+                    return Some(BTreeSet::new());
+                }
+                (Bound::Included(min), Bound::Included(max))
+            }
+            (Some(Some(min)), None) => {
+                let end = min.kind_end();
+                (Bound::Included(min), end)
+            }
+            (None, Some(Some(max))) => (max.kind_start(), Bound::Included(max)),
+            // any value matches an open range
+            (None, None) => return Some(list.values().flatten().copied().collect()),
+        };
+        let lists = list.range(bounds).map(|(_, keys)| keys);
+        Some(lists.flatten().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::vector::Vector;
+
+    #[test]
+    fn test_candidates() {
+        let mut index = FieldIndex::new(&["tag".to_string(), "ts".to_string()]);
+        for key in 0..10u64 {
+            let value = Vector::new(key, vec![]).with_metadata("tag", ["a", "b"][key as usize % 2]).with_metadata("ts", key as i64).with_metadata("other", 1i64);
+            index.insert(key, value.metadata());
+        }
+        index.insert(10, Vector::new(10, vec![]).with_metadata("ts", 2.5).metadata());
+        index.insert(11, Vector::new(11, vec![]).with_metadata("ts", f64::NAN).with_metadata("lang", "en").metadata());
+        assert_eq!(index.len(), 11);
+
+        let candidates = |index: &FieldIndex, filter: &Filter| index.candidates(filter).map(|keys| keys.into_iter().collect::<Vec<u64>>());
+        let keys = |filter: &Filter| candidates(&index, filter);
+        assert_eq!(keys(&Filter::eq("other", 1i64)), None);
+        assert_eq!(keys(&Filter::eq("tag", "a")), Some(vec![0, 2, 4, 6, 8]));
+        // ints and floats compare by value
+        assert_eq!(keys(&Filter::eq("ts", 3.0)), Some(vec![3]));
+        assert_eq!(keys(&Filter::eq("ts", "3")), Some(vec![]));
+        assert_eq!(keys(&Filter::is_in("ts", [7i64, 1, 99])), Some(vec![1, 7]));
+        assert_eq!(keys(&Filter::range("ts", Some(2i64.into()), Some(3.5.into()))), Some(vec![2, 3, 10]));
+        assert_eq!(keys(&Filter::range("ts", None, Some((-0.0).into()))), Some(vec![0]));
+        assert_eq!(keys(&Filter::range("ts", Some(8i64.into()), None)), Some(vec![8, 9]));
+        assert_eq!(keys(&Filter::range("ts", Some(8i64.into()), Some("z".into()))), Some(vec![]));
+        assert_eq!(keys(&Filter::range("tag", None, None)), Some((0..10).collect()));
+
+        // a part over an unindexed field leaves the rest of a conjunction to narrow it down
+        assert_eq!(keys(&Filter::eq("tag", "b").and(Filter::eq("other", 1i64))), Some(vec![1, 3, 5, 7, 9]));
+        assert_eq!(keys(&Filter::eq("tag", "b").and(Filter::range("ts", None, Some(2i64.into())))), Some(vec![0, 1, 2]));
+        assert_eq!(keys(&Filter::eq("tag", "b").or(Filter::eq("ts", 2i64))), Some(vec![1, 2, 3, 5, 7, 9]));
+        assert_eq!(keys(&Filter::eq("tag", "b").or(Filter::eq("other", 1i64))), None);
+        assert_eq!(keys(&Filter::eq("tag", "b").negate()), None);
+
+        // moving and removing keys takes them off their old lists
+        index.insert(0, Vector::new(0, vec![]).with_metadata("tag", "b").metadata());
+        index.remove(1);
+        index.remove_range(5..100);
+        assert_eq!(candidates(&index, &Filter::eq("tag", "b")), Some(vec![0, 3]));
+        assert_eq!(candidates(&index, &Filter::range("ts", None, None)), Some(vec![2, 3, 4]));
+        assert_eq!(index.len(), 4);
+    }
+
+    #[test]
+    fn test_field_tables() {
+        let fields = ["tag".to_string(), "ts".to_string()];
+        let mut index = FieldIndex::new(&fields);
+        for key in 0..6u64 {
+            let value = Vector::new(key, vec![]).with_metadata("tag", ["a", "b"][key as usize % 2]).with_metadata("ts", key as i64 - 3);
+            index.insert(key, value.metadata());
+        }
+        index.insert(6, Vector::new(6, vec![]).with_metadata("tag", true).metadata());
+        let first = encode_table(&index.take_added());
+        // an overwrite leaves the old entry behind in the first table
+        index.insert(0, Vector::new(0, vec![]).with_metadata("tag", "b").metadata());
+        index.remove(1);
+        let second = encode_table(&index.take_added());
+        assert!(index.take_added().is_empty());
+
+        let candidates = |index: &FieldIndex, filter: &Filter| index.candidates(filter).map(|keys| keys.into_iter().collect::<Vec<u64>>());
+        let mut loaded = FieldIndex::new(&fields);
+        loaded.load(&first).unwrap();
+        loaded.load(&second).unwrap();
+        assert_eq!(candidates(&loaded, &Filter::eq("tag", "a")), Some(vec![0, 2, 4]));
+        assert_eq!(candidates(&loaded, &Filter::eq("tag", "b")), Some(vec![0, 1, 3, 5]));
+        assert_eq!(candidates(&loaded, &Filter::range("ts", Some((-1i64).into()), Some(1.5.into()))), Some(vec![2, 3, 4]));
+        assert_eq!(candidates(&loaded, &Filter::eq("tag", true)), Some(vec![6]));
+        assert!(loaded.take_added().is_empty());
+        // until a table of what the index files now replaces them
+        let merged = encode_table(&index.entries());
+        let mut loaded = FieldIndex::new(&fields[..1]);
+        loaded.load(&merged).unwrap();
+        assert_eq!(candidates(&loaded, &Filter::eq("tag", "a")), Some(vec![2, 4]));
+        assert_eq!(candidates(&loaded, &Filter::eq("tag", "b")), Some(vec![0, 3, 5]));
+        assert_eq!(candidates(&loaded, &Filter::eq("ts", 1i64)), None);
+
+        for damaged in [&merged[..merged.len() - 1], &merged[4..]] {
+            assert_eq!(loaded.load(damaged).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        let mut flipped = merged.clone();
+        flipped[MAGIC.len() + 5] ^= 1;
+        assert_eq!(loaded.load(&flipped).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
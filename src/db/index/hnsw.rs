@@ -0,0 +1,721 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::cell::RefCell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use crate::db::search::DistanceMetric;
+
+pub const HNSW_FILE: &str = "hnsw.idx";
+const MAGIC: [u8; 8] = *b"LSMHNSW3";
+const NO_ENTRY: u32 = u32::MAX;
+// keeps a pathological random draw from building a tower of empty layers
+const MAX_LEVEL: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswOptions {
+    // links kept per node on the upper layers, the bottom layer keeps twice as many
+    pub m: usize,
+    // candidates considered when linking a new node: higher builds a better graph, slower
+    pub ef_construction: usize,
+}
+
+impl Default for HnswOptions {
+    fn default() -> HnswOptions {
+        HnswOptions { m: 16, ef_construction: 100 }
+    }
+}
+
+// Hierarchical navigable small world graph. Each node is linked to its closest
+// neighbors on every layer up to a randomly drawn level; a search descends greedily
+// from the sparse top layers and widens to `ef` candidates on the bottom one.
+// Removed nodes are only flagged, they keep routing searches until the graph is rebuilt.
+pub(crate) struct Hnsw {
+    options: HnswOptions,
+    metric: DistanceMetric,
+    nodes: Vec<Node>,
+    by_key: BTreeMap<u64, u32>,
+    entry: Option<u32>,
+    removed: usize,
+    // changed since it was last written out
+    dirty: bool,
+    // what the tree held when the graph was last written out, or as read back
+    watermark: Watermark,
+}
+
+// The writes a graph written out holds: every one through `sequence`, with these live
+// tables. Opening the tree compares it to what it finds to tell what the graph missed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Watermark {
+    pub(crate) sequence: u64,
+    // file numbers, in ascending order
+    pub(crate) tables: Vec<u64>,
+}
+
+impl Watermark {
+    // For a graph written out while writes went on, which matches no sequence: one that
+    // no tree has, so the graph is checked against every vector
+    pub(crate) fn unknown() -> Watermark {
+        Watermark { sequence: u64::MAX, tables: Vec::new() }
+    }
+}
+
+// What `LSMTree::graph_diagnostics` finds in the graph. Nodes a search can't reach are
+// never returned, which loses recall without any error, and `LSMTree::repair_graph`
+// links them back in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphDiagnostics {
+    // live nodes on each layer, bottom first
+    pub layer_sizes: Vec<usize>,
+    // removed nodes still routing searches until the graph is rebuilt
+    pub removed: usize,
+    // links out of a live node on the bottom layer, on average
+    pub average_out_degree: f64,
+    // groups of live nodes joined by bottom layer links taken either way, 1 in a healthy
+    // graph
+    pub components: usize,
+    // live nodes the bottom layer walk from the entry point never reaches, 0 to 100
+    pub unreachable_percent: f64,
+}
+
+struct Node {
+    key: u64,
+    vector: Vec<f64>,
+    // neighbor ids per layer, bottom first
+    links: Vec<Vec<u32>>,
+    removed: bool,
+}
+
+// The nodes a layer search has visited, kept between searches on a thread so short
+// queries don't spend their time allocating and hashing a fresh set: a node is visited
+// when its mark is the current generation, and starting over bumps the generation.
+// Takes four bytes per node of the largest graph the thread has searched.
+#[derive(Default)]
+struct Visited {
+    marks: Vec<u32>,
+    generation: u32,
+}
+
+impl Visited {
+    fn start(&mut self, nodes: usize) {
+        if self.marks.len() < nodes {
+            self.marks.resize(nodes, 0);
+        }
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            self.marks.fill(0);
+            self.generation = 1;
+        }
+    }
+
+    // Whether this is the first visit
+    fn insert(&mut self, id: u32) -> bool {
+        let mark = &mut self.marks[id as usize];
+        let first = *mark != self.generation;
+        *mark = self.generation;
+        first
+    }
+}
+
+thread_local! {
+    static VISITED: RefCell<Visited> = RefCell::new(Visited::default());
+}
+
+#[derive(Clone, Copy)]
+struct Scored {
+    distance: f64,
+    id: u32,
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl Hnsw {
+    pub(crate) fn new(options: HnswOptions, metric: DistanceMetric) -> Hnsw {
+        Hnsw { options, metric, nodes: Vec::new(), by_key: BTreeMap::new(), entry: None, removed: 0, dirty: false, watermark: Watermark::default() }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn watermark(&self) -> &Watermark {
+        &self.watermark
+    }
+
+    // The indexed keys in order
+    pub(crate) fn keys(&self) -> impl Iterator<Item = u64> + '_ {
+        self.by_key.keys().copied()
+    }
+
+    pub(crate) fn vector(&self, key: u64) -> Option<&[f64]> {
+        self.by_key.get(&key).map(|&id| self.nodes[id as usize].vector.as_slice())
+    }
+
+    // Replaces any vector already indexed under `key`
+    pub(crate) fn insert(&mut self, key: u64, vector: Vec<f64>) {
+        self.remove(key);
+        self.dirty = true;
+        let level = self.random_level();
+        let id = self.nodes.len() as u32;
+        self.nodes.push(Node { key, vector, links: vec![Vec::new(); level + 1], removed: false });
+        self.by_key.insert(key, id);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(id);
+            return;
+        };
+        let top = self.nodes[entry as usize].links.len() - 1;
+        let vector = self.nodes[id as usize].vector.clone();
+        let mut entries = vec![self.descend(&vector, entry, top, level + 1)];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&vector, &entries, self.options.ef_construction.max(1), layer);
+            let neighbors: Vec<u32> = found.iter().take(self.options.m.max(1)).map(|s| s.id).collect();
+            for &neighbor in neighbors.iter() {
+                self.nodes[neighbor as usize].links[layer].push(id);
+                self.prune(neighbor, layer);
+            }
+            self.nodes[id as usize].links[layer] = neighbors;
+            entries = found.iter().map(|s| s.id).collect();
+        }
+        if level > top {
+            self.entry = Some(id);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: u64) {
+        let Some(id) = self.by_key.remove(&key) else { return };
+        self.nodes[id as usize].removed = true;
+        self.removed += 1;
+        self.dirty = true;
+        // once most nodes are dead weight, searches waste their effort routing through them
+        if self.removed > self.by_key.len() && self.nodes.len() > 2 * self.options.m {
+            self.rebuild();
+        }
+    }
+
+    pub(crate) fn remove_range(&mut self, range: Range<u64>) {
+        let keys: Vec<u64> = self.by_key.range(range).map(|(&k, _)| k).collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    // Up to `k` indexed keys closest to `query`, closest first, with their distances
+    pub(crate) fn search(&self, query: &[f64], k: usize, ef: usize) -> Vec<(u64, f64)> {
+        let Some(entry) = self.entry else { return Vec::new() };
+        let top = self.nodes[entry as usize].links.len() - 1;
+        let entries = [self.descend(query, entry, top, 1)];
+        self.search_layer(query, &entries, ef.max(k), 0)
+            .into_iter()
+            .filter(|s| !self.nodes[s.id as usize].removed)
+            .take(k)
+            .map(|s| (self.nodes[s.id as usize].key, s.distance))
+            .collect()
+    }
+
+    // Like `search`, returning only the keys `accept` takes. The nodes it turns down
+    // still route the walk, so a selective filter narrows the results instead of
+    // leaving a handful of the `ef` closest nodes.
+    pub(crate) fn search_filtered(&self, query: &[f64], k: usize, ef: usize, accept: impl Fn(u64) -> bool) -> Vec<(u64, f64)> {
+        let Some(entry) = self.entry else { return Vec::new() };
+        let top = self.nodes[entry as usize].links.len() - 1;
+        let entries = [self.descend(query, entry, top, 1)];
+        let accept = |id: u32| {
+            let node = &self.nodes[id as usize];
+            !node.removed && accept(node.key)
+        };
+        self.search_layer_where(query, &entries, ef.max(k), 0, &accept)
+            .into_iter()
+            .take(k)
+            .map(|s| (self.nodes[s.id as usize].key, s.distance))
+            .collect()
+    }
+
+    pub(crate) fn diagnostics(&self) -> GraphDiagnostics {
+        let live: Vec<u32> = self.by_key.values().copied().collect();
+        let mut layer_sizes = Vec::new();
+        for &id in live.iter() {
+            let levels = self.nodes[id as usize].links.len();
+            if layer_sizes.len() < levels {
+                layer_sizes.resize(levels, 0);
+            }
+            layer_sizes[..levels].iter_mut().for_each(|size| *size += 1);
+        }
+        let links: usize = live.iter().map(|&id| self.nodes[id as usize].links[0].len()).sum();
+
+        // union-find over every node, removed ones included as they still join others
+        let mut parent: Vec<u32> = (0..self.nodes.len() as u32).collect();
+        fn root(parent: &mut [u32], mut id: u32) -> u32 {
+            while parent[id as usize] != id {
+                parent[id as usize] = parent[parent[id as usize] as usize];
+                id = parent[id as usize];
+            }
+            id
+        }
+        for (id, node) in self.nodes.iter().enumerate() {
+            for &neighbor in node.links[0].iter() {
+                let (a, b) = (root(&mut parent, id as u32), root(&mut parent, neighbor));
+                parent[a as usize] = b;
+            }
+        }
+        let components: HashSet<u32> = live.iter().map(|&id| root(&mut parent, id)).collect();
+
+        let reachable = self.reachable();
+        let unreachable = live.iter().filter(|&&id| !reachable[id as usize]).count();
+        GraphDiagnostics {
+            layer_sizes,
+            removed: self.removed,
+            average_out_degree: if live.is_empty() { 0.0 } else { links as f64 / live.len() as f64 },
+            components: components.len(),
+            unreachable_percent: if live.is_empty() { 0.0 } else { 100.0 * unreachable as f64 / live.len() as f64 },
+        }
+    }
+
+    // Links every live node the walk from the entry point misses to its closest reachable
+    // node, both ways, returning how many it linked. The extra links go past the limit a
+    // layer keeps, so pruning by later inserts doesn't cut the node off again at once.
+    pub(crate) fn repair(&mut self) -> usize {
+        let mut reachable = self.reachable();
+        let unreachable: Vec<u32> = self.by_key.values().copied().filter(|&id| !reachable[id as usize]).collect();
+        let mut repaired = 0;
+        for id in unreachable {
+            // an earlier repair may have brought it in with its neighbors
+            if reachable[id as usize] {
+                continue;
+            }
+            let vector = &self.nodes[id as usize].vector;
+            let closest = (0..self.nodes.len() as u32)
+                .filter(|&other| reachable[other as usize] && !self.nodes[other as usize].removed)
+                .map(|other| Scored { distance: self.distance(vector, other), id: other })
+                .min();
+            let Some(closest) = closest else { continue };
+            self.nodes[closest.id as usize].links[0].push(id);
+            if !self.nodes[id as usize].links[0].contains(&closest.id) {
+                self.nodes[id as usize].links[0].push(closest.id);
+            }
+            self.mark_reachable(&mut reachable, id);
+            repaired += 1;
+        }
+        if repaired != 0 {
+            self.dirty = true;
+        }
+        repaired
+    }
+
+    // Which nodes the bottom layer walk from the entry point can get to, by id
+    fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.nodes.len()];
+        if let Some(entry) = self.entry {
+            self.mark_reachable(&mut reachable, entry);
+        }
+        reachable
+    }
+
+    fn mark_reachable(&self, reachable: &mut [bool], from: u32) {
+        let mut stack = vec![from];
+        reachable[from as usize] = true;
+        while let Some(id) = stack.pop() {
+            for &neighbor in self.nodes[id as usize].links[0].iter() {
+                if !reachable[neighbor as usize] {
+                    reachable[neighbor as usize] = true;
+                    stack.push(neighbor);
+                }
+            }
+        }
+    }
+
+    // The node closest to `query` found by walking greedily from `entry` down the layers
+    // from `top` to `bottom`, `entry` itself when there are none. Each step moves to the
+    // closest neighbor while that is closer, which is what a layer search with an `ef` of
+    // one does, without its heaps and visited set.
+    fn descend(&self, query: &[f64], entry: u32, top: usize, bottom: usize) -> u32 {
+        let mut closest = Scored { distance: self.distance(query, entry), id: entry };
+        for layer in (bottom..=top).rev() {
+            loop {
+                let mut step = closest;
+                for &neighbor in self.nodes[closest.id as usize].links[layer].iter() {
+                    let scored = Scored { distance: self.distance(query, neighbor), id: neighbor };
+                    step = step.min(scored);
+                }
+                if step.id == closest.id {
+                    break;
+                }
+                closest = step;
+            }
+        }
+        closest.id
+    }
+
+    // Best-first search of one layer, returning up to `ef` nodes closest first
+    fn search_layer(&self, query: &[f64], entries: &[u32], ef: usize, layer: usize) -> Vec<Scored> {
+        self.search_layer_where(query, entries, ef, layer, &|_| true)
+    }
+
+    // Only nodes `accept` takes are returned, the others are walked through
+    fn search_layer_where(&self, query: &[f64], entries: &[u32], ef: usize, layer: usize, accept: &dyn Fn(u32) -> bool) -> Vec<Scored> {
+        // taken rather than borrowed, so a search from inside `accept` gets a set of its own
+        let mut visited = VISITED.take();
+        visited.start(self.nodes.len());
+        for &id in entries {
+            visited.insert(id);
+        }
+        let mut candidates = BinaryHeap::new();
+        let mut results = BinaryHeap::new();
+        for &id in entries {
+            let scored = Scored { distance: self.distance(query, id), id };
+            candidates.push(Reverse(scored));
+            if accept(id) {
+                results.push(scored);
+            }
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(closest)) = candidates.pop() {
+            if results.len() >= ef && results.peek().is_some_and(|farthest: &Scored| closest.distance > farthest.distance) {
+                break;
+            }
+            for &neighbor in self.nodes[closest.id as usize].links[layer].iter() {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let scored = Scored { distance: self.distance(query, neighbor), id: neighbor };
+                if results.len() < ef || results.peek().is_some_and(|farthest| scored < *farthest) {
+                    candidates.push(Reverse(scored));
+                    if accept(neighbor) {
+                        results.push(scored);
+                    }
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        VISITED.set(visited);
+        results.into_sorted_vec()
+    }
+
+    // Keeps only the closest neighbors of a node whose layer has grown past its limit
+    fn prune(&mut self, id: u32, layer: usize) {
+        let limit = if layer == 0 { 2 * self.options.m.max(1) } else { self.options.m.max(1) };
+        if self.nodes[id as usize].links[layer].len() <= limit {
+            return;
+        }
+        let vector = &self.nodes[id as usize].vector;
+        let mut scored: Vec<Scored> = self.nodes[id as usize].links[layer].iter()
+            .map(|&n| Scored { distance: self.distance(vector, n), id: n })
+            .collect();
+        scored.sort();
+        self.nodes[id as usize].links[layer] = scored.into_iter().take(limit).map(|s| s.id).collect();
+    }
+
+    fn rebuild(&mut self) {
+        let live: Vec<(u64, Vec<f64>)> = std::mem::take(&mut self.nodes).into_iter()
+            .filter(|n| !n.removed)
+            .map(|n| (n.key, n.vector))
+            .collect();
+        *self = Hnsw::new(self.options, self.metric);
+        for (key, vector) in live {
+            self.insert(key, vector);
+        }
+    }
+
+    fn distance(&self, query: &[f64], id: u32) -> f64 {
+        self.metric.distance(query, &self.nodes[id as usize].vector)
+    }
+
+    fn random_level(&self) -> usize {
+        let scale = 1.0 / (self.options.m.max(2) as f64).ln();
+        let draw = 1.0 - rand::random::<f64>();
+        ((-draw.ln() * scale).floor() as usize).min(MAX_LEVEL)
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W, watermark: &Watermark) -> io::Result<()> {
+        out.write_all(&MAGIC)?;
+        out.write_u32::<LittleEndian>(self.options.m as u32)?;
+        out.write_u32::<LittleEndian>(self.options.ef_construction as u32)?;
+        out.write_u8(self.metric.to_u8())?;
+        out.write_u64::<LittleEndian>(watermark.sequence)?;
+        out.write_u32::<LittleEndian>(watermark.tables.len() as u32)?;
+        for &table in watermark.tables.iter() {
+            out.write_u64::<LittleEndian>(table)?;
+        }
+        out.write_u32::<LittleEndian>(self.entry.unwrap_or(NO_ENTRY))?;
+        out.write_u32::<LittleEndian>(self.nodes.len() as u32)?;
+        for node in self.nodes.iter() {
+            out.write_u64::<LittleEndian>(node.key)?;
+            out.write_u8(node.removed as u8)?;
+            out.write_u32::<LittleEndian>(node.vector.len() as u32)?;
+            for &x in node.vector.iter() {
+                out.write_f64::<LittleEndian>(x)?;
+            }
+            out.write_u32::<LittleEndian>(node.links.len() as u32)?;
+            for links in node.links.iter() {
+                out.write_u32::<LittleEndian>(links.len() as u32)?;
+                for &n in links.iter() {
+                    out.write_u32::<LittleEndian>(n)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Records that the graph was written out as it is with `watermark`
+    pub(crate) fn mark_saved(&mut self, watermark: Watermark) {
+        self.dirty = false;
+        self.watermark = watermark;
+    }
+
+    // None if the graph was built with other options or metric and has to be rebuilt
+    pub(crate) fn read_from<R: Read>(input: &mut R, options: HnswOptions, metric: DistanceMetric) -> io::Result<Option<Hnsw>> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad magic number, not an hnsw index"));
+        }
+        let m = input.read_u32::<LittleEndian>()? as usize;
+        let ef_construction = input.read_u32::<LittleEndian>()? as usize;
+        let built_with = DistanceMetric::from_u8(input.read_u8()?)?;
+        if (HnswOptions { m, ef_construction }) != options || built_with != metric {
+            return Ok(None);
+        }
+        let sequence = input.read_u64::<LittleEndian>()?;
+        let tables = input.read_u32::<LittleEndian>()?;
+        let tables = (0..tables).map(|_| input.read_u64::<LittleEndian>()).collect::<io::Result<Vec<_>>>()?;
+        let entry = input.read_u32::<LittleEndian>()?;
+        let count = input.read_u32::<LittleEndian>()?;
+
+        let mut hnsw = Hnsw::new(options, metric);
+        hnsw.watermark = Watermark { sequence, tables };
+        for id in 0..count {
+            let key = input.read_u64::<LittleEndian>()?;
+            let removed = input.read_u8()? != 0;
+            let dim = input.read_u32::<LittleEndian>()?;
+            let vector = (0..dim).map(|_| input.read_f64::<LittleEndian>()).collect::<io::Result<Vec<_>>>()?;
+            let layers = input.read_u32::<LittleEndian>()?;
+            let mut links = Vec::new();
+            for _ in 0..layers {
+                let n = input.read_u32::<LittleEndian>()?;
+                let layer = (0..n).map(|_| input.read_u32::<LittleEndian>()).collect::<io::Result<Vec<_>>>()?;
+                if layer.iter().any(|&l| l >= count) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("hnsw node {} links past the last node", id)));
+                }
+                links.push(layer);
+            }
+            if removed {
+                hnsw.removed += 1;
+            } else {
+                hnsw.by_key.insert(key, id);
+            }
+            hnsw.nodes.push(Node { key, vector, links, removed });
+        }
+        if entry != NO_ENTRY {
+            if entry >= count {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "hnsw entry point past the last node"));
+            }
+            hnsw.entry = Some(entry);
+        }
+        Ok(Some(hnsw))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::search::{self, TopK};
+    use rand::Rng;
+
+    fn random_vectors(n: usize, dim: usize) -> Vec<Vec<f64>> {
+        let mut rng = rand::rng();
+        (0..n).map(|_| (0..dim).map(|_| rng.random_range(-1.0..1.0)).collect()).collect()
+    }
+
+    fn exact(vectors: &[Vec<f64>], query: &[f64], k: usize) -> Vec<u64> {
+        let mut top = TopK::new(k);
+        for (key, v) in vectors.iter().enumerate() {
+            top.push(key as u64, search::euclidean(query, v));
+        }
+        top.into_sorted().into_iter().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn test_recall() {
+        let vectors = random_vectors(1000, 8);
+        let mut hnsw = Hnsw::new(HnswOptions::default(), DistanceMetric::L2);
+        for (key, v) in vectors.iter().enumerate() {
+            hnsw.insert(key as u64, v.clone());
+        }
+
+        let mut hits = 0;
+        for query in random_vectors(20, 8) {
+            let expected = exact(&vectors, &query, 10);
+            let found = hnsw.search(&query, 10, 64);
+            assert_eq!(found.len(), 10);
+            hits += found.iter().filter(|(k, _)| expected.contains(k)).count();
+        }
+        assert!(hits >= 180, "recall {}/200", hits);
+    }
+
+    #[test]
+    fn test_filtered_recall() {
+        let vectors = random_vectors(1000, 8);
+        let mut hnsw = Hnsw::new(HnswOptions::default(), DistanceMetric::L2);
+        for (key, v) in vectors.iter().enumerate() {
+            hnsw.insert(key as u64, v.clone());
+        }
+
+        // one key in twenty passes, far fewer than ef would find unfiltered
+        let selected: Vec<Vec<f64>> = vectors.iter().step_by(20).cloned().collect();
+        let mut hits = 0;
+        for query in random_vectors(20, 8) {
+            let expected: Vec<u64> = exact(&selected, &query, 10).into_iter().map(|k| k * 20).collect();
+            let found = hnsw.search_filtered(&query, 10, 64, |key| key % 20 == 0);
+            assert_eq!(found.len(), 10);
+            assert!(found.iter().all(|(k, _)| k % 20 == 0));
+            hits += found.iter().filter(|(k, _)| expected.contains(k)).count();
+        }
+        assert!(hits >= 180, "recall {}/200", hits);
+    }
+
+    #[test]
+    fn test_remove_and_replace() {
+        let mut hnsw = Hnsw::new(HnswOptions { m: 4, ef_construction: 16 }, DistanceMetric::L2);
+        for i in 0..50u64 {
+            hnsw.insert(i, vec![i as f64]);
+        }
+        hnsw.remove(10);
+        hnsw.remove_range(20..30);
+        assert_eq!(hnsw.len(), 39);
+        // 9 and 11 tie, the older node wins
+        assert_eq!(hnsw.search(&[10.0], 1, 16)[0].0, 9);
+        assert!(hnsw.search(&[25.0], 5, 32).iter().all(|(k, _)| !(20..30).contains(k)));
+
+        // a new vector under an existing key moves it
+        hnsw.insert(5, vec![100.0]);
+        assert_eq!(hnsw.len(), 39);
+        assert_eq!(hnsw.search(&[100.0], 1, 16), vec![(5, 0.0)]);
+
+        // removing most nodes rebuilds the graph from the live ones
+        for i in 0..45u64 {
+            hnsw.remove(i);
+        }
+        assert_eq!(hnsw.len(), 5);
+        assert!(hnsw.nodes.len() <= 10);
+        assert_eq!(hnsw.search(&[0.0], 10, 16).len(), 5);
+    }
+
+    #[test]
+    fn test_diagnostics_and_repair() {
+        let vectors = random_vectors(300, 4);
+        let mut hnsw = Hnsw::new(HnswOptions { m: 8, ef_construction: 32 }, DistanceMetric::L2);
+        for (key, v) in vectors.iter().enumerate() {
+            hnsw.insert(key as u64, v.clone());
+        }
+        hnsw.remove(0);
+        hnsw.repair();
+        let healthy = hnsw.diagnostics();
+        assert_eq!(healthy.layer_sizes[0], 299);
+        assert!(healthy.layer_sizes.windows(2).all(|sizes| sizes[0] >= sizes[1]));
+        assert_eq!(healthy.removed, 1);
+        assert!(healthy.average_out_degree > 1.0 && healthy.average_out_degree <= 16.0);
+        assert_eq!((healthy.components, healthy.unreachable_percent), (1, 0.0));
+
+        // cut three nodes off from everything linking to them
+        let entry = hnsw.entry.unwrap();
+        let cut: Vec<u32> = (1..300).filter(|&id| id != entry).take(3).collect();
+        for node in hnsw.nodes.iter_mut() {
+            for links in node.links.iter_mut() {
+                links.retain(|id| !cut.contains(id));
+            }
+        }
+        let broken = hnsw.diagnostics();
+        assert_eq!(broken.unreachable_percent, 100.0 * 3.0 / 299.0);
+        let found = |hnsw: &Hnsw, id: u32| hnsw.search(&vectors[id as usize], 1, 64)[0].0 == id as u64;
+        assert!(cut.iter().all(|&id| !found(&hnsw, id)));
+
+        assert_eq!(hnsw.repair(), 3);
+        assert!(hnsw.is_dirty());
+        assert_eq!(hnsw.diagnostics().unreachable_percent, 0.0);
+        assert!(cut.iter().all(|&id| found(&hnsw, id)));
+        assert_eq!(hnsw.repair(), 0);
+        assert_eq!(Hnsw::new(HnswOptions::default(), DistanceMetric::L2).diagnostics().components, 0);
+    }
+
+    #[test]
+    fn test_visited_reuse() {
+        let mut visited = Visited { marks: Vec::new(), generation: u32::MAX - 1 };
+        visited.start(3);
+        assert!(visited.insert(2) && !visited.insert(2));
+        assert_eq!(visited.generation, u32::MAX);
+        // the generation wraps around to a cleared set
+        visited.start(5);
+        assert_eq!((visited.generation, visited.marks.len()), (1, 5));
+        assert!(visited.insert(2) && visited.insert(4) && !visited.insert(4));
+
+        // graphs of different sizes searched in turn on one thread share the set
+        let small = random_vectors(10, 2);
+        let mut a = Hnsw::new(HnswOptions::default(), DistanceMetric::L2);
+        let mut b = Hnsw::new(HnswOptions::default(), DistanceMetric::L2);
+        for (key, v) in random_vectors(200, 2).into_iter().enumerate() {
+            a.insert(key as u64, v);
+        }
+        for (key, v) in small.iter().enumerate() {
+            b.insert(key as u64, v.clone());
+        }
+        for query in small.iter() {
+            assert_eq!(a.search(query, 5, 32).len(), 5);
+            assert_eq!(b.search(query, 10, 32).len(), 10);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let options = HnswOptions { m: 8, ef_construction: 32 };
+        let mut hnsw = Hnsw::new(options, DistanceMetric::L2);
+        for (key, v) in random_vectors(200, 4).into_iter().enumerate() {
+            hnsw.insert(key as u64, v);
+        }
+        hnsw.remove(3);
+        let mut buf = Vec::new();
+        let watermark = Watermark { sequence: 42, tables: vec![3, 7] };
+        hnsw.write_to(&mut buf, &watermark).unwrap();
+        assert!(hnsw.is_dirty());
+        hnsw.mark_saved(watermark.clone());
+        assert!(!hnsw.is_dirty());
+
+        let read = Hnsw::read_from(&mut buf.as_slice(), options, DistanceMetric::L2).unwrap().unwrap();
+        assert_eq!(read.len(), 199);
+        assert_eq!((read.watermark(), read.is_dirty()), (&watermark, false));
+        assert_eq!(read.vector(4), hnsw.vector(4));
+        assert_eq!(read.keys().take(4).collect::<Vec<_>>(), vec![0, 1, 2, 4]);
+        let query = [0.1, 0.2, 0.3, 0.4];
+        assert_eq!(read.search(&query, 5, 32), hnsw.search(&query, 5, 32));
+        assert!(Hnsw::read_from(&mut buf.as_slice(), HnswOptions::default(), DistanceMetric::L2).unwrap().is_none());
+        assert!(Hnsw::read_from(&mut buf.as_slice(), options, DistanceMetric::Cosine).unwrap().is_none());
+        assert!(Hnsw::read_from(&mut &buf[..20], options, DistanceMetric::L2).is_err());
+    }
+}
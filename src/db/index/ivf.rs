@@ -0,0 +1,204 @@
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::ops::Range;
+use crate::db::search::{self, DistanceMetric};
+use crate::db::simd;
+
+const KMEANS_ITERATIONS: usize = 25;
+
+// Clusters the sample into `nlist` partitions with Lloyd's k-means, seeded k-means++
+// style: each initial centroid is a sample point drawn with probability proportional
+// to its squared distance from the centroids picked so far. A centroid left without
+// members is moved to a random sample point so every partition ends up used.
+pub fn train_kmeans(sample: &[Vec<f64>], nlist: usize) -> io::Result<Vec<Vec<f64>>> {
+    let Some(first) = sample.first() else {
+        return Err(invalid("cannot train centroids on an empty sample".to_string()));
+    };
+    let dim = first.len();
+    if sample.iter().any(|v| v.len() != dim) {
+        return Err(invalid("all sample vectors must have the same dimension".to_string()));
+    }
+    if nlist == 0 || nlist > sample.len() {
+        return Err(invalid(format!("cannot split a sample of {} vectors into {} partitions", sample.len(), nlist)));
+    }
+
+    let mut rng = rand::rng();
+    let mut centroids = seed(sample, nlist, &mut rng);
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![vec![0.0; dim]; nlist];
+        let mut counts = vec![0usize; nlist];
+        for v in sample {
+            let c = nearest(&centroids, v);
+            counts[c] += 1;
+            sums[c].iter_mut().zip(v).for_each(|(s, x)| *s += x);
+        }
+
+        let mut moved = false;
+        for (c, sum) in sums.into_iter().enumerate() {
+            let centroid = if counts[c] == 0 {
+                sample[rng.random_range(0..sample.len())].clone()
+            } else {
+                sum.into_iter().map(|s| s / counts[c] as f64).collect()
+            };
+            moved |= centroid != centroids[c];
+            centroids[c] = centroid;
+        }
+        if !moved {
+            break;
+        }
+    }
+    Ok(centroids)
+}
+
+fn seed(sample: &[Vec<f64>], nlist: usize, rng: &mut impl Rng) -> Vec<Vec<f64>> {
+    let mut centroids = vec![sample[rng.random_range(0..sample.len())].clone()];
+    let mut weights: Vec<f64> = sample.iter().map(|v| simd::squared_l2(v, &centroids[0])).collect();
+    while centroids.len() < nlist {
+        let total: f64 = weights.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.random_range(0.0..total);
+            weights.iter().position(|&w| {
+                target -= w;
+                target < 0.0
+            }).unwrap_or(sample.len() - 1)
+        } else {
+            // every point coincides with a centroid already
+            rng.random_range(0..sample.len())
+        };
+        let centroid = sample[next].clone();
+        for (w, v) in weights.iter_mut().zip(sample) {
+            *w = w.min(simd::squared_l2(v, &centroid));
+        }
+        centroids.push(centroid);
+    }
+    centroids
+}
+
+// Inverted file: every key is filed under the centroid closest to its vector, and a
+// search only visits the partitions closest to the query. Only keys are kept, the
+// vectors stay in the tree, so the index costs a few words per entry.
+pub(crate) struct Ivf {
+    metric: DistanceMetric,
+    centroids: Vec<Vec<f64>>,
+    lists: Vec<BTreeSet<u64>>,
+    // partition of every indexed key, to find it again on removal
+    assigned: BTreeMap<u64, usize>,
+}
+
+impl Ivf {
+    pub(crate) fn new(centroids: Vec<Vec<f64>>, metric: DistanceMetric) -> Ivf {
+        let lists = vec![BTreeSet::new(); centroids.len()];
+        Ivf { metric, centroids, lists, assigned: BTreeMap::new() }
+    }
+
+    pub(crate) fn centroids(&self) -> &[Vec<f64>] {
+        &self.centroids
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.assigned.len()
+    }
+
+    // Files the key under its closest centroid, moving it if it was indexed already.
+    // A vector of another dimension than the centroids can't be placed and is left out.
+    pub(crate) fn insert(&mut self, key: u64, vector: &[f64]) {
+        self.remove(key);
+        if self.centroids.first().is_some_and(|c| c.len() == vector.len()) {
+            let c = self.closest(vector, 1)[0];
+            self.lists[c].insert(key);
+            self.assigned.insert(key, c);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: u64) {
+        if let Some(c) = self.assigned.remove(&key) {
+            self.lists[c].remove(&key);
+        }
+    }
+
+    pub(crate) fn remove_range(&mut self, range: Range<u64>) {
+        let keys: Vec<u64> = self.assigned.range(range).map(|(&k, _)| k).collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    // Keys filed under the `nprobe` centroids closest to the query
+    pub(crate) fn candidates(&self, query: &[f64], nprobe: usize) -> Vec<u64> {
+        self.closest(query, nprobe).into_iter().flat_map(|c| self.lists[c].iter().copied()).collect()
+    }
+
+    // Centroids by the tree's metric, closest first
+    fn closest(&self, vector: &[f64], n: usize) -> Vec<usize> {
+        let mut order: Vec<(f64, usize)> = self.centroids.iter().enumerate().map(|(c, centroid)| (self.metric.distance(vector, centroid), c)).collect();
+        order.sort_by(|a, b| a.0.total_cmp(&b.0));
+        order.into_iter().take(n).map(|(_, c)| c).collect()
+    }
+}
+
+fn nearest(centroids: &[Vec<f64>], vector: &[f64]) -> usize {
+    let distances = centroids.iter().map(|c| search::euclidean(vector, c));
+    distances.enumerate().min_by(|a, b| a.1.total_cmp(&b.1)).map_or(0, |(c, _)| c)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // three tight clusters around (0, 0), (10, 0) and (0, 10)
+    fn clustered_sample() -> Vec<Vec<f64>> {
+        let mut rng = rand::rng();
+        let centers = [[0.0, 0.0], [10.0, 0.0], [0.0, 10.0]];
+        (0..300).map(|i| {
+            let center = centers[i % 3];
+            vec![center[0] + rng.random_range(-0.5..0.5), center[1] + rng.random_range(-0.5..0.5)]
+        }).collect()
+    }
+
+    #[test]
+    fn test_train_kmeans() {
+        let sample = clustered_sample();
+        let centroids = train_kmeans(&sample, 3).unwrap();
+        let mut found: Vec<usize> = [[0.0, 0.0], [10.0, 0.0], [0.0, 10.0]].iter().map(|center| {
+            let c = nearest(&centroids, center);
+            assert!(search::euclidean(&centroids[c], center) < 0.5, "{:?} is far from {:?}", centroids[c], center);
+            c
+        }).collect();
+        found.sort();
+        assert_eq!(found, vec![0, 1, 2]);
+
+        assert!(train_kmeans(&[], 1).is_err());
+        assert!(train_kmeans(&sample, 0).is_err());
+        assert!(train_kmeans(&sample[..2], 3).is_err());
+        assert!(train_kmeans(&[vec![1.0], vec![1.0, 2.0]], 1).is_err());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mut ivf = Ivf::new(vec![vec![0.0, 0.0], vec![10.0, 0.0], vec![0.0, 10.0]], DistanceMetric::L2);
+        ivf.insert(1, &[0.5, 0.5]);
+        ivf.insert(2, &[9.0, 1.0]);
+        ivf.insert(3, &[1.0, 9.0]);
+        ivf.insert(4, &[1.0]);
+        assert_eq!(ivf.len(), 3);
+
+        assert_eq!(ivf.candidates(&[8.0, 0.0], 1), vec![2]);
+        assert_eq!(ivf.candidates(&[8.0, 0.0], 2), vec![2, 1]);
+        assert_eq!(ivf.candidates(&[8.0, 0.0], 10).len(), 3);
+
+        // moving a key files it under its new partition only
+        ivf.insert(2, &[0.0, 11.0]);
+        assert!(ivf.candidates(&[8.0, 0.0], 1).is_empty());
+        assert_eq!(ivf.candidates(&[0.0, 12.0], 1), vec![2, 3]);
+
+        ivf.remove(1);
+        ivf.remove_range(2..3);
+        assert_eq!(ivf.candidates(&[0.0, 0.0], 3), vec![3]);
+    }
+}
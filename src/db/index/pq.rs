@@ -0,0 +1,189 @@
+use std::io;
+use crate::db::checksum;
+use crate::db::index::ivf;
+use crate::db::search::DistanceMetric;
+use crate::db::simd;
+
+// Codes are one byte per subspace
+pub const MAX_CENTROIDS: usize = 256;
+
+// Product quantizer: vectors are cut into `subspaces` equal slices and every slice is
+// replaced by the index of its closest centroid in that subspace's codebook, so a
+// vector of `dim` f64s is coded in `subspaces` bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductQuantizer {
+    // codebooks[subspace][centroid] is a slice of `dim / subspaces` values
+    codebooks: Vec<Vec<Vec<f64>>>,
+}
+
+impl ProductQuantizer {
+    // Trains one k-means codebook of `centroids` entries per subspace
+    pub fn train(sample: &[Vec<f64>], subspaces: usize, centroids: usize) -> io::Result<ProductQuantizer> {
+        let Some(first) = sample.first() else {
+            return Err(invalid("cannot train codebooks on an empty sample".to_string()));
+        };
+        let dim = first.len();
+        if subspaces == 0 || dim % subspaces != 0 {
+            return Err(invalid(format!("cannot split {} dimensions into {} equal subspaces", dim, subspaces)));
+        }
+        if centroids > MAX_CENTROIDS {
+            return Err(invalid(format!("at most {} centroids fit in a one byte code", MAX_CENTROIDS)));
+        }
+
+        let width = dim / subspaces;
+        let mut codebooks = Vec::with_capacity(subspaces);
+        for s in 0..subspaces {
+            let slices: Vec<Vec<f64>> = sample.iter().map(|v| v.get(s * width..(s + 1) * width).unwrap_or_default().to_vec()).collect();
+            codebooks.push(ivf::train_kmeans(&slices, centroids)?);
+        }
+        Ok(ProductQuantizer { codebooks })
+    }
+
+    pub(crate) fn from_codebooks(codebooks: Vec<Vec<Vec<f64>>>) -> ProductQuantizer {
+        ProductQuantizer { codebooks }
+    }
+
+    pub(crate) fn codebooks(&self) -> &[Vec<Vec<f64>>] {
+        &self.codebooks
+    }
+
+    pub fn dim(&self) -> usize {
+        self.codebooks.iter().map(|book| book.first().map_or(0, |c| c.len())).sum()
+    }
+
+    // None if the vector doesn't have the quantizer's dimension
+    pub fn encode(&self, vector: &[f64]) -> Option<Vec<u8>> {
+        if vector.len() != self.dim() {
+            return None;
+        }
+        let mut offset = 0;
+        let code = self.codebooks.iter().map(|book| {
+            let slice = &vector[offset..offset + book[0].len()];
+            offset += slice.len();
+            let distances = book.iter().map(|c| simd::squared_l2(slice, c));
+            distances.enumerate().min_by(|a, b| a.1.total_cmp(&b.1)).map_or(0, |(i, _)| i as u8)
+        }).collect();
+        Some(code)
+    }
+
+    // Identifies the codebooks, so codes a table stored with others are told apart
+    pub(crate) fn fingerprint(&self) -> u32 {
+        let mut crc = 0;
+        for value in self.codebooks.iter().flatten().flatten() {
+            crc = checksum::extend(crc, &value.to_le_bytes());
+        }
+        crc
+    }
+
+    pub fn decode(&self, code: &[u8]) -> Vec<f64> {
+        self.codebooks.iter().zip(code).flat_map(|(book, &c)| book[c as usize].iter().copied()).collect()
+    }
+
+    // Dot products of each slice of the query with every centroid of its subspace,
+    // computed once per query so a code is scored with one lookup per subspace
+    fn dot_table(&self, query: &[f64]) -> Vec<Vec<f64>> {
+        let mut offset = 0;
+        self.codebooks.iter().map(|book| {
+            let slice = &query[offset..offset + book[0].len()];
+            offset += slice.len();
+            book.iter().map(|c| simd::dot(slice, c)).collect()
+        }).collect()
+    }
+
+    // Squared norm of every centroid, for the norm of a decoded vector
+    fn norm_table(&self) -> Vec<Vec<f64>> {
+        self.codebooks.iter().map(|book| book.iter().map(|c| simd::dot(c, c)).collect()).collect()
+    }
+}
+
+// Scores codes against one query with asymmetric distances, where the query stays exact
+// and only the stored side is quantized. Every metric follows from the dot product and
+// the two norms, so a code costs a lookup per subspace in tables built once per query.
+pub(crate) struct CodeScorer {
+    metric: DistanceMetric,
+    dots: Vec<Vec<f64>>,
+    norms: Vec<Vec<f64>>,
+    query_norm: f64,
+}
+
+impl CodeScorer {
+    pub(crate) fn new(quantizer: &ProductQuantizer, metric: DistanceMetric, query: &[f64]) -> io::Result<CodeScorer> {
+        if query.len() != quantizer.dim() {
+            return Err(invalid(format!("query has {} dimensions, the quantizer {}", query.len(), quantizer.dim())));
+        }
+        Ok(CodeScorer { metric, dots: quantizer.dot_table(query), norms: quantizer.norm_table(), query_norm: simd::dot(query, query) })
+    }
+
+    pub(crate) fn distance(&self, code: &[u8]) -> f64 {
+        let dot: f64 = self.dots.iter().zip(code).map(|(row, &c)| row[c as usize]).sum();
+        let norm: f64 = self.norms.iter().zip(code).map(|(row, &c)| row[c as usize]).sum();
+        let query_norm = self.query_norm;
+        match self.metric {
+            DistanceMetric::L2 => (query_norm - 2.0 * dot + norm).max(0.0).sqrt(),
+            DistanceMetric::Cosine if query_norm == 0.0 || norm == 0.0 => 1.0,
+            DistanceMetric::Cosine => 1.0 - dot / (query_norm * norm).sqrt(),
+            DistanceMetric::InnerProduct => -dot,
+            // between bit vectors the squared distance counts the bits that differ,
+            // near enough to gather candidates for either binary metric
+            DistanceMetric::Hamming | DistanceMetric::Jaccard => (query_norm - 2.0 * dot + norm).max(0.0),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::search;
+    use rand::Rng;
+
+    fn random_vectors(n: usize, dim: usize) -> Vec<Vec<f64>> {
+        let mut rng = rand::rng();
+        (0..n).map(|_| (0..dim).map(|_| rng.random_range(-1.0..1.0)).collect()).collect()
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let sample = random_vectors(500, 8);
+        let quantizer = ProductQuantizer::train(&sample, 4, 16).unwrap();
+        assert_eq!(quantizer.dim(), 8);
+        let code = quantizer.encode(&sample[0]).unwrap();
+        assert_eq!(code.len(), 4);
+        // each 2-d slice lands within a fraction of the range of a centroid
+        let decoded = quantizer.decode(&code);
+        assert!(search::euclidean(&decoded, &sample[0]) < 1.0);
+        assert!(quantizer.encode(&[0.0; 3]).is_none());
+
+        assert!(ProductQuantizer::train(&sample, 3, 16).is_err());
+        assert!(ProductQuantizer::train(&sample, 4, 300).is_err());
+        assert!(ProductQuantizer::train(&[], 4, 16).is_err());
+    }
+
+    #[test]
+    fn test_asymmetric_distance() {
+        // with a centroid per sample point every code is exact
+        let vectors = random_vectors(16, 4);
+        let quantizer = ProductQuantizer::train(&vectors, 2, 16).unwrap();
+        let scorer = CodeScorer::new(&quantizer, DistanceMetric::L2, &vectors[3]).unwrap();
+        let codes: Vec<Vec<u8>> = vectors.iter().map(|v| quantizer.encode(v).unwrap()).collect();
+        // the expanded form of the distance leaves a rounding residue under the root
+        assert!(scorer.distance(&codes[3]) < 1e-6);
+        assert!(codes.iter().all(|code| scorer.distance(code) >= scorer.distance(&codes[3])));
+        assert!(CodeScorer::new(&quantizer, DistanceMetric::L2, &[0.0]).is_err());
+
+        // the other metrics score exact codes like the full vectors
+        for metric in [DistanceMetric::Cosine, DistanceMetric::InnerProduct] {
+            let scorer = CodeScorer::new(&quantizer, metric, &vectors[5]).unwrap();
+            for (code, v) in codes.iter().zip(&vectors) {
+                assert!((scorer.distance(code) - metric.distance(&vectors[5], v)).abs() < 1e-9);
+            }
+        }
+
+        let other = ProductQuantizer::train(&random_vectors(16, 4), 2, 16).unwrap();
+        assert_eq!(quantizer.fingerprint(), quantizer.clone().fingerprint());
+        assert_ne!(quantizer.fingerprint(), other.fingerprint());
+    }
+}
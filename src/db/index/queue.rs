@@ -0,0 +1,272 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+pub const INDEX_QUEUE_FILE: &str = "index.queue";
+
+// bytes of queued batches the indexer reads back at a time, past the first
+const READ_CHUNK: u64 = 1 << 20;
+
+const INSERT: u8 = 0;
+const REMOVE: u8 = 1;
+const REMOVE_RANGE: u8 = 2;
+
+// A change to the HNSW graph, the vector already projected
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum GraphUpdate {
+    Insert(u64, Vec<f64>),
+    Remove(u64),
+    RemoveRange(Range<u64>),
+}
+
+// Graph updates written by writes and waiting for the indexer thread, see
+// `Options::index_queue`. They are kept in a file in the tree's directory rather than in
+// memory, so a burst of writes the graph can't keep up with costs disk space instead.
+// The file is never synced: a close drains the queue before the graph is saved, and
+// after a crash the saved graph is reconciled with the tree, so the leftover file is
+// truncated unread.
+pub(crate) struct IndexQueue {
+    state: Mutex<QueueState>,
+    // signalled when a batch is queued or the queue is closed
+    queued: Condvar,
+    // signalled when the indexer has applied batches, or failed
+    applied: Condvar,
+}
+
+struct QueueState {
+    file: File,
+    // where the indexer reads the next batch, and where the next one is written. Both go
+    // back to 0 each time the indexer catches up.
+    read_offset: u64,
+    write_offset: u64,
+    // updates queued and applied since the tree was opened
+    queued: u64,
+    applied: u64,
+    // sequence of the last batch queued, and of the last one applied
+    queued_sequence: u64,
+    applied_sequence: u64,
+    closed: bool,
+    error: Option<String>,
+}
+
+impl IndexQueue {
+    // Starts an empty queue, dropping what a crashed tree left in the file
+    pub(crate) fn create(directory: &Path) -> io::Result<IndexQueue> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(directory.join(INDEX_QUEUE_FILE))?;
+        let state = QueueState {
+            file,
+            read_offset: 0,
+            write_offset: 0,
+            queued: 0,
+            applied: 0,
+            queued_sequence: 0,
+            applied_sequence: 0,
+            closed: false,
+            error: None,
+        };
+        Ok(IndexQueue { state: Mutex::new(state), queued: Condvar::new(), applied: Condvar::new() })
+    }
+
+    // Queues the graph updates of the batch written at `sequence`. Sequences have to
+    // increase from one call to the next. A batch that fails to be written isn't queued.
+    pub(crate) fn push(&self, sequence: u64, updates: &[GraphUpdate]) -> io::Result<()> {
+        let record = encode(sequence, updates)?;
+        let mut state = self.state();
+        if let Some(e) = &state.error {
+            return Err(io::Error::other(e.clone()));
+        }
+        let offset = state.write_offset;
+        // a torn write is overwritten by the next batch, as the offset stays put
+        state.file.seek(SeekFrom::Start(offset))?;
+        state.file.write_all(&record)?;
+        state.write_offset += record.len() as u64;
+        state.queued += updates.len() as u64;
+        state.queued_sequence = sequence;
+        self.queued.notify_all();
+        Ok(())
+    }
+
+    // Waits for queued batches and reads them back, returning the sequence of the last one
+    // and their updates in order, for the caller to pass to `applied` once they are in the
+    // graph. None once the queue is closed and every batch has been taken.
+    pub(crate) fn take(&self) -> Option<io::Result<(u64, Vec<GraphUpdate>)>> {
+        let mut state = self.state();
+        while state.read_offset == state.write_offset && !state.closed {
+            state = self.queued.wait(state).unwrap();
+        }
+        if state.read_offset == state.write_offset {
+            return None;
+        }
+        Some(state.read_batches())
+    }
+
+    // Records that the updates `take` returned are in the graph
+    pub(crate) fn applied(&self, sequence: u64, updates: u64) {
+        let mut state = self.state();
+        state.applied += updates;
+        state.applied_sequence = sequence;
+        self.applied.notify_all();
+    }
+
+    // Gives up on the queue: writes stop queueing and waits return the error
+    pub(crate) fn fail(&self, error: String) {
+        self.state().error = Some(error);
+        self.applied.notify_all();
+    }
+
+    // Lets the indexer finish: `take` returns what is left, then None
+    pub(crate) fn close(&self) {
+        self.state().closed = true;
+        self.queued.notify_all();
+    }
+
+    // Returns once every batch queued through `sequence` has been applied
+    pub(crate) fn wait(&self, sequence: u64) -> io::Result<()> {
+        let mut state = self.state();
+        loop {
+            if let Some(e) = &state.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            // batches are queued in sequence order, so applying one at or past `sequence`
+            // applied all those before it
+            if state.applied_sequence >= sequence.min(state.queued_sequence) {
+                return Ok(());
+            }
+            state = self.applied.wait(state).unwrap();
+        }
+    }
+
+    // Updates queued but not yet applied
+    pub(crate) fn missing(&self) -> u64 {
+        let state = self.state();
+        state.queued - state.applied
+    }
+
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl QueueState {
+    fn read_batches(&mut self) -> io::Result<(u64, Vec<GraphUpdate>)> {
+        let (start, end) = (self.read_offset, self.write_offset);
+        self.file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new((&mut self.file).take(end - start));
+        let (mut sequence, mut updates, mut offset) = (0, Vec::new(), start);
+        while offset < end && (offset == start || offset - start < READ_CHUNK) {
+            let len = reader.read_u32::<LittleEndian>()?;
+            let mut payload = vec![0; len as usize];
+            reader.read_exact(&mut payload)?;
+            sequence = decode(&payload, &mut updates)?;
+            offset += 4 + len as u64;
+        }
+        drop(reader);
+        if offset == end {
+            // caught up, so the file starts over instead of growing for as long as writes go on
+            self.file.set_len(0)?;
+            (self.read_offset, self.write_offset) = (0, 0);
+        } else {
+            self.read_offset = offset;
+        }
+        Ok((sequence, updates))
+    }
+}
+
+// A batch is a u32 length, then its sequence, its number of updates and each update:
+// a tag byte, the key and for an insert the vector's length and components, or for a
+// range the end key
+fn encode(sequence: u64, updates: &[GraphUpdate]) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    payload.write_u64::<LittleEndian>(sequence)?;
+    payload.write_u32::<LittleEndian>(updates.len() as u32)?;
+    for update in updates {
+        match update {
+            GraphUpdate::Insert(key, vector) => {
+                payload.write_u8(INSERT)?;
+                payload.write_u64::<LittleEndian>(*key)?;
+                payload.write_u32::<LittleEndian>(vector.len() as u32)?;
+                for x in vector {
+                    payload.write_f64::<LittleEndian>(*x)?;
+                }
+            }
+            GraphUpdate::Remove(key) => {
+                payload.write_u8(REMOVE)?;
+                payload.write_u64::<LittleEndian>(*key)?;
+            }
+            GraphUpdate::RemoveRange(range) => {
+                payload.write_u8(REMOVE_RANGE)?;
+                payload.write_u64::<LittleEndian>(range.start)?;
+                payload.write_u64::<LittleEndian>(range.end)?;
+            }
+        }
+    }
+    let mut record = Vec::with_capacity(4 + payload.len());
+    record.write_u32::<LittleEndian>(payload.len() as u32)?;
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+fn decode(mut payload: &[u8], updates: &mut Vec<GraphUpdate>) -> io::Result<u64> {
+    let sequence = payload.read_u64::<LittleEndian>()?;
+    let count = payload.read_u32::<LittleEndian>()?;
+    for _ in 0..count {
+        let tag = payload.read_u8()?;
+        let key = payload.read_u64::<LittleEndian>()?;
+        updates.push(match tag {
+            INSERT => {
+                let len = payload.read_u32::<LittleEndian>()? as usize;
+                let mut vector = vec![0.0; len];
+                payload.read_f64_into::<LittleEndian>(&mut vector)?;
+                GraphUpdate::Insert(key, vector)
+            }
+            REMOVE => GraphUpdate::Remove(key),
+            REMOVE_RANGE => GraphUpdate::RemoveRange(key..payload.read_u64::<LittleEndian>()?),
+            tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown index queue update {}", tag))),
+        });
+    }
+    Ok(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+
+    #[test]
+    fn test_index_queue() {
+        let path = empty_test_dir("queue");
+        std::fs::write(path.join(INDEX_QUEUE_FILE), b"left by a crash").unwrap();
+        let queue = IndexQueue::create(&path).unwrap();
+        queue.wait(10).unwrap();
+
+        queue.push(3, &[GraphUpdate::Insert(1, vec![1.0, 2.0]), GraphUpdate::Remove(2)]).unwrap();
+        queue.push(5, &[GraphUpdate::RemoveRange(4..9)]).unwrap();
+        assert_eq!(queue.missing(), 3);
+        let (sequence, updates) = queue.take().unwrap().unwrap();
+        assert_eq!(sequence, 5);
+        assert_eq!(updates, [GraphUpdate::Insert(1, vec![1.0, 2.0]), GraphUpdate::Remove(2), GraphUpdate::RemoveRange(4..9)]);
+        // taken everything, so the file starts over
+        assert_eq!(std::fs::metadata(path.join(INDEX_QUEUE_FILE)).unwrap().len(), 0);
+
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| queue.wait(4));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!waiter.is_finished());
+            queue.applied(sequence, updates.len() as u64);
+            waiter.join().unwrap().unwrap();
+        });
+        assert_eq!(queue.missing(), 0);
+
+        queue.push(6, &[GraphUpdate::Remove(7)]).unwrap();
+        queue.close();
+        assert_eq!(queue.take().unwrap().unwrap(), (6, vec![GraphUpdate::Remove(7)]));
+        assert!(queue.take().is_none());
+
+        queue.fail("disk gone".to_string());
+        assert!(queue.wait(6).is_err());
+        assert!(queue.push(7, &[]).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+// Told about a tree's background work as it happens, so an application can log it,
+// alert on it or export metrics without polling `LSMTree::stats`. Registered with
+// `Options::listeners`. Flush and compaction callbacks run on the thread doing the job,
+// and stall callbacks on the writing thread, so they hold that work up until they
+// return and shouldn't block or call back into the tree.
+pub trait EventListener: Send + Sync {
+    fn on_flush_begin(&self, _info: &FlushInfo) {}
+
+    // once the table is installed; a failed flush gets no call
+    fn on_flush_completed(&self, _info: &FlushInfo) {}
+
+    fn on_compaction_begin(&self, _info: &CompactionInfo) {}
+
+    // once the outputs have replaced the inputs; a failed compaction gets no call
+    fn on_compaction_completed(&self, _info: &CompactionInfo) {}
+
+    // when a write finds the tree's stall condition changed since the last write did
+    fn on_write_stall(&self, _info: &WriteStallInfo) {}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlushInfo {
+    pub file_number: u64,
+    // the log holding the memtable's writes, deleted after the flush
+    pub wal_number: u64,
+    pub entries: usize,
+    pub tombstones: usize,
+    pub range_tombstones: usize,
+    // the table written, 0 in `on_flush_begin`
+    pub file_size: u64,
+    pub elapsed_micros: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionInfo {
+    // the tables merged, oldest first
+    pub inputs: Vec<u64>,
+    pub input_bytes: u64,
+    // the tables written, each holding a key range of its own. Empty in
+    // `on_compaction_begin`.
+    pub outputs: Vec<u64>,
+    pub output_bytes: u64,
+    // the merge included the oldest table, so deletes and expired entries were dropped
+    pub bottommost: bool,
+    pub elapsed_micros: u64,
+}
+
+// How far background work is behind, see `Options::stop_tables`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteStallCondition {
+    #[default]
+    Normal,
+    // writes sleep `Options::slowdown_write_micros` first
+    Slowdown,
+    // writes wait for background work to catch up
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteStallInfo {
+    pub condition: WriteStallCondition,
+    pub previous: WriteStallCondition,
+    // frozen memtables waiting to be flushed, and sorted runs of tables
+    pub immutables: usize,
+    pub runs: usize,
+}
@@ -1,259 +1,8462 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use memmap2::{Mmap};
-use std::collections::{BTreeSet, BTreeMap};
-use std::fs::OpenOptions;
-use std::io::{self, BufWriter, Seek, Write, Read};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::{Bound, Range, RangeBounds, RangeInclusive};
 use std::path::{Path, PathBuf};
-use crate::db::vector::Vector;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::db::batch::{BatchOp, WriteBatch};
+use crate::db::binary;
+use crate::db::bulk::ExternalSorter;
+use crate::db::cache::{BlockCache, RowCache};
+use crate::db::compaction::{self, CompactionStyle, HintClaim, ReadHint, ReadHints};
+use crate::db::codec;
+use crate::db::config::{self, Config, ConfigReload};
+use crate::db::direct::DirectWriter;
+use crate::db::embeddings::{self, EmbeddingFile, EmbeddingFormat, EmbeddingIds};
+use crate::db::entry;
+use crate::db::error::{self, LsmError};
+use crate::db::events::{self, Event, Subscribers};
+use crate::db::executor::Executor;
+use crate::db::export::{self, ExportFormat};
+#[cfg(feature = "failpoints")]
+use crate::db::failpoint::FailPoint;
+use crate::db::filter::Filter;
+use crate::db::index::field::{self, FieldIndex};
+use crate::db::index::hnsw::{self, GraphDiagnostics, Hnsw, HnswOptions, Watermark};
+use crate::db::index::ivf::{self, Ivf};
+use crate::db::index::pq::{CodeScorer, ProductQuantizer};
+use crate::db::index::queue::{self, GraphUpdate, IndexQueue};
+use crate::db::listener::{CompactionInfo, FlushInfo, WriteStallCondition, WriteStallInfo};
+use crate::db::manifest::{self, Manifest, VersionEdit};
+use crate::db::memory::MemoryUsage;
+use crate::db::memtable::Memtable;
+use crate::db::options::Options;
+use crate::db::pca;
+use crate::db::pipeline::Pipeline;
+use crate::db::platform::sync_dir;
+use crate::db::prefix::PrefixReport;
+use crate::db::preflight::{self, StartupReport};
+use crate::db::ratelimit::{Metered, RateLimiter};
+use crate::db::search::{DistanceMetric, Scorer, TopK};
+use crate::db::sstable::{self, ScanHint, SSTable, TableFormat};
+use crate::db::stats::{Counters, Histogram, Stats, TableInfo, Timer};
+use crate::db::storage::{Appender, Storage, StoredTable};
+use crate::db::stream::WriteStream;
+use crate::db::transaction::Transaction;
+use crate::db::vector::{self, ElementType, MetadataValue, ValueRef, Vector};
+use crate::db::verify::{TableReport, VerifyReport};
+use crate::db::wal::{self, SyncPolicy, Wal, WalRecovery};
 
 pub struct LSMTree {
-    memtable: BTreeMap<u64, Vector>,
-    sstables: Vec<SSTable>,
+    inner: Arc<Inner>,
+    // flush threads then compaction threads, none under `Executor::Manual`
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
     directory: PathBuf,
-    sstable_size: usize,
-    max_open_sstables: usize,
+    options: Options,
+    // opened with `LSMTree::open_read_only`
+    read_only: bool,
+    // holds the directory's LOCK file until the tree is dropped; read-only trees take none
+    _lock: Option<File>,
+    startup_report: StartupReport,
+    counters: Arc<Counters>,
+    // raw entries read from the tables, with `Options::block_cache_bytes` set
+    cache: Option<Arc<BlockCache>>,
+    // decoded values of recently read keys, with `Options::row_cache_entries` set
+    row_cache: Option<RowCache>,
+    // paces compactions, see `Options::compaction_bytes_per_sec`
+    rate_limiter: RateLimiter,
+    // pace `write` by its bytes and operations, see `Options::write_bytes_per_sec`
+    write_bytes_limiter: RateLimiter,
+    write_ops_limiter: RateLimiter,
+    // receivers of `LSMTree::subscribe`, sent each batch as it is appended to the WAL
+    subscribers: Subscribers,
+    // graph over the projected vectors, updated by writes after they are applied
+    index: Option<RwLock<Hnsw>>,
+    // the graph's updates waiting for the indexer, with `Options::index_queue` set
+    index_queue: Option<IndexQueue>,
+    // partitions of the preprocessed vectors, once `train_ivf` has been called
+    ivf: RwLock<Option<Ivf>>,
+    // codebooks for `pq_search`, once `train_pq` has been called, which tables written with
+    // `Options::pq_codes` store the codes of their vectors under
+    pq: RwLock<Option<Arc<ProductQuantizer>>>,
+    // the keys holding each value of `Options::indexed_fields`
+    fields: Option<RwLock<FieldIndex>>,
+    // what lookups read; writers hold it exclusively only to apply or install changes
+    state: RwLock<State>,
+    // serializes writers: WAL appends, manifest edits and flushes. Always taken before `state`.
+    writer: Mutex<Writer>,
+    background: Mutex<Background>,
+    // signalled when a job is queued or on shutdown
+    job_requested: Condvar,
+    // signalled whenever a background job finishes or fails
+    job_done: Condvar,
+    // signalled with `writer` when a WAL sync finishes
+    synced: Condvar,
+    // what the last write found, for `EventListener::on_write_stall`
+    stall_condition: Mutex<WriteStallCondition>,
+    // set by `LSMTree::retire`, which leaves what to run once the tree is dropped
+    retired: AtomicBool,
+    on_retired: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+#[derive(Clone)]
+struct State {
+    memtable: Memtable,
+    // the memtable's arena and index, merge operands and tombstones, see `Memtable::bytes`
+    memtable_bytes: usize,
+    // merge operands not yet folded into a value, oldest first
+    merges: BTreeMap<u64, Vec<Vec<u8>>>,
+    // keys deleted since the memtable was created, none of them in it, hiding older
+    // memtables and tables. Flushed with the memtable so the deletes outlive it.
+    tombstones: BTreeSet<u64>,
+    // key ranges deleted since the memtable was created, hiding older memtables and tables
+    range_tombstones: Vec<Range<u64>>,
+    // frozen memtables waiting to be flushed, oldest first
+    immutables: Vec<Immutable>,
+    sstables: Vec<SSTable>,
+    pipeline: Pipeline,
+    projection: Pipeline,
+    metric: DistanceMetric,
+    // what `LSMTree::set_query_metrics` declared
+    query_metrics: Vec<DistanceMetric>,
+    element_type: ElementType,
+    dimension: Option<usize>,
+    // the tree's counters, shared with snapshots so their lookups are counted too
+    counters: Arc<Counters>,
+    // where iterators leave the ranges they found crowded, also the snapshots' ones
+    read_hints: Arc<ReadHints>,
+}
+
+struct Writer {
+    manifest: Manifest,
+    wal: Wal,
+    sequence: u64,
+    // last sequence number whose WAL record has been synced
+    synced_sequence: u64,
+    // a writer is waiting out the commit window or syncing, the others wait for it
+    syncing: bool,
+    // write batches appended since the last sync
+    unsynced_commits: u64,
+    last_sync: Instant,
+    // when the oldest write still in the active memtable was applied, see
+    // `Options::flush_interval_millis`
+    memtable_since: Option<Instant>,
+    history: History,
+    // a merge of the field tables is writing its table, see `Inner::merge_field_tables`
+    merging_fields: bool,
+}
+
+// What keys held before the writes of the last `Options::history_retention_millis`, for
+// `LSMTree::get_at` and `iter_at`. Kept in memory only, so it starts over when the tree
+// is opened.
+#[derive(Default)]
+struct History {
+    // (key, sequence) to the key's value before the batch ending at that sequence
+    before: BTreeMap<(u64, u64), Option<Vector>>,
+    // (when, sequence, key) of each value in `before`, oldest first
+    recorded: VecDeque<(Instant, u64, u64)>,
+    // sequences before this can't be read back: a write after them has been forgotten
+    horizon: u64,
+}
+
+impl History {
+    fn record(&mut self, sequence: u64, key: u64, before: Option<Vector>) {
+        self.before.insert((key, sequence), before);
+        self.recorded.push_back((Instant::now(), sequence, key));
+    }
+
+    fn expire(&mut self, retention: Duration) {
+        while let Some(&(at, sequence, key)) = self.recorded.front()
+            && at.elapsed() > retention
+        {
+            self.recorded.pop_front();
+            self.before.remove(&(key, sequence));
+            self.horizon = self.horizon.max(sequence);
+        }
+    }
+
+    // After keys changed without a sequence number, leaving nothing before `sequence`
+    fn forget(&mut self, sequence: u64) {
+        self.before.clear();
+        self.recorded.clear();
+        self.horizon = sequence;
+    }
+
+    fn check(&self, sequence: u64) -> io::Result<()> {
+        match sequence < self.horizon {
+            true => Err(io::Error::new(io::ErrorKind::NotFound, format!("the history starts at sequence {}, after {}", self.horizon, sequence))),
+            false => Ok(()),
+        }
+    }
+
+    // The key's value as of `sequence`, if a later write changed it
+    fn value_at(&self, key: u64, sequence: u64) -> Option<&Option<Vector>> {
+        self.before.range((key, sequence + 1)..=(key, u64::MAX)).next().map(|(_, value)| value)
+    }
+
+    // As of `sequence`, the value of every key written since
+    fn changed_since(&self, sequence: u64) -> BTreeMap<u64, Option<Vector>> {
+        let mut changed = BTreeMap::new();
+        for &(_, at, key) in self.recorded.iter().filter(|(_, at, _)| *at > sequence) {
+            changed.entry(key).or_insert_with(|| self.before[&(key, at)].clone());
+        }
+        changed
+    }
+}
+
+// Where `State::find` found a key
+enum Found<'a> {
+    Memory(ValueRef<'a>),
+    // the table and the entry's offset in it
+    Table(&'a SSTable, usize),
+}
+
+#[derive(Clone)]
+struct Immutable {
+    memtable: Arc<Memtable>,
+    tombstones: Arc<BTreeSet<u64>>,
+    range_tombstones: Vec<Range<u64>>,
+    // `State::memtable_bytes` when it was frozen
+    bytes: usize,
+    // log holding this memtable's writes, obsolete once the table is installed
+    wal_number: u64,
+    last_sequence: u64,
+    // the entries its writes filed in the field indexes, for a field table flushed with it
+    field_entries: Arc<BTreeSet<Vec<u8>>>,
+}
+
+// A frozen, consistent view of the tree as of one sequence number. Later writes,
+// flushes and compactions don't affect it: it holds its own copy of the memtable and
+// references to the tables it saw, which stay readable until the snapshot is dropped.
+pub struct Snapshot {
+    sequence: u64,
+    options: Options,
+    state: State,
+}
+
+// What `LSMTree::iter_at` returns: the current entries, with each key written since
+// replaced by its value back then
+pub struct IterAt {
+    current: std::iter::Peekable<Iter>,
+    changed: std::iter::Peekable<std::collections::btree_map::IntoIter<u64, Option<Vector>>>,
+}
+
+// Where a page of `LSMTree::scan` stopped: the key the next page starts at, and the
+// sequence the first page was read as of, which later pages read as of too while the
+// history reaches back to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    next: u64,
+    sequence: u64,
+}
+
+// A page of `LSMTree::scan`: its entries, and the cursor to the next page if there is one
+pub type Page = (Vec<(u64, Vector)>, Option<Cursor>);
+
+#[derive(Default)]
+struct Background {
+    shutdown: bool,
+    // a failed background job stops every worker and fails later writes
+    error: Option<String>,
+    // WAL numbers of the immutable memtables a flush thread has taken
+    flushing: BTreeSet<u64>,
+    // tables that are inputs to a running compaction
+    compacting: BTreeSet<u64>,
+    // `Options::compaction_style` until `LSMTree::set_compaction_style` changes it
+    style: CompactionStyle,
+}
+
+// Every live entry in ascending key order, k-way merged from the memtables and SSTables
+// one key at a time. Like a `Snapshot` it reads the tree as of when it was created.
+// Pagination and replication rely on the order, so it is a contract that flushes,
+// compactions and reopening never change, and that `range` follows as well:
+// - keys come out strictly ascending, each at most once (descending from `iter_rev`)
+// - a key yields its newest write: a put, point delete or covering range delete
+//   hides every older write to it, whichever layers they sit in
+// - deleted and expired keys are skipped, merge operands are folded over the newest value
+pub struct Iter {
+    options: Options,
+    state: State,
+    // keys outside these aren't returned
+    bounds: (Bound<u64>, Bound<u64>),
+    // descending order, for `iter_rev`
+    reverse: bool,
+    layers: Vec<Layer>,
+    // next key of each layer after the last one returned, same order as `layers`
+    heads: Vec<Option<u64>>,
+    merge_head: Option<u64>,
+    // a table that couldn't be read, returned by the next call to `next`
+    error: Option<io::Error>,
+    // readahead over the tables for as long as the iteration, see `Options::access_hints`
+    _hints: Vec<ScanHint>,
+    // table entries stepped over for being deleted, expired or overwritten, and the first
+    // and last keys they were under, see `Options::read_compaction_skips`
+    skipped: usize,
+    skipped_keys: Option<(u64, u64)>,
+}
+
+// Where entries live, newest first
+#[derive(Clone, Copy)]
+enum Layer {
+    Memtable,
+    Immutable(usize),
+    Table(usize),
+}
+
+enum IndexUpdate {
+    // the stored vector and its projection, when there is a graph to insert it into
+    Insert(u64, Vector, Option<Vec<f64>>),
+    Remove(u64),
+    RemoveRange(Range<u64>),
+}
+
+impl From<IndexUpdate> for GraphUpdate {
+    fn from(update: IndexUpdate) -> GraphUpdate {
+        match update {
+            IndexUpdate::Insert(key, _, Some(projected)) => GraphUpdate::Insert(key, projected),
+            // a vector the projection can't reduce is left out of the graph
+            IndexUpdate::Insert(key, _, None) | IndexUpdate::Remove(key) => GraphUpdate::Remove(key),
+            IndexUpdate::RemoveRange(range) => GraphUpdate::RemoveRange(range),
+        }
+    }
+}
+
+// A background job claimed by a worker, with what it needs from the state at that time
+enum Job {
+    Flush { memtable: Arc<Memtable>, tombstones: Arc<BTreeSet<u64>>, range_tombstones: Vec<Range<u64>>, field_entries: Arc<BTreeSet<Vec<u8>>>, wal_number: u64, last_sequence: u64 },
+    // the file numbers of the tables to merge, oldest first
+    Compaction { start: usize, picked: Vec<u64> },
 }
 
-struct SSTable {
-    mmap: Mmap,
-    index: BTreeMap<u64, usize>,
-    tombstones: BTreeSet<u64>,
-}
 
-impl LSMTree {
-    pub fn new(directory: &Path) -> io::Result<Self> {
-        std::fs::create_dir_all(directory)?;
-        Ok(LSMTree {
-            memtable: BTreeMap::new(),
-            sstables: Vec::new(),
-            directory: directory.to_path_buf(),
-            sstable_size: 10,
-            max_open_sstables: 10,
-        })
+#[derive(Clone, Copy, PartialEq)]
+enum JobKind {
+    Flush,
+    Compaction,
+}
+
+impl LSMTree {
+    pub fn new(directory: &Path) -> Result<Self, LsmError> {
+        LSMTree::open(directory, Options::default())
+    }
+
+    pub fn open(directory: &Path, options: Options) -> Result<Self, LsmError> {
+        check_options(&options)?;
+        let inner = Arc::new(Inner::open(directory, options, false)?);
+        let weak = Arc::downgrade(&inner);
+        inner.state().read_hints.set_wake(move || {
+            // the iterator may be dropped under the state lock, which a thread holding
+            // `background` may be waiting for; a hint missing the wakeup waits for the next job
+            if let Some(inner) = weak.upgrade()
+                && let Ok(_background) = inner.background.try_lock()
+            {
+                inner.job_requested.notify_all();
+            }
+        });
+        let mut tree = LSMTree { inner, workers: Vec::new() };
+        if tree.inner.options.executor == Executor::Threaded {
+            let pools = [
+                (JobKind::Flush, "flush", tree.inner.options.max_flush_threads),
+                (JobKind::Compaction, "compact", tree.inner.options.max_compaction_threads),
+            ];
+            for (kind, name, threads) in pools {
+                for i in 0..threads {
+                    let inner = tree.inner.clone();
+                    // a failed spawn drops the tree, which stops the workers already started
+                    tree.workers.push(std::thread::Builder::new()
+                        .name(format!("{}-{}-{}", inner.options.thread_name_prefix, name, i))
+                        .spawn(move || inner.background_loop(kind))?);
+                }
+            }
+            if tree.inner.index_queue.is_some() {
+                let inner = tree.inner.clone();
+                tree.workers.push(std::thread::Builder::new()
+                    .name(format!("{}-indexer", inner.options.thread_name_prefix))
+                    .spawn(move || inner.index_loop())?);
+            }
+            if tree.inner.options.flush_interval_millis != 0 {
+                let inner = tree.inner.clone();
+                tree.workers.push(std::thread::Builder::new()
+                    .name(format!("{}-flush-timer", inner.options.thread_name_prefix))
+                    .spawn(move || inner.flush_timer(Duration::from_millis(inner.options.flush_interval_millis)))?);
+            }
+            if let SyncPolicy::EveryNMillis(millis @ 1..) = tree.inner.options.sync_policy {
+                let inner = tree.inner.clone();
+                tree.workers.push(std::thread::Builder::new()
+                    .name(format!("{}-sync-timer", inner.options.thread_name_prefix))
+                    .spawn(move || inner.sync_timer(Duration::from_millis(millis)))?);
+            }
+        }
+        Ok(tree)
+    }
+
+    // Opens an existing tree without changing anything in its directory, so it can be read
+    // while another process writes to it. The WAL is replayed into memory only, as it was
+    // when opened: later writes aren't seen. Writes, flushes and compactions are refused
+    // with PermissionDenied, and there are no background threads.
+    pub fn open_read_only(directory: &Path, options: Options) -> Result<Self, LsmError> {
+        check_options(&options)?;
+        let inner = Arc::new(Inner::open(directory, options, true)?);
+        Ok(LSMTree { inner, workers: Vec::new() })
+    }
+
+    // Deletes the tree in `directory` and the directory with it. Refused with
+    // InvalidArgument unless the directory holds a tree's MANIFEST or LOCK and nothing but
+    // a tree's files, so a mistyped path can't wipe anything else, and with ResourceBusy
+    // while the tree is open. Tables kept in `Options::storage` aren't touched. A missing
+    // directory is fine.
+    pub fn destroy(directory: &Path) -> Result<(), LsmError> {
+        let mut names = Vec::new();
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_file() || !is_tree_file(&name) {
+                return Err(LsmError::InvalidArgument(format!("{} holds {}, which isn't a tree's, so it isn't destroyed", directory.display(), name)));
+            }
+            names.push(name);
+        }
+        if !names.iter().any(|name| name == manifest::MANIFEST_FILE || name == LOCK_FILE) {
+            return Err(LsmError::InvalidArgument(format!("{} isn't a tree's directory", directory.display())));
+        }
+        let lock = lock_directory(directory)?;
+        // the manifest first, so a crash partway leaves no tree that opens with tables missing
+        names.sort_by_key(|name| (name != manifest::MANIFEST_FILE, name == LOCK_FILE));
+        for name in names.iter().filter(|name| *name != LOCK_FILE) {
+            match std::fs::remove_file(directory.join(name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        std::fs::remove_file(directory.join(LOCK_FILE))?;
+        drop(lock);
+        Ok(std::fs::remove_dir(directory)?)
+    }
+
+    // What the preflight checks and recovery found when this tree was opened
+    pub fn startup_report(&self) -> &StartupReport {
+        &self.inner.startup_report
+    }
+
+    pub fn insert(&self, key: u64, value: Vector) -> Result<(), LsmError> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    // Like `insert`, returning the value the key held before, read under the same writer
+    // lock as the write so no other write can land in between
+    pub fn insert_fetch(&self, key: u64, value: Vector) -> Result<Option<Vector>, LsmError> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        Ok(self.write_fetch(key, batch)?)
+    }
+
+    // The entry reads as absent once `ttl` has passed and is dropped by a later compaction
+    pub fn insert_with_ttl(&self, key: u64, value: Vector, ttl: Duration) -> Result<(), LsmError> {
+        let mut value = value;
+        value.set_expires_at(vector::now_millis().saturating_add(ttl.as_millis() as u64));
+        self.insert(key, value)
+    }
+
+    // Applies every operation in the batch or none of them: the batch is a single WAL
+    // record, so recovery either replays it whole or drops it as a torn write.
+    // Deleting a key that doesn't exist is a no-op inside a batch.
+    pub fn write(&self, batch: WriteBatch) -> Result<(), LsmError> {
+        Ok(self.inner.write(batch)?)
+    }
+
+    // Like `write`, returning the sequence number of the batch's last operation
+    pub(crate) fn write_sequenced(&self, batch: WriteBatch) -> io::Result<u64> {
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        let ops = batch.len() as u64;
+        let bytes = self.inner.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)?;
+        self.inner.pace_write(ops, bytes);
+        Ok(sequence)
+    }
+
+    // Sequence number of the last write
+    pub fn sequence(&self) -> u64 {
+        self.inner.writer().sequence
+    }
+
+    pub(crate) fn synced_sequence(&self) -> u64 {
+        self.inner.writer().synced_sequence
+    }
+
+    // Starts a stream of writes applied `batch_size` operations at a time
+    pub fn write_stream(&self, batch_size: usize) -> WriteStream<'_> {
+        WriteStream::new(self, batch_size)
+    }
+
+    // Receives every batch written from now on as events, in sequence order, as each is
+    // appended to the WAL. Bulk loads bypass the log and send none.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.inner.subscribers.subscribe()
+    }
+
+    // The events after `sequence` still held by the WAL, in order, to catch a subscriber up
+    // after a disconnect. Logs are deleted once flushed unless `Options::retained_wals`
+    // keeps them, so a sequence that far back is NotFound.
+    pub fn read_log_since(&self, sequence: u64) -> Result<Vec<Event>, LsmError> {
+        // the writer lock keeps appends out; a flushed log may still be deleted meanwhile
+        let writer = self.inner.writer();
+        let mut batches = Vec::new();
+        for number in wal::list_wals(&self.inner.directory)? {
+            match Wal::read(&self.inner.directory, number) {
+                Ok((_, records, _)) => batches.extend(records),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut batches: Vec<(u64, WriteBatch)> = batches.iter().map(|record| WriteBatch::decode(record)).collect::<io::Result<_>>()?;
+        batches.sort_by_key(|(first, _)| *first);
+        let oldest = batches.first().map_or(writer.sequence + 1, |(first, _)| *first);
+        drop(writer);
+        if sequence.saturating_add(1) < oldest {
+            return Err(LsmError::NotFound(format!("the log starts at sequence {}, after {}", oldest, sequence)));
+        }
+        Ok(batches.iter().flat_map(|(first, batch)| events::from_batch(*first, batch)).filter(|event| event.sequence() > sequence).collect())
+    }
+
+    // Buffers a partial update; reads and flushes fold it into the value with the
+    // configured merge operator
+    pub fn merge(&self, key: u64, operand: Vec<u8>) -> Result<(), LsmError> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(batch)
+    }
+
+    // The key's live value, or None. A table entry that can't be read or decoded is an
+    // error rather than reading as absent.
+    pub fn get(&self, key: u64) -> Result<Option<Vector>, LsmError> {
+        let _timer = self.inner.time(&self.inner.counters.get_latency);
+        let state = self.inner.state();
+        let Some(cache) = &self.inner.row_cache else {
+            return Ok(state.get(key, &self.inner.options)?);
+        };
+        if let Some(value) = cache.get(key) {
+            Counters::add(&self.inner.counters.gets, 1);
+            return Ok(Some(value).filter(|value| !value.is_expired(vector::now_millis())));
+        }
+        // filled under the state lock, so a write can't slip in between reading and caching
+        let value = state.get(key, &self.inner.options)?;
+        if let Some(value) = &value {
+            cache.insert(key, value.clone());
+        }
+        Ok(value)
+    }
+
+    // Calls `f` with the value of `key`, or None, without copying it out: data on disk
+    // is read in place from the table's mapping or block cache. The tree's read lock is
+    // held while `f` runs, which keeps writes waiting, so `f` should be brief.
+    pub fn get_with<R>(&self, key: u64, f: impl FnOnce(Option<ValueRef<'_>>) -> R) -> Result<R, LsmError> {
+        Ok(self.inner.state().get_with(key, &self.inner.options, f)?)
+    }
+
+    // Every live entry whose metadata `field` holds `value`, in key order, found through
+    // the field's index. InvalidArgument for a field not in `Options::indexed_fields`.
+    pub fn get_by_field(&self, field: &str, value: impl Into<MetadataValue>) -> Result<Vec<(u64, Vector)>, LsmError> {
+        let filter = Filter::eq(field, value);
+        let candidates = match &self.inner.fields {
+            Some(fields) => fields.read().unwrap().candidates(&filter),
+            None => None,
+        };
+        let Some(candidates) = candidates else {
+            return Err(LsmError::InvalidArgument(format!("field '{}' has no index in Options::indexed_fields", field)));
+        };
+        let state = self.inner.state();
+        let mut entries = Vec::new();
+        for key in candidates {
+            // the index is updated after the write it follows, so it is checked against the value
+            if let Some(value) = state.get(key, &self.inner.options)?.filter(|value| filter.matches(value.metadata())) {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    // Every live entry with a key in `range`, in key order
+    pub fn range<R: RangeBounds<u64>>(&self, range: R) -> Result<Vec<(u64, Vector)>, LsmError> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(self.inner.state().range(bounds, &self.inner.options)?)
+    }
+
+    // Starts an optimistic transaction reading from a snapshot of the tree as it is now
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self, self.snapshot())
+    }
+
+    // Applies the batch only if every key in `reads` still has the value that was read.
+    // Holding the writer lock makes the check and the write one atomic step.
+    pub(crate) fn write_if_unchanged(&self, reads: &BTreeMap<u64, Option<Vector>>, batch: WriteBatch) -> io::Result<()> {
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        {
+            let state = self.inner.state();
+            for (&key, seen) in reads.iter() {
+                if state.get(key, &self.inner.options)? != *seen {
+                    return Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("transaction conflict: key '{}' was modified", key)));
+                }
+            }
+        }
+        self.inner.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)
+    }
+
+    // Inserts the value only if the key has no live value, returning whether it did
+    pub fn put_if_absent(&self, key: u64, value: Vector) -> Result<bool, LsmError> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        Ok(self.write_if(key, None, batch)?)
+    }
+
+    // The key's value, or the one `f` makes, stored under the key first. `f` runs under the
+    // writer lock, so concurrent callers for a missing key don't both compute it, but it
+    // holds up every other write meanwhile and mustn't write to the tree itself.
+    pub fn get_or_insert_with(&self, key: u64, f: impl FnOnce() -> Vector) -> Result<Vector, LsmError> {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        // another writer may have stored it while this one waited for the lock
+        if let Some(value) = self.inner.state().get(key, &self.inner.options)? {
+            return Ok(value);
+        }
+        let mut batch = WriteBatch::new();
+        batch.put(key, f());
+        self.inner.write_locked(&mut writer, batch)?;
+        // what a get returns, after the pipeline has had its way with the value
+        let stored = self.inner.state().get(key, &self.inner.options)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)?;
+        stored.ok_or_else(|| LsmError::InvalidArgument(format!("the value made for key {} has already expired", key)))
+    }
+
+    // Replaces the key's value with `new` only if it is currently `expected`, where `None`
+    // means absent, returning whether it did
+    pub fn compare_and_swap(&self, key: u64, expected: Option<&Vector>, new: Vector) -> Result<bool, LsmError> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, new);
+        Ok(self.write_if(key, expected, batch)?)
+    }
+
+    // Like `write_if_unchanged` for a single key, reporting a mismatch as false
+    fn write_if(&self, key: u64, expected: Option<&Vector>, batch: WriteBatch) -> io::Result<bool> {
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        if self.inner.state().get(key, &self.inner.options)?.as_ref() != expected {
+            return Ok(false);
+        }
+        self.inner.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)?;
+        Ok(true)
+    }
+
+    // Writes `batch`, returning what `key` held just before it
+    fn write_fetch(&self, key: u64, batch: WriteBatch) -> io::Result<Option<Vector>> {
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        let previous = self.inner.state().get(key, &self.inner.options)?;
+        self.inner.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)?;
+        Ok(previous)
+    }
+
+    // Counts every stored entry by key prefix across the memtables and SSTables, to find
+    // which key ranges dominate the tree
+    pub fn prefix_report(&self, prefix_bits: u32) -> Result<PrefixReport, LsmError> {
+        let mut report = PrefixReport::new(prefix_bits)?;
+        let state = self.inner.state();
+        let memtables = std::iter::once(&state.memtable).chain(state.immutables.iter().map(|m| m.memtable.as_ref()));
+        for memtable in memtables {
+            for (key, size) in memtable.sizes((Bound::Unbounded, Bound::Unbounded)) {
+                report.add_memtable(key, size);
+            }
+        }
+        for sstable in state.sstables.iter() {
+            for entry in sstable.entries(..) {
+                let (key, offset) = entry?;
+                report.add_sstable(key, sstable.entry_size(offset)?);
+            }
+        }
+        Ok(report)
+    }
+
+    // Number of live keys, found by scanning the whole tree. Meant for small trees, see
+    // `approximate_len` otherwise.
+    pub fn len(&self) -> Result<usize, LsmError> {
+        Ok(self.range(..)?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, LsmError> {
+        Ok(self.len()? == 0)
+    }
+
+    // Entries stored in every memtable and table minus their point tombstones, without
+    // reading any data. Overwritten keys are counted once per layer holding them and
+    // range deletes aren't subtracted, so this overestimates until compaction catches up.
+    pub fn approximate_len(&self) -> usize {
+        self.inner.state().approximate_len()
+    }
+
+    // Bytes the keys in `start..end` take up: in each table, from the first entry in the
+    // range to the first one past it, read off the index, and in the memtables, the sizes
+    // `Stats::memtable_bytes` counts. Deleted and overwritten entries count until compaction
+    // drops them, as they still take up the space.
+    pub fn approximate_size_of_range(&self, start: u64, end: u64) -> Result<u64, LsmError> {
+        Ok(self.inner.state().approximate_size(start..end)?)
+    }
+
+    // The live SSTables, oldest first
+    pub fn describe(&self) -> Vec<TableInfo> {
+        let state = self.inner.state();
+        let levels = compaction::runs(&state.sstables).into_iter().enumerate().flat_map(|(level, run)| run.map(move |_| level));
+        state.sstables.iter().zip(levels).map(|(table, level)| TableInfo {
+            file_number: table.file_number,
+            file_name: manifest::table_file_name(table.file_number),
+            file_size: table.file_size(),
+            format_version: table.version,
+            key_range: table.key_range(),
+            entries: table.len(),
+            tombstones: table.tombstones.len(),
+            range_tombstones: table.range_tombstones.len(),
+            level,
+            cold: table.cold,
+        }).collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.inner.state();
+        let mut stats = self.inner.counters.read(state.sstables.len());
+        stats.table_bytes = state.sstables.iter().map(|t| t.file_size()).sum();
+        let usage = state.memory_usage();
+        drop(state);
+        stats.memtable_bytes = usage.memtable;
+        stats.frozen_memtable_bytes = usage.frozen;
+        stats.filter_bytes = usage.filters;
+        stats.index_bytes = usage.indexes;
+        if let Some(cache) = &self.inner.cache {
+            stats.block_cache_hits = cache.hits();
+            stats.block_cache_misses = cache.misses();
+            stats.block_cache_bytes = cache.bytes();
+        }
+        if let Some(cache) = &self.inner.row_cache {
+            stats.row_cache_hits = cache.hits();
+            stats.row_cache_misses = cache.misses();
+        }
+        stats.compaction_throttled_micros = self.inner.rate_limiter.waited_micros();
+        stats.compaction_throttled = self.inner.rate_limiter.is_throttling();
+        stats.write_throttled_micros = self.inner.write_bytes_limiter.waited_micros() + self.inner.write_ops_limiter.waited_micros();
+        stats.index_backlog = self.inner.index_queue.as_ref().map_or(0, |queue| queue.missing());
+        stats
+    }
+
+    // Streams every live entry in key order without collecting them like `range` does.
+    // Later writes aren't seen; tables compacted away meanwhile stay readable.
+    pub fn iter(&self) -> Iter {
+        Iter::new(self.inner.state().clone(), self.inner.options.clone())
+    }
+
+    // Like `iter` in descending key order, so the largest keys come first without a full scan
+    pub fn iter_rev(&self) -> Iter {
+        Iter::reversed(self.inner.state().clone(), self.inner.options.clone())
+    }
+
+    // The keys `iter` would return, without reading their values
+    pub fn keys(&self) -> Keys {
+        Keys(self.iter())
+    }
+
+    // The live keys in `range`, in ascending order
+    pub fn range_keys<R: RangeBounds<u64>>(&self, range: R) -> Keys {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        Keys(Iter::bounded(self.inner.state().clone(), self.inner.options.clone(), bounds, None))
+    }
+
+    // The live keys whose top `prefix_bits` bits are `prefix`, in ascending order like
+    // `iter`. Tables written with a prefix filter of at most that many bits that doesn't
+    // have the prefix are passed over.
+    pub fn iter_prefix(&self, prefix: u64, prefix_bits: u32) -> Result<Iter, LsmError> {
+        if prefix_bits == 0 || prefix_bits > 64 || prefix.checked_shr(prefix_bits).unwrap_or(0) != 0 {
+            return Err(LsmError::InvalidArgument(format!("{:#x} is not a prefix of {} bits", prefix, prefix_bits)));
+        }
+        let shift = 64 - prefix_bits;
+        let start = prefix << shift;
+        let end = start | u64::MAX.checked_shr(prefix_bits).unwrap_or(0);
+        let bounds = (Bound::Included(start), Bound::Included(end));
+        Ok(Iter::bounded(self.inner.state().clone(), self.inner.options.clone(), bounds, Some((prefix, prefix_bits))))
+    }
+
+    // The key's value as of `sequence`, like a snapshot taken then would read it. A
+    // sequence older than `Options::history_retention_millis` keeps, or than the tree's
+    // opening, is NotFound; one not written yet reads the current value.
+    pub fn get_at(&self, key: u64, sequence: u64) -> Result<Option<Vector>, LsmError> {
+        let mut writer = self.inner.writer();
+        if sequence >= writer.sequence {
+            return Ok(self.inner.state().get(key, &self.inner.options)?);
+        }
+        writer.history.expire(Duration::from_millis(self.inner.options.history_retention_millis));
+        writer.history.check(sequence)?;
+        match writer.history.value_at(key, sequence) {
+            Some(value) => Ok(value.clone().filter(|value| !value.is_expired(vector::now_millis()))),
+            None => Ok(self.inner.state().get(key, &self.inner.options)?),
+        }
+    }
+
+    // Every live entry as of `sequence` in key order, the way `get_at` reads each key
+    pub fn iter_at(&self, sequence: u64) -> Result<IterAt, LsmError> {
+        Ok(self.iter_at_bounded(Some(sequence), (Bound::Unbounded, Bound::Unbounded), false)?.1)
+    }
+
+    // The entries in `bounds` as of `sequence`, or as of now, and the sequence read at.
+    // A sequence the history no longer reaches is NotFound, or read as of now with
+    // `or_now`.
+    fn iter_at_bounded(&self, sequence: Option<u64>, bounds: (Bound<u64>, Bound<u64>), or_now: bool) -> io::Result<(u64, IterAt)> {
+        let mut writer = self.inner.writer();
+        let mut sequence = sequence.unwrap_or(writer.sequence);
+        if sequence < writer.sequence {
+            writer.history.expire(Duration::from_millis(self.inner.options.history_retention_millis));
+            match writer.history.check(sequence) {
+                Err(_) if or_now => sequence = writer.sequence,
+                checked => checked?,
+            }
+        }
+        let mut changed = match sequence >= writer.sequence {
+            true => BTreeMap::new(),
+            false => writer.history.changed_since(sequence),
+        };
+        changed.retain(|key, _| bounds.contains(key));
+        let current = Iter::bounded(self.inner.state().clone(), self.inner.options.clone(), bounds, None);
+        Ok((sequence, IterAt { current: current.peekable(), changed: changed.into_iter().peekable() }))
+    }
+
+    // Up to `limit` live entries of `range` in key order, and a cursor to pass back for
+    // the next page while there are more. The first page reads the tree as it is; later
+    // ones read it as of then, like `iter_at`, so a write between pages is seen by none
+    // of them, while `Options::history_retention_millis` covers the whole scan. Past
+    // that, as on every write by default, the next page reads the tree as it is from the
+    // cursor's key on, so a write between pages is seen if it lands ahead of the cursor
+    // and missed behind it. Either way each key is returned once. Every page should be
+    // asked for the same range.
+    pub fn scan<R: RangeBounds<u64>>(&self, range: R, limit: usize, cursor: Option<&Cursor>) -> Result<Page, LsmError> {
+        if limit == 0 {
+            return Err(LsmError::InvalidArgument("a page must hold at least one entry".to_string()));
+        }
+        let mut bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        if let Some(cursor) = cursor {
+            let behind = match bounds.0 {
+                Bound::Included(start) => start > cursor.next,
+                Bound::Excluded(start) => start >= cursor.next,
+                Bound::Unbounded => false,
+            };
+            if !behind {
+                bounds.0 = Bound::Included(cursor.next);
+            }
+        }
+        let (sequence, mut entries) = self.iter_at_bounded(cursor.map(|cursor| cursor.sequence), bounds, true)?;
+        let page = entries.by_ref().take(limit).collect::<Result<Vec<_>, _>>()?;
+        let next = entries.next().transpose()?.map(|(next, _)| Cursor { next, sequence });
+        Ok((page, next))
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        // writes apply to the state under the writer lock, so this sequence matches the view
+        let writer = self.inner.writer();
+        let state = self.inner.state().clone();
+        Snapshot { sequence: writer.sequence, options: self.inner.options.clone(), state }
+    }
+
+    // Like `get(key).is_some()`, but answered from the memtables, tombstones and table
+    // indexes without copying or decoding the stored vector
+    pub fn contains_key(&self, key: u64) -> Result<bool, LsmError> {
+        Ok(self.inner.state().contains_key(key, &self.inner.options)?)
+    }
+
+    // Deletes the key, returning whether it was live. A key that isn't is left alone and
+    // nothing is logged. That is answered like `contains_key`, from the newest layer holding
+    // the key, so a missing key costs a few index lookups rather than reading values.
+    pub fn delete(&self, key: u64) -> Result<bool, LsmError> {
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        if !self.inner.state().contains_key(key, &self.inner.options)? {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.inner.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)?;
+        Ok(true)
+    }
+
+    // Deletes the key, returning the value it held, or None without writing anything
+    // when it held none
+    pub fn delete_fetch(&self, key: u64) -> Result<Option<Vector>, LsmError> {
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        let Some(previous) = self.inner.state().get(key, &self.inner.options)? else {
+            return Ok(None);
+        };
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.inner.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)?;
+        Ok(Some(previous))
+    }
+
+    // Deletes every live key of `keys` with a single record, returning for each key
+    // whether it was live, like `delete` does; a key given twice is found only the first
+    // time. Logs nothing when none of them is live.
+    pub fn delete_many(&self, keys: &[u64]) -> Result<Vec<bool>, LsmError> {
+        let _timer = self.inner.time(&self.inner.counters.write_latency);
+        self.inner.stall_writes()?;
+        let mut writer = self.inner.writer();
+        let mut batch = WriteBatch::new();
+        let mut deleted = HashSet::new();
+        let found: Vec<bool> = {
+            let state = self.inner.state();
+            keys.iter().map(|&key| {
+                let live = !deleted.contains(&key) && state.contains_key(key, &self.inner.options)?;
+                if live {
+                    deleted.insert(key);
+                    batch.delete(key);
+                }
+                Ok(live)
+            }).collect::<io::Result<_>>()?
+        };
+        if batch.is_empty() {
+            return Ok(found);
+        }
+        self.inner.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.inner.wait_synced(writer, sequence)?;
+        Ok(found)
+    }
+
+    // Deletes every key in [start, end) with a single record; the covered entries are
+    // dropped for good once compaction reaches the oldest table. A range ending before it
+    // starts is InvalidArgument, and an empty one writes nothing.
+    pub fn delete_range(&self, start: u64, end: u64) -> Result<(), LsmError> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(start, end);
+        self.write(batch)
+    }
+
+    pub fn pipeline(&self) -> Pipeline {
+        self.inner.state().pipeline.clone()
+    }
+
+    // The pipeline can only be changed while the tree is empty, otherwise stored
+    // vectors and queries would go through different transforms
+    pub fn set_pipeline(&self, pipeline: Pipeline) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let mut writer = self.inner.writer();
+        let mut state = self.inner.state_mut();
+        if !state.is_empty() {
+            return Err(LsmError::InvalidArgument("cannot change the pipeline of a non-empty tree".to_string()));
+        }
+        writer.manifest.log(&[VersionEdit::SetPipeline(pipeline.clone())])?;
+        state.pipeline = pipeline;
+        Ok(())
+    }
+
+    // Runs a query vector through the same pipeline as stored vectors
+    pub fn prepare_query(&self, query: &[f64]) -> Result<Vec<f64>, LsmError> {
+        let state = self.inner.state();
+        if let Some(dimension) = state.dimension.filter(|&dimension| dimension != query.len()) {
+            return Err(LsmError::InvalidArgument(format!("query has {} dimensions, the tree's dimension is {}", query.len(), dimension)));
+        }
+        Ok(state.pipeline.apply(query)?)
+    }
+
+    pub fn metric(&self) -> DistanceMetric {
+        self.inner.state().metric
+    }
+
+    // Like the pipeline, the metric can only be changed while the tree is empty. The
+    // indexes are rebuilt to link and partition vectors by the new metric.
+    pub fn set_metric(&self, metric: DistanceMetric) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let mut writer = self.inner.writer();
+        let mut state = self.inner.state_mut();
+        if !state.is_empty() {
+            return Err(LsmError::InvalidArgument("cannot change the metric of a non-empty tree".to_string()));
+        }
+        writer.manifest.log(&[VersionEdit::SetMetric(metric)])?;
+        state.metric = metric;
+        let state = state.clone();
+        let options = &self.inner.options;
+        if let (Some(index), Some(hnsw_options)) = (&self.inner.index, options.hnsw) {
+            self.inner.drain_index_queue();
+            *index.write().unwrap() = build_index(hnsw_options, &state, options)?;
+        }
+        if let Some(centroids) = self.inner.ivf.read().unwrap().as_ref().map(|ivf| ivf.centroids().to_vec()) {
+            *self.inner.ivf.write().unwrap() = Some(build_ivf(centroids, &state, options)?);
+        }
+        drop(writer);
+        Ok(())
+    }
+
+    // The metrics `knn_by` may rank by besides the tree's own
+    pub fn query_metrics(&self) -> Vec<DistanceMetric> {
+        self.inner.state().query_metrics.clone()
+    }
+
+    // Declares which other metrics rank the stored vectors meaningfully, so `knn_by` can
+    // use them, replacing any declared before. Whether one does depends on the data, e.g.
+    // inner product ranks vectors the caller knows to be unit length like cosine does,
+    // so the tree takes the caller's word for it. A tree whose pipeline normalizes
+    // already takes L2, cosine and inner product alike. Kept across opens.
+    pub fn set_query_metrics(&self, metrics: &[DistanceMetric]) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let mut writer = self.inner.writer();
+        writer.manifest.log(&[VersionEdit::SetQueryMetrics(metrics.to_vec())])?;
+        self.inner.state_mut().query_metrics = metrics.to_vec();
+        Ok(())
+    }
+
+    pub fn element_type(&self) -> ElementType {
+        self.inner.state().element_type
+    }
+
+    // How vector data is stored on disk, see `ElementType`. Like the metric, it can only
+    // be changed while the tree is empty.
+    pub fn set_element_type(&self, element_type: ElementType) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let mut writer = self.inner.writer();
+        let mut state = self.inner.state_mut();
+        if !state.is_empty() {
+            return Err(LsmError::InvalidArgument("cannot change the element type of a non-empty tree".to_string()));
+        }
+        writer.manifest.log(&[VersionEdit::SetElementType(element_type)])?;
+        state.element_type = element_type;
+        Ok(())
+    }
+
+    // None until the dimension is set, or taken from the first insert with
+    // `Options::fix_dimension`
+    pub fn dimension(&self) -> Option<usize> {
+        self.inner.state().dimension
+    }
+
+    // Refuses every later insert and query without exactly `dimension` components, as
+    // they come in before the pipeline. Like the metric, it can only be set while the
+    // tree is empty, and it stays set across opens.
+    pub fn set_dimension(&self, dimension: usize) -> Result<(), LsmError> {
+        if dimension == 0 {
+            return Err(LsmError::InvalidArgument("dimension must be at least 1".to_string()));
+        }
+        self.inner.check_writable()?;
+        let mut writer = self.inner.writer();
+        let mut state = self.inner.state_mut();
+        if !state.is_empty() {
+            return Err(LsmError::InvalidArgument("cannot change the dimension of a non-empty tree".to_string()));
+        }
+        writer.manifest.log(&[VersionEdit::SetDimension(dimension as u64)])?;
+        state.dimension = Some(dimension);
+        Ok(())
+    }
+
+    // Whether the pipeline scales every stored vector and query to unit length, which
+    // searches by cosine distance take advantage of
+    pub fn is_normalized(&self) -> bool {
+        self.inner.state().pipeline.normalizes()
+    }
+
+    // The preprocessed query and how to rank by the tree's metric, if the metric can
+    // compare it
+    fn search_query(&self, query: &[f64]) -> io::Result<(Vec<f64>, Scorer)> {
+        self.search_query_by(query, self.metric())
+    }
+
+    // Like `search_query` for a metric the query asks for, which the tree must take
+    fn search_query_by(&self, query: &[f64], metric: DistanceMetric) -> io::Result<(Vec<f64>, Scorer)> {
+        self.inner.state().check_query_metric(metric)?;
+        let query = self.prepare_query(query)?;
+        metric.validate_query(&query)?;
+        Ok((query, Scorer::new(metric, self.is_normalized())))
+    }
+
+    // The `k` stored vectors closest to `query` by the tree's metric, closest first.
+    // Scans every live vector; the query goes through the pipeline like stored vectors.
+    // Tables with column blocks, see `Options::columnar_block_vectors`, are ranked from
+    // those, and the packed bits of a binary tree's tables are compared where they lie.
+    pub fn knn(&self, query: &[f64], k: usize) -> Result<Vec<(u64, f64)>, LsmError> {
+        self.knn_by(query, k, self.metric())
+    }
+
+    // `knn` ranking by `metric` rather than the tree's own, for the metrics
+    // `set_query_metrics` declared. Any other is InvalidArgument, since it would rank the
+    // vectors without anything saying the ranking means something.
+    pub fn knn_by(&self, query: &[f64], k: usize, metric: DistanceMetric) -> Result<Vec<(u64, f64)>, LsmError> {
+        let _timer = self.inner.time(&self.inner.counters.search_latency);
+        let (query, scorer) = self.search_query_by(query, metric)?;
+        let state = self.inner.state().clone();
+        Counters::add(&self.inner.counters.searches, 1);
+        if state.sstables.iter().any(|t| t.has_columns()) || state.element_type == ElementType::Binary {
+            return Ok(state.knn(&query, k, &scorer, &self.inner.options)?.into_sorted());
+        }
+        drop(state);
+        let mut top = TopK::new(k);
+        for entry in self.iter() {
+            let (key, value) = entry?;
+            if value.data().len() != query.len() {
+                return Err(LsmError::InvalidArgument(format!("query has {} dimensions, key '{}' has {}", query.len(), key, value.data().len())));
+            }
+            top.push(key, scorer.distance(&query, value.data()));
+        }
+        Ok(top.into_sorted())
+    }
+
+    // Exact `knn` for many queries over one scan of the tree: entries are read once, a
+    // chunk at a time, and every chunk is handed to `search_threads` threads that each
+    // rank it against their share of the queries. Results are in query order.
+    pub fn search_batch(&self, queries: &[Vec<f64>], k: usize) -> Result<Vec<Vec<(u64, f64)>>, LsmError> {
+        let mut prepared = Vec::with_capacity(queries.len());
+        let mut scorer = None;
+        for query in queries {
+            let (query, query_scorer) = self.search_query(query)?;
+            prepared.push(query);
+            scorer = Some(query_scorer);
+        }
+        let Some(scorer) = scorer else {
+            return Ok(Vec::new());
+        };
+        Counters::add(&self.inner.counters.searches, prepared.len() as u64);
+
+        let options = &self.inner.options;
+        let threads = options.search_threads.min(prepared.len());
+        let share = prepared.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(threads);
+            let mut handles = Vec::with_capacity(threads);
+            for (i, queries) in prepared.chunks(share).enumerate() {
+                let (sender, receiver) = std::sync::mpsc::sync_channel::<Arc<Vec<(u64, Vector)>>>(2);
+                senders.push(sender);
+                handles.push(std::thread::Builder::new()
+                    .name(format!("{}-search-{}", options.thread_name_prefix, i))
+                    .spawn_scoped(scope, move || rank_chunks(queries, k, scorer, receiver))?);
+            }
+
+            let mut chunk = Vec::with_capacity(SEARCH_BATCH_CHUNK);
+            let mut scanned = Ok(());
+            for entry in self.iter() {
+                match entry {
+                    Ok(entry) => chunk.push(entry),
+                    Err(e) => {
+                        scanned = Err(e);
+                        break;
+                    }
+                }
+                if chunk.len() == SEARCH_BATCH_CHUNK {
+                    let full = Arc::new(std::mem::replace(&mut chunk, Vec::with_capacity(SEARCH_BATCH_CHUNK)));
+                    // a thread that hung up has failed, which joining it reports
+                    senders.retain(|sender| sender.send(full.clone()).is_ok());
+                }
+            }
+            if !chunk.is_empty() {
+                let last = Arc::new(chunk);
+                for sender in senders.iter() {
+                    let _ = sender.send(last.clone());
+                }
+            }
+            drop(senders);
+
+            let mut results = Vec::with_capacity(prepared.len());
+            for handle in handles {
+                let ranked = handle.join().unwrap_or_else(|_| Err(io::Error::other("search thread panicked")))?;
+                results.extend(ranked.into_iter().map(TopK::into_sorted));
+            }
+            scanned?;
+            Ok(results)
+        })
+    }
+
+    // Approximate `knn` through the HNSW index: `ef_search` candidates are gathered from
+    // the graph over the projected vectors, then ranked by their distance to the
+    // unprojected query. A larger `ef_search` finds more of the true neighbors.
+    pub fn search(&self, query: &[f64], k: usize, ef_search: usize) -> Result<Vec<(u64, f64)>, LsmError> {
+        self.search_lagging(query, k, ef_search).map(|(found, _)| found)
+    }
+
+    // `search`, also returning how many of the newest updates to the graph it searched
+    // were still queued for the indexer, see `Options::index_queue`. Vectors written by
+    // those may be missing from the results, or removed ones still there until `get`
+    // rules them out. Always 0 without the queue.
+    pub fn search_lagging(&self, query: &[f64], k: usize, ef_search: usize) -> Result<(Vec<(u64, f64)>, u64), LsmError> {
+        let _timer = self.inner.time(&self.inner.counters.search_latency);
+        let Some(index) = &self.inner.index else {
+            return Err(LsmError::InvalidArgument("search requires an HNSW index in Options".to_string()));
+        };
+        let (query, scorer) = self.search_query(query)?;
+        let projected = self.project(&query)?;
+        let (candidates, missing) = {
+            let index = index.read().unwrap();
+            let missing = self.inner.index_queue.as_ref().map_or(0, |queue| queue.missing());
+            (index.search(&projected, ef_search.max(k), ef_search), missing)
+        };
+
+        let mut top = TopK::new(k);
+        for (key, _) in candidates {
+            // expired entries are still in the graph
+            if let Some(value) = self.get(key)? {
+                top.push(key, scorer.distance(&query, value.data()));
+            }
+        }
+        Ok((self.count_approximate(top.into_sorted(), k), missing))
+    }
+
+    // Returns once the graph holds every write through `sequence`, such as one read
+    // from `sequence()` after writing, so a `search` sees them. Returns at once without
+    // `Options::index_queue`, and fails if the indexer did.
+    pub fn wait_for_index(&self, sequence: u64) -> Result<(), LsmError> {
+        match &self.inner.index_queue {
+            Some(queue) => Ok(queue.wait(sequence)?),
+            None => Ok(()),
+        }
+    }
+
+    // Counts an approximate search, see `Stats::short_searches`, returning its results
+    fn count_approximate(&self, found: Vec<(u64, f64)>, k: usize) -> Vec<(u64, f64)> {
+        let counters = &self.inner.counters;
+        Counters::add(&counters.searches, 1);
+        Counters::add(&counters.approximate_searches, 1);
+        if found.len() < k && self.approximate_len() >= k {
+            Counters::add(&counters.short_searches, 1);
+        }
+        found
+    }
+
+    // How the HNSW graph is holding up: its layers, how well linked its nodes are, and
+    // how many of them searches can no longer reach. None without an index.
+    pub fn graph_diagnostics(&self) -> Option<GraphDiagnostics> {
+        self.inner.index.as_ref().map(|index| index.read().unwrap().diagnostics())
+    }
+
+    // Links the nodes `graph_diagnostics` counts as unreachable back into the graph,
+    // returning how many there were. The repaired graph is saved at the next close.
+    pub fn repair_graph(&self) -> Result<usize, LsmError> {
+        self.inner.check_writable()?;
+        let Some(index) = &self.inner.index else {
+            return Err(LsmError::InvalidArgument("repair_graph requires an HNSW index in Options".to_string()));
+        };
+        Ok(index.write().unwrap().repair())
+    }
+
+    // The `k` closest vectors whose metadata matches `filter`. The filter is applied while
+    // candidates are gathered, not to the results, so a selective filter still returns
+    // `k` hits when that many match. Where the filter tests fields of
+    // `Options::indexed_fields`, only the vectors their indexes pick out are compared,
+    // unless there are more of them than the graph would gather. With an HNSW index the
+    // graph walk skips over non-matching vectors, gathering `ef_construction` candidates;
+    // otherwise every matching vector is compared.
+    pub fn search_filtered(&self, query: &[f64], k: usize, filter: &Filter) -> Result<Vec<(u64, f64)>, LsmError> {
+        let _timer = self.inner.time(&self.inner.counters.search_latency);
+        let (query, scorer) = self.search_query(query)?;
+        let options = &self.inner.options;
+        let rank = |top: &mut TopK, key: u64, value: &Vector| {
+            if !filter.matches(value.metadata()) {
+                return Ok(());
+            }
+            if value.data().len() != query.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("query has {} dimensions, key '{}' has {}", query.len(), key, value.data().len())));
+            }
+            top.push(key, scorer.distance(&query, value.data()));
+            Ok(())
+        };
+        let ef = options.hnsw.map(|hnsw_options| hnsw_options.ef_construction.max(k));
+        let indexed = match &self.inner.fields {
+            Some(fields) => fields.read().unwrap().candidates(filter),
+            None => None,
+        };
+        Counters::add(&self.inner.counters.searches, 1);
+        if let Some(candidates) = indexed.filter(|keys| self.inner.index.is_none() || ef.is_some_and(|ef| keys.len() <= ef)) {
+            let state = self.inner.state();
+            let mut top = TopK::new(k);
+            for key in candidates {
+                if let Some(value) = state.get(key, options)? {
+                    rank(&mut top, key, &value)?;
+                }
+            }
+            return Ok(top.into_sorted());
+        }
+        let (Some(index), Some(ef)) = (&self.inner.index, ef) else {
+            let mut top = TopK::new(k);
+            for entry in self.iter() {
+                let (key, value) = entry?;
+                rank(&mut top, key, &value)?;
+            }
+            return Ok(top.into_sorted());
+        };
+
+        // a copy of the state, so no state lock is taken under the index lock
+        let state = self.inner.state().clone();
+        let projected = self.project(&query)?;
+        let candidates = index.read().unwrap().search_filtered(&projected, ef, ef, |key| {
+            // a failed read passes, for the read below to report
+            state.get(key, options).map_or(true, |value| value.is_some_and(|value| filter.matches(value.metadata())))
+        });
+        // fewer than k may be all the filter matches, so these aren't counted short
+        Counters::add(&self.inner.counters.approximate_searches, 1);
+
+        let mut top = TopK::new(k);
+        for (key, _) in candidates {
+            if let Some(value) = state.get(key, options)?
+                && filter.matches(value.metadata())
+            {
+                top.push(key, scorer.distance(&query, value.data()));
+            }
+        }
+        Ok(top.into_sorted())
+    }
+
+    // Approximate `knn` through the IVF index: only the vectors filed under the `nprobe`
+    // centroids closest to the query are compared to it
+    pub fn ivf_search(&self, query: &[f64], nprobe: usize, k: usize) -> Result<Vec<(u64, f64)>, LsmError> {
+        let (query, scorer) = self.search_query(query)?;
+        let candidates = match self.inner.ivf.read().unwrap().as_ref() {
+            Some(ivf) => ivf.candidates(&query, nprobe),
+            None => return Err(LsmError::InvalidArgument("ivf_search requires an index trained with train_ivf".to_string())),
+        };
+
+        let mut top = TopK::new(k);
+        for key in candidates {
+            if let Some(value) = self.get(key)? {
+                top.push(key, scorer.distance(&query, value.data()));
+            }
+        }
+        Ok(self.count_approximate(top.into_sorted(), k))
+    }
+
+    // Clusters a sample, preprocessed like stored vectors, into `nlist` IVF partitions
+    // and files every stored vector under its closest centroid. Writes wait until the
+    // partitions are filled; training again replaces them.
+    pub fn train_ivf(&self, sample: &[Vec<f64>], nlist: usize) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let pipeline = self.pipeline();
+        let sample = sample.iter().map(|v| pipeline.apply(v)).collect::<io::Result<Vec<_>>>()?;
+        let centroids = ivf::train_kmeans(&sample, nlist)?;
+
+        let mut writer = self.inner.writer();
+        writer.manifest.log(&[VersionEdit::SetCentroids(centroids.clone())])?;
+        let state = self.inner.state().clone();
+        *self.inner.ivf.write().unwrap() = Some(build_ivf(centroids, &state, &self.inner.options)?);
+        drop(writer);
+        Ok(())
+    }
+
+    // Approximate `knn` over product quantization codes of the vectors: the `rerank`
+    // closest codes by asymmetric distance are ranked again by their full-precision
+    // distance, read from the entries. Tables written with `Options::pq_codes` are scanned
+    // by the codes they store; memtables and other tables are encoded as they are read.
+    pub fn pq_search(&self, query: &[f64], k: usize, rerank: usize) -> Result<Vec<(u64, f64)>, LsmError> {
+        let (query, scorer) = self.search_query(query)?;
+        let Some(quantizer) = self.inner.pq.read().unwrap().clone() else {
+            return Err(LsmError::InvalidArgument("pq_search requires codebooks trained with train_pq".to_string()));
+        };
+        let codes = CodeScorer::new(&quantizer, self.metric(), &query)?;
+        let state = self.inner.state().clone();
+        let candidates = state.pq_candidates(&quantizer, &codes, rerank.max(k), &self.inner.options)?;
+
+        let mut top = TopK::new(k);
+        for (key, _) in candidates.into_sorted() {
+            if let Some(value) = state.get(key, &self.inner.options)?.filter(|value| value.data().len() == query.len()) {
+                top.push(key, scorer.distance(&query, value.data()));
+            }
+        }
+        Ok(self.count_approximate(top.into_sorted(), k))
+    }
+
+    // Trains product quantization codebooks on a sample, preprocessed like stored
+    // vectors, for `pq_search`, and keeps them in the manifest. With `Options::pq_codes`,
+    // tables written from then on store codes under them, and `compact` rewrites the
+    // older ones. Training again replaces the codebooks; codes stored under the old ones
+    // are ignored.
+    pub fn train_pq(&self, sample: &[Vec<f64>], subspaces: usize, centroids: usize) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let pipeline = self.pipeline();
+        let sample = sample.iter().map(|v| pipeline.apply(v)).collect::<io::Result<Vec<_>>>()?;
+        let quantizer = ProductQuantizer::train(&sample, subspaces, centroids)?;
+
+        let mut writer = self.inner.writer();
+        writer.manifest.log(&[VersionEdit::SetQuantizer(quantizer.clone())])?;
+        *self.inner.pq.write().unwrap() = Some(Arc::new(quantizer));
+        drop(writer);
+        Ok(())
+    }
+
+    // Trains a PCA projection used to reduce vectors for indexing. Stored vectors
+    // keep their full dimensionality, the projection is applied on top of the pipeline.
+    pub fn train_projection(&self, sample: &[Vec<f64>], components: usize) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let pipeline = self.pipeline();
+        let sample = sample.iter().map(|v| pipeline.apply(v)).collect::<io::Result<Vec<_>>>()?;
+        let options = &self.inner.options;
+        let thread_name = format!("{}-index", options.thread_name_prefix);
+        let transform = pca::train_pca_parallel(&sample, components, options.index_build_threads, &thread_name)?;
+        let projection = Pipeline::new(vec![transform])?;
+
+        let mut writer = self.inner.writer();
+        writer.manifest.log(&[VersionEdit::SetProjection(projection.clone())])?;
+        self.inner.state_mut().projection = projection;
+        // the graph links projected vectors, so it starts over in the new space
+        if let (Some(index), Some(hnsw_options)) = (&self.inner.index, options.hnsw) {
+            // queued updates hold vectors projected the old way
+            self.inner.drain_index_queue();
+            let rebuilt = build_index(hnsw_options, &self.inner.state(), options)?;
+            *index.write().unwrap() = rebuilt;
+        }
+        drop(writer);
+        Ok(())
+    }
+
+    pub fn projection(&self) -> Pipeline {
+        self.inner.state().projection.clone()
+    }
+
+    // Reduces an already preprocessed vector with the trained projection, if any
+    pub fn project(&self, data: &[f64]) -> Result<Vec<f64>, LsmError> {
+        Ok(self.inner.state().projection.apply(data)?)
+    }
+
+    // Loads entries given in any order using bounded memory: they are sorted externally
+    // and written straight to new SSTables, bypassing the WAL and memtable. The loaded
+    // entries are newer than everything written before the call; writes that race with
+    // the load win. Returns the number of distinct keys loaded.
+    pub fn bulk_load<I: IntoIterator<Item = (u64, Vector)>>(&self, entries: I) -> Result<usize, LsmError> {
+        Ok(self.try_bulk_load(entries.into_iter().map(Ok))?)
+    }
+
+    // Like `bulk_load`, failing without loading anything on the first entry that is an error
+    fn try_bulk_load<I: IntoIterator<Item = io::Result<(u64, Vector)>>>(&self, entries: I) -> io::Result<usize> {
+        self.inner.check_writable()?;
+        let pipeline = self.pipeline();
+        let element_type = self.element_type();
+        let mut dimension = self.dimension();
+        let run_size = self.inner.options.bulk_run_size.max(1);
+        let mut sorter = ExternalSorter::new(&self.inner.directory, run_size);
+        for entry in entries {
+            let (key, value) = entry?;
+            check_limits(&self.inner.options, key, &value)?;
+            // like an insert, the first vector decides with fix_dimension
+            if dimension.is_none() && self.inner.options.fix_dimension {
+                dimension = Some(value.data().len());
+            }
+            check_dimension(dimension, key, &value)?;
+            sorter.add(key, preprocess(&pipeline, element_type, value)?)?;
+        }
+        self.flush()?;
+
+        let mut tables = Vec::new();
+        let mut chunk = BTreeMap::new();
+        let mut loaded = 0;
+        let projection = self.projection();
+        let mut updates = Vec::new();
+        for entry in sorter.finish()? {
+            let (key, value) = entry?;
+            if self.inner.has_index() {
+                let projected = self.inner.index.as_ref().and_then(|_| projection.apply(value.data()).ok());
+                updates.push(IndexUpdate::Insert(key, value.clone(), projected));
+            }
+            chunk.insert(key, value);
+            loaded += 1;
+            if chunk.len() >= run_size {
+                tables.push(self.inner.write_new_sstable(&std::mem::take(&mut chunk))?);
+            }
+        }
+        if !chunk.is_empty() {
+            tables.push(self.inner.write_new_sstable(&chunk)?);
+        }
+        self.inner.install_tables(tables, dimension, updates)?;
+        Ok(loaded)
+    }
+
+    // Adds a table built by `SSTableWriter` as the newest, without its entries passing
+    // through the WAL or memtable. The file is checked in full first and copied in, and
+    // is left where it was. Like `bulk_load`, its entries are newer than everything
+    // written before the call, but they go in as stored, without the tree's pipeline.
+    // Returns the number of entries ingested.
+    pub fn ingest_external_file(&self, path: &Path) -> Result<usize, LsmError> {
+        self.inner.check_writable()?;
+        let external = SSTable::open(path, 0, self.inner.options.read_path)?;
+        if let Some(error) = external.verify().into_iter().next() {
+            return Err(LsmError::Corruption { file: Some(path.display().to_string()), offset: None, message: error.to_string() });
+        }
+        if external.is_empty() {
+            return Ok(0);
+        }
+        let projection = self.projection();
+        let element_type = self.element_type();
+        let mut updates = Vec::new();
+        for entry in external.entries(..) {
+            let (key, value) = external.read_value(entry?.1)?;
+            check_limits(&self.inner.options, key, &value)?;
+            if !element_type.is_exact(value.data()) {
+                return Err(LsmError::InvalidArgument(format!("vector for key {} doesn't fit the tree's element type {:?}", key, element_type)));
+            }
+            if self.inner.has_index() {
+                let projected = self.inner.index.as_ref().and_then(|_| projection.apply(value.data()).ok());
+                updates.push(IndexUpdate::Insert(key, value, projected));
+            }
+        }
+        self.flush()?;
+
+        let file_number = self.inner.writer().manifest.new_file_number();
+        let table_path = self.inner.directory.join(manifest::table_file_name(file_number));
+        let temp_path = table_path.with_extension(TEMP_EXTENSION);
+        match &self.inner.options.storage {
+            Some(storage) => self.inner.store_table(storage.as_ref(), file_number, true, |mut out| out.write_all(&std::fs::read(path)?))?,
+            None => {
+                std::fs::copy(path, &temp_path)?;
+                File::open(&temp_path)?.sync_all()?;
+            }
+        }
+        if self.inner.options.storage.is_none() {
+            std::fs::rename(&temp_path, &table_path)?;
+            sync_dir(&self.inner.directory)?;
+        }
+        let table = self.inner.open_new_table(file_number, Placement::Local)?;
+        self.inner.install_tables(vec![table], None, updates)?;
+        Ok(external.len())
+    }
+
+    // Writes every live record to `path` in key order, as of when the call started.
+    // Returns the number of records written.
+    pub fn export(&self, path: &Path, format: ExportFormat) -> Result<usize, LsmError> {
+        let mut file = File::create(path)?;
+        let mut out = BufWriter::new(&mut file);
+        export::write_header(&mut out, format)?;
+        let mut exported = 0;
+        for entry in self.iter() {
+            let (key, value) = entry?;
+            export::write_record(&mut out, format, key, &value)?;
+            exported += 1;
+        }
+        out.flush()?;
+        drop(out);
+        file.sync_all()?;
+        Ok(exported)
+    }
+
+    // Bulk loads the records of a file written by `export`, or by other tools in the same
+    // format. A malformed line fails the import before anything is loaded.
+    pub fn import(&self, path: &Path, format: ExportFormat) -> Result<usize, LsmError> {
+        Ok(self.try_bulk_load(export::read_records(BufReader::new(File::open(path)?), format))?)
+    }
+
+    // Bulk loads every vector of a .npy or .fvecs file, told apart by extension, reading
+    // the memory mapped file one vector at a time
+    pub fn import_embeddings(&self, path: &Path, ids: EmbeddingIds) -> Result<usize, LsmError> {
+        let format = EmbeddingFormat::from_path(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is neither a .npy nor a .fvecs file", path.display())))?;
+        let file = EmbeddingFile::open(path, format)?;
+        let ids = match ids {
+            EmbeddingIds::Sequential(first) => match first.checked_add(file.len() as u64) {
+                Some(_) => (first..first + file.len() as u64).collect(),
+                None => return Err(LsmError::InvalidArgument("ids would run past u64::MAX".to_string())),
+            },
+            EmbeddingIds::Npy(path) => embeddings::read_ids(&path)?,
+        };
+        if ids.len() != file.len() {
+            return Err(LsmError::InvalidArgument(format!("{} ids for {} vectors", ids.len(), file.len())));
+        }
+        Ok(self.try_bulk_load(ids.into_iter().enumerate().map(|(i, id)| Ok((id, Vector::new(id, file.get(i)?)))))?)
+    }
+
+    // Freezes the memtable and waits until it and every earlier frozen memtable
+    // have been written out as SSTables
+    pub fn flush(&self) -> Result<(), LsmError> {
+        self.inner.check_writable()?;
+        let _timer = self.inner.time(&self.inner.counters.flush_latency);
+        let mut writer = self.inner.writer();
+        self.inner.freeze(&mut writer)?;
+        drop(writer);
+        Ok(self.inner.wait_for_flushes()?)
+    }
+
+    // The caps on writes, see `Options::write_bytes_per_sec`: bytes, then operations per
+    // second, 0 for none
+    pub fn write_rate_limit(&self) -> (u64, u64) {
+        (self.inner.write_bytes_limiter.rate(), self.inner.write_ops_limiter.rate())
+    }
+
+    // Writes from now on are paced by the new caps; one already sleeping off its share
+    // finishes at the old ones
+    pub fn set_write_rate_limit(&self, bytes_per_sec: u64, ops_per_sec: u64) {
+        self.inner.write_bytes_limiter.set_rate(bytes_per_sec);
+        self.inner.write_ops_limiter.set_rate(ops_per_sec);
+    }
+
+    // The cap on compaction bytes per second, see `Options::compaction_bytes_per_sec`
+    pub fn compaction_rate_limit(&self) -> u64 {
+        self.inner.rate_limiter.rate()
+    }
+
+    // Compactions are paced by the new cap from their next chunk of reads or writes on
+    pub fn set_compaction_rate_limit(&self, bytes_per_sec: u64) {
+        self.inner.rate_limiter.set_rate(bytes_per_sec);
+    }
+
+    // Brings the tree in line with `config`, say after a server's configuration file was
+    // edited: the rate limits change at once, see `Config::is_runtime_key`, and the other
+    // keys set to something else than the tree was opened with are reported as waiting
+    // for it to be reopened. Keys the configuration leaves out keep their values.
+    pub fn reload_config(&self, config: &Config) -> ConfigReload {
+        let mut running = self.inner.options.clone();
+        (running.write_bytes_per_sec, running.write_ops_per_sec) = self.write_rate_limit();
+        running.compaction_bytes_per_sec = self.compaction_rate_limit();
+        let mut wanted = running.clone();
+        config.apply(&mut wanted);
+
+        let mut reload = ConfigReload::default();
+        for key in config.keys() {
+            if config::get(&wanted, key) == config::get(&running, key) {
+                continue;
+            }
+            match Config::is_runtime_key(key) {
+                true => reload.applied.push(key.to_string()),
+                false => reload.needs_restart.push(key.to_string()),
+            }
+        }
+        self.set_write_rate_limit(wanted.write_bytes_per_sec, wanted.write_ops_per_sec);
+        self.set_compaction_rate_limit(wanted.compaction_bytes_per_sec);
+        reload
+    }
+
+    pub fn compaction_style(&self) -> CompactionStyle {
+        self.inner.background().style
+    }
+
+    // Compactions from now on pick their inputs by `style`; one already running finishes
+    // as it was picked
+    pub fn set_compaction_style(&self, style: CompactionStyle) -> Result<(), LsmError> {
+        check_compaction_style(style)?;
+        self.inner.background().style = style;
+        self.inner.job_requested.notify_all();
+        Ok(())
+    }
+
+    // Flushes, then merges every SSTable into one on the calling thread, once the
+    // compactions already running have finished
+    pub fn compact(&self) -> Result<(), LsmError> {
+        self.flush()?;
+        Ok(self.inner.compact_all()?)
+    }
+
+    // Flushes, then merges the SSTables holding keys or deletes in `start..end`, and the
+    // ones between them, into one. Reaching down to the oldest table also drops the
+    // deleted and expired entries for good; tables past either end are left alone.
+    pub fn compact_range(&self, start: u64, end: u64) -> Result<(), LsmError> {
+        self.flush()?;
+        Ok(self.inner.compact_range(start..end)?)
+    }
+
+    // Writes a copy of the tree into `destination`, which must not exist yet, that opens
+    // as a tree of its own. Writes go on meanwhile: the copy holds the tables and logs as
+    // of one point, with tables hard linked where the filesystem allows and copied
+    // otherwise. Nothing is flushed first, the memtables are carried over in their logs,
+    // so taking one costs about as much as linking the tables, copying the unflushed logs
+    // and writing out the HNSW graph if there is one. The manifest goes in last, so a
+    // checkpoint cut short has none.
+    pub fn checkpoint(&self, destination: &Path) -> Result<(), LsmError> {
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::create_dir(destination)?;
+
+        // under the writer lock no table is installed, log rotated or record appended, so
+        // the tables, manifest and the logs' current lengths all agree
+        let mut writer = self.inner.writer();
+        let sstables = self.inner.state().sstables.clone();
+        // cold tables are copied in, so the checkpoint holds them all in its directory. The
+        // field tables, which a merge may delete before they are linked, give way to one
+        // of what the field indexes file as of the logs' lengths.
+        let mut edits = writer.manifest.snapshot();
+        edits.retain(|edit| !matches!(edit, VersionEdit::ColdTable(_) | VersionEdit::AddFieldTable(_)));
+        let field_table = self.inner.fields.as_ref().map(|fields| (writer.manifest.new_file_number(), fields.read().unwrap().entries()));
+        let mut wals = Vec::new();
+        for number in wal::list_wals(&self.inner.directory)? {
+            if number >= writer.manifest.log_number() {
+                let file = File::open(self.inner.directory.join(wal::wal_file_name(number)))?;
+                let len = file.metadata()?.len();
+                wals.push((number, file, len));
+            }
+        }
+        drop(writer);
+
+        // a table compacted away since keeps its bytes readable through its handle. Tables
+        // from the cold directory go in with the rest.
+        for sstable in &sstables {
+            let name = manifest::table_file_name(sstable.file_number);
+            let source = table_path(&self.inner.directory, &self.inner.options, sstable.file_number, Placement::of(sstable));
+            if std::fs::hard_link(source, destination.join(&name)).is_err() {
+                sstable.copy_to(&destination.join(&name))?;
+            }
+        }
+        // logs are copied rather than linked, the copy appends to its newest one once
+        // opened. A log deleted since is still read through its handle, and records
+        // appended since are left out.
+        for (number, file, len) in wals {
+            let mut copy = File::create(destination.join(wal::wal_file_name(number)))?;
+            if io::copy(&mut file.take(len), &mut copy)? != len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("log {} shrank while being copied", number)).into());
+            }
+            copy.sync_all()?;
+        }
+        // the graph is taken as writes go on, so the copy checks it against every vector
+        // it holds when opened, which costs far less than building it again
+        if let Some(index) = &self.inner.index {
+            self.inner.write_index(&index.read().unwrap(), destination, &Watermark::unknown())?;
+        }
+        if let Some((number, entries)) = field_table {
+            write_field_table(destination, number, &entries, true)?;
+            edits.push(VersionEdit::AddFieldTable(number));
+        }
+        manifest::write_new(destination, &edits)?;
+        Ok(sync_dir(destination)?)
+    }
+
+    // Reads every live table end to end, checking what `Options::paranoid_checks` checks
+    // on each read plus the index's order and the entries' layout. Writes go on meanwhile.
+    pub fn verify(&self) -> Result<VerifyReport, LsmError> {
+        let sstables = self.inner.state().sstables.clone();
+        let tables = sstables.iter().map(|table| TableReport {
+            file_number: table.file_number,
+            file_size: table.file_size(),
+            entries: table.len(),
+            errors: table.verify(),
+            ..TableReport::default()
+        });
+        Ok(VerifyReport { tables: tables.collect() })
+    }
+
+    // Verifies, then replaces each damaged table with one holding the entries of it that
+    // can still be read. What couldn't be read is lost; the report counts it.
+    pub fn repair(&self) -> Result<VerifyReport, LsmError> {
+        self.inner.check_writable()?;
+        let mut report = self.verify()?;
+        for table in report.tables.iter_mut().filter(|table| !table.errors.is_empty()) {
+            if let Some((file_number, salvaged)) = self.inner.salvage(table.file_number)? {
+                table.repaired_as = Some(file_number);
+                table.lost_entries = table.entries - salvaged;
+            }
+        }
+        Ok(report)
+    }
+
+    // Refuses every write from now on, failing them with NotFound, and runs `then` once the
+    // last handle to the tree has been dropped and its workers stopped, for the owner to
+    // delete its directory. Nothing is flushed or saved at that point. Readers may go on
+    // until then.
+    pub(crate) fn retire(&self, then: impl FnOnce() + Send + 'static) {
+        // no write is half done under the writer lock
+        let _writer = self.inner.writer();
+        self.inner.retired.store(true, Ordering::Relaxed);
+        *self.inner.on_retired.lock().unwrap() = Some(Box::new(then));
+    }
+
+    // Flushes the memtable, syncs the WAL and stops the background workers once they have
+    // written every frozen memtable out, reporting the first thing that failed: the flush,
+    // a background job or saving the index. Dropping the tree does the same but has to
+    // discard the error.
+    pub fn close(mut self) -> Result<(), LsmError> {
+        let flushed = self.flush_for_close();
+        self.stop_workers();
+        let saved = self.inner.save_index();
+        flushed.and(saved)?;
+        Ok(self.inner.check_background_error()?)
+    }
+
+    // Leaves nothing for the next open to replay. A tree closed after an earlier attempt
+    // failed still tries, so the memtable isn't given up on while it may yet be written.
+    fn flush_for_close(&self) -> io::Result<()> {
+        if self.inner.read_only || self.inner.retired.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.flush()?;
+        self.inner.sync_locked(&mut self.inner.writer())
+    }
+
+    // Lets the workers drain the frozen memtables, then joins them
+    fn stop_workers(&mut self) {
+        self.inner.background().shutdown = true;
+        self.inner.job_requested.notify_all();
+        if let Some(queue) = &self.inner.index_queue {
+            queue.close();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+
+    // Runs the oldest queued background job on this thread, returning false if there
+    // was none. Only meaningful under `Executor::Manual`.
+    #[cfg(feature = "deterministic")]
+    pub fn run_pending_job(&self) -> Result<bool, LsmError> {
+        Ok(self.inner.run_pending_job()?)
+    }
+
+    // Stops the tree the way the process dying would: the workers are stopped, but the
+    // memtable isn't flushed, the WAL isn't synced and the graph isn't saved. What was
+    // written to files so far stays there for the next open to recover.
+    #[cfg(feature = "failpoints")]
+    pub fn crash(self) {
+        self.abandon();
+    }
+
+    // Crashes the tree, then opens its directory again with the same options, the
+    // failpoints reset, so a test can check what survived
+    #[cfg(feature = "failpoints")]
+    pub fn crash_and_reopen(self) -> Result<LSMTree, LsmError> {
+        let (directory, options) = (self.inner.directory.clone(), self.inner.options.clone());
+        self.abandon();
+        if let Some(failpoints) = &options.failpoints {
+            failpoints.reset();
+        }
+        LSMTree::open(&directory, options)
+    }
+
+    #[cfg(any(test, feature = "failpoints"))]
+    fn abandon(self) {
+        let mut lsm = std::mem::ManuallyDrop::new(self);
+        lsm.stop_workers();
+        // the tree is never used or dropped again, so its reference is read out only once
+        drop(unsafe { std::ptr::read(&lsm.inner) });
+    }
+}
+
+impl Drop for LSMTree {
+    fn drop(&mut self) {
+        let _ = self.flush_for_close();
+        self.stop_workers();
+        let _ = self.inner.save_index();
+        // iterators and snapshots may outlive the tree, reading tables they already opened
+        let on_retired = self.inner.on_retired.lock().unwrap().take();
+        if let Some(then) = on_retired {
+            then();
+        }
+    }
+}
+
+impl Iterator for IterAt {
+    type Item = Result<(u64, Vector), LsmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let current = match self.current.peek() {
+                Some(Ok((key, _))) => Some(*key),
+                Some(Err(_)) => return self.current.next(),
+                None => None,
+            };
+            let changed = self.changed.peek().map(|(key, _)| *key);
+            match (current, changed) {
+                (None, None) => return None,
+                (Some(current), Some(changed)) if changed <= current => {
+                    if changed == current {
+                        self.current.next();
+                    }
+                }
+                (Some(_), _) => return self.current.next(),
+                (None, Some(_)) => {}
+            }
+            // a key written since: absent back then, or holding its old value
+            let (key, value) = self.changed.next().unwrap();
+            if let Some(value) = value.filter(|value| !value.is_expired(vector::now_millis())) {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+impl Cursor {
+    // As a token to hand a client, 32 hex digits
+    pub fn encode(&self) -> String {
+        format!("{:016x}{:016x}", self.next, self.sequence)
+    }
+
+    pub fn decode(token: &str) -> Result<Cursor, LsmError> {
+        let invalid = || LsmError::InvalidArgument(format!("'{}' is not a scan cursor", token));
+        if token.len() != 32 || !token.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let next = u64::from_str_radix(&token[..16], 16).map_err(|_| invalid())?;
+        let sequence = u64::from_str_radix(&token[16..], 16).map_err(|_| invalid())?;
+        Ok(Cursor { next, sequence })
+    }
+}
+
+impl Snapshot {
+    // Sequence number of the last write visible in this snapshot
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn get(&self, key: u64) -> Result<Option<Vector>, LsmError> {
+        Ok(self.state.get(key, &self.options)?)
+    }
+
+    pub fn range<R: RangeBounds<u64>>(&self, range: R) -> Result<Vec<(u64, Vector)>, LsmError> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(self.state.range(bounds, &self.options)?)
+    }
+
+    pub fn iter(&self) -> Iter {
+        Iter::new(self.state.clone(), self.options.clone())
+    }
+
+    pub fn iter_rev(&self) -> Iter {
+        Iter::reversed(self.state.clone(), self.options.clone())
+    }
+}
+
+impl Iter {
+    fn new(state: State, options: Options) -> Iter {
+        Iter::bounded(state, options, (Bound::Unbounded, Bound::Unbounded), None)
+    }
+
+    // The same entries, largest key first
+    fn reversed(state: State, options: Options) -> Iter {
+        let mut iter = Iter::bounded(state, options, (Bound::Unbounded, Bound::Unbounded), None);
+        iter.seek_to_last();
+        iter
+    }
+
+    // Confined to `bounds`. Tables that can't affect a key in them are left out: ones
+    // with no entry, point tombstone or range tombstone there, and ones whose prefix
+    // filter rules out `prefix`, given as (prefix, bits), while holding no tombstones there.
+    // A table whose index can't be read is kept, for the iteration to report.
+    fn bounded(state: State, options: Options, bounds: (Bound<u64>, Bound<u64>), prefix: Option<(u64, u32)>) -> Iter {
+        let relevant = |table: &SSTable| {
+            let tombstones = table.tombstones.range(bounds).next().is_some() || table.range_tombstones.iter().any(|r| overlaps(r, bounds));
+            let filtered_out = prefix.is_some_and(|(prefix, bits)| table.prefix_filter.as_ref().is_some_and(|f| !f.may_contain_prefix(prefix, bits)));
+            tombstones || (!filtered_out && table.may_hold_range(bounds) && !matches!(table.first_key(bounds, false), Ok(None)))
+        };
+        let layers: Vec<Layer> = std::iter::once(Layer::Memtable)
+            .chain((0..state.immutables.len()).rev().map(Layer::Immutable))
+            .chain((0..state.sstables.len()).rev().filter(|&i| relevant(&state.sstables[i])).map(Layer::Table))
+            .collect();
+        let hints = layers.iter().filter_map(|&layer| match layer {
+            Layer::Table(i) => state.sstables[i].scan_hint(bounds),
+            Layer::Memtable | Layer::Immutable(_) => None,
+        }).collect();
+        let mut iter = Iter { options, state, bounds, reverse: false, layers, heads: Vec::new(), merge_head: None, error: None, _hints: hints, skipped: 0, skipped_keys: None };
+        iter.seek_heads(bounds.0);
+        iter
+    }
+
+    // Moves to `key`, or the first key after it in the iterator's direction (before it
+    // for `iter_rev`), so that is what `next` returns. Keys outside the bounds the
+    // iterator was created with stay out of reach.
+    pub fn seek(&mut self, key: u64) {
+        let (near, far) = match self.reverse {
+            true => (self.bounds.1, self.bounds.0),
+            false => (self.bounds.0, self.bounds.1),
+        };
+        // a key before the bounds starts at their first key, one past them finds nothing
+        let before = match (near, self.reverse) {
+            (Bound::Included(b), false) => key < b,
+            (Bound::Excluded(b), false) => key <= b,
+            (Bound::Included(b), true) => key > b,
+            (Bound::Excluded(b), true) => key >= b,
+            (Bound::Unbounded, _) => false,
+        };
+        let past = match (far, self.reverse) {
+            (Bound::Included(b), false) => key > b,
+            (Bound::Excluded(b), false) => key >= b,
+            (Bound::Included(b), true) => key < b,
+            (Bound::Excluded(b), true) => key <= b,
+            (Bound::Unbounded, _) => false,
+        };
+        if before {
+            self.seek_heads(near);
+        } else if past {
+            self.error = None;
+            self.heads = vec![None; self.layers.len()];
+            self.merge_head = None;
+        } else {
+            self.seek_heads(Bound::Included(key));
+        }
+    }
+
+    // Back to the smallest key, iterating in ascending order from there
+    pub fn seek_to_first(&mut self) {
+        self.reverse = false;
+        self.seek_heads(self.bounds.0);
+    }
+
+    // To the largest key, iterating in descending order from there
+    pub fn seek_to_last(&mut self) {
+        self.reverse = true;
+        self.seek_heads(self.bounds.1);
+    }
+
+    // Points every layer and the merge operands at their first key from `from` on
+    fn seek_heads(&mut self, from: Bound<u64>) {
+        self.error = None;
+        let bounds = self.bounds_from(from);
+        self.merge_head = match self.reverse {
+            true => self.state.merges.range(bounds).next_back(),
+            false => self.state.merges.range(bounds).next(),
+        }.map(|(&k, _)| k);
+        let heads = self.layers.iter().map(|&layer| self.next_key(layer, from)).collect();
+        match heads {
+            Ok(heads) => self.heads = heads,
+            Err(e) => self.fail(e),
+        }
+    }
+
+    // The iterator's bounds from `from` on in its direction
+    fn bounds_from(&self, from: Bound<u64>) -> (Bound<u64>, Bound<u64>) {
+        if self.reverse { (self.bounds.0, from) } else { (from, self.bounds.1) }
+    }
+
+    // The layer's first key from `from` on in the iterator's direction, within its bounds
+    fn next_key(&self, layer: Layer, from: Bound<u64>) -> io::Result<Option<u64>> {
+        let bounds = self.bounds_from(from);
+        match layer {
+            Layer::Memtable => Ok(first_key(self.state.memtable.range(bounds), self.reverse)),
+            Layer::Immutable(i) => Ok(first_key(self.state.immutables[i].memtable.range(bounds), self.reverse)),
+            Layer::Table(i) => self.state.sstables[i].first_key(bounds, self.reverse),
+        }
+    }
+
+    // Ends the iteration with `e`, as a layer whose next key is unknown could hide any later one
+    fn fail(&mut self, e: io::Error) {
+        self.heads = vec![None; self.layers.len()];
+        self.merge_head = None;
+        self.error = Some(e);
+    }
+
+    // Where the key's newest write is, by the same precedence as a point lookup: the
+    // newest layer that has the key or deletes it wins
+    fn locate(&self, key: u64) -> io::Result<Option<Found<'_>>> {
+        for (&layer, &head) in self.layers.iter().zip(self.heads.iter()) {
+            let (tombstones, range_tombstones) = match layer {
+                Layer::Memtable => (&self.state.tombstones, self.state.range_tombstones.as_slice()),
+                Layer::Immutable(i) => {
+                    let immutable = &self.state.immutables[i];
+                    (immutable.tombstones.as_ref(), immutable.range_tombstones.as_slice())
+                }
+                Layer::Table(i) => {
+                    let sstable = &self.state.sstables[i];
+                    (sstable.tombstones.as_ref(), sstable.range_tombstones.as_slice())
+                }
+            };
+            if tombstones.contains(&key) {
+                return Ok(None);
+            }
+            if head == Some(key) {
+                let found = match layer {
+                    Layer::Memtable => Found::Memory(self.state.memtable.get(key).unwrap()),
+                    Layer::Immutable(i) => Found::Memory(self.state.immutables[i].memtable.get(key).unwrap()),
+                    Layer::Table(i) => {
+                        let sstable = &self.state.sstables[i];
+                        let Some(offset) = sstable.offset_of(key)? else {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("table {}: key {} went missing from the index", sstable.file_number, key)));
+                        };
+                        Found::Table(sstable, offset)
+                    }
+                };
+                return Ok(Some(found));
+            }
+            if covers(range_tombstones, key) {
+                return Ok(None);
+            }
+        }
+        Ok(None)
+    }
+
+    // The key's live value, merge operands folded in
+    fn value(&self, key: u64, now: u64) -> io::Result<Option<Vector>> {
+        let base = match self.locate(key)? {
+            Some(Found::Memory(value)) => Some(value.to_vector()?),
+            Some(Found::Table(sstable, offset)) => Some(sstable.read_value(offset)?.1),
+            None => None,
+        };
+        let base = base.filter(|value| !value.is_expired(now));
+        Ok(match self.state.merges.get(&key) {
+            Some(operands) => full_merge(&self.options, key, base.as_ref(), operands),
+            None => base,
+        })
+    }
+
+    // Whether the key is live, from the entry's header where it can be. Only operands
+    // need the value, as the merge operator may delete the key.
+    fn is_live(&self, key: u64, now: u64) -> io::Result<bool> {
+        if self.state.merges.contains_key(&key) {
+            return Ok(self.value(key, now)?.is_some());
+        }
+        match self.locate(key)? {
+            Some(Found::Memory(value)) => Ok(!value.is_expired(now)),
+            Some(Found::Table(sstable, offset)) => Ok(!sstable.is_expired(offset, now)?),
+            None => Ok(false),
+        }
+    }
+
+    // Counts the table entries under `key` that the iteration steps over: all of them when
+    // the key is deleted, expired or found in memory, all but the newest when it is `live`
+    fn count_skipped(&mut self, key: u64, live: bool) {
+        let at_key = || self.layers.iter().zip(&self.heads).filter(|&(_, &head)| head == Some(key)).map(|(layer, _)| layer);
+        let tables = at_key().filter(|layer| matches!(layer, Layer::Table(_))).count();
+        let in_memory = at_key().any(|layer| !matches!(layer, Layer::Table(_)));
+        let skipped = tables.saturating_sub((live && !in_memory) as usize);
+        if skipped == 0 {
+            return;
+        }
+        self.skipped += skipped;
+        self.skipped_keys = Some(match self.skipped_keys {
+            Some((first, last)) => (first.min(key), last.max(key)),
+            None => (key, key),
+        });
+    }
+
+    // The next key `resolve` finds something for, moving every layer past it
+    fn advance<T>(&mut self, resolve: impl Fn(&Iter, u64, u64) -> io::Result<Option<T>>) -> Option<io::Result<(u64, T)>> {
+        loop {
+            if let Some(e) = self.error.take() {
+                return Some(Err(e));
+            }
+            let candidates = self.heads.iter().chain(std::iter::once(&self.merge_head)).flatten();
+            let key = if self.reverse { candidates.max() } else { candidates.min() }.copied()?;
+            let resolved = resolve(self, key, vector::now_millis());
+            self.count_skipped(key, matches!(resolved, Ok(Some(_))));
+
+            for i in 0..self.layers.len() {
+                if self.heads[i] == Some(key) {
+                    match self.next_key(self.layers[i], Bound::Excluded(key)) {
+                        Ok(head) => self.heads[i] = head,
+                        Err(e) => {
+                            self.fail(e);
+                            break;
+                        }
+                    }
+                }
+            }
+            if self.merge_head == Some(key) {
+                let bounds = self.bounds_from(Bound::Excluded(key));
+                self.merge_head = match self.reverse {
+                    true => self.state.merges.range(bounds).next_back(),
+                    false => self.state.merges.range(bounds).next(),
+                }.map(|(&k, _)| k);
+            }
+
+            match resolved {
+                Ok(Some(found)) => return Some(Ok((key, found))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// An iteration that stepped over `Options::read_compaction_skips` entries leaves a hint
+// for compactions to merge the tables it read over the keys it found them under
+impl Drop for Iter {
+    fn drop(&mut self) {
+        let threshold = self.options.read_compaction_skips;
+        let Some((first, last)) = self.skipped_keys else {
+            return;
+        };
+        if threshold == 0 || self.skipped < threshold {
+            return;
+        }
+        let tables = self.layers.iter().filter_map(|&layer| match layer {
+            Layer::Table(i) => Some(self.state.sstables[i].file_number),
+            Layer::Memtable | Layer::Immutable(_) => None,
+        }).collect();
+        self.state.read_hints.record(ReadHint { range: first..last.saturating_add(1), tables });
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(u64, Vector), LsmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance(Iter::value).map(|entry| entry.map_err(LsmError::from))
+    }
+}
+
+// The live keys of an `Iter`, in the same order, found from the memtables and table
+// indexes. Values are read only for keys with merge operands.
+pub struct Keys(Iter);
+
+impl Iterator for Keys {
+    type Item = Result<u64, LsmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let live = |iter: &Iter, key, now| Ok(iter.is_live(key, now)?.then_some(()));
+        self.0.advance(live).map(|found| found.map(|(key, ())| key).map_err(LsmError::from))
+    }
+}
+
+impl Inner {
+    fn open(directory: &Path, options: Options, read_only: bool) -> io::Result<Inner> {
+        if !read_only {
+            let exists = directory.join(manifest::MANIFEST_FILE).exists();
+            if !exists && !options.create_if_missing {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no tree in {}", directory.display())));
+            }
+            if exists && options.error_if_exists {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already holds a tree", directory.display())));
+            }
+            std::fs::create_dir_all(directory)?;
+        }
+        let lock = match read_only {
+            true => None,
+            false => Some(lock_directory(directory)?),
+        };
+        let mut report = preflight::run(directory, &options, read_only)?;
+        if let Some(err) = report.to_error() {
+            return Err(err);
+        }
+
+        let mut manifest = match read_only {
+            true => Manifest::open_read_only(directory)?,
+            false => {
+                remove_temp_files(directory, options.storage.as_deref())?;
+                let mut manifest = Manifest::open(directory)?;
+                remove_obsolete_tables(directory, options.storage.as_deref(), &mut manifest)?;
+                remove_obsolete_field_tables(directory, &mut manifest)?;
+                if let Some(cold_directory) = &options.cold_directory {
+                    std::fs::create_dir_all(cold_directory)?;
+                    remove_temp_files(cold_directory, None)?;
+                    remove_obsolete_cold_tables(cold_directory, &mut manifest)?;
+                }
+                manifest
+            }
+        };
+        #[cfg(feature = "failpoints")]
+        {
+            manifest.failpoints = options.failpoints.clone();
+        }
+
+        let cache = (options.block_cache_bytes != 0).then(|| Arc::new(BlockCache::new(options.block_cache_bytes)));
+        let mut sstables = Vec::new();
+        for &file_number in manifest.live_tables() {
+            let (mut sstable, map_error) = open_table(directory, &options, file_number, Placement::in_manifest(&manifest, file_number))?;
+            if let Some(e) = map_error {
+                report.warnings.push(format!("table {} could not be memory mapped ({}), reading it with pread", file_number, e));
+            }
+            sstable.cache = cache.clone();
+            sstable.paranoid = options.paranoid_checks;
+            sstable.continues_run = manifest.continues_run(file_number);
+            if options.access_hints {
+                sstable.use_access_hints();
+            }
+            sstables.push(sstable);
+        }
+        report.table_count = sstables.len();
+        report.table_format_versions = sstables.iter().map(|t| t.version).collect();
+        preflight::check_format_versions(&mut report, sstable::FORMAT_VERSION);
+        if let Some(err) = report.to_error() {
+            return Err(err);
+        }
+
+        // logs below the manifest's log number were flushed before a crash could delete them.
+        // Everything newer is replayed into one memtable that keeps appending to the newest log.
+        // Logs kept by `Options::retained_wals` are left as they are.
+        let mut replayed = Vec::new();
+        let mut current_wal = None;
+        let wals = wal::list_wals(directory)?;
+        // With `WalRecovery::PointInTime`, the logs after one that lost its tail are dropped,
+        // and the next writes go on in that one.
+        let flushed = wals.iter().filter(|&&number| number < manifest.log_number()).count();
+        let mut torn = None;
+        for (i, number) in wals.into_iter().enumerate() {
+            manifest.mark_file_number_used(number);
+            if number < manifest.log_number() {
+                if !read_only && i + options.retained_wals < flushed {
+                    std::fs::remove_file(directory.join(wal::wal_file_name(number)))?;
+                }
+                continue;
+            }
+            if let Some(torn) = torn {
+                report.warnings.push(format!("dropped {}, written after the torn {}", wal::wal_file_name(number), wal::wal_file_name(torn)));
+                if !read_only {
+                    std::fs::remove_file(directory.join(wal::wal_file_name(number)))?;
+                }
+                continue;
+            }
+            let (wal, records, dropped) = match read_only {
+                true => Wal::read(directory, number)?,
+                false => Wal::replay(directory, number)?,
+            };
+            if dropped != 0 {
+                report.warnings.push(format!("dropped a torn or corrupt {} byte tail from {}", dropped, wal::wal_file_name(number)));
+                if options.wal_recovery == WalRecovery::PointInTime {
+                    torn = Some(number);
+                }
+            }
+            for record in records {
+                replayed.push(WriteBatch::decode(&record)?);
+            }
+            current_wal = Some(wal);
+        }
+        let wal = match current_wal {
+            Some(wal) => wal,
+            None if read_only => return Err(io::Error::new(io::ErrorKind::NotFound, format!("no write-ahead log in {}", directory.display()))),
+            None => Wal::create(directory, manifest.new_file_number())?,
+        };
+        report.replayed_batches = replayed.len();
+        // the graph's writes are replayed into it from its watermark on
+        let saved_index = options.hnsw.and_then(|hnsw_options| load_index(directory, hnsw_options, manifest.metric()));
+        let mut logged = Vec::new();
+        // and the field indexes' from their tables on, if the tables index the same fields
+        let mut field_ops = (!options.indexed_fields.is_empty() && manifest.indexed_fields() == options.indexed_fields).then(Vec::new);
+
+        let counters = Arc::new(Counters::default());
+        let mut state = State {
+            memtable: Memtable::default(),
+            memtable_bytes: 0,
+            merges: BTreeMap::new(),
+            tombstones: BTreeSet::new(),
+            range_tombstones: Vec::new(),
+            immutables: Vec::new(),
+            sstables,
+            pipeline: manifest.pipeline().clone(),
+            metric: manifest.metric(),
+            query_metrics: manifest.query_metrics().to_vec(),
+            element_type: manifest.element_type(),
+            dimension: manifest.dimension(),
+            projection: manifest.projection().clone(),
+            counters: counters.clone(),
+            read_hints: Arc::new(ReadHints::default()),
+        };
+        let mut sequence = manifest.last_sequence();
+        for (first, batch) in replayed {
+            let last = first + batch.len() as u64 - 1;
+            sequence = sequence.max(last);
+            if let Some(index) = &saved_index
+                && last > index.watermark().sequence
+            {
+                logged.push((first, batch.ops.clone()));
+            }
+            if let Some(ops) = &mut field_ops {
+                ops.extend(batch.ops.iter().cloned());
+            }
+            state.apply(batch);
+        }
+        let index = match options.hnsw {
+            Some(hnsw_options) => {
+                let (index, reconciled) = open_index(saved_index, hnsw_options, &state, &options, manifest.last_sequence()..=sequence, logged)?;
+                report.reconciled_vectors = reconciled;
+                Some(RwLock::new(index))
+            }
+            None => None,
+        };
+        let index_queue = open_index_queue(directory, &options, read_only)?;
+        let ivf = match manifest.centroids() {
+            [] => None,
+            centroids => Some(build_ivf(centroids.to_vec(), &state, &options)?),
+        };
+
+        let pq = manifest.quantizer().cloned().map(Arc::new);
+        let fields = open_fields(directory, &mut manifest, &state, &options, read_only, field_ops, &mut report)?.map(RwLock::new);
+
+        // writes replayed from the log are as old as the open, for the flush timer
+        let memtable_since = state.has_writes().then(Instant::now);
+        let row_cache = (options.row_cache_entries != 0).then(|| RowCache::new(options.row_cache_entries));
+        let rate_limiter = RateLimiter::new(options.compaction_bytes_per_sec);
+        let (write_bytes_limiter, write_ops_limiter) = (RateLimiter::new(options.write_bytes_per_sec), RateLimiter::new(options.write_ops_per_sec));
+        let background = Background { style: options.compaction_style, ..Background::default() };
+        Ok(Inner {
+            directory: directory.to_path_buf(),
+            options,
+            read_only,
+            _lock: lock,
+            startup_report: report,
+            counters,
+            cache,
+            row_cache,
+            rate_limiter,
+            write_bytes_limiter,
+            write_ops_limiter,
+            subscribers: Subscribers::default(),
+            index,
+            index_queue,
+            ivf: RwLock::new(ivf),
+            pq: RwLock::new(pq),
+            fields,
+            state: RwLock::new(state),
+            writer: Mutex::new(Writer {
+                manifest,
+                wal,
+                sequence,
+                synced_sequence: sequence,
+                syncing: false,
+                unsynced_commits: 0,
+                last_sync: Instant::now(),
+                memtable_since,
+                history: History { horizon: sequence, ..History::default() },
+                merging_fields: false,
+            }),
+            background: Mutex::new(background),
+            job_requested: Condvar::new(),
+            job_done: Condvar::new(),
+            synced: Condvar::new(),
+            stall_condition: Mutex::new(WriteStallCondition::Normal),
+            retired: AtomicBool::new(false),
+            on_retired: Mutex::new(None),
+        })
+    }
+
+    fn write(&self, batch: WriteBatch) -> io::Result<()> {
+        let _timer = self.time(&self.counters.write_latency);
+        self.stall_writes()?;
+        let mut writer = self.writer();
+        let ops = batch.len() as u64;
+        let bytes = self.write_locked(&mut writer, batch)?;
+        let sequence = writer.sequence;
+        self.wait_synced(writer, sequence)?;
+        self.pace_write(ops, bytes);
+        Ok(())
+    }
+
+    // Sleeps off what a write took beyond `Options::write_bytes_per_sec` and
+    // `write_ops_per_sec`, with no lock held
+    fn pace_write(&self, ops: u64, bytes: u64) {
+        self.write_ops_limiter.acquire(ops);
+        self.write_bytes_limiter.acquire(bytes);
+    }
+
+    // Err once `point` or an earlier one has fired, see `Options::failpoints`
+    #[cfg(feature = "failpoints")]
+    fn fail(&self, point: FailPoint) -> io::Result<()> {
+        match &self.options.failpoints {
+            Some(failpoints) => failpoints.check(point),
+            None => Ok(()),
+        }
+    }
+
+    // Times a call into `histogram`, see `Options::latency_histograms`
+    fn time<'a>(&self, histogram: &'a Histogram) -> Timer<'a> {
+        histogram.time(self.options.latency_histograms)
+    }
+
+    // Holds a write back while background work is behind, see `Options::stop_tables`.
+    // Called before the writer lock is taken, since flushes need it to install their tables.
+    fn stall_writes(&self) -> io::Result<()> {
+        let started = Instant::now();
+        let condition = self.write_pressure();
+        if !self.options.listeners.is_empty() {
+            self.report_stall(condition);
+        }
+        match condition {
+            WriteStallCondition::Normal => return Ok(()),
+            WriteStallCondition::Slowdown => {
+                Counters::add(&self.counters.write_slowdowns, 1);
+                std::thread::sleep(Duration::from_micros(self.options.slowdown_write_micros));
+            }
+            WriteStallCondition::Stop => {
+                Counters::add(&self.counters.write_stops, 1);
+                Counters::add(&self.counters.stalled_writers, 1);
+                let result = self.wait_for_room();
+                self.counters.stalled_writers.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+                result?;
+            }
+        }
+        Counters::add(&self.counters.write_stall_micros, started.elapsed().as_micros() as u64);
+        Ok(())
+    }
+
+    fn report_stall(&self, condition: WriteStallCondition) {
+        // held while the listeners are told, so they see the changes in order
+        let mut last = self.stall_condition.lock().unwrap();
+        if *last == condition {
+            return;
+        }
+        let state = self.state();
+        let info = WriteStallInfo { condition, previous: *last, immutables: state.immutables.len(), runs: compaction::runs(&state.sstables).len() };
+        drop(state);
+        *last = condition;
+        for listener in self.options.listeners.iter() {
+            listener.on_write_stall(&info);
+        }
+    }
+
+    fn write_pressure(&self) -> WriteStallCondition {
+        let options = &self.options;
+        let state = self.state();
+        let past = |count: usize, threshold: usize| threshold != 0 && count >= threshold;
+        let runs = match options.stop_tables != 0 || options.slowdown_tables != 0 {
+            true => compaction::runs(&state.sstables).len(),
+            false => 0,
+        };
+        if past(state.immutables.len(), options.stop_immutables) || past(runs, options.stop_tables) {
+            WriteStallCondition::Stop
+        } else if past(state.immutables.len(), options.slowdown_immutables) || past(runs, options.slowdown_tables) {
+            WriteStallCondition::Slowdown
+        } else {
+            WriteStallCondition::Normal
+        }
+    }
+
+    // Waits until the tree is back under its stop thresholds
+    fn wait_for_room(&self) -> io::Result<()> {
+        // nothing else will run the jobs, so the stopped writer does
+        #[cfg(feature = "deterministic")]
+        if self.options.executor == Executor::Manual {
+            while self.write_pressure() == WriteStallCondition::Stop && self.run_pending_job()? {}
+            return Ok(());
+        }
+
+        // jobs signal under the background lock after installing their output, so a
+        // check made holding it can't miss the wakeup
+        let mut background = self.background();
+        while self.write_pressure() == WriteStallCondition::Stop {
+            if let Some(e) = &background.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            if background.shutdown {
+                break;
+            }
+            background = self.job_done.wait(background).unwrap();
+        }
+        Ok(())
+    }
+
+    // Returns once the WAL is synced through `sequence`. With a commit window the first
+    // writer to get here leads: it waits out the window so concurrent writers can append
+    // behind it, then syncs once for all of them without holding the writer lock.
+    fn wait_synced<'a>(&'a self, mut writer: MutexGuard<'a, Writer>, sequence: u64) -> io::Result<()> {
+        if self.options.sync_policy != SyncPolicy::Always {
+            return Ok(());
+        }
+        while writer.synced_sequence < sequence {
+            if writer.syncing {
+                writer = self.synced.wait(writer).unwrap();
+                continue;
+            }
+            writer.syncing = true;
+            drop(writer);
+            std::thread::sleep(Duration::from_micros(self.options.commit_window_micros));
+
+            writer = self.writer();
+            let target = writer.sequence;
+            let commits = std::mem::take(&mut writer.unsynced_commits);
+            let handle = writer.wal.sync_handle();
+            drop(writer);
+            let result = handle.and_then(|file| file.sync_data());
+
+            writer = self.writer();
+            writer.syncing = false;
+            self.synced.notify_all();
+            result?;
+            writer.synced_sequence = writer.synced_sequence.max(target);
+            Counters::add(&self.counters.wal_syncs, 1);
+            Counters::add(&self.counters.synced_commits, commits);
+        }
+        Ok(())
+    }
+
+    // Syncs what was appended to the current WAL, before it is frozen
+    fn sync_locked(&self, writer: &mut Writer) -> io::Result<()> {
+        if writer.unsynced_commits == 0 || self.options.sync_policy == SyncPolicy::Never {
+            return Ok(());
+        }
+        writer.wal.sync()?;
+        writer.last_sync = Instant::now();
+        writer.synced_sequence = writer.sequence;
+        Counters::add(&self.counters.wal_syncs, 1);
+        Counters::add(&self.counters.synced_commits, std::mem::take(&mut writer.unsynced_commits));
+        self.synced.notify_all();
+        Ok(())
+    }
+
+    // Applies and logs the batch, returning the bytes it appended to the WAL
+    fn write_locked(&self, writer: &mut Writer, batch: WriteBatch) -> io::Result<u64> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        self.check_writable()?;
+        self.check_background_error()?;
+        #[cfg(feature = "failpoints")]
+        if let Some(failpoints) = &self.options.failpoints {
+            failpoints.check_crashed()?;
+        }
+
+        let pipeline = writer.manifest.pipeline().clone();
+        let element_type = writer.manifest.element_type();
+        let mut dimension = writer.manifest.dimension();
+        let mut prepared = WriteBatch::new();
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    check_limits(&self.options, key, &value)?;
+                    if dimension.is_none() && self.options.fix_dimension {
+                        dimension = Some(value.data().len());
+                    }
+                    check_dimension(dimension, key, &value)?;
+                    prepared.put(key, preprocess(&pipeline, element_type, value)?)
+                }
+                BatchOp::Delete(key) => prepared.delete(key),
+                BatchOp::DeleteRange(range) if range.start > range.end => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("range delete from {} to {} ends before it starts", range.start, range.end)));
+                }
+                // an empty range deletes nothing, so nothing is logged for it
+                BatchOp::DeleteRange(range) if range.is_empty() => &mut prepared,
+                BatchOp::DeleteRange(range) => prepared.delete_range(range.start, range.end),
+                BatchOp::Merge(key, operand) => {
+                    if self.options.merge_operator.is_none() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, "merge requires a merge operator in Options"));
+                    }
+                    if self.options.max_value_bytes != 0 && operand.len() > self.options.max_value_bytes {
+                        return Err(too_large(format!("merge operand for key {} is {} bytes, more than max_value_bytes {}", key, operand.len(), self.options.max_value_bytes)));
+                    }
+                    prepared.merge(key, operand)
+                }
+            };
+        }
+        if prepared.is_empty() {
+            return Ok(0);
+        }
+
+        // the indexes are updated from the ops once they are applied
+        let index_ops = self.has_index().then(|| prepared.ops.clone());
+        // read before anything is logged, so a failed read fails the write
+        let before = match self.options.history_retention_millis {
+            0 => None,
+            _ => Some(self.state().values_before(&prepared, &self.options)?),
+        };
+        let first = writer.sequence + 1;
+        let (kind, record) = (prepared.record_type(), prepared.encode(first)?);
+        let appended = writer.wal.append_unsynced(kind, &record)? as u64;
+        // a dimension taken from the batch is only kept once the batch itself is
+        if dimension != writer.manifest.dimension()
+            && let Some(dimension) = dimension
+        {
+            writer.manifest.log(&[VersionEdit::SetDimension(dimension as u64)])?;
+            self.state_mut().dimension = Some(dimension);
+        }
+        #[cfg(feature = "failpoints")]
+        self.fail(FailPoint::WalAppend)?;
+        if self.options.sync_policy == SyncPolicy::Always && self.options.commit_window_micros == 0 {
+            writer.wal.sync()?;
+            writer.sequence += prepared.len() as u64;
+            writer.synced_sequence = writer.sequence;
+            Counters::add(&self.counters.wal_syncs, 1);
+            Counters::add(&self.counters.synced_commits, 1);
+        } else {
+            // reads can see the batch slightly before it is durable, see `wait_synced`
+            writer.sequence += prepared.len() as u64;
+            writer.unsynced_commits += 1;
+        }
+        if let SyncPolicy::EveryNMillis(millis) = self.options.sync_policy
+            && writer.last_sync.elapsed() >= Duration::from_millis(millis)
+        {
+            self.sync_locked(writer)?;
+        }
+        Counters::add(&self.counters.bytes_written, appended);
+        self.subscribers.publish(first, &prepared);
+        let sequence = writer.sequence;
+        match before {
+            Some(before) => {
+                for (key, value) in before {
+                    writer.history.record(sequence, key, value);
+                }
+                writer.history.expire(Duration::from_millis(self.options.history_retention_millis));
+            }
+            None => writer.history.forget(sequence),
+        }
+
+        let mut state = self.state_mut();
+        if let Some(cache) = &self.row_cache {
+            for op in &prepared.ops {
+                match op {
+                    BatchOp::Put(key, _) | BatchOp::Delete(key) | BatchOp::Merge(key, _) => cache.remove(*key),
+                    BatchOp::DeleteRange(range) => cache.remove_range(range),
+                }
+            }
+        }
+        state.apply(prepared);
+        writer.memtable_since.get_or_insert_with(Instant::now);
+        let mut full = state.memtable.len() + state.merges.len() + state.tombstones.len() + state.range_tombstones.len() >= self.options.sstable_size
+            || (self.options.memtable_bytes != 0 && state.memtable_bytes >= self.options.memtable_bytes);
+        if !full && self.balance_memory(&state) {
+            Counters::add(&self.counters.memory_flushes, 1);
+            full = true;
+        }
+        let index_updates = index_ops.map(|ops| state.index_updates(ops, &self.options, self.index.is_some()));
+        drop(state);
+        if let Some(updates) = index_updates {
+            let updates = updates?;
+            match &self.index_queue {
+                Some(queue) => self.queue_index_updates(queue, sequence, updates),
+                None => self.update_indexes(updates),
+            }
+        }
+
+        if full {
+            self.freeze(writer)?;
+        }
+        Ok(appended)
+    }
+
+    // Holds the block cache to what the rest leaves of `Options::memory_budget_bytes`, and
+    // says whether the memtable should be frozen early to stay within it
+    fn balance_memory(&self, state: &State) -> bool {
+        if self.options.memory_budget_bytes == 0 {
+            return false;
+        }
+        let usage = state.memory_usage();
+        if let Some(cache) = &self.cache {
+            cache.set_capacity(usage.cache_capacity(self.options.memory_budget_bytes, self.options.block_cache_bytes));
+        }
+        usage.should_flush(self.options.memory_budget_bytes)
+    }
+
+    // Moves the active memtable onto the immutable queue, starting a fresh WAL for new writes
+    fn freeze(&self, writer: &mut Writer) -> io::Result<()> {
+        if !self.state().has_writes() {
+            return Ok(());
+        }
+
+        // folded while the writer lock keeps writes out, before anything can fail halfway
+        self.state_mut().fold_merges(&self.options)?;
+        // a later sync only reaches the new log
+        self.sync_locked(writer)?;
+        let wal = Wal::create(&self.directory, writer.manifest.new_file_number())?;
+        let frozen_wal = std::mem::replace(&mut writer.wal, wal);
+        // with the writer lock held, what the field indexes filed since the last freeze
+        // came from the memtable's writes
+        let field_entries = self.fields.as_ref().map(|fields| fields.write().unwrap().take_added()).unwrap_or_default();
+
+        let mut state = self.state_mut();
+        let memtable = std::mem::take(&mut state.memtable);
+        let tombstones = std::mem::take(&mut state.tombstones);
+        let range_tombstones = std::mem::take(&mut state.range_tombstones);
+        let bytes = std::mem::take(&mut state.memtable_bytes);
+        state.immutables.push(Immutable {
+            memtable: Arc::new(memtable),
+            tombstones: Arc::new(tombstones),
+            range_tombstones,
+            bytes,
+            wal_number: frozen_wal.number(),
+            last_sequence: writer.sequence,
+            field_entries: Arc::new(field_entries),
+        });
+        drop(state);
+        writer.memtable_since = None;
+
+        let _background = self.background();
+        self.job_requested.notify_all();
+        Ok(())
+    }
+
+    fn background_loop(&self, kind: JobKind) {
+        loop {
+            let mut background = self.background();
+            let job = loop {
+                if background.error.is_some() {
+                    return;
+                }
+                // shutting down still drains the flushes so frozen memtables aren't left to
+                // replay, pending compactions are simply picked up again after the next open
+                let job = match kind {
+                    JobKind::Flush => self.claim_flush(&mut background),
+                    JobKind::Compaction if !background.shutdown => self.claim_compaction(&mut background),
+                    JobKind::Compaction => None,
+                };
+                if let Some(job) = job {
+                    break job;
+                }
+                if background.shutdown {
+                    return;
+                }
+                background = self.job_requested.wait(background).unwrap();
+            };
+            drop(background);
+
+            if self.run_job(job).is_err() {
+                return;
+            }
+        }
+    }
+
+    // Freezes the active memtable once its oldest write is `interval` old, for the flush
+    // workers to write out. Stops on shutdown, or with the others if a job fails.
+    fn flush_timer(&self, interval: Duration) {
+        let mut wait = interval;
+        loop {
+            let background = self.background();
+            if background.shutdown || background.error.is_some() {
+                return;
+            }
+            // woken early by every queued job and by shutdown
+            let (background, _) = self.job_requested.wait_timeout(background, wait).unwrap();
+            if background.shutdown || background.error.is_some() {
+                return;
+            }
+            drop(background);
+
+            let mut writer = self.writer();
+            let age = writer.memtable_since.map(|since| since.elapsed());
+            wait = match age {
+                Some(age) if age < interval => interval - age,
+                Some(_) => {
+                    if let Err(e) = self.freeze(&mut writer) {
+                        let mut background = self.background();
+                        background.error.get_or_insert_with(|| format!("timed flush failed: {}", e));
+                        self.job_requested.notify_all();
+                        self.job_done.notify_all();
+                        return;
+                    }
+                    Counters::add(&self.counters.timed_flushes, 1);
+                    interval
+                }
+                None => interval,
+            };
+        }
+    }
+
+    // Syncs the WAL once the last sync is `interval` old and writes have come since, so
+    // writes before a quiet spell don't wait for the next one. Stops like `flush_timer`.
+    fn sync_timer(&self, interval: Duration) {
+        let mut wait = interval;
+        loop {
+            let background = self.background();
+            if background.shutdown || background.error.is_some() {
+                return;
+            }
+            let (background, _) = self.job_requested.wait_timeout(background, wait).unwrap();
+            if background.shutdown || background.error.is_some() {
+                return;
+            }
+            drop(background);
+
+            let mut writer = self.writer();
+            let age = writer.last_sync.elapsed();
+            wait = match writer.unsynced_commits {
+                0 => interval,
+                _ if age < interval => interval - age,
+                _ => {
+                    if let Err(e) = self.sync_locked(&mut writer) {
+                        let mut background = self.background();
+                        background.error.get_or_insert_with(|| format!("timed sync failed: {}", e));
+                        self.job_requested.notify_all();
+                        self.job_done.notify_all();
+                        return;
+                    }
+                    interval
+                }
+            };
+        }
+    }
+
+    fn has_pending_flush(&self) -> bool {
+        !self.state().immutables.is_empty()
+    }
+
+    // Whether any job is queued or still running
+    #[cfg(test)]
+    fn has_pending_job(&self, background: &Background) -> bool {
+        self.has_pending_flush() || compaction::pick(&self.state().sstables, self.options.compaction_trigger, background.style, &BTreeSet::new()).is_some()
+    }
+
+    // Takes the oldest frozen memtable no other flush thread is writing
+    fn claim_flush(&self, background: &mut Background) -> Option<Job> {
+        let state = self.state();
+        let immutable = state.immutables.iter().find(|m| !background.flushing.contains(&m.wal_number))?;
+        background.flushing.insert(immutable.wal_number);
+        Some(Job::Flush {
+            memtable: immutable.memtable.clone(),
+            tombstones: immutable.tombstones.clone(),
+            range_tombstones: immutable.range_tombstones.clone(),
+            field_entries: immutable.field_entries.clone(),
+            wal_number: immutable.wal_number,
+            last_sequence: immutable.last_sequence,
+        })
+    }
+
+    fn claim_compaction(&self, background: &mut Background) -> Option<Job> {
+        let state = self.state();
+        if let Some(job) = self.claim_read_hint(&state, background) {
+            return Some(job);
+        }
+        let range = compaction::pick(&state.sstables, self.options.compaction_trigger, background.style, &background.compacting)?;
+        let picked: Vec<u64> = state.sstables[range.clone()].iter().map(|t| t.file_number).collect();
+        background.compacting.extend(picked.iter().copied());
+        Some(Job::Compaction { start: range.start, picked })
+    }
+
+    // The tables covering the first range an iterator found crowded that no running
+    // compaction holds, see `Options::read_compaction_skips`. A hint is dropped once the
+    // tables it was read from have all been compacted away, or when it lies in the newest
+    // tables alone, which can't drop the deletes and older versions it found.
+    fn claim_read_hint(&self, state: &State, background: &mut Background) -> Option<Job> {
+        if self.options.compaction_trigger == 0 {
+            return None;
+        }
+        let range = state.read_hints.take(|hint| {
+            if !state.sstables.iter().any(|t| hint.tables.contains(&t.file_number)) {
+                return HintClaim::Drop;
+            }
+            let Some(range) = compaction::covering(&state.sstables, &hint.range) else {
+                return HintClaim::Drop;
+            };
+            if range.len() < 2 && range.start != 0 {
+                return HintClaim::Drop;
+            }
+            match state.sstables[range.clone()].iter().any(|t| background.compacting.contains(&t.file_number)) {
+                true => HintClaim::Keep,
+                false => HintClaim::Take(range),
+            }
+        })?;
+        let picked: Vec<u64> = state.sstables[range.clone()].iter().map(|t| t.file_number).collect();
+        background.compacting.extend(picked.iter().copied());
+        Counters::add(&self.counters.read_compactions, 1);
+        Some(Job::Compaction { start: range.start, picked })
+    }
+
+    fn compact_all(&self) -> io::Result<()> {
+        let mut background = self.background();
+        while !background.compacting.is_empty() {
+            if let Some(e) = &background.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            background = self.job_done.wait(background).unwrap();
+        }
+        let picked: Vec<u64> = self.state().sstables.iter().map(|t| t.file_number).collect();
+        if picked.is_empty() {
+            return Ok(());
+        }
+        background.compacting.extend(picked.iter().copied());
+        drop(background);
+        self.run_job(Job::Compaction { start: 0, picked })
+    }
+
+    fn compact_range(&self, range: Range<u64>) -> io::Result<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let mut background = self.background();
+        while !background.compacting.is_empty() {
+            if let Some(e) = &background.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            background = self.job_done.wait(background).unwrap();
+        }
+        let state = self.state();
+        let Some(covering) = compaction::covering(&state.sstables, &range) else {
+            return Ok(());
+        };
+        let picked: Vec<u64> = state.sstables[covering.clone()].iter().map(|t| t.file_number).collect();
+        drop(state);
+        background.compacting.extend(picked.iter().copied());
+        drop(background);
+        self.run_job(Job::Compaction { start: covering.start, picked })
+    }
+
+    // Flushes take priority over compactions so writers aren't held up by a growing queue
+    #[cfg(feature = "deterministic")]
+    fn run_pending_job(&self) -> io::Result<bool> {
+        let job = {
+            let mut background = self.background();
+            self.claim_flush(&mut background).or_else(|| self.claim_compaction(&mut background))
+        };
+        let Some(job) = job else { return Ok(false) };
+        self.run_job(job)?;
+        Ok(true)
+    }
+
+    fn run_job(&self, job: Job) -> io::Result<()> {
+        let (kind, result) = match &job {
+            Job::Flush { memtable, tombstones, range_tombstones, field_entries, wal_number, last_sequence } => {
+                ("flush", self.flush_immutable(memtable, tombstones, range_tombstones, field_entries, *wal_number, *last_sequence))
+            }
+            Job::Compaction { start, picked } => ("compaction", self.compact(*start, picked)),
+        };
+
+        let mut background = self.background();
+        match &job {
+            Job::Flush { wal_number, .. } => {
+                background.flushing.remove(wal_number);
+            }
+            Job::Compaction { picked, .. } => {
+                for n in picked {
+                    background.compacting.remove(n);
+                }
+            }
+        }
+        if let Err(e) = &result && background.error.is_none() {
+            background.error = Some(format!("background {} failed: {}", kind, e));
+        }
+        // the finished job may have made room for, or created, work for the other workers
+        self.job_requested.notify_all();
+        self.job_done.notify_all();
+        drop(background);
+        if result.is_ok() {
+            // a flush or compaction frees memory the block cache can grow back into
+            self.balance_memory(&self.state());
+        }
+        result
+    }
+
+    // Writes a frozen memtable out as an SSTable. Flushes of newer memtables may finish
+    // first but are installed oldest first.
+    fn flush_immutable(&self, memtable: &Memtable, tombstones: &BTreeSet<u64>, range_tombstones: &[Range<u64>], field_entries: &BTreeSet<Vec<u8>>, wal_number: u64, last_sequence: u64) -> io::Result<()> {
+        let started = Instant::now();
+        let file_number = self.writer().manifest.new_file_number();
+        let mut info = FlushInfo {
+            file_number,
+            wal_number,
+            entries: memtable.len(),
+            tombstones: tombstones.len(),
+            range_tombstones: range_tombstones.len(),
+            file_size: 0,
+            elapsed_micros: 0,
+        };
+        for listener in self.options.listeners.iter() {
+            listener.on_flush_begin(&info);
+        }
+        let table = self.write_sstable(file_number, memtable.decoded(), tombstones, range_tombstones, None, false)?;
+        Counters::add(&self.counters.bytes_flushed, table.file_size());
+        info.file_size = table.file_size();
+
+        let mut background = self.background();
+        while self.state().immutables.first().is_some_and(|oldest| oldest.wal_number != wal_number) {
+            if let Some(e) = &background.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            background = self.job_done.wait(background).unwrap();
+        }
+        drop(background);
+
+        let mut writer = self.writer();
+        let edits = vec![VersionEdit::AddTable(file_number), VersionEdit::LastSequence(last_sequence), VersionEdit::LogNumber(wal_number + 1)];
+        self.log_with_field_table(&mut writer, edits, field_entries)?;
+
+        let mut state = self.state_mut();
+        state.immutables.remove(0);
+        state.sstables.push(table);
+        drop(state);
+        drop(writer);
+        Counters::add(&self.counters.flushes, 1);
+        info.elapsed_micros = started.elapsed().as_micros() as u64;
+        for listener in self.options.listeners.iter() {
+            listener.on_flush_completed(&info);
+        }
+
+        let flushed: Vec<u64> = wal::list_wals(&self.directory)?.into_iter().filter(|&number| number <= wal_number).collect();
+        for number in &flushed[..flushed.len().saturating_sub(self.options.retained_wals)] {
+            std::fs::remove_file(self.directory.join(wal::wal_file_name(*number)))?;
+        }
+        self.merge_field_tables()
+    }
+
+    // Logs `edits` along with a field table of `field_entries`, if there are any, written
+    // out first
+    fn log_with_field_table(&self, writer: &mut Writer, mut edits: Vec<VersionEdit>, field_entries: &BTreeSet<Vec<u8>>) -> io::Result<()> {
+        if !field_entries.is_empty() {
+            let number = writer.manifest.new_file_number();
+            write_field_table(&self.directory, number, field_entries, self.options.sync_policy != SyncPolicy::Never)?;
+            edits.push(VersionEdit::AddFieldTable(number));
+        }
+        writer.manifest.log(&edits)
+    }
+
+    // Replaces the field tables with one of every entry the field indexes file now, once
+    // there are `field::TABLE_LIMIT` of them, dropping the entries keys no longer have. The
+    // log is synced first, as a write it holds may be what dropped one.
+    fn merge_field_tables(&self) -> io::Result<()> {
+        let Some(fields) = &self.fields else { return Ok(()) };
+        let mut writer = self.writer();
+        if writer.merging_fields || writer.manifest.field_tables().len() < field::TABLE_LIMIT {
+            return Ok(());
+        }
+        self.sync_locked(&mut writer)?;
+        let inputs = writer.manifest.field_tables().to_vec();
+        let number = writer.manifest.new_file_number();
+        let entries = fields.read().unwrap().entries();
+        writer.merging_fields = true;
+        drop(writer);
+
+        let written = write_field_table(&self.directory, number, &entries, self.options.sync_policy != SyncPolicy::Never);
+        let mut writer = self.writer();
+        writer.merging_fields = false;
+        written?;
+        // tables added meanwhile stay, the merged one adds nothing they lack
+        let mut edits: Vec<VersionEdit> = inputs.iter().map(|&n| VersionEdit::RemoveFieldTable(n)).collect();
+        edits.push(VersionEdit::AddFieldTable(number));
+        writer.manifest.log(&edits)?;
+        drop(writer);
+        for n in inputs {
+            std::fs::remove_file(self.directory.join(manifest::field_table_file_name(n)))?;
+        }
+        Ok(())
+    }
+
+    // Merges a run of adjacent SSTables into one that takes their place
+    fn compact(&self, start: usize, picked: &[u64]) -> io::Result<()> {
+        // the inputs are opened separately so the merge runs without holding any locks.
+        // They are claimed by this job, so they stay live until it installs its output.
+        let started = Instant::now();
+        let placements: BTreeMap<u64, Placement> = self.state().sstables.iter().map(|t| (t.file_number, Placement::of(t))).collect();
+        let mut inputs = Vec::with_capacity(picked.len());
+        for file_number in picked.iter() {
+            let (mut input, _) = open_table(&self.directory, &self.options, *file_number, placements[file_number])?;
+            input.paranoid = self.options.paranoid_checks;
+            if self.options.access_hints {
+                input.use_access_hints();
+            }
+            inputs.push(input);
+        }
+        let bottommost = (start == 0).then(vector::now_millis);
+        // the oldest run is the one kept cold
+        let cold = bottommost.is_some() && self.options.cold_directory.is_some();
+        let mut info = CompactionInfo {
+            inputs: picked.to_vec(),
+            input_bytes: inputs.iter().map(|input| input.file_size()).sum(),
+            outputs: Vec::new(),
+            output_bytes: 0,
+            bottommost: bottommost.is_some(),
+            elapsed_micros: 0,
+        };
+        for listener in self.options.listeners.iter() {
+            listener.on_compaction_begin(&info);
+        }
+
+        // a large merge is split into key ranges merged and written on threads of their own
+        let splits = match self.options.max_subcompactions {
+            1 => Vec::new(),
+            parts => compaction::split_points(&inputs, parts, self.options.sstable_size)?,
+        };
+        let mut parts = Vec::with_capacity(splits.len() + 1);
+        let mut low = Bound::Unbounded;
+        for &split in splits.iter() {
+            parts.push((low, Bound::Excluded(split)));
+            low = Bound::Included(split);
+        }
+        parts.push((low, Bound::Unbounded));
+        let written: Vec<io::Result<SSTable>> = match parts.len() {
+            1 => vec![self.compact_part(&inputs, parts[0], bottommost, picked[0], cold)],
+            _ => std::thread::scope(|scope| {
+                let inputs = &inputs;
+                let handles: Vec<_> = parts.iter().enumerate().map(|(i, &bounds)| {
+                    std::thread::Builder::new()
+                        .name(format!("{}-subcompact-{}", self.options.thread_name_prefix, i))
+                        .spawn_scoped(scope, move || self.compact_part(inputs, bounds, bottommost, picked[0], cold))
+                }).collect();
+                handles.into_iter().map(|handle| {
+                    handle?.join().unwrap_or_else(|_| Err(io::Error::other("sub-compaction thread panicked")))
+                }).collect()
+            }),
+        };
+        drop(inputs);
+        let mut outputs = Vec::with_capacity(written.len());
+        let mut failure = None;
+        for result in written {
+            match result {
+                Ok(output) => outputs.push(output),
+                Err(e) => failure = failure.or(Some(e)),
+            }
+        }
+        if let Some(e) = failure {
+            for table in outputs {
+                let placement = Placement::of(&table);
+                let _ = self.retire_table(table, placement);
+            }
+            return Err(e);
+        }
+
+        info.outputs = outputs.iter().map(|t| t.file_number).collect();
+        info.output_bytes = outputs.iter().map(|t| t.file_size()).sum();
+        let mut writer = self.writer();
+        let mut edits = vec![VersionEdit::CompactTables { inputs: picked.to_vec(), outputs: info.outputs.clone() }];
+        if cold {
+            edits.extend(info.outputs.iter().map(|&n| VersionEdit::ColdTable(n)));
+        }
+        writer.manifest.log(&edits)?;
+
+        let mut state = self.state_mut();
+        let start = state.sstables.iter().position(|t| t.file_number == picked[0]).unwrap();
+        let replaced: Vec<SSTable> = state.sstables.splice(start..start + picked.len(), []).collect();
+        let continues_run = replaced[0].continues_run;
+        let tables = outputs.into_iter().enumerate().map(|(i, mut table)| {
+            table.continues_run = i > 0 || continues_run;
+            table
+        });
+        state.sstables.splice(start..start, tables);
+        drop(state);
+        drop(writer);
+        Counters::add(&self.counters.compactions, 1);
+        info.elapsed_micros = started.elapsed().as_micros() as u64;
+        for listener in self.options.listeners.iter() {
+            listener.on_compaction_completed(&info);
+        }
+
+        for table in replaced {
+            let placement = Placement::of(&table);
+            self.retire_table(table, placement)?;
+        }
+        Ok(())
+    }
+
+    // Merges and writes out the inputs' entries within `bounds`, with the tombstones
+    // still needed, into the cold directory if `cold`. `first_input` is the oldest input's
+    // number.
+    fn compact_part(&self, inputs: &[SSTable], bounds: (Bound<u64>, Bound<u64>), bottommost: Option<u64>, first_input: u64, cold: bool) -> io::Result<SSTable> {
+        let mut merged = compaction::merge(inputs, bounds, bottommost, Some(&self.rate_limiter))?;
+        if bottommost.is_none() {
+            // tables older than the inputs can be compacted meanwhile, but only lose keys
+            let state = self.state();
+            let first = state.sstables.iter().position(|t| t.file_number == first_input).unwrap();
+            compaction::drop_unneeded_tombstones(&mut merged, &state.sstables[..first]);
+        }
+
+        let file_number = self.writer().manifest.new_file_number();
+        let table = self.write_sstable(file_number, merged.entries.iter(), &merged.tombstones, &merged.range_tombstones, Some(&self.rate_limiter), cold)?;
+        Counters::add(&self.counters.bytes_compacted, table.file_size());
+        Ok(table)
+    }
+
+    // Rewrites a table with only its readable entries, returning the new table's number
+    // and entry count, or None if the table was compacted away meanwhile. The table is
+    // claimed like a compaction input so no compaction picks it up halfway.
+    fn salvage(&self, file_number: u64) -> io::Result<Option<(u64, usize)>> {
+        let mut background = self.background();
+        while background.compacting.contains(&file_number) {
+            if let Some(e) = &background.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            background = self.job_done.wait(background).unwrap();
+        }
+        background.compacting.insert(file_number);
+        drop(background);
+        let result = self.replace_with_salvage(file_number);
+        self.background().compacting.remove(&file_number);
+        self.job_done.notify_all();
+        result
+    }
+
+    fn replace_with_salvage(&self, file_number: u64) -> io::Result<Option<(u64, usize)>> {
+        let Some(damaged) = self.state().sstables.iter().find(|t| t.file_number == file_number).cloned() else {
+            return Ok(None);
+        };
+        let entries = damaged.salvage();
+        let output = self.writer().manifest.new_file_number();
+        let mut table = self.write_sstable(output, entries.iter(), &damaged.tombstones, &damaged.range_tombstones, None, damaged.cold)?;
+        drop(damaged);
+
+        let mut writer = self.writer();
+        let mut edits = vec![VersionEdit::CompactTables { inputs: vec![file_number], outputs: vec![output] }];
+        if table.cold {
+            edits.push(VersionEdit::ColdTable(output));
+        }
+        writer.manifest.log(&edits)?;
+        let sequence = writer.sequence;
+        writer.history.forget(sequence);
+        let mut state = self.state_mut();
+        let at = state.sstables.iter().position(|t| t.file_number == file_number).unwrap();
+        table.continues_run = state.sstables[at].continues_run;
+        let damaged = std::mem::replace(&mut state.sstables[at], table);
+        if let Some(cache) = &self.row_cache {
+            cache.clear();
+        }
+        drop(state);
+        drop(writer);
+        let placement = Placement::of(&damaged);
+        self.retire_table(damaged, placement)?;
+        Ok(Some((output, entries.len())))
+    }
+
+    // Deletes a table that is no longer live from `placement`, once no iterator or snapshot
+    // reads it any more
+    fn retire_table(&self, table: SSTable, placement: Placement) -> io::Result<()> {
+        let file_number = table.file_number;
+        let (directory, options) = (self.directory.clone(), self.options.clone());
+        table.retire(move || match placement {
+            Placement::Local => remove_local_table(&directory, &options, file_number),
+            Placement::ColdDirectory => std::fs::remove_file(table_path(&directory, &options, file_number, placement)),
+        })
+    }
+
+    fn write_new_sstable(&self, entries: &BTreeMap<u64, Vector>) -> io::Result<SSTable> {
+        let file_number = self.writer().manifest.new_file_number();
+        let table = self.write_sstable(file_number, entries.iter(), &BTreeSet::new(), &[], None, false)?;
+        Counters::add(&self.counters.bytes_flushed, table.file_size());
+        Ok(table)
+    }
+
+    // Adds tables as the newest in one manifest edit, along with the dimension their
+    // vectors were checked against if the tree has none yet. The indexes are updated
+    // for the tables' entries once they are in.
+    fn install_tables(&self, tables: Vec<SSTable>, dimension: Option<usize>, updates: Vec<IndexUpdate>) -> io::Result<()> {
+        if tables.is_empty() {
+            return Ok(());
+        }
+        self.check_writable()?;
+        let mut writer = self.writer();
+        let mut edits: Vec<VersionEdit> = tables.iter().map(|t| VersionEdit::AddTable(t.file_number)).collect();
+        // the dimension the tables' vectors were checked against is set with them, unless
+        // a write set another meanwhile
+        let new_dimension = match (writer.manifest.dimension(), dimension) {
+            (Some(set), Some(dimension)) if set != dimension => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("loaded vectors have {} dimensions, the tree's dimension is {}", dimension, set)));
+            }
+            (None, Some(dimension)) => {
+                edits.push(VersionEdit::SetDimension(dimension as u64));
+                Some(dimension)
+            }
+            _ => None,
+        };
+        // the entries the tables' keys filed go in with them, as no log holds those keys.
+        // Entries of writes to the memtable since it was last frozen come along.
+        let field_entries = self.fields.as_ref().map(|fields| fields.write().unwrap().take_added()).unwrap_or_default();
+        let mut logged = field_entries.clone();
+        if let Some(fields) = &self.fields {
+            let fields = fields.read().unwrap();
+            for update in &updates {
+                if let IndexUpdate::Insert(key, value, _) = update {
+                    logged.extend(fields.entries_of(*key, value.metadata()));
+                }
+            }
+        }
+        if let Err(e) = self.log_with_field_table(&mut writer, edits, &logged) {
+            if let Some(fields) = &self.fields {
+                fields.write().unwrap().restore_added(field_entries);
+            }
+            return Err(e);
+        }
+        let sequence = writer.sequence;
+        writer.history.forget(sequence);
+        let mut state = self.state_mut();
+        state.sstables.extend(tables);
+        if new_dimension.is_some() {
+            state.dimension = new_dimension;
+        }
+        // the new tables may shadow any cached value
+        if let Some(cache) = &self.row_cache {
+            cache.clear();
+        }
+        drop(state);
+        if !updates.is_empty() {
+            self.drain_index_queue();
+            self.update_indexes(updates);
+            // what they filed went into the field table above, and with the writer lock
+            // held no write filed anything meanwhile
+            if let Some(fields) = &self.fields {
+                fields.write().unwrap().take_added();
+            }
+        }
+        drop(writer);
+
+        let background = self.background();
+        self.job_requested.notify_all();
+        drop(background);
+        self.merge_field_tables()
+    }
+
+    // Writes and syncs a table under a temporary name, moving it into place once durable,
+    // in `Options::cold_directory` if `cold`. No locks are held while the table is written,
+    // and writes are paced by `limiter` if there is one.
+    fn write_sstable<'a, V: Borrow<Vector>>(&self, file_number: u64, entries: impl IntoIterator<Item = (&'a u64, V)>, tombstones: &BTreeSet<u64>, range_tombstones: &[Range<u64>], limiter: Option<&RateLimiter>, cold: bool) -> io::Result<SSTable> {
+        let mut format = TableFormat::new(&self.options, self.state().element_type);
+        if self.options.pq_codes {
+            format.quantizer = self.pq.read().unwrap().clone();
+        }
+        let sync = self.options.sync_policy != SyncPolicy::Never;
+        let placement = match cold {
+            true => Placement::ColdDirectory,
+            false => Placement::Local,
+        };
+        if let Some(storage) = &self.options.storage {
+            self.store_table(storage.as_ref(), file_number, sync, |out| {
+                let mut buf = BufWriter::new(Metered::new(out, limiter));
+                sstable::write_table(&mut buf, entries, tombstones, range_tombstones, &format)?;
+                buf.flush()
+            })?;
+            return self.open_new_table(file_number, placement);
+        }
+
+        let sstable_path = table_path(&self.directory, &self.options, file_number, placement);
+        let temp_path = sstable_path.with_extension(TEMP_EXTENSION);
+        let file = match self.options.direct_io_writes {
+            true => {
+                let mut out = Metered::new(DirectWriter::create(&temp_path)?, limiter);
+                sstable::write_table(&mut out, entries, tombstones, range_tombstones, &format)?;
+                out.into_inner().finish()?
+            }
+            false => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&temp_path)?;
+                let mut buf = BufWriter::new(Metered::new(&mut file, limiter));
+                sstable::write_table(&mut buf, entries, tombstones, range_tombstones, &format)?;
+                buf.flush()?;
+                drop(buf);
+                file
+            }
+        };
+        // the process dying mid-write leaves a torn table under the temporary name
+        #[cfg(feature = "failpoints")]
+        self.fail(FailPoint::TableWrite).inspect_err(|_| {
+            let _ = file.metadata().and_then(|metadata| file.set_len(metadata.len() / 2));
+        })?;
+        if sync {
+            file.sync_all()?;
+        }
+        drop(file);
+
+        std::fs::rename(&temp_path, &sstable_path)?;
+        if sync {
+            sync_dir(sstable_path.parent().unwrap())?;
+        }
+        self.open_new_table(file_number, placement)
+    }
+
+    // Writes table `file_number` into `storage` under a temporary name, then renames it
+    fn store_table(&self, storage: &dyn Storage, file_number: u64, sync: bool, write: impl FnOnce(Appender) -> io::Result<()>) -> io::Result<()> {
+        let name = manifest::table_file_name(file_number);
+        let temp_name = format!("{}.tmp", name);
+        let mut file = storage.create(&temp_name)?;
+        write(Appender::new(&mut *file))?;
+        if sync {
+            file.sync()?;
+        }
+        drop(file);
+        storage.rename(&temp_name, &name)?;
+        if sync {
+            storage.sync()?;
+        }
+        Ok(())
+    }
+
+    // A table just written, read like the live ones
+    fn open_new_table(&self, file_number: u64, placement: Placement) -> io::Result<SSTable> {
+        let (mut table, _) = open_table(&self.directory, &self.options, file_number, placement)?;
+        table.cache = self.cache.clone();
+        table.paranoid = self.options.paranoid_checks;
+        if self.options.access_hints {
+            table.use_access_hints();
+        }
+        Ok(table)
+    }
+
+    fn wait_for_flushes(&self) -> io::Result<()> {
+        // nothing else will run the jobs, so drain them here
+        #[cfg(feature = "deterministic")]
+        if self.options.executor == Executor::Manual {
+            while self.has_pending_flush() {
+                self.run_pending_job()?;
+            }
+            return Ok(());
+        }
+
+        let mut background = self.background();
+        while self.has_pending_flush() {
+            if let Some(e) = &background.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            background = self.job_done.wait(background).unwrap();
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn wait_for_idle(&self) -> io::Result<()> {
+        let mut background = self.background();
+        // a claimed job has left nothing to pick, but may not have deleted its inputs yet
+        while self.has_pending_job(&background) || !background.compacting.is_empty() || !background.flushing.is_empty() {
+            if let Some(e) = &background.error {
+                return Err(io::Error::other(e.clone()));
+            }
+            background = self.job_done.wait(background).unwrap();
+        }
+        Ok(())
+    }
+
+    fn has_index(&self) -> bool {
+        self.index.is_some() || self.ivf.read().unwrap().is_some() || self.fields.is_some()
+    }
+
+    fn update_indexes(&self, updates: Vec<IndexUpdate>) {
+        self.update_secondary_indexes(&updates);
+        if let Some(index) = &self.index {
+            let mut index = index.write().unwrap();
+            for update in updates {
+                update_graph(&mut index, update.into());
+            }
+        }
+    }
+
+    // Waits for the indexer to apply what writes have queued, before the graph is updated
+    // or rebuilt in place. With the writer lock held nothing more is queued meanwhile.
+    fn drain_index_queue(&self) {
+        if let Some(queue) = &self.index_queue {
+            // a failed indexer has nothing more to apply
+            let _ = queue.wait(u64::MAX);
+        }
+    }
+
+    // Like `update_indexes`, leaving the graph's updates to the indexer. Should the
+    // batch fail to be queued, the graph is updated here once the indexer has applied
+    // the batches before it, so it still sees them in order.
+    fn queue_index_updates(&self, queue: &IndexQueue, sequence: u64, updates: Vec<IndexUpdate>) {
+        self.update_secondary_indexes(&updates);
+        let Some(index) = &self.index else { return };
+        let updates: Vec<GraphUpdate> = updates.into_iter().map(GraphUpdate::from).collect();
+        if queue.push(sequence, &updates).is_err() {
+            self.drain_index_queue();
+            let mut index = index.write().unwrap();
+            for update in updates {
+                update_graph(&mut index, update);
+            }
+        }
+    }
+
+    // The indexes kept exactly in step with writes, all but the graph
+    fn update_secondary_indexes(&self, updates: &[IndexUpdate]) {
+        if let Some(ivf) = self.ivf.write().unwrap().as_mut() {
+            for update in updates {
+                match update {
+                    IndexUpdate::Insert(key, value, _) => ivf.insert(*key, value.data()),
+                    IndexUpdate::Remove(key) => ivf.remove(*key),
+                    IndexUpdate::RemoveRange(range) => ivf.remove_range(range.clone()),
+                }
+            }
+        }
+        if let Some(fields) = &self.fields {
+            let mut fields = fields.write().unwrap();
+            for update in updates {
+                update_fields(&mut fields, update);
+            }
+        }
+    }
+
+    // Applies the queued graph updates until the tree is closed and the queue drained.
+    // Each batch is marked applied under the graph's lock, so a search sees the graph and
+    // what it is missing as of the same moment.
+    fn index_loop(&self) {
+        let (Some(queue), Some(index)) = (&self.index_queue, &self.index) else { return };
+        while let Some(taken) = queue.take() {
+            match taken {
+                Ok((sequence, updates)) => {
+                    let mut index = index.write().unwrap();
+                    let count = updates.len() as u64;
+                    for update in updates {
+                        update_graph(&mut index, update);
+                    }
+                    queue.applied(sequence, count);
+                }
+                Err(e) => return queue.fail(e.to_string()),
+            }
+        }
+    }
+
+    // Writes the graph out for the next open, under a temporary name until it is complete
+    // along with what the tree holds now, for the next open to reconcile it against
+    fn save_index(&self) -> io::Result<()> {
+        let Some(index) = self.index.as_ref().filter(|_| !self.read_only && !self.retired.load(Ordering::Relaxed)) else { return Ok(()) };
+        let watermark = {
+            let writer = self.writer();
+            Watermark { sequence: writer.sequence, tables: self.state().table_numbers() }
+        };
+        let mut index = index.write().unwrap();
+        if !index.is_dirty() && *index.watermark() == watermark {
+            return Ok(());
+        }
+        self.write_index(&index, &self.directory, &watermark)?;
+        index.mark_saved(watermark);
+        Ok(())
+    }
+
+    // Writes the graph into `directory`, under a temporary name until it is complete
+    fn write_index(&self, index: &Hnsw, directory: &Path, watermark: &Watermark) -> io::Result<()> {
+        let path = directory.join(hnsw::HNSW_FILE);
+        let temp_path = directory.join(format!("{}.tmp", hnsw::HNSW_FILE));
+        let mut file = File::create(&temp_path)?;
+        let mut buf = BufWriter::new(&mut file);
+        index.write_to(&mut buf, watermark)?;
+        buf.flush()?;
+        drop(buf);
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &path)?;
+        sync_dir(directory)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.retired.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has been dropped", self.directory.display())));
+        }
+        match self.read_only {
+            true => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is open read-only", self.directory.display()))),
+            false => Ok(()),
+        }
+    }
+
+    fn check_background_error(&self) -> io::Result<()> {
+        match &self.background().error {
+            Some(e) => Err(io::Error::other(e.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap()
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap()
+    }
+
+    fn writer(&self) -> MutexGuard<'_, Writer> {
+        self.writer.lock().unwrap()
+    }
+
+    fn background(&self) -> MutexGuard<'_, Background> {
+        self.background.lock().unwrap()
+    }
+}
+
+impl State {
+    // Whether a query may rank the tree's vectors by `metric`, see `set_query_metrics`
+    fn check_query_metric(&self, metric: DistanceMetric) -> io::Result<()> {
+        let unit_equivalent = |metric| matches!(metric, DistanceMetric::L2 | DistanceMetric::Cosine | DistanceMetric::InnerProduct);
+        if metric == self.metric
+            || self.query_metrics.contains(&metric)
+            || (self.pipeline.normalizes() && unit_equivalent(metric) && unit_equivalent(self.metric))
+        {
+            return Ok(());
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the tree ranks by {:?} and doesn't declare {:?} valid for queries, see set_query_metrics", self.metric, metric)))
+    }
+
+    fn get(&self, key: u64, options: &Options) -> io::Result<Option<Vector>> {
+        match self.merges.get(&key) {
+            Some(operands) => Ok(full_merge(options, key, self.get_live(key)?.as_ref(), operands)),
+            None => self.get_live(key),
+        }
+    }
+
+    // An expired record still shadows older versions of its key, it just reads as absent
+    fn get_live(&self, key: u64) -> io::Result<Option<Vector>> {
+        Ok(self.get_base(key)?.filter(|value| !value.is_expired(vector::now_millis())))
+    }
+
+    fn get_base(&self, key: u64) -> io::Result<Option<Vector>> {
+        match self.find(key)? {
+            Some(Found::Memory(value)) => Ok(Some(value.to_vector()?)),
+            Some(Found::Table(sstable, offset)) => Ok(Some(sstable.read_value(offset)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    // See `LSMTree::get_with`
+    fn get_with<R>(&self, key: u64, options: &Options, f: impl FnOnce(Option<ValueRef<'_>>) -> R) -> io::Result<R> {
+        let now = vector::now_millis();
+        if let Some(operands) = self.merges.get(&key) {
+            let merged = full_merge(options, key, self.get_live(key)?.as_ref(), operands);
+            return Ok(f(merged.as_ref().map(ValueRef::decoded)));
+        }
+        let (sstable, offset) = match self.find(key)? {
+            Some(Found::Memory(value)) => return Ok(f(Some(value).filter(|value| !value.is_expired(now)))),
+            Some(Found::Table(sstable, offset)) => (sstable, offset),
+            None => return Ok(f(None)),
+        };
+        sstable.with_entry(offset, |_, flags, serialized| {
+            if flags & entry::VALUE_TYPE_MASK == entry::VALUE_TYPE_DENSE {
+                return Ok(f(Some(ValueRef::dense(flags, serialized)?).filter(|value| !value.is_expired(now))));
+            }
+            let value = sstable.codec.decode(flags, serialized)?;
+            Ok(f(Some(&value).filter(|value| !value.is_expired(now)).map(ValueRef::decoded)))
+        })
+    }
+
+    // Where the newest version of `key` is, unless it was deleted
+    fn find(&self, key: u64) -> io::Result<Option<Found<'_>>> {
+        Counters::add(&self.counters.gets, 1);
+        if let Some(value) = self.memtable.get(key) {
+            Counters::add(&self.counters.memtable_hits, 1);
+            return Ok(Some(Found::Memory(value)));
+        }
+        if self.tombstones.contains(&key) || covers(&self.range_tombstones, key) {
+            return Ok(None);
+        }
+
+        for immutable in self.immutables.iter().rev() {
+            if immutable.tombstones.contains(&key) {
+                return Ok(None);
+            }
+            if let Some(value) = immutable.memtable.get(key) {
+                Counters::add(&self.counters.memtable_hits, 1);
+                return Ok(Some(Found::Memory(value)));
+            }
+            if covers(&immutable.range_tombstones, key) {
+                return Ok(None);
+            }
+        }
+
+        for sstable in self.sstables.iter().rev() {
+            if sstable.tombstones.contains(&key) {
+                return Ok(None);
+            }
+            if !sstable.may_hold_key(key) {
+                Counters::add(&self.counters.key_range_skips, 1);
+                if covers(&sstable.range_tombstones, key) {
+                    return Ok(None);
+                }
+                continue;
+            }
+            if !sstable.may_contain_key(key)? {
+                Counters::add(&self.counters.filter_negatives, 1);
+                // the table's own range tombstones still hide older tables
+                if covers(&sstable.range_tombstones, key) {
+                    return Ok(None);
+                }
+                continue;
+            }
+            Counters::add(&self.counters.sstable_probes, 1);
+            if let Some(offset) = sstable.offset_of(key)? {
+                return Ok(Some(Found::Table(sstable, offset)));
+            }
+            if sstable.is_false_positive(key) {
+                Counters::add(&self.counters.filter_false_positives, 1);
+            }
+            if covers(&sstable.range_tombstones, key) {
+                return Ok(None);
+            }
+        }
+
+        Ok(None)
+    }
+
+    // `LSMTree::knn` over the sources newest first, ranking each key by the first version
+    // of it found and skipping older ones, like `find`. Tables are ranked a column block
+    // at a time where they have them.
+    fn knn(&self, query: &[f64], k: usize, scorer: &Scorer, options: &Options) -> io::Result<TopK> {
+        let now = vector::now_millis();
+        let mismatch = |key: u64, dimension: usize| io::Error::new(io::ErrorKind::InvalidInput, format!("query has {} dimensions, key '{}' has {}", query.len(), key, dimension));
+        let mut top = TopK::new(k);
+        // binary values are compared a word of packed bits at a time, without unpacking
+        let packed_query = binary::pack(query);
+        let rank = |top: &mut TopK, key: u64, value: ValueRef| {
+            if value.is_expired(now) {
+                return Ok(());
+            }
+            if value.len() != query.len() {
+                return Err(mismatch(key, value.len()));
+            }
+            if let Some(distance) = value.as_bits().and_then(|bits| scorer.packed_distance(&packed_query, bits)) {
+                top.push(key, distance);
+                return Ok(());
+            }
+            match value.as_slice() {
+                Some(data) => top.push(key, scorer.distance(query, data)),
+                None => top.push(key, scorer.distance(query, &value.iter().collect::<Vec<f64>>())),
+            }
+            Ok(())
+        };
+        let mut seen = HashSet::new();
+        for &key in self.merges.keys() {
+            seen.insert(key);
+            if let Some(value) = self.get(key, options)? {
+                rank(&mut top, key, ValueRef::decoded(&value))?;
+            }
+        }
+        seen.extend(self.tombstones.iter().copied());
+        for (&key, value) in self.memtable.iter() {
+            if seen.insert(key) {
+                rank(&mut top, key, value)?;
+            }
+        }
+
+        // deleted ranges of the sources seen so far, which hide the older ones
+        let mut hidden = self.range_tombstones.clone();
+        for immutable in self.immutables.iter().rev() {
+            seen.extend(immutable.tombstones.iter().copied());
+            for (&key, value) in immutable.memtable.iter() {
+                if !covers(&hidden, key) && seen.insert(key) {
+                    rank(&mut top, key, value)?;
+                }
+            }
+            hidden.extend(immutable.range_tombstones.iter().cloned());
+        }
+        for sstable in self.sstables.iter().rev() {
+            seen.extend(sstable.tombstones.iter().copied());
+            match sstable.column_blocks()? {
+                Some(section) => for block in sstable::column_blocks(&section) {
+                    let block = block?;
+                    let distances = (block.dimension == query.len()).then(|| block.distances(scorer, query));
+                    for i in 0..block.len() {
+                        let key = block.key(i);
+                        if covers(&hidden, key) || !seen.insert(key) || block.expires_at(i).is_some_and(|at| now >= at) {
+                            continue;
+                        }
+                        match &distances {
+                            Some(distances) => top.push(key, distances[i]),
+                            None => return Err(mismatch(key, block.dimension)),
+                        }
+                    }
+                },
+                None => for entry in sstable.entries(..) {
+                    let (key, offset) = entry?;
+                    if !covers(&hidden, key) && seen.insert(key) {
+                        rank(&mut top, key, ValueRef::decoded(&sstable.read_value(offset)?.1))?;
+                    }
+                },
+            }
+            hidden.extend(sstable.range_tombstones.iter().cloned());
+        }
+        Ok(top)
+    }
+
+    // The `n` keys whose codes `codes` ranks closest, over the sources newest first and
+    // skipping older versions like `knn`. Tables holding codes under `quantizer` are
+    // ranked from them without reading their entries; the rest are encoded as they are
+    // read. Vectors of another dimension have no code, and expired entries of tables
+    // ranked by code are left for the rerank to drop.
+    fn pq_candidates(&self, quantizer: &ProductQuantizer, codes: &CodeScorer, n: usize, options: &Options) -> io::Result<TopK> {
+        let now = vector::now_millis();
+        let fingerprint = quantizer.fingerprint();
+        let mut top = TopK::new(n);
+        let rank = |top: &mut TopK, key: u64, value: ValueRef| {
+            if value.is_expired(now) {
+                return;
+            }
+            let code = match value.as_slice() {
+                Some(data) => quantizer.encode(data),
+                None => quantizer.encode(&value.iter().collect::<Vec<f64>>()),
+            };
+            if let Some(code) = code {
+                top.push(key, codes.distance(&code));
+            }
+        };
+        let mut seen = HashSet::new();
+        for &key in self.merges.keys() {
+            seen.insert(key);
+            if let Some(value) = self.get(key, options)? {
+                rank(&mut top, key, ValueRef::decoded(&value));
+            }
+        }
+        seen.extend(self.tombstones.iter().copied());
+        for (&key, value) in self.memtable.iter() {
+            if seen.insert(key) {
+                rank(&mut top, key, value);
+            }
+        }
+
+        let mut hidden = self.range_tombstones.clone();
+        for immutable in self.immutables.iter().rev() {
+            seen.extend(immutable.tombstones.iter().copied());
+            for (&key, value) in immutable.memtable.iter() {
+                if !covers(&hidden, key) && seen.insert(key) {
+                    rank(&mut top, key, value);
+                }
+            }
+            hidden.extend(immutable.range_tombstones.iter().cloned());
+        }
+        for sstable in self.sstables.iter().rev() {
+            seen.extend(sstable.tombstones.iter().copied());
+            let section = sstable.codes()?;
+            match section.as_deref().map(sstable::Codes::parse).transpose()?.filter(|stored| stored.fingerprint == fingerprint) {
+                Some(stored) => for i in 0..stored.len() {
+                    let key = stored.key(i);
+                    if !covers(&hidden, key) && seen.insert(key) {
+                        top.push(key, codes.distance(stored.code(i)));
+                    }
+                },
+                None => for entry in sstable.entries(..) {
+                    let (key, offset) = entry?;
+                    if !covers(&hidden, key) && seen.insert(key) {
+                        rank(&mut top, key, ValueRef::decoded(&sstable.read_value(offset)?.1));
+                    }
+                },
+            }
+            hidden.extend(sstable.range_tombstones.iter().cloned());
+        }
+        Ok(top)
+    }
+
+    // Layers the sources oldest first so newer entries and tombstones win
+    fn range(&self, bounds: (Bound<u64>, Bound<u64>), options: &Options) -> io::Result<Vec<(u64, Vector)>> {
+        let mut entries = ScanBuffer::new(options.max_scan_bytes);
+        for sstable in self.sstables.iter() {
+            entries.remove_ranges(&sstable.range_tombstones);
+            if sstable.may_hold_range(bounds) {
+                let _hint = sstable.scan_hint(bounds);
+                for entry in sstable.entries(bounds) {
+                    let (key, offset) = entry?;
+                    if !sstable.tombstones.contains(&key) {
+                        entries.insert(key, sstable.read_value(offset)?.1)?;
+                    }
+                }
+            }
+            for &key in sstable.tombstones.range(bounds) {
+                entries.remove(key);
+            }
+        }
+        for immutable in self.immutables.iter() {
+            entries.remove_ranges(&immutable.range_tombstones);
+            for (&key, value) in immutable.memtable.range(bounds) {
+                if !immutable.tombstones.contains(&key) {
+                    entries.insert(key, value.to_vector()?)?;
+                }
+            }
+            for &key in immutable.tombstones.range(bounds) {
+                entries.remove(key);
+            }
+        }
+        entries.remove_ranges(&self.range_tombstones);
+        for &key in self.tombstones.range(bounds) {
+            entries.remove(key);
+        }
+        for (&key, value) in self.memtable.range(bounds) {
+            entries.insert(key, value.to_vector()?)?;
+        }
+        let now = vector::now_millis();
+        for (&key, operands) in self.merges.range(bounds) {
+            match full_merge(options, key, entries.entries.get(&key).filter(|v| !v.is_expired(now)), operands) {
+                Some(value) => entries.insert(key, value)?,
+                None => entries.remove(key),
+            };
+        }
+        Ok(entries.entries.into_iter().filter(|(_, v)| !v.is_expired(now)).collect())
+    }
+
+    // What the indexes have to do for ops that were just applied; values come from the
+    // state so merges are resolved. Vectors are projected only if asked, for the graph.
+    fn index_updates(&self, ops: Vec<BatchOp>, options: &Options, project: bool) -> io::Result<Vec<IndexUpdate>> {
+        let insert = |key: u64, value: Option<Vector>| match value {
+            Some(value) => {
+                let projected = if project { self.projection.apply(value.data()).ok() } else { None };
+                IndexUpdate::Insert(key, value, projected)
+            }
+            None => IndexUpdate::Remove(key),
+        };
+        ops.into_iter().map(|op| Ok(match op {
+            BatchOp::Put(key, value) => insert(key, Some(value)),
+            BatchOp::Merge(key, _) => insert(key, self.get(key, options)?),
+            BatchOp::Delete(key) => IndexUpdate::Remove(key),
+            BatchOp::DeleteRange(range) => IndexUpdate::RemoveRange(range),
+        })).collect()
+    }
+
+    // file numbers of the live tables, in ascending order
+    fn table_numbers(&self) -> Vec<u64> {
+        let mut numbers: Vec<u64> = self.sstables.iter().map(|t| t.file_number).collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            memtable: self.memtable_bytes,
+            frozen: self.immutables.iter().map(|m| m.bytes).sum(),
+            filters: self.sstables.iter().map(SSTable::filter_bytes).sum(),
+            indexes: self.sstables.iter().map(SSTable::index_bytes).sum(),
+        }
+    }
+
+    // Each tombstone is taken to cancel an older entry
+    fn approximate_len(&self) -> usize {
+        let memtable = self.memtable.len() + self.merges.keys().filter(|&&k| !self.memtable.contains_key(k)).count();
+        let immutables: usize = self.immutables.iter().map(|m| m.memtable.len()).sum();
+        let sstables: usize = self.sstables.iter().map(|t| t.len()).sum();
+        let tombstones = self.tombstones.len()
+            + self.immutables.iter().map(|m| m.tombstones.len()).sum::<usize>()
+            + self.sstables.iter().map(|t| t.tombstones.len()).sum::<usize>();
+        (memtable + immutables + sstables).saturating_sub(tombstones)
+    }
+
+    // What each key a batch writes holds before it, for `History`
+    fn values_before(&self, batch: &WriteBatch, options: &Options) -> io::Result<BTreeMap<u64, Option<Vector>>> {
+        let mut before = BTreeMap::new();
+        for op in batch.ops.iter() {
+            match op {
+                BatchOp::Put(key, _) | BatchOp::Delete(key) | BatchOp::Merge(key, _) => {
+                    if !before.contains_key(key) {
+                        before.insert(*key, self.get(*key, options)?);
+                    }
+                }
+                BatchOp::DeleteRange(range) => {
+                    for (key, value) in self.range((Bound::Included(range.start), Bound::Excluded(range.end)), options)? {
+                        before.entry(key).or_insert(Some(value));
+                    }
+                }
+            }
+        }
+        Ok(before)
+    }
+
+    fn approximate_size(&self, range: Range<u64>) -> io::Result<u64> {
+        if range.is_empty() {
+            return Ok(0);
+        }
+        let memtables = std::iter::once(&self.memtable).chain(self.immutables.iter().map(|m| m.memtable.as_ref()));
+        let bounds = (Bound::Included(range.start), Bound::Excluded(range.end));
+        let mut bytes: usize = memtables.flat_map(|memtable| memtable.sizes(bounds)).map(|(_, size)| size).sum();
+        bytes += self.merges.range(range.clone()).map(|(_, operands)| operands_size(operands)).sum::<usize>();
+        for table in self.sstables.iter().filter(|table| table.may_hold_range(bounds)) {
+            bytes += table.data_range(bounds)?.len();
+        }
+        Ok(bytes as u64)
+    }
+
+    // Mirrors `get`: the newest layer that knows about the key decides
+    fn contains_key(&self, key: u64, options: &Options) -> io::Result<bool> {
+        // pending operands can only be resolved by running the merge
+        if self.merges.contains_key(&key) {
+            return Ok(self.get(key, options)?.is_some());
+        }
+        let now = vector::now_millis();
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(!value.is_expired(now));
+        }
+        if self.tombstones.contains(&key) || covers(&self.range_tombstones, key) {
+            return Ok(false);
+        }
+
+        for immutable in self.immutables.iter().rev() {
+            if immutable.tombstones.contains(&key) {
+                return Ok(false);
+            }
+            if let Some(value) = immutable.memtable.get(key) {
+                return Ok(!value.is_expired(now));
+            }
+            if covers(&immutable.range_tombstones, key) {
+                return Ok(false);
+            }
+        }
+
+        for sstable in self.sstables.iter().rev() {
+            if sstable.tombstones.contains(&key) {
+                return Ok(false);
+            }
+            // the key range and prefix filter rule most tables out without their index
+            if !sstable.may_hold_key(key) || !sstable.may_contain_key(key).unwrap_or(true) {
+                if covers(&sstable.range_tombstones, key) {
+                    return Ok(false);
+                }
+                continue;
+            }
+            if let Some(offset) = sstable.offset_of(key)? {
+                return Ok(!sstable.is_expired(offset, now)?);
+            }
+            if covers(&sstable.range_tombstones, key) {
+                return Ok(false);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.has_writes() && self.immutables.is_empty() && self.sstables.is_empty()
+    }
+
+    // Whether the active memtable has anything to flush
+    fn has_writes(&self) -> bool {
+        !self.memtable.is_empty() || !self.merges.is_empty() || !self.tombstones.is_empty() || !self.range_tombstones.is_empty()
+    }
+
+    fn apply(&mut self, batch: WriteBatch) {
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    self.remove_merges(key);
+                    self.insert(key, value);
+                }
+                BatchOp::Delete(key) => {
+                    self.remove_merges(key);
+                    self.remove(key);
+                }
+                BatchOp::DeleteRange(range) => {
+                    let mut freed = self.memtable.remove_range(&range);
+                    self.merges.retain(|key, operands| {
+                        let hit = range.contains(key);
+                        freed += if hit { operands_size(operands) } else { 0 };
+                        !hit
+                    });
+                    let tombstones = self.tombstones.len();
+                    self.tombstones.retain(|key| !range.contains(key));
+                    freed += (tombstones - self.tombstones.len()) * TOMBSTONE_SIZE;
+                    self.memtable_bytes -= freed;
+                    if !range.is_empty() {
+                        self.range_tombstones.push(range);
+                    }
+                }
+                BatchOp::Merge(key, operand) => {
+                    self.memtable_bytes += std::mem::size_of::<Vec<u8>>() + operand.len();
+                    self.merges.entry(key).or_default().push(operand);
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, key: u64, value: Vector) {
+        if self.tombstones.remove(&key) {
+            self.memtable_bytes -= TOMBSTONE_SIZE;
+        }
+        self.memtable_bytes += self.memtable.insert(key, &value);
+    }
+
+    fn remove_merges(&mut self, key: u64) {
+        if let Some(operands) = self.merges.remove(&key) {
+            self.memtable_bytes -= operands_size(&operands);
+        }
+    }
+
+    // Resolves every buffered operand into a plain value so the memtable can be flushed.
+    // The values under them are all read first, so a failed read leaves the operands as
+    // they were.
+    fn fold_merges(&mut self, options: &Options) -> io::Result<()> {
+        let mut folded = Vec::with_capacity(self.merges.len());
+        for (&key, operands) in &self.merges {
+            folded.push((key, full_merge(options, key, self.get_live(key)?.as_ref(), operands)));
+        }
+        for operands in std::mem::take(&mut self.merges).into_values() {
+            self.memtable_bytes -= operands_size(&operands);
+        }
+        for (key, value) in folded {
+            match value {
+                Some(value) => self.insert(key, value),
+                None => self.remove(key),
+            }
+        }
+        Ok(())
+    }
+
+    // Older memtables and tables are left as they are, a tombstone hides the key in them.
+    // It is only needed where one of them may hold the key, which the key ranges and
+    // prefix filters mostly answer without reading an index.
+    fn remove(&mut self, key: u64) {
+        self.memtable_bytes -= self.memtable.remove(key);
+        let shadowed = self.immutables.iter().any(|m| m.memtable.contains_key(key))
+            || self.sstables.iter().any(|t| t.may_hold_key(key) && t.may_contain_key(key).unwrap_or(true));
+        if shadowed && self.tombstones.insert(key) {
+            self.memtable_bytes += TOMBSTONE_SIZE;
+        }
+    }
+}
+
+// Results of a range scan with a running estimate of the memory they hold
+struct ScanBuffer {
+    entries: BTreeMap<u64, Vector>,
+    bytes: usize,
+    limit: usize,
+}
+
+impl ScanBuffer {
+    fn new(limit: usize) -> ScanBuffer {
+        ScanBuffer { entries: BTreeMap::new(), bytes: 0, limit }
+    }
+
+    fn insert(&mut self, key: u64, value: Vector) -> io::Result<()> {
+        self.bytes += entry_size(&value);
+        if let Some(old) = self.entries.insert(key, value) {
+            self.bytes -= entry_size(&old);
+        }
+        if self.limit > 0 && self.bytes > self.limit {
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory, format!("range scan exceeded its {} byte limit", self.limit)));
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: u64) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.bytes -= entry_size(&old);
+        }
+    }
+
+    fn remove_ranges(&mut self, ranges: &[Range<u64>]) {
+        for range in ranges {
+            let keys: Vec<u64> = self.entries.range(range.clone()).map(|(&k, _)| k).collect();
+            for key in keys {
+                self.remove(key);
+            }
+        }
+    }
+}
+
+fn covers(ranges: &[Range<u64>], key: u64) -> bool {
+    ranges.iter().any(|r| r.contains(&key))
+}
+
+fn first_key<'a, V: 'a>(mut keys: impl DoubleEndedIterator<Item = (&'a u64, V)>, reverse: bool) -> Option<u64> {
+    if reverse { keys.next_back() } else { keys.next() }.map(|(&k, _)| k)
+}
+
+// Whether some key is both in `range` and within `bounds`
+fn overlaps(range: &Range<u64>, bounds: (Bound<u64>, Bound<u64>)) -> bool {
+    let start = match bounds.0 {
+        Bound::Included(k) => k as u128,
+        Bound::Excluded(k) => k as u128 + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match bounds.1 {
+        Bound::Included(k) => k as u128 + 1,
+        Bound::Excluded(k) => k as u128,
+        Bound::Unbounded => 1 << 64,
+    };
+    start.max(range.start as u128) < end.min(range.end as u128)
+}
+
+// What a tombstone in the memtable counts toward `State::memtable_bytes`
+const TOMBSTONE_SIZE: usize = std::mem::size_of::<u64>();
+
+fn entry_size(value: &Vector) -> usize {
+    std::mem::size_of::<u64>() + std::mem::size_of::<Vector>() + std::mem::size_of_val(value.data().as_slice()) + value.metadata_size()
+}
+
+fn operands_size(operands: &[Vec<u8>]) -> usize {
+    operands.iter().map(|operand| std::mem::size_of::<Vec<u8>>() + operand.len()).sum()
+}
+
+fn full_merge(options: &Options, key: u64, existing: Option<&Vector>, operands: &[Vec<u8>]) -> Option<Vector> {
+    let operator = options.merge_operator.as_ref()?;
+    operator.full_merge(key, existing, operands)
+}
+
+// Refuses a value past the size limits in Options
+fn check_limits(options: &Options, key: u64, value: &Vector) -> io::Result<()> {
+    let dimension = value.data().len();
+    if options.max_dimension != 0 && dimension > options.max_dimension {
+        return Err(too_large(format!("vector for key {} has {} dimensions, more than max_dimension {}", key, dimension, options.max_dimension)));
+    }
+    if options.max_payload_bytes != 0 && !value.metadata().is_empty() {
+        let payload = bson::to_vec(value.metadata()).map_err(io::Error::other)?.len();
+        if payload > options.max_payload_bytes {
+            return Err(too_large(format!("metadata for key {} is {} bytes, more than max_payload_bytes {}", key, payload, options.max_payload_bytes)));
+        }
+    }
+    if options.max_value_bytes != 0 {
+        let size = bson::to_vec(value).map_err(io::Error::other)?.len();
+        if size > options.max_value_bytes {
+            return Err(too_large(format!("value for key {} is {} bytes, more than max_value_bytes {}", key, size, options.max_value_bytes)));
+        }
+    }
+    Ok(())
+}
+
+fn too_large(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// Refuses a vector without the tree's dimension, if it has one
+fn check_dimension(dimension: Option<usize>, key: u64, value: &Vector) -> io::Result<()> {
+    match dimension {
+        Some(dimension) if value.data().len() != dimension => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("vector for key {} has {} dimensions, the tree's dimension is {}", key, value.data().len(), dimension))),
+        _ => Ok(()),
+    }
+}
+
+fn preprocess(pipeline: &Pipeline, element_type: ElementType, value: Vector) -> io::Result<Vector> {
+    if pipeline.is_empty() && element_type.is_exact(value.data()) {
+        return Ok(value);
+    }
+    let mut value = value;
+    let mut data = pipeline.apply(value.data())?;
+    element_type.round(&mut data);
+    value.set_data(data);
+    Ok(value)
+}
+
+const TEMP_EXTENSION: &str = "sdb.tmp";
+const LOCK_FILE: &str = "LOCK";
+// entries `search_batch` reads before handing them to its threads
+const SEARCH_BATCH_CHUNK: usize = 1024;
+
+// Whether `name` is one a tree writes in its directory
+fn is_tree_file(name: &str) -> bool {
+    [manifest::MANIFEST_FILE, LOCK_FILE, hnsw::HNSW_FILE, queue::INDEX_QUEUE_FILE, preflight::PROBE_FILE].contains(&name)
+        || name.ends_with(".tmp")
+        || manifest::parse_table_file_name(name).is_some()
+        || manifest::parse_field_table_file_name(name).is_some()
+        || wal::parse_wal_file_name(name).is_some()
+}
+
+// Leftovers of flushes that crashed before their rename
+fn remove_temp_files(directory: &Path, storage: Option<&dyn Storage>) -> io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        // half-written tables and leftover bulk load runs
+        if path.to_string_lossy().ends_with(".tmp") {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    if let Some(storage) = storage {
+        for name in storage.list()?.iter().filter(|name| name.ends_with(".tmp")) {
+            storage.delete(name)?;
+        }
+    }
+    Ok(())
+}
+
+// Tables a crash left behind: compaction inputs not yet deleted, or outputs never logged
+fn remove_obsolete_tables(directory: &Path, storage: Option<&dyn Storage>, manifest: &mut Manifest) -> io::Result<()> {
+    let names = match storage {
+        Some(storage) => storage.list()?,
+        None => std::fs::read_dir(directory)?.map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned())).collect::<io::Result<_>>()?,
+    };
+    for name in names {
+        if let Some(number) = manifest::parse_table_file_name(&name) {
+            manifest.mark_file_number_used(number);
+            if !manifest.live_tables().contains(&number) {
+                match storage {
+                    Some(storage) => storage.delete(&name)?,
+                    None => std::fs::remove_file(directory.join(&name))?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Field tables a crash left behind: merge inputs not yet deleted, or tables never logged
+fn remove_obsolete_field_tables(directory: &Path, manifest: &mut Manifest) -> io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(number) = manifest::parse_field_table_file_name(&name) {
+            manifest.mark_file_number_used(number);
+            if !manifest.field_tables().contains(&number) {
+                std::fs::remove_file(directory.join(&name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Where a live table's file is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placement {
+    // the directory, or `Options::storage`
+    Local,
+    // `Options::cold_directory`
+    ColdDirectory,
+}
+
+impl Placement {
+    fn of(table: &SSTable) -> Placement {
+        match table.cold {
+            true => Placement::ColdDirectory,
+            false => Placement::Local,
+        }
+    }
+
+    fn in_manifest(manifest: &Manifest, file_number: u64) -> Placement {
+        match manifest.is_cold(file_number) {
+            true => Placement::ColdDirectory,
+            false => Placement::Local,
+        }
+    }
+}
+
+// The file of a table in the directory or the cold directory
+fn table_path(directory: &Path, options: &Options, file_number: u64, placement: Placement) -> PathBuf {
+    let name = manifest::table_file_name(file_number);
+    match (placement, &options.cold_directory) {
+        (Placement::ColdDirectory, Some(cold_directory)) => cold_directory.join(name),
+        _ => directory.join(name),
+    }
+}
+
+// Tables in the cold directory that aren't live there: compaction inputs not yet deleted
+// or outputs never logged
+fn remove_obsolete_cold_tables(cold_directory: &Path, manifest: &mut Manifest) -> io::Result<()> {
+    for entry in std::fs::read_dir(cold_directory)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(number) = manifest::parse_table_file_name(&name) {
+            manifest.mark_file_number_used(number);
+            if !manifest.is_cold(number) {
+                std::fs::remove_file(cold_directory.join(&name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Where a live table's file is kept wherever `placement` says it is, with the error if it
+// couldn't be mapped and is read with pread instead
+fn open_table(directory: &Path, options: &Options, file_number: u64, placement: Placement) -> io::Result<(SSTable, Option<io::Error>)> {
+    match placement {
+        Placement::ColdDirectory if options.cold_directory.is_none() => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("table {} is in the cold directory, open the tree with Options::cold_directory", file_number)));
+        }
+        Placement::ColdDirectory => {
+            let path = table_path(directory, options, file_number, placement);
+            let (mut table, map_error) = SSTable::open_reporting(&path, file_number, options.read_path)?;
+            table.cold = true;
+            return Ok((table, map_error));
+        }
+        Placement::Local => {}
+    }
+    let name = manifest::table_file_name(file_number);
+    match &options.storage {
+        Some(storage) => Ok((SSTable::from_data(Arc::new(StoredTable(storage.open(&name)?)), file_number)?, None)),
+        None => SSTable::open_reporting(&directory.join(name), file_number, options.read_path),
+    }
+}
+
+// Deletes a table that isn't in the cold directory, from the storage or the directory
+fn remove_local_table(directory: &Path, options: &Options, file_number: u64) -> io::Result<()> {
+    let name = manifest::table_file_name(file_number);
+    match &options.storage {
+        Some(storage) => storage.delete(&name),
+        None => std::fs::remove_file(directory.join(name)),
+    }
+}
+
+// The graph written out at the last clean close. None if there is none, or it doesn't
+// open, or was built with other options or another metric.
+fn load_index(directory: &Path, options: HnswOptions, metric: DistanceMetric) -> Option<Hnsw> {
+    let file = File::open(directory.join(hnsw::HNSW_FILE)).ok()?;
+    Hnsw::read_from(&mut BufReader::new(file), options, metric).ok().flatten()
+}
+
+// Brings a saved graph in line with the tree, or builds one from the stored vectors
+// without it, returning how many vectors the saved graph had missing, stale or left
+// over. If the tree still has the tables it had when the graph was saved, and has
+// flushed nothing past the watermark, the graph only lacks the writes the log replayed
+// since, `logged`, which are applied to it in order. Otherwise, after a flush,
+// compaction or bulk load, the graph is checked against every stored vector.
+fn open_index(saved: Option<Hnsw>, options: HnswOptions, state: &State, tree_options: &Options, replayed: RangeInclusive<u64>, logged: Vec<(u64, Vec<BatchOp>)>) -> io::Result<(Hnsw, usize)> {
+    let Some(mut index) = saved else {
+        return Ok((build_index(options, state, tree_options)?, 0));
+    };
+    let watermark = index.watermark().clone();
+    if watermark.tables != state.table_numbers() || !replayed.contains(&watermark.sequence) {
+        let fixed = reconcile_index(&mut index, state, tree_options)?;
+        return Ok((index, fixed));
+    }
+    let mut fixed = 0;
+    for (first, ops) in logged {
+        let skip = (watermark.sequence + 1).saturating_sub(first) as usize;
+        for update in state.index_updates(ops.into_iter().skip(skip).collect(), tree_options, true)? {
+            update_graph(&mut index, update.into());
+            fixed += 1;
+        }
+    }
+    Ok((index, fixed))
+}
+
+// Inserts the stored vectors the graph lacks or holds another projection of, and removes
+// the keys the tree no longer has, returning how many it changed
+fn reconcile_index(index: &mut Hnsw, state: &State, options: &Options) -> io::Result<usize> {
+    let mut left: BTreeSet<u64> = index.keys().collect();
+    let mut fixed = 0;
+    for entry in Iter::new(state.clone(), options.clone()) {
+        let (key, value) = entry?;
+        left.remove(&key);
+        match state.projection.apply(value.data()) {
+            Ok(projected) if index.vector(key) != Some(projected.as_slice()) => index.insert(key, projected),
+            // a vector the projection can't reduce is left out of the graph
+            Err(_) if index.vector(key).is_some() => index.remove(key),
+            _ => continue,
+        }
+        fixed += 1;
+    }
+    fixed += left.len();
+    for key in left {
+        index.remove(key);
+    }
+    Ok(fixed)
+}
+
+// The queue of `Options::index_queue`, if the tree keeps one. What a crash left of one is
+// dropped either way, as the saved graph is reconciled with the tree instead.
+fn open_index_queue(directory: &Path, options: &Options, read_only: bool) -> io::Result<Option<IndexQueue>> {
+    if read_only {
+        return Ok(None);
+    }
+    if options.index_queue && options.hnsw.is_some() && options.executor == Executor::Threaded {
+        return IndexQueue::create(directory).map(Some);
+    }
+    match std::fs::remove_file(directory.join(queue::INDEX_QUEUE_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(None),
+    }
+}
+
+fn update_graph(index: &mut Hnsw, update: GraphUpdate) {
+    match update {
+        GraphUpdate::Insert(key, projected) => index.insert(key, projected),
+        GraphUpdate::Remove(key) => index.remove(key),
+        GraphUpdate::RemoveRange(range) => index.remove_range(range),
+    }
+}
+
+fn build_index(options: HnswOptions, state: &State, tree_options: &Options) -> io::Result<Hnsw> {
+    let mut index = Hnsw::new(options, state.metric);
+    for entry in Iter::new(state.clone(), tree_options.clone()) {
+        let (key, value) = entry?;
+        if let Ok(vector) = state.projection.apply(value.data()) {
+            index.insert(key, vector);
+        }
+    }
+    Ok(index)
+}
+
+fn build_ivf(centroids: Vec<Vec<f64>>, state: &State, options: &Options) -> io::Result<Ivf> {
+    let mut ivf = Ivf::new(centroids, state.metric);
+    for entry in Iter::new(state.clone(), options.clone()) {
+        let (key, value) = entry?;
+        ivf.insert(key, value.data());
+    }
+    Ok(ivf)
+}
+
+// The field indexes of `Options::indexed_fields`, read from the field tables and brought
+// up to date with the writes the log replayed, `replayed`, which is None when the tables
+// index other fields. Then the indexes are built from the stored vectors instead and
+// written out as the only table, or without fields to index the tables are dropped.
+fn open_fields(directory: &Path, manifest: &mut Manifest, state: &State, options: &Options, read_only: bool, replayed: Option<Vec<BatchOp>>, report: &mut StartupReport) -> io::Result<Option<FieldIndex>> {
+    let fields = options.indexed_fields.as_slice();
+    if let Some(ops) = replayed {
+        let mut index = FieldIndex::new(fields);
+        for &number in manifest.field_tables() {
+            let name = manifest::field_table_file_name(number);
+            index.load(&std::fs::read(directory.join(&name))?).map_err(|e| error::locate(e, None, || name.clone()))?;
+        }
+        for update in state.index_updates(ops, options, false)? {
+            update_fields(&mut index, &update);
+        }
+        return Ok(Some(index));
+    }
+    let index = match fields {
+        [] => None,
+        fields => {
+            report.rebuilt_field_indexes = true;
+            let mut index = build_fields(fields, state, options)?;
+            // the table written below holds them
+            index.take_added();
+            Some(index)
+        }
+    };
+    if read_only || manifest.indexed_fields() == fields {
+        return Ok(index);
+    }
+    let stale = manifest.field_tables().to_vec();
+    let mut edits = vec![VersionEdit::SetIndexedFields(fields.to_vec())];
+    edits.extend(stale.iter().map(|&n| VersionEdit::RemoveFieldTable(n)));
+    if let Some(index) = &index {
+        let number = manifest.new_file_number();
+        write_field_table(directory, number, &index.entries(), options.sync_policy != SyncPolicy::Never)?;
+        edits.push(VersionEdit::AddFieldTable(number));
+    }
+    manifest.log(&edits)?;
+    for number in stale {
+        std::fs::remove_file(directory.join(manifest::field_table_file_name(number)))?;
+    }
+    Ok(index)
+}
+
+// Writes a field table of `entries` into `directory`, under a temporary name until it is
+// complete
+fn write_field_table<'a>(directory: &Path, file_number: u64, entries: impl IntoIterator<Item = &'a Vec<u8>>, sync: bool) -> io::Result<()> {
+    let name = manifest::field_table_file_name(file_number);
+    let temp_path = directory.join(format!("{}.tmp", name));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&field::encode_table(entries))?;
+    if sync {
+        file.sync_all()?;
+    }
+    std::fs::rename(&temp_path, directory.join(name))?;
+    match sync {
+        true => sync_dir(directory),
+        false => Ok(()),
+    }
+}
+
+fn update_fields(index: &mut FieldIndex, update: &IndexUpdate) {
+    match update {
+        IndexUpdate::Insert(key, value, _) => index.insert(*key, value.metadata()),
+        IndexUpdate::Remove(key) => index.remove(*key),
+        IndexUpdate::RemoveRange(range) => index.remove_range(range.clone()),
+    }
+}
+
+fn build_fields(fields: &[String], state: &State, options: &Options) -> io::Result<FieldIndex> {
+    let mut index = FieldIndex::new(fields);
+    for entry in Iter::new(state.clone(), options.clone()) {
+        let (key, value) = entry?;
+        index.insert(key, value.metadata());
+    }
+    Ok(index)
+}
+
+// Takes an exclusive lock on the directory's LOCK file, so a second tree opened on the
+// directory, from this process or another, fails instead of writing over the first one's
+// files. The lock goes with the file handle, also when the process dies.
+fn lock_directory(directory: &Path) -> io::Result<File> {
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(directory.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("{} is already open in another tree", directory.display()))),
+        Err(std::fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+// The closest `k` entries to each of `queries` over every chunk received
+fn rank_chunks(queries: &[Vec<f64>], k: usize, scorer: Scorer, chunks: Receiver<Arc<Vec<(u64, Vector)>>>) -> io::Result<Vec<TopK>> {
+    let mut tops: Vec<TopK> = queries.iter().map(|_| TopK::new(k)).collect();
+    for chunk in chunks {
+        for (key, value) in chunk.iter() {
+            for (query, top) in queries.iter().zip(tops.iter_mut()) {
+                if value.data().len() != query.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("query has {} dimensions, key '{}' has {}", query.len(), key, value.data().len())));
+                }
+                top.push(*key, scorer.distance(query, value.data()));
+            }
+        }
+    }
+    Ok(tops)
+}
+
+fn check_options(options: &Options) -> io::Result<()> {
+    if options.max_flush_threads == 0 || options.max_compaction_threads == 0 || options.max_subcompactions == 0 || options.index_build_threads == 0 || options.search_threads == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "every thread pool needs at least one thread"));
+    }
+    if options.prefix_bloom_bits > 64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("prefix of {} bits is longer than a key", options.prefix_bloom_bits)));
+    }
+    if options.prefix_bloom_bits != 0 && options.bloom_bits_per_key == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a prefix filter needs at least one bit per key"));
+    }
+    for (i, field) in options.indexed_fields.iter().enumerate() {
+        if field.is_empty() || options.indexed_fields[..i].contains(field) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("indexed field '{}' is empty or named twice", field)));
+        }
+        // field table entries give the name's length in two bytes
+        if field.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("indexed field name of {} bytes is too long", field.len())));
+        }
+    }
+    if options.index_restart_interval == 0 || options.index_restart_interval > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("index restart interval of {} is out of range", options.index_restart_interval)));
+    }
+    // writes held back at a table count compactions never get below would wait forever
+    for (name, threshold) in [("slowdown_tables", options.slowdown_tables), ("stop_tables", options.stop_tables)] {
+        if threshold != 0 && (options.compaction_trigger == 0 || threshold <= options.compaction_trigger) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} of {} needs a lower compaction_trigger", name, threshold)));
+        }
+    }
+    if options.cold_directory.is_some() && options.storage.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a cold directory can't be combined with a storage"));
+    }
+    // tables are read back with the built-in codec their footer names
+    if codec::by_id(options.codec.id()).is_none_or(|builtin| builtin.name() != options.codec.name()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("codec {} with id {} isn't a built-in codec", options.codec.name(), options.codec.id())));
+    }
+    check_compaction_style(options.compaction_style)
+}
+
+fn check_compaction_style(style: CompactionStyle) -> io::Result<()> {
+    match style {
+        CompactionStyle::Universal { max_space_amplification_percent: 0, .. } => Err(io::Error::new(io::ErrorKind::InvalidInput, "a max_space_amplification_percent of 0 merges everything on every compaction")),
+        CompactionStyle::Leveled { fanout: 0 | 1 } => Err(io::Error::new(io::ErrorKind::InvalidInput, "leveled compaction needs a fanout of at least 2")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+    use crate::db::wal::RecordType;
+    use crate::db::binary::BinaryVector;
+    use crate::db::merge::MergeOperator;
+    use crate::db::stats::Latency;
+    use crate::db::pipeline::Transform;
+    use crate::db::sstable::ReadPath;
+    use std::sync::Arc;
+    use crate::db::vector::MetadataValue;
+    use rand::Rng;
+
+    // Stops the tree the way a crash would, leaving the active memtable in its log
+    fn crash(lsm: LSMTree) {
+        lsm.abandon();
+    }
+
+    #[test]
+    fn test_write_read_memtable() {
+        let mut rng = rand::rng();
+        let path: PathBuf = test_dir("write_read_memtable");
+        let lsm = LSMTree::new(&path).unwrap();
+        let v0 = Vector::new(5, vec![rng.random(), rng.random(), rng.random()]);
+
+        for i in 0..8 {
+            if i == 5 {
+                let _ = lsm.insert(5, v0.clone());
+            } else {
+                let _ = lsm.insert(i, Vector::new(i, vec![rng.random(), rng.random(), rng.random()]));
+            }
+        }
+
+        let val = lsm.get(5);
+        assert_eq!(val.unwrap().unwrap().id(), 5);
+    }
+
+    #[test]
+    fn test_write_read_sstable() {
+        let mut rng = rand::rng();
+        let path: PathBuf = test_dir("write_read_sstable");
+        let lsm = LSMTree::new(&path).unwrap();
+        let v0 = Vector::new(49, vec![rng.random(), rng.random(), rng.random()]);
+
+        for i in 0..100 {
+            if i == 49 {
+                let _ = lsm.insert(49, v0.clone());
+            } else {
+                let _ = lsm.insert(i, Vector::new(i, vec![rng.random(), rng.random(), rng.random()]));
+            }
+        }
+
+        lsm.flush().unwrap();
+
+        let val = lsm.get(49);
+        assert_eq!(val.unwrap().unwrap().id(), 49);
+    }
+
+    #[test]
+    fn test_delete_from_memtable() {
+        let path: PathBuf = test_dir("delete_from_memtable");
+        let lsm = LSMTree::new(&path).unwrap();
+        let k1: u64 = 1;
+        let v1 = Vector::new(k1, vec![0.0, 1.0]);
+        let _ = lsm.insert(1, v1.clone());
+        assert!(lsm.delete(1).unwrap());
+
+        assert_eq!(lsm.inner.state().memtable.len(), 0);
+    }
+
+    #[test]
+    fn test_delete_from_sstable() {
+        let path: PathBuf = test_dir("delete_from_sstable");
+        let lsm = LSMTree::new(&path).unwrap();
+        let k1: u64 = 1;
+        let v1 = Vector::new(k1, vec![0.0, 1.0]);
+        let _ = lsm.insert(1, v1.clone());
+        lsm.flush().unwrap();
+        assert!(lsm.delete(1).is_ok());
+
+        // the table is left as it was, the delete is a tombstone in the memtable
+        assert_eq!(lsm.inner.state().memtable.len(), 0);
+        assert_eq!(*lsm.inner.state().tombstones.iter().collect::<Vec<_>>(), [&1]);
+        assert!(lsm.inner.state().sstables[0].tombstones.is_empty());
+        assert!(lsm.get(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_delete_is_durable() {
+        let path: PathBuf = test_dir("delete_is_durable");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..5 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        // an overwrite in the memtable, then a delete: the flushed value mustn't come back
+        lsm.insert(1, Vector::new(1, vec![-1.0])).unwrap();
+        lsm.delete(1).unwrap();
+        lsm.delete(2).unwrap();
+        assert!(lsm.get(1).unwrap().is_none() && lsm.get(2).unwrap().is_none());
+
+        // nor after flushing an unrelated memtable, a reopen, or compacting the upper tables
+        lsm.insert(10, Vector::new(10, vec![10.0])).unwrap();
+        lsm.flush().unwrap();
+        lsm.insert(11, Vector::new(11, vec![11.0])).unwrap();
+        lsm.flush().unwrap();
+        lsm.close().unwrap();
+        let lsm = LSMTree::open(&path, options).unwrap();
+        let oldest = lsm.describe()[0].file_number;
+        lsm.inner.background().compacting.insert(oldest);
+        let picked: Vec<u64> = lsm.inner.state().sstables[1..].iter().map(|t| t.file_number).collect();
+        lsm.inner.background().compacting.extend(picked.iter().copied());
+        lsm.inner.run_job(Job::Compaction { start: 1, picked }).unwrap();
+        lsm.inner.background().compacting.clear();
+        let keys = |lsm: &LSMTree| lsm.keys().map(|key| key.unwrap()).collect::<Vec<u64>>();
+        assert_eq!(keys(&lsm), vec![0, 3, 4, 10, 11]);
+        assert_eq!(lsm.describe().iter().map(|t| t.tombstones).collect::<Vec<_>>(), vec![0, 2]);
+
+        // a full compaction drops the tombstones with what they hid
+        lsm.compact().unwrap();
+        assert_eq!(keys(&lsm), vec![0, 3, 4, 10, 11]);
+        assert_eq!(lsm.describe().iter().map(|t| t.tombstones).collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_delete_no_key() {
+        let path: PathBuf = test_dir("delete_no_key");
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(!lsm.delete(1).unwrap());
+        assert_eq!(lsm.sequence(), 0);
+    }
+
+    #[test]
+    fn test_delete_prevents_get_memtable() {
+        let path: PathBuf = test_dir("delete_prevents_get_memtable");
+        let lsm = LSMTree::new(&path).unwrap();
+        let k1: u64 = 1;
+        let v1 = Vector::new(k1, vec![0.0, 1.0]);
+        let _ = lsm.insert(1, v1.clone());
+        assert!(lsm.delete(1).is_ok());
+
+        assert!(lsm.get(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_delete_prevents_get_sstable() {
+        let path: PathBuf = test_dir("delete_prevents_get_sstable");
+        let lsm = LSMTree::new(&path).unwrap();
+        let k1: u64 = 1;
+        let v1 = Vector::new(k1, vec![0.0, 1.0]);
+        let _ = lsm.insert(1, v1.clone());
+        lsm.flush().unwrap();
+        assert!(lsm.delete(1).is_ok());
+
+        assert!(lsm.get(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reopen_loads_sstables_from_manifest() {
+        let path: PathBuf = test_dir("reopen_loads_sstables_from_manifest");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.inner.wait_for_flushes().unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 2);
+        crash(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 2);
+        assert_eq!(lsm.inner.writer().sequence, 25);
+        assert_eq!(lsm.get(13).unwrap().unwrap().data(), &vec![13.0]);
+        assert_eq!(lsm.get(24).unwrap().unwrap().data(), &vec![24.0]);
+
+        // new tables must not reuse the file numbers of existing ones
+        for i in 100..110 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.inner.wait_for_flushes().unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 3);
+        assert_eq!(lsm.inner.writer().manifest.live_tables().len(), 3);
+        assert_eq!(lsm.get(5).unwrap().unwrap().id(), 5);
+    }
+
+    #[test]
+    fn test_pipeline_applied_and_persisted() {
+        let path: PathBuf = test_dir("pipeline_applied_and_persisted");
+        let lsm = LSMTree::new(&path).unwrap();
+        let pipeline = Pipeline::new(vec![Transform::Dimension(2), Transform::Normalize]).unwrap();
+        lsm.set_pipeline(pipeline.clone()).unwrap();
+
+        lsm.insert(1, Vector::new(1, vec![3.0, 4.0])).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![0.6, 0.8]);
+        assert!(lsm.insert(2, Vector::new(2, vec![1.0])).is_err());
+        assert!(lsm.set_pipeline(Pipeline::default()).is_err());
+        drop(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.pipeline(), pipeline);
+        assert_eq!(lsm.prepare_query(&[0.0, 2.0]).unwrap(), vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_projection_trained_and_persisted() {
+        let path: PathBuf = test_dir("projection_trained_and_persisted");
+        let lsm = LSMTree::new(&path).unwrap();
+        let sample: Vec<Vec<f64>> = (0..50).map(|i| vec![i as f64, i as f64, 0.0]).collect();
+        lsm.train_projection(&sample, 1).unwrap();
+
+        lsm.insert(1, Vector::new(1, vec![1.0, 1.0, 0.0])).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().data().len(), 3);
+        drop(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        let projected = lsm.project(&[1.0, 1.0, 0.0]).unwrap();
+        assert_eq!(projected.len(), 1);
+        assert!((projected[0].abs() - 2.0f64.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let path: PathBuf = test_dir("write_batch");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(2, Vector::new(2, vec![2.0])).put(3, Vector::new(3, vec![3.0])).delete(1).delete(99);
+        lsm.write(batch).unwrap();
+
+        assert!(lsm.get(1).unwrap().is_none());
+        assert_eq!(lsm.get(2).unwrap().unwrap().id(), 2);
+        assert_eq!(lsm.get(3).unwrap().unwrap().id(), 3);
+        assert_eq!(lsm.inner.writer().sequence, 5);
+    }
+
+    #[test]
+    fn test_write_batch_all_or_nothing() {
+        let path: PathBuf = test_dir("write_batch_all_or_nothing");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.set_pipeline(Pipeline::new(vec![Transform::Dimension(1)]).unwrap()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(1, Vector::new(1, vec![1.0])).put(2, Vector::new(2, vec![1.0, 2.0]));
+        assert!(lsm.write(batch).is_err());
+        assert!(lsm.get(1).unwrap().is_none());
+        drop(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(lsm.get(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_recover_memtable_from_wal() {
+        let path: PathBuf = test_dir("recover_memtable_from_wal");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..13 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete(11).delete(2);
+        lsm.write(batch).unwrap();
+        crash(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.inner.state().memtable.len(), 2);
+        assert_eq!(lsm.inner.writer().sequence, 15);
+        assert_eq!(lsm.get(12).unwrap().unwrap().id(), 12);
+        assert!(lsm.get(11).unwrap().is_none());
+        assert!(lsm.get(2).unwrap().is_none());
+        assert_eq!(wal::list_wals(&path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_metadata_persisted() {
+        let path: PathBuf = test_dir("metadata_persisted");
+        let lsm = LSMTree::new(&path).unwrap();
+        let tagged = |i: u64| Vector::new(i, vec![i as f64]).with_metadata("doc", format!("doc-{}", i)).with_metadata("rank", i as i64);
+        for i in 0..12 {
+            lsm.insert(i, tagged(i)).unwrap();
+        }
+        lsm.insert(12, tagged(12).with_metadata("score", 0.5).with_metadata("draft", true)).unwrap();
+        assert_eq!(lsm.get(12).unwrap().unwrap(), tagged(12).with_metadata("score", 0.5).with_metadata("draft", true));
+        drop(lsm);
+
+        // the older records come back from a table, the newest from the log
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.get(3).unwrap().unwrap().metadata()["doc"], MetadataValue::from("doc-3"));
+        assert_eq!(lsm.get(3).unwrap().unwrap().metadata()["rank"], MetadataValue::Int(3));
+        let latest = lsm.get(12).unwrap().unwrap();
+        assert_eq!(latest.metadata()["score"], MetadataValue::Float(0.5));
+        assert_eq!(latest.metadata()["draft"], MetadataValue::Bool(true));
+        assert!(lsm.knn(&[4.0], 1).unwrap().iter().all(|(key, _)| lsm.get(*key).unwrap().unwrap().metadata().contains_key("doc")));
+    }
+
+    #[test]
+    fn test_recover_drops_torn_batch() {
+        let path: PathBuf = test_dir("recover_drops_torn_batch");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        let wal_path = path.join(wal::wal_file_name(lsm.inner.writer().wal.number()));
+        crash(lsm);
+
+        let mut batch = WriteBatch::new();
+        batch.put(2, Vector::new(2, vec![2.0])).put(3, Vector::new(3, vec![3.0]));
+        let record = batch.encode(2).unwrap();
+        let mut frame = (record.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&record[..record.len() / 2]);
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(&frame).unwrap();
+        drop(file);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().id(), 1);
+        assert!(lsm.get(2).unwrap().is_none());
+        assert!(lsm.get(3).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_recover_drops_corrupt_batch() {
+        let path: PathBuf = test_dir("recover_drops_corrupt_batch");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.insert(2, Vector::new(2, vec![2.0])).unwrap();
+        let wal_path = path.join(wal::wal_file_name(lsm.inner.writer().wal.number()));
+        crash(lsm);
+
+        // the last frame is whole but its value didn't make it to disk intact
+        let mut contents = std::fs::read(&wal_path).unwrap();
+        let len = contents.len();
+        contents[len - 1] ^= 0xff;
+        std::fs::write(&wal_path, &contents).unwrap();
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().id(), 1);
+        assert!(lsm.get(2).unwrap().is_none());
+        assert!(lsm.startup_report().warnings.iter().any(|warning| warning.contains("torn or corrupt")));
+        lsm.insert(3, Vector::new(3, vec![3.0])).unwrap();
+        drop(lsm);
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.get(3).unwrap().unwrap().id(), 3);
+        assert!(lsm.startup_report().warnings.is_empty());
+    }
+
+    #[test]
+    fn test_recover_stops_at_torn_log() {
+        for wal_recovery in [WalRecovery::PointInTime, WalRecovery::TolerateCorruption] {
+            let path: PathBuf = test_dir(&format!("recover_stops_at_torn_log_{:?}", wal_recovery));
+            let options = Options { wal_recovery, ..Options::default() };
+            let lsm = LSMTree::open(&path, options.clone()).unwrap();
+            lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+            lsm.insert(2, Vector::new(2, vec![2.0])).unwrap();
+            let number = lsm.inner.writer().wal.number();
+            crash(lsm);
+
+            // the first log lost its last record, and a newer one holds a later write
+            let wal_path = path.join(wal::wal_file_name(number));
+            let contents = std::fs::read(&wal_path).unwrap();
+            std::fs::write(&wal_path, &contents[..contents.len() - 1]).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(3, Vector::new(3, vec![3.0]));
+            let mut newer = Wal::create(&path, number + 1).unwrap();
+            newer.append(RecordType::BatchCommit, &batch.encode(3).unwrap()).unwrap();
+            drop(newer);
+
+            let lsm = LSMTree::open(&path, options).unwrap();
+            assert_eq!(lsm.get(1).unwrap().unwrap().id(), 1);
+            assert!(lsm.get(2).unwrap().is_none());
+            let point_in_time = wal_recovery == WalRecovery::PointInTime;
+            assert_eq!(lsm.get(3).unwrap().is_none(), point_in_time);
+            assert_eq!(lsm.startup_report().warnings.iter().any(|warning| warning.contains("written after the torn")), point_in_time);
+            assert_eq!(path.join(wal::wal_file_name(number + 1)).exists(), !point_in_time);
+
+            lsm.insert(4, Vector::new(4, vec![4.0])).unwrap();
+            drop(lsm);
+            let lsm = LSMTree::new(&path).unwrap();
+            assert_eq!(lsm.get(3).unwrap().is_none(), point_in_time);
+            assert_eq!(lsm.get(4).unwrap().unwrap().id(), 4);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_readers_and_writer() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LSMTree>();
+
+        let path: PathBuf = test_dir("concurrent_readers_and_writer");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..50 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 50..200 {
+                    lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+                }
+            });
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for round in 0..20 {
+                        for i in 0..50 {
+                            let value = lsm.get(i).unwrap().unwrap_or_else(|| panic!("key {} missing in round {}", i, round));
+                            assert_eq!(value.data(), &vec![i as f64]);
+                        }
+                    }
+                });
+            }
+        });
+
+        for i in 0..200 {
+            assert_eq!(lsm.get(i).unwrap().unwrap().id(), i);
+        }
+    }
+
+    // Treats each operand as a little-endian f64 appended to the vector
+    struct AppendOperator;
+
+    impl MergeOperator for AppendOperator {
+        fn full_merge(&self, key: u64, existing: Option<&Vector>, operands: &[Vec<u8>]) -> Option<Vector> {
+            let mut data = existing.map(|v| v.data().clone()).unwrap_or_default();
+            for op in operands {
+                data.push(f64::from_le_bytes(op[..8].try_into().unwrap()));
+            }
+            Some(Vector::new(key, data))
+        }
+    }
+
+    fn merge_options() -> Options {
+        Options { merge_operator: Some(Arc::new(AppendOperator)), ..Options::default() }
+    }
+
+    #[test]
+    fn test_merge_folds_on_read() {
+        let path: PathBuf = test_dir("merge_folds_on_read");
+        let lsm = LSMTree::open(&path, merge_options()).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.merge(1, 2.0f64.to_le_bytes().to_vec()).unwrap();
+        lsm.merge(1, 3.0f64.to_le_bytes().to_vec()).unwrap();
+        lsm.merge(2, 5.0f64.to_le_bytes().to_vec()).unwrap();
+
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![1.0, 2.0, 3.0]);
+        assert_eq!(lsm.get(2).unwrap().unwrap().data(), &vec![5.0]);
+
+        // a put replaces pending operands
+        lsm.insert(1, Vector::new(1, vec![9.0])).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![9.0]);
+    }
+
+    #[test]
+    fn test_merge_survives_flush_and_reopen() {
+        let path: PathBuf = test_dir("merge_survives_flush_and_reopen");
+        let lsm = LSMTree::open(&path, merge_options()).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.flush().unwrap();
+        lsm.merge(1, 2.0f64.to_le_bytes().to_vec()).unwrap();
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, merge_options()).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![1.0, 2.0]);
+        lsm.flush().unwrap();
+        assert!(lsm.inner.state().merges.is_empty());
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_merge_requires_operator() {
+        let path: PathBuf = test_dir("merge_requires_operator");
+        let lsm = LSMTree::new(&path).unwrap();
+        let err = lsm.merge(1, vec![0; 8]).unwrap_err();
+        assert!(matches!(err, LsmError::InvalidArgument(_)));
+    }
+
+    #[test]
+    fn test_insert_fetch() {
+        let path: PathBuf = test_dir("insert_fetch");
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(lsm.insert_fetch(1, Vector::new(1, vec![1.0])).unwrap().is_none());
+        assert_eq!(lsm.insert_fetch(1, Vector::new(1, vec![2.0])).unwrap().unwrap().data(), &vec![1.0]);
+        lsm.flush().unwrap();
+        assert_eq!(lsm.insert_fetch(1, Vector::new(1, vec![3.0])).unwrap().unwrap().data(), &vec![2.0]);
+        lsm.flush().unwrap();
+        lsm.delete(1).unwrap();
+        assert!(lsm.insert_fetch(1, Vector::new(1, vec![4.0])).unwrap().is_none());
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![4.0]);
+
+        // each insert sees the one before it, so every value is handed back exactly once
+        let lsm = &lsm;
+        let mut returned: Vec<f64> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4).map(|t| s.spawn(move || {
+                (0..25).map(|i| lsm.insert_fetch(1, Vector::new(1, vec![(5 + t * 25 + i) as f64])).unwrap().unwrap().data()[0]).collect::<Vec<f64>>()
+            })).collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+        returned.push(lsm.get(1).unwrap().unwrap().data()[0]);
+        returned.sort_by(f64::total_cmp);
+        assert_eq!(returned, (4..105).map(|i| i as f64).collect::<Vec<f64>>());
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let path: PathBuf = test_dir("get_or_insert_with");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        assert_eq!(lsm.get_or_insert_with(1, || unreachable!()).unwrap().data(), &vec![1.0]);
+        assert_eq!(lsm.get_or_insert_with(2, || Vector::new(2, vec![2.0])).unwrap().data(), &vec![2.0]);
+        assert_eq!(lsm.get(2).unwrap().unwrap().data(), &vec![2.0]);
+
+        // racing callers for a missing key compute it once and all get the same value
+        let computed = std::sync::atomic::AtomicUsize::new(0);
+        let lsm = &lsm;
+        let values: Vec<Vector> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8).map(|t| {
+                let computed = &computed;
+                s.spawn(move || lsm.get_or_insert_with(3, || {
+                    computed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    Vector::new(3, vec![t as f64])
+                }).unwrap())
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert_eq!(computed.into_inner(), 1);
+        assert!(values.iter().all(|value| *value == values[0]));
+        assert_eq!(lsm.get(3).unwrap().unwrap(), values[0]);
+    }
+
+    #[test]
+    fn test_put_if_absent_and_compare_and_swap() {
+        let path: PathBuf = test_dir("compare_and_swap");
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(lsm.put_if_absent(1, Vector::new(1, vec![1.0])).unwrap());
+        assert!(!lsm.put_if_absent(1, Vector::new(1, vec![2.0])).unwrap());
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![1.0]);
+
+        let stale = Vector::new(1, vec![9.0]);
+        assert!(!lsm.compare_and_swap(1, Some(&stale), Vector::new(1, vec![3.0])).unwrap());
+        assert!(!lsm.compare_and_swap(1, None, Vector::new(1, vec![3.0])).unwrap());
+        let current = lsm.get(1).unwrap().unwrap();
+        assert!(lsm.compare_and_swap(1, Some(&current), Vector::new(1, vec![3.0])).unwrap());
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![3.0]);
+
+        // a flushed or deleted key is compared the same way
+        lsm.flush().unwrap();
+        assert!(!lsm.put_if_absent(1, Vector::new(1, vec![4.0])).unwrap());
+        lsm.delete(1).unwrap();
+        assert!(lsm.compare_and_swap(1, None, Vector::new(1, vec![5.0])).unwrap());
+
+        // concurrent increments each retry until their swap lands, so none are lost
+        lsm.insert(2, Vector::new(2, vec![0.0])).unwrap();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        loop {
+                            let current = lsm.get(2).unwrap().unwrap();
+                            let next = Vector::new(2, vec![current.data()[0] + 1.0]);
+                            if lsm.compare_and_swap(2, Some(&current), next).unwrap() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(lsm.get(2).unwrap().unwrap().data(), &vec![100.0]);
+    }
+
+    #[test]
+    fn test_compact_range() {
+        let path: PathBuf = test_dir("compact_range");
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 0, ..Options::default() }).unwrap();
+        // a table of the other tenant on either side of the two holding this one's keys
+        for keys in [1000..1010, 0..10, 10..20, 2000..2010] {
+            for i in keys {
+                lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+            }
+            lsm.flush().unwrap();
+        }
+        let before: Vec<u64> = lsm.describe().iter().map(|t| t.file_number).collect();
+        lsm.delete_range(0, 100).unwrap();
+        lsm.compact_range(0, 100).unwrap();
+
+        // the delete went into a fifth table, merged with the two before it and the other
+        // tenant's table in between
+        let after = lsm.describe();
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0].file_number, before[0]);
+        assert_eq!((after[1].entries, after[1].key_range), (10, Some((2000, 2009))));
+        // and having nothing left to mask in the oldest table, the delete went too
+        assert_eq!(after[1].range_tombstones, 0);
+        assert!(lsm.range(0..100).unwrap().is_empty());
+        assert_eq!(lsm.len().unwrap(), 20);
+
+        // nothing overlaps these
+        lsm.compact_range(5000, 6000).unwrap();
+        lsm.compact_range(6000, 5000).unwrap();
+        assert_eq!(lsm.describe().len(), 2);
+
+        // a range reaching the oldest table takes in every table up to the last it touches
+        lsm.compact_range(0, 2001).unwrap();
+        let after = lsm.describe();
+        assert_eq!((after.len(), after[0].entries), (1, 20));
+    }
+
+    #[test]
+    fn test_read_compaction_hints() {
+        let path: PathBuf = test_dir("read_compaction_hints");
+        let options = Options { sstable_size: 1000, compaction_trigger: 100, read_compaction_skips: 50, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..200 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        // a table of others' keys in between, which the hinted compaction has to take in
+        lsm.insert(5000, Vector::new(5000, vec![0.0])).unwrap();
+        lsm.flush().unwrap();
+        for i in 0..60 {
+            lsm.delete(i).unwrap();
+        }
+        for i in 150..170 {
+            lsm.insert(i, Vector::new(i, vec![-1.0])).unwrap();
+        }
+        lsm.flush().unwrap();
+        let hints = || lsm.inner.state().read_hints.pending();
+        let before: Vec<u64> = lsm.describe().iter().map(|t| t.file_number).collect();
+
+        // 20 overwritten entries aren't enough
+        assert_eq!(lsm.range(100..200).unwrap().len(), 100);
+        assert!(hints().is_empty());
+        // 60 deleted ones are, for the keys they were found under
+        let mut iter = lsm.iter_rev();
+        assert_eq!(iter.next().unwrap().unwrap().0, 5000);
+        assert_eq!(iter.count(), 140);
+        let started = Instant::now();
+        while lsm.stats().read_compactions == 0 {
+            assert!(started.elapsed() < Duration::from_secs(10), "no compaction for {:?}", hints());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        lsm.inner.wait_for_idle().unwrap();
+
+        // the oldest table was in it, so the deleted entries are gone for good
+        let tables = lsm.describe();
+        assert_eq!(tables.len(), 1);
+        assert_eq!((tables[0].entries, tables[0].tombstones), (141, 0));
+        assert_eq!(lsm.get(160).unwrap().unwrap().data(), &[-1.0]);
+        assert_eq!(lsm.stats().read_compactions, 1);
+
+        // a hint over tables compacted away meanwhile is dropped unused
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.flush().unwrap();
+        let state = lsm.inner.state().clone();
+        state.read_hints.record(ReadHint { range: 0..10, tables: BTreeSet::from([before[0]]) });
+        assert!(lsm.inner.claim_read_hint(&state, &mut lsm.inner.background()).is_none());
+        assert!(hints().is_empty());
+        assert_eq!((lsm.describe().len(), lsm.stats().read_compactions), (2, 1));
+    }
+
+    #[test]
+    fn test_subcompactions() {
+        let path: PathBuf = test_dir("subcompactions");
+        let options = Options { sstable_size: 10, compaction_trigger: 0, max_subcompactions: 4, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        let mut model = BTreeMap::new();
+        for i in 0..200u64 {
+            let key = i * 7 % 200;
+            lsm.insert(key, Vector::new(key, vec![i as f64])).unwrap();
+            model.insert(key, i as f64);
+        }
+        for key in [3, 50, 51, 199] {
+            lsm.delete(key).unwrap();
+            model.remove(&key);
+        }
+        lsm.flush().unwrap();
+        // the oldest table isn't in the merge, so the tombstones have to stay
+        let oldest = lsm.describe()[0].file_number;
+        lsm.inner.background().compacting.insert(oldest);
+        let picked: Vec<u64> = lsm.inner.state().sstables[1..].iter().map(|t| t.file_number).collect();
+        lsm.inner.background().compacting.extend(picked.iter().copied());
+        lsm.inner.run_job(Job::Compaction { start: 1, picked }).unwrap();
+        lsm.inner.background().compacting.clear();
+        assert!(lsm.describe().len() > 2);
+        assert!([3, 50, 51, 199].iter().all(|&key| lsm.get(key).unwrap().is_none()));
+        assert_eq!(lsm.iter().count(), model.len());
+        lsm.delete_range(100, 120).unwrap();
+        model.retain(|&k, _| !(100..120).contains(&k));
+        lsm.compact().unwrap();
+
+        // four tables of disjoint key ranges, in key order, making up one run
+        let check = |lsm: &LSMTree| {
+            let tables = lsm.describe();
+            assert_eq!(tables.len(), 4);
+            assert!(tables.iter().all(|t| t.level == 0 && t.tombstones == 0 && t.range_tombstones == 0));
+            assert!(tables.windows(2).all(|w| w[0].key_range.unwrap().1 < w[1].key_range.unwrap().0));
+            let entries: Vec<(u64, f64)> = lsm.iter().map(|e| e.unwrap()).map(|(k, v)| (k, v.data()[0])).collect();
+            assert_eq!(entries, model.iter().map(|(&k, &x)| (k, x)).collect::<Vec<_>>());
+            for (&key, &x) in model.iter() {
+                assert_eq!(lsm.get(key).unwrap().unwrap().data(), &vec![x]);
+            }
+        };
+        check(&lsm);
+        assert_eq!(lsm.stats().compactions, 2);
+        drop(lsm);
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        check(&lsm);
+        drop(lsm);
+
+        // the run counts once towards the trigger, and is merged whole with a newer table
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 2, max_subcompactions: 2, ..options }).unwrap();
+        check(&lsm);
+        for key in 0..10 {
+            lsm.insert(key, Vector::new(key, vec![-1.0])).unwrap();
+            model.insert(key, -1.0);
+        }
+        lsm.inner.wait_for_idle().unwrap();
+        assert_eq!(lsm.describe().iter().map(|t| t.level).collect::<Vec<_>>(), vec![0, 0]);
+        lsm.compact_range(0, 5).unwrap();
+        assert_eq!(lsm.describe().len(), 2);
+        assert_eq!(lsm.iter().count(), model.len());
+    }
+
+    #[test]
+    fn test_compaction_drops_unneeded_tombstones() {
+        let path: PathBuf = test_dir("compaction_drops_unneeded_tombstones");
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 0, ..Options::default() }).unwrap();
+        for keys in [1..5, 10..15] {
+            for i in keys {
+                lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+            }
+            lsm.flush().unwrap();
+        }
+        lsm.delete(14).unwrap();
+        lsm.delete_range(10, 13).unwrap();
+        lsm.delete_range(3, 4).unwrap();
+        lsm.insert(20, Vector::new(20, vec![0.0])).unwrap();
+        lsm.flush().unwrap();
+
+        // the oldest table isn't an input, but holds none of 10..15
+        lsm.compact_range(10, 15).unwrap();
+        let tables = lsm.describe();
+        assert_eq!(tables.len(), 2);
+        assert_eq!((tables[1].entries, tables[1].tombstones, tables[1].range_tombstones), (2, 0, 1));
+        let keys: Vec<u64> = lsm.range(..).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![1, 2, 4, 13, 20]);
+    }
+
+    #[test]
+    fn test_describe() {
+        let path: PathBuf = test_dir("describe");
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 0, ..Options::default() }).unwrap();
+        assert!(lsm.describe().is_empty());
+        for i in 5..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.delete_range(0, 3).unwrap();
+        lsm.insert(20, Vector::new(20, vec![0.0])).unwrap();
+        lsm.delete(6).unwrap();
+        lsm.flush().unwrap();
+
+        let tables = lsm.describe();
+        assert_eq!(tables.len(), 2);
+        assert_eq!((tables[0].key_range, tables[0].entries, tables[0].tombstones, tables[0].level), (Some((5, 9)), 5, 0, 0));
+        assert_eq!((tables[1].key_range, tables[1].tombstones, tables[1].range_tombstones, tables[1].level), (Some((20, 20)), 1, 1, 1));
+        assert_eq!(tables[1].file_name, manifest::table_file_name(tables[1].file_number));
+        assert_eq!(tables[1].file_size, std::fs::metadata(path.join(&tables[1].file_name)).unwrap().len());
+        assert_eq!(tables[1].format_version, sstable::FORMAT_VERSION);
+
+        lsm.compact().unwrap();
+        assert_eq!(lsm.describe().iter().map(|t| (t.entries, t.key_range)).collect::<Vec<_>>(), vec![(5, Some((5, 20)))]);
+    }
+
+    #[test]
+    fn test_startup_report() {
+        let path: PathBuf = test_dir("startup_report");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..12 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        crash(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        let report = lsm.startup_report();
+        assert!(report.passed());
+        assert_eq!(report.table_count, 1);
+        assert_eq!(report.table_format_versions, vec![sstable::FORMAT_VERSION]);
+        assert_eq!(report.replayed_batches, 2);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_open_refuses_failed_preflight() {
+        let path: PathBuf = test_dir("open_refuses_failed_preflight");
+        let options = Options { min_open_files: u64::MAX, ..Options::default() };
+        let err = LSMTree::open(&path, options).err().unwrap();
+        assert!(err.to_string().contains("open_files_limit"));
+        assert!(!path.join(manifest::MANIFEST_FILE).exists());
+    }
+
+    #[test]
+    fn test_flush_leaves_no_temp_files() {
+        let path: PathBuf = test_dir("flush_leaves_no_temp_files");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.flush().unwrap();
+
+        let names: Vec<String> = std::fs::read_dir(&path).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(names.iter().any(|n| n.starts_with("sstable_") && n.ends_with(".sdb")));
+        assert!(!names.iter().any(|n| n.ends_with(".tmp")));
+    }
+
+    #[test]
+    fn test_memtable_bytes() {
+        let path: PathBuf = test_dir("memtable_bytes");
+        let options = Options { sstable_size: 1000, memtable_bytes: 16 * 1024, compaction_trigger: 0, fix_dimension: false, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        let live = |state: &State| state.memtable.sizes((Bound::Unbounded, Bound::Unbounded)).map(|(_, size)| size).sum::<usize>();
+
+        // overwrites and deletes leave their entries in the arena until the flush, and
+        // give back only their place in the index
+        for i in 0..4 {
+            lsm.insert(i, Vector::new(i, vec![1.0; 256])).unwrap();
+        }
+        let four = lsm.inner.state().memtable_bytes;
+        lsm.insert(0, Vector::new(0, vec![1.0; 8])).unwrap();
+        lsm.delete(1).unwrap();
+        lsm.delete_range(3, 4).unwrap();
+        let state = lsm.inner.state();
+        assert_eq!(state.memtable.len(), 2);
+        assert_eq!(state.memtable_bytes, state.memtable.bytes());
+        assert!(live(&state) < four / 2 && state.memtable_bytes > live(&state) + 4 * 1024);
+        drop(state);
+
+        // 2 KiB vectors fill the budget long before the entry count
+        for i in 10..20 {
+            lsm.insert(i, Vector::new(i, vec![1.0; 256])).unwrap();
+        }
+        lsm.flush().unwrap();
+        let state = lsm.inner.state();
+        assert_eq!(state.sstables.len(), 2);
+        assert!(state.memtable.is_empty());
+        assert_eq!(state.memtable_bytes, 0);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let path: PathBuf = test_dir("memory_budget");
+        let unbudgeted = Options {
+            sstable_size: 1000,
+            compaction_trigger: 0,
+            prefix_bloom_bits: 8,
+            read_path: ReadPath::Pread,
+            block_cache_bytes: 1 << 20,
+            ..Options::default()
+        };
+        let options = Options { memory_budget_bytes: 64 * 1024, ..unbudgeted.clone() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+
+        // 2 KiB vectors freeze the memtable well before the entry count
+        for i in 0..100 {
+            lsm.insert(i, Vector::new(i, vec![i as f64; 256])).unwrap();
+            assert!(lsm.stats().memtable_bytes <= 64 * 1024);
+        }
+        lsm.flush().unwrap();
+        let stats = lsm.stats();
+        assert!(stats.memory_flushes > 1);
+        assert_eq!(stats.table_count as u64, stats.memory_flushes + 1);
+        assert!(stats.filter_bytes > 0 && stats.index_bytes > 0);
+
+        // the block cache keeps what the rest leaves over
+        for i in 0..100 {
+            assert_eq!(lsm.get(i).unwrap().unwrap().data(), &vec![i as f64; 256]);
+        }
+        let stats = lsm.stats();
+        assert!(stats.block_cache_bytes > 0);
+        assert!(stats.memory_bytes() <= 64 * 1024);
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, unbudgeted).unwrap();
+        for i in 0..100 {
+            lsm.get(i).unwrap();
+        }
+        let stats = lsm.stats();
+        assert!(stats.block_cache_bytes > 64 * 1024);
+        assert_eq!(stats.memory_flushes, 0);
+    }
+
+    #[test]
+    fn test_direct_io_writes() {
+        let path: PathBuf = test_dir("direct_io_writes");
+        let options = Options { sstable_size: 10, compaction_trigger: 3, direct_io_writes: true, fix_dimension: false, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..50 {
+            lsm.insert(i, Vector::new(i, vec![i as f64; 1 + i as usize * 37])).unwrap();
+        }
+        lsm.compact().unwrap();
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..50 {
+            assert_eq!(lsm.get(i).unwrap().unwrap().data().len(), 1 + i as usize * 37);
+        }
+    }
+
+    #[test]
+    fn test_compaction_rate_limit() {
+        let path: PathBuf = test_dir("compaction_rate_limit");
+        let options = Options { sstable_size: 50, compaction_trigger: 0, compaction_bytes_per_sec: 256 * 1024, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..400 {
+            lsm.insert(i, Vector::new(i, vec![i as f64; 64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        // flushes aren't paced
+        assert_eq!(lsm.stats().compaction_throttled_micros, 0);
+
+        // about 500 KiB read and written, half a second past what the bucket starts with
+        let start = Instant::now();
+        lsm.compact().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400), "{:?}", start.elapsed());
+        let stats = lsm.stats();
+        assert!(stats.compaction_throttled_micros > 0);
+        assert!(!stats.compaction_throttled);
+        assert_eq!(lsm.get(399).unwrap().unwrap().data(), &vec![399.0; 64]);
+    }
+
+    #[test]
+    fn test_pread_read_path() {
+        let path: PathBuf = test_dir("pread_read_path");
+        let options = Options { sstable_size: 10, read_path: ReadPath::Pread, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        assert!(lsm.inner.state().sstables.iter().all(|t| !t.is_mapped()));
+        assert_eq!(lsm.get(17).unwrap().unwrap().data(), &vec![17.0]);
+        assert_eq!(lsm.iter().count(), 25);
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let path: PathBuf = test_dir("block_cache");
+        let options = Options { sstable_size: 10, compaction_trigger: 0, read_path: ReadPath::Pread, block_cache_bytes: 64 * 1024, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..20 {
+            lsm.insert(i, Vector::new(i, vec![i as f64; 8])).unwrap();
+        }
+        lsm.flush().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(lsm.get(3).unwrap().unwrap().data(), &vec![3.0; 8]);
+        }
+        let stats = lsm.stats();
+        assert_eq!((stats.block_cache_hits, stats.block_cache_misses), (2, 1));
+        assert!(stats.block_cache_bytes > 0);
+
+        // compacted tables get new file numbers, so their entries are read afresh
+        lsm.compact().unwrap();
+        assert_eq!(lsm.get(3).unwrap().unwrap().data(), &vec![3.0; 8]);
+        assert_eq!(lsm.stats().block_cache_misses, 2);
+        assert_eq!(lsm.iter().count(), 20);
+
+        let unbudgeted = Options { block_cache_bytes: 0, ..options };
+        drop(lsm);
+        let lsm = LSMTree::open(&path, unbudgeted).unwrap();
+        lsm.get(3).unwrap();
+        assert_eq!(lsm.stats().block_cache_misses, 0);
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let path: PathBuf = test_dir("row_cache");
+        let options = Options { sstable_size: 10, row_cache_entries: 4, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..20 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.get(1).unwrap();
+        lsm.get(1).unwrap();
+        let stats = lsm.stats();
+        assert_eq!((stats.row_cache_hits, stats.row_cache_misses), (1, 1));
+
+        // every kind of write reaches past the cache
+        lsm.get(2).unwrap();
+        lsm.get(3).unwrap();
+        lsm.insert(1, Vector::new(1, vec![10.0])).unwrap();
+        lsm.delete(2).unwrap();
+        lsm.delete_range(3, 4).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![10.0]);
+        assert!(lsm.get(2).unwrap().is_none() && lsm.get(3).unwrap().is_none());
+        lsm.bulk_load([(1, Vector::new(1, vec![100.0]))]).unwrap();
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![100.0]);
+
+        // a cached value still expires
+        lsm.insert_with_ttl(5, Vector::new(5, vec![5.0]), Duration::from_millis(20)).unwrap();
+        lsm.get(5).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(lsm.get(5).unwrap().is_none());
+        assert_eq!(lsm.stats().row_cache_hits, 2);
+    }
+
+    #[test]
+    fn test_get_reports_corrupt_entries() {
+        let path: PathBuf = test_dir("get_reports_corrupt_entries");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.flush().unwrap();
+        let name = manifest::table_file_name(lsm.inner.state().sstables[0].file_number);
+        let table = path.join(&name);
+        drop(lsm);
+
+        // the first entry's value length, after its key and flags
+        let mut bytes = std::fs::read(&table).unwrap();
+        bytes[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&table, &bytes).unwrap();
+
+        let lsm = LSMTree::new(&path).unwrap();
+        // reported where the entry is
+        match lsm.get(1) {
+            Err(LsmError::Corruption { file, offset, .. }) => assert_eq!((file.as_deref(), offset), (Some(name.as_str()), Some(0))),
+            read => panic!("{:?}", read),
+        }
+        assert!(lsm.get(2).unwrap().is_none());
+        assert!(matches!(lsm.snapshot().get(1), Err(LsmError::Corruption { .. })));
+    }
+
+    #[test]
+    fn test_verify_and_repair() {
+        let path: PathBuf = test_dir("verify_and_repair");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for keys in [1..4, 10..12] {
+            for i in keys {
+                lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+            }
+            lsm.flush().unwrap();
+        }
+        let report = lsm.verify().unwrap();
+        assert!(report.passed());
+        assert_eq!(report.tables.iter().map(|t| t.entries).collect::<Vec<_>>(), vec![3, 2]);
+        let damaged = lsm.inner.state().sstables[0].file_number;
+        drop(lsm);
+
+        let table = path.join(manifest::table_file_name(damaged));
+        let mut bytes = std::fs::read(&table).unwrap();
+        let at = bytes.windows(8).position(|w| w == 2.0f64.to_le_bytes()).unwrap();
+        bytes[at + 7] ^= 0x01;
+        std::fs::write(&table, &bytes).unwrap();
+
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        let report = lsm.verify().unwrap();
+        let errors: Vec<&TableReport> = report.damaged().collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].file_number, damaged);
+        assert!(errors[0].errors[0].contains("checksum"), "{:?}", errors[0].errors);
+
+        let report = lsm.repair().unwrap();
+        let repaired = report.damaged().next().unwrap();
+        assert_eq!(repaired.lost_entries, 1);
+        assert!(repaired.repaired_as.is_some());
+        assert!(lsm.verify().unwrap().passed());
+        assert!(!table.exists());
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert!(lsm.verify().unwrap().passed());
+        let keys: Vec<u64> = lsm.range(..).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![1, 3, 10, 11]);
+    }
+
+    #[test]
+    fn test_paranoid_checks() {
+        let path: PathBuf = test_dir("paranoid_checks");
+        // every index entry a restart point, holding its whole key and offset
+        let options = Options { prefix_bloom_bits: 16, index_restart_interval: 1, ..Options::default() };
+        let paranoid = Options { paranoid_checks: true, ..options.clone() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.insert(2, Vector::new(2, vec![4.0])).unwrap();
+        lsm.flush().unwrap();
+        let table = path.join(manifest::table_file_name(lsm.inner.state().sstables[0].file_number));
+        drop(lsm);
+        let original = std::fs::read(&table).unwrap();
+        let footer = sstable::Footer::read(&original, original.len()).unwrap();
+        let corrupt = |corrupt: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = original.clone();
+            corrupt(&mut bytes);
+            std::fs::write(&table, &bytes).unwrap();
+        };
+        let reads = |options: &Options| {
+            let lsm = LSMTree::open(&path, options.clone()).unwrap();
+            (lsm.get(1).map(|v| v.map(|v| v.data()[0])), lsm.get(2).map(|v| v.map(|v| v.data()[0])))
+        };
+        let name = table.file_name().unwrap().to_str().unwrap().to_string();
+        let is_corrupt = |read: Result<Option<f64>, LsmError>| matches!(read, Err(LsmError::Corruption { file: Some(file), .. }) if file == name);
+
+        // a flipped bit in a value decodes fine and only the checksum catches it
+        corrupt(&|bytes| {
+            let at = bytes.windows(8).position(|w| w == 1.0f64.to_le_bytes()).unwrap();
+            bytes[at + 7] ^= 0x40;
+        });
+        assert_ne!(reads(&options).0.unwrap(), Some(1.0));
+        let (first, second) = reads(&paranoid);
+        assert!(matches!(first, Err(LsmError::Corruption { offset: Some(0), ref message, .. }) if message == "entry checksum mismatch"));
+        assert_eq!(second.unwrap(), Some(4.0));
+
+        // index entries pointing at each other's entries
+        corrupt(&|bytes| {
+            // each entry is the shared byte count, the key and a one byte varint offset
+            let index = footer.index_offset as usize;
+            assert!(bytes[index + 9] < 0x80 && bytes[index + 19] < 0x80);
+            bytes.swap(index + 9, index + 19);
+        });
+        assert_eq!(reads(&options).0.unwrap(), Some(4.0));
+        assert!(is_corrupt(reads(&paranoid).0));
+
+        // a filter that rules out keys the table holds
+        corrupt(&|bytes| bytes[footer.filter_offset as usize + 6..footer.range_tombstone_offset as usize].fill(0));
+        assert_eq!(reads(&options).0.unwrap(), None);
+        let (first, second) = reads(&paranoid);
+        assert!(is_corrupt(first) && is_corrupt(second));
+
+        corrupt(&|_| {});
+        assert_eq!(reads(&paranoid).0.unwrap(), Some(1.0));
+    }
+
+    #[test]
+    fn test_subscribe_and_read_log_since() {
+        let path: PathBuf = test_dir("subscribe");
+        let lsm = LSMTree::new(&path).unwrap();
+        let events = lsm.subscribe();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete(1).delete_range(5, 10);
+        lsm.write(batch).unwrap();
+
+        let expected = vec![
+            Event::Put { sequence: 1, key: 1, value: Vector::new(1, vec![1.0]) },
+            Event::Delete { sequence: 2, key: 1 },
+            Event::DeleteRange { sequence: 3, start: 5, end: 10 },
+        ];
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(lsm.read_log_since(0).unwrap(), expected);
+        assert_eq!(lsm.read_log_since(2).unwrap(), expected[2..]);
+        assert!(lsm.read_log_since(3).unwrap().is_empty());
+
+        // a flush deletes the log it covered
+        lsm.flush().unwrap();
+        lsm.insert(2, Vector::new(2, vec![2.0])).unwrap();
+        assert!(matches!(lsm.read_log_since(0), Err(LsmError::NotFound(_))));
+        assert_eq!(lsm.read_log_since(3).unwrap().len(), 1);
+        drop(events);
+        lsm.insert(3, Vector::new(3, vec![3.0])).unwrap();
+        crash(lsm);
+
+        // unless it is retained, across reopens too
+        let options = Options { retained_wals: 2, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        lsm.flush().unwrap();
+        drop(lsm);
+        let lsm = LSMTree::open(&path, options).unwrap();
+        lsm.insert(4, Vector::new(4, vec![4.0])).unwrap();
+        lsm.flush().unwrap();
+        let sequences: Vec<u64> = lsm.read_log_since(3).unwrap().iter().map(|e| e.sequence()).collect();
+        assert_eq!(sequences, vec![4, 5, 6]);
+        assert!(matches!(lsm.read_log_since(2), Err(LsmError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let syncs = |name: &str, policy: SyncPolicy| {
+            let path: PathBuf = test_dir(name);
+            let options = Options { sstable_size: 100, sync_policy: policy, ..Options::default() };
+            let lsm = LSMTree::open(&path, options.clone()).unwrap();
+            for i in 0..20 {
+                lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+            }
+            let during = lsm.stats().wal_syncs;
+            lsm.flush().unwrap();
+            let after = lsm.stats().wal_syncs;
+            drop(lsm);
+            assert_eq!(LSMTree::open(&path, options).unwrap().len().unwrap(), 20);
+            (during, after)
+        };
+        assert_eq!(syncs("sync_policy_always", SyncPolicy::Always), (20, 20));
+        assert_eq!(syncs("sync_policy_every_0", SyncPolicy::EveryNMillis(0)), (20, 20));
+        // nothing is old enough to sync until the flush
+        assert_eq!(syncs("sync_policy_every_minute", SyncPolicy::EveryNMillis(60_000)), (0, 1));
+        assert_eq!(syncs("sync_policy_never", SyncPolicy::Never), (0, 0));
+    }
+
+    #[test]
+    fn test_sync_policy_timer() {
+        let path: PathBuf = test_dir("sync_policy_timer");
+        let lsm = LSMTree::open(&path, Options { sync_policy: SyncPolicy::EveryNMillis(50), ..Options::default() }).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        // no later write comes along, the timer syncs it
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(lsm.synced_sequence(), lsm.sequence());
+        assert_eq!(lsm.stats().wal_syncs, 1);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let path: PathBuf = test_dir("open_read_only");
+        let options = Options { sstable_size: 10, compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.inner.wait_for_idle().unwrap();
+        let listing = || {
+            let mut files: Vec<(String, u64)> = std::fs::read_dir(&path).unwrap()
+                .map(|e| e.unwrap())
+                .map(|e| (e.file_name().to_string_lossy().into_owned(), e.metadata().unwrap().len()))
+                .collect();
+            files.sort();
+            files
+        };
+        let before = listing();
+
+        // opened next to the live writer, seeing its tables and its unflushed log
+        let reader = LSMTree::open_read_only(&path, options.clone()).unwrap();
+        assert_eq!(reader.len().unwrap(), 25);
+        assert_eq!(reader.get(24).unwrap().unwrap().data(), &vec![24.0]);
+        assert!(matches!(reader.insert(30, Vector::new(30, vec![1.0])), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::PermissionDenied));
+        assert!(matches!(reader.delete(1), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::PermissionDenied));
+        assert!(matches!(reader.flush(), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::PermissionDenied));
+        assert!(matches!(reader.compact(), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::PermissionDenied));
+        assert!(matches!(reader.bulk_load([(40, Vector::new(40, vec![1.0]))]), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::PermissionDenied));
+        assert_eq!(listing(), before);
+
+        lsm.insert(25, Vector::new(25, vec![25.0])).unwrap();
+        assert!(reader.get(25).unwrap().is_none());
+        reader.close().unwrap();
+        drop(lsm);
+
+        assert!(matches!(LSMTree::open_read_only(&path.join("missing"), options), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_create_if_missing_and_error_if_exists() {
+        let path: PathBuf = test_dir("create_if_missing_and_error_if_exists");
+        let existing = Options { create_if_missing: false, ..Options::default() };
+        let new = Options { error_if_exists: true, ..Options::default() };
+
+        assert!(matches!(LSMTree::open(&path.join("tree"), existing.clone()), Err(LsmError::NotFound(_))));
+        assert!(!path.join("tree").exists());
+        // an empty directory holds no tree either
+        assert!(matches!(LSMTree::open(&path, existing.clone()), Err(LsmError::NotFound(_))));
+
+        let lsm = LSMTree::open(&path, new.clone()).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        drop(lsm);
+        assert!(matches!(LSMTree::open(&path, new), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(LSMTree::open(&path, existing).unwrap().get(1).unwrap().unwrap().id(), 1);
+    }
+
+    #[test]
+    fn test_directory_lock() {
+        let path: PathBuf = test_dir("directory_lock");
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(matches!(LSMTree::new(&path), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::ResourceBusy));
+        // readers take no lock
+        LSMTree::open_read_only(&path, Options::default()).unwrap();
+        drop(lsm);
+        LSMTree::new(&path).unwrap();
+    }
+
+    #[test]
+    fn test_destroy() {
+        let path: PathBuf = test_dir("destroy");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        assert!(matches!(LSMTree::destroy(&path), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::ResourceBusy));
+        drop(lsm);
+
+        // nothing is deleted from a directory holding anything else
+        std::fs::write(path.join("notes.txt"), b"mine").unwrap();
+        assert!(matches!(LSMTree::destroy(&path), Err(LsmError::InvalidArgument(_))));
+        assert!(path.join(manifest::MANIFEST_FILE).exists());
+        std::fs::remove_file(path.join("notes.txt")).unwrap();
+        LSMTree::destroy(&path).unwrap();
+        assert!(!path.exists());
+        LSMTree::destroy(&path).unwrap();
+
+        // nor from one that never held a tree
+        std::fs::create_dir_all(path.join("data")).unwrap();
+        std::fs::write(path.join("sstable_1.sdb"), b"").unwrap();
+        assert!(matches!(LSMTree::destroy(&path.join("data")), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(LSMTree::destroy(&path), Err(LsmError::InvalidArgument(_))));
+        assert!(path.join("sstable_1.sdb").exists());
+    }
+
+    #[test]
+    fn test_open_removes_stray_temp_files() {
+        let path: PathBuf = test_dir("open_removes_stray_temp_files");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.flush().unwrap();
+        drop(lsm);
+
+        let stray = path.join("sstable_1.sdb.tmp");
+        std::fs::write(&stray, b"half written").unwrap();
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(!stray.exists());
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        assert_eq!(lsm.get(1).unwrap().unwrap().id(), 1);
+    }
+
+    #[test]
+    fn test_reads_see_immutable_memtables() {
+        let path: PathBuf = test_dir("reads_see_immutable_memtables");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+
+        // holding the writer lock keeps the worker from installing the table
+        let mut writer = lsm.inner.writer();
+        lsm.inner.freeze(&mut writer).unwrap();
+        assert_eq!(lsm.inner.state().immutables.len(), 1);
+        assert!(lsm.inner.state().memtable.is_empty());
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![1.0]);
+        drop(writer);
+
+        lsm.insert(2, Vector::new(2, vec![2.0])).unwrap();
+        lsm.inner.wait_for_flushes().unwrap();
+        assert!(lsm.inner.state().immutables.is_empty());
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![1.0]);
+        assert_eq!(lsm.get(2).unwrap().unwrap().data(), &vec![2.0]);
+    }
+
+    #[test]
+    fn test_writes_continue_during_background_flush() {
+        let path: PathBuf = test_dir("writes_continue_during_background_flush");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..100 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 10);
+        assert!(lsm.inner.state().memtable.is_empty());
+        // only the active log remains once every frozen memtable is flushed
+        assert_eq!(wal::list_wals(&path).unwrap().len(), 1);
+        for i in 0..100 {
+            assert_eq!(lsm.get(i).unwrap().unwrap().id(), i);
+        }
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 10);
+        assert_eq!(lsm.startup_report().replayed_batches, 0);
+    }
+
+    #[test]
+    fn test_background_thread_pools() {
+        let path: PathBuf = test_dir("background_thread_pools");
+        let options = Options {
+            max_flush_threads: 3,
+            max_compaction_threads: 2,
+            compaction_trigger: 2,
+            thread_name_prefix: "pools".to_string(),
+            ..Options::default()
+        };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        let names: Vec<&str> = lsm.workers.iter().map(|w| w.thread().name().unwrap()).collect();
+        assert_eq!(names, vec!["pools-flush-0", "pools-flush-1", "pools-flush-2", "pools-compact-0", "pools-compact-1"]);
+
+        for i in 0..200 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.delete_range(50, 60).unwrap();
+        lsm.flush().unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        assert_eq!(lsm.range(..).unwrap().len(), 190);
+        lsm.close().unwrap();
+
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.range(..).unwrap().len(), 190);
+        assert!(lsm.get(55).unwrap().is_none());
+
+        let none = Options { max_flush_threads: 0, ..Options::default() };
+        assert!(matches!(LSMTree::open(&test_dir("background_thread_pools_none"), none), Err(LsmError::InvalidArgument(_))));
+    }
+
+    #[cfg(feature = "deterministic")]
+    #[test]
+    fn test_manual_executor_steps_flushes() {
+        let path: PathBuf = test_dir("manual_executor_steps_flushes");
+        let options = Options { executor: Executor::Manual, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        assert_eq!(lsm.inner.state().immutables.len(), 2);
+        assert!(lsm.inner.state().sstables.is_empty());
+
+        // a delete between freezing and flushing lands on the immutable memtable
+        lsm.delete(3).unwrap();
+        assert!(lsm.run_pending_job().unwrap());
+        assert_eq!(lsm.inner.state().immutables.len(), 1);
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        assert!(lsm.get(3).unwrap().is_none());
+        assert_eq!(lsm.get(13).unwrap().unwrap().id(), 13);
+
+        assert!(lsm.run_pending_job().unwrap());
+        assert!(!lsm.run_pending_job().unwrap());
+        lsm.flush().unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 3);
+        assert!(lsm.get(3).unwrap().is_none());
+        assert_eq!(lsm.get(24).unwrap().unwrap().id(), 24);
+    }
+
+    #[cfg(feature = "deterministic")]
+    #[test]
+    fn test_write_stalls() {
+        let path: PathBuf = test_dir("write_stalls");
+        let options = Options { executor: Executor::Manual, sstable_size: 1, slowdown_immutables: 2, stop_immutables: 3, slowdown_write_micros: 2000, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        lsm.insert(0, Vector::new(0, vec![0.0])).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        assert_eq!(lsm.stats().write_slowdowns, 0);
+        // two frozen memtables slow the next write down
+        lsm.insert(2, Vector::new(2, vec![2.0])).unwrap();
+        let stats = lsm.stats();
+        assert_eq!((stats.write_slowdowns, stats.write_stops), (1, 0));
+        assert!(stats.write_stall_micros >= 2000);
+        assert_eq!(lsm.inner.state().immutables.len(), 3);
+
+        // three stop it until a flush makes room, which the writer runs itself here
+        lsm.insert(3, Vector::new(3, vec![3.0])).unwrap();
+        let stats = lsm.stats();
+        assert_eq!((stats.write_slowdowns, stats.write_stops, stats.stalled_writers), (1, 1, 0));
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        assert_eq!(lsm.inner.state().immutables.len(), 3);
+        assert_eq!(lsm.get(3).unwrap().unwrap().id(), 3);
+    }
+
+    #[cfg(feature = "deterministic")]
+    #[test]
+    fn test_event_listener() {
+        use crate::db::listener::EventListener;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+        impl EventListener for Recorder {
+            fn on_flush_begin(&self, info: &FlushInfo) {
+                assert_eq!((info.file_size, info.elapsed_micros), (0, 0));
+                self.0.lock().unwrap().push(format!("flush {} of {}", info.file_number, info.entries));
+            }
+            fn on_flush_completed(&self, info: &FlushInfo) {
+                assert!(info.file_size > 0);
+                self.0.lock().unwrap().push(format!("flushed {}", info.file_number));
+            }
+            fn on_compaction_begin(&self, info: &CompactionInfo) {
+                assert!(info.outputs.is_empty() && info.input_bytes > 0);
+                self.0.lock().unwrap().push(format!("compact {:?} bottommost {}", info.inputs, info.bottommost));
+            }
+            fn on_compaction_completed(&self, info: &CompactionInfo) {
+                assert!(info.output_bytes > 0);
+                self.0.lock().unwrap().push(format!("compacted {:?} into {:?}", info.inputs, info.outputs));
+            }
+            fn on_write_stall(&self, info: &WriteStallInfo) {
+                self.0.lock().unwrap().push(format!("{:?} after {:?} with {} frozen", info.condition, info.previous, info.immutables));
+            }
+        }
+
+        let path: PathBuf = test_dir("event_listener");
+        let recorder = Arc::new(Recorder::default());
+        let options = Options {
+            executor: Executor::Manual,
+            sstable_size: 1,
+            compaction_trigger: 2,
+            slowdown_immutables: 2,
+            slowdown_write_micros: 10,
+            listeners: vec![recorder.clone()],
+            ..Options::default()
+        };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..3 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        while lsm.run_pending_job().unwrap() {}
+        lsm.insert(3, Vector::new(3, vec![3.0])).unwrap();
+        let events = recorder.0.lock().unwrap().clone();
+        assert_eq!(events, [
+            "Slowdown after Normal with 2 frozen",
+            "flush 4 of 1",
+            "flushed 4",
+            "flush 5 of 1",
+            "flushed 5",
+            "flush 6 of 1",
+            "flushed 6",
+            "compact [4, 5] bottommost true",
+            "compacted [4, 5] into [7]",
+            "compact [7, 6] bottommost true",
+            "compacted [7, 6] into [8]",
+            "Normal after Slowdown with 0 frozen",
+        ]);
+    }
+
+    #[test]
+    fn test_write_stall_thresholds() {
+        let path: PathBuf = test_dir("write_stall_thresholds");
+        let options = Options { sstable_size: 2, compaction_trigger: 2, stop_immutables: 1, slowdown_tables: 3, stop_tables: 4, slowdown_write_micros: 10, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..100 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        assert_eq!(lsm.len().unwrap(), 100);
+        assert_eq!(lsm.stats().stalled_writers, 0);
+        drop(lsm);
+
+        // compactions only start at the trigger, so the table count could stay at the threshold
+        for options in [Options { stop_tables: 4, ..Options::default() }, Options { slowdown_tables: 8, compaction_trigger: 0, ..Options::default() }] {
+            assert!(matches!(LSMTree::open(&test_dir("write_stall_thresholds_bad"), options), Err(LsmError::InvalidArgument(_))));
+        }
+    }
+
+    #[test]
+    fn test_compaction_styles() {
+        let universal = CompactionStyle::Universal { size_ratio_percent: 1, max_space_amplification_percent: 200 };
+        let options = Options { sstable_size: 20, compaction_trigger: 3, compaction_style: universal, ..Options::default() };
+        let path = test_dir("compaction_styles");
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..400 {
+            lsm.insert(i % 150, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+        let runs = |lsm: &LSMTree| compaction::runs(&lsm.inner.state().sstables).len();
+        assert!(runs(&lsm) < 3, "{} runs", runs(&lsm));
+
+        // switched for serving reads, the runs settle into levels
+        assert!(matches!(lsm.set_compaction_style(CompactionStyle::Leveled { fanout: 1 }), Err(LsmError::InvalidArgument(_))));
+        lsm.set_compaction_style(CompactionStyle::Leveled { fanout: 4 }).unwrap();
+        assert_eq!(lsm.compaction_style(), CompactionStyle::Leveled { fanout: 4 });
+        for i in 400..480 {
+            lsm.insert(i % 150, Vector::new(i, vec![i as f64])).unwrap();
+            if i % 20 == 19 {
+                lsm.flush().unwrap();
+            }
+        }
+        lsm.inner.wait_for_idle().unwrap();
+        let state = lsm.inner.state().clone();
+        let runs = compaction::runs(&state.sstables);
+        let bytes: Vec<u64> = runs.iter().map(|run| state.sstables[run.clone()].iter().map(|t| t.file_size()).sum()).collect();
+        for level in 0..bytes.len() {
+            assert!(bytes[level + 1..].iter().sum::<u64>() * 4 <= bytes[level], "{:?}", bytes);
+        }
+        drop(state);
+        for key in 0..150 {
+            let newest = (0..480).rev().find(|i| i % 150 == key).unwrap();
+            assert_eq!(lsm.get(key).unwrap().unwrap().data(), &vec![newest as f64]);
+        }
+        lsm.close().unwrap();
+
+        // the style comes from the options again on the next open
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.compaction_style(), universal);
+        let invalid = Options { compaction_style: CompactionStyle::Universal { size_ratio_percent: 1, max_space_amplification_percent: 0 }, ..Options::default() };
+        assert!(matches!(LSMTree::open(&test_dir("compaction_styles_invalid"), invalid), Err(LsmError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let path: PathBuf = test_dir("background_compaction");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..60 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        // overwrites and deletes in newer tables must win over the merged older ones
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![-1.0])).unwrap();
+        }
+        lsm.delete(20).unwrap();
+        lsm.flush().unwrap();
+        lsm.delete(30).unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+
+        let tables = lsm.inner.state().sstables.len();
+        assert!(tables < 4, "{} tables left after compaction", tables);
+        assert_eq!(lsm.inner.writer().manifest.live_tables().len(), tables);
+        let on_disk = std::fs::read_dir(&path).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".sdb"))
+            .count();
+        assert_eq!(on_disk, tables);
+
+        let check = |lsm: &LSMTree| {
+            for i in 0..60 {
+                match i {
+                    0..10 => assert_eq!(lsm.get(i).unwrap().unwrap().data(), &vec![-1.0]),
+                    20 | 30 => assert!(lsm.get(i).unwrap().is_none()),
+                    _ => assert_eq!(lsm.get(i).unwrap().unwrap().data(), &vec![i as f64]),
+                }
+            }
+        };
+        check(&lsm);
+        lsm.close().unwrap();
+
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+        assert_eq!(lsm.get(5).unwrap().unwrap().data(), &vec![-1.0]);
+        assert_eq!(lsm.get(59).unwrap().unwrap().data(), &vec![59.0]);
+    }
+
+    #[test]
+    fn test_manual_compaction() {
+        let path: PathBuf = test_dir("manual_compaction");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        lsm.compact().unwrap();
+        for i in 0..45 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.delete_range(10, 20).unwrap();
+        lsm.compact().unwrap();
+        assert_eq!(lsm.stats().table_count, 1);
+        assert_eq!(lsm.stats().compactions, 1);
+        assert_eq!(lsm.len().unwrap(), 35);
+        assert!(lsm.get(15).unwrap().is_none());
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.range(..).unwrap().len(), 35);
+        assert_eq!(lsm.get(44).unwrap().unwrap().data(), &vec![44.0]);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let path: PathBuf = test_dir("checkpoint");
+        let destination: PathBuf = test_dir("checkpoint_copy");
+        let options = Options { sstable_size: 10, compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        lsm.set_metric(DistanceMetric::Cosine).unwrap();
+        for i in 0..35 {
+            lsm.insert(i, Vector::new(i, vec![i as f64, 1.0])).unwrap();
+        }
+        lsm.delete_range(5, 10).unwrap();
+
+        // a writer keeps going while the checkpoint is taken, so the copy holds some prefix of its writes
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 1000..1200 {
+                    lsm.insert(i, Vector::new(i, vec![i as f64, 1.0])).unwrap();
+                }
+            });
+            lsm.checkpoint(&destination).unwrap();
+        });
+        assert!(matches!(lsm.checkpoint(&destination), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let name = manifest::table_file_name(lsm.inner.state().sstables[0].file_number);
+            let inode = |dir: &PathBuf| std::fs::metadata(dir.join(&name)).unwrap().ino();
+            assert_eq!(inode(&path), inode(&destination));
+        }
+
+        // the memtable goes over in its log, nothing is flushed for it
+        let second: PathBuf = test_dir("checkpoint_unflushed");
+        lsm.flush().unwrap();
+        lsm.insert(1500, Vector::new(1500, vec![0.5, 1.0])).unwrap();
+        let tables = lsm.inner.state().sstables.len();
+        lsm.checkpoint(&second).unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), tables);
+        assert_eq!(LSMTree::open(&second, options.clone()).unwrap().get(1500).unwrap().unwrap().data(), &vec![0.5, 1.0]);
+
+        // the source compacting its tables away doesn't reach the copy
+        lsm.insert(2000, Vector::new(2000, vec![0.0, 1.0])).unwrap();
+        lsm.compact().unwrap();
+        drop(lsm);
+
+        let copy = LSMTree::open(&destination, options).unwrap();
+        assert_eq!(copy.metric(), DistanceMetric::Cosine);
+        let keys: Vec<u64> = copy.range(..).unwrap().into_iter().map(|(k, _)| k).collect();
+        let late = keys.iter().filter(|&&k| k >= 1000).count() as u64;
+        let expected: Vec<u64> = (0..5).chain(10..35).chain(1000..1000 + late).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(copy.get(20).unwrap().unwrap().data(), &vec![20.0, 1.0]);
+        copy.insert(3000, Vector::new(3000, vec![1.0, 1.0])).unwrap();
+        copy.close().unwrap();
+        assert!(LSMTree::open(&path, Options::default()).unwrap().get(3000).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_open_removes_obsolete_tables() {
+        let path: PathBuf = test_dir("open_removes_obsolete_tables");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.flush().unwrap();
+        lsm.close().unwrap();
+
+        let live = Manifest::open(&path).unwrap().live_tables()[0];
+        let leftover = path.join(manifest::table_file_name(1000));
+        std::fs::copy(path.join(manifest::table_file_name(live)), &leftover).unwrap();
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(!leftover.exists());
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        assert_eq!(lsm.get(1).unwrap().unwrap().id(), 1);
+        // the number of the removed file is never handed out again
+        assert!(lsm.inner.writer().manifest.new_file_number() > 1000);
+    }
+
+    #[test]
+    fn test_range() {
+        let path: PathBuf = test_dir("range");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..30 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.insert(12, Vector::new(12, vec![-1.0])).unwrap();
+        lsm.delete(14).unwrap();
+        lsm.insert(100, Vector::new(100, vec![100.0])).unwrap();
+
+        let entries = lsm.range(10..16).unwrap();
+        let keys: Vec<u64> = entries.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![10, 11, 12, 13, 15]);
+        assert_eq!(entries[2].1.data(), &vec![-1.0]);
+        assert_eq!(lsm.range(..).unwrap().len(), 30);
+        assert_eq!(lsm.range(29..=100).unwrap().len(), 2);
+        assert!(lsm.range(40..50).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_is_isolated() {
+        let path: PathBuf = test_dir("snapshot_is_isolated");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        let snapshot = lsm.snapshot();
+        assert_eq!(snapshot.sequence(), 25);
+
+        lsm.insert(3, Vector::new(3, vec![-3.0])).unwrap();
+        lsm.delete(4).unwrap();
+        lsm.delete(22).unwrap();
+        lsm.insert(100, Vector::new(100, vec![100.0])).unwrap();
+        // flush and compact the tables the snapshot is reading from
+        for i in 200..260 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+
+        assert_eq!(snapshot.get(3).unwrap().unwrap().data(), &vec![3.0]);
+        assert_eq!(snapshot.get(4).unwrap().unwrap().id(), 4);
+        assert_eq!(snapshot.get(22).unwrap().unwrap().id(), 22);
+        assert!(snapshot.get(100).unwrap().is_none());
+        assert_eq!(snapshot.range(..).unwrap().len(), 25);
+
+        assert_eq!(lsm.get(3).unwrap().unwrap().data(), &vec![-3.0]);
+        assert!(lsm.get(4).unwrap().is_none());
+        assert_eq!(lsm.snapshot().sequence(), 89);
+    }
+
+    #[test]
+    fn test_readers_pin_compacted_tables() {
+        let path: PathBuf = test_dir("readers_pin_compacted_tables");
+        let options = Options { sstable_size: 10, compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..30 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        let files = |lsm: &LSMTree| lsm.inner.state().sstables.iter().map(|t| path.join(manifest::table_file_name(t.file_number))).collect::<Vec<_>>();
+        let compacted = files(&lsm);
+        assert_eq!(compacted.len(), 3);
+
+        // the iterator and the snapshot each hold the tables compaction replaces
+        let mut iter = lsm.iter();
+        assert_eq!(iter.next().unwrap().unwrap().0, 0);
+        let snapshot = lsm.snapshot();
+        lsm.compact().unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        assert!(compacted.iter().all(|file| file.exists()));
+
+        assert_eq!(iter.map(|entry| entry.unwrap().0).collect::<Vec<_>>(), (1..30).collect::<Vec<_>>());
+        assert!(compacted.iter().all(|file| file.exists()));
+        assert_eq!(snapshot.range(..).unwrap().len(), 30);
+        drop(snapshot);
+        assert!(!compacted.iter().any(|file| file.exists()));
+
+        // with no reader holding them they go as soon as they are replaced
+        let live = files(&lsm);
+        lsm.delete(5).unwrap();
+        lsm.flush().unwrap();
+        lsm.compact().unwrap();
+        assert!(!live[0].exists());
+        assert!(files(&lsm)[0].exists());
+    }
+
+    #[test]
+    fn test_get_at() {
+        let path: PathBuf = test_dir("get_at");
+        let options = Options { history_retention_millis: 60_000, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        let before = lsm.snapshot().sequence();
+        lsm.insert(3, Vector::new(3, vec![-3.0])).unwrap();
+        lsm.insert(3, Vector::new(3, vec![-30.0])).unwrap();
+        lsm.delete(4).unwrap();
+        lsm.delete_range(10, 20).unwrap();
+        lsm.insert(100, Vector::new(100, vec![100.0])).unwrap();
+        // the history outlives flushes and compactions, it isn't kept in the tables
+        lsm.flush().unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+
+        assert_eq!(lsm.get_at(3, before).unwrap().unwrap().data(), &vec![3.0]);
+        assert_eq!(lsm.get_at(3, before + 1).unwrap().unwrap().data(), &vec![-3.0]);
+        assert_eq!(lsm.get_at(4, before).unwrap().unwrap().id(), 4);
+        assert_eq!(lsm.get_at(15, before).unwrap().unwrap().id(), 15);
+        assert!(lsm.get_at(100, before).unwrap().is_none());
+        assert_eq!(lsm.get_at(3, u64::MAX).unwrap().unwrap().data(), &vec![-30.0]);
+        assert!(lsm.get_at(15, u64::MAX).unwrap().is_none());
+
+        let then: Vec<(u64, Vector)> = lsm.iter_at(before).unwrap().map(|entry| entry.unwrap()).collect();
+        let keys: Vec<u64> = then.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, (0..25).collect::<Vec<u64>>());
+        assert_eq!(then[3].1.data(), &vec![3.0]);
+        let now: Vec<u64> = lsm.iter_at(lsm.snapshot().sequence()).unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(now, lsm.keys().map(|key| key.unwrap()).collect::<Vec<u64>>());
+
+        // nothing from before the tree was opened is kept
+        lsm.close().unwrap();
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert!(matches!(lsm.get_at(3, before), Err(LsmError::NotFound(_))));
+        assert_eq!(lsm.get_at(3, lsm.snapshot().sequence()).unwrap().unwrap().data(), &vec![-30.0]);
+
+        let lsm = LSMTree::open(&test_dir("get_at_disabled"), Options::default()).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.insert(1, Vector::new(1, vec![2.0])).unwrap();
+        assert!(matches!(lsm.get_at(1, 1), Err(LsmError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_scan() {
+        let options = Options { history_retention_millis: 60_000, ..Options::default() };
+        let lsm = LSMTree::open(&test_dir("scan"), options).unwrap();
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.delete(4).unwrap();
+
+        let (page, cursor) = lsm.scan(2..9, 3, None).unwrap();
+        assert_eq!(page.iter().map(|(key, _)| *key).collect::<Vec<u64>>(), vec![2, 3, 5]);
+        let cursor = Cursor::decode(&cursor.unwrap().encode()).unwrap();
+        // writes between pages aren't seen, neither ahead of the cursor nor behind it
+        lsm.insert(6, Vector::new(6, vec![-6.0])).unwrap();
+        lsm.insert(7, Vector::new(7, vec![-7.0])).unwrap();
+        lsm.delete(8).unwrap();
+        lsm.insert(1, Vector::new(1, vec![-1.0])).unwrap();
+        let (page, cursor) = lsm.scan(2..9, 3, Some(&cursor)).unwrap();
+        assert_eq!(page.iter().map(|(key, value)| (*key, value.data()[0])).collect::<Vec<_>>(), vec![(6, 6.0), (7, 7.0), (8, 8.0)]);
+        assert!(cursor.is_none());
+
+        // a fresh scan sees them, and a page ending on the range's last key has no cursor
+        let (page, cursor) = lsm.scan(.., 4, None).unwrap();
+        assert_eq!(page.iter().map(|(key, _)| *key).collect::<Vec<u64>>(), vec![0, 1, 2, 3]);
+        assert_eq!(page[1].1.data(), &vec![-1.0]);
+        let (page, cursor) = lsm.scan(.., 4, cursor.as_ref()).unwrap();
+        assert_eq!(page.iter().map(|(key, _)| *key).collect::<Vec<u64>>(), vec![5, 6, 7, 9]);
+        assert!(cursor.is_none());
+        assert!(lsm.scan(20.., 4, None).unwrap().0.is_empty());
+
+        assert!(matches!(lsm.scan(.., 0, None), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(Cursor::decode("12"), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(Cursor::decode(&"g".repeat(32)), Err(LsmError::InvalidArgument(_))));
+
+        // without the history, a page after a write carries on from the cursor's key
+        // as the tree is now
+        let lsm = LSMTree::open(&test_dir("scan_no_history"), Options::default()).unwrap();
+        for i in 0..6 {
+            lsm.insert(i, Vector::new(i, vec![0.0])).unwrap();
+        }
+        let (page, cursor) = lsm.scan(.., 2, None).unwrap();
+        assert_eq!(page.iter().map(|(key, _)| *key).collect::<Vec<u64>>(), vec![0, 1]);
+        assert_eq!(lsm.scan(.., 2, cursor.as_ref()).unwrap().0.len(), 2);
+        lsm.insert(0, Vector::new(0, vec![1.0])).unwrap();
+        lsm.insert(3, Vector::new(3, vec![1.0])).unwrap();
+        lsm.delete(4).unwrap();
+        let (page, cursor) = lsm.scan(.., 2, cursor.as_ref()).unwrap();
+        assert_eq!(page.iter().map(|(key, value)| (*key, value.data()[0])).collect::<Vec<_>>(), vec![(2, 0.0), (3, 1.0)]);
+        lsm.insert(9, Vector::new(9, vec![0.0])).unwrap();
+        let (page, cursor) = lsm.scan(.., 2, cursor.as_ref()).unwrap();
+        assert_eq!(page.iter().map(|(key, _)| *key).collect::<Vec<u64>>(), vec![5, 9]);
+        assert!(cursor.is_none());
+        assert!(lsm.iter_at(0).is_err());
+    }
+
+    #[test]
+    fn test_range_memory_limit() {
+        let path: PathBuf = test_dir("range_memory_limit");
+        let options = Options { max_scan_bytes: 2048, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..40 {
+            lsm.insert(i, Vector::new(i, vec![0.0; 8])).unwrap();
+        }
+        assert_eq!(lsm.range(0..5).unwrap().len(), 5);
+        assert!(matches!(lsm.range(..), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::OutOfMemory));
+        assert!(matches!(lsm.snapshot().range(..), Err(LsmError::Io(e)) if e.kind() == io::ErrorKind::OutOfMemory));
+    }
+
+    #[test]
+    fn test_write_size_limits() {
+        let path: PathBuf = test_dir("write_size_limits");
+        let options = Options { max_value_bytes: 256, max_dimension: 32, max_payload_bytes: 32, merge_operator: Some(Arc::new(AppendOperator)), ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0; 8]).with_metadata("tag", "a")).unwrap();
+
+        let err = lsm.insert(2, Vector::new(2, vec![1.0; 33])).unwrap_err();
+        assert!(matches!(err, LsmError::InvalidArgument(_)));
+        assert!(err.to_string().contains("max_dimension"), "{}", err);
+        let err = lsm.insert(2, Vector::new(2, vec![1.0]).with_metadata("tag", "a".repeat(64))).unwrap_err();
+        assert!(err.to_string().contains("max_payload_bytes"), "{}", err);
+        let wide = Vector::new(2, vec![1.0; 30]);
+        let err = lsm.insert(2, wide.clone()).unwrap_err();
+        assert!(err.to_string().contains("max_value_bytes"), "{}", err);
+        assert!(lsm.merge(2, vec![0; 257]).is_err());
+        assert!(lsm.bulk_load(vec![(3, Vector::new(3, vec![0.0; 33]))]).is_err());
+
+        // one oversized record fails its whole batch
+        let mut batch = WriteBatch::new();
+        batch.put(4, Vector::new(4, vec![4.0])).put(5, Vector::new(5, vec![0.0; 100]));
+        assert!(lsm.write(batch).is_err());
+        assert!(lsm.get(4).unwrap().is_none());
+
+        let path: PathBuf = test_dir("write_size_limits_disabled");
+        let options = Options { max_value_bytes: 0, max_dimension: 0, max_payload_bytes: 0, fix_dimension: false, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        lsm.insert(2, wide).unwrap();
+        lsm.insert(3, Vector::new(3, vec![0.0; 70_000])).unwrap();
+    }
+
+    #[test]
+    fn test_close_flushes_memtable() {
+        let path: PathBuf = test_dir("close_flushes_memtable");
+        let options = Options { sstable_size: 100, sync_policy: SyncPolicy::Never, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for key in 0..5 {
+            lsm.insert(key, Vector::new(key, vec![key as f64])).unwrap();
+        }
+        lsm.close().unwrap();
+
+        // nothing was left in the log to replay
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!((lsm.startup_report().replayed_batches, lsm.startup_report().table_count), (0, 1));
+        assert_eq!(lsm.len().unwrap(), 5);
+        lsm.insert(5, Vector::new(5, vec![5.0])).unwrap();
+        drop(lsm);
+
+        // dropping flushes as well, and a read-only tree writes nothing
+        let reader = LSMTree::open_read_only(&path, options.clone()).unwrap();
+        assert_eq!(reader.startup_report().replayed_batches, 0);
+        assert_eq!(reader.get(5).unwrap().unwrap().data(), &vec![5.0]);
+        reader.close().unwrap();
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.stats().table_count, 2);
+        assert_eq!(lsm.len().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_cold_directory() {
+        let path: PathBuf = test_dir("cold_directory");
+        let cold_directory: PathBuf = test_dir("cold_directory_cold");
+        let destination: PathBuf = test_dir("cold_directory_checkpoint");
+        let options = Options { sstable_size: 10, compaction_trigger: 0, cold_directory: Some(cold_directory.clone()), ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        let in_directory = |lsm: &LSMTree, directory: &Path| lsm.describe().iter().filter(|t| directory.join(&t.file_name).exists()).count();
+        for i in 0..30 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        assert!(lsm.describe().iter().all(|t| !t.cold));
+
+        // the oldest run moves over as it is compacted, and fresh flushes stay put
+        lsm.compact().unwrap();
+        let cold = lsm.describe().len();
+        assert!(lsm.describe().iter().all(|t| t.cold));
+        assert_eq!((in_directory(&lsm, &cold_directory), in_directory(&lsm, &path)), (cold, 0));
+        for i in 30..40 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        assert_eq!(lsm.describe().iter().filter(|t| !t.cold).count(), lsm.describe().len() - cold);
+        assert_eq!(lsm.get(5).unwrap().unwrap().data(), &vec![5.0]);
+        lsm.close().unwrap();
+
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(lsm.describe().iter().filter(|t| t.cold).count(), cold);
+        assert_eq!(lsm.range(..).unwrap().len(), 40);
+        lsm.compact().unwrap();
+        assert!(lsm.describe().iter().all(|t| t.cold));
+        assert_eq!(in_directory(&lsm, &path), 0);
+
+        // a checkpoint holds the cold tables in its own directory
+        lsm.checkpoint(&destination).unwrap();
+        lsm.close().unwrap();
+        let copy = LSMTree::open(&destination, Options::default()).unwrap();
+        assert!(copy.describe().iter().all(|t| !t.cold));
+        assert_eq!(copy.range(..).unwrap().len(), 40);
+        drop(copy);
+
+        assert!(matches!(LSMTree::open(&path, Options::default()), Err(LsmError::InvalidArgument(_))));
+        let with_storage = Options { storage: Some(Arc::new(crate::db::storage::MemoryStorage::new())), ..options };
+        assert!(matches!(LSMTree::open(&test_dir("cold_directory_storage"), with_storage), Err(LsmError::InvalidArgument(_))));
+    }
+
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn test_failpoints() {
+        use crate::db::failpoint::{FailPoint, FailPoints};
+        let path: PathBuf = test_dir("failpoints");
+        let failpoints = Arc::new(FailPoints::new());
+        let options = Options { sstable_size: 10, compaction_trigger: 0, failpoints: Some(failpoints.clone()), ..Options::default() };
+        let table_files = |path: &Path| std::fs::read_dir(path).unwrap().filter(|entry| {
+            manifest::parse_table_file_name(&entry.as_ref().unwrap().file_name().to_string_lossy()).is_some()
+        }).count();
+
+        // a write failing between its append and its sync may or may not survive, the
+        // ones before it do, and nothing after it reaches the log
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        failpoints.arm(FailPoint::WalAppend, 5);
+        for i in 0..5 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        assert!(lsm.insert(5, Vector::new(5, vec![5.0])).is_err());
+        assert!(lsm.insert(6, Vector::new(6, vec![6.0])).is_err());
+        assert_eq!(failpoints.fired(), Some(FailPoint::WalAppend));
+        let lsm = lsm.crash_and_reopen().unwrap();
+        assert_eq!(failpoints.fired(), None);
+        let keys: Vec<u64> = lsm.range(..).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert!(keys == (0..5).collect::<Vec<_>>() || keys == (0..6).collect::<Vec<_>>(), "{:?}", keys);
+
+        // a torn table is thrown away and its memtable replayed from the log
+        lsm.insert(10, Vector::new(10, vec![10.0])).unwrap();
+        failpoints.arm(FailPoint::TableWrite, 0);
+        assert!(lsm.flush().is_err());
+        assert!(lsm.insert(11, Vector::new(11, vec![11.0])).is_err());
+        let lsm = lsm.crash_and_reopen().unwrap();
+        assert_eq!(lsm.get(10).unwrap().unwrap().data(), &vec![10.0]);
+        assert!(lsm.get(11).unwrap().is_none());
+        assert!(std::fs::read_dir(&path).unwrap().all(|entry| !entry.unwrap().path().to_string_lossy().ends_with(".tmp")));
+
+        // a table never logged in the manifest is removed, and its writes are replayed
+        lsm.flush().unwrap();
+        let tables = lsm.stats().table_count;
+        lsm.insert(20, Vector::new(20, vec![20.0])).unwrap();
+        failpoints.arm(FailPoint::ManifestCommit, 0);
+        assert!(lsm.flush().is_err());
+        let lsm = lsm.crash_and_reopen().unwrap();
+        assert_eq!(lsm.get(20).unwrap().unwrap().data(), &vec![20.0]);
+        assert_eq!((lsm.stats().table_count, table_files(&path)), (tables, tables));
+        lsm.flush().unwrap();
+        assert_eq!(lsm.stats().table_count, tables + 1);
+
+        // without a failpoint firing, a crash keeps every acknowledged write
+        lsm.insert(30, Vector::new(30, vec![30.0])).unwrap();
+        lsm.crash();
+        assert!(LSMTree::open(&path, options.clone()).unwrap().get(30).unwrap().is_some());
+
+        // a bulk load whose tables aren't logged leaves the indexes as they were
+        let indexed = Options { hnsw: Some(HnswOptions::default()), indexed_fields: vec!["doc".to_string()], ..options };
+        let lsm = LSMTree::open(&test_dir("failpoints_bulk"), indexed).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0]).with_metadata("doc", 1i64)).unwrap();
+        failpoints.arm(FailPoint::ManifestCommit, 0);
+        assert!(lsm.bulk_load((2..6u64).map(|i| (i, Vector::new(i, vec![i as f64]).with_metadata("doc", 1i64)))).is_err());
+        assert_eq!(lsm.inner.index.as_ref().unwrap().read().unwrap().len(), 1);
+        assert_eq!(lsm.inner.fields.as_ref().unwrap().read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_storage() {
+        use crate::db::storage::MemoryStorage;
+        let path: PathBuf = test_dir("storage");
+        let _ = std::fs::remove_dir_all(path.with_extension("checkpoint"));
+        let source = test_dir("storage_source");
+        std::fs::create_dir_all(&source).unwrap();
+        let file = source.join("external.sdb");
+        let mut writer = sstable::SSTableWriter::create(&file).unwrap();
+        for key in 100..110 {
+            writer.add(key, &Vector::new(key, vec![key as f64])).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let storage = Arc::new(MemoryStorage::new());
+        let options = Options { compaction_trigger: 3, storage: Some(storage.clone()), ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for key in 0..50 {
+            lsm.insert(key, Vector::new(key, vec![key as f64])).unwrap();
+        }
+        lsm.delete_range(10, 20).unwrap();
+        assert_eq!(lsm.ingest_external_file(&file).unwrap(), 10);
+        lsm.flush().unwrap();
+        // the tables are all in the storage, and compacted ones leave it
+        let names = |storage: &MemoryStorage| storage.list().unwrap().into_iter().filter(|name| name.ends_with(".sdb")).count();
+        let on_disk = |path: &Path| std::fs::read_dir(path).unwrap().filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|e| e == "sdb")).count();
+        assert_eq!(on_disk(&path), 0);
+        lsm.compact().unwrap();
+        assert_eq!(names(&storage), 1);
+        assert_eq!(lsm.get(42).unwrap().unwrap().data(), &vec![42.0]);
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(lsm.range(..).unwrap().len(), 50);
+        assert_eq!(lsm.get(105).unwrap().unwrap().data(), &vec![105.0]);
+        assert!(lsm.get(15).unwrap().is_none());
+        // a checkpoint is an ordinary tree on disk
+        lsm.checkpoint(&path.with_extension("checkpoint")).unwrap();
+        drop(lsm);
+        let copy = LSMTree::open(&path.with_extension("checkpoint"), Options { storage: None, ..options }).unwrap();
+        assert_eq!(copy.range(..).unwrap().len(), 50);
+        assert_eq!(on_disk(&path.with_extension("checkpoint")), 1);
+    }
+
+    #[test]
+    fn test_payload_codec() {
+        use crate::db::entry::PayloadCodec;
+        let path: PathBuf = test_dir("payload_codec");
+        let value = |key: u64| Vector::new(key, vec![key as f64]).with_metadata("label", format!("v{}", key)).with_metadata("rank", key as i64);
+        let lsm = LSMTree::open(&path, Options { payload_codec: PayloadCodec::Compact, ..Options::default() }).unwrap();
+        for key in 0..20 {
+            lsm.insert(key, value(key)).unwrap();
+        }
+        lsm.flush().unwrap();
+        assert_eq!(lsm.get(7).unwrap().unwrap(), value(7));
+        drop(lsm);
+
+        // tables of both codecs read back, and compact into one of the current codec
+        let lsm = LSMTree::open(&path, Options::default()).unwrap();
+        for key in 20..40 {
+            lsm.insert(key, value(key)).unwrap();
+        }
+        lsm.compact().unwrap();
+        assert_eq!(lsm.range(..).unwrap(), (0..40).map(|key| (key, value(key))).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_codec() {
+        use crate::db::codec::{BsonCodec, Codec, RawCodec};
+        let path: PathBuf = test_dir("codec");
+        let value = |key: u64| Vector::new(key, vec![key as f64, 1.0]).with_metadata("label", format!("v{}", key));
+        let options = Options { codec: Arc::new(BsonCodec), dedup_vectors: true, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for key in 0..20 {
+            lsm.insert(key, value(key)).unwrap();
+        }
+        lsm.flush().unwrap();
+        assert!(lsm.inner.state().sstables.iter().all(|t| t.codec.id() == codec::BSON_ID));
+        assert_eq!(lsm.get(7).unwrap().unwrap(), value(7));
+        assert_eq!(lsm.get_with(7, |v| v.unwrap().to_vector().unwrap()).unwrap(), value(7));
+        drop(lsm);
+
+        // tables of either codec read back, and compact into one of the current codec
+        let lsm = LSMTree::open(&path, Options::default()).unwrap();
+        for key in 20..40 {
+            lsm.insert(key, value(key)).unwrap();
+        }
+        lsm.compact().unwrap();
+        assert!(lsm.inner.state().sstables.iter().all(|t| t.codec.id() == codec::RAW_ID));
+        assert_eq!(lsm.range(..).unwrap(), (0..40).map(|key| (key, value(key))).collect::<Vec<_>>());
+        drop(lsm);
+
+        // a codec of its own couldn't be read back by the tables written with it
+        struct Renamed;
+        impl Codec for Renamed {
+            fn id(&self) -> u32 {
+                codec::RAW_ID
+            }
+            fn name(&self) -> &'static str {
+                "renamed"
+            }
+            fn encode(&self, value: &Vector, at: usize, element: ElementType, payload: entry::PayloadCodec) -> io::Result<(u8, Vec<u8>)> {
+                RawCodec.encode(value, at, element, payload)
+            }
+            fn decode(&self, flags: u8, serialized: &[u8]) -> io::Result<Vector> {
+                RawCodec.decode(flags, serialized)
+            }
+            fn shares_data(&self) -> bool {
+                true
+            }
+        }
+        assert!(matches!(LSMTree::open(&path, Options { codec: Arc::new(Renamed), ..Options::default() }), Err(LsmError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_columnar_knn() {
+        let cases = [(DistanceMetric::L2, ElementType::F64), (DistanceMetric::Cosine, ElementType::F32), (DistanceMetric::InnerProduct, ElementType::F64), (DistanceMetric::Hamming, ElementType::Binary), (DistanceMetric::Jaccard, ElementType::F32)];
+        for (metric, element) in cases {
+            let trees: Vec<LSMTree> = [0, 4].into_iter().map(|columnar_block_vectors| {
+                let path: PathBuf = test_dir(&format!("columnar_knn_{:?}_{}", metric, columnar_block_vectors));
+                let options = Options { sstable_size: 16, compaction_trigger: 100, columnar_block_vectors, ..Options::default() };
+                let lsm = LSMTree::open(&path, options).unwrap();
+                lsm.set_metric(metric).unwrap();
+                lsm.set_element_type(element).unwrap();
+                lsm
+            }).collect();
+            let mut rng = rand::rng();
+            for tree in trees.iter() {
+                for key in 0..60u64 {
+                    let data = vec![(key % 7) as f64 - 3.0, (key % 5) as f64 * 0.5, 1.0];
+                    tree.insert(key, Vector::new(key, data)).unwrap();
+                }
+                tree.flush().unwrap();
+                // newer versions, deletes, a range delete and expired entries, some still in
+                // the memtable and some in newer tables
+                for key in (0..60).step_by(4) {
+                    tree.insert(key, Vector::new(key, vec![key as f64 * 0.1, -1.0, 2.0])).unwrap();
+                }
+                for key in [3, 13, 33] {
+                    tree.delete(key).unwrap();
+                }
+                tree.delete_range(40, 48).unwrap();
+                tree.insert_with_ttl(5, Vector::new(5, vec![0.0, 0.0, 1.0]), Duration::ZERO).unwrap();
+                tree.flush().unwrap();
+                tree.insert(7, Vector::new(7, vec![9.0, 9.0, 9.0])).unwrap();
+            }
+            assert!(trees[1].inner.state().sstables.iter().all(|t| t.has_columns()));
+            assert!(!trees[0].inner.state().sstables.iter().any(|t| t.has_columns()));
+            for _ in 0..5 {
+                let query: Vec<f64> = (0..3).map(|_| rng.random_range(-4.0..4.0)).collect();
+                let (expected, found) = (trees[0].knn(&query, 10).unwrap(), trees[1].knn(&query, 10).unwrap());
+                assert_eq!(expected.len(), found.len());
+                for ((expected_key, expected), (key, distance)) in expected.into_iter().zip(found) {
+                    assert!((expected - distance).abs() < 1e-9, "{:?}: {} at {} against {} at {}", metric, key, distance, expected_key, expected);
+                }
+            }
+            assert!(matches!(trees[1].knn(&[1.0, 2.0], 3), Err(LsmError::InvalidArgument(_))));
+        }
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let path: PathBuf = test_dir("flush_interval");
+        let options = Options { sstable_size: 1000, flush_interval_millis: 50, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for key in 0..3 {
+            lsm.insert(key, Vector::new(key, vec![key as f64])).unwrap();
+        }
+        let started = Instant::now();
+        while lsm.stats().table_count == 0 {
+            assert!(started.elapsed() < Duration::from_secs(10), "the memtable was never flushed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(lsm.stats().timed_flushes, 1);
+        // an empty memtable is left alone
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!((lsm.stats().table_count, lsm.stats().timed_flushes), (1, 1));
+        assert_eq!(lsm.get(2).unwrap().unwrap().data(), &vec![2.0]);
+        lsm.close().unwrap();
+
+        // the timer thread doesn't hold up closing
+        let lsm = LSMTree::open(&path, Options { flush_interval_millis: 60_000, ..options }).unwrap();
+        let started = Instant::now();
+        lsm.close().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_access_hints() {
+        for read_path in [ReadPath::Mmap, ReadPath::Pread] {
+            let path: PathBuf = test_dir(&format!("access_hints_{:?}", read_path));
+            let options = Options { read_path, access_hints: true, compaction_trigger: 3, ..Options::default() };
+            let lsm = LSMTree::open(&path, options.clone()).unwrap();
+            for i in 0..100u64 {
+                lsm.insert(i, Vector::new(i, vec![i as f64; 8])).unwrap();
+            }
+            lsm.flush().unwrap();
+            lsm.delete_range(20, 30).unwrap();
+            lsm.compact().unwrap();
+            let keys = |entries: Vec<(u64, Vector)>| entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+            let expected: Vec<u64> = (0..20).chain(30..100).collect();
+            assert_eq!(keys(lsm.iter().collect::<Result<_, _>>().unwrap()), expected);
+            assert_eq!(keys(lsm.range(15..35).unwrap()), (15..20).chain(30..35).collect::<Vec<_>>());
+            // an iterator's hints are held while another reads the same tables
+            let mut reversed = lsm.iter_rev();
+            assert_eq!(reversed.next().unwrap().unwrap().0, 99);
+            assert_eq!(lsm.get(50).unwrap().unwrap().data(), &vec![50.0; 8]);
+            assert_eq!(reversed.count(), expected.len() - 1);
+            drop(lsm);
+
+            let lsm = LSMTree::open(&path, options).unwrap();
+            assert_eq!(keys(lsm.range(..).unwrap()), expected);
+        }
+    }
+
+    #[test]
+    fn test_get_with() {
+        for read_path in [ReadPath::Mmap, ReadPath::Pread] {
+            let path: PathBuf = test_dir(&format!("get_with_{:?}", read_path));
+            let lsm = LSMTree::open(&path, Options { read_path, fix_dimension: false, ..Options::default() }).unwrap();
+            for i in 0..20u64 {
+                lsm.insert(i, Vector::new(i, vec![i as f64, 0.5, -1.0]).with_metadata("even", i % 2 == 0)).unwrap();
+            }
+            lsm.insert_with_ttl(30, Vector::new(30, vec![3.0]), Duration::from_millis(1)).unwrap();
+            lsm.flush().unwrap();
+            lsm.delete(3).unwrap();
+            lsm.insert(4, Vector::new(4, vec![4.0])).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+
+            let sum = lsm.get_with(7, |value| value.map(|value| value.iter().sum::<f64>())).unwrap();
+            assert_eq!(sum, Some(6.5));
+            lsm.get_with(7, |value| {
+                let value = value.unwrap();
+                assert_eq!((value.id(), value.len(), value.get(0), value.get(3)), (7, 3, Some(7.0), None));
+                // a mapped table lends its entries out in place
+                if read_path == ReadPath::Mmap {
+                    assert_eq!(value.as_slice(), Some(&[7.0, 0.5, -1.0][..]));
+                }
+                assert_eq!(value.to_vector().unwrap(), lsm.get(7).unwrap().unwrap());
+            }).unwrap();
+            // the memtable's values, deletes and expired records
+            assert_eq!(lsm.get_with(4, |value| value.unwrap().as_slice().map(<[f64]>::to_vec)).unwrap(), Some(vec![4.0]));
+            assert!(lsm.get_with(3, |value| value.is_none()).unwrap());
+            assert!(lsm.get_with(30, |value| value.is_none()).unwrap());
+            assert!(lsm.get_with(99, |value| value.is_none()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_element_types() {
+        let data: Vec<f64> = (0..64).map(|i| i as f64 / 10.0).collect();
+        let mut sizes = Vec::new();
+        for element_type in [ElementType::F64, ElementType::F32, ElementType::F16, ElementType::BF16, ElementType::Binary] {
+            let path: PathBuf = test_dir(&format!("element_type_{:?}", element_type));
+            let lsm = LSMTree::new(&path).unwrap();
+            lsm.set_element_type(element_type).unwrap();
+            lsm.insert(1, Vector::new(1, data.clone())).unwrap();
+            assert!(matches!(lsm.set_element_type(ElementType::F64), Err(LsmError::InvalidArgument(_))));
+            // the data reads back the same before and after it is stored
+            let mut expected = data.clone();
+            element_type.round(&mut expected);
+            assert_eq!(lsm.get(1).unwrap().unwrap().data(), &expected);
+            lsm.flush().unwrap();
+            assert_eq!(lsm.get(1).unwrap().unwrap().data(), &expected);
+            lsm.get_with(1, |value| {
+                let value = value.unwrap();
+                assert_eq!(value.element_type(), element_type);
+                assert_eq!(value.iter().collect::<Vec<_>>(), expected);
+                assert_eq!(value.as_f32_slice().is_some(), element_type == ElementType::F32);
+                assert_eq!(value.as_bits().is_some(), element_type == ElementType::Binary);
+            }).unwrap();
+            sizes.push(lsm.describe()[0].file_size);
+            drop(lsm);
+
+            let lsm = LSMTree::new(&path).unwrap();
+            assert_eq!(lsm.element_type(), element_type);
+            assert_eq!(lsm.get(1).unwrap().unwrap().data(), &expected);
+        }
+        // 64 elements of 8, 4 and 2 bytes, and of a bit
+        assert!(sizes[1] + 250 < sizes[0] && sizes[2] + 120 < sizes[1] && sizes[3] == sizes[2] && sizes[4] + 100 < sizes[3], "{:?}", sizes);
+
+        // external tables must hold data the tree's type does
+        let source = test_dir("element_type_source");
+        std::fs::create_dir_all(&source).unwrap();
+        let file = source.join("external.sdb");
+        let mut writer = sstable::SSTableWriter::create(&file).unwrap();
+        writer.add(2, &Vector::new(2, data.clone())).unwrap();
+        writer.finish().unwrap();
+        let lsm = LSMTree::new(&test_dir("element_type_ingest")).unwrap();
+        lsm.set_element_type(ElementType::F32).unwrap();
+        assert!(matches!(lsm.ingest_external_file(&file), Err(LsmError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_dimension() {
+        let path = test_dir("dimension");
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(matches!(lsm.set_dimension(0), Err(LsmError::InvalidArgument(_))));
+        lsm.set_dimension(3).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0, 2.0, 3.0])).unwrap();
+        let err = lsm.insert(2, Vector::new(2, vec![1.0, 2.0])).unwrap_err();
+        assert!(matches!(err, LsmError::InvalidArgument(_)));
+        assert!(err.to_string().contains("dimension is 3"), "{}", err);
+        assert!(matches!(lsm.set_dimension(2), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(lsm.knn(&[1.0, 2.0], 1), Err(LsmError::InvalidArgument(_))));
+        assert_eq!(lsm.knn(&[1.0, 2.0, 3.0], 1).unwrap(), vec![(1, 0.0)]);
+        assert!(matches!(lsm.bulk_load(vec![(3, Vector::new(3, vec![1.0]))]), Err(LsmError::InvalidArgument(_))));
+        lsm.close().unwrap();
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.dimension(), Some(3));
+        assert!(lsm.insert(2, Vector::new(2, vec![0.0; 4])).is_err());
+        lsm.close().unwrap();
+
+        // without one set, the first insert decides with fix_dimension, also in a batch
+        let options = Options { fix_dimension: true, ..Options::default() };
+        let lsm = LSMTree::open(&test_dir("dimension_fixed"), options).unwrap();
+        assert_eq!(lsm.dimension(), None);
+        let mut batch = WriteBatch::new();
+        batch.put(1, Vector::new(1, vec![1.0, 2.0]));
+        batch.put(2, Vector::new(2, vec![1.0]));
+        assert!(matches!(lsm.write(batch), Err(LsmError::InvalidArgument(_))));
+        assert!(lsm.get(1).unwrap().is_none());
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        assert_eq!(lsm.dimension(), Some(1));
+        assert!(lsm.insert(2, Vector::new(2, vec![1.0, 2.0])).is_err());
+
+        // and the first vector of a bulk load, kept once the load is
+        let path = test_dir("dimension_bulk");
+        let lsm = LSMTree::open(&path, Options { fix_dimension: true, ..Options::default() }).unwrap();
+        let mixed = vec![(1, Vector::new(1, vec![1.0, 2.0])), (2, Vector::new(2, vec![1.0]))];
+        assert!(matches!(lsm.bulk_load(mixed), Err(LsmError::InvalidArgument(_))));
+        assert_eq!(lsm.dimension(), None);
+        lsm.bulk_load(vec![(1, Vector::new(1, vec![1.0, 2.0])), (2, Vector::new(2, vec![3.0, 4.0]))]).unwrap();
+        assert_eq!(lsm.dimension(), Some(2));
+        lsm.close().unwrap();
+        let lsm = LSMTree::open(&path, Options { fix_dimension: true, ..Options::default() }).unwrap();
+        assert_eq!(lsm.dimension(), Some(2));
+        assert!(lsm.insert(3, Vector::new(3, vec![1.0])).is_err());
+
+        // as it is by default
+        let lsm = LSMTree::new(&test_dir("dimension_default")).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        assert!(matches!(lsm.insert(2, Vector::new(2, vec![1.0, 2.0])), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(lsm.knn(&[1.0, 2.0], 1), Err(LsmError::InvalidArgument(_))));
+        for query in [[f64::NAN], [f64::INFINITY]] {
+            assert!(matches!(lsm.knn(&query, 1), Err(LsmError::InvalidArgument(_))));
+            assert!(matches!(lsm.search_batch(&[vec![1.0], query.to_vec()], 1), Err(LsmError::InvalidArgument(_))));
+        }
+        assert_eq!(lsm.knn(&[0.5], 1).unwrap(), vec![(1, 0.5)]);
+
+        // and without it, any dimension goes
+        let lsm = LSMTree::open(&test_dir("dimension_any"), Options { fix_dimension: false, ..Options::default() }).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.insert(2, Vector::new(2, vec![1.0, 2.0])).unwrap();
+        assert_eq!(lsm.dimension(), None);
+    }
+
+    #[test]
+    fn test_dedup_vectors() {
+        let table_bytes = |lsm: &LSMTree| lsm.inner.state().sstables.iter().map(|table| table.file_size()).sum::<u64>();
+        let embedding = |key: u64| Vector::new(key, vec![(key % 3) as f64; 64]);
+        let mut sizes = Vec::new();
+        for dedup_vectors in [false, true] {
+            let options = Options { dedup_vectors, sstable_size: 1000, compaction_trigger: 0, ..Options::default() };
+            let lsm = LSMTree::open(&test_dir(&format!("dedup_vectors_{}", dedup_vectors)), options).unwrap();
+            for key in 0..60 {
+                lsm.insert(key, embedding(key)).unwrap();
+                if key % 20 == 19 {
+                    lsm.flush().unwrap();
+                }
+            }
+            // the first entries with each data are gone from the merged table, and the rest
+            // share what is left
+            for key in 0..3 {
+                lsm.delete(key).unwrap();
+            }
+            lsm.compact().unwrap();
+            assert_eq!(lsm.inner.state().sstables.len(), 1);
+            for key in 3..60 {
+                assert_eq!(lsm.get(key).unwrap().unwrap(), embedding(key));
+            }
+            assert!(lsm.get(0).unwrap().is_none());
+            assert_eq!(lsm.knn(&[1.0; 64], 3).unwrap().iter().map(|&(key, _)| key % 3).collect::<Vec<_>>(), vec![1, 1, 1]);
+            assert!(lsm.verify().unwrap().passed());
+            sizes.push(table_bytes(&lsm));
+        }
+        // 57 entries of 512 bytes of data each, 3 of which keep theirs
+        assert!(sizes[1] + 54 * 500 < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_ttl_expires_entries() {
+        let path: PathBuf = test_dir("ttl_expires_entries");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.flush().unwrap();
+        // an expired entry hides the older version instead of falling through to it
+        lsm.insert_with_ttl(1, Vector::new(1, vec![2.0]), Duration::ZERO).unwrap();
+        lsm.insert_with_ttl(2, Vector::new(2, vec![2.0]), Duration::from_secs(3600)).unwrap();
+        assert!(lsm.get(1).unwrap().is_none());
+        assert!(lsm.get(2).unwrap().unwrap().expires_at().is_some());
+        assert_eq!(lsm.range(..).unwrap().len(), 1);
+
+        // survives a flush and a reopen
+        lsm.flush().unwrap();
+        lsm.close().unwrap();
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(lsm.get(1).unwrap().is_none());
+        assert_eq!(lsm.get(2).unwrap().unwrap().data(), &vec![2.0]);
+
+        // a compaction that includes the oldest table drops the expired entry for good
+        for i in 10..40 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+        assert!(!lsm.inner.state().sstables.iter().any(|t| t.contains_key(1).unwrap()));
+        assert!(lsm.get(2).unwrap().is_some());
     }
 
-    pub fn insert(&mut self, key: u64, value: Vector) -> io::Result<()> {
-        self.memtable.insert(key, value);
-        if self.memtable.len() >= self.sstable_size {
-            self.flush_memtable()?;
+    #[test]
+    fn test_delete_range() {
+        let path: PathBuf = test_dir("delete_range");
+        let options = Options { compaction_trigger: 2, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..15 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
         }
-        Ok(())
+        lsm.inner.wait_for_flushes().unwrap();
+        // covers keys in a table and in the memtable
+        lsm.delete_range(5, 12).unwrap();
+        lsm.insert(7, Vector::new(7, vec![-7.0])).unwrap();
+        let keys = |lsm: &LSMTree| lsm.range(..).unwrap().into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(&lsm), vec![0, 1, 2, 3, 4, 7, 12, 13, 14]);
+        assert!(lsm.get(5).unwrap().is_none());
+        assert!(lsm.get(11).unwrap().is_none());
+        assert_eq!(lsm.get(7).unwrap().unwrap().data(), &vec![-7.0]);
+
+        // survives a reopen from the WAL, then from a table
+        lsm.close().unwrap();
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(keys(&lsm), vec![0, 1, 2, 3, 4, 7, 12, 13, 14]);
+        lsm.flush().unwrap();
+        lsm.close().unwrap();
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(keys(&lsm), vec![0, 1, 2, 3, 4, 7, 12, 13, 14]);
+
+        // compacting down to the oldest table reclaims the covered entries and the tombstone
+        lsm.inner.wait_for_idle().unwrap();
+        let state = lsm.inner.state();
+        assert_eq!(state.sstables.len(), 1);
+        assert!(!state.sstables[0].contains_key(6).unwrap());
+        assert!(state.sstables[0].range_tombstones.is_empty());
+        drop(state);
+        assert_eq!(keys(&lsm), vec![0, 1, 2, 3, 4, 7, 12, 13, 14]);
     }
 
-    pub fn get(&self, key: u64) -> Option<Vector> {
-        if let Some(value) = self.memtable.get(&key) {
-            return Some(value.clone());
+    #[test]
+    fn test_delete_range_bounds() {
+        let lsm = LSMTree::new(&test_dir("delete_range_bounds")).unwrap();
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
         }
+        let sequence = lsm.sequence();
+        assert!(matches!(lsm.delete_range(6, 2), Err(LsmError::InvalidArgument(_))));
+        // an inverted range fails the whole batch
+        let mut batch = WriteBatch::new();
+        batch.delete(1).delete_range(9, 8);
+        assert!(matches!(lsm.write(batch), Err(LsmError::InvalidArgument(_))));
+        assert!(lsm.get(1).unwrap().is_some());
 
-        // TODO: check tombstones on delete
-        for sstable in self.sstables.iter().rev() {
-            if let Some(_) = sstable.tombstones.get(&key) {
-                return None;
-            }
-            if let Some(&offset) = sstable.index.get(&key) {
-                let Ok((_, value)) = self.read_value_from_sstable(&sstable.mmap, offset) else {return None};
-                       return Some(value);
+        // an empty range is no write at all, alone or in a batch
+        lsm.delete_range(4, 4).unwrap();
+        assert_eq!(lsm.sequence(), sequence);
+        let mut batch = WriteBatch::new();
+        batch.delete_range(3, 3).delete(1);
+        lsm.write(batch).unwrap();
+        assert_eq!(lsm.sequence(), sequence + 1);
+        assert!(lsm.inner.state().range_tombstones.is_empty());
+        lsm.flush().unwrap();
+        assert!(lsm.inner.state().sstables.iter().all(|table| table.range_tombstones.is_empty()));
+        assert_eq!(lsm.len().unwrap(), 9);
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let path: PathBuf = test_dir("contains_key");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.insert_with_ttl(20, Vector::new(20, vec![20.0]), Duration::ZERO).unwrap();
+        lsm.insert_with_ttl(21, Vector::new(21, vec![21.0]), Duration::from_secs(3600)).unwrap();
+        lsm.flush().unwrap();
+        lsm.delete(3).unwrap();
+        lsm.delete_range(5, 7).unwrap();
+        lsm.insert(30, Vector::new(30, vec![30.0])).unwrap();
+
+        for key in 0..40 {
+            assert_eq!(lsm.contains_key(key).unwrap(), lsm.get(key).unwrap().is_some(), "key {}", key);
+        }
+        assert!(lsm.contains_key(21).unwrap());
+        assert!(!lsm.contains_key(20).unwrap());
+        assert!(!lsm.contains_key(3).unwrap());
+        assert!(!lsm.contains_key(6).unwrap());
+    }
+
+    #[test]
+    fn test_delete_fetch() {
+        let path: PathBuf = test_dir("delete_fetch");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.insert(20, Vector::new(20, vec![20.0])).unwrap();
+
+        assert_eq!(lsm.delete_fetch(3).unwrap().unwrap().data(), &vec![3.0]);
+        assert_eq!(lsm.delete_fetch(20).unwrap().unwrap().data(), &vec![20.0]);
+        assert!(lsm.delete_fetch(3).unwrap().is_none());
+        assert!(lsm.get(3).unwrap().is_none());
+
+        // a missing key is left alone without logging anything, however many tables it misses
+        let sequence = lsm.sequence();
+        assert!(lsm.delete_fetch(100).unwrap().is_none());
+        assert!(!lsm.delete(100).unwrap());
+        assert!(!lsm.delete(3).unwrap());
+        assert_eq!(lsm.sequence(), sequence);
+        assert!(lsm.delete(4).unwrap());
+        assert_eq!(lsm.sequence(), sequence + 1);
+    }
+
+    #[test]
+    fn test_delete_many() {
+        let path: PathBuf = test_dir("delete_many");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.insert(20, Vector::new(20, vec![20.0])).unwrap();
+        lsm.delete(5).unwrap();
+
+        // one record for every key found, a repeated key found only once
+        let sequence = lsm.sequence();
+        assert_eq!(lsm.delete_many(&[3, 20, 5, 100, 3, 7]).unwrap(), vec![true, true, false, false, false, true]);
+        assert_eq!(lsm.sequence(), sequence + 3);
+        assert!(lsm.get(3).unwrap().is_none() && lsm.get(20).unwrap().is_none() && lsm.get(7).unwrap().is_none());
+        assert!(lsm.get(4).unwrap().is_some());
+
+        // nothing to delete logs nothing
+        assert_eq!(lsm.delete_many(&[3, 100]).unwrap(), vec![false, false]);
+        assert!(lsm.delete_many(&[]).unwrap().is_empty());
+        assert_eq!(lsm.sequence(), sequence + 3);
+
+        drop(lsm);
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.len().unwrap(), 7);
+        assert!(lsm.get(3).unwrap().is_none() && lsm.get(20).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_len() {
+        let path: PathBuf = test_dir("len");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert!(lsm.is_empty().unwrap());
+        assert_eq!(lsm.approximate_len(), 0);
+        for i in 0..15 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.inner.wait_for_flushes().unwrap();
+        assert_eq!(lsm.len().unwrap(), 15);
+        assert_eq!(lsm.approximate_len(), 15);
+
+        lsm.delete(2).unwrap();
+        lsm.delete(12).unwrap();
+        assert_eq!(lsm.len().unwrap(), 13);
+        assert_eq!(lsm.approximate_len(), 13);
+
+        // an overwrite of a flushed key is counted in both places until compaction
+        lsm.insert(0, Vector::new(0, vec![-1.0])).unwrap();
+        assert_eq!(lsm.len().unwrap(), 13);
+        assert_eq!(lsm.approximate_len(), 14);
+    }
+
+    #[test]
+    fn test_approximate_size_of_range() {
+        let path: PathBuf = test_dir("approximate_size_of_range");
+        let lsm = LSMTree::open(&path, Options { sstable_size: 1000, ..Options::default() }).unwrap();
+        for i in 0..100 {
+            lsm.insert(i, Vector::new(i, vec![i as f64; 8])).unwrap();
+        }
+        lsm.flush().unwrap();
+        let tables = lsm.describe();
+        assert_eq!(tables.len(), 1);
+        let all = lsm.approximate_size_of_range(0, 100).unwrap();
+        assert!(all > tables[0].file_size / 2 && all < tables[0].file_size);
+        assert_eq!(lsm.approximate_size_of_range(0, u64::MAX).unwrap(), all);
+        assert_eq!(lsm.approximate_size_of_range(0, 50).unwrap(), all / 2);
+        assert_eq!(lsm.approximate_size_of_range(25, 50).unwrap(), all / 4);
+        assert_eq!(lsm.approximate_size_of_range(100, 200).unwrap(), 0);
+        assert_eq!(lsm.approximate_size_of_range(50, 50).unwrap(), 0);
+        assert_eq!(lsm.approximate_size_of_range(60, 40).unwrap(), 0);
+
+        // the memtable's entries count alongside the table's
+        for i in 200..210 {
+            lsm.insert(i, Vector::new(i, vec![i as f64; 8])).unwrap();
+        }
+        let memtable = lsm.approximate_size_of_range(200, 210).unwrap();
+        assert_eq!(memtable, lsm.stats().memtable_bytes as u64);
+        assert_eq!(lsm.approximate_size_of_range(0, 300).unwrap(), all + memtable);
+    }
+
+    #[test]
+    fn test_stats() {
+        let path: PathBuf = test_dir("stats");
+        let options = Options { compaction_trigger: 2, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        for i in 0..20 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.insert(100, Vector::new(100, vec![100.0])).unwrap();
+        lsm.inner.wait_for_idle().unwrap();
+
+        assert!(lsm.get(100).unwrap().is_some());
+        assert!(lsm.get(3).unwrap().is_some());
+        assert!(lsm.get(1000).unwrap().is_none());
+        let stats = lsm.stats();
+        assert_eq!(stats.gets, 3);
+        assert_eq!(stats.memtable_hits, 1);
+        // the one table left is probed for the key not in the memtable, and passed over for
+        // the one past its keys
+        assert_eq!(stats.table_count, 1);
+        assert_eq!((stats.sstable_probes, stats.key_range_skips), (1, 1));
+        assert_eq!(stats.flushes, 2);
+        assert_eq!(stats.compactions, 1);
+        assert!(stats.bytes_written > 0 && stats.bytes_flushed > 0 && stats.bytes_compacted > 0);
+        assert!(stats.write_amplification() > 1.0);
+
+        // the bytes written are whole frames, headers included, as they are in the log
+        let fresh = LSMTree::open(&test_dir("stats_frames"), Options::default()).unwrap();
+        fresh.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        let log = fresh.inner.directory.join(wal::wal_file_name(fresh.inner.writer().wal.number()));
+        assert_eq!(fresh.stats().bytes_written, std::fs::metadata(log).unwrap().len());
+
+        // snapshot reads count towards the tree's stats
+        lsm.snapshot().get(3).unwrap();
+        assert_eq!(lsm.stats().gets, 4);
+    }
+
+    #[test]
+    fn test_write_rate_limit() {
+        let path: PathBuf = test_dir("write_rate_limit");
+        let lsm = LSMTree::open(&path, Options { write_ops_per_sec: 20, fix_dimension: false, ..Options::default() }).unwrap();
+        assert_eq!(lsm.write_rate_limit(), (0, 20));
+        // a second's worth goes through at once, the rest is paced
+        let start = Instant::now();
+        for i in 0..30 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400), "{:?}", start.elapsed());
+        assert!(lsm.stats().write_throttled_micros > 0);
+
+        // a large batch runs into the byte budget
+        lsm.set_write_rate_limit(8 * 1024, 0);
+        assert_eq!(lsm.write_rate_limit(), (8 * 1024, 0));
+        let throttled = lsm.stats().write_throttled_micros;
+        lsm.insert(100, Vector::new(100, vec![1.0; 768])).unwrap();
+        assert_eq!(lsm.stats().write_throttled_micros, throttled);
+        let start = Instant::now();
+        lsm.insert(101, Vector::new(101, vec![1.0; 768])).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400), "{:?}", start.elapsed());
+
+        lsm.set_write_rate_limit(0, 0);
+        let throttled = lsm.stats().write_throttled_micros;
+        for i in 200..300 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        assert_eq!(lsm.stats().write_throttled_micros, throttled);
+        assert_eq!(lsm.len().unwrap(), 132);
+
+        // small records are paced on their whole frames, header included: with a budget of
+        // 20 frames a second, 30 of them take half a second
+        let log = path.join(wal::wal_file_name(lsm.inner.writer().wal.number()));
+        let before = std::fs::metadata(&log).unwrap().len();
+        lsm.insert(300, Vector::new(300, vec![1.0])).unwrap();
+        let frame = std::fs::metadata(&log).unwrap().len() - before;
+        lsm.set_write_rate_limit(20 * frame, 0);
+        let start = Instant::now();
+        for i in 301..331 {
+            lsm.insert(i, Vector::new(i, vec![1.0])).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(490), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_latency_histograms() {
+        let path: PathBuf = test_dir("latency_histograms");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        lsm.get(1).unwrap();
+        assert_eq!(lsm.stats().get_latency, Latency::default());
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, Options { latency_histograms: true, ..Options::default() }).unwrap();
+        for i in 0..10 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.delete(3).unwrap();
+        lsm.get(1).unwrap();
+        lsm.get(100).unwrap();
+        lsm.flush().unwrap();
+        lsm.knn(&[2.0], 2).unwrap();
+        let stats = lsm.stats();
+        assert_eq!((stats.write_latency.count, stats.get_latency.count), (11, 2));
+        assert_eq!((stats.flush_latency.count, stats.search_latency.count), (1, 1));
+        let flush = &stats.flush_latency;
+        assert!(flush.max_micros > 0 && flush.percentile_micros(50.0) == flush.max_micros);
+        assert!(stats.write_latency.percentile_micros(99.0) <= stats.write_latency.max_micros);
+    }
+
+    #[test]
+    fn test_key_range_pruning() {
+        let path: PathBuf = test_dir("key_range_pruning");
+        let options = Options { sstable_size: 10, compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        // three tables of disjoint keys, and one deleting a range across the first two
+        for base in [0, 100, 200] {
+            for i in base..base + 10 {
+                lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
             }
         }
+        lsm.delete_range(5, 105).unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(lsm.stats().table_count, 4);
+
+        assert!(lsm.get(205).unwrap().is_some());
+        assert!(lsm.get(7).unwrap().is_none());
+        assert!(lsm.get(150).unwrap().is_none());
+        let stats = lsm.stats();
+        // the newest table holds only the range tombstone, so each lookup passes over it;
+        // 205 is then found, 7 is deleted by it, and 150 passes over every table
+        assert_eq!((stats.sstable_probes, stats.key_range_skips), (1, 1 + 1 + 4));
 
-        None
+        // scans leave out the tables outside their bounds, but not range tombstones over them
+        let layers = |start, end| Iter::bounded(lsm.inner.state().clone(), Options::default(), (Bound::Included(start), Bound::Excluded(end)), None).layers.len();
+        assert_eq!(layers(200, 210), 2);
+        assert_eq!(layers(0, 50), 3);
+        assert_eq!(lsm.range(100..110).unwrap().len(), 5);
+        assert_eq!(lsm.range(0..10).unwrap().iter().map(|(k, _)| *k).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
     }
 
-    pub fn delete(&mut self, key: u64) -> io::Result<()> {
-        if let Some(_) = self.memtable.remove(&key) {
-            return Ok(());
+    #[test]
+    fn test_commit_window() {
+        let path: PathBuf = test_dir("commit_window");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        assert_eq!(lsm.stats().commit_batch_size(), 1.0);
+        drop(lsm);
+
+        let path: PathBuf = test_dir("commit_window_coalesced");
+        let options = Options { commit_window_micros: 20_000, sstable_size: 1000, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        std::thread::scope(|s| {
+            for t in 0..8u64 {
+                let lsm = &lsm;
+                s.spawn(move || {
+                    for i in 0..10 {
+                        let key = t * 100 + i;
+                        let mut batch = WriteBatch::new();
+                        batch.put(key, Vector::new(key, vec![key as f64]));
+                        // the write is synced by the time it returns
+                        let sequence = lsm.write_sequenced(batch).unwrap();
+                        assert!(lsm.synced_sequence() >= sequence);
+                    }
+                });
+            }
+        });
+        let stats = lsm.stats();
+        assert_eq!(stats.synced_commits, 80);
+        assert!(stats.commit_batch_size() > 1.0, "{:?}", stats);
+        assert_eq!(lsm.inner.writer().synced_sequence, 80);
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.len().unwrap(), 80);
+    }
+
+    #[test]
+    fn test_iter() {
+        let path: PathBuf = test_dir("iter");
+        let options = Options {
+            compaction_trigger: 0,
+            merge_operator: Some(Arc::new(AppendOperator)),
+            ..Options::default()
+        };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert!(lsm.iter().next().is_none());
+        for i in (0..25).rev() {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
         }
+        lsm.inner.wait_for_flushes().unwrap();
+        // overwrites, deletes and merges spread over the memtable and both tables
+        lsm.insert(3, Vector::new(3, vec![-3.0])).unwrap();
+        lsm.delete(7).unwrap();
+        lsm.delete(21).unwrap();
+        lsm.delete_range(10, 13).unwrap();
+        lsm.merge(4, 1.0f64.to_le_bytes().to_vec()).unwrap();
+        lsm.merge(40, 1.0f64.to_le_bytes().to_vec()).unwrap();
+        lsm.insert_with_ttl(5, Vector::new(5, vec![5.0]), Duration::ZERO).unwrap();
 
-        for sstable in self.sstables.iter_mut().rev() {
-            if let Some(_) = sstable.index.get(&key) {
-                sstable.tombstones.insert(key);
-                return Ok(());
+        let mut iter = lsm.iter();
+        lsm.insert(30, Vector::new(30, vec![30.0])).unwrap();
+        let first = iter.next().unwrap().unwrap();
+        assert_eq!(first.0, 0);
+        let mut entries = vec![first];
+        entries.extend(iter.map(|e| e.unwrap()));
+        let keys: Vec<u64> = entries.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![0, 1, 2, 3, 4, 6, 8, 9, 13, 14, 15, 16, 17, 18, 19, 20, 22, 23, 24, 40]);
+        assert_eq!(entries[3].1.data(), &vec![-3.0]);
+        // the iterator agrees with point lookups
+        for (key, value) in entries.iter() {
+            assert_eq!(lsm.get(*key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(lsm.iter().count(), keys.len() + 1);
+    }
+
+    // Random writes, flushes and reopens checked against a plain map after every step,
+    // with compactions running in the background. Point deletes are left out for now:
+    // they only drop a key from the layer holding its newest value, see `State::remove`.
+    #[test]
+    fn test_iteration_matches_model() {
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+
+        for seed in 0..4u64 {
+            let path: PathBuf = test_dir(&format!("iteration_matches_model_{}", seed));
+            let options = Options { sstable_size: 8, compaction_trigger: 3, ..merge_options() };
+            let mut lsm = LSMTree::open(&path, options.clone()).unwrap();
+            let mut model: BTreeMap<u64, Vector> = BTreeMap::new();
+            let mut rng = StdRng::seed_from_u64(seed);
+            for step in 0..400 {
+                let key = rng.random_range(0..48u64);
+                match rng.random_range(0..100) {
+                    0..50 => {
+                        let value = Vector::new(key, vec![step as f64]);
+                        lsm.insert(key, value.clone()).unwrap();
+                        model.insert(key, value);
+                    }
+                    50..58 => assert_eq!(lsm.delete(key).unwrap(), model.remove(&key).is_some(), "seed {} step {}", seed, step),
+                    58..68 => {
+                        let end = key + rng.random_range(1..8);
+                        lsm.delete_range(key, end).unwrap();
+                        model.retain(|k, _| !(key..end).contains(k));
+                    }
+                    68..85 => {
+                        lsm.merge(key, (step as f64).to_le_bytes().to_vec()).unwrap();
+                        let mut data = model.get(&key).map(|v| v.data().clone()).unwrap_or_default();
+                        data.push(step as f64);
+                        model.insert(key, Vector::new(key, data));
+                    }
+                    85..97 => lsm.flush().unwrap(),
+                    _ => {
+                        lsm.close().unwrap();
+                        lsm = LSMTree::open(&path, options.clone()).unwrap();
+                    }
+                }
+
+                let entries: Vec<(u64, Vector)> = lsm.iter().map(|e| e.unwrap()).collect();
+                let expected: Vec<(u64, Vector)> = model.iter().map(|(k, v)| (*k, v.clone())).collect();
+                assert_eq!(entries, expected, "seed {} step {}", seed, step);
+                let reversed: Vec<(u64, Vector)> = lsm.iter_rev().map(|e| e.unwrap()).collect();
+                assert!(reversed.iter().eq(expected.iter().rev()), "seed {} step {}", seed, step);
+                let (start, end) = (key.saturating_sub(5), key + 5);
+                let expected: Vec<(u64, Vector)> = model.range(start..end).map(|(k, v)| (*k, v.clone())).collect();
+                assert_eq!(lsm.range(start..end).unwrap(), expected, "seed {} step {}", seed, step);
             }
         }
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let path: PathBuf = test_dir("iter_rev");
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 0, ..Options::default() }).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![0.0])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.insert(23, Vector::new(23, vec![1.0])).unwrap();
+        lsm.delete(24).unwrap();
+        let snapshot = lsm.snapshot();
+        lsm.insert(30, Vector::new(30, vec![0.0])).unwrap();
 
-        Err(io::Error::new(io::ErrorKind::NotFound, format!("Could not find key '{}'", key)))
+        let latest: Vec<(u64, f64)> = lsm.iter_rev().take(3).map(|e| e.map(|(k, v)| (k, v.data()[0])).unwrap()).collect();
+        assert_eq!(latest, vec![(30, 0.0), (23, 1.0), (22, 0.0)]);
+        assert_eq!(snapshot.iter_rev().next().unwrap().unwrap().0, 23);
+        assert_eq!(lsm.iter_rev().count(), 25);
     }
 
-    // TODO: refactor for any memtable, to re-use in compaction
-    fn flush_memtable(&mut self) -> io::Result<()> {
-        let sstable_path = self.directory.join(format!("sstable_{}.sdb", self.sstables.len()));
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&sstable_path)?;
+    #[test]
+    fn test_seek() {
+        let path: PathBuf = test_dir("seek");
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 0, ..merge_options() }).unwrap();
+        for i in (0..40).step_by(2) {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.delete(10).unwrap();
+        lsm.insert(13, Vector::new(13, vec![13.0])).unwrap();
+        lsm.merge(15, 1.0f64.to_le_bytes().to_vec()).unwrap();
+        let keys = |iter: &mut Iter, n| iter.take(n).map(|entry| entry.unwrap().0).collect::<Vec<_>>();
 
-        let mut writer = BufWriter::new(&mut file);
-        let index = self.write_buffer(&mut writer);
+        let mut iter = lsm.iter();
+        assert_eq!(keys(&mut iter, 2), vec![0, 2]);
+        iter.seek(9);
+        assert_eq!(keys(&mut iter, 4), vec![12, 13, 14, 15]);
+        // backwards too, and onto a key exactly
+        iter.seek(4);
+        assert_eq!(keys(&mut iter, 2), vec![4, 6]);
+        iter.seek(100);
+        assert!(iter.next().is_none());
+        iter.seek_to_first();
+        assert_eq!(keys(&mut iter, 1), vec![0]);
+        iter.seek_to_last();
+        assert_eq!(keys(&mut iter, 3), vec![38, 36, 34]);
+        iter.seek(15);
+        assert_eq!(keys(&mut iter, 3), vec![15, 14, 13]);
+        iter.seek(11);
+        assert_eq!(keys(&mut iter, 2), vec![8, 6]);
 
-        writer.flush().expect("ERROR flushing");
-        drop(writer);
+        // a bounded iterator stays within its bounds
+        let mut iter = Iter::bounded(lsm.inner.state().clone(), lsm.inner.options.clone(), (Bound::Excluded(4), Bound::Included(20)), None);
+        iter.seek(0);
+        assert_eq!(keys(&mut iter, 1), vec![6]);
+        iter.seek(20);
+        assert_eq!(keys(&mut iter, 2), vec![20]);
+        iter.seek(21);
+        assert!(iter.next().is_none());
+        iter.seek_to_last();
+        assert_eq!(keys(&mut iter, 1), vec![20]);
+        iter.seek(30);
+        assert_eq!(keys(&mut iter, 1), vec![20]);
+        iter.seek(4);
+        assert!(iter.next().is_none());
+        iter.seek(6);
+        assert_eq!(keys(&mut iter, 5), vec![6]);
+    }
 
-        let mmap = unsafe { Mmap::map(&file)? };
-        self.sstables.push( SSTable { mmap, index, tombstones: BTreeSet::new() });
-        self.memtable.clear();
+    #[test]
+    fn test_keys() {
+        let path: PathBuf = test_dir("keys");
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 0, ..merge_options() }).unwrap();
+        for i in 0..25 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.insert_with_ttl(7, Vector::new(7, vec![7.0]), Duration::from_millis(1)).unwrap();
+        lsm.flush().unwrap();
+        lsm.insert_with_ttl(40, Vector::new(40, vec![40.0]), Duration::from_millis(1)).unwrap();
+        lsm.delete(3).unwrap();
+        lsm.delete_range(10, 15).unwrap();
+        lsm.merge(12, 1.0f64.to_le_bytes().to_vec()).unwrap();
+        lsm.merge(50, 5.0f64.to_le_bytes().to_vec()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
 
-        Ok(())
+        let keys: Vec<u64> = lsm.keys().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<u64> = lsm.iter().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(keys, (0..25).filter(|&k| (k != 3 && k != 7 && !(10..15).contains(&k)) || k == 12).chain([50]).collect::<Vec<_>>());
+        let keys: Vec<u64> = lsm.range_keys(5..=12).collect::<Result<_, _>>().unwrap();
+        assert_eq!(keys, vec![5, 6, 8, 9, 12]);
+        assert_eq!(lsm.range_keys(30..).map(Result::unwrap).collect::<Vec<_>>(), vec![50]);
+        assert_eq!(lsm.range_keys(26..30).count(), 0);
     }
 
-    fn write_buffer<W: Write + Seek>(&mut self, buf: &mut W) -> BTreeMap::<u64, usize> {
-        let mut index = BTreeMap::<u64, usize>::new();
-        let mut offset = 0;
-        for (&key, value) in self.memtable.iter() {
-            let entry_offset = offset;
-            buf.write_u64::<LittleEndian>(key).expect("ERROR writing u64");
-            let serialized = bson::to_vec(value).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())).expect("ERROR serializing vector");
-            buf.write_u32::<LittleEndian>(serialized.len() as u32).expect("ERROR writing u32");
-            buf.write_all(&serialized).expect("ERROR writing vector");
-            index.insert(key, entry_offset);
-            offset = buf.stream_position().expect("ERROR seeking").try_into().unwrap();
+    #[test]
+    fn test_knn() {
+        let path: PathBuf = test_dir("knn");
+        let lsm = LSMTree::new(&path).unwrap();
+        for i in 0..30u64 {
+            lsm.insert(i, Vector::new(i, vec![i as f64, 0.0])).unwrap();
         }
+        lsm.delete(11).unwrap();
 
-        index
+        let found = lsm.knn(&[10.2, 0.0], 3).unwrap();
+        let keys: Vec<u64> = found.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![10, 9, 12]);
+        assert!((found[0].1 - 0.2).abs() < 1e-9);
+        assert_eq!(lsm.knn(&[0.0, 0.0], 100).unwrap().len(), 29);
+        assert!(matches!(lsm.knn(&[0.0], 1), Err(LsmError::InvalidArgument(_))));
     }
 
-    fn read_value_from_buffer<R: Read + Seek>(&self, buf: &mut R) -> io::Result<(u64, Vector)> {
-        let key = buf.read_u64::<LittleEndian>()?;
-        let len = buf.read_u32::<LittleEndian>()? as usize;
-        let mut serialized = vec![0u8; len];
-        buf.read_exact(&mut serialized)?;
-        let v: Vector = bson::from_slice(&serialized).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        Ok((key, v))
+    #[test]
+    fn test_search_batch() {
+        let path: PathBuf = test_dir("search_batch");
+        let options = Options { search_threads: 3, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        let mut rng = rand::rng();
+        // more than a chunk, some of it in a table
+        for i in 0..2500u64 {
+            lsm.insert(i, Vector::new(i, vec![rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0)])).unwrap();
+            if i == 1200 {
+                lsm.flush().unwrap();
+            }
+        }
+        let queries: Vec<Vec<f64>> = (0..7).map(|_| vec![rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0)]).collect();
+        let found = lsm.search_batch(&queries, 4).unwrap();
+        assert_eq!(found.len(), 7);
+        for (query, found) in queries.iter().zip(found) {
+            assert_eq!(found, lsm.knn(query, 4).unwrap());
+        }
+        assert!(lsm.search_batch(&[], 4).unwrap().is_empty());
+        assert!(matches!(lsm.search_batch(&[vec![0.0, 0.0], vec![0.0]], 1), Err(LsmError::InvalidArgument(_))));
     }
 
-    fn read_value_from_sstable(&self, mmap: &Mmap, offset: usize) -> io::Result<(u64, Vector)> {
-        let mut cursor = io::Cursor::new(&mmap[offset..]);
-        let key = cursor.read_u64::<LittleEndian>()?;
-        let len = cursor.read_u32::<LittleEndian>()? as usize;
-        let mut serialized = vec![0u8; len];
-        cursor.read_exact(&mut serialized)?;
-        Ok((key, bson::from_slice(&serialized).expect("Unable to deserialize")))
+    #[test]
+    fn test_binary_rerank() {
+        // the same embeddings binarized in one tree and in full in another: the binary
+        // tree gathers candidates by Hamming distance, the float tree ranks them again
+        let dimension = 96;
+        let binary = LSMTree::new(&test_dir("binary_rerank_bits")).unwrap();
+        binary.set_metric(DistanceMetric::Hamming).unwrap();
+        binary.set_element_type(ElementType::Binary).unwrap();
+        let full = LSMTree::new(&test_dir("binary_rerank_full")).unwrap();
+        let mut rng = rand::rng();
+        let centers: Vec<Vec<f64>> = (0..8).map(|_| (0..dimension).map(|_| rng.random_range(-1.0..1.0)).collect()).collect();
+        for key in 0..400u64 {
+            let data: Vec<f64> = centers[key as usize % 8].iter().map(|x| x + rng.random_range(-0.2..0.2)).collect();
+            binary.insert(key, Vector::new(key, data.clone())).unwrap();
+            full.insert(key, Vector::new(key, data)).unwrap();
+            if key == 250 {
+                binary.flush().unwrap();
+                full.flush().unwrap();
+            }
+        }
+        let queries: Vec<Vec<f64>> = centers.iter().take(3).map(|center| center.iter().map(|x| x + rng.random_range(-0.1..0.1)).collect()).collect();
+
+        let batches = binary.search_batch(&queries, 60).unwrap();
+        for (query, candidates) in queries.iter().zip(batches) {
+            // the distances count the sign bits that differ, read from the packed tables
+            // and the memtable alike
+            assert_eq!(candidates, binary.knn(query, 60).unwrap());
+            let bits = BinaryVector::from_signs(query);
+            for &(key, distance) in candidates.iter() {
+                assert_eq!(distance, bits.hamming(&BinaryVector::from_signs(full.get(key).unwrap().unwrap().data())) as f64);
+                assert_eq!(binary.get(key).unwrap().unwrap().data(), &BinaryVector::from_signs(full.get(key).unwrap().unwrap().data()).to_data());
+            }
+
+            let mut top = TopK::new(5);
+            for (key, _) in candidates {
+                top.push(key, crate::db::search::euclidean(query, full.get(key).unwrap().unwrap().data()));
+            }
+            assert_eq!(top.into_sorted(), full.knn(query, 5).unwrap());
+        }
+        // 12 bytes of data an entry rather than 768
+        let size = |lsm: &LSMTree| lsm.describe().iter().map(|table| table.file_size).sum::<u64>();
+        assert!(size(&binary) * 8 < size(&full), "{} {}", size(&binary), size(&full));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::{Cursor, SeekFrom};
-    use rand::Rng;
+    #[test]
+    fn test_hnsw_search() {
+        let path: PathBuf = test_dir("hnsw_search");
+        let options = Options { hnsw: Some(HnswOptions { m: 8, ef_construction: 64 }), ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert!(matches!(LSMTree::new(&test_dir("hnsw_search_none")).unwrap().search(&[0.0], 1, 10), Err(LsmError::InvalidArgument(_))));
+
+        let mut rng = rand::rng();
+        for i in 0..300u64 {
+            lsm.insert(i, Vector::new(i, vec![rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0)])).unwrap();
+        }
+        let query = [0.2, -0.1, 0.4];
+        let exact = lsm.knn(&query, 5).unwrap();
+        assert_eq!(lsm.search(&query, 5, 100).unwrap(), exact);
+
+        // deletes and overwrites are reflected immediately
+        lsm.delete(exact[0].0).unwrap();
+        lsm.delete_range(exact[1].0, exact[1].0 + 1).unwrap();
+        lsm.insert(1000, Vector::new(1000, query.to_vec())).unwrap();
+        let found = lsm.search(&query, 3, 100).unwrap();
+        assert_eq!(found[0], (1000, 0.0));
+        assert!(!found.iter().any(|(k, _)| *k == exact[0].0 || *k == exact[1].0));
+        assert_eq!(found, lsm.knn(&query, 3).unwrap());
+
+        // a clean close writes the graph out for the next open, which finds nothing to fix
+        lsm.close().unwrap();
+        assert!(path.join(hnsw::HNSW_FILE).exists());
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(lsm.startup_report().reconciled_vectors, 0);
+        assert_eq!(lsm.inner.index.as_ref().unwrap().read().unwrap().len(), 299);
+        assert_eq!(lsm.search(&query, 3, 100).unwrap(), found);
+        drop(lsm);
+
+        // without the file the graph is rebuilt from the stored vectors
+        std::fs::remove_file(path.join(hnsw::HNSW_FILE)).unwrap();
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.search(&query, 3, 100).unwrap(), found);
+
+        let diagnostics = lsm.graph_diagnostics().unwrap();
+        assert_eq!((diagnostics.layer_sizes[0], diagnostics.removed), (299, 0));
+        lsm.repair_graph().unwrap();
+        assert_eq!(lsm.graph_diagnostics().unwrap().unreachable_percent, 0.0);
+        let none = LSMTree::new(&test_dir("hnsw_search_no_graph")).unwrap();
+        assert!(none.graph_diagnostics().is_none());
+        assert!(matches!(none.repair_graph(), Err(LsmError::InvalidArgument(_))));
+    }
 
     #[test]
-    fn test_write_read_buf() {
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let k1: u64 = 1;
-        let v1 = Vector::new(k1, vec![0.0, 1.0]);
-        let _ = lsm.insert(1, v1.clone());
-        let mut buf = Cursor::new(Vec::new());
-        let _index = lsm.write_buffer(&mut buf);
+    fn test_reconcile_index() {
+        let path: PathBuf = test_dir("reconcile_index");
+        let options = Options { hnsw: Some(HnswOptions { m: 8, ef_construction: 64 }), ..Options::default() };
+        let graph_keys = |lsm: &LSMTree| lsm.inner.index.as_ref().unwrap().read().unwrap().keys().collect::<Vec<u64>>();
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..50u64 {
+            lsm.insert(i, Vector::new(i, vec![i as f64, 1.0])).unwrap();
+        }
+        lsm.close().unwrap();
 
-        buf.seek(SeekFrom::Start(0)).unwrap();
+        // crashed before a flush, the saved graph lacks only what the log replays
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(lsm.startup_report().reconciled_vectors, 0);
+        lsm.insert(50, Vector::new(50, vec![50.0, 1.0])).unwrap();
+        lsm.insert(3, Vector::new(3, vec![-3.0, 1.0])).unwrap();
+        lsm.delete(7).unwrap();
+        lsm.abandon();
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(lsm.startup_report().reconciled_vectors, 3);
+        assert_eq!(graph_keys(&lsm), (0..51).filter(|&k| k != 7).collect::<Vec<_>>());
+        assert_eq!(lsm.search(&[-3.0, 1.0], 1, 50).unwrap(), vec![(3, 0.0)]);
+
+        // after a flush it is checked against every stored vector, the writes of the
+        // first crash included as it was never saved since
+        lsm.delete_range(10, 20).unwrap();
+        lsm.insert(60, Vector::new(60, vec![60.0, 1.0])).unwrap();
+        lsm.flush().unwrap();
+        lsm.abandon();
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(lsm.startup_report().reconciled_vectors, 14);
+        assert_eq!(graph_keys(&lsm), lsm.iter().map(|entry| entry.unwrap().0).collect::<Vec<_>>());
+        assert_eq!(lsm.search(&[60.0, 1.0], 1, 50).unwrap(), vec![(60, 0.0)]);
+        lsm.close().unwrap();
+        assert_eq!(LSMTree::open(&path, options).unwrap().startup_report().reconciled_vectors, 0);
+    }
+
+    #[test]
+    fn test_index_queue() {
+        let path: PathBuf = test_dir("index_queue");
+        let options = Options { hnsw: Some(HnswOptions { m: 8, ef_construction: 64 }), index_queue: true, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..100u64 {
+            lsm.insert(i, Vector::new(i, vec![i as f64, 1.0])).unwrap();
+        }
+        lsm.wait_for_index(lsm.sequence()).unwrap();
 
-        let Ok((k2, v2)) = lsm.read_value_from_buffer(&mut buf) else { panic!("could not read from buffer") };
+        // writes don't wait for the graph, they leave their updates queued
+        let graph = lsm.inner.index.as_ref().unwrap().write().unwrap();
+        lsm.insert(1000, Vector::new(1000, vec![50.2, 1.0])).unwrap();
+        lsm.delete(50).unwrap();
+        let sequence = lsm.sequence();
+        assert_eq!(lsm.stats().index_backlog, 2);
+        drop(graph);
+        lsm.wait_for_index(sequence).unwrap();
+        let (found, missing) = lsm.search_lagging(&[50.0, 1.0], 2, 50).unwrap();
+        assert_eq!(missing, 0);
+        assert_eq!(found, lsm.knn(&[50.0, 1.0], 2).unwrap());
+        assert_eq!(found[0].0, 1000);
+        assert!(path.join(queue::INDEX_QUEUE_FILE).exists());
 
-        assert_eq!(k1, k2);
-        assert_eq!(v1, v2);
-        assert_eq!(v1.id(), v2.id());
-        assert_eq!(v1.data(), v2.data());
+        // a close applies what is left before saving the graph
+        for i in 100..200u64 {
+            lsm.insert(i, Vector::new(i, vec![i as f64, 1.0])).unwrap();
+        }
+        lsm.close().unwrap();
+        let lsm = LSMTree::open(&path, Options { index_queue: false, ..options }).unwrap();
+        assert!(!path.join(queue::INDEX_QUEUE_FILE).exists());
+        assert_eq!(lsm.inner.index.as_ref().unwrap().read().unwrap().len(), 200);
+        assert_eq!(lsm.search_lagging(&[150.0, 1.0], 1, 50).unwrap(), (vec![(150, 0.0)], 0));
+        lsm.wait_for_index(u64::MAX).unwrap();
     }
 
     #[test]
-    fn test_write_read_memtable() {
+    fn test_search_filtered() {
+        let scan = LSMTree::new(&test_dir("search_filtered_scan")).unwrap();
+        let options = Options { hnsw: Some(HnswOptions { m: 8, ef_construction: 64 }), ..Options::default() };
+        let graph = LSMTree::open(&test_dir("search_filtered"), options).unwrap();
         let mut rng = rand::rng();
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let v0 = Vector::new(5, vec![rng.random(), rng.random(), rng.random()]);
+        for i in 0..300u64 {
+            let value = Vector::new(i, vec![rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0)]).with_metadata("tenant", (i % 10) as i64).with_metadata("ts", i as i64);
+            scan.insert(i, value.clone()).unwrap();
+            graph.insert(i, value).unwrap();
+        }
 
-        for i in 0..8 {
-            if i == 5 {
-                let _ = lsm.insert(5, v0.clone());
-            } else {
-                let _ = lsm.insert(i, Vector::new(i, vec![rng.random(), rng.random(), rng.random()]));
-            }
+        // a tenant holds a tenth of the vectors, more than the unfiltered ef would find
+        let query = [0.3, -0.2];
+        let filter = Filter::eq("tenant", 3i64).and(Filter::range("ts", Some(100i64.into()), None));
+        let found = scan.search_filtered(&query, 5, &filter).unwrap();
+        assert_eq!(found.len(), 5);
+        assert!(found.iter().all(|(k, _)| k % 10 == 3 && *k >= 100));
+        let expected: Vec<(u64, f64)> = scan.knn(&query, 300).unwrap().into_iter().filter(|(k, _)| k % 10 == 3 && *k >= 100).take(5).collect();
+        assert_eq!(found, expected);
+        assert_eq!(graph.search_filtered(&query, 5, &filter).unwrap(), expected);
+
+        assert!(scan.search_filtered(&query, 5, &Filter::eq("tenant", 11i64)).unwrap().is_empty());
+        assert!(graph.search_filtered(&query, 5, &Filter::eq("tenant", 11i64)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_by_field() {
+        let path: PathBuf = test_dir("get_by_field");
+        let options = Options { sstable_size: 50, indexed_fields: vec!["doc".to_string(), "tag".to_string()], ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert!(lsm.get_by_field("chunk", 1i64).is_err());
+        for i in 0..120u64 {
+            let value = Vector::new(i, vec![i as f64, 1.0]).with_metadata("doc", (i / 10) as i64).with_metadata("tag", ["red", "blue", "green"][i as usize % 3]).with_metadata("chunk", i as i64);
+            lsm.insert(i, value).unwrap();
         }
+        let keys = |lsm: &LSMTree, field: &str, value: MetadataValue| lsm.get_by_field(field, value).unwrap().into_iter().map(|(key, _)| key).collect::<Vec<u64>>();
+        assert_eq!(keys(&lsm, "doc", 3i64.into()), (30..40).collect::<Vec<_>>());
+        assert_eq!(keys(&lsm, "doc", 3.0.into()), (30..40).collect::<Vec<_>>());
+        assert!(keys(&lsm, "doc", "3".into()).is_empty());
 
-        let val = lsm.get(5);
-        assert_eq!(val.unwrap().id(), 5);
+        // overwrites move a key to its new value's list, deletes take it off
+        lsm.insert(31, Vector::new(31, vec![0.0, 0.0]).with_metadata("doc", 99i64)).unwrap();
+        lsm.delete(32).unwrap();
+        lsm.delete_range(35, 38).unwrap();
+        assert_eq!(keys(&lsm, "doc", 3i64.into()), vec![30, 33, 34, 38, 39]);
+        assert_eq!(keys(&lsm, "doc", 99i64.into()), vec![31]);
+        let found = lsm.get_by_field("doc", 99i64).unwrap();
+        assert_eq!(found[0].1.data(), &vec![0.0, 0.0]);
+
+        // the index picks out the vectors to rank, with the same results as a full scan
+        let query = [40.2, 1.0];
+        let filter = Filter::eq("tag", "blue").and(Filter::range("chunk", Some(20i64.into()), Some(60i64.into())));
+        let expected: Vec<(u64, f64)> = lsm.knn(&query, 120).unwrap().into_iter().filter(|(k, _)| k % 3 == 1 && (20..=60).contains(k) && *k != 31 && *k != 37).take(4).collect();
+        assert_eq!(expected.iter().map(|(k, _)| *k).collect::<Vec<_>>(), vec![40, 43, 46, 34]);
+        assert_eq!(lsm.search_filtered(&query, 4, &filter).unwrap(), expected);
+        assert_eq!(lsm.search_filtered(&query, 4, &filter.clone().negate()).unwrap().len(), 4);
+
+        // reopened, the index is read back from its tables
+        lsm.flush().unwrap();
+        drop(lsm);
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert!(!lsm.startup_report().rebuilt_field_indexes);
+        assert_eq!(keys(&lsm, "doc", 3i64.into()), vec![30, 33, 34, 38, 39]);
+        assert_eq!(keys(&lsm, "tag", "green".into()).len(), 40 - 2);
+        assert_eq!(lsm.search_filtered(&query, 4, &filter).unwrap(), expected);
+        drop(lsm);
+
+        let twice = Options { indexed_fields: vec!["doc".to_string(), "doc".to_string()], ..options };
+        assert!(matches!(LSMTree::open(&path, twice), Err(LsmError::InvalidArgument(_))));
     }
 
     #[test]
-    fn test_write_read_sstable() {
+    fn test_field_tables() {
+        let path: PathBuf = test_dir("field_tables");
+        let destination: PathBuf = test_dir("field_tables_checkpoint");
+        let options = Options { sstable_size: 4, indexed_fields: vec!["doc".to_string()], ..Options::default() };
+        let doc = |i: u64, doc: i64| Vector::new(i, vec![i as f64]).with_metadata("doc", doc);
+        let keys = |lsm: &LSMTree, doc: i64| lsm.get_by_field("doc", doc).unwrap().into_iter().map(|(key, _)| key).collect::<Vec<u64>>();
+        let field_files = || std::fs::read_dir(&path).unwrap().filter(|entry| manifest::parse_field_table_file_name(&entry.as_ref().unwrap().file_name().to_string_lossy()).is_some()).count();
+
+        // a tree written without the index has it built once
+        let lsm = LSMTree::open(&path, Options { indexed_fields: Vec::new(), ..options.clone() }).unwrap();
+        for i in 0..6u64 {
+            lsm.insert(i, doc(i, (i % 2) as i64)).unwrap();
+        }
+        drop(lsm);
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert!(lsm.startup_report().rebuilt_field_indexes);
+        assert_eq!(keys(&lsm, 1), vec![1, 3, 5]);
+
+        // flushes write what their memtables filed, and an open reads it back and replays
+        // the log over it
+        for i in 6..60u64 {
+            lsm.insert(i, doc(i, (i % 2) as i64)).unwrap();
+        }
+        lsm.insert(1, doc(1, 7)).unwrap();
+        lsm.delete(3).unwrap();
+        lsm.delete_range(50, 60).unwrap();
+        crash(lsm);
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert!(!lsm.startup_report().rebuilt_field_indexes);
+        assert_eq!(keys(&lsm, 1), (5..50).step_by(2).collect::<Vec<_>>());
+        assert_eq!(keys(&lsm, 7), vec![1]);
+        // merged as they pile up, leaving no file the manifest doesn't list
+        let live = lsm.inner.writer().manifest.field_tables().len();
+        assert!(live < field::TABLE_LIMIT, "{} field tables", live);
+        assert_eq!(field_files(), live);
+
+        // a bulk load's entries go in with its tables, as no log holds them
+        lsm.bulk_load((100..104u64).map(|i| (i, doc(i, 9)))).unwrap();
+        crash(lsm);
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(keys(&lsm, 9), (100..104).collect::<Vec<_>>());
+
+        lsm.insert(200, doc(200, 9)).unwrap();
+        lsm.checkpoint(&destination).unwrap();
+        drop(lsm);
+        let copy = LSMTree::open(&destination, options.clone()).unwrap();
+        assert!(!copy.startup_report().rebuilt_field_indexes);
+        assert_eq!(keys(&copy, 9), vec![100, 101, 102, 103, 200]);
+        drop(copy);
+
+        // other fields are indexed from the stored vectors again, none drops the tables
+        let lsm = LSMTree::open(&path, Options { indexed_fields: vec!["doc".to_string(), "tag".to_string()], ..options.clone() }).unwrap();
+        assert!(lsm.startup_report().rebuilt_field_indexes);
+        assert_eq!(keys(&lsm, 7), vec![1]);
+        drop(lsm);
+        drop(LSMTree::open(&path, Options { indexed_fields: Vec::new(), ..options.clone() }).unwrap());
+        assert_eq!(field_files(), 0);
+        assert!(LSMTree::open(&path, options).unwrap().startup_report().rebuilt_field_indexes);
+    }
+
+    #[test]
+    fn test_distance_metric() {
+        let path: PathBuf = test_dir("distance_metric");
+        let options = Options { hnsw: Some(HnswOptions::default()), ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert_eq!(lsm.metric(), DistanceMetric::L2);
+        lsm.set_metric(DistanceMetric::Cosine).unwrap();
+
+        // by cosine only the direction counts, not the length
+        lsm.insert(1, Vector::new(1, vec![100.0, 1.0])).unwrap();
+        lsm.insert(2, Vector::new(2, vec![0.5, 0.6])).unwrap();
+        lsm.insert(3, Vector::new(3, vec![-1.0, 0.0])).unwrap();
+        let found = lsm.knn(&[1.0, 0.0], 3).unwrap();
+        assert_eq!(found.iter().map(|(k, _)| *k).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(found[2].1, 2.0);
+        assert_eq!(lsm.search(&[1.0, 0.0], 3, 10).unwrap(), found);
+        assert!(matches!(lsm.knn(&[0.0, 0.0], 1), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(lsm.set_metric(DistanceMetric::L2), Err(LsmError::InvalidArgument(_))));
+        drop(lsm);
+
+        // the metric is kept in the manifest
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.metric(), DistanceMetric::Cosine);
+        assert_eq!(lsm.knn(&[1.0, 0.0], 3).unwrap(), found);
+        assert_eq!(lsm.search(&[1.0, 0.0], 3, 10).unwrap(), found);
+    }
+
+    #[test]
+    fn test_knn_by() {
+        let path: PathBuf = test_dir("knn_by");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![100.0, 1.0])).unwrap();
+        lsm.insert(2, Vector::new(2, vec![0.5, 0.6])).unwrap();
+        lsm.insert(3, Vector::new(3, vec![-1.0, 0.0])).unwrap();
+        let keys = |found: Vec<(u64, f64)>| found.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(lsm.knn_by(&[1.0, 0.0], 3, DistanceMetric::L2).unwrap(), lsm.knn(&[1.0, 0.0], 3).unwrap());
+
+        // a metric nobody said ranks these vectors is refused, not answered
+        let refused = lsm.knn_by(&[1.0, 0.0], 3, DistanceMetric::Cosine).unwrap_err();
+        assert!(matches!(refused, LsmError::InvalidArgument(_)));
+        assert!(refused.to_string().contains("doesn't declare Cosine"));
+        lsm.set_query_metrics(&[DistanceMetric::Cosine, DistanceMetric::InnerProduct]).unwrap();
+        assert_eq!(keys(lsm.knn_by(&[1.0, 0.0], 3, DistanceMetric::Cosine).unwrap()), vec![1, 2, 3]);
+        assert_eq!(keys(lsm.knn_by(&[1.0, 0.0], 3, DistanceMetric::L2).unwrap()), vec![2, 3, 1]);
+        assert!(matches!(lsm.knn_by(&[1.0, 0.0], 3, DistanceMetric::Hamming), Err(LsmError::InvalidArgument(_))));
+        assert!(matches!(lsm.knn_by(&[0.0, 0.0], 3, DistanceMetric::Cosine), Err(LsmError::InvalidArgument(_))));
+        drop(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.query_metrics(), vec![DistanceMetric::Cosine, DistanceMetric::InnerProduct]);
+        assert_eq!(keys(lsm.knn_by(&[1.0, 0.0], 1, DistanceMetric::InnerProduct).unwrap()), vec![1]);
+        lsm.set_query_metrics(&[]).unwrap();
+        assert!(lsm.knn_by(&[1.0, 0.0], 1, DistanceMetric::InnerProduct).is_err());
+
+        // unit vectors rank the same by all three without declaring them
+        let normalized = LSMTree::new(&test_dir("knn_by_normalized")).unwrap();
+        normalized.set_pipeline(Pipeline::new(vec![Transform::Normalize]).unwrap()).unwrap();
+        for (key, data) in [(1, vec![100.0, 1.0]), (2, vec![0.5, 0.6]), (3, vec![-1.0, 0.0])] {
+            normalized.insert(key, Vector::new(key, data)).unwrap();
+        }
+        for metric in [DistanceMetric::L2, DistanceMetric::Cosine, DistanceMetric::InnerProduct] {
+            assert_eq!(keys(normalized.knn_by(&[1.0, 0.0], 3, metric).unwrap()), vec![1, 2, 3]);
+        }
+        assert!(normalized.knn_by(&[1.0, 0.0], 3, DistanceMetric::Jaccard).is_err());
+    }
+
+    #[test]
+    fn test_ivf_search() {
+        let path: PathBuf = test_dir("ivf_search");
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(matches!(lsm.ivf_search(&[0.0], 1, 1), Err(LsmError::InvalidArgument(_))));
+
         let mut rng = rand::rng();
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let v0 = Vector::new(49, vec![rng.random(), rng.random(), rng.random()]);
+        let vectors: Vec<Vec<f64>> = (0..300).map(|_| vec![rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0)]).collect();
+        for (i, v) in vectors.iter().enumerate() {
+            lsm.insert(i as u64, Vector::new(i as u64, v.clone())).unwrap();
+        }
+        lsm.train_ivf(&vectors, 8).unwrap();
+        assert_eq!(lsm.inner.ivf.read().unwrap().as_ref().unwrap().len(), 300);
 
-        for i in 0..100 {
-            if i == 49 {
-                let _ = lsm.insert(49, v0.clone());
-            } else {
-                let _ = lsm.insert(i, Vector::new(i, vec![rng.random(), rng.random(), rng.random()]));
-            }
+        // probing every partition is exact
+        let query = [0.3, -0.2];
+        let exact = lsm.knn(&query, 5).unwrap();
+        assert_eq!(lsm.ivf_search(&query, 8, 5).unwrap(), exact);
+
+        // later writes are filed as they are applied
+        lsm.delete(exact[0].0).unwrap();
+        lsm.insert(1000, Vector::new(1000, query.to_vec())).unwrap();
+        // a vector equal to the query is in the query's own partition
+        assert_eq!(lsm.ivf_search(&query, 1, 1).unwrap(), vec![(1000, 0.0)]);
+        let found = lsm.ivf_search(&query, 8, 3).unwrap();
+        assert_eq!(found[0], (1000, 0.0));
+        assert_eq!(found, lsm.knn(&query, 3).unwrap());
+        drop(lsm);
+
+        // the centroids are kept in the manifest and the partitions refilled on open
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.inner.ivf.read().unwrap().as_ref().unwrap().len(), 300);
+        assert_eq!(lsm.ivf_search(&query, 8, 3).unwrap(), found);
+    }
+
+    #[test]
+    fn test_pq_search() {
+        let path: PathBuf = test_dir("pq_search");
+        let options = Options { pq_codes: true, sstable_size: 100, compaction_trigger: 100, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        assert!(matches!(lsm.pq_search(&[0.0], 1, 1), Err(LsmError::InvalidArgument(_))));
+
+        let mut rng = rand::rng();
+        let vectors: Vec<Vec<f64>> = (0..300).map(|_| (0..4).map(|_| rng.random_range(-1.0..1.0)).collect()).collect();
+        for (i, v) in vectors.iter().enumerate() {
+            lsm.insert(i as u64, Vector::new(i as u64, v.clone())).unwrap();
         }
+        lsm.flush().unwrap();
+        lsm.train_pq(&vectors, 2, 16).unwrap();
+        // tables written before the codebooks have no codes, and are encoded as searched
+        assert!(!lsm.inner.state().sstables.iter().any(|t| t.has_codes()));
+        let query = [0.3, -0.2, 0.1, 0.5];
+        let exact = lsm.knn(&query, 5).unwrap();
+        assert_eq!(lsm.pq_search(&query, 5, 300).unwrap(), exact);
 
-        lsm.flush_memtable();
+        // compaction rewrites them with codes, and reranking every code is exact
+        lsm.compact().unwrap();
+        assert!(lsm.inner.state().sstables.iter().all(|t| t.has_codes()));
+        assert_eq!(lsm.pq_search(&query, 5, 300).unwrap(), exact);
+        let fast = lsm.pq_search(&query, 5, 50).unwrap();
+        assert_eq!(fast.len(), 5);
+        assert!(fast.windows(2).all(|w| w[0].1 <= w[1].1));
 
-        let val = lsm.get(49);
-        assert_eq!(val.unwrap().id(), 49);
+        // newer writes in the memtable shadow the codes of the tables
+        lsm.delete(exact[0].0).unwrap();
+        lsm.insert(1000, Vector::new(1000, query.to_vec())).unwrap();
+        lsm.insert(exact[1].0, Vector::new(exact[1].0, vec![-1.0, 1.0, -1.0, -1.0])).unwrap();
+        let found = lsm.pq_search(&query, 3, 300).unwrap();
+        assert_eq!(found[0], (1000, 0.0));
+        assert_eq!(found, lsm.knn(&query, 3).unwrap());
+        lsm.flush().unwrap();
+        assert_eq!(lsm.pq_search(&query, 3, 300).unwrap(), found);
+        drop(lsm);
+
+        // the codebooks are kept in the manifest and the codes in the tables
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert!(lsm.inner.state().sstables.iter().all(|t| t.has_codes()));
+        assert_eq!(lsm.pq_search(&query, 3, 300).unwrap(), found);
+
+        // codes under codebooks trained since are passed over
+        lsm.train_pq(&vectors[..100], 2, 8).unwrap();
+        assert_eq!(lsm.pq_search(&query, 3, 300).unwrap(), found);
     }
 
     #[test]
-    fn test_delete_from_memtable() {
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let k1: u64 = 1;
-        let v1 = Vector::new(k1, vec![0.0, 1.0]);
-        let _ = lsm.insert(1, v1.clone());
-        assert!(lsm.delete(1).is_ok());
+    fn test_bulk_load() {
+        let path: PathBuf = test_dir("bulk_load");
+        let options = Options { bulk_run_size: 16, compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        lsm.insert(5, Vector::new(5, vec![-1.0])).unwrap();
+        lsm.insert(500, Vector::new(500, vec![500.0])).unwrap();
+
+        let entries = (0..100u64).rev().map(|i| (i, Vector::new(i, vec![i as f64])));
+        assert_eq!(lsm.bulk_load(entries).unwrap(), 100);
+        assert_eq!(lsm.inner.state().sstables.len(), 1 + 7);
+        // loaded entries are newer than earlier writes
+        assert_eq!(lsm.get(5).unwrap().unwrap().data(), &vec![5.0]);
+        assert_eq!(lsm.get(500).unwrap().unwrap().id(), 500);
+        assert_eq!(lsm.range(..).unwrap().len(), 101);
+        assert!(!std::fs::read_dir(&path).unwrap().any(|e| e.unwrap().file_name().to_string_lossy().ends_with(".tmp")));
+        drop(lsm);
 
-        assert_eq!(lsm.memtable.len(), 0);
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.get(99).unwrap().unwrap().data(), &vec![99.0]);
     }
 
     #[test]
-    fn test_delete_from_sstable() {
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let k1: u64 = 1;
-        let v1 = Vector::new(k1, vec![0.0, 1.0]);
-        let _ = lsm.insert(1, v1.clone());
-        lsm.flush_memtable();
-        assert!(lsm.delete(1).is_ok());
+    fn test_ingest_external_file() {
+        let source = test_dir("ingest_source");
+        std::fs::create_dir_all(&source).unwrap();
+        let file = source.join("external.sdb");
+        let mut writer = sstable::SSTableWriter::create(&file).unwrap();
+        for i in 0..100u64 {
+            writer.add(i * 2, &Vector::new(i * 2, vec![i as f64])).unwrap();
+        }
+        assert_eq!(writer.add(10, &Vector::new(10, vec![0.0])).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.finish().unwrap(), 100);
+
+        let path: PathBuf = test_dir("ingest_external_file");
+        let lsm = LSMTree::open(&path, Options { compaction_trigger: 0, ..Options::default() }).unwrap();
+        lsm.insert(4, Vector::new(4, vec![-1.0])).unwrap();
+        lsm.insert(5, Vector::new(5, vec![5.0])).unwrap();
+        let sequence = lsm.sequence();
+        assert_eq!(lsm.ingest_external_file(&file).unwrap(), 100);
+        // ingested entries are newer than earlier writes, and never touched the log
+        assert_eq!(lsm.get(4).unwrap().unwrap().data(), &vec![2.0]);
+        assert_eq!(lsm.get(5).unwrap().unwrap().data(), &vec![5.0]);
+        assert_eq!(lsm.range(..).unwrap().len(), 101);
+        assert_eq!(lsm.sequence(), sequence);
+        assert!(file.exists());
+        drop(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert_eq!(lsm.get(198).unwrap().unwrap().data(), &vec![99.0]);
 
-        assert_eq!(lsm.memtable.len(), 0);
-        assert_eq!(lsm.sstables[0].tombstones.len(), 1);
+        // a damaged file is refused whole
+        let mut bytes = std::fs::read(&file).unwrap();
+        bytes[30] ^= 0xff;
+        let damaged = source.join("damaged.sdb");
+        std::fs::write(&damaged, bytes).unwrap();
+        assert!(matches!(lsm.ingest_external_file(&damaged), Err(LsmError::Corruption { .. })));
+        assert_eq!(lsm.describe().len(), 2);
+        drop(lsm);
+
+        // and so is one breaking the tree's limits
+        let wide = source.join("wide.sdb");
+        let mut writer = sstable::SSTableWriter::create(&wide).unwrap();
+        writer.add(1, &Vector::new(1, vec![1.0; 8])).unwrap();
+        writer.finish().unwrap();
+        let limited = LSMTree::open(&path, Options { max_dimension: 4, ..Options::default() }).unwrap();
+        assert!(limited.ingest_external_file(&wide).is_err());
+        assert!(limited.get(1).unwrap().is_none());
     }
 
     #[test]
-    fn test_delete_no_key() {
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let result = lsm.delete(1);
-        assert!(result.is_err());
-        assert!(matches!(result, Err(Error)));
+    fn test_export_import() {
+        let source = LSMTree::new(&test_dir("export")).unwrap();
+        for i in 0..50 {
+            source.insert(i, Vector::new(i, vec![i as f64 / 3.0, 1.0]).with_metadata("even", i % 2 == 0)).unwrap();
+        }
+        source.flush().unwrap();
+        source.delete_range(10, 20).unwrap();
+        source.insert_with_ttl(7, Vector::new(7, vec![7.0, 1.0]), Duration::from_secs(3600)).unwrap();
+
+        for (format, name) in [(ExportFormat::JsonLines, "export_json"), (ExportFormat::Csv, "export_csv")] {
+            let file = test_dir(&format!("{}.txt", name));
+            assert_eq!(source.export(&file, format).unwrap(), 40);
+            let target = LSMTree::new(&test_dir(name)).unwrap();
+            assert_eq!(target.import(&file, format).unwrap(), 40);
+            assert_eq!(target.range(..).unwrap(), source.range(..).unwrap());
+            assert_eq!(target.get(7).unwrap().unwrap().expires_at(), source.get(7).unwrap().unwrap().expires_at());
+        }
+
+        // nothing is loaded from a file with a bad line
+        let file = test_dir("export_bad.txt");
+        std::fs::write(&file, "{\"id\": 1, \"data\": [1]}\n{\"id\": 2}\n").unwrap();
+        let target = LSMTree::new(&test_dir("export_bad")).unwrap();
+        assert!(matches!(target.import(&file, ExportFormat::JsonLines), Err(LsmError::Corruption { .. })));
+        assert!(target.is_empty().unwrap());
     }
 
     #[test]
-    fn test_delete_prevents_get_memtable() {
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let k1: u64 = 1;
-        let v1 = Vector::new(k1, vec![0.0, 1.0]);
-        let _ = lsm.insert(1, v1.clone());
-        assert!(lsm.delete(1).is_ok());
+    fn test_iter_prefix() {
+        let path: PathBuf = test_dir("iter_prefix");
+        let options = Options { sstable_size: 1000, compaction_trigger: 0, prefix_bloom_bits: 8, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        let tenant = |t: u64, i: u64| (t << 56) | i;
+        // a table per pair of tenants, with interleaved key ranges so only the filter tells them apart
+        for t in [1, 3] {
+            for i in 0..10 {
+                lsm.insert(tenant(t, i), Vector::new(i, vec![t as f64])).unwrap();
+            }
+        }
+        lsm.flush().unwrap();
+        for t in [2, 4] {
+            for i in 0..10 {
+                lsm.insert(tenant(t, i), Vector::new(i, vec![t as f64])).unwrap();
+            }
+        }
+        lsm.flush().unwrap();
+        lsm.delete_range(tenant(2, 3), tenant(2, 6)).unwrap();
+        lsm.insert(tenant(2, 20), Vector::new(20, vec![2.0])).unwrap();
+
+        let keys = |iter: Iter| iter.map(|e| e.unwrap().0 & 0xff).collect::<Vec<_>>();
+        assert_eq!(keys(lsm.iter_prefix(2, 8).unwrap()), vec![0, 1, 2, 6, 7, 8, 9, 20]);
+        assert_eq!(keys(lsm.iter_prefix(3, 8).unwrap()), (0..10).collect::<Vec<_>>());
+        assert!(lsm.iter_prefix(5, 8).unwrap().next().is_none());
+        assert_eq!(keys(lsm.iter_prefix(tenant(4, 0) >> 48, 16).unwrap()).len(), 10);
+        // the table holding tenants 2 and 4 is all that's left of the tables for tenant 4
+        assert_eq!(lsm.iter_prefix(4, 8).unwrap().layers.len(), 2);
+        assert_eq!(lsm.iter_prefix(0, 4).unwrap().layers.len(), 3);
+        assert!(lsm.iter_prefix(16, 4).is_err());
 
-        assert!(lsm.get(1).is_none());
+        let probes = lsm.stats().sstable_probes;
+        assert_eq!(lsm.get(tenant(1, 5)).unwrap().unwrap().data(), &vec![1.0]);
+        assert_eq!(lsm.stats().sstable_probes - probes, 1);
+        assert!(lsm.get(tenant(2, 4)).unwrap().is_none());
+        drop(lsm);
+
+        // the filters are read back from the tables
+        let lsm = LSMTree::open(&path, options).unwrap();
+        assert_eq!(lsm.iter_prefix(3, 8).unwrap().layers.len(), 2);
+        assert_eq!(keys(lsm.iter_prefix(4, 8).unwrap()).len(), 10);
     }
 
     #[test]
-    fn test_delete_prevents_get_sstable() {
-        let path: PathBuf = "/tmp/lsm".into();
-        let mut lsm = LSMTree::new(&path).unwrap();
-        let k1: u64 = 1;
-        let v1 = Vector::new(k1, vec![0.0, 1.0]);
-        let _ = lsm.insert(1, v1.clone());
-        lsm.flush_memtable();
-        assert!(lsm.delete(1).is_ok());
+    fn test_bloom_bits_per_key() {
+        let false_positive_rate = |bits: usize| {
+            let path: PathBuf = test_dir(&format!("bloom_bits_per_key_{}", bits));
+            let options = Options { sstable_size: 10_000, compaction_trigger: 0, prefix_bloom_bits: 64, bloom_bits_per_key: bits, ..Options::default() };
+            let lsm = LSMTree::open(&path, options).unwrap();
+            // the misses stay within the table's key range, so its filter is consulted
+            for i in 0..=5000 {
+                lsm.insert(i * 2, Vector::new(i, vec![i as f64])).unwrap();
+            }
+            lsm.flush().unwrap();
+            assert!(lsm.get(10).unwrap().is_some());
+            for i in 0..5000 {
+                assert!(lsm.get(i * 2 + 1).unwrap().is_none());
+            }
+            let stats = lsm.stats();
+            assert_eq!(stats.filter_negatives + stats.filter_false_positives, 5000);
+            stats.filter_false_positive_rate()
+        };
+        let (coarse, fine) = (false_positive_rate(4), false_positive_rate(20));
+        assert!(coarse > 0.05, "{}", coarse);
+        assert!(fine < 0.005, "{}", fine);
+
+        let options = Options { prefix_bloom_bits: 8, bloom_bits_per_key: 0, ..Options::default() };
+        assert!(matches!(LSMTree::open(&test_dir("bloom_bits_per_key_none"), options), Err(LsmError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_partitioned_index() {
+        let path: PathBuf = test_dir("partitioned_index");
+        let options = Options { sstable_size: 100, compaction_trigger: 0, index_partition_entries: 8, block_cache_bytes: 1 << 20, ..Options::default() };
+        let lsm = LSMTree::open(&path, options.clone()).unwrap();
+        for i in 0..300 {
+            lsm.insert(i, Vector::new(i, vec![i as f64])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.delete(150).unwrap();
+        lsm.delete_range(200, 210).unwrap();
+        assert!(lsm.inner.state().sstables.iter().all(|t| t.is_partitioned()));
+
+        let check = |lsm: &LSMTree| {
+            assert_eq!(lsm.get(42).unwrap().unwrap().data(), &vec![42.0]);
+            assert!(lsm.get(150).unwrap().is_none() && lsm.get(205).unwrap().is_none() && lsm.get(300).unwrap().is_none());
+            assert!(lsm.contains_key(299).unwrap() && !lsm.contains_key(150).unwrap());
+            let keys: Vec<u64> = lsm.range(145..215).unwrap().into_iter().map(|(k, _)| k).collect();
+            let expected: Vec<u64> = (145..215).filter(|&k| k != 150 && !(200..210).contains(&k)).collect();
+            assert_eq!(keys, expected);
+            let reversed: Vec<u64> = lsm.iter_rev().take(3).map(|e| e.unwrap().0).collect();
+            assert_eq!(reversed, vec![299, 298, 297]);
+            assert_eq!(lsm.len().unwrap(), 289);
+        };
+        check(&lsm);
+        lsm.compact().unwrap();
+        assert_eq!(lsm.inner.state().sstables.len(), 1);
+        check(&lsm);
+        assert!(lsm.verify().unwrap().passed());
+        drop(lsm);
+
+        let lsm = LSMTree::open(&path, options).unwrap();
+        check(&lsm);
+        assert!(lsm.stats().block_cache_hits > 0);
+    }
+
+    #[test]
+    fn test_prefix_report() {
+        let path: PathBuf = test_dir("prefix_report");
+        let options = Options { compaction_trigger: 0, ..Options::default() };
+        let lsm = LSMTree::open(&path, options).unwrap();
+        let tenant = |t: u64, i: u64| (t << 56) | i;
+        for i in 0..30 {
+            lsm.insert(tenant(1, i), Vector::new(i, vec![0.0; 4])).unwrap();
+        }
+        for i in 0..5 {
+            lsm.insert(tenant(2, i), Vector::new(i, vec![0.0; 4])).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.insert(tenant(2, 0), Vector::new(0, vec![1.0; 4])).unwrap();
 
-        assert!(lsm.get(1).is_none());
+        let report = lsm.prefix_report(8).unwrap();
+        assert_eq!(report.buckets.len(), 2);
+        assert_eq!(report.buckets[&1].entries, 30);
+        assert_eq!(report.buckets[&1].sstable_entries, 30);
+        // the overwritten key is counted in both the memtable and its table
+        assert_eq!(report.buckets[&2].entries, 6);
+        assert_eq!(report.buckets[&2].memtable_entries, 1);
+        assert_eq!(report.largest()[0].0, 1);
     }
 }
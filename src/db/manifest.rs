@@ -0,0 +1,748 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+#[cfg(feature = "failpoints")]
+use std::sync::Arc;
+#[cfg(feature = "failpoints")]
+use crate::db::failpoint::{FailPoint, FailPoints};
+use crate::db::index::pq::ProductQuantizer;
+use crate::db::pipeline::Pipeline;
+use crate::db::search::DistanceMetric;
+use crate::db::vector::ElementType;
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+const ADD_TABLE: u8 = 1;
+const REMOVE_TABLE: u8 = 2;
+const LAST_SEQUENCE: u8 = 3;
+const SET_PIPELINE: u8 = 4;
+const SET_PROJECTION: u8 = 5;
+const LOG_NUMBER: u8 = 6;
+const COMPACT_TABLES: u8 = 7;
+const SET_CENTROIDS: u8 = 8;
+const SET_QUANTIZER: u8 = 9;
+const SET_METRIC: u8 = 10;
+const SET_ELEMENT_TYPE: u8 = 11;
+const COMPACT_TABLES_INTO: u8 = 13;
+const SET_DIMENSION: u8 = 14;
+const COLD_TABLE: u8 = 15;
+const SET_QUERY_METRICS: u8 = 16;
+const ADD_FIELD_TABLE: u8 = 17;
+const REMOVE_FIELD_TABLE: u8 = 18;
+const SET_INDEXED_FIELDS: u8 = 19;
+
+// One record of the append-only version log
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum VersionEdit {
+    AddTable(u64),
+    RemoveTable(u64),
+    LastSequence(u64),
+    SetPipeline(Pipeline),
+    SetProjection(Pipeline),
+    // WAL files numbered below this have been flushed and are obsolete
+    LogNumber(u64),
+    // Replaces a run of adjacent live tables with their merged outputs, in one record so
+    // recovery sees either all the inputs or just the outputs. Outputs hold disjoint key
+    // ranges, in key order, and make up one sorted run. With no inputs they are added as
+    // the newest run, which is how a snapshot records a run of several tables.
+    CompactTables { inputs: Vec<u64>, outputs: Vec<u64> },
+    // IVF partition centroids, all of the same dimension
+    SetCentroids(Vec<Vec<f64>>),
+    SetQuantizer(ProductQuantizer),
+    SetMetric(DistanceMetric),
+    SetElementType(ElementType),
+    // Every stored vector and query must have this many dimensions
+    SetDimension(u64),
+    // The table's file is in `Options::cold_directory`, logged after the edit adding it
+    ColdTable(u64),
+    // Metrics queries may rank by in place of the tree's own, replacing any set before
+    SetQueryMetrics(Vec<DistanceMetric>),
+    // A table of field index entries, see `FieldIndex`. The live ones together hold every
+    // entry of the flushed tables.
+    AddFieldTable(u64),
+    RemoveFieldTable(u64),
+    // The fields the field tables index, replacing any set before
+    SetIndexedFields(Vec<String>),
+}
+
+impl VersionEdit {
+    // Record layout: tag (u8), payload length (u32), payload
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let (tag, payload) = match self {
+            VersionEdit::AddTable(n) => (ADD_TABLE, n.to_le_bytes().to_vec()),
+            VersionEdit::RemoveTable(n) => (REMOVE_TABLE, n.to_le_bytes().to_vec()),
+            VersionEdit::LastSequence(s) => (LAST_SEQUENCE, s.to_le_bytes().to_vec()),
+            VersionEdit::SetPipeline(p) => (SET_PIPELINE, p.encode()?),
+            VersionEdit::SetProjection(p) => (SET_PROJECTION, p.encode()?),
+            VersionEdit::LogNumber(n) => (LOG_NUMBER, n.to_le_bytes().to_vec()),
+            // one output keeps the layout from before there could be several
+            VersionEdit::CompactTables { inputs, outputs } if outputs.len() == 1 => {
+                let mut payload = Vec::with_capacity(8 + 4 + inputs.len() * 8);
+                payload.write_u64::<LittleEndian>(outputs[0])?;
+                payload.write_u32::<LittleEndian>(inputs.len() as u32)?;
+                for n in inputs.iter() {
+                    payload.write_u64::<LittleEndian>(*n)?;
+                }
+                (COMPACT_TABLES, payload)
+            }
+            // output count (u32), outputs, input count (u32), inputs
+            VersionEdit::CompactTables { inputs, outputs } => {
+                let mut payload = Vec::with_capacity(8 + (inputs.len() + outputs.len()) * 8);
+                for numbers in [outputs, inputs] {
+                    payload.write_u32::<LittleEndian>(numbers.len() as u32)?;
+                    for n in numbers.iter() {
+                        payload.write_u64::<LittleEndian>(*n)?;
+                    }
+                }
+                (COMPACT_TABLES_INTO, payload)
+            }
+            VersionEdit::SetCentroids(centroids) => {
+                let dim = centroids.first().map_or(0, |c| c.len());
+                let mut payload = Vec::with_capacity(8 + centroids.len() * dim * 8);
+                payload.write_u32::<LittleEndian>(centroids.len() as u32)?;
+                payload.write_u32::<LittleEndian>(dim as u32)?;
+                for x in centroids.iter().flatten() {
+                    payload.write_f64::<LittleEndian>(*x)?;
+                }
+                (SET_CENTROIDS, payload)
+            }
+            VersionEdit::SetQuantizer(quantizer) => {
+                let codebooks = quantizer.codebooks();
+                let centroids = codebooks.first().map_or(0, |book| book.len());
+                let width = codebooks.first().and_then(|book| book.first()).map_or(0, |c| c.len());
+                let mut payload = Vec::with_capacity(12 + codebooks.len() * centroids * width * 8);
+                payload.write_u32::<LittleEndian>(codebooks.len() as u32)?;
+                payload.write_u32::<LittleEndian>(centroids as u32)?;
+                payload.write_u32::<LittleEndian>(width as u32)?;
+                for x in codebooks.iter().flatten().flatten() {
+                    payload.write_f64::<LittleEndian>(*x)?;
+                }
+                (SET_QUANTIZER, payload)
+            }
+            VersionEdit::SetMetric(metric) => (SET_METRIC, vec![metric.to_u8()]),
+            VersionEdit::SetElementType(element) => (SET_ELEMENT_TYPE, vec![element.to_u8()]),
+            VersionEdit::SetDimension(d) => (SET_DIMENSION, d.to_le_bytes().to_vec()),
+            VersionEdit::ColdTable(n) => (COLD_TABLE, n.to_le_bytes().to_vec()),
+            VersionEdit::SetQueryMetrics(metrics) => (SET_QUERY_METRICS, metrics.iter().map(|metric| metric.to_u8()).collect()),
+            VersionEdit::AddFieldTable(n) => (ADD_FIELD_TABLE, n.to_le_bytes().to_vec()),
+            VersionEdit::RemoveFieldTable(n) => (REMOVE_FIELD_TABLE, n.to_le_bytes().to_vec()),
+            // count (u32), then each name's length (u32) and bytes
+            VersionEdit::SetIndexedFields(fields) => {
+                let mut payload = Vec::new();
+                payload.write_u32::<LittleEndian>(fields.len() as u32)?;
+                for field in fields.iter() {
+                    payload.write_u32::<LittleEndian>(field.len() as u32)?;
+                    payload.extend_from_slice(field.as_bytes());
+                }
+                (SET_INDEXED_FIELDS, payload)
+            }
+        };
+        let mut record = Vec::with_capacity(1 + 4 + payload.len());
+        record.write_u8(tag)?;
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    fn decode(tag: u8, payload: &[u8]) -> io::Result<VersionEdit> {
+        let mut cursor = io::Cursor::new(payload);
+        match tag {
+            ADD_TABLE => Ok(VersionEdit::AddTable(cursor.read_u64::<LittleEndian>()?)),
+            REMOVE_TABLE => Ok(VersionEdit::RemoveTable(cursor.read_u64::<LittleEndian>()?)),
+            LAST_SEQUENCE => Ok(VersionEdit::LastSequence(cursor.read_u64::<LittleEndian>()?)),
+            SET_PIPELINE => Ok(VersionEdit::SetPipeline(Pipeline::decode(&mut cursor)?)),
+            SET_PROJECTION => Ok(VersionEdit::SetProjection(Pipeline::decode(&mut cursor)?)),
+            LOG_NUMBER => Ok(VersionEdit::LogNumber(cursor.read_u64::<LittleEndian>()?)),
+            COMPACT_TABLES => {
+                let output = cursor.read_u64::<LittleEndian>()?;
+                let count = cursor.read_u32::<LittleEndian>()?;
+                let mut inputs = Vec::new();
+                for _ in 0..count {
+                    inputs.push(cursor.read_u64::<LittleEndian>()?);
+                }
+                Ok(VersionEdit::CompactTables { inputs, outputs: vec![output] })
+            }
+            COMPACT_TABLES_INTO => {
+                let mut numbers = [Vec::new(), Vec::new()];
+                for list in numbers.iter_mut() {
+                    for _ in 0..cursor.read_u32::<LittleEndian>()? {
+                        list.push(cursor.read_u64::<LittleEndian>()?);
+                    }
+                }
+                let [outputs, inputs] = numbers;
+                Ok(VersionEdit::CompactTables { inputs, outputs })
+            }
+            SET_CENTROIDS => {
+                let count = cursor.read_u32::<LittleEndian>()?;
+                let dim = cursor.read_u32::<LittleEndian>()?;
+                let mut centroids = Vec::new();
+                for _ in 0..count {
+                    let mut centroid = Vec::new();
+                    for _ in 0..dim {
+                        centroid.push(cursor.read_f64::<LittleEndian>()?);
+                    }
+                    centroids.push(centroid);
+                }
+                Ok(VersionEdit::SetCentroids(centroids))
+            }
+            SET_QUANTIZER => {
+                let subspaces = cursor.read_u32::<LittleEndian>()?;
+                let centroids = cursor.read_u32::<LittleEndian>()?;
+                let width = cursor.read_u32::<LittleEndian>()?;
+                let mut codebooks = Vec::new();
+                for _ in 0..subspaces {
+                    let mut book = Vec::new();
+                    for _ in 0..centroids {
+                        let mut centroid = Vec::new();
+                        for _ in 0..width {
+                            centroid.push(cursor.read_f64::<LittleEndian>()?);
+                        }
+                        book.push(centroid);
+                    }
+                    codebooks.push(book);
+                }
+                Ok(VersionEdit::SetQuantizer(ProductQuantizer::from_codebooks(codebooks)))
+            }
+            SET_METRIC => Ok(VersionEdit::SetMetric(DistanceMetric::from_u8(cursor.read_u8()?)?)),
+            SET_ELEMENT_TYPE => Ok(VersionEdit::SetElementType(ElementType::from_u8(cursor.read_u8()?)?)),
+            SET_DIMENSION => Ok(VersionEdit::SetDimension(cursor.read_u64::<LittleEndian>()?)),
+            COLD_TABLE => Ok(VersionEdit::ColdTable(cursor.read_u64::<LittleEndian>()?)),
+            SET_QUERY_METRICS => Ok(VersionEdit::SetQueryMetrics(payload.iter().map(|&tag| DistanceMetric::from_u8(tag)).collect::<io::Result<_>>()?)),
+            ADD_FIELD_TABLE => Ok(VersionEdit::AddFieldTable(cursor.read_u64::<LittleEndian>()?)),
+            REMOVE_FIELD_TABLE => Ok(VersionEdit::RemoveFieldTable(cursor.read_u64::<LittleEndian>()?)),
+            SET_INDEXED_FIELDS => {
+                let mut fields = Vec::new();
+                for _ in 0..cursor.read_u32::<LittleEndian>()? {
+                    let mut name = vec![0; cursor.read_u32::<LittleEndian>()? as usize];
+                    cursor.read_exact(&mut name)?;
+                    fields.push(String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+                }
+                Ok(VersionEdit::SetIndexedFields(fields))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown manifest record type {}", tag))),
+        }
+    }
+}
+
+pub(crate) struct Manifest {
+    file: File,
+    // oldest first; a newer table's entries shadow an older one's
+    live_tables: Vec<u64>,
+    // live tables in `Options::cold_directory` rather than the directory
+    cold: BTreeSet<u64>,
+    // live tables in the same sorted run as the table before them, written by one
+    // compaction split into sub-compactions; every other table is a run of its own
+    continuations: BTreeSet<u64>,
+    next_file_number: u64,
+    last_sequence: u64,
+    log_number: u64,
+    pipeline: Pipeline,
+    projection: Pipeline,
+    centroids: Vec<Vec<f64>>,
+    quantizer: Option<ProductQuantizer>,
+    metric: DistanceMetric,
+    query_metrics: Vec<DistanceMetric>,
+    element_type: ElementType,
+    dimension: Option<usize>,
+    // live field tables, and the fields they index
+    field_tables: Vec<u64>,
+    indexed_fields: Vec<String>,
+    // `Options::failpoints`, checked before each edit is appended
+    #[cfg(feature = "failpoints")]
+    pub(crate) failpoints: Option<Arc<FailPoints>>,
+}
+
+impl Manifest {
+    // Replays the existing log (creating an empty one if missing) and opens it for appending
+    pub(crate) fn open(directory: &Path) -> io::Result<Manifest> {
+        let path = directory.join(MANIFEST_FILE);
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let (edits, valid_len) = read_edits(&contents)?;
+        if valid_len < contents.len() {
+            file.set_len(valid_len as u64)?;
+        }
+        Ok(Manifest::replay(file, edits))
+    }
+
+    // Like `open` for a log that must already exist and is left as it is: a torn tail,
+    // maybe an edit a live writer is appending, is skipped rather than truncated
+    pub(crate) fn open_read_only(directory: &Path) -> io::Result<Manifest> {
+        let mut file = File::open(directory.join(MANIFEST_FILE))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let (edits, _) = read_edits(&contents)?;
+        Ok(Manifest::replay(file, edits))
+    }
+
+    fn replay(file: File, edits: Vec<VersionEdit>) -> Manifest {
+        let mut manifest = Manifest {
+            file,
+            live_tables: Vec::new(),
+            cold: BTreeSet::new(),
+            continuations: BTreeSet::new(),
+            next_file_number: 0,
+            last_sequence: 0,
+            log_number: 0,
+            pipeline: Pipeline::default(),
+            projection: Pipeline::default(),
+            centroids: Vec::new(),
+            quantizer: None,
+            metric: DistanceMetric::default(),
+            query_metrics: Vec::new(),
+            element_type: ElementType::default(),
+            dimension: None,
+            field_tables: Vec::new(),
+            indexed_fields: Vec::new(),
+            #[cfg(feature = "failpoints")]
+            failpoints: None,
+        };
+        for edit in edits {
+            manifest.apply(&edit);
+        }
+        manifest
+    }
+
+    pub(crate) fn live_tables(&self) -> &[u64] {
+        &self.live_tables
+    }
+
+    pub(crate) fn is_cold(&self, file_number: u64) -> bool {
+        self.cold.contains(&file_number)
+    }
+
+    // Whether the table belongs to the sorted run of the live table before it
+    pub(crate) fn continues_run(&self, file_number: u64) -> bool {
+        self.continuations.contains(&file_number)
+    }
+
+    pub(crate) fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    pub(crate) fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    pub(crate) fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    pub(crate) fn projection(&self) -> &Pipeline {
+        &self.projection
+    }
+
+    // Empty until an IVF index has been trained
+    pub(crate) fn centroids(&self) -> &[Vec<f64>] {
+        &self.centroids
+    }
+
+    pub(crate) fn quantizer(&self) -> Option<&ProductQuantizer> {
+        self.quantizer.as_ref()
+    }
+
+    pub(crate) fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    pub(crate) fn query_metrics(&self) -> &[DistanceMetric] {
+        &self.query_metrics
+    }
+
+    pub(crate) fn element_type(&self) -> ElementType {
+        self.element_type
+    }
+
+    // None until a dimension has been set or taken from the first insert
+    pub(crate) fn dimension(&self) -> Option<usize> {
+        self.dimension
+    }
+
+    pub(crate) fn field_tables(&self) -> &[u64] {
+        &self.field_tables
+    }
+
+    pub(crate) fn indexed_fields(&self) -> &[String] {
+        &self.indexed_fields
+    }
+
+    // Makes sure a file found on disk (e.g. an unflushed WAL) is never handed out again
+    pub(crate) fn mark_file_number_used(&mut self, number: u64) {
+        self.next_file_number = self.next_file_number.max(number + 1);
+    }
+
+    pub(crate) fn new_file_number(&mut self) -> u64 {
+        let number = self.next_file_number;
+        self.next_file_number += 1;
+        number
+    }
+
+    // Durably appends the edits as one write, then applies them in memory
+    pub(crate) fn log(&mut self, edits: &[VersionEdit]) -> io::Result<()> {
+        #[cfg(feature = "failpoints")]
+        if let Some(failpoints) = &self.failpoints {
+            failpoints.check(FailPoint::ManifestCommit)?;
+        }
+        let mut buf = Vec::new();
+        for edit in edits {
+            buf.extend_from_slice(&edit.encode()?);
+        }
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+
+        for edit in edits {
+            self.apply(edit);
+        }
+        Ok(())
+    }
+
+    // Edits that rebuild the current state from an empty log
+    pub(crate) fn snapshot(&self) -> Vec<VersionEdit> {
+        let mut runs: Vec<Vec<u64>> = Vec::new();
+        for &n in self.live_tables.iter() {
+            match runs.last_mut() {
+                Some(run) if self.continues_run(n) => run.push(n),
+                _ => runs.push(vec![n]),
+            }
+        }
+        let mut edits: Vec<VersionEdit> = runs.into_iter().map(|run| match run.len() {
+            1 => VersionEdit::AddTable(run[0]),
+            _ => VersionEdit::CompactTables { inputs: Vec::new(), outputs: run },
+        }).collect();
+        edits.extend(self.cold.iter().map(|&n| VersionEdit::ColdTable(n)));
+        edits.push(VersionEdit::LastSequence(self.last_sequence));
+        edits.push(VersionEdit::LogNumber(self.log_number));
+        if !self.pipeline.is_empty() {
+            edits.push(VersionEdit::SetPipeline(self.pipeline.clone()));
+        }
+        if !self.projection.is_empty() {
+            edits.push(VersionEdit::SetProjection(self.projection.clone()));
+        }
+        if !self.centroids.is_empty() {
+            edits.push(VersionEdit::SetCentroids(self.centroids.clone()));
+        }
+        if let Some(quantizer) = &self.quantizer {
+            edits.push(VersionEdit::SetQuantizer(quantizer.clone()));
+        }
+        edits.push(VersionEdit::SetMetric(self.metric));
+        if !self.query_metrics.is_empty() {
+            edits.push(VersionEdit::SetQueryMetrics(self.query_metrics.clone()));
+        }
+        edits.push(VersionEdit::SetElementType(self.element_type));
+        if let Some(dimension) = self.dimension {
+            edits.push(VersionEdit::SetDimension(dimension as u64));
+        }
+        if !self.indexed_fields.is_empty() {
+            edits.push(VersionEdit::SetIndexedFields(self.indexed_fields.clone()));
+        }
+        edits.extend(self.field_tables.iter().map(|&n| VersionEdit::AddFieldTable(n)));
+        edits
+    }
+
+    fn apply(&mut self, edit: &VersionEdit) {
+        match edit {
+            &VersionEdit::AddTable(n) => {
+                self.live_tables.push(n);
+                self.next_file_number = self.next_file_number.max(n + 1);
+            }
+            &VersionEdit::RemoveTable(n) => {
+                // the rest of a run it started is a run of its own
+                if let Some(at) = self.live_tables.iter().position(|&t| t == n)
+                    && !self.continuations.remove(&n)
+                    && let Some(next) = self.live_tables.get(at + 1)
+                {
+                    self.continuations.remove(next);
+                }
+                self.live_tables.retain(|&t| t != n);
+                self.cold.remove(&n);
+                self.next_file_number = self.next_file_number.max(n + 1);
+            }
+            VersionEdit::CompactTables { inputs, outputs } => {
+                // the outputs take the place of the oldest input, the first in its run
+                let position = self.live_tables.iter().position(|t| inputs.contains(t)).unwrap_or(self.live_tables.len());
+                let continues = self.live_tables.get(position).is_some_and(|t| self.continuations.contains(t));
+                self.live_tables.retain(|t| !inputs.contains(t));
+                self.cold.retain(|t| !inputs.contains(t));
+                self.continuations.retain(|t| !inputs.contains(t));
+                let position = position.min(self.live_tables.len());
+                self.live_tables.splice(position..position, outputs.iter().copied());
+                for (i, &output) in outputs.iter().enumerate() {
+                    if i > 0 || continues {
+                        self.continuations.insert(output);
+                    }
+                    self.next_file_number = self.next_file_number.max(output + 1);
+                }
+            }
+            &VersionEdit::LastSequence(s) => {
+                self.last_sequence = self.last_sequence.max(s);
+            }
+            &VersionEdit::LogNumber(n) => {
+                self.log_number = self.log_number.max(n);
+                self.next_file_number = self.next_file_number.max(n + 1);
+            }
+            VersionEdit::SetPipeline(pipeline) => {
+                self.pipeline = pipeline.clone();
+            }
+            VersionEdit::SetProjection(projection) => {
+                self.projection = projection.clone();
+            }
+            VersionEdit::SetCentroids(centroids) => {
+                self.centroids = centroids.clone();
+            }
+            VersionEdit::SetQuantizer(quantizer) => {
+                self.quantizer = Some(quantizer.clone());
+            }
+            &VersionEdit::SetMetric(metric) => {
+                self.metric = metric;
+            }
+            VersionEdit::SetQueryMetrics(metrics) => {
+                self.query_metrics = metrics.clone();
+            }
+            &VersionEdit::SetElementType(element_type) => {
+                self.element_type = element_type;
+            }
+            &VersionEdit::SetDimension(dimension) => {
+                self.dimension = Some(dimension as usize);
+            }
+            &VersionEdit::ColdTable(n) => {
+                if self.live_tables.contains(&n) {
+                    self.cold.insert(n);
+                }
+            }
+            &VersionEdit::AddFieldTable(n) => {
+                self.field_tables.push(n);
+                self.next_file_number = self.next_file_number.max(n + 1);
+            }
+            &VersionEdit::RemoveFieldTable(n) => {
+                self.field_tables.retain(|&t| t != n);
+            }
+            VersionEdit::SetIndexedFields(fields) => {
+                self.indexed_fields = fields.clone();
+            }
+        }
+    }
+}
+
+// Starts a log in `directory` holding just `edits`, under a temporary name until it is complete
+pub(crate) fn write_new(directory: &Path, edits: &[VersionEdit]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for edit in edits {
+        buf.extend_from_slice(&edit.encode()?);
+    }
+    let temp_path = directory.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, directory.join(MANIFEST_FILE))
+}
+
+// A record cut short by a crash mid-append is dropped, everything before it is kept
+fn read_edits(contents: &[u8]) -> io::Result<(Vec<VersionEdit>, usize)> {
+    let mut edits = Vec::new();
+    let mut pos = 0;
+    while pos + 5 <= contents.len() {
+        let tag = contents[pos];
+        let len = u32::from_le_bytes(contents[pos + 1..pos + 5].try_into().unwrap()) as usize;
+        let start = pos + 5;
+        if start + len > contents.len() {
+            break;
+        }
+        edits.push(VersionEdit::decode(tag, &contents[start..start + len])?);
+        pos = start + len;
+    }
+    Ok((edits, pos))
+}
+
+pub(crate) fn table_file_name(file_number: u64) -> String {
+    format!("sstable_{}.sdb", file_number)
+}
+
+pub(crate) fn parse_table_file_name(name: &str) -> Option<u64> {
+    name.strip_prefix("sstable_")?.strip_suffix(".sdb")?.parse().ok()
+}
+
+pub(crate) fn field_table_file_name(file_number: u64) -> String {
+    format!("fields_{}.fdx", file_number)
+}
+
+pub(crate) fn parse_field_table_file_name(name: &str) -> Option<u64> {
+    name.strip_prefix("fields_")?.strip_suffix(".fdx")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+
+    #[test]
+    fn test_replay_edits() {
+        let path = empty_test_dir("manifest_replay");
+        let mut manifest = Manifest::open(&path).unwrap();
+        let a = manifest.new_file_number();
+        let b = manifest.new_file_number();
+        manifest.log(&[VersionEdit::AddTable(a), VersionEdit::LastSequence(10)]).unwrap();
+        manifest.log(&[VersionEdit::AddTable(b), VersionEdit::RemoveTable(a)]).unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.live_tables(), &[b]);
+        assert_eq!(manifest.last_sequence(), 10);
+        assert_eq!(manifest.new_file_number(), b + 1);
+    }
+
+    #[test]
+    fn test_ignores_torn_record() {
+        let path = empty_test_dir("manifest_torn");
+        let mut manifest = Manifest::open(&path).unwrap();
+        manifest.log(&[VersionEdit::AddTable(0)]).unwrap();
+        drop(manifest);
+
+        let mut file = OpenOptions::new().append(true).open(path.join(MANIFEST_FILE)).unwrap();
+        let record = VersionEdit::AddTable(1).encode().unwrap();
+        file.write_all(&record[..record.len() - 3]).unwrap();
+        drop(file);
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.live_tables().len(), 1);
+        assert!(manifest.live_tables().contains(&0));
+
+        manifest.log(&[VersionEdit::AddTable(2)]).unwrap();
+        drop(manifest);
+        let manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.live_tables().len(), 2);
+    }
+
+    #[test]
+    fn test_compact_tables_keeps_order() {
+        let path = empty_test_dir("manifest_compact_tables_keeps_order");
+        let mut manifest = Manifest::open(&path).unwrap();
+        manifest.log(&[VersionEdit::AddTable(1), VersionEdit::AddTable(2), VersionEdit::AddTable(3), VersionEdit::AddTable(4)]).unwrap();
+        manifest.log(&[VersionEdit::CompactTables { inputs: vec![2, 3], outputs: vec![7] }]).unwrap();
+        assert_eq!(manifest.live_tables(), &[1, 7, 4]);
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.live_tables(), &[1, 7, 4]);
+        assert_eq!(manifest.new_file_number(), 8);
+    }
+
+    #[test]
+    fn test_compact_tables_into_a_run() {
+        let path = empty_test_dir("manifest_compact_tables_into_a_run");
+        let mut manifest = Manifest::open(&path).unwrap();
+        manifest.log(&[VersionEdit::AddTable(1), VersionEdit::AddTable(2), VersionEdit::AddTable(3), VersionEdit::AddTable(4)]).unwrap();
+        manifest.log(&[VersionEdit::CompactTables { inputs: vec![2, 3], outputs: vec![5, 6, 7] }]).unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.live_tables(), &[1, 5, 6, 7, 4]);
+        let runs: Vec<bool> = manifest.live_tables().iter().map(|&n| manifest.continues_run(n)).collect();
+        assert_eq!(runs, vec![false, false, true, true, false]);
+        assert_eq!(manifest.new_file_number(), 8);
+
+        // a table rewritten in place stays in its run, and removing a run's first table
+        // leaves the rest a run of their own
+        manifest.log(&[VersionEdit::CompactTables { inputs: vec![6], outputs: vec![8] }, VersionEdit::RemoveTable(5)]).unwrap();
+        assert_eq!(manifest.live_tables(), &[1, 8, 7, 4]);
+        assert!(!manifest.continues_run(8) && manifest.continues_run(7));
+        manifest.log(&[VersionEdit::CompactTables { inputs: vec![1, 8, 7], outputs: vec![9, 10] }]).unwrap();
+
+        // a snapshot records the run in one edit
+        let snapshot = manifest.snapshot();
+        assert_eq!(&snapshot[..2], &[VersionEdit::CompactTables { inputs: vec![], outputs: vec![9, 10] }, VersionEdit::AddTable(4)]);
+        let mut rebuilt = Manifest::replay(File::open(path.join(MANIFEST_FILE)).unwrap(), snapshot);
+        assert_eq!(rebuilt.live_tables(), &[9, 10, 4]);
+        assert!(!rebuilt.continues_run(9) && rebuilt.continues_run(10) && !rebuilt.continues_run(4));
+        assert_eq!(rebuilt.new_file_number(), 11);
+    }
+
+    #[test]
+    fn test_cold_table() {
+        let path = empty_test_dir("manifest_cold_table");
+        let mut manifest = Manifest::open(&path).unwrap();
+        manifest.log(&[VersionEdit::AddTable(1), VersionEdit::AddTable(2)]).unwrap();
+        manifest.log(&[VersionEdit::CompactTables { inputs: vec![1, 2], outputs: vec![3, 4] }, VersionEdit::ColdTable(3), VersionEdit::ColdTable(4), VersionEdit::ColdTable(9)]).unwrap();
+        manifest.log(&[VersionEdit::AddTable(5)]).unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert!(manifest.is_cold(3) && manifest.is_cold(4) && !manifest.is_cold(5) && !manifest.is_cold(9));
+        assert!(manifest.snapshot().contains(&VersionEdit::ColdTable(4)));
+        // an output of compacting a cold table is hot unless logged cold too
+        manifest.log(&[VersionEdit::CompactTables { inputs: vec![4, 5], outputs: vec![6] }, VersionEdit::RemoveTable(3)]).unwrap();
+        assert!(!manifest.is_cold(3) && !manifest.is_cold(4) && !manifest.is_cold(6));
+    }
+
+    #[test]
+    fn test_set_dimension() {
+        let path = empty_test_dir("manifest_set_dimension");
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.dimension(), None);
+        manifest.log(&[VersionEdit::SetDimension(128)]).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.dimension(), Some(128));
+        assert!(manifest.snapshot().contains(&VersionEdit::SetDimension(128)));
+    }
+
+    #[test]
+    fn test_set_query_metrics() {
+        let path = empty_test_dir("manifest_set_query_metrics");
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert!(manifest.query_metrics().is_empty());
+        let metrics = vec![DistanceMetric::Cosine, DistanceMetric::InnerProduct];
+        manifest.log(&[VersionEdit::SetQueryMetrics(vec![DistanceMetric::L2]), VersionEdit::SetQueryMetrics(metrics.clone())]).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.query_metrics(), metrics.as_slice());
+        assert!(manifest.snapshot().contains(&VersionEdit::SetQueryMetrics(metrics)));
+    }
+
+    #[test]
+    fn test_set_centroids() {
+        let path = empty_test_dir("manifest_set_centroids");
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert!(manifest.centroids().is_empty());
+        let centroids = vec![vec![0.0, 1.5], vec![-2.0, 3.0]];
+        manifest.log(&[VersionEdit::SetCentroids(centroids.clone())]).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.centroids(), centroids.as_slice());
+    }
+
+    #[test]
+    fn test_set_quantizer() {
+        let path = empty_test_dir("manifest_set_quantizer");
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert!(manifest.quantizer().is_none());
+        let quantizer = ProductQuantizer::from_codebooks(vec![vec![vec![0.0, 1.0], vec![2.0, 3.0]], vec![vec![4.0, 5.0], vec![6.0, 7.0]]]);
+        manifest.log(&[VersionEdit::SetQuantizer(quantizer.clone())]).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&path).unwrap();
+        assert_eq!(manifest.quantizer(), Some(&quantizer));
+    }
+
+    #[test]
+    fn test_field_tables() {
+        let path = empty_test_dir("manifest_field_tables");
+        let mut manifest = Manifest::open(&path).unwrap();
+        let fields = vec!["doc".to_string(), "tág".to_string()];
+        manifest.log(&[VersionEdit::SetIndexedFields(fields.clone()), VersionEdit::AddFieldTable(3), VersionEdit::AddFieldTable(5)]).unwrap();
+        manifest.log(&[VersionEdit::RemoveFieldTable(3), VersionEdit::RemoveFieldTable(5), VersionEdit::AddFieldTable(8)]).unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&path).unwrap();
+        assert_eq!((manifest.indexed_fields(), manifest.field_tables()), (fields.as_slice(), &[8][..]));
+        assert!(manifest.snapshot().ends_with(&[VersionEdit::SetIndexedFields(fields), VersionEdit::AddFieldTable(8)]));
+        assert_eq!(manifest.new_file_number(), 9);
+        assert_eq!(parse_field_table_file_name(&field_table_file_name(8)), Some(8));
+    }
+}
@@ -0,0 +1,63 @@
+// How `Options::memory_budget_bytes` is shared: the tables' filters and indexes are
+// counted first, as they stay in memory while the table is live, and the memtables get
+// what is left, but never less than a quarter of the budget. The block cache is held to
+// whatever the memtables and tables leave over, up to `Options::block_cache_bytes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct MemoryUsage {
+    pub(crate) memtable: usize,
+    // frozen memtables waiting to be flushed
+    pub(crate) frozen: usize,
+    pub(crate) filters: usize,
+    pub(crate) indexes: usize,
+}
+
+impl MemoryUsage {
+    fn write_budget(&self, budget: usize) -> usize {
+        budget.saturating_sub(self.filters + self.indexes).max(budget / 4)
+    }
+
+    // Whether the active memtable should be frozen before it is full: once it alone takes
+    // most of what the memtables may use, or they use all of it between them and it takes
+    // half, so a memtable isn't frozen while only the frozen ones are using the memory
+    pub(crate) fn should_flush(&self, budget: usize) -> bool {
+        let write_budget = self.write_budget(budget);
+        budget != 0 && (self.memtable >= write_budget / 8 * 7 || (self.memtable + self.frozen >= write_budget && self.memtable >= write_budget / 2))
+    }
+
+    pub(crate) fn cache_capacity(&self, budget: usize, block_cache_bytes: usize) -> usize {
+        match budget {
+            0 => block_cache_bytes,
+            _ => budget.saturating_sub(self.memtable + self.frozen + self.filters + self.indexes).min(block_cache_bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_flush() {
+        let usage = |memtable, frozen| MemoryUsage { memtable, frozen, filters: 200, indexes: 200 };
+        // the memtables may use 600 of the 1000
+        assert!(!usage(500, 0).should_flush(1000));
+        assert!(usage(525, 0).should_flush(1000));
+        assert!(!usage(200, 500).should_flush(1000));
+        assert!(usage(300, 300).should_flush(1000));
+        assert!(!usage(1 << 30, 0).should_flush(0));
+
+        // tables over the budget leave the memtables a quarter of it
+        let crowded = MemoryUsage { memtable: 200, frozen: 0, filters: 2000, indexes: 0 };
+        assert!(!crowded.should_flush(1000));
+        assert!(MemoryUsage { memtable: 250, ..crowded }.should_flush(1000));
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let usage = MemoryUsage { memtable: 100, frozen: 200, filters: 50, indexes: 50 };
+        assert_eq!(usage.cache_capacity(1000, 10_000), 600);
+        assert_eq!(usage.cache_capacity(1000, 500), 500);
+        assert_eq!(usage.cache_capacity(300, 500), 0);
+        assert_eq!(usage.cache_capacity(0, 500), 500);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, Range, RangeBounds};
+use crate::db::entry::{self, PayloadCodec};
+use crate::db::vector::{ElementType, ValueRef, Vector};
+
+// The writes of a memtable: each value encoded as a dense entry, as a table stores it,
+// into an arena of large chunks, with an ordered index of where each key's entry lies.
+// An insert copies the encoding into the current chunk rather than keeping the vector's
+// own allocations, so heavy ingestion fills a few big buffers instead of scattering small
+// ones over the heap, and the arena goes in one piece with the memtable once it has been
+// flushed. Until then an overwritten or deleted entry keeps its place in the arena.
+#[derive(Clone, Default)]
+pub(crate) struct Memtable {
+    index: BTreeMap<u64, Slot>,
+    // u64 words, so every entry starts on an 8 byte boundary and can lend out its data
+    chunks: Vec<Vec<u64>>,
+    // bytes of the chunks handed out to entries, live or not
+    used: usize,
+}
+
+// Where an entry is in the arena: its chunk, the word it starts at, its length in bytes
+// and its flags
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    chunk: usize,
+    at: usize,
+    len: usize,
+    flags: u8,
+}
+
+// The first chunk's size in words, doubling with each chunk up to the largest, so a small
+// memtable doesn't hold on to much more than it uses
+const FIRST_CHUNK_WORDS: usize = 512;
+const MAX_CHUNK_WORDS: usize = 128 * 1024;
+
+// What the index takes for each key on top of its entry
+const SLOT_SIZE: usize = std::mem::size_of::<u64>() + std::mem::size_of::<Slot>();
+
+impl Memtable {
+    pub(crate) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub(crate) fn contains_key(&self, key: u64) -> bool {
+        self.index.contains_key(&key)
+    }
+
+    pub(crate) fn get(&self, key: u64) -> Option<ValueRef<'_>> {
+        self.index.get(&key).map(|slot| self.value(slot))
+    }
+
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = (&u64, ValueRef<'_>)> {
+        self.range(..)
+    }
+
+    pub(crate) fn range<R: RangeBounds<u64>>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&u64, ValueRef<'_>)> {
+        self.index.range(bounds).map(|(key, slot)| (key, self.value(slot)))
+    }
+
+    // Every entry decoded, for writing the memtable out as a table
+    pub(crate) fn decoded(&self) -> impl Iterator<Item = (&u64, Vector)> {
+        self.iter().map(|(key, value)| (key, value.to_vector().expect("memtable entries are encoded by `insert`")))
+    }
+
+    // What each entry in `bounds` counts toward `bytes`
+    pub(crate) fn sizes(&self, bounds: (Bound<u64>, Bound<u64>)) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.index.range(bounds).map(|(&key, slot)| (key, SLOT_SIZE + words(slot.len) * 8))
+    }
+
+    // The arena handed out so far and the index over the live entries, which the tree
+    // tracks as it goes in `State::memtable_bytes`
+    #[cfg(test)]
+    pub(crate) fn bytes(&self) -> usize {
+        self.used + self.index.len() * SLOT_SIZE
+    }
+
+    // Stores a copy of `value` under `key`, returning how much `bytes` grew by
+    pub(crate) fn insert(&mut self, key: u64, value: &Vector) -> usize {
+        // the compact codec writes into a Vec, which doesn't fail
+        let (flags, serialized) = entry::encode_at(value, 0, ElementType::F64, PayloadCodec::Compact).expect("encoding into memory doesn't fail");
+        let n = words(serialized.len());
+        let fits = self.chunks.last().is_some_and(|chunk| chunk.capacity() - chunk.len() >= n);
+        if !fits {
+            let size = self.chunks.last().map_or(FIRST_CHUNK_WORDS, |chunk| (chunk.capacity() * 2).min(MAX_CHUNK_WORDS));
+            self.chunks.push(Vec::with_capacity(size.max(n)));
+        }
+        let chunk = self.chunks.len() - 1;
+        let words = &mut self.chunks[chunk];
+        let at = words.len();
+        words.resize(at + n, 0);
+        bytes_mut(&mut words[at..])[..serialized.len()].copy_from_slice(&serialized);
+        self.used += n * 8;
+
+        let slot = Slot { chunk, at, len: serialized.len(), flags };
+        match self.index.insert(key, slot) {
+            Some(_) => n * 8,
+            None => n * 8 + SLOT_SIZE,
+        }
+    }
+
+    // Drops the key's entry from the index, returning how much `bytes` shrank by
+    pub(crate) fn remove(&mut self, key: u64) -> usize {
+        match self.index.remove(&key) {
+            Some(_) => SLOT_SIZE,
+            None => 0,
+        }
+    }
+
+    // Like `remove` for every key in `range`
+    pub(crate) fn remove_range(&mut self, range: &Range<u64>) -> usize {
+        let before = self.index.len();
+        self.index.retain(|key, _| !range.contains(key));
+        (before - self.index.len()) * SLOT_SIZE
+    }
+
+    fn value(&self, slot: &Slot) -> ValueRef<'_> {
+        let serialized = &bytes(&self.chunks[slot.chunk][slot.at..slot.at + words(slot.len)])[..slot.len];
+        ValueRef::dense(slot.flags, serialized).expect("memtable entries are encoded by `insert`")
+    }
+}
+
+fn words(len: usize) -> usize {
+    len.div_ceil(8)
+}
+
+fn bytes(words: &[u64]) -> &[u8] {
+    // any u64 is 8 valid bytes
+    unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) }
+}
+
+fn bytes_mut(words: &mut [u64]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::vector::MetadataValue;
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut memtable = Memtable::default();
+        assert!(memtable.is_empty());
+        let mut tagged = Vector::new(2, vec![0.5, -1.0]);
+        tagged.metadata_mut().insert("tag".to_string(), MetadataValue::String("a".to_string()));
+        tagged.set_expires_at(1000);
+        let grew = memtable.insert(1, &Vector::new(1, vec![1.0, 2.0, 3.0])) + memtable.insert(2, &tagged);
+        assert_eq!(grew, memtable.bytes());
+
+        assert_eq!(memtable.len(), 2);
+        assert!(memtable.contains_key(2) && !memtable.contains_key(3));
+        assert_eq!(memtable.get(1).unwrap().as_slice(), Some(&[1.0, 2.0, 3.0][..]));
+        let value = memtable.get(2).unwrap();
+        assert_eq!(value.expires_at(), Some(1000));
+        assert_eq!(value.to_vector().unwrap(), tagged);
+        assert!(memtable.get(3).is_none());
+
+        // an overwrite and a delete give back only their index entries, the arena keeps
+        // their bytes until the memtable goes
+        let bytes = memtable.bytes();
+        assert_eq!(memtable.insert(1, &Vector::new(1, vec![4.0])), 24);
+        assert_eq!(memtable.get(1).unwrap().to_vector().unwrap(), Vector::new(1, vec![4.0]));
+        assert_eq!(memtable.remove(2), SLOT_SIZE);
+        assert_eq!(memtable.remove(2), 0);
+        assert_eq!(memtable.bytes(), bytes + 24 - SLOT_SIZE);
+        let keys: Vec<u64> = memtable.iter().map(|(&key, _)| key).collect();
+        assert_eq!(keys, vec![1]);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut memtable = Memtable::default();
+        for key in 0..200 {
+            memtable.insert(key, &Vector::new(key, vec![key as f64; 64]));
+        }
+        // a value bigger than any chunk gets one of its own
+        memtable.insert(1000, &Vector::new(1000, vec![1.0; 2 * MAX_CHUNK_WORDS]));
+        assert!(memtable.chunks.len() > 2);
+        assert!(memtable.chunks.iter().all(|chunk| chunk.len() <= chunk.capacity()));
+        assert_eq!(memtable.get(1000).unwrap().len(), 2 * MAX_CHUNK_WORDS);
+
+        // clones read the same and don't see each other's writes
+        let clone = memtable.clone();
+        memtable.insert(7, &Vector::new(7, vec![0.0]));
+        assert_eq!(clone.get(7).unwrap().as_slice().unwrap(), &[7.0; 64][..]);
+        assert_eq!(memtable.get(7).unwrap().as_slice().unwrap(), &[0.0][..]);
+
+        let freed = memtable.remove_range(&(10..20));
+        assert_eq!(freed, 10 * SLOT_SIZE);
+        let keys: Vec<u64> = memtable.range(5..25).rev().map(|(&key, _)| key).collect();
+        assert_eq!(keys, vec![24, 23, 22, 21, 20, 9, 8, 7, 6, 5]);
+        let sizes: usize = memtable.sizes((Bound::Unbounded, Bound::Unbounded)).map(|(_, size)| size).sum();
+        assert!(sizes < memtable.bytes());
+        assert_eq!(memtable.decoded().count(), memtable.len());
+    }
+}
@@ -0,0 +1,8 @@
+use crate::db::vector::Vector;
+
+// Folds buffered merge operands into a key's value. `existing` is the newest
+// value below the operands (None if the key is absent), `operands` are oldest first.
+// Returning None leaves the key absent.
+pub trait MergeOperator: Send + Sync {
+    fn full_merge(&self, key: u64, existing: Option<&Vector>, operands: &[Vec<u8>]) -> Option<Vector>;
+}
@@ -0,0 +1,121 @@
+use std::fmt::Write;
+use crate::db::lsm::LSMTree;
+use crate::db::stats::Stats;
+
+// Trees' statistics in the Prometheus text exposition format, for a scraper to poll. Every
+// series is labelled with the collection the tree holds, so a database of several shows
+// which one the load, the data or the misses come from:
+//
+//     # HELP lsm_searches_total Queries answered by knn, search_batch and the index searches.
+//     # TYPE lsm_searches_total counter
+//     lsm_searches_total{collection="images"} 1042
+//
+// Counters start over when a tree is opened, which Prometheus takes for a reset.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Gauge,
+    Counter,
+}
+
+struct Metric {
+    name: &'static str,
+    kind: Kind,
+    help: &'static str,
+    value: fn(&Sample) -> u64,
+}
+
+// What is read off a tree once per scrape
+struct Sample {
+    stats: Stats,
+    approximate_keys: u64,
+}
+
+const METRICS: &[Metric] = &[
+    Metric { name: "lsm_tables", kind: Kind::Gauge, help: "Live SSTables.", value: |s| s.stats.table_count as u64 },
+    Metric { name: "lsm_table_bytes", kind: Kind::Gauge, help: "Bytes of the live SSTables' files.", value: |s| s.stats.table_bytes },
+    Metric { name: "lsm_approximate_keys", kind: Kind::Gauge, help: "Keys stored, counting overwrites and range deletes until compaction drops them.", value: |s| s.approximate_keys },
+    Metric { name: "lsm_memory_bytes", kind: Kind::Gauge, help: "Bytes of memtables, filters, indexes and block cache.", value: |s| s.stats.memory_bytes() as u64 },
+    Metric { name: "lsm_index_backlog", kind: Kind::Gauge, help: "Graph updates queued for the indexer and not yet applied.", value: |s| s.stats.index_backlog },
+    Metric { name: "lsm_stalled_writers", kind: Kind::Gauge, help: "Writes waiting for flushes or compactions to catch up.", value: |s| s.stats.stalled_writers },
+    Metric { name: "lsm_gets_total", kind: Kind::Counter, help: "Point lookups.", value: |s| s.stats.gets },
+    Metric { name: "lsm_written_bytes_total", kind: Kind::Counter, help: "Bytes appended to the WAL.", value: |s| s.stats.bytes_written },
+    Metric { name: "lsm_flushes_total", kind: Kind::Counter, help: "Memtables written out as SSTables.", value: |s| s.stats.flushes },
+    Metric { name: "lsm_compactions_total", kind: Kind::Counter, help: "Compactions run.", value: |s| s.stats.compactions },
+    Metric { name: "lsm_compacted_bytes_total", kind: Kind::Counter, help: "Bytes of SSTables written by compactions.", value: |s| s.stats.bytes_compacted },
+    Metric { name: "lsm_write_stops_total", kind: Kind::Counter, help: "Writes stopped until background work caught up.", value: |s| s.stats.write_stops },
+    Metric { name: "lsm_searches_total", kind: Kind::Counter, help: "Queries answered by knn, search_batch and the index searches.", value: |s| s.stats.searches },
+    Metric { name: "lsm_approximate_searches_total", kind: Kind::Counter, help: "Queries answered through an HNSW, IVF or PQ index.", value: |s| s.stats.approximate_searches },
+    Metric { name: "lsm_short_searches_total", kind: Kind::Counter, help: "Unfiltered approximate queries returning fewer than k results from a tree holding k or more.", value: |s| s.stats.short_searches },
+];
+
+// The metrics of each `(collection, tree)`, grouped by metric as the format wants
+pub fn prometheus<'a>(trees: impl IntoIterator<Item = (&'a str, &'a LSMTree)>) -> String {
+    let samples: Vec<(String, Sample)> = trees.into_iter()
+        .map(|(collection, tree)| (escape(collection), Sample { stats: tree.stats(), approximate_keys: tree.approximate_len() as u64 }))
+        .collect();
+    let mut out = String::new();
+    for metric in METRICS {
+        let kind = match metric.kind {
+            Kind::Gauge => "gauge",
+            Kind::Counter => "counter",
+        };
+        // writing to a String can't fail
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+        for (collection, sample) in &samples {
+            let _ = writeln!(out, "{}{{collection=\"{}\"}} {}", metric.name, collection, (metric.value)(sample));
+        }
+    }
+    out
+}
+
+// A label value with the characters the format escapes escaped
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::index::hnsw::HnswOptions;
+    use crate::db::options::Options;
+    use crate::db::test_util::test_dir;
+    use crate::db::vector::Vector;
+
+    #[test]
+    fn test_prometheus() {
+        let options = Options { hnsw: Some(HnswOptions::default()), ..Options::default() };
+        let images = LSMTree::open(&test_dir("metrics_images"), options).unwrap();
+        let text = LSMTree::new(&test_dir("metrics_text")).unwrap();
+        for i in 0..3 {
+            images.insert(i, Vector::new(i, vec![i as f64, 1.0])).unwrap();
+        }
+        images.knn(&[0.0, 1.0], 2).unwrap();
+        images.search(&[0.0, 1.0], 2, 10).unwrap();
+        // only 3 vectors to find
+        images.search(&[0.0, 1.0], 3, 10).unwrap();
+
+        let out = prometheus([("images", &images), ("te\"xt", &text)]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), METRICS.len() * 4);
+        let at = lines.iter().position(|l| *l == "# TYPE lsm_searches_total counter").unwrap();
+        assert_eq!(lines[at - 1], "# HELP lsm_searches_total Queries answered by knn, search_batch and the index searches.");
+        assert_eq!(lines[at + 1..at + 3], ["lsm_searches_total{collection=\"images\"} 3", "lsm_searches_total{collection=\"te\\\"xt\"} 0"]);
+        assert!(lines.contains(&"lsm_approximate_searches_total{collection=\"images\"} 2"));
+        assert!(lines.contains(&"lsm_approximate_keys{collection=\"images\"} 3"));
+        assert!(lines.contains(&"# TYPE lsm_tables gauge"));
+        assert_eq!(images.stats().short_searches, 0);
+
+        // one IVF partition probed of two holds too few vectors
+        let ivf = LSMTree::new(&test_dir("metrics_ivf")).unwrap();
+        for (i, data) in [[0.0, 1.0], [0.1, 1.0], [10.0, 0.0], [10.0, 0.1]].into_iter().enumerate() {
+            ivf.insert(i as u64, Vector::new(i as u64, data.to_vec())).unwrap();
+        }
+        ivf.train_ivf(&[vec![0.0, 1.0], vec![10.0, 0.0]], 2).unwrap();
+        assert_eq!(ivf.ivf_search(&[0.0, 1.0], 1, 3).unwrap().len(), 2);
+        assert_eq!(ivf.ivf_search(&[0.0, 1.0], 2, 3).unwrap().len(), 3);
+        assert!(prometheus([("ivf", &ivf)]).contains("lsm_short_searches_total{collection=\"ivf\"} 1\n"));
+        assert_eq!(prometheus([]), METRICS.iter().map(|m| format!("# HELP {} {}\n# TYPE {} {}\n", m.name, m.help, m.name, if m.kind == Kind::Gauge { "gauge" } else { "counter" })).collect::<String>());
+    }
+}
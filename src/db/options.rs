@@ -0,0 +1,295 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use crate::db::bloom;
+use crate::db::codec::{Codec, RawCodec};
+use crate::db::compaction::CompactionStyle;
+use crate::db::entry::PayloadCodec;
+use crate::db::executor::Executor;
+#[cfg(feature = "failpoints")]
+use crate::db::failpoint::FailPoints;
+use crate::db::index::hnsw::HnswOptions;
+use crate::db::listener::EventListener;
+use crate::db::merge::MergeOperator;
+use crate::db::sstable::ReadPath;
+use crate::db::storage::Storage;
+use crate::db::wal::{SyncPolicy, WalRecovery};
+
+#[derive(Clone)]
+pub struct Options {
+    // a directory without a tree in it is an empty new tree, or NotFound when this is off
+    pub create_if_missing: bool,
+    // opening a directory that already holds a tree fails with AlreadyExists
+    pub error_if_exists: bool,
+    // number of memtable entries that triggers a flush
+    pub sstable_size: usize,
+    // approximate bytes of memtable entries that also trigger a flush, so a memtable of
+    // high-dimensional vectors stays bounded. Overwritten and deleted entries keep their
+    // bytes in the memtable's arena until it is flushed. 0 flushes on the entry count alone.
+    pub memtable_bytes: usize,
+    // the memtable is also flushed once its oldest write is this many milliseconds old,
+    // so a trickle of writes too slow to fill it still reaches an SSTable. Checked by a
+    // `<prefix>-flush-timer` thread, so not under `Executor::Manual`. 0 for no timer.
+    pub flush_interval_millis: u64,
+    // number of SSTables that triggers a background compaction, 0 disables compaction
+    pub compaction_trigger: usize,
+    // how compactions pick their inputs, see `CompactionStyle`
+    pub compaction_style: CompactionStyle,
+    // an iterator that stepped over this many deleted, expired or overwritten table
+    // entries leaves a hint, when it is dropped, for the next compaction to merge the
+    // tables under the keys it found them at before what `compaction_style` picks, so
+    // ranges that are slow to read get compacted first. Hints from reads over tables that
+    // are compacted meanwhile are dropped. 0 for no hints.
+    pub read_compaction_skips: usize,
+    // caps the bytes per second compactions read and write, so they leave the disk to
+    // foreground reads. Changed at runtime with `LSMTree::set_compaction_rate_limit`.
+    // 0 for no cap.
+    pub compaction_bytes_per_sec: u64,
+    // caps the WAL bytes, counted as whole frames with their headers, and the operations
+    // per second of `insert`, `merge`, `write` and write streams, so an ingest job leaves
+    // the disk to queries. A write past the budget
+    // returns once its share has been waited off. Changed at runtime with
+    // `LSMTree::set_write_rate_limit`. 0 for no cap.
+    pub write_bytes_per_sec: u64,
+    pub write_ops_per_sec: u64,
+    // required to use `LSMTree::merge`
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    // preflight: refuse to open with less free disk space than this
+    pub min_free_bytes: u64,
+    // preflight: refuse to open if RLIMIT_NOFILE is below this
+    pub min_open_files: u64,
+    // where flushes and compactions run
+    pub executor: Executor,
+    // told as flushes and compactions begin and complete, and as writes stall, see
+    // `EventListener`
+    pub listeners: Vec<Arc<dyn EventListener>>,
+    // abort a range scan whose results would hold more than this many bytes, 0 for no limit
+    pub max_scan_bytes: usize,
+    // entries a bulk load sorts in memory before spilling a run, and per table it builds
+    pub bulk_run_size: usize,
+    // background threads writing frozen memtables out; tables are still installed in order
+    pub max_flush_threads: usize,
+    // background threads merging SSTables, each working on its own run of tables
+    pub max_compaction_threads: usize,
+    // a compaction splits its inputs' key range into up to this many parts of at least
+    // `sstable_size` keys, each merged into a table of its own on a thread of its own.
+    // The tables make up one sorted run, counted once by `compaction_trigger` and the
+    // table thresholds below. 1 merges on the compaction's own thread.
+    pub max_subcompactions: usize,
+    // threads sharing the work of training a projection
+    pub index_build_threads: usize,
+    // threads splitting the queries of `LSMTree::search_batch` between them
+    pub search_threads: usize,
+    // threads are named `<prefix>-flush-<n>`, `<prefix>-compact-<n>`,
+    // `<prefix>-subcompact-<n>`, `<prefix>-index-<n>`, `<prefix>-search-<n>`,
+    // `<prefix>-indexer` and `<prefix>-flush-timer`.
+    // Linux shows only the first 15 bytes of a name.
+    pub thread_name_prefix: String,
+    // maintain an HNSW graph over the stored vectors for `LSMTree::search`. It is written
+    // out at close with the sequence and tables it has seen, and brought in line with the
+    // tree when that is opened again, after a crash too, see
+    // `StartupReport::reconciled_vectors`. Without a saved graph it is built from scratch.
+    pub hnsw: Option<HnswOptions>,
+    // writes leave their changes to that graph in a queue on disk for the
+    // `<prefix>-indexer` thread instead of linking them in themselves, so ingestion isn't
+    // held to the pace of graph maintenance. Until the indexer catches up, `search` can
+    // miss the newest writes, see `LSMTree::search_lagging` and `wait_for_index`. Only
+    // with `Executor::Threaded`; otherwise writes update the graph themselves.
+    pub index_queue: bool,
+    // metadata fields to keep a secondary index on, for `LSMTree::get_by_field`, and for
+    // `LSMTree::search_filtered` to rank only the vectors a filter on them picks out.
+    // Kept in field tables that are flushed with the memtables and read back when the
+    // tree is opened; built from the stored vectors only when the fields differ from the
+    // ones the tables index.
+    pub indexed_fields: Vec<String>,
+    // backpressure on writers when flushes or compactions fall behind. Past a slowdown
+    // threshold every write first sleeps `slowdown_write_micros`; past a stop threshold
+    // writes wait until background work brings the tree back under it. Counted in frozen
+    // memtables waiting to be flushed, and in sorted runs of SSTables, where the table
+    // thresholds have to exceed `compaction_trigger` so compactions can bring the count
+    // down. 0 disables a threshold.
+    pub slowdown_immutables: usize,
+    pub stop_immutables: usize,
+    pub slowdown_tables: usize,
+    pub stop_tables: usize,
+    pub slowdown_write_micros: u64,
+    // how long a write waits for others to join its WAL sync, trading commit latency for
+    // fewer fsyncs under concurrent writes. 0 syncs every write on its own.
+    pub commit_window_micros: u64,
+    // trades durability for fewer fsyncs, for the WAL and for the tables flushes and
+    // compactions write
+    pub sync_policy: SyncPolicy,
+    // logs kept after their memtable is flushed, so `LSMTree::read_log_since` can reach
+    // back past recent flushes. 0 deletes each log as soon as it is flushed.
+    pub retained_wals: usize,
+    // what opening the tree does with the logs after one with a torn or corrupt tail,
+    // see `WalRecovery`
+    pub wal_recovery: WalRecovery,
+    // how long the values keys held before each write stay readable by
+    // `LSMTree::get_at` and `iter_at`, in memory, from when the tree is opened. 0 keeps
+    // none, so only the current sequence can be read.
+    pub history_retention_millis: u64,
+    // writes are refused past these, so one record can't outgrow what the entry headers,
+    // the cache accounting or a network message expects. 0 for no limit.
+    // bytes of a serialized value, and of a merge operand
+    pub max_value_bytes: usize,
+    pub max_dimension: usize,
+    // the first vector written to a tree without a dimension sets it, so every later
+    // vector and query must match it, see `LSMTree::set_dimension`. On by default, as one
+    // vector of another dimension would make every search of the tree fail; off lets
+    // a tree hold vectors of any dimensions, which searches then refuse.
+    pub fix_dimension: bool,
+    // bytes of a value's serialized metadata
+    pub max_payload_bytes: usize,
+    // how new tables store metadata, see `PayloadCodec`. Tables written with either stay
+    // readable, and the log always holds bson.
+    pub payload_codec: PayloadCodec,
+    // how new tables lay out their values, see `Codec`. Only the built-in codecs are
+    // taken, since tables are read back with the one their footer names.
+    pub codec: Arc<dyn Codec>,
+    // new tables keep a bloom filter over the top this many bits of their keys, so gets
+    // and `LSMTree::iter_prefix` skip tables without the prefix. 0 for none, at most 64.
+    pub prefix_bloom_bits: u32,
+    // bits per distinct prefix in those filters, trading memory for fewer false positives:
+    // 10 is about 1%, every 5 more divide that by about 10
+    pub bloom_bits_per_key: usize,
+    // flushes and compactions write their tables with O_DIRECT on Linux, so streaming them
+    // out doesn't evict the working set from the page cache. Other platforms, and
+    // filesystems refusing O_DIRECT, write through the cache as usual.
+    pub direct_io_writes: bool,
+    // tables with more entries than this split their index into partitions of this many,
+    // and only a small top-level index over the partitions is read when a table is
+    // opened. Partitions are read when lookups need them, through the block cache if
+    // there is one, so a large table's index isn't held in memory. 0 for one index block.
+    pub index_partition_entries: usize,
+    // keys in a table's index blocks are stored as the bytes they don't share with the key
+    // before them, which for keys allocated close together is one or two of their eight,
+    // and offsets as the distance from the entry before. Every this many entries one is
+    // written whole, for lookups to binary search between: more shrinks the index further
+    // and makes lookups decode more entries after the restart point they land on.
+    pub index_restart_interval: usize,
+    // new tables also store their vectors column by column, in blocks of this many, which
+    // `LSMTree::knn` ranks a dimension at a time without decoding the entries. The data
+    // is stored twice, so tables grow by about that much. 0 for none.
+    pub columnar_block_vectors: usize,
+    // new tables store the data of vectors repeated under other keys once, by a content
+    // hash of it, and point the other entries at it. Duplicates are only found within
+    // one table: an entry never points into another table, which compaction could drop
+    // under it, so a vector written again after its first copy was flushed is stored
+    // once per table holding it until compaction merges those tables into one. Trees
+    // taking the same vectors over a long time save the most once most of their data
+    // sits in the last level. Data only deleted or replaced entries pointed at isn't
+    // copied into compaction outputs.
+    pub dedup_vectors: bool,
+    // new tables also store the code of every vector under the codebooks `LSMTree::train_pq`
+    // trained, which `LSMTree::pq_search` scans instead of the vectors, reranking the
+    // closest by their full-precision distance. A code is a byte per subspace, so tables
+    // grow by that much per vector. Has no effect until codebooks are trained.
+    pub pq_codes: bool,
+    // how tables are read, see `ReadPath`
+    pub read_path: ReadPath,
+    // tells the kernel how mapped tables are read: lookups at random, so it doesn't read
+    // ahead around them, and iterators, range reads and compactions in key order, with
+    // the first few MiB of each table they cover read in when they start
+    pub access_hints: bool,
+    // bytes of table entries kept in memory after they are read, least recently used
+    // first out. Mostly worth it with `ReadPath::Pread`, where the entries would otherwise
+    // be read from the file on every access. 0 for no cache.
+    pub block_cache_bytes: usize,
+    // decoded values of this many recently read keys are kept for `LSMTree::get`, so hot
+    // keys skip the memtable and table lookups. 0 for no cache.
+    pub row_cache_entries: usize,
+    // bytes the memtables, the tables' filters and indexes, and the block cache may use
+    // between them. Nearing it freezes the memtable before it is full and shrinks the
+    // block cache below `block_cache_bytes`; `LSMTree::stats` reports what each uses.
+    // The row cache and the vector indexes aren't counted. 0 for no budget.
+    pub memory_budget_bytes: usize,
+    // every table read verifies the entry's checksum, that it lies within the data section
+    // and is indexed where it was found, and that a prefix filter negative isn't in the
+    // index, reporting a mismatch as InvalidData naming the table and offset
+    pub paranoid_checks: bool,
+    // times gets, writes, flushes and searches into the histograms `LSMTree::stats`
+    // reports. Off, the calls don't read the clock.
+    pub latency_histograms: bool,
+    // where the tables are kept, the tree's directory when None. Tables in a storage are
+    // read through it, so `read_path`, `access_hints` and `direct_io_writes` don't apply.
+    pub storage: Option<Arc<dyn Storage>>,
+    // a second directory, typically on slower and cheaper disks, for the oldest sorted
+    // run: compactions reaching the oldest table write their outputs there, while flushes
+    // and the newer runs stay in the tree's directory. Tables already in the directory
+    // move over as compactions rewrite them. Created if missing, and left alone by
+    // `LSMTree::destroy`. Can't be combined with `storage`.
+    pub cold_directory: Option<PathBuf>,
+    // crash injection for tests, see `FailPoint`
+    #[cfg(feature = "failpoints")]
+    pub failpoints: Option<Arc<FailPoints>>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            create_if_missing: true,
+            error_if_exists: false,
+            sstable_size: 10,
+            memtable_bytes: 0,
+            flush_interval_millis: 0,
+            compaction_trigger: 4,
+            compaction_style: CompactionStyle::Tiered,
+            read_compaction_skips: 0,
+            compaction_bytes_per_sec: 0,
+            write_bytes_per_sec: 0,
+            write_ops_per_sec: 0,
+            merge_operator: None,
+            min_free_bytes: 0,
+            min_open_files: 64,
+            executor: Executor::default(),
+            listeners: Vec::new(),
+            max_scan_bytes: 0,
+            bulk_run_size: 100_000,
+            max_flush_threads: 1,
+            max_compaction_threads: 1,
+            max_subcompactions: 1,
+            index_build_threads: 1,
+            search_threads: 1,
+            thread_name_prefix: "lsm".to_string(),
+            hnsw: None,
+            index_queue: false,
+            indexed_fields: Vec::new(),
+            slowdown_immutables: 0,
+            stop_immutables: 0,
+            slowdown_tables: 0,
+            stop_tables: 0,
+            slowdown_write_micros: 1000,
+            commit_window_micros: 0,
+            sync_policy: SyncPolicy::Always,
+            retained_wals: 0,
+            wal_recovery: WalRecovery::PointInTime,
+            history_retention_millis: 0,
+            // bson's own document size limit
+            max_value_bytes: 16 * 1024 * 1024,
+            max_dimension: 65_536,
+            fix_dimension: true,
+            max_payload_bytes: 64 * 1024,
+            payload_codec: PayloadCodec::Bson,
+            codec: Arc::new(RawCodec),
+            prefix_bloom_bits: 0,
+            bloom_bits_per_key: bloom::DEFAULT_BITS_PER_PREFIX,
+            direct_io_writes: false,
+            index_partition_entries: 0,
+            index_restart_interval: 16,
+            columnar_block_vectors: 0,
+            dedup_vectors: false,
+            pq_codes: false,
+            read_path: ReadPath::Mmap,
+            access_hints: false,
+            block_cache_bytes: 0,
+            row_cache_entries: 0,
+            memory_budget_bytes: 0,
+            paranoid_checks: false,
+            latency_histograms: false,
+            storage: None,
+            cold_directory: None,
+            #[cfg(feature = "failpoints")]
+            failpoints: None,
+        }
+    }
+}
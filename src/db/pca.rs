@@ -0,0 +1,181 @@
+use std::io;
+use std::thread;
+use crate::db::pipeline::Transform;
+
+const POWER_ITERATIONS: usize = 200;
+
+// Trains a PCA projection onto the top `components` principal axes of the sample.
+// The result is a `Transform::Rotation` with one row per component. Only the axes are
+// kept, not the sample mean: translation doesn't change L2 distances between projections.
+pub fn train_pca(sample: &[Vec<f64>], components: usize) -> io::Result<Transform> {
+    train_pca_parallel(sample, components, 1, "pca")
+}
+
+// Same as `train_pca`, with the covariance accumulated over `threads` threads named
+// `<thread_name>-<n>`
+pub(crate) fn train_pca_parallel(sample: &[Vec<f64>], components: usize, threads: usize, thread_name: &str) -> io::Result<Transform> {
+    let Some(first) = sample.first() else {
+        return Err(invalid("cannot train a projection on an empty sample".to_string()));
+    };
+    let dim = first.len();
+    if sample.iter().any(|v| v.len() != dim) {
+        return Err(invalid("all sample vectors must have the same dimension".to_string()));
+    }
+    if components == 0 || components > dim {
+        return Err(invalid(format!("cannot reduce {} dimensions to {}", dim, components)));
+    }
+
+    let mut covariance = covariance(sample, dim, threads, thread_name)?;
+    let mut matrix = Vec::with_capacity(components * dim);
+    for c in 0..components {
+        let axis = dominant_eigenvector(&covariance, dim, c);
+        let eigenvalue = rayleigh_quotient(&covariance, &axis, dim);
+        // deflate so the next iteration converges to the next largest axis
+        for i in 0..dim {
+            for j in 0..dim {
+                covariance[i * dim + j] -= eigenvalue * axis[i] * axis[j];
+            }
+        }
+        matrix.extend_from_slice(&axis);
+    }
+
+    Ok(Transform::Rotation { rows: components, cols: dim, matrix })
+}
+
+fn covariance(sample: &[Vec<f64>], dim: usize, threads: usize, thread_name: &str) -> io::Result<Vec<f64>> {
+    let n = sample.len() as f64;
+    let mut mean = vec![0.0; dim];
+    for v in sample {
+        for (m, x) in mean.iter_mut().zip(v) {
+            *m += x / n;
+        }
+    }
+    if threads <= 1 {
+        return Ok(partial_covariance(sample, &mean, n));
+    }
+
+    let chunk = sample.len().div_ceil(threads);
+    thread::scope(|s| {
+        let mut handles = Vec::with_capacity(threads);
+        for (i, part) in sample.chunks(chunk).enumerate() {
+            let mean = &mean;
+            handles.push(thread::Builder::new()
+                .name(format!("{}-{}", thread_name, i))
+                .spawn_scoped(s, move || partial_covariance(part, mean, n))?);
+        }
+        let mut cov = vec![0.0; dim * dim];
+        for handle in handles {
+            for (c, p) in cov.iter_mut().zip(handle.join().unwrap()) {
+                *c += p;
+            }
+        }
+        Ok(cov)
+    })
+}
+
+// This part of the sample's contribution to the covariance of the whole sample
+fn partial_covariance(sample: &[Vec<f64>], mean: &[f64], n: f64) -> Vec<f64> {
+    let dim = mean.len();
+    let mut cov = vec![0.0; dim * dim];
+    for v in sample {
+        for i in 0..dim {
+            let di = v[i] - mean[i];
+            for j in 0..dim {
+                cov[i * dim + j] += di * (v[j] - mean[j]) / n;
+            }
+        }
+    }
+    cov
+}
+
+fn dominant_eigenvector(matrix: &[f64], dim: usize, seed: usize) -> Vec<f64> {
+    // deterministic start that isn't orthogonal to any axis in practice
+    let mut v: Vec<f64> = (0..dim).map(|i| 1.0 + ((i + seed) % dim) as f64 / dim as f64).collect();
+    normalize(&mut v);
+    for _ in 0..POWER_ITERATIONS {
+        let mut next = vec![0.0; dim];
+        for i in 0..dim {
+            next[i] = (0..dim).map(|j| matrix[i * dim + j] * v[j]).sum();
+        }
+        if !normalize(&mut next) {
+            break;
+        }
+        v = next;
+    }
+    v
+}
+
+fn rayleigh_quotient(matrix: &[f64], v: &[f64], dim: usize) -> f64 {
+    (0..dim).map(|i| v[i] * (0..dim).map(|j| matrix[i * dim + j] * v[j]).sum::<f64>()).sum()
+}
+
+fn normalize(v: &mut [f64]) -> bool {
+    let norm = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm < f64::EPSILON {
+        return false;
+    }
+    v.iter_mut().for_each(|x| *x /= norm);
+    true
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_finds_dominant_axis() {
+        let mut rng = rand::rng();
+        // spread mostly along (1, 1, 0), a little along z
+        let sample: Vec<Vec<f64>> = (0..500).map(|_| {
+            let t: f64 = rng.random_range(-10.0..10.0);
+            vec![t, t, rng.random_range(-0.1..0.1)]
+        }).collect();
+
+        let Transform::Rotation { rows, cols, matrix } = train_pca(&sample, 1).unwrap() else { panic!("expected a rotation") };
+        assert_eq!((rows, cols), (1, 3));
+        let expected = 1.0 / 2.0f64.sqrt();
+        assert!((matrix[0].abs() - expected).abs() < 1e-3);
+        assert!((matrix[1].abs() - expected).abs() < 1e-3);
+        assert!(matrix[2].abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_components_are_orthonormal() {
+        let mut rng = rand::rng();
+        let sample: Vec<Vec<f64>> = (0..200).map(|_| {
+            vec![rng.random_range(-5.0..5.0), rng.random_range(-2.0..2.0), rng.random_range(-1.0..1.0)]
+        }).collect();
+
+        let Transform::Rotation { matrix, .. } = train_pca(&sample, 2).unwrap() else { panic!("expected a rotation") };
+        let (a, b) = matrix.split_at(3);
+        let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        assert!(dot.abs() < 1e-3);
+        assert!((a.iter().map(|x| x * x).sum::<f64>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let mut rng = rand::rng();
+        let sample: Vec<Vec<f64>> = (0..301).map(|_| {
+            vec![rng.random_range(-5.0..5.0), rng.random_range(-2.0..2.0), rng.random_range(-1.0..1.0)]
+        }).collect();
+
+        let Transform::Rotation { matrix: serial, .. } = train_pca(&sample, 2).unwrap() else { panic!("expected a rotation") };
+        let Transform::Rotation { matrix: parallel, .. } = train_pca_parallel(&sample, 2, 4, "pca-test").unwrap() else { panic!("expected a rotation") };
+        for (a, b) in serial.iter().zip(parallel.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_input() {
+        assert!(train_pca(&[], 1).is_err());
+        assert!(train_pca(&[vec![1.0, 2.0]], 3).is_err());
+        assert!(train_pca(&[vec![1.0, 2.0], vec![1.0]], 1).is_err());
+    }
+}
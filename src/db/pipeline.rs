@@ -0,0 +1,174 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read};
+
+const NORMALIZE: u8 = 1;
+const DIMENSION: u8 = 2;
+const ROTATION: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    // Scale to unit L2 norm
+    Normalize,
+    // Reject vectors that don't have exactly this many components
+    Dimension(usize),
+    // Row-major `rows x cols` matrix multiplied onto the vector, e.g. a PCA or OPQ rotation
+    Rotation { rows: usize, cols: usize, matrix: Vec<f64> },
+}
+
+// Steps applied in order to every stored vector and every query
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    steps: Vec<Transform>,
+}
+
+impl Pipeline {
+    pub fn new(steps: Vec<Transform>) -> io::Result<Pipeline> {
+        for step in steps.iter() {
+            if let Transform::Rotation { rows, cols, matrix } = step
+                && matrix.len() != rows * cols {
+                return Err(invalid(format!("rotation matrix has {} elements, expected {}x{}", matrix.len(), rows, cols)));
+            }
+        }
+        Ok(Pipeline { steps })
+    }
+
+    pub fn steps(&self) -> &[Transform] {
+        &self.steps
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // Whether every vector comes out with unit L2 norm: the last step that changes norms
+    // is a normalize. A rotation isn't assumed to keep them.
+    pub fn normalizes(&self) -> bool {
+        self.steps.iter().rev().find(|step| !matches!(step, Transform::Dimension(_))).is_some_and(|step| *step == Transform::Normalize)
+    }
+
+    pub fn apply(&self, data: &[f64]) -> io::Result<Vec<f64>> {
+        let mut out = data.to_vec();
+        for step in self.steps.iter() {
+            out = match step {
+                Transform::Normalize => {
+                    let norm = out.iter().map(|x| x * x).sum::<f64>().sqrt();
+                    if norm == 0.0 {
+                        return Err(invalid("cannot normalize a zero vector".to_string()));
+                    }
+                    out.iter().map(|x| x / norm).collect()
+                }
+                Transform::Dimension(dim) => {
+                    if out.len() != *dim {
+                        return Err(invalid(format!("expected {} dimensions, got {}", dim, out.len())));
+                    }
+                    out
+                }
+                Transform::Rotation { rows, cols, matrix } => {
+                    if out.len() != *cols {
+                        return Err(invalid(format!("rotation expects {} dimensions, got {}", cols, out.len())));
+                    }
+                    matrix.chunks(*cols).take(*rows)
+                        .map(|row| row.iter().zip(out.iter()).map(|(a, b)| a * b).sum())
+                        .collect()
+                }
+            };
+        }
+        Ok(out)
+    }
+
+    pub(crate) fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(self.steps.len() as u32)?;
+        for step in self.steps.iter() {
+            match step {
+                Transform::Normalize => buf.write_u8(NORMALIZE)?,
+                Transform::Dimension(dim) => {
+                    buf.write_u8(DIMENSION)?;
+                    buf.write_u64::<LittleEndian>(*dim as u64)?;
+                }
+                Transform::Rotation { rows, cols, matrix } => {
+                    buf.write_u8(ROTATION)?;
+                    buf.write_u64::<LittleEndian>(*rows as u64)?;
+                    buf.write_u64::<LittleEndian>(*cols as u64)?;
+                    for x in matrix.iter() {
+                        buf.write_f64::<LittleEndian>(*x)?;
+                    }
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    pub(crate) fn decode<R: Read>(buf: &mut R) -> io::Result<Pipeline> {
+        let count = buf.read_u32::<LittleEndian>()?;
+        let mut steps = Vec::new();
+        for _ in 0..count {
+            let step = match buf.read_u8()? {
+                NORMALIZE => Transform::Normalize,
+                DIMENSION => Transform::Dimension(buf.read_u64::<LittleEndian>()? as usize),
+                ROTATION => {
+                    let rows = buf.read_u64::<LittleEndian>()? as usize;
+                    let cols = buf.read_u64::<LittleEndian>()? as usize;
+                    let mut matrix = Vec::new();
+                    for _ in 0..rows * cols {
+                        matrix.push(buf.read_f64::<LittleEndian>()?);
+                    }
+                    Transform::Rotation { rows, cols, matrix }
+                }
+                tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown pipeline step {}", tag))),
+            };
+            steps.push(step);
+        }
+        Pipeline::new(steps)
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_steps_in_order() {
+        let swap = Transform::Rotation { rows: 2, cols: 2, matrix: vec![0.0, 1.0, 1.0, 0.0] };
+        let pipeline = Pipeline::new(vec![Transform::Dimension(2), Transform::Normalize, swap]).unwrap();
+        assert_eq!(pipeline.apply(&[3.0, 4.0]).unwrap(), vec![0.8, 0.6]);
+
+        let err = pipeline.apply(&[1.0, 2.0, 3.0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(pipeline.apply(&[0.0, 0.0]).is_err());
+    }
+
+    #[test]
+    fn test_normalizes() {
+        let rotation = Transform::Rotation { rows: 1, cols: 1, matrix: vec![2.0] };
+        assert!(Pipeline::new(vec![Transform::Normalize, Transform::Dimension(3)]).unwrap().normalizes());
+        assert!(Pipeline::new(vec![rotation.clone(), Transform::Normalize]).unwrap().normalizes());
+        assert!(!Pipeline::new(vec![Transform::Normalize, rotation]).unwrap().normalizes());
+        assert!(!Pipeline::default().normalizes());
+    }
+
+    #[test]
+    fn test_rotation_reduces_dimension() {
+        let project = Transform::Rotation { rows: 1, cols: 3, matrix: vec![1.0, 1.0, 1.0] };
+        let pipeline = Pipeline::new(vec![project]).unwrap();
+        assert_eq!(pipeline.apply(&[1.0, 2.0, 3.0]).unwrap(), vec![6.0]);
+
+        let bad = Transform::Rotation { rows: 2, cols: 2, matrix: vec![1.0] };
+        assert!(Pipeline::new(vec![bad]).is_err());
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let pipeline = Pipeline::new(vec![
+            Transform::Normalize,
+            Transform::Dimension(3),
+            Transform::Rotation { rows: 1, cols: 3, matrix: vec![0.5, 0.25, 0.125] },
+        ]).unwrap();
+        let bytes = pipeline.encode().unwrap();
+        assert_eq!(Pipeline::decode(&mut io::Cursor::new(bytes)).unwrap(), pipeline);
+    }
+}
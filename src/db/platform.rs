@@ -0,0 +1,197 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+// Everything the tree asks of the OS beyond what std offers everywhere: direct I/O, page
+// cache hints, positioned reads, directory syncs and the preflight's limits. Each has a
+// portable fallback, so the engine builds and runs on any target std supports, giving up
+// only the optimizations and checks the platform can't provide.
+
+// How a mapped range is about to be read, see `Options::access_hints`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    Random,
+    Sequential,
+    WillNeed,
+}
+
+// Only a hint, refused or not the reads come out the same
+pub(crate) fn advise(map: &memmap2::Mmap, range: Range<usize>, access: Access) {
+    #[cfg(unix)]
+    {
+        let advice = match access {
+            Access::Random => memmap2::Advice::Random,
+            Access::Sequential => memmap2::Advice::Sequential,
+            Access::WillNeed => memmap2::Advice::WillNeed,
+        };
+        let _ = map.advise_range(advice, range.start, range.len());
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (map, range, access);
+    }
+}
+
+// `options` opened with O_DIRECT, or None where the platform or the filesystem doesn't
+// take it (tmpfs refuses it with EINVAL)
+pub(crate) fn open_direct(options: &OpenOptions, path: &Path) -> Option<io::Result<File>> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => None,
+            result => Some(result),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (options, path);
+        None
+    }
+}
+
+// Fills `buf` from `offset` without moving the file's cursor, so readers can share it
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut done = 0;
+        while done < buf.len() {
+            match file.seek_read(&mut buf[done..], offset + done as u64) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+                Ok(n) => done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        // without positioned reads the cursor is shared, so reads take turns moving it
+        use std::io::{Read, Seek, SeekFrom};
+        static CURSOR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _turn = CURSOR.lock().unwrap();
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+}
+
+// Makes the creates, renames and deletes in `directory` survive a crash. Windows can't
+// open a directory to sync it and journals the changes itself.
+pub(crate) fn sync_dir(directory: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        File::open(directory)?.sync_all()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = directory;
+        Ok(())
+    }
+}
+
+// Bytes an unprivileged writer can still use on the filesystem holding `directory`, None
+// where that can't be asked
+pub(crate) fn free_bytes(directory: &Path) -> io::Result<Option<u64>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = std::ffi::CString::new(directory.as_os_str().as_bytes())?;
+        unsafe {
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::statvfs(c_path.as_ptr(), &mut stat) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = directory;
+        Ok(None)
+    }
+}
+
+// The soft limit on open files, None where there is no such limit to ask about
+pub(crate) fn open_files_limit() -> io::Result<Option<u64>> {
+    #[cfg(unix)]
+    unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(limit.rlim_cur as u64))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(None)
+    }
+}
+
+// SIGHUPs the process has received since `catch_hangups`, for servers to reload their
+// configuration on. Installing the handler keeps the signal from terminating the process.
+// Always 0 where there are no signals.
+#[cfg(unix)]
+static HANGUPS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+pub(crate) fn catch_hangups() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_hangup(_: libc::c_int) {
+            // an atomic add is all a handler can safely do
+            HANGUPS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| unsafe {
+            libc::signal(libc::SIGHUP, on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        });
+    }
+}
+
+pub(crate) fn hangups() -> u64 {
+    #[cfg(unix)]
+    {
+        HANGUPS.load(std::sync::atomic::Ordering::Relaxed)
+    }
+    #[cfg(not(unix))]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_read_exact_at() {
+        let path = empty_test_dir("platform_read_exact_at");
+        std::fs::write(path.join("file"), b"hello world").unwrap();
+        sync_dir(&path).unwrap();
+
+        let file = File::open(path.join("file")).unwrap();
+        let mut buf = [0; 5];
+        read_exact_at(&file, &mut buf, 6).unwrap();
+        assert_eq!(&buf, b"world");
+        read_exact_at(&file, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(read_exact_at(&file, &mut buf, 8).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_limits() {
+        let path = PathBuf::from("/tmp/lsm");
+        std::fs::create_dir_all(&path).unwrap();
+        assert_eq!(free_bytes(&path).unwrap().is_some(), cfg!(unix));
+        assert_eq!(open_files_limit().unwrap().is_some_and(|limit| limit > 0), cfg!(unix));
+    }
+}
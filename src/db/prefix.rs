@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PrefixStats {
+    // physical entries, so a key overwritten since its last compaction counts more than once
+    pub entries: u64,
+    // on-disk size for SSTable entries, estimated in-memory size for memtable entries
+    pub bytes: u64,
+    pub memtable_entries: u64,
+    pub sstable_entries: u64,
+}
+
+// Entry counts and sizes bucketed by the top `prefix_bits` bits of the key
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PrefixReport {
+    pub prefix_bits: u32,
+    pub buckets: BTreeMap<u64, PrefixStats>,
+}
+
+impl PrefixReport {
+    pub(crate) fn new(prefix_bits: u32) -> io::Result<PrefixReport> {
+        if prefix_bits > 64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("prefix of {} bits is longer than a key", prefix_bits)));
+        }
+        Ok(PrefixReport { prefix_bits, buckets: BTreeMap::new() })
+    }
+
+    pub fn prefix(&self, key: u64) -> u64 {
+        key.checked_shr(64 - self.prefix_bits).unwrap_or(0)
+    }
+
+    // Buckets ordered from the most to the least bytes
+    pub fn largest(&self) -> Vec<(u64, PrefixStats)> {
+        let mut buckets: Vec<(u64, PrefixStats)> = self.buckets.iter().map(|(&p, &s)| (p, s)).collect();
+        buckets.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(&b.0)));
+        buckets
+    }
+
+    pub(crate) fn add_memtable(&mut self, key: u64, bytes: usize) {
+        let stats = self.buckets.entry(self.prefix(key)).or_default();
+        stats.entries += 1;
+        stats.memtable_entries += 1;
+        stats.bytes += bytes as u64;
+    }
+
+    pub(crate) fn add_sstable(&mut self, key: u64, bytes: usize) {
+        let stats = self.buckets.entry(self.prefix(key)).or_default();
+        stats.entries += 1;
+        stats.sstable_entries += 1;
+        stats.bytes += bytes as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_buckets() {
+        let mut report = PrefixReport::new(8).unwrap();
+        assert_eq!(report.prefix(0xAB00_0000_0000_0001), 0xAB);
+        report.add_memtable(0xAB00_0000_0000_0001, 10);
+        report.add_sstable(0xAB00_0000_0000_0002, 30);
+        report.add_sstable(0x0100_0000_0000_0000, 5);
+
+        let ab = report.buckets[&0xAB];
+        assert_eq!((ab.entries, ab.bytes, ab.memtable_entries, ab.sstable_entries), (2, 40, 1, 1));
+        assert_eq!(report.largest()[0].0, 0xAB);
+
+        assert_eq!(PrefixReport::new(0).unwrap().prefix(u64::MAX), 0);
+        assert_eq!(PrefixReport::new(64).unwrap().prefix(7), 7);
+        assert!(PrefixReport::new(65).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::db::options::Options;
+use crate::db::platform;
+
+// 2020-01-01T00:00:00Z, anything earlier means the clock was never set
+const EARLIEST_SANE_TIME: Duration = Duration::from_secs(1_577_836_800);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartupReport {
+    pub directory: PathBuf,
+    // u64::MAX where the platform can't tell
+    pub free_bytes: u64,
+    pub open_files_limit: u64,
+    pub table_count: usize,
+    pub table_format_versions: Vec<u32>,
+    pub replayed_batches: usize,
+    // vectors the HNSW graph saved at the last close had missing, stale or left over,
+    // fixed as it was opened, see `Options::hnsw`
+    pub reconciled_vectors: usize,
+    // whether the field indexes were built from the stored vectors, as when
+    // `Options::indexed_fields` changed, rather than read from their tables
+    pub rebuilt_field_indexes: bool,
+    pub checks: Vec<Check>,
+    // problems the tree opened despite, like a table read without a mapping
+    pub warnings: Vec<String>,
+}
+
+impl StartupReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+
+    // An error naming every failed check, if any
+    pub(crate) fn to_error(&self) -> Option<io::Error> {
+        let failed: Vec<String> = self.checks.iter()
+            .filter(|c| !c.passed)
+            .map(|c| format!("{}: {}", c.name, c.detail))
+            .collect();
+        if failed.is_empty() {
+            return None;
+        }
+        Some(io::Error::other(format!("preflight checks failed for {}: {}", self.directory.display(), failed.join("; "))))
+    }
+}
+
+// Environment checks that run before anything in the directory is touched
+pub(crate) fn run(directory: &Path, options: &Options, read_only: bool) -> io::Result<StartupReport> {
+    let mut checks = Vec::new();
+
+    // a read-only open mustn't write even a probe
+    if !read_only {
+        let writable = check_writable(directory);
+        checks.push(Check {
+            name: "directory_writable",
+            passed: writable.is_ok(),
+            detail: match writable {
+                Ok(()) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            },
+        });
+    }
+
+    // what the platform can't tell is taken to be plenty
+    let free_bytes = platform::free_bytes(directory)?;
+    checks.push(Check {
+        name: "disk_free_space",
+        passed: free_bytes.is_none_or(|free| free >= options.min_free_bytes),
+        detail: match free_bytes {
+            Some(free) => format!("{} bytes free, {} required", free, options.min_free_bytes),
+            None => "free space isn't known on this platform".to_string(),
+        },
+    });
+    let free_bytes = free_bytes.unwrap_or(u64::MAX);
+
+    let open_files_limit = platform::open_files_limit()?;
+    checks.push(Check {
+        name: "open_files_limit",
+        passed: open_files_limit.is_none_or(|limit| limit >= options.min_open_files),
+        detail: match open_files_limit {
+            Some(limit) => format!("limit is {}, {} required", limit, options.min_open_files),
+            None => "there is no open files limit on this platform".to_string(),
+        },
+    });
+    let open_files_limit = open_files_limit.unwrap_or(u64::MAX);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    checks.push(Check {
+        name: "clock_sanity",
+        passed: now >= EARLIEST_SANE_TIME,
+        detail: format!("system time is {}s since the epoch", now.as_secs()),
+    });
+
+    Ok(StartupReport {
+        directory: directory.to_path_buf(),
+        free_bytes,
+        open_files_limit,
+        table_count: 0,
+        table_format_versions: Vec::new(),
+        replayed_batches: 0,
+        reconciled_vectors: 0,
+        rebuilt_field_indexes: false,
+        checks,
+        warnings: Vec::new(),
+    })
+}
+
+pub(crate) fn check_format_versions(report: &mut StartupReport, supported: u32) {
+    let newest = report.table_format_versions.iter().copied().max().unwrap_or(supported);
+    report.checks.push(Check {
+        name: "format_versions",
+        passed: newest <= supported,
+        detail: format!("tables use versions {:?}, up to {} supported", report.table_format_versions, supported),
+    });
+}
+
+// written and removed again to check the directory is writable
+pub(crate) const PROBE_FILE: &str = ".preflight";
+
+fn check_writable(directory: &Path) -> io::Result<()> {
+    let probe = directory.join(PROBE_FILE);
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+
+    #[test]
+    fn test_default_checks_pass() {
+        let path = empty_test_dir("preflight_default_checks_pass");
+        let report = run(&path, &Options::default(), false).unwrap();
+        assert!(report.passed(), "{:?}", report.checks);
+        assert!(report.free_bytes > 0);
+        assert!(report.open_files_limit > 0);
+        assert!(report.to_error().is_none());
+    }
+
+    #[test]
+    fn test_failed_check_is_reported() {
+        let path = empty_test_dir("preflight_failed_check_is_reported");
+        let options = Options { min_free_bytes: u64::MAX, ..Options::default() };
+        let report = run(&path, &options, false).unwrap();
+        assert!(!report.passed());
+        let err = report.to_error().unwrap();
+        assert!(err.to_string().contains("disk_free_space"));
+    }
+
+    #[test]
+    fn test_newer_format_version_fails() {
+        let path = empty_test_dir("preflight_newer_format_version_fails");
+        let mut report = run(&path, &Options::default(), false).unwrap();
+        report.table_format_versions = vec![1, 3];
+        check_format_versions(&mut report, 2);
+        assert!(!report.passed());
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{self, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// bytes charged to the bucket at a time, so a stream of small reads or writes doesn't
+// take the lock for each
+const CHUNK: u64 = 64 * 1024;
+
+// Token bucket holding up to a second's worth of bytes, or of whatever else it paces.
+// Taking more than it holds goes into debt, which the taker sleeps off, so a large charge
+// is paced rather than refused. A rate of 0 lets everything through.
+pub(crate) struct RateLimiter {
+    per_sec: AtomicU64,
+    bucket: Mutex<Bucket>,
+    waiting: AtomicUsize,
+    waited_micros: AtomicU64,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_sec: u64) -> RateLimiter {
+        let bucket = Bucket { tokens: per_sec as f64, refilled: Instant::now() };
+        RateLimiter { per_sec: AtomicU64::new(per_sec), bucket: Mutex::new(bucket), waiting: AtomicUsize::new(0), waited_micros: AtomicU64::new(0) }
+    }
+
+    pub(crate) fn rate(&self) -> u64 {
+        self.per_sec.load(Ordering::Relaxed)
+    }
+
+    // Takes effect for the next `acquire`; a debt already run up is slept off at the old rate
+    pub(crate) fn set_rate(&self, per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if self.per_sec.swap(per_sec, Ordering::Relaxed) == 0 {
+            // a bucket that wasn't limiting starts out full
+            bucket.tokens = per_sec as f64;
+            bucket.refilled = Instant::now();
+        }
+        bucket.tokens = bucket.tokens.min(per_sec as f64);
+    }
+
+    // Blocks until `bytes` fit in the budget
+    pub(crate) fn acquire(&self, bytes: u64) {
+        let rate = self.rate() as f64;
+        if rate == 0.0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate).min(rate);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate)
+        };
+        if wait.is_zero() {
+            return;
+        }
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        std::thread::sleep(wait);
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        self.waited_micros.fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Whether a taker is sleeping off its debt right now
+    pub(crate) fn is_throttling(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed) != 0
+    }
+
+    pub(crate) fn waited_micros(&self) -> u64 {
+        self.waited_micros.load(Ordering::Relaxed)
+    }
+}
+
+// Charges bytes to a limiter, if there is one, a chunk at a time
+pub(crate) struct Meter<'a> {
+    limiter: Option<&'a RateLimiter>,
+    pending: u64,
+}
+
+impl<'a> Meter<'a> {
+    pub(crate) fn new(limiter: Option<&'a RateLimiter>) -> Meter<'a> {
+        Meter { limiter, pending: 0 }
+    }
+
+    pub(crate) fn charge(&mut self, bytes: u64) {
+        let Some(limiter) = self.limiter else {
+            return;
+        };
+        self.pending += bytes;
+        if self.pending >= CHUNK {
+            limiter.acquire(self.pending);
+            self.pending = 0;
+        }
+    }
+}
+
+// A writer whose writes are charged to a meter
+pub(crate) struct Metered<'a, W> {
+    inner: W,
+    meter: Meter<'a>,
+}
+
+impl<'a, W> Metered<'a, W> {
+    pub(crate) fn new(inner: W, limiter: Option<&'a RateLimiter>) -> Metered<'a, W> {
+        Metered { inner, meter: Meter::new(limiter) }
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Metered<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.meter.charge(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for Metered<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1 << 20);
+        // the first second's worth is already in the bucket
+        let start = Instant::now();
+        limiter.acquire(1 << 20);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(limiter.waited_micros(), 0);
+
+        // the next quarter second's worth has to be waited for
+        limiter.acquire(1 << 18);
+        assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+        assert!(limiter.waited_micros() > 0);
+        assert!(!limiter.is_throttling());
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let limiter = RateLimiter::new(0);
+        let start = Instant::now();
+        limiter.acquire(1 << 30);
+        assert_eq!(limiter.waited_micros(), 0);
+
+        // turned on, the bucket starts full, and lowering the rate drains it to the new one
+        limiter.set_rate(1000);
+        limiter.set_rate(100);
+        assert_eq!(limiter.rate(), 100);
+        limiter.acquire(100);
+        assert_eq!(limiter.waited_micros(), 0);
+        limiter.acquire(20);
+        assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+
+        limiter.set_rate(0);
+        let waited = limiter.waited_micros();
+        limiter.acquire(1 << 30);
+        assert_eq!(limiter.waited_micros(), waited);
+    }
+
+    #[test]
+    fn test_metered_writes() {
+        let limiter = RateLimiter::new(CHUNK);
+        let mut out = Metered::new(io::Cursor::new(Vec::new()), Some(&limiter));
+        let start = Instant::now();
+        // one chunk drains the bucket, the next is waited for
+        for _ in 0..2 * CHUNK / 1024 {
+            out.write_all(&[7; 1024]).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(800), "{:?}", start.elapsed());
+        assert_eq!(out.stream_position().unwrap(), 2 * CHUNK);
+        assert_eq!(out.into_inner().into_inner().len() as u64, 2 * CHUNK);
+    }
+}
@@ -0,0 +1,256 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::thread::{self, JoinHandle};
+use crate::db::batch::WriteBatch;
+use crate::db::error::LsmError;
+use crate::db::events::Event;
+use crate::db::lsm::LSMTree;
+use crate::db::server::{self, ServerHandle};
+
+// Log shipping from a primary to replicas. A replica connects and sends the sequence
+// number of the last write it applied (u64). The primary answers with a status byte and,
+// if it is STREAMING, sends every write after that one, backlog first, then each write as
+// it commits, as WAL records (u32 length, then the record) of one operation each.
+//
+// A replica's tree must only be written by replication, so its own sequence numbers
+// follow the primary's and double as the resume point. Values arrive as the primary
+// stored them, so replicas want the primary's merge operator but no pipeline of their own.
+// A batch on the primary arrives as its operations, so a replica can briefly show part
+// of one.
+
+const STREAMING: u8 = 0;
+// the primary's log no longer reaches back to the replica, which needs a fresh copy
+const BEHIND: u8 = 1;
+// the replica has writes the primary doesn't
+const AHEAD: u8 = 2;
+
+pub struct Primary {
+    listener: TcpListener,
+    lsm: Arc<LSMTree>,
+}
+
+impl Primary {
+    pub fn bind(addr: impl ToSocketAddrs, lsm: Arc<LSMTree>) -> io::Result<Primary> {
+        Ok(Primary { listener: TcpListener::bind(addr)?, lsm })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Serves until the listener fails.
+    pub fn serve(self) -> io::Result<()> {
+        let lsm = self.lsm;
+        server::accept_until(&self.listener, &AtomicBool::new(false), "lsm-primary", Arc::new(move |stream| serve_replica(&lsm, stream)))
+    }
+
+    // Serves on a background thread until the handle is shut down or dropped. Replicas
+    // already connected keep streaming until they disconnect.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let lsm = self.lsm;
+        server::spawn(self.listener, "lsm-primary", move |stream| serve_replica(&lsm, stream))
+    }
+}
+
+// A replica applying the writes of its primary on a background thread, until shut down,
+// dropped or disconnected.
+pub struct Replica {
+    stream: TcpStream,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Replica {
+    // Connects to the primary at `addr` and resumes after the last write `lsm` applied.
+    // A primary that can't resume from there is refused here rather than on the thread.
+    pub fn follow(lsm: Arc<LSMTree>, addr: impl ToSocketAddrs) -> io::Result<Replica> {
+        let stream = TcpStream::connect(addr)?;
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        writer.write_u64::<LittleEndian>(lsm.sequence())?;
+        writer.flush()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        match reader.read_u8()? {
+            STREAMING => {}
+            BEHIND => return Err(io::Error::new(io::ErrorKind::NotFound, "the primary's log no longer holds this replica's next write")),
+            AHEAD => return Err(io::Error::new(io::ErrorKind::InvalidData, "the replica has writes the primary doesn't")),
+            status => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown replication status {}", status))),
+        }
+        let thread = thread::Builder::new()
+            .name("lsm-replica".to_string())
+            .spawn(move || apply_stream(&lsm, reader))?;
+        Ok(Replica { stream, thread: Some(thread) })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|thread| thread.is_finished())
+    }
+
+    // Stops applying writes, returning the error that ended replication early if there was one.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        // the applying thread reads this as the primary closing the stream
+        let _ = self.stream.shutdown(Shutdown::Both);
+        thread.join().map_err(|_| io::Error::other("replica thread panicked"))?
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+fn serve_replica(lsm: &LSMTree, stream: TcpStream) {
+    // the replica notices a primary that gave up on it as a closed stream
+    let _ = stream_to_replica(lsm, stream);
+}
+
+fn stream_to_replica(lsm: &LSMTree, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let applied = reader.read_u64::<LittleEndian>()?;
+
+    // subscribed before reading the backlog so nothing committed in between is missed;
+    // what arrives both ways is sent once
+    let events = lsm.subscribe();
+    if applied > lsm.sequence() {
+        writer.write_u8(AHEAD)?;
+        return writer.flush();
+    }
+    let backlog = match lsm.read_log_since(applied) {
+        Ok(backlog) => backlog,
+        Err(LsmError::NotFound(_)) => {
+            writer.write_u8(BEHIND)?;
+            return writer.flush();
+        }
+        Err(e) => return Err(e.into()),
+    };
+    writer.write_u8(STREAMING)?;
+
+    let mut sent = applied;
+    for event in backlog {
+        send(&mut writer, &event, &mut sent)?;
+    }
+    writer.flush()?;
+    // runs until a send fails because the replica went away
+    while let Ok(event) = events.recv() {
+        send(&mut writer, &event, &mut sent)?;
+        for event in events.try_iter() {
+            send(&mut writer, &event, &mut sent)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn send(writer: &mut impl Write, event: &Event, sent: &mut u64) -> io::Result<()> {
+    if event.sequence() <= *sent {
+        return Ok(());
+    }
+    let mut batch = WriteBatch::new();
+    match event {
+        Event::Put { key, value, .. } => batch.put(*key, value.clone()),
+        Event::Delete { key, .. } => batch.delete(*key),
+        Event::DeleteRange { start, end, .. } => batch.delete_range(*start, *end),
+        Event::Merge { key, operand, .. } => batch.merge(*key, operand.clone()),
+    };
+    let record = batch.encode(event.sequence())?;
+    writer.write_u32::<LittleEndian>(record.len() as u32)?;
+    writer.write_all(&record)?;
+    *sent = event.sequence();
+    Ok(())
+}
+
+fn apply_stream(lsm: &LSMTree, mut reader: impl Read) -> io::Result<()> {
+    loop {
+        let len = match reader.read_u32::<LittleEndian>() {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut record = vec![0; len];
+        reader.read_exact(&mut record)?;
+        let (sequence, batch) = WriteBatch::decode(&record)?;
+        let expected = lsm.sequence() + 1;
+        if sequence != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("replicated write {} arrived when {} was expected", sequence, expected)));
+        }
+        lsm.write(batch)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+    use crate::db::options::Options;
+    use crate::db::vector::Vector;
+    use std::time::{Duration, Instant};
+
+    fn wait_for(replica: &LSMTree, primary: &LSMTree) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while replica.sequence() < primary.sequence() {
+            assert!(Instant::now() < deadline, "replica stuck at {} of {}", replica.sequence(), primary.sequence());
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_replicate_and_resume() {
+        let path = test_dir("replication_resume");
+        let primary = Arc::new(LSMTree::new(&path.join("primary")).unwrap());
+        // written before the replica connects, so it comes from the log
+        primary.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        let server = Primary::bind("127.0.0.1:0", primary.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let _server = server.spawn().unwrap();
+
+        let replica = Arc::new(LSMTree::new(&path.join("replica")).unwrap());
+        let following = Replica::follow(replica.clone(), addr).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(2, Vector::new(2, vec![2.0])).put(3, Vector::new(3, vec![3.0])).delete(1);
+        primary.write(batch).unwrap();
+        wait_for(&replica, &primary);
+        assert_eq!(replica.range(..).unwrap(), primary.range(..).unwrap());
+        following.shutdown().unwrap();
+
+        // a replica that reconnects picks up where it left off, across a reopen too
+        primary.delete_range(0, 3).unwrap();
+        primary.insert(4, Vector::new(4, vec![4.0])).unwrap();
+        drop(replica);
+        let replica = Arc::new(LSMTree::new(&path.join("replica")).unwrap());
+        let following = Replica::follow(replica.clone(), addr).unwrap();
+        wait_for(&replica, &primary);
+        assert_eq!(replica.range(..).unwrap(), primary.range(..).unwrap());
+        assert_eq!(replica.sequence(), 6);
+        assert!(!following.is_finished());
+        following.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_refused_replicas() {
+        let path = test_dir("replication_refused");
+        let primary = Arc::new(LSMTree::open(&path.join("primary"), Options::default()).unwrap());
+        primary.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        primary.flush().unwrap();
+        let server = Primary::bind("127.0.0.1:0", primary.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let _server = server.spawn().unwrap();
+
+        // the first write was flushed out of the log
+        let replica = Arc::new(LSMTree::new(&path.join("replica")).unwrap());
+        assert_eq!(Replica::follow(replica.clone(), addr).err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        replica.insert(1, Vector::new(1, vec![1.0])).unwrap();
+        replica.insert(2, Vector::new(2, vec![2.0])).unwrap();
+        assert_eq!(Replica::follow(replica, addr).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
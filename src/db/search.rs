@@ -0,0 +1,304 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use crate::db::binary;
+use crate::db::simd;
+
+pub fn euclidean(a: &[f64], b: &[f64]) -> f64 {
+    simd::l2(a, b)
+}
+
+// How every search ranks vectors. Distances are smaller for closer vectors whatever the
+// metric, so results always come back closest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DistanceMetric {
+    #[default]
+    L2,
+    // 1 - cosine similarity, from 0 for the same direction to 2 for opposite ones
+    Cosine,
+    // the negated dot product
+    InnerProduct,
+    // the number of dimensions whose sign bits differ, a component's bit being set where
+    // it is positive, for binarized vectors, see `binary`
+    Hamming,
+    // 1 - |a ∧ b| / |a ∨ b| over the same bits, 0 between two vectors with none set
+    Jaccard,
+}
+
+impl DistanceMetric {
+    pub fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self {
+            DistanceMetric::L2 => simd::l2(a, b),
+            DistanceMetric::Cosine => 1.0 - simd::cosine(a, b),
+            DistanceMetric::InnerProduct => -simd::dot(a, b),
+            DistanceMetric::Hamming => binary::hamming(a, b),
+            DistanceMetric::Jaccard => binary::jaccard(a, b),
+        }
+    }
+
+    // Whether the metric compares only the sign bits of vectors
+    pub fn is_binary(&self) -> bool {
+        matches!(self, DistanceMetric::Hamming | DistanceMetric::Jaccard)
+    }
+
+    // A NaN or infinite component makes every distance NaN or infinite, which ranks in no
+    // useful order, and a zero query has no direction to compare by cosine
+    pub fn validate_query(&self, query: &[f64]) -> io::Result<()> {
+        if let Some(x) = query.iter().find(|x| !x.is_finite()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("query has a component of {}", x)));
+        }
+        if *self == DistanceMetric::Cosine && query.iter().all(|&x| x == 0.0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cosine distance is undefined for a zero query"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            DistanceMetric::L2 => 0,
+            DistanceMetric::Cosine => 1,
+            DistanceMetric::InnerProduct => 2,
+            DistanceMetric::Hamming => 3,
+            DistanceMetric::Jaccard => 4,
+        }
+    }
+
+    pub(crate) fn from_u8(tag: u8) -> io::Result<DistanceMetric> {
+        match tag {
+            0 => Ok(DistanceMetric::L2),
+            1 => Ok(DistanceMetric::Cosine),
+            2 => Ok(DistanceMetric::InnerProduct),
+            3 => Ok(DistanceMetric::Hamming),
+            4 => Ok(DistanceMetric::Jaccard),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown distance metric {}", tag))),
+        }
+    }
+}
+
+// Ranks by a metric, knowing whether the vectors compared have unit norm: cosine
+// distance between unit vectors is one dot product, without the norms
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Scorer {
+    metric: DistanceMetric,
+    unit_vectors: bool,
+}
+
+impl Scorer {
+    pub(crate) fn new(metric: DistanceMetric, unit_vectors: bool) -> Scorer {
+        Scorer { metric, unit_vectors }
+    }
+
+    pub(crate) fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self.metric {
+            DistanceMetric::Cosine if self.unit_vectors => 1.0 - simd::dot(a, b),
+            metric => metric.distance(a, b),
+        }
+    }
+
+    // `distance` between the packed bits of `query` and of a value stored as
+    // `ElementType::Binary`, see `binary::pack`, or None for a metric that compares more
+    // than the bits
+    pub(crate) fn packed_distance(&self, query: &[u8], bits: &[u8]) -> Option<f64> {
+        match self.metric {
+            DistanceMetric::Hamming => Some(binary::packed_hamming(query, bits)),
+            DistanceMetric::Jaccard => Some(binary::packed_jaccard(query, bits)),
+            _ => None,
+        }
+    }
+
+    // `distance` from `query` to each of `count` vectors stored dimension-major, all
+    // their first elements, then all their second ones. Each pass over a dimension is a
+    // straight loop over contiguous elements, which the compiler vectorizes.
+    pub(crate) fn column_distances<T: Copy + Into<f64>>(&self, query: &[f64], columns: &[T], count: usize) -> Vec<f64> {
+        let mut sums = vec![0.0; count];
+        let column = |d: usize| columns[d * count..(d + 1) * count].iter().map(|&x| x.into());
+        match self.metric {
+            DistanceMetric::L2 => {
+                for (d, &q) in query.iter().enumerate() {
+                    for (sum, x) in sums.iter_mut().zip(column(d)) {
+                        *sum += (x - q) * (x - q);
+                    }
+                }
+                sums.iter_mut().for_each(|sum| *sum = sum.sqrt());
+            }
+            DistanceMetric::InnerProduct => {
+                for (d, &q) in query.iter().enumerate() {
+                    for (sum, x) in sums.iter_mut().zip(column(d)) {
+                        *sum -= x * q;
+                    }
+                }
+            }
+            DistanceMetric::Cosine if self.unit_vectors => {
+                for (d, &q) in query.iter().enumerate() {
+                    for (sum, x) in sums.iter_mut().zip(column(d)) {
+                        *sum += x * q;
+                    }
+                }
+                sums.iter_mut().for_each(|dot| *dot = 1.0 - *dot);
+            }
+            DistanceMetric::Cosine => {
+                let mut norms = vec![0.0; count];
+                for (d, &q) in query.iter().enumerate() {
+                    for ((sum, norm), x) in sums.iter_mut().zip(norms.iter_mut()).zip(column(d)) {
+                        *sum += x * q;
+                        *norm += x * x;
+                    }
+                }
+                let query_norm = simd::dot(query, query);
+                for (dot, norm) in sums.iter_mut().zip(norms) {
+                    let norms = (norm * query_norm).sqrt();
+                    *dot = 1.0 - if norms == 0.0 { 0.0 } else { *dot / norms };
+                }
+            }
+            DistanceMetric::Hamming => {
+                for (d, &q) in query.iter().enumerate() {
+                    for (sum, x) in sums.iter_mut().zip(column(d)) {
+                        *sum += ((x > 0.0) != (q > 0.0)) as u8 as f64;
+                    }
+                }
+            }
+            DistanceMetric::Jaccard => {
+                // the bits set in both, then the bits set in either
+                let mut either = vec![0.0; count];
+                for (d, &q) in query.iter().enumerate() {
+                    for ((both, either), x) in sums.iter_mut().zip(either.iter_mut()).zip(column(d)) {
+                        *both += (x > 0.0 && q > 0.0) as u8 as f64;
+                        *either += (x > 0.0 || q > 0.0) as u8 as f64;
+                    }
+                }
+                for (both, either) in sums.iter_mut().zip(either) {
+                    *both = if either == 0.0 { 0.0 } else { 1.0 - *both / either };
+                }
+            }
+        }
+        sums
+    }
+}
+
+// The `k` closest candidates offered so far. The heap's top is the farthest one kept,
+// so a new candidate only has to beat it.
+pub(crate) struct TopK {
+    k: usize,
+    heap: BinaryHeap<Candidate>,
+}
+
+struct Candidate {
+    distance: f64,
+    key: u64,
+}
+
+impl Ord for Candidate {
+    // ties go to the smaller key so results don't depend on scan order
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.key.cmp(&other.key))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl TopK {
+    pub(crate) fn new(k: usize) -> TopK {
+        TopK { k, heap: BinaryHeap::with_capacity(k + 1) }
+    }
+
+    pub(crate) fn push(&mut self, key: u64, distance: f64) {
+        if self.k == 0 {
+            return;
+        }
+        let candidate = Candidate { distance, key };
+        if self.heap.len() < self.k {
+            self.heap.push(candidate);
+        } else if self.heap.peek().is_some_and(|farthest| candidate < *farthest) {
+            self.heap.pop();
+            self.heap.push(candidate);
+        }
+    }
+
+    // Closest first
+    pub(crate) fn into_sorted(self) -> Vec<(u64, f64)> {
+        self.heap.into_sorted_vec().into_iter().map(|c| (c.key, c.distance)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_euclidean() {
+        assert_eq!(euclidean(&[0.0, 0.0], &[3.0, 4.0]), 5.0);
+        assert_eq!(euclidean(&[1.0], &[1.0]), 0.0);
+    }
+
+    #[test]
+    fn test_distance_metrics() {
+        let (a, b) = ([1.0, 0.0], [0.0, 2.0]);
+        assert_eq!(DistanceMetric::L2.distance(&a, &b), 5.0f64.sqrt());
+        assert_eq!(DistanceMetric::Cosine.distance(&a, &b), 1.0);
+        assert_eq!(DistanceMetric::Cosine.distance(&a, &[3.0, 0.0]), 0.0);
+        assert_eq!(DistanceMetric::InnerProduct.distance(&[1.0, 2.0], &[3.0, 4.0]), -11.0);
+
+        assert!(DistanceMetric::Cosine.validate_query(&[0.0, 0.0]).is_err());
+        assert!(DistanceMetric::L2.validate_query(&[0.0, 0.0]).is_ok());
+        assert!(DistanceMetric::L2.validate_query(&[1.0, f64::NAN]).is_err());
+        assert!(DistanceMetric::InnerProduct.validate_query(&[f64::INFINITY]).is_err());
+        assert_eq!(DistanceMetric::Hamming.distance(&[1.0, -2.0, 0.0, 3.0], &[0.5, 2.0, 1.0, 0.0]), 3.0);
+        assert_eq!(DistanceMetric::Jaccard.distance(&[1.0, -2.0, 0.0, 3.0], &[0.5, 2.0, 1.0, 0.0]), 0.75);
+        for metric in [DistanceMetric::L2, DistanceMetric::Cosine, DistanceMetric::InnerProduct, DistanceMetric::Hamming, DistanceMetric::Jaccard] {
+            assert_eq!(DistanceMetric::from_u8(metric.to_u8()).unwrap(), metric);
+        }
+        assert!(DistanceMetric::from_u8(9).is_err());
+    }
+
+    #[test]
+    fn test_scorer_on_unit_vectors() {
+        let (a, b) = ([0.6, 0.8], [1.0, 0.0]);
+        for metric in [DistanceMetric::L2, DistanceMetric::Cosine, DistanceMetric::InnerProduct] {
+            assert!((Scorer::new(metric, true).distance(&a, &b) - metric.distance(&a, &b)).abs() < 1e-12);
+        }
+        // vectors that aren't unit length get the full computation without the promise
+        assert_eq!(Scorer::new(DistanceMetric::Cosine, false).distance(&[3.0, 4.0], &b), DistanceMetric::Cosine.distance(&[3.0, 4.0], &b));
+    }
+
+    #[test]
+    fn test_column_distances() {
+        let vectors = [[1.0, 2.0, 0.0], [0.0, 0.0, 0.0], [-3.0, 0.5, 4.0]];
+        let columns: Vec<f64> = (0..3).flat_map(|d| vectors.iter().map(move |v| v[d])).collect();
+        let query = [0.5, -1.0, 2.0];
+        for metric in [DistanceMetric::L2, DistanceMetric::Cosine, DistanceMetric::InnerProduct, DistanceMetric::Hamming, DistanceMetric::Jaccard] {
+            let scorer = Scorer::new(metric, false);
+            let distances = scorer.column_distances(&query, &columns, vectors.len());
+            for (vector, distance) in vectors.iter().zip(distances) {
+                assert!((distance - scorer.distance(&query, vector)).abs() < 1e-12, "{:?}", metric);
+            }
+        }
+        let unit = [0.6f32, 0.0, 0.8, 1.0];
+        let distances = Scorer::new(DistanceMetric::Cosine, true).column_distances(&[1.0, 0.0], &unit, 2);
+        assert!((distances[0] - 0.4).abs() < 1e-6 && (distances[1] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_top_k_keeps_closest() {
+        let mut top = TopK::new(3);
+        for (key, distance) in [(1, 5.0), (2, 1.0), (3, 4.0), (4, 0.5), (5, 1.0), (6, 9.0)] {
+            top.push(key, distance);
+        }
+        assert_eq!(top.into_sorted(), vec![(4, 0.5), (2, 1.0), (5, 1.0)]);
+
+        let mut none = TopK::new(0);
+        none.push(1, 0.0);
+        assert!(none.into_sorted().is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+// Network front ends for a tree, each behind its own feature
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "resp")]
+pub mod resp;
+
+// A server accepting on a background thread, until shut down or dropped.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Stops accepting connections; ones already open run to completion.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        self.stop.store(true, Ordering::Release);
+        // wake the accept loop so it sees the flag
+        let _ = TcpStream::connect(self.addr);
+        thread.join().map_err(|_| io::Error::other("server thread panicked"))?
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub(crate) fn spawn<F>(listener: TcpListener, name: &str, serve: F) -> io::Result<ServerHandle>
+where
+    F: Fn(TcpStream) + Send + Sync + 'static,
+{
+    let addr = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    let name = name.to_string();
+    let thread = thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || accept_until(&listener, &flag, &name, Arc::new(serve)))?;
+    Ok(ServerHandle { addr, stop, thread: Some(thread) })
+}
+
+// Hands every connection to `serve` on a thread of its own until `stop` is set.
+pub(crate) fn accept_until<F>(listener: &TcpListener, stop: &AtomicBool, name: &str, serve: Arc<F>) -> io::Result<()>
+where
+    F: Fn(TcpStream) + Send + Sync + 'static,
+{
+    for stream in listener.incoming() {
+        if stop.load(Ordering::Acquire) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            // the client went away before we got to it
+            Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+            Err(e) => return Err(e),
+        };
+        let serve = Arc::clone(&serve);
+        thread::Builder::new()
+            .name(format!("{}-conn", name))
+            .spawn(move || serve(stream))?;
+    }
+    Ok(())
+}
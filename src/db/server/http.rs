@@ -0,0 +1,518 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use crate::db::error::LsmError;
+use crate::db::filter::Filter;
+use crate::db::lsm::{Cursor, LSMTree};
+use crate::db::server::{self, ServerHandle};
+use crate::db::stats::Latency;
+use crate::db::vector::{MetadataValue, Vector};
+
+// A single-node vector service over one tree, JSON over HTTP/1.1:
+//
+//     PUT    /vectors/{id}  {"data": [..], "metadata": {..}, "ttl_ms": n}
+//     GET    /vectors/{id}
+//     DELETE /vectors/{id}
+//     POST   /search        {"vector": [..], "k": n, "filter": {..}}
+//     POST   /scan          {"start": a, "end": b, "limit": n, "cursor": ".."}
+//     GET    /stats
+//
+// A search filter maps each field to a value it must equal, a list of values it must
+// be one of, or {"min": a, "max": b}; every field has to match. A scan returns up to
+// `limit` vectors with ids from `start` up to `end`, exclusive, and a cursor to send with
+// the same range for the next page, null after the last one. Each connection gets a
+// thread and carries one request.
+
+// bodies past this are refused before being read
+const MAX_BODY: usize = 64 << 20;
+const MAX_HEAD: usize = 64 << 10;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_K: usize = 10;
+const DEFAULT_LIMIT: usize = 100;
+
+pub struct HttpServer {
+    listener: TcpListener,
+    lsm: Arc<LSMTree>,
+}
+
+impl HttpServer {
+    pub fn bind(addr: impl ToSocketAddrs, lsm: Arc<LSMTree>) -> io::Result<HttpServer> {
+        Ok(HttpServer { listener: TcpListener::bind(addr)?, lsm })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Serves until the listener fails.
+    pub fn serve(self) -> io::Result<()> {
+        let lsm = self.lsm;
+        server::accept_until(&self.listener, &AtomicBool::new(false), "lsm-http", Arc::new(move |stream| serve_connection(&lsm, stream)))
+    }
+
+    // Serves on a background thread until the handle is shut down or dropped.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let lsm = self.lsm;
+        server::spawn(self.listener, "lsm-http", move |stream| serve_connection(&lsm, stream))
+    }
+}
+
+fn serve_connection(lsm: &LSMTree, stream: TcpStream) {
+    // a failed write means the client hung up; nothing to tell it
+    let _ = handle_connection(lsm, stream);
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: Option<Value>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Response {
+        Response { status, body: Some(body) }
+    }
+
+    fn empty() -> Response {
+        Response { status: 204, body: None }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Response {
+        Response::json(status, json!({ "error": message.into() }))
+    }
+}
+
+impl From<io::Error> for Response {
+    fn from(e: io::Error) -> Response {
+        let status = match e.kind() {
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => 400,
+            io::ErrorKind::NotFound => 404,
+            _ => 500,
+        };
+        Response::error(status, e.to_string())
+    }
+}
+
+impl From<LsmError> for Response {
+    fn from(e: LsmError) -> Response {
+        let status = match e {
+            LsmError::InvalidArgument(_) => 400,
+            LsmError::NotFound(_) => 404,
+            LsmError::Io(_) | LsmError::Corruption { .. } | LsmError::Serialization(_) => 500,
+        };
+        Response::error(status, e.to_string())
+    }
+}
+
+fn handle_connection(lsm: &LSMTree, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader) {
+        Ok(Ok(request)) => route(lsm, &request),
+        Ok(Err(response)) => response,
+        Err(e) => return Err(e),
+    };
+    write_response(stream, &response)
+}
+
+// The outer error is the connection failing, the inner one a request we won't serve.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Result<Request, Response>> {
+    let mut line = String::new();
+    let mut head_size = 0;
+    if let Err(response) = read_head_line(reader, &mut line, &mut head_size)? {
+        return Ok(Err(response));
+    }
+    let mut request_line = line.split_whitespace();
+    let (Some(method), Some(path), Some(_version)) = (request_line.next(), request_line.next(), request_line.next()) else {
+        return Ok(Err(Response::error(400, "malformed request line")));
+    };
+    // the query string isn't used by any route
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut content_length = 0;
+    loop {
+        if let Err(response) = read_head_line(reader, &mut line, &mut head_size)? {
+            return Ok(Err(response));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Ok(Err(Response::error(400, format!("malformed header '{}'", header))));
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            let Ok(len) = value.trim().parse() else {
+                return Ok(Err(Response::error(400, "bad content-length")));
+            };
+            content_length = len;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Ok(Err(Response::error(501, "chunked bodies are not supported")));
+        }
+    }
+    if content_length > MAX_BODY {
+        return Ok(Err(Response::error(413, format!("bodies are limited to {} bytes", MAX_BODY))));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(Request { method, path, body }))
+}
+
+// Reads no further than `MAX_HEAD` into the head, so a line that never ends is refused
+// once it reaches the limit rather than buffered whole
+fn read_head_line(reader: &mut impl BufRead, line: &mut String, head_size: &mut usize) -> io::Result<Result<(), Response>> {
+    line.clear();
+    let read = reader.take((MAX_HEAD - *head_size) as u64 + 1).read_line(line)?;
+    if read == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-request"));
+    }
+    *head_size += read;
+    if *head_size > MAX_HEAD {
+        return Ok(Err(Response::error(431, "request head too large")));
+    }
+    Ok(Ok(()))
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> io::Result<()> {
+    let body = match &response.body {
+        Some(body) => serde_json::to_vec(body).map_err(io::Error::other)?,
+        None => Vec::new(),
+    };
+    let mut head = format!("HTTP/1.1 {} {}\r\nConnection: close\r\nContent-Length: {}\r\n", response.status, reason(response.status), body.len());
+    if response.body.is_some() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+fn route(lsm: &LSMTree, request: &Request) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("PUT", ["vectors", id]) => parse_id(id).and_then(|id| put_vector(lsm, id, &request.body)),
+        ("GET", ["vectors", id]) => parse_id(id).and_then(|id| get_vector(lsm, id)),
+        ("DELETE", ["vectors", id]) => parse_id(id).and_then(|id| delete_vector(lsm, id)),
+        ("POST", ["search"]) => search(lsm, &request.body),
+        ("POST", ["scan"]) => scan(lsm, &request.body),
+        ("GET", ["stats"]) => stats(lsm),
+        (_, ["vectors", _] | ["search"] | ["scan"] | ["stats"]) => Err(Response::error(405, format!("{} is not allowed on {}", request.method, request.path))),
+        _ => Err(Response::error(404, format!("no route for {}", request.path))),
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+fn parse_id(id: &str) -> Result<u64, Response> {
+    id.parse().map_err(|_| Response::error(400, format!("'{}' is not a vector id", id)))
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Response> {
+    serde_json::from_slice(body).map_err(|e| Response::error(400, format!("bad request body: {}", e)))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PutBody {
+    data: Vec<f64>,
+    #[serde(default)]
+    metadata: Map<String, Value>,
+    ttl_ms: Option<u64>,
+}
+
+fn put_vector(lsm: &LSMTree, id: u64, body: &[u8]) -> Result<Response, Response> {
+    let body: PutBody = parse_body(body)?;
+    let mut value = Vector::new(id, body.data);
+    for (field, v) in body.metadata {
+        let v = metadata_value(&v).ok_or_else(|| Response::error(400, format!("metadata field '{}' must be a bool, number or string", field)))?;
+        value = value.with_metadata(field, v);
+    }
+    match body.ttl_ms {
+        Some(ttl) => lsm.insert_with_ttl(id, value, Duration::from_millis(ttl))?,
+        None => lsm.insert(id, value)?,
+    }
+    Ok(Response::empty())
+}
+
+fn delete_vector(lsm: &LSMTree, id: u64) -> Result<Response, Response> {
+    match lsm.delete(id)? {
+        true => Ok(Response::empty()),
+        false => Err(Response::error(404, format!("vector {} not found", id))),
+    }
+}
+
+fn get_vector(lsm: &LSMTree, id: u64) -> Result<Response, Response> {
+    let value = lsm.get(id)?.ok_or_else(|| Response::error(404, format!("vector {} not found", id)))?;
+    let mut body = json!({ "id": id, "data": value.data(), "metadata": value.metadata() });
+    if let Some(at) = value.expires_at() {
+        body["expires_at"] = json!(at);
+    }
+    Ok(Response::json(200, body))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SearchBody {
+    vector: Vec<f64>,
+    k: Option<usize>,
+    #[serde(default)]
+    filter: Map<String, Value>,
+}
+
+fn search(lsm: &LSMTree, body: &[u8]) -> Result<Response, Response> {
+    let body: SearchBody = parse_body(body)?;
+    let filter = Filter::And(body.filter.iter().map(|(field, v)| field_filter(field, v)).collect::<Result<_, _>>()?);
+    // an empty And matches everything, and search_filtered picks HNSW or a scan
+    let hits = lsm.search_filtered(&body.vector, body.k.unwrap_or(DEFAULT_K), &filter)?;
+    let results: Vec<Value> = hits.into_iter().map(|(id, distance)| json!({ "id": id, "distance": distance })).collect();
+    Ok(Response::json(200, json!({ "results": results })))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScanBody {
+    #[serde(default)]
+    start: u64,
+    end: Option<u64>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+fn scan(lsm: &LSMTree, body: &[u8]) -> Result<Response, Response> {
+    let body: ScanBody = parse_body(body)?;
+    let cursor = body.cursor.as_deref().map(Cursor::decode).transpose()?;
+    let end = body.end.map_or(Bound::Unbounded, Bound::Excluded);
+    let (page, next) = lsm.scan((Bound::Included(body.start), end), body.limit.unwrap_or(DEFAULT_LIMIT), cursor.as_ref())?;
+    let results: Vec<Value> = page.iter().map(|(id, value)| json!({ "id": id, "data": value.data(), "metadata": value.metadata() })).collect();
+    Ok(Response::json(200, json!({ "results": results, "cursor": next.map(|next| next.encode()) })))
+}
+
+fn field_filter(field: &str, v: &Value) -> Result<Filter, Response> {
+    let bad = || Response::error(400, format!("bad filter on '{}'", field));
+    match v {
+        Value::Array(values) => Ok(Filter::In(field.to_string(), values.iter().map(metadata_value).collect::<Option<_>>().ok_or_else(bad)?)),
+        Value::Object(bounds) => {
+            if bounds.keys().any(|k| k != "min" && k != "max") {
+                return Err(bad());
+            }
+            let bound = |name| bounds.get(name).map(|b| metadata_value(b).ok_or_else(bad)).transpose();
+            Ok(Filter::range(field, bound("min")?, bound("max")?))
+        }
+        v => Ok(Filter::Eq(field.to_string(), metadata_value(v).ok_or_else(bad)?)),
+    }
+}
+
+fn metadata_value(v: &Value) -> Option<MetadataValue> {
+    match v {
+        Value::Bool(b) => Some(MetadataValue::Bool(*b)),
+        Value::Number(n) => n.as_i64().map(MetadataValue::Int).or_else(|| n.as_f64().map(MetadataValue::Float)),
+        Value::String(s) => Some(MetadataValue::String(s.clone())),
+        _ => None,
+    }
+}
+
+fn stats(lsm: &LSMTree) -> Result<Response, Response> {
+    let stats = lsm.stats();
+    let mut body = json!({
+        "len": lsm.len()?,
+        "table_count": stats.table_count,
+        "gets": stats.gets,
+        "memtable_hits": stats.memtable_hits,
+        "sstable_probes": stats.sstable_probes,
+        "filter_negatives": stats.filter_negatives,
+        "filter_false_positives": stats.filter_false_positives,
+        "key_range_skips": stats.key_range_skips,
+        "bytes_written": stats.bytes_written,
+        "bytes_flushed": stats.bytes_flushed,
+        "bytes_compacted": stats.bytes_compacted,
+        "flushes": stats.flushes,
+        "compactions": stats.compactions,
+        "timed_flushes": stats.timed_flushes,
+        "wal_syncs": stats.wal_syncs,
+        "synced_commits": stats.synced_commits,
+        "block_cache_hits": stats.block_cache_hits,
+        "block_cache_misses": stats.block_cache_misses,
+        "block_cache_bytes": stats.block_cache_bytes,
+        "row_cache_hits": stats.row_cache_hits,
+        "row_cache_misses": stats.row_cache_misses,
+        "compaction_throttled_micros": stats.compaction_throttled_micros,
+        "compaction_throttled": stats.compaction_throttled,
+        "write_throttled_micros": stats.write_throttled_micros,
+        "write_slowdowns": stats.write_slowdowns,
+        "write_stops": stats.write_stops,
+        "write_stall_micros": stats.write_stall_micros,
+        "stalled_writers": stats.stalled_writers,
+        "memtable_bytes": stats.memtable_bytes,
+        "frozen_memtable_bytes": stats.frozen_memtable_bytes,
+        "filter_bytes": stats.filter_bytes,
+        "index_bytes": stats.index_bytes,
+        "memory_flushes": stats.memory_flushes,
+        "memory_bytes": stats.memory_bytes(),
+        "read_amplification": stats.read_amplification(),
+        "filter_false_positive_rate": stats.filter_false_positive_rate(),
+        "write_amplification": stats.write_amplification(),
+    });
+    // added one by one, a single json! this long runs past the macro recursion limit
+    for (name, histogram) in [("get_latency", &stats.get_latency), ("write_latency", &stats.write_latency), ("flush_latency", &stats.flush_latency), ("search_latency", &stats.search_latency)] {
+        body[name] = latency(histogram);
+    }
+    Ok(Response::json(200, body))
+}
+
+fn latency(latency: &Latency) -> Value {
+    json!({
+        "count": latency.count,
+        "mean_micros": latency.mean_micros(),
+        "p50_micros": latency.percentile_micros(50.0),
+        "p99_micros": latency.percentile_micros(99.0),
+        "p999_micros": latency.percentile_micros(99.9),
+        "max_micros": latency.max_micros,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+    use crate::db::options::Options;
+
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = if body.is_empty() { Value::Null } else { serde_json::from_str(body).unwrap() };
+        (status, body)
+    }
+
+    #[test]
+    fn test_vectors_and_search() {
+        let lsm = Arc::new(LSMTree::open(&test_dir("http_vectors"), Options { latency_histograms: true, ..Options::default() }).unwrap());
+        let server = HttpServer::bind("127.0.0.1:0", Arc::clone(&lsm)).unwrap().spawn().unwrap();
+        let addr = server.local_addr();
+
+        for (id, x, lang) in [(1, 0.0, "en"), (2, 1.0, "de"), (3, 2.0, "en")] {
+            let body = format!(r#"{{"data": [{}, 0.0], "metadata": {{"lang": "{}", "rank": {}}}}}"#, x, lang, id);
+            assert_eq!(request(addr, "PUT", &format!("/vectors/{}", id), &body).0, 204);
+        }
+
+        let (status, body) = request(addr, "GET", "/vectors/3", "");
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "id": 3, "data": [2.0, 0.0], "metadata": { "lang": "en", "rank": 3 } }));
+
+        let (status, body) = request(addr, "POST", "/search", r#"{"vector": [0.9, 0.0], "k": 2}"#);
+        assert_eq!(status, 200);
+        let ids: Vec<u64> = body["results"].as_array().unwrap().iter().map(|hit| hit["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, vec![2, 1]);
+
+        let (_, body) = request(addr, "POST", "/search", r#"{"vector": [0.9, 0.0], "filter": {"lang": "en", "rank": {"min": 2}}}"#);
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["id"], 3);
+
+        let (status, body) = request(addr, "POST", "/scan", r#"{"limit": 2}"#);
+        assert_eq!(status, 200);
+        let ids: Vec<u64> = body["results"].as_array().unwrap().iter().map(|hit| hit["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(body["results"][1]["data"], json!([1.0, 0.0]));
+        // a write between pages, which the default options keep no history for
+        assert_eq!(request(addr, "PUT", "/vectors/4", r#"{"data": [3.0, 0.0]}"#).0, 204);
+        let page = format!(r#"{{"limit": 2, "cursor": {}}}"#, body["cursor"]);
+        let (status, body) = request(addr, "POST", "/scan", &page);
+        assert_eq!(status, 200);
+        let ids: Vec<u64> = body["results"].as_array().unwrap().iter().map(|hit| hit["id"].as_u64().unwrap()).collect();
+        assert_eq!((ids, &body["cursor"]), (vec![3, 4], &Value::Null));
+        assert_eq!(request(addr, "DELETE", "/vectors/4", "").0, 204);
+        let (_, body) = request(addr, "POST", "/scan", r#"{"start": 2, "end": 3}"#);
+        assert_eq!(body["results"].as_array().unwrap().len(), 1);
+        assert_eq!(request(addr, "POST", "/scan", r#"{"cursor": "nope"}"#).0, 400);
+
+        assert_eq!(request(addr, "DELETE", "/vectors/3", "").0, 204);
+        assert_eq!(request(addr, "GET", "/vectors/3", "").0, 404);
+        assert_eq!(request(addr, "DELETE", "/vectors/3", "").0, 404);
+
+        let (status, body) = request(addr, "GET", "/stats", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["len"], 2);
+        assert_eq!(body["write_latency"]["count"], 7);
+        assert!(body["search_latency"]["count"].as_u64().unwrap() >= 2);
+        assert!(body["get_latency"]["p99_micros"].as_u64().unwrap() <= body["get_latency"]["max_micros"].as_u64().unwrap());
+
+        server.shutdown().unwrap();
+        // connection threads may still hold the tree for a moment, so it closes on drop
+        drop(lsm);
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let lsm = Arc::new(LSMTree::open(&test_dir("http_bad_requests"), Options::default()).unwrap());
+        let server = HttpServer::bind("127.0.0.1:0", lsm).unwrap().spawn().unwrap();
+        let addr = server.local_addr();
+
+        assert_eq!(request(addr, "PUT", "/vectors/x", r#"{"data": [1.0]}"#).0, 400);
+        assert_eq!(request(addr, "PUT", "/vectors/1", r#"{"data": "nope"}"#).0, 400);
+        assert_eq!(request(addr, "PUT", "/vectors/1", r#"{"data": [1.0], "metadata": {"tags": {}}}"#).0, 400);
+        assert_eq!(request(addr, "POST", "/search", r#"{"vector": [1.0], "filter": {"lang": {"above": 1}}}"#).0, 400);
+        assert_eq!(request(addr, "POST", "/vectors/1", "").0, 405);
+        assert_eq!(request(addr, "GET", "/nowhere", "").0, 404);
+
+        let (status, body) = request(addr, "PUT", "/vectors/1", r#"{"data": [1.0], "ttl_ms": 60000}"#);
+        assert_eq!(status, 204, "{}", body);
+        assert!(request(addr, "GET", "/vectors/1", "").1["expires_at"].is_u64());
+    }
+
+    #[test]
+    fn test_head_limit() {
+        let status = |input: Vec<u8>| {
+            let mut reader = io::Cursor::new(input);
+            let status = match read_request(&mut reader).unwrap() {
+                Ok(_) => 200,
+                Err(response) => response.status,
+            };
+            (status, reader.position() as usize)
+        };
+        let head = |headers: &str| format!("GET /stats HTTP/1.1\r\n{}\r\n", headers).into_bytes();
+        assert_eq!(status(head("Host: a\r\n")).0, 200);
+
+        // a header line that never ends is cut off at the limit, not read to its end
+        let mut endless = head("");
+        endless.truncate(endless.len() - 2);
+        endless.extend(b"X-Long: ");
+        endless.resize(4 * MAX_HEAD, b'a');
+        let (code, read) = status(endless);
+        assert_eq!(code, 431);
+        assert!(read <= MAX_HEAD + 1, "{}", read);
+
+        // and so is a head of many short lines
+        let (code, read) = status(head(&"X-Short: a\r\n".repeat(MAX_HEAD / 8)));
+        assert_eq!(code, 431);
+        assert!(read <= MAX_HEAD + 1, "{}", read);
+    }
+}
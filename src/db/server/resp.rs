@@ -0,0 +1,348 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use crate::db::error::LsmError;
+use crate::db::filter::Filter;
+use crate::db::lsm::LSMTree;
+use crate::db::server::{self, ServerHandle};
+use crate::db::vector::Vector;
+
+// Speaks enough of the Redis protocol (RESP2) for redis clients to work against a tree.
+// Keys are u64s written in decimal and values are vectors written as comma separated
+// numbers:
+//
+//     SET key x,y,... [EX seconds | PX milliseconds]
+//     GET key
+//     DEL key [key ...]
+//     EXISTS key [key ...]
+//     SCAN cursor [COUNT n]
+//     VSEARCH x,y,... [K n]     -> [key, distance, key, distance, ...]
+//     PING [message] | QUIT
+//
+// SCAN's cursor is the key to resume from, so it never skips or repeats a key that stays
+// put, and 0 both starts a scan and ends one.
+
+const MAX_ARGS: usize = 1 << 20;
+const MAX_BULK: usize = 64 << 20;
+const DEFAULT_COUNT: usize = 10;
+const DEFAULT_K: usize = 10;
+
+pub struct RespServer {
+    listener: TcpListener,
+    lsm: Arc<LSMTree>,
+}
+
+impl RespServer {
+    pub fn bind(addr: impl ToSocketAddrs, lsm: Arc<LSMTree>) -> io::Result<RespServer> {
+        Ok(RespServer { listener: TcpListener::bind(addr)?, lsm })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Serves until the listener fails.
+    pub fn serve(self) -> io::Result<()> {
+        let lsm = self.lsm;
+        server::accept_until(&self.listener, &AtomicBool::new(false), "lsm-resp", Arc::new(move |stream| serve_connection(&lsm, stream)))
+    }
+
+    // Serves on a background thread until the handle is shut down or dropped.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let lsm = self.lsm;
+        server::spawn(self.listener, "lsm-resp", move |stream| serve_connection(&lsm, stream))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn bulk(s: impl Into<String>) -> Reply {
+        Reply::Bulk(Some(s.into()))
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(out, "+{}\r\n", s),
+            // a newline would end the error early
+            Reply::Error(e) => write!(out, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(i) => write!(out, ":{}\r\n", i),
+            Reply::Bulk(None) => write!(out, "$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(out, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(out))
+            }
+        }
+    }
+}
+
+impl From<io::Error> for Reply {
+    fn from(e: io::Error) -> Reply {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+impl From<LsmError> for Reply {
+    fn from(e: LsmError) -> Reply {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+fn serve_connection(lsm: &LSMTree, stream: TcpStream) {
+    // an error here means the client hung up or broke the protocol; either way it's gone
+    let _ = handle_connection(lsm, stream);
+}
+
+fn handle_connection(lsm: &LSMTree, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let command = match read_command(&mut reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // a framing error leaves nothing to resync on
+                Reply::Error(format!("ERR Protocol error: {}", e)).write_to(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        if command.is_empty() {
+            continue;
+        }
+        let quit = command[0].eq_ignore_ascii_case("quit");
+        let reply = if quit { Reply::Simple("OK") } else { execute(lsm, &command).unwrap_or_else(|e| e) };
+        reply.write_to(&mut writer)?;
+        // replies to pipelined commands go out together
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+// None once the client closes the connection between commands. Besides arrays of bulk
+// strings this takes inline commands, a line of words, as typed into telnet.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<String>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let Some(count) = line.strip_prefix('*') else {
+        return Ok(Some(line.split_whitespace().map(str::to_string).collect()));
+    };
+    let count = parse_length(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let Some(len) = line.strip_prefix('$') else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a bulk string"));
+        };
+        let len = parse_length(len, MAX_BULK)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bulk string not terminated"));
+        }
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "arguments must be UTF-8"))?);
+    }
+    Ok(Some(args))
+}
+
+fn parse_length(line: &str, max: usize) -> io::Result<usize> {
+    match line.trim_end().parse() {
+        Ok(len) if len <= max => Ok(len),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad length '{}'", line.trim_end()))),
+    }
+}
+
+fn execute(lsm: &LSMTree, command: &[String]) -> Result<Reply, Reply> {
+    let name = command[0].to_ascii_uppercase();
+    let args = &command[1..];
+    match (name.as_str(), args) {
+        ("PING", []) => Ok(Reply::Simple("PONG")),
+        ("PING", [message]) => Ok(Reply::bulk(message.as_str())),
+        ("SET", [key, value, expiry @ ..]) => {
+            let key = parse_key(key)?;
+            let value = Vector::new(key, parse_vector(value)?);
+            match expiry {
+                [] => lsm.insert(key, value)?,
+                [unit, n] if unit.eq_ignore_ascii_case("ex") => lsm.insert_with_ttl(key, value, Duration::from_secs(parse_count(n)? as u64))?,
+                [unit, n] if unit.eq_ignore_ascii_case("px") => lsm.insert_with_ttl(key, value, Duration::from_millis(parse_count(n)? as u64))?,
+                _ => return Err(syntax_error()),
+            }
+            Ok(Reply::Simple("OK"))
+        }
+        ("GET", [key]) => Ok(Reply::Bulk(lsm.get(parse_key(key)?)?.map(|value| format_vector(value.data())))),
+        ("DEL", keys) if !keys.is_empty() => {
+            let mut removed = 0;
+            for key in keys {
+                removed += lsm.delete(parse_key(key)?)? as i64;
+            }
+            Ok(Reply::Integer(removed))
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            let keys = keys.iter().map(|k| parse_key(k)).collect::<Result<Vec<_>, _>>()?;
+            let mut found = 0;
+            for key in keys {
+                found += lsm.contains_key(key)? as i64;
+            }
+            Ok(Reply::Integer(found))
+        }
+        ("SCAN", [cursor, rest @ ..]) => {
+            let cursor = parse_key(cursor).map_err(|_| Reply::Error("ERR invalid cursor".to_string()))?;
+            let count = match rest {
+                [] => DEFAULT_COUNT,
+                [option, n] if option.eq_ignore_ascii_case("count") => parse_count(n)?.max(1),
+                _ => return Err(syntax_error()),
+            };
+            scan(lsm, cursor, count)
+        }
+        ("VSEARCH", [query, rest @ ..]) => {
+            let k = match rest {
+                [] => DEFAULT_K,
+                [option, n] if option.eq_ignore_ascii_case("k") => parse_count(n)?,
+                _ => return Err(syntax_error()),
+            };
+            // an empty And matches everything, and search_filtered picks HNSW or a scan
+            let hits = lsm.search_filtered(&parse_vector(query)?, k, &Filter::And(Vec::new()))?;
+            Ok(Reply::Array(hits.into_iter().flat_map(|(key, distance)| [Reply::bulk(key.to_string()), Reply::bulk(distance.to_string())]).collect()))
+        }
+        // redis-cli asks for command docs on connect; an empty answer is fine
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        ("PING" | "SET" | "GET" | "DEL" | "EXISTS" | "SCAN" | "VSEARCH", _) => Err(Reply::Error(format!("ERR wrong number of arguments for '{}' command", command[0].to_lowercase()))),
+        _ => Err(Reply::Error(format!("ERR unknown command '{}'", command[0]))),
+    }
+}
+
+fn scan(lsm: &LSMTree, cursor: u64, count: usize) -> Result<Reply, Reply> {
+    let mut keys = Vec::with_capacity(count);
+    for entry in lsm.iter() {
+        let (key, _) = entry?;
+        if key < cursor {
+            continue;
+        }
+        keys.push(key);
+        if keys.len() == count {
+            break;
+        }
+    }
+    // a short page is the last one
+    let next = match keys.last() {
+        Some(&last) if keys.len() == count => last.wrapping_add(1),
+        _ => 0,
+    };
+    Ok(Reply::Array(vec![
+        Reply::bulk(next.to_string()),
+        Reply::Array(keys.into_iter().map(|k| Reply::bulk(k.to_string())).collect()),
+    ]))
+}
+
+fn parse_key(key: &str) -> Result<u64, Reply> {
+    key.parse().map_err(|_| Reply::Error(format!("ERR key '{}' is not an unsigned integer", key)))
+}
+
+fn parse_count(n: &str) -> Result<usize, Reply> {
+    n.parse().map_err(|_| Reply::Error("ERR value is not an integer or out of range".to_string()))
+}
+
+fn parse_vector(value: &str) -> Result<Vec<f64>, Reply> {
+    value.split(',').map(|x| x.trim().parse().map_err(|_| Reply::Error(format!("ERR '{}' is not a number", x)))).collect()
+}
+
+fn format_vector(data: &[f64]) -> String {
+    data.iter().map(f64::to_string).collect::<Vec<_>>().join(",")
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+    use crate::db::options::Options;
+    use std::io::Read;
+
+    fn encode(command: &[&str]) -> String {
+        let mut out = format!("*{}\r\n", command.len());
+        for arg in command {
+            out.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        out
+    }
+
+    fn run(lsm: &LSMTree, command: &[&str]) -> Reply {
+        let command: Vec<String> = command.iter().map(|s| s.to_string()).collect();
+        execute(lsm, &command).unwrap_or_else(|e| e)
+    }
+
+    #[test]
+    fn test_commands() {
+        let lsm = LSMTree::open(&test_dir("resp_commands"), Options::default()).unwrap();
+        assert_eq!(run(&lsm, &["set", "1", "0,0"]), Reply::Simple("OK"));
+        assert_eq!(run(&lsm, &["SET", "2", "1,0.5", "PX", "60000"]), Reply::Simple("OK"));
+        assert_eq!(run(&lsm, &["SET", "3", "3,0"]), Reply::Simple("OK"));
+        assert_eq!(run(&lsm, &["GET", "2"]), Reply::bulk("1,0.5"));
+        assert!(lsm.get(2).unwrap().unwrap().expires_at().is_some());
+        assert_eq!(run(&lsm, &["GET", "9"]), Reply::Bulk(None));
+        assert_eq!(run(&lsm, &["EXISTS", "1", "9", "3"]), Reply::Integer(2));
+
+        let page = run(&lsm, &["SCAN", "0", "COUNT", "2"]);
+        assert_eq!(page, Reply::Array(vec![Reply::bulk("3"), Reply::Array(vec![Reply::bulk("1"), Reply::bulk("2")])]));
+        let page = run(&lsm, &["SCAN", "3", "COUNT", "2"]);
+        assert_eq!(page, Reply::Array(vec![Reply::bulk("0"), Reply::Array(vec![Reply::bulk("3")])]));
+
+        let Reply::Array(hits) = run(&lsm, &["VSEARCH", "2.9,0", "K", "2"]) else { panic!() };
+        assert_eq!(hits.len(), 4);
+        assert_eq!((&hits[0], &hits[2]), (&Reply::bulk("3"), &Reply::bulk("2")));
+
+        assert_eq!(run(&lsm, &["DEL", "1", "9"]), Reply::Integer(1));
+        assert_eq!(run(&lsm, &["GET", "1"]), Reply::Bulk(None));
+
+        assert!(matches!(run(&lsm, &["SET", "x", "1"]), Reply::Error(_)));
+        assert!(matches!(run(&lsm, &["SET", "1", "a,b"]), Reply::Error(_)));
+        assert!(matches!(run(&lsm, &["SET", "1", "1", "KEEPTTL"]), Reply::Error(_)));
+        assert!(matches!(run(&lsm, &["GET"]), Reply::Error(e) if e.contains("wrong number")));
+        assert!(matches!(run(&lsm, &["FLUSHALL"]), Reply::Error(e) if e.contains("unknown command")));
+    }
+
+    #[test]
+    fn test_connection() {
+        let lsm = Arc::new(LSMTree::open(&test_dir("resp_connection"), Options::default()).unwrap());
+        let server = RespServer::bind("127.0.0.1:0", Arc::clone(&lsm)).unwrap().spawn().unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+
+        // pipelined, with an inline command mixed in
+        let requests = [encode(&["SET", "7", "1,2"]), "GET 7\r\n".to_string(), encode(&["PING"]), encode(&["QUIT"])].concat();
+        stream.write_all(requests.as_bytes()).unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, "+OK\r\n$3\r\n1,2\r\n+PONG\r\n+OK\r\n");
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"*1\r\n+GET\r\n").unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        assert!(replies.starts_with("-ERR Protocol error"));
+
+        server.shutdown().unwrap();
+        // connection threads may still hold the tree for a moment, so it closes on drop
+        drop(lsm);
+    }
+}
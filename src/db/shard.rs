@@ -0,0 +1,242 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::thread;
+use crate::db::batch::{BatchOp, WriteBatch};
+use crate::db::bloom;
+use crate::db::error::LsmError;
+use crate::db::lsm::LSMTree;
+use crate::db::options::Options;
+use crate::db::platform;
+use crate::db::vector::Vector;
+
+pub const SHARDS_FILE: &str = "SHARDS";
+const SHARDS_MAGIC: [u8; 8] = *b"LSMSHRD1";
+
+// Keys hash-partitioned across independent trees in `shard_<i>` under one directory.
+// Each shard has its own writer, memtable and compactions, so writes to different
+// shards don't wait on each other. The shard count is fixed when the directory is
+// created, since changing it would move keys.
+//
+// A batch is atomic within each shard it touches but not across them: a crash can
+// leave one shard's part applied and another's not.
+pub struct ShardedTree {
+    directory: PathBuf,
+    shards: Vec<LSMTree>,
+}
+
+impl ShardedTree {
+    // Opens the shards of `directory`, creating `shards` of them if it holds none yet.
+    // Every shard gets `options`.
+    pub fn open(directory: &Path, shards: usize, options: Options) -> Result<ShardedTree, LsmError> {
+        if shards == 0 {
+            return Err(LsmError::InvalidArgument("a sharded tree needs at least one shard".to_string()));
+        }
+        std::fs::create_dir_all(directory)?;
+        match read_shard_count(directory) {
+            Ok(existing) if existing != shards => {
+                return Err(LsmError::InvalidArgument(format!("{} was created with {} shards, not {}", directory.display(), existing, shards)));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => write_shard_count(directory, shards)?,
+            Err(e) => return Err(e.into()),
+        }
+
+        // shards recover their logs independently, so they open side by side
+        let opened: Vec<Result<LSMTree, LsmError>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..shards)
+                .map(|i| {
+                    let options = options.clone();
+                    scope.spawn(move || LSMTree::open(&shard_path(directory, i), options))
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap_or_else(|_| Err(LsmError::Io(io::Error::other("shard open panicked"))))).collect()
+        });
+        let shards = opened.into_iter().collect::<Result<Vec<_>, _>>()?;
+        Ok(ShardedTree { directory: directory.to_path_buf(), shards })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn shards(&self) -> &[LSMTree] {
+        &self.shards
+    }
+
+    // The shard `key` lives in
+    pub fn shard_of(&self, key: u64) -> usize {
+        (bloom::mix(key) % self.shards.len() as u64) as usize
+    }
+
+    pub fn insert(&self, key: u64, value: Vector) -> Result<(), LsmError> {
+        self.shards[self.shard_of(key)].insert(key, value)
+    }
+
+    pub fn get(&self, key: u64) -> Result<Option<Vector>, LsmError> {
+        self.shards[self.shard_of(key)].get(key)
+    }
+
+    pub fn delete(&self, key: u64) -> Result<bool, LsmError> {
+        self.shards[self.shard_of(key)].delete(key)
+    }
+
+    pub fn merge(&self, key: u64, operand: Vec<u8>) -> Result<(), LsmError> {
+        self.shards[self.shard_of(key)].merge(key, operand)
+    }
+
+    // The range spans every shard, so each gets the delete
+    pub fn delete_range(&self, start: u64, end: u64) -> Result<(), LsmError> {
+        self.each_shard(|shard| shard.delete_range(start, end))
+    }
+
+    // Splits the batch by shard and writes the parts side by side
+    pub fn write(&self, batch: WriteBatch) -> Result<(), LsmError> {
+        let mut parts = vec![WriteBatch::new(); self.shards.len()];
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(key, _) | BatchOp::Delete(key) | BatchOp::Merge(key, _) => parts[self.shard_of(key)].ops.push(op),
+                BatchOp::DeleteRange(_) => {
+                    for part in parts.iter_mut() {
+                        part.ops.push(op.clone());
+                    }
+                }
+            }
+        }
+        let mut parts: Vec<(usize, WriteBatch)> = parts.into_iter().enumerate().filter(|(_, part)| !part.is_empty()).collect();
+        if parts.len() <= 1 {
+            return match parts.pop() {
+                Some((i, part)) => self.shards[i].write(part),
+                None => Ok(()),
+            };
+        }
+        let results: Vec<Result<(), LsmError>> = thread::scope(|scope| {
+            let handles: Vec<_> = parts.into_iter().map(|(i, part)| (&self.shards[i], part)).map(|(shard, part)| scope.spawn(move || shard.write(part))).collect();
+            handles.into_iter().map(join).collect()
+        });
+        results.into_iter().collect()
+    }
+
+    // Keys in order across every shard
+    pub fn range<R: RangeBounds<u64> + Clone>(&self, range: R) -> Result<Vec<(u64, Vector)>, LsmError> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            entries.extend(shard.range(range.clone())?);
+        }
+        // no key is in two shards
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        Ok(entries)
+    }
+
+    pub fn len(&self) -> Result<usize, LsmError> {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, LsmError> {
+        for shard in self.shards.iter() {
+            if !shard.is_empty()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn flush(&self) -> Result<(), LsmError> {
+        self.each_shard(|shard| shard.flush())
+    }
+
+    pub fn compact(&self) -> Result<(), LsmError> {
+        self.each_shard(|shard| shard.compact())
+    }
+
+    // Closes every shard, reporting the first that failed
+    pub fn close(self) -> Result<(), LsmError> {
+        let mut result = Ok(());
+        for shard in self.shards {
+            let closed = shard.close();
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        result
+    }
+
+    // Runs `f` on every shard at once, returning the first error
+    fn each_shard<F: Fn(&LSMTree) -> Result<(), LsmError> + Sync>(&self, f: F) -> Result<(), LsmError> {
+        let f = &f;
+        let results: Vec<Result<(), LsmError>> = thread::scope(|scope| {
+            let handles: Vec<_> = self.shards.iter().map(|shard| scope.spawn(move || f(shard))).collect();
+            handles.into_iter().map(join).collect()
+        });
+        results.into_iter().collect()
+    }
+}
+
+fn join(handle: thread::ScopedJoinHandle<'_, Result<(), LsmError>>) -> Result<(), LsmError> {
+    handle.join().unwrap_or_else(|_| Err(LsmError::Io(io::Error::other("shard thread panicked"))))
+}
+
+fn shard_path(directory: &Path, shard: usize) -> PathBuf {
+    directory.join(format!("shard_{}", shard))
+}
+
+fn read_shard_count(directory: &Path) -> io::Result<usize> {
+    let mut file = File::open(directory.join(SHARDS_FILE))?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if magic != SHARDS_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad magic number, not a shards file"));
+    }
+    Ok(file.read_u64::<LittleEndian>()? as usize)
+}
+
+// Written before any shard exists, so a directory with shards always says how many
+fn write_shard_count(directory: &Path, shards: usize) -> io::Result<()> {
+    let temp_path = directory.join(format!("{}.tmp", SHARDS_FILE));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&SHARDS_MAGIC)?;
+    file.write_u64::<LittleEndian>(shards as u64)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, directory.join(SHARDS_FILE))?;
+    platform::sync_dir(directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+
+    #[test]
+    fn test_sharded_tree() {
+        let path = test_dir("shard_sharded_tree");
+        let tree = ShardedTree::open(&path, 4, Options::default()).unwrap();
+        for key in 0..200 {
+            tree.insert(key, Vector::new(key, vec![key as f64])).unwrap();
+        }
+        // every shard holds part of the keys
+        assert!(tree.shards().iter().all(|shard| shard.len().unwrap() > 0));
+        assert_eq!(tree.len().unwrap(), 200);
+        assert_eq!(tree.get(7).unwrap().unwrap().data(), &vec![7.0]);
+        assert_eq!(tree.shards()[tree.shard_of(7)].get(7).unwrap().unwrap().data(), &vec![7.0]);
+
+        let mut batch = WriteBatch::new();
+        batch.delete_range(10, 20).delete(30).put(300, Vector::new(300, vec![300.0]));
+        tree.write(batch).unwrap();
+        tree.compact().unwrap();
+        let keys: Vec<u64> = tree.range(5..35).unwrap().into_iter().map(|(key, _)| key).collect();
+        let expected: Vec<u64> = (5..10).chain(20..30).chain(31..35).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.len().unwrap(), 190);
+        tree.close().unwrap();
+
+        let tree = ShardedTree::open(&path, 4, Options::default()).unwrap();
+        assert_eq!(tree.get(300).unwrap().unwrap().data(), &vec![300.0]);
+        assert!(tree.get(30).unwrap().is_none());
+        assert_eq!(tree.range(..).unwrap().len(), 190);
+        drop(tree);
+        // the keys would land in different shards
+        assert!(matches!(ShardedTree::open(&path, 3, Options::default()), Err(LsmError::InvalidArgument(_))));
+    }
+}
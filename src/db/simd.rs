@@ -0,0 +1,236 @@
+// Distance kernels for every search path. x86_64 uses AVX2 when the CPU has it,
+// aarch64 always has NEON, anything else gets the scalar loops. Slices of different
+// lengths are compared over the shorter one, like `zip`.
+
+#[cfg(target_arch = "aarch64")]
+use neon as fallback;
+#[cfg(not(target_arch = "aarch64"))]
+use scalar as fallback;
+
+macro_rules! dispatch {
+    ($kernel:ident, $a:expr, $b:expr) => {{
+        let n = $a.len().min($b.len());
+        let (a, b) = (&$a[..n], &$b[..n]);
+        #[cfg(target_arch = "x86_64")]
+        if std::arch::is_x86_feature_detected!("avx2") {
+            // checked just above
+            return unsafe { avx2::$kernel(a, b) };
+        }
+        fallback::$kernel(a, b)
+    }};
+}
+
+pub fn dot(a: &[f64], b: &[f64]) -> f64 {
+    dispatch!(dot_f64, a, b)
+}
+
+pub fn squared_l2(a: &[f64], b: &[f64]) -> f64 {
+    dispatch!(squared_l2_f64, a, b)
+}
+
+pub fn l2(a: &[f64], b: &[f64]) -> f64 {
+    squared_l2(a, b).sqrt()
+}
+
+// Cosine similarity, 0 if either vector is all zeros
+pub fn cosine(a: &[f64], b: &[f64]) -> f64 {
+    let norms = (dot(a, a) * dot(b, b)).sqrt();
+    if norms == 0.0 { 0.0 } else { dot(a, b) / norms }
+}
+
+pub fn dot_f32(a: &[f32], b: &[f32]) -> f32 {
+    dispatch!(dot_f32, a, b)
+}
+
+pub fn squared_l2_f32(a: &[f32], b: &[f32]) -> f32 {
+    dispatch!(squared_l2_f32, a, b)
+}
+
+pub fn l2_f32(a: &[f32], b: &[f32]) -> f32 {
+    squared_l2_f32(a, b).sqrt()
+}
+
+pub fn cosine_f32(a: &[f32], b: &[f32]) -> f32 {
+    let norms = (dot_f32(a, a) * dot_f32(b, b)).sqrt();
+    if norms == 0.0 { 0.0 } else { dot_f32(a, b) / norms }
+}
+
+// Reference loops, also used for the tails the vector kernels leave over
+mod scalar {
+    pub(super) fn dot_f64(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    pub(super) fn squared_l2_f64(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+    }
+
+    pub(super) fn dot_f32(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    pub(super) fn squared_l2_f32(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+    }
+}
+
+// Callers check for AVX2 and pass slices of equal length
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+    use super::scalar;
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn dot_f64(a: &[f64], b: &[f64]) -> f64 {
+        let split = a.len() / 4 * 4;
+        let mut sum = _mm256_setzero_pd();
+        for i in (0..split).step_by(4) {
+            let (x, y) = unsafe { (_mm256_loadu_pd(a.as_ptr().add(i)), _mm256_loadu_pd(b.as_ptr().add(i))) };
+            sum = _mm256_add_pd(sum, _mm256_mul_pd(x, y));
+        }
+        horizontal_sum_pd(sum) + scalar::dot_f64(&a[split..], &b[split..])
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn squared_l2_f64(a: &[f64], b: &[f64]) -> f64 {
+        let split = a.len() / 4 * 4;
+        let mut sum = _mm256_setzero_pd();
+        for i in (0..split).step_by(4) {
+            let (x, y) = unsafe { (_mm256_loadu_pd(a.as_ptr().add(i)), _mm256_loadu_pd(b.as_ptr().add(i))) };
+            let d = _mm256_sub_pd(x, y);
+            sum = _mm256_add_pd(sum, _mm256_mul_pd(d, d));
+        }
+        horizontal_sum_pd(sum) + scalar::squared_l2_f64(&a[split..], &b[split..])
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn dot_f32(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() / 8 * 8;
+        let mut sum = _mm256_setzero_ps();
+        for i in (0..split).step_by(8) {
+            let (x, y) = unsafe { (_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i))) };
+            sum = _mm256_add_ps(sum, _mm256_mul_ps(x, y));
+        }
+        horizontal_sum_ps(sum) + scalar::dot_f32(&a[split..], &b[split..])
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn squared_l2_f32(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() / 8 * 8;
+        let mut sum = _mm256_setzero_ps();
+        for i in (0..split).step_by(8) {
+            let (x, y) = unsafe { (_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i))) };
+            let d = _mm256_sub_ps(x, y);
+            sum = _mm256_add_ps(sum, _mm256_mul_ps(d, d));
+        }
+        horizontal_sum_ps(sum) + scalar::squared_l2_f32(&a[split..], &b[split..])
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn horizontal_sum_pd(v: __m256d) -> f64 {
+        let mut lanes = [0.0; 4];
+        unsafe { _mm256_storeu_pd(lanes.as_mut_ptr(), v) };
+        lanes.iter().sum()
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn horizontal_sum_ps(v: __m256) -> f32 {
+        let mut lanes = [0.0; 8];
+        unsafe { _mm256_storeu_ps(lanes.as_mut_ptr(), v) };
+        lanes.iter().sum()
+    }
+}
+
+// NEON is part of the aarch64 baseline, so these need no runtime check
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+    use super::scalar;
+
+    pub(super) fn dot_f64(a: &[f64], b: &[f64]) -> f64 {
+        let split = a.len() / 2 * 2;
+        unsafe {
+            let mut sum = vdupq_n_f64(0.0);
+            for i in (0..split).step_by(2) {
+                sum = vfmaq_f64(sum, vld1q_f64(a.as_ptr().add(i)), vld1q_f64(b.as_ptr().add(i)));
+            }
+            vaddvq_f64(sum) + scalar::dot_f64(&a[split..], &b[split..])
+        }
+    }
+
+    pub(super) fn squared_l2_f64(a: &[f64], b: &[f64]) -> f64 {
+        let split = a.len() / 2 * 2;
+        unsafe {
+            let mut sum = vdupq_n_f64(0.0);
+            for i in (0..split).step_by(2) {
+                let d = vsubq_f64(vld1q_f64(a.as_ptr().add(i)), vld1q_f64(b.as_ptr().add(i)));
+                sum = vfmaq_f64(sum, d, d);
+            }
+            vaddvq_f64(sum) + scalar::squared_l2_f64(&a[split..], &b[split..])
+        }
+    }
+
+    pub(super) fn dot_f32(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() / 4 * 4;
+        unsafe {
+            let mut sum = vdupq_n_f32(0.0);
+            for i in (0..split).step_by(4) {
+                sum = vfmaq_f32(sum, vld1q_f32(a.as_ptr().add(i)), vld1q_f32(b.as_ptr().add(i)));
+            }
+            vaddvq_f32(sum) + scalar::dot_f32(&a[split..], &b[split..])
+        }
+    }
+
+    pub(super) fn squared_l2_f32(a: &[f32], b: &[f32]) -> f32 {
+        let split = a.len() / 4 * 4;
+        unsafe {
+            let mut sum = vdupq_n_f32(0.0);
+            for i in (0..split).step_by(4) {
+                let d = vsubq_f32(vld1q_f32(a.as_ptr().add(i)), vld1q_f32(b.as_ptr().add(i)));
+                sum = vfmaq_f32(sum, d, d);
+            }
+            vaddvq_f32(sum) + scalar::squared_l2_f32(&a[split..], &b[split..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn close(x: f64, y: f64) -> bool {
+        (x - y).abs() <= 1e-9 * x.abs().max(y.abs()).max(1.0)
+    }
+
+    #[test]
+    fn test_matches_scalar() {
+        let mut rng = rand::rng();
+        // every length around the vector widths, so the tails are covered too
+        for n in 0..40 {
+            let a: Vec<f64> = (0..n).map(|_| rng.random_range(-1.0..1.0)).collect();
+            let b: Vec<f64> = (0..n).map(|_| rng.random_range(-1.0..1.0)).collect();
+            assert!(close(dot(&a, &b), scalar::dot_f64(&a, &b)), "n = {}", n);
+            assert!(close(squared_l2(&a, &b), scalar::squared_l2_f64(&a, &b)), "n = {}", n);
+
+            let a32: Vec<f32> = a.iter().map(|&x| x as f32).collect();
+            let b32: Vec<f32> = b.iter().map(|&x| x as f32).collect();
+            assert!((dot_f32(&a32, &b32) - scalar::dot_f32(&a32, &b32)).abs() < 1e-4, "n = {}", n);
+            assert!((squared_l2_f32(&a32, &b32) - scalar::squared_l2_f32(&a32, &b32)).abs() < 1e-4, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_kernels() {
+        assert_eq!(l2(&[0.0, 0.0], &[3.0, 4.0]), 5.0);
+        assert_eq!(l2_f32(&[0.0, 0.0], &[3.0, 4.0]), 5.0);
+        assert_eq!(dot(&[1.0, 2.0, 3.0, 4.0, 5.0], &[1.0; 5]), 15.0);
+        // the longer slice is cut to the shorter one
+        assert_eq!(dot(&[1.0, 2.0, 3.0], &[1.0, 1.0]), 3.0);
+
+        assert!(close(cosine(&[1.0, 0.0], &[0.0, 2.0]), 0.0));
+        assert!(close(cosine(&[1.0, 1.0], &[2.0, 2.0]), 1.0));
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+        assert!((cosine_f32(&[1.0, 0.0], &[-3.0, 0.0]) + 1.0).abs() < 1e-6);
+    }
+}
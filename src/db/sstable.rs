@@ -0,0 +1,2248 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap2::Mmap;
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, BTreeSet, HashMap, hash_map};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::db::bloom::{self, PrefixBloom};
+use crate::db::cache::BlockCache;
+use crate::db::checksum;
+use crate::db::codec::{self, Codec};
+use crate::db::entry::{self, PayloadCodec};
+use crate::db::index::pq::ProductQuantizer;
+use crate::db::error;
+use crate::db::manifest;
+use crate::db::options::Options;
+use crate::db::platform::{self, Access};
+use crate::db::search::Scorer;
+use crate::db::vector::{self, ElementType, Vector};
+
+pub const MAGIC: [u8; 8] = *b"LSMSSTBL";
+// 2 added the range tombstone block, 3 a flags byte in every entry header, 4 a checksum
+// in every entry header, 5 the top-level index of a partitioned index, 6 the column
+// blocks, 7 the point tombstone block, 8 prefix compressed index blocks and the entry
+// count of every index partition, 9 the product quantization codes, 10 the codec
+pub const FORMAT_VERSION: u32 = 10;
+
+// index offset, filter offset, range tombstone offset, top-level index offset, column
+// block offset, point tombstone offset, code offset, codec, format version, magic
+pub const FOOTER_SIZE: usize = 8 + 8 + 8 + 8 + 8 + 8 + 8 + 4 + 4 + MAGIC.len();
+const FOOTER_SIZE_V1: usize = 8 + 8 + 4 + MAGIC.len();
+const FOOTER_SIZE_V4: usize = 8 + 8 + 8 + 4 + MAGIC.len();
+const FOOTER_SIZE_V5: usize = 8 + 8 + 8 + 8 + 4 + MAGIC.len();
+const FOOTER_SIZE_V6: usize = 8 + 8 + 8 + 8 + 8 + 4 + MAGIC.len();
+const FOOTER_SIZE_V9: usize = 8 + 8 + 8 + 8 + 8 + 8 + 8 + 4 + MAGIC.len();
+const FOOTER_SIZE_V7: usize = 8 + 8 + 8 + 8 + 8 + 8 + 4 + MAGIC.len();
+// vector count, dimension, element type and padding at the start of a column block
+const COLUMN_HEADER_SIZE: usize = 4 + 4 + 1 + 7;
+// code count, bytes per code, codebook fingerprint and padding at the start of the codes
+const CODE_HEADER_SIZE: usize = 4 + 4 + 4 + 4;
+// key and offset of an index entry before version 8
+const INDEX_ENTRY_SIZE: usize = 8 + 8;
+// entry count and restart interval at the end of a prefix compressed index block
+const INDEX_TRAILER_SIZE: usize = 4 + 4;
+// first key, last key and offset of a partition, and from version 8 its entry count
+const PARTITION_ENTRY_SIZE: usize = 8 + 8 + 8 + 8;
+const PARTITION_ENTRY_SIZE_V7: usize = 8 + 8 + 8;
+const RANGE_TOMBSTONE_SIZE: usize = 8 + 8;
+// bytes at the start of a scan read in as soon as it is created, the kernel's readahead
+// takes it from there
+const PREFETCH_BYTES: usize = 4 * 1024 * 1024;
+
+// Clones share the mapping, the index and the pin, so an iterator or snapshot keeps a
+// table readable, and its file in place, after compaction has replaced it
+#[derive(Clone)]
+pub(crate) struct SSTable {
+    pub(crate) file_number: u64,
+    pub(crate) version: u32,
+    pub(crate) data: Arc<dyn TableReader>,
+    index: Index,
+    // where the data section ends and the index block starts
+    data_end: usize,
+    // deleted keys, hiding entries in this table and older ones
+    pub(crate) tombstones: Arc<BTreeSet<u64>>,
+    // deleted key ranges, hiding entries in older tables but not this one's
+    pub(crate) range_tombstones: Arc<Vec<Range<u64>>>,
+    // over the key prefixes, for tables written with `Options::prefix_bloom_bits` set
+    pub(crate) prefix_filter: Option<Arc<PrefixBloom>>,
+    // the tree's, for tables it reads lookups and scans from; compaction inputs go without
+    pub(crate) cache: Option<Arc<BlockCache>>,
+    // with `Options::paranoid_checks`, see `check_entry`
+    pub(crate) paranoid: bool,
+    // what the entries were written with, named by the footer
+    pub(crate) codec: &'static dyn Codec,
+    // with `Options::access_hints`, see `use_access_hints`
+    access_hints: bool,
+    // kept in `Options::cold_directory` rather than the directory
+    pub(crate) cold: bool,
+    // in the same sorted run as the table before it, see `compaction::runs`
+    pub(crate) continues_run: bool,
+    // the column blocks, empty for tables written without `Options::columnar_block_vectors`
+    columns: Range<usize>,
+    // the product quantization codes, empty for tables written without `Options::pq_codes`
+    codes: Range<usize>,
+    // the smallest and largest key with an entry, from the index when the table is opened
+    key_range: Option<(u64, u64)>,
+    pin: Arc<Pin>,
+}
+
+// Shared by every clone of a table, so the file stays until the last of them is dropped
+// once the table is obsolete, see `SSTable::retire`. A mapping or an open file outlives
+// its deletion on unix, but a table read through `Options::storage`, or one on Windows,
+// needs the file there to be read.
+#[derive(Default)]
+struct Pin {
+    // what deletes the file, set when the table is retired
+    retired: Mutex<Option<Deleter>>,
+}
+
+type Deleter = Box<dyn FnOnce() -> io::Result<()> + Send>;
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        // the last reader has no one to fail to, a file left behind is removed as
+        // obsolete when the tree is next opened
+        if let Some(delete) = self.retired.get_mut().unwrap().take() {
+            let _ = delete();
+        }
+    }
+}
+
+// Where a table's entries are by key. A table written with
+// `Options::index_partition_entries` set keeps its index entries in partitions, and only
+// the top-level index saying which keys each partition covers is read when the table is
+// opened. The partitions are read as lookups need them, through the block cache.
+#[derive(Clone, Debug, PartialEq)]
+enum Index {
+    Full(Arc<BTreeMap<u64, usize>>),
+    Partitioned { partitions: Arc<Vec<Partition>>, entries: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Partition {
+    first: u64,
+    last: u64,
+    // the partition's index entries in the file
+    start: usize,
+    end: usize,
+}
+
+// A partition as read: borrowed from the mapping, read into a buffer, or from the cache
+enum Block<'a> {
+    Read(Cow<'a, [u8]>),
+    Cached(Arc<[u8]>),
+}
+
+impl std::ops::Deref for Block<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Block::Read(block) => block,
+            Block::Cached(block) => block,
+        }
+    }
+}
+
+// A block of index entries, the whole index or one of its partitions, in key order.
+// Before version 8 an entry is its key and offset as u64s. From then on it is how many
+// leading bytes of its big endian key it shares with the key before it, the rest of the
+// key, and its offset less that key's offset as a varint. Every
+// `Options::index_restart_interval`th entry is a restart point, sharing nothing and
+// holding its whole offset, and the block ends with the position of each restart point,
+// the entry count and the interval, all u32s. Lookups binary search the keys of the
+// restart points and decode at most an interval of entries from there.
+#[derive(Clone, Copy)]
+struct IndexBlock<'a> {
+    entries: &'a [u8],
+    restarts: &'a [u8],
+    len: usize,
+    // 0 for the fixed size entries before version 8
+    interval: usize,
+}
+
+// How far a walk through an index block got: the next entry, and the key and offset of
+// the one before it, which the next entry's are relative to
+#[derive(Clone, Copy, Default)]
+struct IndexPosition {
+    i: usize,
+    at: usize,
+    key: u64,
+    offset: u64,
+}
+
+impl<'a> IndexBlock<'a> {
+    fn new(block: &'a [u8], version: u32) -> io::Result<IndexBlock<'a>> {
+        if version < 8 {
+            if !block.len().is_multiple_of(INDEX_ENTRY_SIZE) {
+                return Err(corruption(format!("index block length {} is not a multiple of {}", block.len(), INDEX_ENTRY_SIZE)));
+            }
+            return Ok(IndexBlock { entries: block, restarts: &[], len: block.len() / INDEX_ENTRY_SIZE, interval: 0 });
+        }
+        let malformed = || corruption(format!("malformed index block of {} bytes", block.len()));
+        let trailer = block.len().checked_sub(INDEX_TRAILER_SIZE).ok_or_else(malformed)?;
+        let len = u32::from_le_bytes(block[trailer..trailer + 4].try_into().unwrap()) as usize;
+        let interval = u32::from_le_bytes(block[trailer + 4..].try_into().unwrap()) as usize;
+        if interval == 0 {
+            return Err(malformed());
+        }
+        let restarts = trailer.checked_sub(len.div_ceil(interval) * 4).ok_or_else(malformed)?;
+        Ok(IndexBlock { entries: &block[..restarts], restarts: &block[restarts..trailer], len, interval })
+    }
+
+    fn restart_points(&self) -> usize {
+        match self.interval {
+            0 => self.len,
+            interval => self.len.div_ceil(interval),
+        }
+    }
+
+    // The position of the `r`th restart point, and the key of the entry there
+    fn restart(&self, r: usize) -> io::Result<(IndexPosition, u64)> {
+        let position = match self.interval {
+            0 => IndexPosition { i: r, at: r * INDEX_ENTRY_SIZE, key: 0, offset: 0 },
+            interval => {
+                let at = u32::from_le_bytes(self.restarts[r * 4..r * 4 + 4].try_into().unwrap()) as usize;
+                IndexPosition { i: r * interval, at, key: 0, offset: 0 }
+            }
+        };
+        Ok((position, self.step(position)?.key))
+    }
+
+    // Decodes the entry at `position`, returning the position after it
+    fn step(&self, position: IndexPosition) -> io::Result<IndexPosition> {
+        let truncated = || corruption(format!("index entry {} runs past the end of its block", position.i));
+        if self.interval == 0 {
+            let entry = self.entries.get(position.at..position.at + INDEX_ENTRY_SIZE).ok_or_else(truncated)?;
+            let (key, offset) = (u64::from_le_bytes(entry[..8].try_into().unwrap()), u64::from_le_bytes(entry[8..].try_into().unwrap()));
+            return Ok(IndexPosition { i: position.i + 1, at: position.at + INDEX_ENTRY_SIZE, key, offset });
+        }
+        let restart = position.i.is_multiple_of(self.interval);
+        let (&shared, rest) = self.entries.get(position.at..).and_then(|entry| entry.split_first()).ok_or_else(truncated)?;
+        let shared = shared as usize;
+        if shared >= 8 || (restart && shared != 0) {
+            return Err(corruption(format!("index entry {} shares {} bytes of its key", position.i, shared)));
+        }
+        let mut key = position.key.to_be_bytes();
+        key[shared..].copy_from_slice(rest.get(..8 - shared).ok_or_else(truncated)?);
+        let mut rest = &rest[8 - shared..];
+        let delta = entry::read_varint(&mut rest).map_err(|_| truncated())?;
+        let offset = match restart {
+            true => delta,
+            false => position.offset.checked_add(delta).ok_or_else(truncated)?,
+        };
+        Ok(IndexPosition { i: position.i + 1, at: self.entries.len() - rest.len(), key: u64::from_be_bytes(key), offset })
+    }
+
+    // The entry at `position`, moving it past the entry. None at the end of the block, and
+    // after an entry that couldn't be decoded.
+    fn next(&self, position: &mut IndexPosition) -> Option<io::Result<(u64, usize)>> {
+        if position.i >= self.len {
+            return None;
+        }
+        match self.step(*position) {
+            Ok(next) => {
+                *position = next;
+                Some(Ok((next.key, next.offset as usize)))
+            }
+            Err(e) => {
+                position.i = self.len;
+                Some(Err(e))
+            }
+        }
+    }
+
+    // The position of the first entry for which `pred` doesn't hold, which it has to for a
+    // prefix of the entries. The entry before it, if there is one, is the last it holds for.
+    fn seek(&self, pred: impl Fn(u64) -> bool) -> io::Result<IndexPosition> {
+        let (mut low, mut high) = (0, self.restart_points());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.restart(mid)?.1) { low = mid + 1 } else { high = mid }
+        }
+        // the entry sought is past the last restart point `pred` holds for, and no further
+        // than the next one
+        let Some(r) = low.checked_sub(1) else {
+            return Ok(IndexPosition::default());
+        };
+        let mut position = self.restart(r)?.0;
+        while position.i < self.len {
+            let next = self.step(position)?;
+            if !pred(next.key) {
+                break;
+            }
+            position = next;
+        }
+        Ok(position)
+    }
+
+    // The first and last key, None for an empty block
+    fn key_range(&self) -> io::Result<Option<(u64, u64)>> {
+        let first = self.next(&mut IndexPosition::default()).transpose()?;
+        let last = self.seek(|_| true)?;
+        Ok(first.map(|(first, _)| (first, last.key)))
+    }
+
+    fn entries(self) -> impl Iterator<Item = io::Result<(u64, usize)>> + 'a {
+        let mut position = IndexPosition::default();
+        std::iter::from_fn(move || self.next(&mut position))
+    }
+}
+
+// Appends the entries as an index block of the current version, with a restart point
+// every `interval` entries
+fn encode_index_block(out: &mut Vec<u8>, entries: &[(u64, usize)], interval: usize) {
+    let start = out.len();
+    let mut restarts = Vec::with_capacity(entries.len().div_ceil(interval));
+    let (mut previous_key, mut previous_offset) = (0u64, 0u64);
+    for (i, &(key, offset)) in entries.iter().enumerate() {
+        let offset = offset as u64;
+        let (shared, delta) = match i % interval {
+            0 => {
+                restarts.push((out.len() - start) as u32);
+                (0, offset)
+            }
+            _ => ((((previous_key ^ key).leading_zeros() / 8) as usize).min(7), offset - previous_offset),
+        };
+        out.push(shared as u8);
+        out.extend_from_slice(&key.to_be_bytes()[shared..]);
+        entry::write_varint(out, delta);
+        (previous_key, previous_offset) = (key, offset);
+    }
+    for restart in restarts {
+        out.extend_from_slice(&restart.to_le_bytes());
+    }
+    out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(interval as u32).to_le_bytes());
+}
+
+fn after_start(bound: Bound<u64>, key: u64) -> bool {
+    match bound {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+fn before_end(bound: Bound<u64>, key: u64) -> bool {
+    match bound {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+// A scan's readahead from `SSTable::scan_hint`, until it is dropped and the range goes
+// back to being read at random
+pub(crate) struct ScanHint {
+    data: Arc<dyn TableReader>,
+    range: Range<usize>,
+}
+
+impl Drop for ScanHint {
+    fn drop(&mut self) {
+        self.data.advise(self.range.clone(), Access::Random);
+    }
+}
+
+// The index entries of a table within bounds, in key order. A partition that can't be
+// read is an error in its place, and the entries of the next one follow.
+pub(crate) struct IndexEntries<'a> {
+    table: &'a SSTable,
+    bounds: (Bound<u64>, Bound<u64>),
+    inner: EntriesInner<'a>,
+}
+
+enum EntriesInner<'a> {
+    Full(std::collections::btree_map::Range<'a, u64, usize>),
+    Partitioned { partitions: std::slice::Iter<'a, Partition>, block: Option<(Block<'a>, IndexPosition)> },
+}
+
+impl Iterator for IndexEntries<'_> {
+    type Item = io::Result<(u64, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (partitions, block) = match &mut self.inner {
+            EntriesInner::Full(range) => return range.next().map(|(&key, &offset)| Ok((key, offset))),
+            EntriesInner::Partitioned { partitions, block } => (partitions, block),
+        };
+        let version = self.table.version;
+        loop {
+            if let Some((entries, position)) = block {
+                let entry = IndexBlock::new(entries, version).map(|index| index.next(position));
+                match entry {
+                    Ok(Some(Ok((key, _)))) if !before_end(self.bounds.1, key) => return None,
+                    Ok(Some(entry)) => return Some(entry.map_err(|e| self.table.locate(e, None))),
+                    Ok(None) => {}
+                    Err(e) => {
+                        *block = None;
+                        return Some(Err(self.table.locate(e, None)));
+                    }
+                }
+            }
+            let partition = partitions.next()?;
+            if !before_end(self.bounds.1, partition.first) {
+                return None;
+            }
+            let start = self.table.partition(partition).and_then(|entries| {
+                let start = IndexBlock::new(&entries, version)?.seek(|key| !after_start(self.bounds.0, key))?;
+                Ok((entries, start))
+            });
+            match start {
+                Ok(start) => *block = Some(start),
+                Err(e) => {
+                    *block = None;
+                    return Some(Err(self.table.locate(e, Some(partition.start))));
+                }
+            }
+        }
+    }
+}
+
+// How a table is written
+#[derive(Debug, Clone)]
+pub(crate) struct TableFormat {
+    // a prefix filter over the top `prefix_bits` bits of the keys with `bits_per_prefix`
+    // bits per distinct prefix, none for 0
+    pub(crate) prefix_bits: u32,
+    pub(crate) bits_per_prefix: usize,
+    pub(crate) element: ElementType,
+    // index entries per partition, 0 for a single index block
+    pub(crate) index_partition_entries: usize,
+    // entries from one restart point of an index block to the next, see `IndexBlock`
+    pub(crate) index_restart_interval: usize,
+    pub(crate) payload_codec: PayloadCodec,
+    pub(crate) codec: Arc<dyn Codec>,
+    // vectors per column block, 0 for none
+    pub(crate) column_block_vectors: usize,
+    // entries with the same data as an earlier entry share it, see `Options::dedup_vectors`
+    pub(crate) dedup_vectors: bool,
+    // codebooks every entry's code is stored with, none for no codes
+    pub(crate) quantizer: Option<Arc<ProductQuantizer>>,
+}
+
+impl TableFormat {
+    pub(crate) fn new(options: &Options, element: ElementType) -> TableFormat {
+        TableFormat {
+            prefix_bits: options.prefix_bloom_bits,
+            bits_per_prefix: options.bloom_bits_per_key,
+            element,
+            index_partition_entries: options.index_partition_entries,
+            index_restart_interval: options.index_restart_interval,
+            payload_codec: options.payload_codec,
+            codec: options.codec.clone(),
+            column_block_vectors: options.columnar_block_vectors,
+            dedup_vectors: options.dedup_vectors,
+            quantizer: None,
+        }
+    }
+}
+
+impl Default for TableFormat {
+    fn default() -> TableFormat {
+        TableFormat::new(&Options::default(), ElementType::F64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Footer {
+    pub(crate) index_offset: u64,
+    pub(crate) filter_offset: u64,
+    // equal to the footer start for version 1 tables, which have no range tombstones
+    pub(crate) range_tombstone_offset: u64,
+    // where the index entries end and the top-level index over their partitions starts,
+    // the filter offset for tables whose index isn't partitioned
+    pub(crate) top_index_offset: u64,
+    // where the range tombstones end and the point tombstones start, the column offset
+    // for tables before version 7
+    pub(crate) tombstone_offset: u64,
+    // where the point tombstones end, and the column blocks start from the next 8 byte
+    // boundary. The code offset for tables without them.
+    pub(crate) column_offset: u64,
+    // where the column blocks end, and the codes start from the next 8 byte boundary.
+    // The footer start for tables without them, and for tables before version 9.
+    pub(crate) code_offset: u64,
+    // the id of the `Codec` the entries were written with, raw before version 10
+    pub(crate) codec: u32,
+    pub(crate) version: u32,
+}
+
+fn footer_size(version: u32) -> usize {
+    match version {
+        10.. => FOOTER_SIZE,
+        9 => FOOTER_SIZE_V9,
+        7..=8 => FOOTER_SIZE_V7,
+        6 => FOOTER_SIZE_V6,
+        5 => FOOTER_SIZE_V5,
+        2..=4 => FOOTER_SIZE_V4,
+        _ => FOOTER_SIZE_V1,
+    }
+}
+
+impl Footer {
+    fn write<W: Write>(&self, buf: &mut W) -> io::Result<()> {
+        buf.write_u64::<LittleEndian>(self.index_offset)?;
+        buf.write_u64::<LittleEndian>(self.filter_offset)?;
+        buf.write_u64::<LittleEndian>(self.range_tombstone_offset)?;
+        buf.write_u64::<LittleEndian>(self.top_index_offset)?;
+        buf.write_u64::<LittleEndian>(self.column_offset)?;
+        buf.write_u64::<LittleEndian>(self.tombstone_offset)?;
+        buf.write_u64::<LittleEndian>(self.code_offset)?;
+        buf.write_u32::<LittleEndian>(self.codec)?;
+        buf.write_u32::<LittleEndian>(self.version)?;
+        buf.write_all(&MAGIC)
+    }
+
+    // Validates the trailing footer of a table file of `file_len` bytes, given at least
+    // its last FOOTER_SIZE bytes (or the whole file if it's shorter) in `data`.
+    // The footer size depends on the version, which sits at a fixed distance from the end.
+    pub(crate) fn read(data: &[u8], file_len: usize) -> io::Result<Footer> {
+        if file_len < FOOTER_SIZE_V1 || data.len() < FOOTER_SIZE_V1 {
+            return Err(corruption(format!("file is {} bytes, too short for a footer", file_len)));
+        }
+        let tail = &data[data.len() - 4 - MAGIC.len()..];
+        let version = u32::from_le_bytes(tail[..4].try_into().unwrap());
+        if tail[4..] != MAGIC {
+            return Err(corruption("bad magic number, not an sstable".to_string()));
+        }
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("unsupported sstable format version {}", version)));
+        }
+
+        let footer_size = footer_size(version);
+        if file_len < footer_size || data.len() < footer_size {
+            return Err(corruption(format!("file is {} bytes, too short for a version {} footer", file_len, version)));
+        }
+        let footer_start = file_len - footer_size;
+        let mut cursor = io::Cursor::new(&data[data.len() - footer_size..]);
+        let index_offset = cursor.read_u64::<LittleEndian>()?;
+        let filter_offset = cursor.read_u64::<LittleEndian>()?;
+        let range_tombstone_offset = if version == 1 { footer_start as u64 } else { cursor.read_u64::<LittleEndian>()? };
+        let top_index_offset = if version >= 5 { cursor.read_u64::<LittleEndian>()? } else { filter_offset };
+        let column_offset = if version >= 6 { cursor.read_u64::<LittleEndian>()? } else { footer_start as u64 };
+        let tombstone_offset = if version >= 7 { cursor.read_u64::<LittleEndian>()? } else { column_offset };
+        let code_offset = if version >= 9 { cursor.read_u64::<LittleEndian>()? } else { footer_start as u64 };
+        let codec = if version >= 10 { cursor.read_u32::<LittleEndian>()? } else { codec::RAW_ID };
+        if codec::by_id(codec).is_none() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("table written with unknown codec {}", codec)));
+        }
+
+        if index_offset > top_index_offset || top_index_offset > filter_offset || filter_offset > range_tombstone_offset || range_tombstone_offset > tombstone_offset
+            || tombstone_offset > column_offset || column_offset > code_offset || code_offset > footer_start as u64
+        {
+            return Err(corruption(format!("footer offsets out of bounds (index {}, top-level index {}, filter {}, range tombstones {}, tombstones {}, columns {}, codes {}, footer {})", index_offset, top_index_offset, filter_offset, range_tombstone_offset, tombstone_offset, column_offset, code_offset, footer_start)));
+        }
+
+        Ok(Footer { index_offset, filter_offset, range_tombstone_offset, top_index_offset, tombstone_offset, column_offset, code_offset, codec, version })
+    }
+
+    // every index entry, in all the partitions of a partitioned index
+    fn index_block(&self) -> Range<usize> {
+        self.index_offset as usize..self.top_index_offset as usize
+    }
+
+    fn top_index_block(&self) -> Range<usize> {
+        self.top_index_offset as usize..self.filter_offset as usize
+    }
+
+    fn filter_block(&self) -> Range<usize> {
+        self.filter_offset as usize..self.range_tombstone_offset as usize
+    }
+
+    fn range_tombstone_block(&self) -> Range<usize> {
+        self.range_tombstone_offset as usize..self.tombstone_offset as usize
+    }
+
+    fn tombstone_block(&self) -> Range<usize> {
+        self.tombstone_offset as usize..self.column_offset as usize
+    }
+
+    fn column_block(&self) -> Range<usize> {
+        (self.column_offset as usize).next_multiple_of(8).min(self.code_offset as usize)..self.code_offset as usize
+    }
+
+    fn code_block(&self, file_len: usize) -> Range<usize> {
+        let footer_start = file_len - footer_size(self.version);
+        (self.code_offset as usize).next_multiple_of(8).min(footer_start)..footer_start
+    }
+}
+
+// How tables are read. mmap serves reads from the page cache without a copy, but turns an
+// I/O error into SIGBUS and behaves badly on network filesystems; pread costs a system
+// call and a copy per access and reports errors as errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadPath {
+    // Mapping can fail where pread still works, for a file bigger than the address space
+    // left on a 32-bit target or under memory pressure, so such tables fall back to pread
+    #[default]
+    Mmap,
+    Pread,
+}
+
+// A table's bytes
+pub(crate) trait TableReader: Send + Sync {
+    fn len(&self) -> usize;
+
+    // `range` is within the table
+    fn read_within(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>>;
+
+    // Borrowed from a mapping, or read from the file
+    fn read(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(corruption(format!("read of {:?} is past the end of a {} byte table", range, self.len())));
+        }
+        self.read_within(range)
+    }
+
+    // How `range` is about to be read, for the page cache of a mapping to read ahead by
+    fn advise(&self, _range: Range<usize>, _access: Access) {}
+
+    #[cfg(test)]
+    fn is_mapped(&self) -> bool {
+        false
+    }
+}
+
+struct MmapReader(Mmap);
+
+impl TableReader for MmapReader {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn read_within(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>> {
+        Ok(Cow::Borrowed(&self.0[range]))
+    }
+
+    fn advise(&self, range: Range<usize>, access: Access) {
+        platform::advise(&self.0, range, access);
+    }
+
+    #[cfg(test)]
+    fn is_mapped(&self) -> bool {
+        true
+    }
+}
+
+struct PreadReader {
+    file: File,
+    len: usize,
+}
+
+impl PreadReader {
+    fn new(file: File) -> io::Result<PreadReader> {
+        let len = usize::try_from(file.metadata()?.len()).map_err(|_| io::Error::new(io::ErrorKind::FileTooLarge, "table is larger than the address space"))?;
+        Ok(PreadReader { file, len })
+    }
+}
+
+impl TableReader for PreadReader {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_within(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>> {
+        let mut buf = vec![0u8; range.len()];
+        platform::read_exact_at(&self.file, &mut buf, range.start as u64)?;
+        Ok(Cow::Owned(buf))
+    }
+}
+
+// Opens the file for `read_path`, also returning why it couldn't be mapped if it fell back
+// to pread
+fn open_reader(path: &Path, read_path: ReadPath) -> io::Result<(Arc<dyn TableReader>, Option<io::Error>)> {
+    let file = File::open(path)?;
+    match read_path {
+        ReadPath::Mmap => match unsafe { Mmap::map(&file) } {
+            Ok(mmap) => Ok((Arc::new(MmapReader(mmap)), None)),
+            Err(e) => Ok((Arc::new(PreadReader::new(file)?), Some(e))),
+        },
+        ReadPath::Pread => Ok((Arc::new(PreadReader::new(file)?), None)),
+    }
+}
+
+impl SSTable {
+    pub(crate) fn open(path: &Path, file_number: u64, read_path: ReadPath) -> io::Result<SSTable> {
+        Ok(SSTable::open_reporting(path, file_number, read_path)?.0)
+    }
+
+    // Like `open`, also returning the error if the table couldn't be mapped and is read
+    // with pread instead
+    pub(crate) fn open_reporting(path: &Path, file_number: u64, read_path: ReadPath) -> io::Result<(SSTable, Option<io::Error>)> {
+        let (data, map_error) = open_reader(path, read_path)?;
+        Ok((SSTable::from_data(data, file_number)?, map_error))
+    }
+
+    // Writes the table's bytes out to a new file, for copies that can't be hard linked
+    pub(crate) fn copy_to(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.data.read(0..self.data.len())?)?;
+        file.sync_all()
+    }
+
+    // A table read from `data`, with corruption found in its footer, index or filter
+    // reported against its file
+    pub(crate) fn from_data(data: Arc<dyn TableReader>, file_number: u64) -> io::Result<SSTable> {
+        SSTable::read_blocks(data, file_number).map_err(|e| error::locate(e, None, || manifest::table_file_name(file_number)))
+    }
+
+    fn read_blocks(data: Arc<dyn TableReader>, file_number: u64) -> io::Result<SSTable> {
+        let len = data.len();
+        let footer = Footer::read(&data.read(len.saturating_sub(FOOTER_SIZE)..len)?, len)?;
+        let index = match footer.top_index_block().is_empty() {
+            true => Index::Full(Arc::new(read_index(&data.read(footer.index_block())?, &footer)?)),
+            false => read_top_index(&data.read(footer.top_index_block())?, &footer)?,
+        };
+        let range_tombstones = read_range_tombstones(&data.read(footer.range_tombstone_block())?)?;
+        let tombstones = read_tombstones(&data.read(footer.tombstone_block())?)?;
+        let filter = data.read(footer.filter_block())?;
+        let prefix_filter = match filter.is_empty() {
+            true => None,
+            false => Some(Arc::new(PrefixBloom::decode(&filter)?)),
+        };
+        let key_range = match &index {
+            Index::Full(index) => index.first_key_value().zip(index.last_key_value()).map(|((&min, _), (&max, _))| (min, max)),
+            Index::Partitioned { partitions, .. } => partitions.first().zip(partitions.last()).map(|(first, last)| (first.first, last.last)),
+        };
+        Ok(SSTable {
+            file_number,
+            version: footer.version,
+            data,
+            index,
+            data_end: footer.index_offset as usize,
+            tombstones: Arc::new(tombstones),
+            range_tombstones: Arc::new(range_tombstones),
+            prefix_filter,
+            cache: None,
+            paranoid: false,
+            codec: codec::by_id(footer.codec).unwrap(),
+            access_hints: false,
+            cold: false,
+            continues_run: false,
+            columns: footer.column_block(),
+            codes: footer.code_block(len),
+            key_range,
+            pin: Arc::default(),
+        })
+    }
+
+    // Hands the file of a table that is no longer live to `delete`, run now if this is the
+    // last clone of the table, returning its error, or else by whichever iterator or
+    // snapshot drops the last one
+    pub(crate) fn retire(self, delete: impl FnOnce() -> io::Result<()> + Send + 'static) -> io::Result<()> {
+        *self.pin.retired.lock().unwrap() = Some(Box::new(delete));
+        let pin = self.pin.clone();
+        drop(self);
+        match Arc::try_unwrap(pin) {
+            Ok(mut pin) => pin.retired.get_mut().unwrap().take().map_or(Ok(()), |delete| delete()),
+            Err(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn has_columns(&self) -> bool {
+        !self.columns.is_empty()
+    }
+
+    // The column blocks, None for a table without them
+    pub(crate) fn column_blocks(&self) -> io::Result<Option<Cow<'_, [u8]>>> {
+        match self.columns.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(self.data.read(self.columns.clone())?)),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_codes(&self) -> bool {
+        !self.codes.is_empty()
+    }
+
+    // The product quantization codes, None for a table without them
+    pub(crate) fn codes(&self) -> io::Result<Option<Cow<'_, [u8]>>> {
+        match self.codes.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(self.data.read(self.codes.clone())?)),
+        }
+    }
+
+    // Lookups read a page or two anywhere in the table, so the mapping isn't read ahead
+    // around them. Scans ask for readahead with `scan_hint`.
+    pub(crate) fn use_access_hints(&mut self) {
+        self.access_hints = true;
+        self.data.advise(0..self.data.len(), Access::Random);
+    }
+
+    // Tells the page cache the entries in `bounds` are about to be read in order, and
+    // starts reading the first of them in. None without `use_access_hints`, or when
+    // there's nothing there to read.
+    pub(crate) fn scan_hint(&self, bounds: (Bound<u64>, Bound<u64>)) -> Option<ScanHint> {
+        if !self.access_hints {
+            return None;
+        }
+        let range = self.data_range(bounds).ok().filter(|range| !range.is_empty())?;
+        self.data.advise(range.clone(), Access::Sequential);
+        self.data.advise(range.start..range.end.min(range.start + PREFETCH_BYTES), Access::WillNeed);
+        Some(ScanHint { data: self.data.clone(), range })
+    }
+
+    // The bytes of the entries in `bounds`, from the first of them to the next entry after
+    pub(crate) fn data_range(&self, bounds: (Bound<u64>, Bound<u64>)) -> io::Result<Range<usize>> {
+        let Some((_, start)) = self.entries(bounds).next().transpose()? else {
+            return Ok(0..0);
+        };
+        let after = match bounds.1 {
+            Bound::Included(key) => Bound::Excluded(key),
+            Bound::Excluded(key) => Bound::Included(key),
+            Bound::Unbounded => return Ok(start..self.data_end),
+        };
+        let end = self.entries((after, Bound::Unbounded)).next().transpose()?.map_or(self.data_end, |(_, offset)| offset);
+        Ok(start..end)
+    }
+
+    // Every problem found reading the whole table: the index's order, the entries tiling
+    // the data section, each entry's checksum, decoding and key, and the prefix filter
+    // and range tombstones. Runs the paranoid checks whatever the table was opened with.
+    pub(crate) fn verify(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let checked = SSTable { paranoid: true, cache: None, ..self.clone() };
+        match self.raw_index() {
+            Ok(raw) => {
+                if raw.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                    errors.push(format!("table {}: index keys are not strictly ascending", self.file_number));
+                }
+                if raw.len() != self.len() {
+                    errors.push(format!("table {}: index holds {} entries for {} keys", self.file_number, raw.len(), self.len()));
+                }
+            }
+            Err(e) => errors.push(format!("table {}: {}", self.file_number, e)),
+        }
+        if let Index::Partitioned { partitions, .. } = &self.index {
+            for partition in partitions.iter() {
+                let keys = checked.partition(partition).and_then(|block| IndexBlock::new(&block, self.version)?.key_range());
+                match keys {
+                    Ok(Some(keys)) if keys != (partition.first, partition.last) => {
+                        errors.push(format!("table {}: index partition at offset {} holds keys {}..={}, not {}..={}", self.file_number, partition.start, keys.0, keys.1, partition.first, partition.last));
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => errors.push(format!("table {}: index partition at offset {} is empty", self.file_number, partition.start)),
+                    Err(e) => errors.push(e.to_string()),
+                }
+            }
+        }
+
+        // entries are written in key order, back to back
+        let mut expected = 0;
+        for entry in self.entries(..) {
+            let (key, offset) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    errors.push(e.to_string());
+                    continue;
+                }
+            };
+            if offset != expected {
+                errors.push(format!("table {} at offset {}: entry for key {} expected at offset {}", self.file_number, offset, key, expected));
+            }
+            if let Err(e) = checked.read_value(offset) {
+                errors.push(e.to_string());
+            }
+            match checked.entry_size(offset) {
+                Ok(size) => expected = offset + size,
+                Err(_) => expected = offset,
+            }
+            if let Err(e) = checked.may_contain_key(key) {
+                errors.push(e.to_string());
+            }
+        }
+        if expected != self.data_end {
+            errors.push(format!("table {}: {} bytes after the last entry", self.file_number, self.data_end - expected));
+        }
+        for range in self.range_tombstones.iter().filter(|range| range.start >= range.end) {
+            errors.push(format!("table {}: empty range tombstone {}..{}", self.file_number, range.start, range.end));
+        }
+        // codes only for keys with an entry, in key order
+        match self.codes() {
+            Ok(Some(section)) => match Codes::parse(&section) {
+                Ok(codes) => {
+                    let keys: Vec<u64> = (0..codes.len()).map(|i| codes.key(i)).collect();
+                    if keys.windows(2).any(|pair| pair[0] >= pair[1]) || !keys.iter().all(|&key| self.contains_key(key).unwrap_or(false)) {
+                        errors.push(format!("table {}: codes are not for the table's keys in order", self.file_number));
+                    }
+                }
+                Err(e) => errors.push(format!("table {}: {}", self.file_number, e)),
+            },
+            Ok(None) => {}
+            Err(e) => errors.push(format!("table {}: {}", self.file_number, e)),
+        }
+        errors
+    }
+
+    // The entries that pass the paranoid checks
+    pub(crate) fn salvage(&self) -> BTreeMap<u64, Vector> {
+        let checked = SSTable { paranoid: true, cache: None, ..self.clone() };
+        self.entries(..)
+            .flatten()
+            .filter_map(|(key, offset)| checked.read_value(offset).ok().filter(|(found, _)| *found == key))
+            .collect()
+    }
+
+    // Entries in the table
+    pub(crate) fn len(&self) -> usize {
+        match &self.index {
+            Index::Full(index) => index.len(),
+            Index::Partitioned { entries, .. } => *entries,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Approximate bytes of the index and the tombstones held in memory, for the accounting
+    // of `Options::memory_budget_bytes`. A partitioned index only keeps its top level.
+    pub(crate) fn index_bytes(&self) -> usize {
+        let index = match &self.index {
+            Index::Full(index) => index.len() * std::mem::size_of::<(u64, usize)>(),
+            Index::Partitioned { partitions, .. } => std::mem::size_of_val(partitions.as_slice()),
+        };
+        index + self.tombstones.len() * std::mem::size_of::<u64>() + std::mem::size_of_val(self.range_tombstones.as_slice())
+    }
+
+    pub(crate) fn filter_bytes(&self) -> usize {
+        self.prefix_filter.as_ref().map_or(0, |filter| filter.bytes())
+    }
+
+    // The smallest and largest key with an entry
+    pub(crate) fn key_range(&self) -> Option<(u64, u64)> {
+        self.key_range
+    }
+
+    // False if the key is outside the table's key range, so lookups can pass over the
+    // table without consulting its filter or index
+    pub(crate) fn may_hold_key(&self, key: u64) -> bool {
+        self.key_range.is_some_and(|(min, max)| (min..=max).contains(&key))
+    }
+
+    // The same for any key within `bounds`
+    pub(crate) fn may_hold_range(&self, bounds: (Bound<u64>, Bound<u64>)) -> bool {
+        let Some((min, max)) = self.key_range else {
+            return false;
+        };
+        let starts_past = match bounds.0 {
+            Bound::Included(start) => start > max,
+            Bound::Excluded(start) => start >= max,
+            Bound::Unbounded => false,
+        };
+        let ends_before = match bounds.1 {
+            Bound::Included(end) => end < min,
+            Bound::Excluded(end) => end <= min,
+            Bound::Unbounded => false,
+        };
+        !starts_past && !ends_before
+    }
+
+    // Where the entry for `key` starts, if the table has one
+    pub(crate) fn offset_of(&self, key: u64) -> io::Result<Option<usize>> {
+        self.index_offset_of(key).map_err(|e| self.locate(e, None))
+    }
+
+    fn index_offset_of(&self, key: u64) -> io::Result<Option<usize>> {
+        let partitions = match &self.index {
+            Index::Full(index) => return Ok(index.get(&key).copied()),
+            Index::Partitioned { partitions, .. } => partitions,
+        };
+        let i = partitions.partition_point(|p| p.last < key);
+        let Some(partition) = partitions.get(i).filter(|p| p.first <= key) else {
+            return Ok(None);
+        };
+        let block = self.partition(partition)?;
+        let index = IndexBlock::new(&block, self.version)?;
+        let mut position = index.seek(|k| k < key)?;
+        Ok(index.next(&mut position).transpose()?.filter(|&(found, _)| found == key).map(|(_, offset)| offset))
+    }
+
+    pub(crate) fn contains_key(&self, key: u64) -> io::Result<bool> {
+        Ok(self.offset_of(key)?.is_some())
+    }
+
+    // The smallest key with an entry within `bounds`, or the largest when `reverse`
+    pub(crate) fn first_key(&self, bounds: (Bound<u64>, Bound<u64>), reverse: bool) -> io::Result<Option<u64>> {
+        self.index_first_key(bounds, reverse).map_err(|e| self.locate(e, None))
+    }
+
+    fn index_first_key(&self, bounds: (Bound<u64>, Bound<u64>), reverse: bool) -> io::Result<Option<u64>> {
+        let partitions = match &self.index {
+            Index::Full(index) => {
+                let mut range = index.range(bounds);
+                return Ok(if reverse { range.next_back() } else { range.next() }.map(|(&key, _)| key));
+            }
+            Index::Partitioned { partitions, .. } => partitions,
+        };
+        // partitions don't overlap, so the first one reaching into the bounds holds the key if any does
+        let (partition, key) = if reverse {
+            let Some(partition) = partitions[..partitions.partition_point(|p| before_end(bounds.1, p.first))].last() else {
+                return Ok(None);
+            };
+            let block = self.partition(partition)?;
+            let position = IndexBlock::new(&block, self.version)?.seek(|k| before_end(bounds.1, k))?;
+            (partition, (position.i > 0).then_some(position.key))
+        } else {
+            let Some(partition) = partitions.get(partitions.partition_point(|p| !after_start(bounds.0, p.last))) else {
+                return Ok(None);
+            };
+            let block = self.partition(partition)?;
+            let index = IndexBlock::new(&block, self.version)?;
+            let mut position = index.seek(|k| !after_start(bounds.0, k))?;
+            (partition, index.next(&mut position).transpose()?.map(|(key, _)| key))
+        };
+        // the top-level index said the partition reaches into the bounds
+        let Some(key) = key else {
+            return Err(self.corrupt(partition.start, format!("index partition doesn't hold keys {}..={}", partition.first, partition.last)));
+        };
+        Ok((after_start(bounds.0, key) && before_end(bounds.1, key)).then_some(key))
+    }
+
+    // The index entries within `bounds` in ascending key order
+    pub(crate) fn entries<R: RangeBounds<u64>>(&self, bounds: R) -> IndexEntries<'_> {
+        let bounds = (bounds.start_bound().cloned(), bounds.end_bound().cloned());
+        let inner = match &self.index {
+            Index::Full(index) => EntriesInner::Full(index.range(bounds)),
+            Index::Partitioned { partitions, .. } => {
+                let first = partitions.partition_point(|p| !after_start(bounds.0, p.last));
+                EntriesInner::Partitioned { partitions: partitions[first..].iter(), block: None }
+            }
+        };
+        IndexEntries { table: self, bounds, inner }
+    }
+
+    // A partition's index entries, from the block cache if the table has one
+    fn partition(&self, partition: &Partition) -> io::Result<Block<'_>> {
+        let read = || self.data.read(partition.start..partition.end);
+        match &self.cache {
+            Some(cache) => Ok(Block::Cached(cache.get_or_read((self.file_number, partition.start), || read().map(Cow::into_owned))?)),
+            None => Ok(Block::Read(read()?)),
+        }
+    }
+
+    // The index entries as written, before `read_index` collected them into a map. The
+    // partitions of a version 8 index are blocks of their own, the ones before tile the
+    // index block with fixed size entries.
+    fn raw_index(&self) -> io::Result<Vec<(u64, usize)>> {
+        let len = self.data.len();
+        let footer = Footer::read(&self.data.read(len.saturating_sub(FOOTER_SIZE)..len)?, len)?;
+        let blocks = match &self.index {
+            Index::Partitioned { partitions, .. } if self.version >= 8 => partitions.iter().map(|p| p.start..p.end).collect(),
+            _ => vec![footer.index_block()],
+        };
+        let mut raw = Vec::new();
+        for block in blocks {
+            let block = self.data.read(block)?;
+            for entry in IndexBlock::new(&block, self.version)?.entries() {
+                raw.push(entry?);
+            }
+        }
+        Ok(raw)
+    }
+
+    // Whether the table has an entry, a point delete or a range delete within `range`
+    // An index partition that can't be read counts as overlapping.
+    pub(crate) fn overlaps(&self, range: &Range<u64>) -> bool {
+        !matches!(self.first_key((Bound::Included(range.start), Bound::Excluded(range.end)), false), Ok(None))
+            || self.tombstones.range(range.clone()).next().is_some()
+            || self.range_tombstones.iter().any(|r| r.start < range.end && range.start < r.end)
+    }
+
+    // False only if the table's prefix filter rules out every key with this key's prefix.
+    // Paranoid tables check a negative against the index, which it can't be in.
+    pub(crate) fn may_contain_key(&self, key: u64) -> io::Result<bool> {
+        let may_contain = self.prefix_filter.as_ref().is_none_or(|filter| filter.may_contain_key(key));
+        if self.paranoid && !may_contain && self.contains_key(key)? {
+            return Err(error::corruption_at(manifest::table_file_name(self.file_number), None, format!("prefix filter rules out key {}, which the index holds", key)));
+        }
+        Ok(may_contain)
+    }
+
+    // Whether the prefix filter let `key` through although no key of the table shares its
+    // prefix, for measuring the filter's false positive rate
+    pub(crate) fn is_false_positive(&self, key: u64) -> bool {
+        self.prefix_filter.as_ref().is_some_and(|filter| self.entries(bloom::prefix_range(key, filter.prefix_bits())).next().is_none())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_mapped(&self) -> bool {
+        self.data.is_mapped()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_partitioned(&self) -> bool {
+        matches!(self.index, Index::Partitioned { .. })
+    }
+
+    pub(crate) fn read_value(&self, offset: usize) -> io::Result<(u64, Vector)> {
+        self.with_entry(offset, |key, flags, serialized| Ok((key, self.codec.decode(flags, serialized)?)))
+    }
+
+    // Corruption at `offset` in this table's file
+    fn corrupt(&self, offset: usize, message: String) -> io::Error {
+        error::corruption_at(manifest::table_file_name(self.file_number), Some(offset as u64), message)
+    }
+
+    // `e` reported against this table's file, at `offset` if it came from there
+    fn locate(&self, e: io::Error, offset: Option<usize>) -> io::Error {
+        error::locate(e, offset.map(|offset| offset as u64), || manifest::table_file_name(self.file_number))
+    }
+
+    // Hands `f` the key, flags and serialized value of the entry at `offset` where they
+    // lie: borrowed from the mapping or the block cache, read into a buffer otherwise. An
+    // entry sharing another's data is handed over with that data in place.
+    // An entry that can't be decoded is reported at its offset.
+    pub(crate) fn with_entry<R>(&self, offset: usize, f: impl FnOnce(u64, u8, &[u8]) -> io::Result<R>) -> io::Result<R> {
+        self.with_raw_entry(offset, f).map_err(|e| self.locate(e, Some(offset)))
+    }
+
+    fn with_raw_entry<R>(&self, offset: usize, f: impl FnOnce(u64, u8, &[u8]) -> io::Result<R>) -> io::Result<R> {
+        let entry = self.raw_entry(offset)?;
+        let key = u64::from_le_bytes(entry[..8].try_into().unwrap());
+        let flags = if self.version >= 3 { entry[8] } else { entry::VALUE_TYPE_BSON };
+        let serialized = &entry[entry_header_size(self.version)..];
+        if flags & entry::SHARED_DATA != 0 {
+            let (flags, unshared) = self.unshare(offset, flags, serialized)?;
+            return f(key, flags, &unshared);
+        }
+        f(key, flags, serialized)
+    }
+
+    // The entry at `offset` as stored, header and all
+    fn raw_entry(&self, offset: usize) -> io::Result<Block<'_>> {
+        let read = || self.data.read(offset..offset + self.entry_size(offset)?);
+        let entry = match &self.cache {
+            Some(cache) => Block::Cached(cache.get_or_read((self.file_number, offset), || read().map(Cow::into_owned))?),
+            None => Block::Read(read()?),
+        };
+        self.check_entry(offset, &entry)?;
+        if entry.len() < entry_header_size(self.version) {
+            return Err(self.corrupt(offset, "entry is shorter than its header".to_string()));
+        }
+        let key = u64::from_le_bytes(entry[..8].try_into().unwrap());
+        if self.paranoid && self.offset_of(key)? != Some(offset) {
+            return Err(self.corrupt(offset, format!("entry for key {} isn't indexed there", key)));
+        }
+        Ok(entry)
+    }
+
+    // The serialized value of the shared entry at `offset` with the data of the earlier
+    // entry it points at in place
+    fn unshare(&self, offset: usize, flags: u8, serialized: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        let shared = entry::Dense::split(flags, serialized)?;
+        let owner = shared.owner.unwrap_or_default() as usize;
+        if owner >= offset {
+            return Err(self.corrupt(offset, format!("entry shares the data of offset {}, which isn't before it", owner)));
+        }
+        let owner_entry = self.raw_entry(owner)?;
+        let owner_flags = owner_entry[8];
+        if owner_flags & entry::VALUE_TYPE_MASK != entry::VALUE_TYPE_DENSE {
+            return Err(self.corrupt(offset, format!("entry shares the data of offset {}, which isn't a dense value", owner)));
+        }
+        let owner_dense = entry::Dense::split(owner_flags, &owner_entry[entry_header_size(self.version)..])?;
+        entry::unshare(flags, &shared, &owner_dense).map_err(|e| self.corrupt(offset, e.to_string()))
+    }
+
+    // Paranoid tables verify every entry they read against the checksum in its header,
+    // for the tables that have one
+    fn check_entry(&self, offset: usize, entry: &[u8]) -> io::Result<()> {
+        if !self.paranoid || self.version < 4 {
+            return Ok(());
+        }
+        let stored = u32::from_le_bytes(entry[13..17].try_into().unwrap());
+        if entry_checksum(&entry[..13], &entry[17..]) != stored {
+            return Err(self.corrupt(offset, "entry checksum mismatch".to_string()));
+        }
+        Ok(())
+    }
+
+    // Whether the entry at `offset` has expired by `now`, found without decoding the
+    // vector. Entries whose flags say they have no expiry aren't looked into at all.
+    pub(crate) fn is_expired(&self, offset: usize, now: u64) -> io::Result<bool> {
+        // tables from before entry flags say nothing, so any entry may expire
+        let flags = match self.version {
+            3.. => self.data.read(offset + 8..offset + 9)?[0],
+            _ => entry::VALUE_TYPE_BSON | entry::HAS_TTL,
+        };
+        if flags & entry::HAS_TTL == 0 {
+            return Ok(false);
+        }
+        let serialized = self.data.read(offset + entry_header_size(self.version)..offset + self.entry_size(offset)?)?;
+        Ok(entry::expires_at(flags, &serialized)?.is_some_and(|at| now >= at))
+    }
+
+    pub(crate) fn file_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    // Bytes the entry at `offset` takes on disk, read from its header without decoding it
+    pub(crate) fn entry_size(&self, offset: usize) -> io::Result<usize> {
+        let header_size = entry_header_size(self.version);
+        let len_at = offset + entry_len_offset(self.version);
+        let len = self.data.read(len_at..len_at + 4)?;
+        let size = header_size + u32::from_le_bytes(len[..].try_into().unwrap()) as usize;
+        if self.paranoid && offset + size > self.data_end {
+            return Err(self.corrupt(offset, format!("{} byte entry runs past the data section at {}", size, self.data_end)));
+        }
+        Ok(size)
+    }
+}
+
+// Writes the data entries, then the index block, the filter block, the range and point
+// tombstone blocks and the footer, as `format` says. The index block ends with the
+// top-level index when it is partitioned, and the filter block is empty without a
+// prefix filter.
+pub(crate) fn write_table<'a, W, I, V>(buf: &mut W, entries: I, tombstones: &BTreeSet<u64>, range_tombstones: &[Range<u64>], format: &TableFormat) -> io::Result<BTreeMap<u64, usize>>
+where
+    W: Write + Seek,
+    I: IntoIterator<Item = (&'a u64, V)>,
+    V: Borrow<Vector>,
+{
+    let mut index = BTreeMap::<u64, usize>::new();
+    let mut columns = ColumnWriter::new(format);
+    let mut codes = CodeWriter::new(format);
+    // the entry first written with each data, by the data's bits
+    let mut owners = HashMap::<Vec<u64>, u64>::new();
+    let mut offset = buf.stream_position()?;
+    for (&key, value) in entries {
+        let value = value.borrow();
+        // sharing pays off once the data is longer than the offset written instead
+        match format.dedup_vectors && format.codec.shares_data() && format.element.data_len(value.data().len()) > 8 {
+            true => match owners.entry(content_hash(value.data())) {
+                hash_map::Entry::Occupied(owner) => {
+                    let (flags, serialized) = entry::encode_shared(value, *owner.get(), format.element, format.payload_codec)?;
+                    write_encoded(buf, key, flags, &serialized)?;
+                }
+                hash_map::Entry::Vacant(vacant) => {
+                    vacant.insert(offset);
+                    write_entry_at(buf, offset as usize, key, value, format)?;
+                }
+            },
+            false => write_entry_at(buf, offset as usize, key, value, format)?,
+        }
+        index.insert(key, offset as usize);
+        columns.add(key, value);
+        codes.add(key, value);
+        offset = buf.stream_position()?;
+    }
+
+    write_blocks(buf, &index, offset, tombstones, range_tombstones, &columns.finish(), &codes.finish(), format)?;
+    Ok(index)
+}
+
+// Everything after the data entries, which end at `index_offset`
+#[allow(clippy::too_many_arguments)]
+fn write_blocks<W: Write + Seek>(buf: &mut W, index: &BTreeMap<u64, usize>, index_offset: u64, tombstones: &BTreeSet<u64>, range_tombstones: &[Range<u64>], columns: &[u8], codes: &[u8], format: &TableFormat) -> io::Result<()> {
+    // the partitions are blocks of runs of the entries, a small index isn't split
+    let entries: Vec<(u64, usize)> = index.iter().map(|(&key, &offset)| (key, offset)).collect();
+    let per_partition = format.index_partition_entries;
+    let mut block = Vec::new();
+    let mut partitions = Vec::new();
+    if per_partition > 0 && entries.len() > per_partition {
+        for run in entries.chunks(per_partition) {
+            partitions.push((run[0].0, run[run.len() - 1].0, index_offset + block.len() as u64, run.len()));
+            encode_index_block(&mut block, run, format.index_restart_interval);
+        }
+    } else {
+        encode_index_block(&mut block, &entries, format.index_restart_interval);
+    }
+    buf.write_all(&block)?;
+    let top_index_offset = buf.stream_position()?;
+    for (first, last, start, len) in partitions {
+        buf.write_u64::<LittleEndian>(first)?;
+        buf.write_u64::<LittleEndian>(last)?;
+        buf.write_u64::<LittleEndian>(start)?;
+        buf.write_u64::<LittleEndian>(len as u64)?;
+    }
+
+    let filter_offset = buf.stream_position()?;
+    if format.prefix_bits > 0 && !index.is_empty() {
+        let mut block = Vec::new();
+        PrefixBloom::build(format.prefix_bits, format.bits_per_prefix, index.keys().copied()).encode(&mut block)?;
+        buf.write_all(&block)?;
+    }
+
+    let range_tombstone_offset = buf.stream_position()?;
+    for range in range_tombstones.iter() {
+        buf.write_u64::<LittleEndian>(range.start)?;
+        buf.write_u64::<LittleEndian>(range.end)?;
+    }
+    let tombstone_offset = buf.stream_position()?;
+    for &key in tombstones.iter() {
+        buf.write_u64::<LittleEndian>(key)?;
+    }
+
+    // the blocks start on an 8 byte boundary, so a mapped table lends out the columns
+    // as slices
+    let column_offset = buf.stream_position()?;
+    if !columns.is_empty() {
+        buf.write_all(&[0; 8][..(8 - column_offset as usize % 8) % 8])?;
+        buf.write_all(columns)?;
+    }
+    let code_offset = buf.stream_position()?;
+    if !codes.is_empty() {
+        buf.write_all(&[0; 8][..(8 - code_offset as usize % 8) % 8])?;
+        buf.write_all(codes)?;
+    }
+
+    Footer { index_offset, filter_offset, range_tombstone_offset, top_index_offset, tombstone_offset, column_offset, code_offset, codec: format.codec.id(), version: FORMAT_VERSION }.write(buf)
+}
+
+// Builds the column blocks of a table: runs of up to `TableFormat::column_block_vectors`
+// consecutive entries of one dimension, each the vector count (u32), the dimension (u32),
+// the element type (u8) and padding to 16 bytes, then the keys, then the expiry times
+// (u64::MAX for none), then the data one dimension after another, every entry's first
+// element first. f64 trees store f64 elements and the rest f32, which holds their values
+// exactly. Blocks are padded to 8 bytes.
+struct ColumnWriter {
+    per_block: usize,
+    element: ElementType,
+    keys: Vec<u64>,
+    expiries: Vec<u64>,
+    // the pending entries' data, one after another
+    rows: Vec<f64>,
+    dimension: usize,
+    out: Vec<u8>,
+}
+
+impl ColumnWriter {
+    fn new(format: &TableFormat) -> ColumnWriter {
+        let element = match format.element {
+            ElementType::F64 => ElementType::F64,
+            _ => ElementType::F32,
+        };
+        ColumnWriter { per_block: format.column_block_vectors, element, keys: Vec::new(), expiries: Vec::new(), rows: Vec::new(), dimension: 0, out: Vec::new() }
+    }
+
+    fn add(&mut self, key: u64, value: &Vector) {
+        if self.per_block == 0 {
+            return;
+        }
+        if !self.keys.is_empty() && (self.keys.len() == self.per_block || value.data().len() != self.dimension) {
+            self.flush_block();
+        }
+        self.dimension = value.data().len();
+        self.keys.push(key);
+        self.expiries.push(value.expires_at().unwrap_or(u64::MAX));
+        self.rows.extend_from_slice(value.data());
+    }
+
+    fn flush_block(&mut self) {
+        let count = self.keys.len();
+        self.out.extend_from_slice(&(count as u32).to_le_bytes());
+        self.out.extend_from_slice(&(self.dimension as u32).to_le_bytes());
+        self.out.push(self.element.to_u8());
+        self.out.extend_from_slice(&[0; COLUMN_HEADER_SIZE - 9]);
+        for key in self.keys.drain(..) {
+            self.out.extend_from_slice(&key.to_le_bytes());
+        }
+        for expiry in self.expiries.drain(..) {
+            self.out.extend_from_slice(&expiry.to_le_bytes());
+        }
+        for d in 0..self.dimension {
+            for i in 0..count {
+                let x = self.rows[i * self.dimension + d];
+                match self.element {
+                    ElementType::F64 => self.out.extend_from_slice(&x.to_le_bytes()),
+                    _ => self.out.extend_from_slice(&(x as f32).to_le_bytes()),
+                }
+            }
+        }
+        self.rows.clear();
+        self.out.resize(self.out.len().next_multiple_of(8), 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if !self.keys.is_empty() {
+            self.flush_block();
+        }
+        self.out
+    }
+}
+
+// Builds the code section of a table, for `LSMTree::pq_search`: the code count (u32), the
+// bytes per code (u32), the fingerprint of the codebooks (u32) and padding to 16 bytes,
+// then the keys, then the codes. An entry the codebooks can't encode, of another
+// dimension, is left out.
+struct CodeWriter {
+    quantizer: Option<Arc<ProductQuantizer>>,
+    keys: Vec<u64>,
+    codes: Vec<u8>,
+}
+
+impl CodeWriter {
+    fn new(format: &TableFormat) -> CodeWriter {
+        CodeWriter { quantizer: format.quantizer.clone(), keys: Vec::new(), codes: Vec::new() }
+    }
+
+    fn add(&mut self, key: u64, value: &Vector) {
+        if let Some(code) = self.quantizer.as_ref().and_then(|quantizer| quantizer.encode(value.data())) {
+            self.keys.push(key);
+            self.codes.extend_from_slice(&code);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let Some(quantizer) = self.quantizer else { return Vec::new() };
+        let mut out = Vec::with_capacity(CODE_HEADER_SIZE + self.keys.len() * 8 + self.codes.len());
+        out.extend_from_slice(&(self.keys.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(quantizer.codebooks().len() as u32).to_le_bytes());
+        out.extend_from_slice(&quantizer.fingerprint().to_le_bytes());
+        out.extend_from_slice(&[0; CODE_HEADER_SIZE - 12]);
+        for key in self.keys {
+            out.extend_from_slice(&key.to_le_bytes());
+        }
+        out.extend_from_slice(&self.codes);
+        out
+    }
+}
+
+// `CodeWriter`'s section, borrowed from the table
+pub(crate) struct Codes<'a> {
+    pub(crate) fingerprint: u32,
+    width: usize,
+    keys: &'a [u8],
+    codes: &'a [u8],
+}
+
+impl<'a> Codes<'a> {
+    pub(crate) fn parse(section: &'a [u8]) -> io::Result<Codes<'a>> {
+        if section.len() < CODE_HEADER_SIZE {
+            return Err(corruption(format!("code section of {} bytes is too short for its header", section.len())));
+        }
+        let count = u32::from_le_bytes(section[..4].try_into().unwrap()) as usize;
+        let width = u32::from_le_bytes(section[4..8].try_into().unwrap()) as usize;
+        let fingerprint = u32::from_le_bytes(section[8..12].try_into().unwrap());
+        let keys_end = CODE_HEADER_SIZE + count * 8;
+        if count.checked_mul(width).and_then(|n| n.checked_add(keys_end)) != Some(section.len()) {
+            return Err(corruption(format!("code section of {} bytes doesn't hold {} codes of {} bytes", section.len(), count, width)));
+        }
+        Ok(Codes { fingerprint, width, keys: &section[CODE_HEADER_SIZE..keys_end], codes: &section[keys_end..] })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len() / 8
+    }
+
+    pub(crate) fn key(&self, i: usize) -> u64 {
+        u64::from_le_bytes(self.keys[i * 8..i * 8 + 8].try_into().unwrap())
+    }
+
+    pub(crate) fn code(&self, i: usize) -> &'a [u8] {
+        &self.codes[i * self.width..(i + 1) * self.width]
+    }
+}
+
+// One block of `ColumnWriter`'s, borrowed from the table
+pub(crate) struct ColumnBlock<'a> {
+    pub(crate) dimension: usize,
+    element: ElementType,
+    keys: &'a [u8],
+    expiries: &'a [u8],
+    columns: &'a [u8],
+}
+
+impl ColumnBlock<'_> {
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len() / 8
+    }
+
+    pub(crate) fn key(&self, i: usize) -> u64 {
+        u64::from_le_bytes(self.keys[i * 8..i * 8 + 8].try_into().unwrap())
+    }
+
+    pub(crate) fn expires_at(&self, i: usize) -> Option<u64> {
+        let expiry = u64::from_le_bytes(self.expiries[i * 8..i * 8 + 8].try_into().unwrap());
+        (expiry != u64::MAX).then_some(expiry)
+    }
+
+    // Every entry's distance to `query`, which has the block's dimension. The columns
+    // are scanned in place where the table is mapped and aligned, and copied otherwise.
+    pub(crate) fn distances(&self, scorer: &Scorer, query: &[f64]) -> Vec<f64> {
+        match self.element {
+            ElementType::F64 => match vector::cast::<f64>(self.columns) {
+                Some(columns) => scorer.column_distances(query, columns, self.len()),
+                None => scorer.column_distances(query, &decode_column(self.columns, 8, |b| f64::from_le_bytes(b.try_into().unwrap())), self.len()),
+            },
+            _ => match vector::cast::<f32>(self.columns) {
+                Some(columns) => scorer.column_distances(query, columns, self.len()),
+                None => scorer.column_distances(query, &decode_column(self.columns, 4, |b| f32::from_le_bytes(b.try_into().unwrap())), self.len()),
+            },
+        }
+    }
+}
+
+fn decode_column<T>(bytes: &[u8], size: usize, decode: impl Fn(&[u8]) -> T) -> Vec<T> {
+    bytes.chunks_exact(size).map(decode).collect()
+}
+
+// The blocks of a table's column section, in key order
+pub(crate) fn column_blocks(mut section: &[u8]) -> impl Iterator<Item = io::Result<ColumnBlock<'_>>> {
+    std::iter::from_fn(move || {
+        if section.is_empty() {
+            return None;
+        }
+        let block = split_column_block(section);
+        match block {
+            Ok((block, rest)) => {
+                section = rest;
+                Some(Ok(block))
+            }
+            Err(e) => {
+                section = &[];
+                Some(Err(e))
+            }
+        }
+    })
+}
+
+fn split_column_block(section: &[u8]) -> io::Result<(ColumnBlock<'_>, &[u8])> {
+    if section.len() < COLUMN_HEADER_SIZE {
+        return Err(corruption(format!("column block of {} bytes is too short for its header", section.len())));
+    }
+    let count = u32::from_le_bytes(section[..4].try_into().unwrap()) as usize;
+    let dimension = u32::from_le_bytes(section[4..8].try_into().unwrap()) as usize;
+    let element = ElementType::from_u8(section[8])?;
+    if !matches!(element, ElementType::F64 | ElementType::F32) {
+        return Err(corruption(format!("column block holds {:?} elements", element)));
+    }
+    let columns_len = count.checked_mul(dimension).and_then(|n| n.checked_mul(element.bits() / 8));
+    let len = columns_len.and_then(|n| n.checked_add(COLUMN_HEADER_SIZE + count * 16)).map(|n| n.next_multiple_of(8));
+    let Some(len) = len.filter(|&len| len <= section.len()) else {
+        return Err(corruption(format!("column block of {} vectors of {} dimensions overruns its section of {} bytes", count, dimension, section.len())));
+    };
+    let keys_end = COLUMN_HEADER_SIZE + count * 8;
+    let expiries_end = keys_end + count * 8;
+    let block = ColumnBlock {
+        dimension,
+        element,
+        keys: &section[COLUMN_HEADER_SIZE..keys_end],
+        expiries: &section[keys_end..expiries_end],
+        columns: &section[expiries_end..expiries_end + columns_len.unwrap()],
+    };
+    Ok((block, &section[len..]))
+}
+
+// Builds a table file outside any tree from entries added in ascending key order, for
+// `LSMTree::ingest_external_file`. The file is complete once `finish` returns.
+pub struct SSTableWriter {
+    out: BufWriter<File>,
+    index: BTreeMap<u64, usize>,
+    offset: usize,
+    format: TableFormat,
+    scratch: Vec<u8>,
+}
+
+impl SSTableWriter {
+    pub fn create(path: &Path) -> io::Result<SSTableWriter> {
+        SSTableWriter::with_prefix_bloom_bits(path, 0)
+    }
+
+    // With a prefix filter over the top `prefix_bits` bits of the keys, as
+    // `Options::prefix_bloom_bits` gives the tree's own tables
+    pub fn with_prefix_bloom_bits(path: &Path, prefix_bits: u32) -> io::Result<SSTableWriter> {
+        let out = BufWriter::new(File::create(path)?);
+        let format = TableFormat { prefix_bits, ..TableFormat::new(&Options::default(), ElementType::F64) };
+        Ok(SSTableWriter { out, index: BTreeMap::new(), offset: 0, format, scratch: Vec::new() })
+    }
+
+    // Bits per distinct prefix in the prefix filter, like `Options::bloom_bits_per_key`
+    pub fn set_bloom_bits_per_key(&mut self, bits: usize) -> io::Result<()> {
+        if bits == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a prefix filter needs at least one bit per key"));
+        }
+        self.format.bits_per_prefix = bits;
+        Ok(())
+    }
+
+    // Splits the index into partitions of this many entries, like
+    // `Options::index_partition_entries`
+    pub fn set_index_partition_entries(&mut self, entries: usize) {
+        self.format.index_partition_entries = entries;
+    }
+
+    // How the data of entries added from now on is stored, f64 unless set. A tree
+    // storing f32 only ingests data that f32 holds exactly.
+    pub fn set_element_type(&mut self, element: ElementType) {
+        self.format.element = element;
+    }
+
+    pub fn add(&mut self, key: u64, value: &Vector) -> io::Result<()> {
+        if let Some((&last, _)) = self.index.last_key_value() && key <= last {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("key {} added after key {}, keys must ascend", key, last)));
+        }
+        self.scratch.clear();
+        write_entry_at(&mut self.scratch, self.offset, key, value, &self.format)?;
+        self.out.write_all(&self.scratch)?;
+        self.index.insert(key, self.offset);
+        self.offset += self.scratch.len();
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // Writes the index, filter and footer and syncs the file, returning the entry count
+    pub fn finish(mut self) -> io::Result<usize> {
+        write_blocks(&mut self.out, &self.index, self.offset as u64, &BTreeSet::new(), &[], &[], &[], &self.format)?;
+        self.out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(self.index.len())
+    }
+}
+
+// key, flags (from version 3), value length, checksum (from version 4)
+fn entry_header_size(version: u32) -> usize {
+    match version {
+        4.. => 8 + 1 + 4 + 4,
+        3 => 8 + 1 + 4,
+        _ => 8 + 4,
+    }
+}
+
+fn entry_len_offset(version: u32) -> usize {
+    if version >= 3 { 8 + 1 } else { 8 }
+}
+
+// Over the header before the checksum, then the value
+fn entry_checksum(header: &[u8], value: &[u8]) -> u32 {
+    checksum::extend(checksum::crc32(header), value)
+}
+
+pub(crate) fn write_entry<W: Write>(buf: &mut W, key: u64, value: &Vector) -> io::Result<()> {
+    write_entry_at(buf, 0, key, value, &TableFormat::default())
+}
+
+// For an entry starting at `offset` of a table, see `Codec::encode`
+fn write_entry_at<W: Write>(buf: &mut W, offset: usize, key: u64, value: &Vector, format: &TableFormat) -> io::Result<()> {
+    let (flags, serialized) = format.codec.encode(value, offset + entry_header_size(FORMAT_VERSION), format.element, format.payload_codec)?;
+    write_encoded(buf, key, flags, &serialized)
+}
+
+fn write_encoded<W: Write>(buf: &mut W, key: u64, flags: u8, serialized: &[u8]) -> io::Result<()> {
+    let mut header = Vec::with_capacity(entry_header_size(FORMAT_VERSION));
+    header.write_u64::<LittleEndian>(key)?;
+    header.write_u8(flags)?;
+    header.write_u32::<LittleEndian>(serialized.len() as u32)?;
+    let crc = entry_checksum(&header, serialized);
+    header.write_u32::<LittleEndian>(crc)?;
+    buf.write_all(&header)?;
+    buf.write_all(serialized)
+}
+
+// What entries with the same data share, whatever their keys, ids and metadata. Bits
+// rather than values, so -0.0 and NaNs are only shared with themselves.
+fn content_hash(data: &[f64]) -> Vec<u64> {
+    data.iter().map(|x| x.to_bits()).collect()
+}
+
+// Reads an entry written by `write_entry`, verifying its checksum
+pub(crate) fn read_entry<R: Read>(buf: &mut R) -> io::Result<(u64, Vector)> {
+    let mut header = [0u8; 17];
+    buf.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+    let mut serialized = vec![0u8; len];
+    buf.read_exact(&mut serialized)?;
+    if entry_checksum(&header[..13], &serialized) != u32::from_le_bytes(header[13..].try_into().unwrap()) {
+        return Err(corruption("entry checksum mismatch".to_string()));
+    }
+    Ok((u64::from_le_bytes(header[..8].try_into().unwrap()), entry::decode(header[8], &serialized)?))
+}
+
+fn read_index(block: &[u8], footer: &Footer) -> io::Result<BTreeMap<u64, usize>> {
+    let mut index = BTreeMap::new();
+    for entry in IndexBlock::new(block, footer.version)?.entries() {
+        let (key, offset) = entry?;
+        if offset as u64 >= footer.index_offset {
+            return Err(corruption(format!("index entry for key {} points past the data section", key)));
+        }
+        index.insert(key, offset);
+    }
+
+    Ok(index)
+}
+
+// The top-level index of a partitioned index. Partitions have to tile the index entries
+// in order and cover ascending, disjoint key ranges.
+fn read_top_index(block: &[u8], footer: &Footer) -> io::Result<Index> {
+    let entry_size = if footer.version >= 8 { PARTITION_ENTRY_SIZE } else { PARTITION_ENTRY_SIZE_V7 };
+    if !block.len().is_multiple_of(entry_size) {
+        return Err(corruption(format!("top-level index length {} is not a multiple of {}", block.len(), entry_size)));
+    }
+    let mut cursor = io::Cursor::new(block);
+    let mut partitions: Vec<Partition> = Vec::with_capacity(block.len() / entry_size);
+    // before version 8 the entry count follows from the size of the index
+    let mut entries = match footer.version {
+        8.. => 0,
+        _ => footer.index_block().len() / INDEX_ENTRY_SIZE,
+    };
+    for _ in 0..block.len() / entry_size {
+        let first = cursor.read_u64::<LittleEndian>()?;
+        let last = cursor.read_u64::<LittleEndian>()?;
+        let start = cursor.read_u64::<LittleEndian>()? as usize;
+        if footer.version >= 8 {
+            entries += cursor.read_u64::<LittleEndian>()? as usize;
+        }
+        if let Some(previous) = partitions.last_mut() {
+            previous.end = start;
+        }
+        partitions.push(Partition { first, last, start, end: footer.top_index_offset as usize });
+    }
+
+    let mut expected = footer.index_offset as usize;
+    let mut previous_last = None;
+    for partition in partitions.iter() {
+        let bytes = partition.end.saturating_sub(partition.start);
+        let misaligned = footer.version < 8 && !bytes.is_multiple_of(INDEX_ENTRY_SIZE);
+        if partition.start != expected || bytes == 0 || misaligned || partition.first > partition.last || previous_last.is_some_and(|last| last >= partition.first) {
+            return Err(corruption(format!("malformed index partition for keys {}..={} at offset {}", partition.first, partition.last, partition.start)));
+        }
+        expected = partition.end;
+        previous_last = Some(partition.last);
+    }
+    if expected != footer.top_index_offset as usize {
+        return Err(corruption("index partitions don't cover the index entries".to_string()));
+    }
+    Ok(Index::Partitioned { partitions: Arc::new(partitions), entries })
+}
+
+fn read_range_tombstones(block: &[u8]) -> io::Result<Vec<Range<u64>>> {
+    if !block.len().is_multiple_of(RANGE_TOMBSTONE_SIZE) {
+        return Err(corruption(format!("range tombstone block length {} is not a multiple of {}", block.len(), RANGE_TOMBSTONE_SIZE)));
+    }
+    let mut cursor = io::Cursor::new(block);
+    let mut ranges = Vec::with_capacity(block.len() / RANGE_TOMBSTONE_SIZE);
+    for _ in 0..block.len() / RANGE_TOMBSTONE_SIZE {
+        let start = cursor.read_u64::<LittleEndian>()?;
+        let end = cursor.read_u64::<LittleEndian>()?;
+        ranges.push(start..end);
+    }
+    Ok(ranges)
+}
+
+// Keys of the point tombstone block, ascending
+fn read_tombstones(block: &[u8]) -> io::Result<BTreeSet<u64>> {
+    if !block.len().is_multiple_of(8) {
+        return Err(corruption(format!("tombstone block length {} is not a multiple of 8", block.len())));
+    }
+    let keys: Vec<u64> = block.chunks_exact(8).map(|key| u64::from_le_bytes(key.try_into().unwrap())).collect();
+    if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(corruption("tombstone keys are not strictly ascending".to_string()));
+    }
+    Ok(keys.into_iter().collect())
+}
+
+fn corruption(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+    use crate::db::search::DistanceMetric;
+    use crate::db::storage::StoredTable;
+    use rand::Rng;
+    use std::io::{Cursor, SeekFrom};
+
+    // The entry at the start of `buf`, laid out as tables of `version` lay them out
+    fn read_versioned_entry<R: Read>(buf: &mut R, version: u32) -> io::Result<(u64, Vector)> {
+        let key = buf.read_u64::<LittleEndian>()?;
+        let flags = if version >= 3 { buf.read_u8()? } else { entry::VALUE_TYPE_BSON };
+        let len = buf.read_u32::<LittleEndian>()? as usize;
+        if version >= 4 {
+            buf.read_u32::<LittleEndian>()?;
+        }
+        let mut serialized = vec![0u8; len];
+        buf.read_exact(&mut serialized)?;
+        Ok((key, entry::decode(flags, &serialized)?))
+    }
+
+    fn table_bytes() -> Vec<u8> {
+        let mut memtable = BTreeMap::new();
+        memtable.insert(1, Vector::new(1, vec![0.0, 1.0]));
+        memtable.insert(2, Vector::new(2, vec![2.0, 3.0]));
+        let mut buf = Cursor::new(Vec::new());
+        write_table(&mut buf, memtable.iter(), &BTreeSet::new(), &[], &TableFormat::default()).unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn test_write_read_buf() {
+        let k1: u64 = 1;
+        let v1 = Vector::new(k1, vec![0.0, 1.0]);
+        let mut memtable = BTreeMap::new();
+        memtable.insert(k1, v1.clone());
+        let mut buf = Cursor::new(Vec::new());
+        let _index = write_table(&mut buf, memtable.iter(), &BTreeSet::new(), &[], &TableFormat::default()).unwrap();
+
+        buf.seek(SeekFrom::Start(0)).unwrap();
+
+        let Ok((k2, v2)) = read_entry(&mut buf) else { panic!("could not read from buffer") };
+
+        assert_eq!(k1, k2);
+        assert_eq!(v1, v2);
+        assert_eq!(v1.id(), v2.id());
+        assert_eq!(v1.data(), v2.data());
+    }
+
+    #[test]
+    fn test_footer_roundtrip() {
+        let data = table_bytes();
+        let footer = Footer::read(&data, data.len()).unwrap();
+        assert_eq!(footer.version, FORMAT_VERSION);
+        assert_eq!(&data[data.len() - MAGIC.len()..], &MAGIC);
+
+        let index = read_index(&data[footer.index_block()], &footer).unwrap();
+        assert_eq!(index.len(), 2);
+        let (key, value) = read_entry(&mut Cursor::new(&data[index[&2]..])).unwrap();
+        assert_eq!(key, 2);
+        assert_eq!(value.data(), &vec![2.0, 3.0]);
+
+        // a version 9 footer has no codec, a version 8 one no code offset either, a version
+        // 6 one no point tombstone offset, a version 5 one no column block offset, and a
+        // version 4 one no top-level index offset
+        for (version, dropped) in [(9, 4), (8, 12), (6, 20), (5, 28), (4, 36)] {
+            let mut old = data[..data.len() - FOOTER_SIZE].to_vec();
+            Footer { version, ..footer }.write(&mut old).unwrap();
+            old.drain(old.len() - 4 - MAGIC.len() - dropped..old.len() - 4 - MAGIC.len());
+            assert_eq!(Footer::read(&old, old.len()).unwrap(), Footer { version, ..footer });
+        }
+    }
+
+    #[test]
+    fn test_column_blocks() {
+        let mut memtable = BTreeMap::new();
+        for key in 0..7u64 {
+            let mut value = Vector::new(key, vec![key as f64, -(key as f64), 0.5]);
+            if key == 2 {
+                value.set_expires_at(99);
+            }
+            memtable.insert(key, value);
+        }
+        memtable.insert(9, Vector::new(9, vec![1.0]));
+        for (element, column_element) in [(ElementType::F64, ElementType::F64), (ElementType::F16, ElementType::F32)] {
+            let format = TableFormat { element, column_block_vectors: 3, ..TableFormat::default() };
+            let mut data = Cursor::new(Vec::new());
+            write_table(&mut data, memtable.iter(), &BTreeSet::new(), &[5..6, 7..9], &format).unwrap();
+            let data = data.into_inner();
+            let footer = Footer::read(&data, data.len()).unwrap();
+            assert_eq!(footer.column_block().start % 8, 0);
+            assert_eq!(read_range_tombstones(&data[footer.range_tombstone_block()]).unwrap(), vec![5..6, 7..9]);
+
+            // runs of three, split where the dimension changes
+            let blocks: Vec<ColumnBlock> = column_blocks(&data[footer.column_block()]).collect::<io::Result<_>>().unwrap();
+            assert_eq!(blocks.iter().map(|b| (b.len(), b.dimension)).collect::<Vec<_>>(), vec![(3, 3), (3, 3), (1, 3), (1, 1)]);
+            assert!(blocks.iter().all(|b| b.element == column_element));
+            assert_eq!((blocks[0].key(2), blocks[0].expires_at(2), blocks[0].expires_at(1)), (2, Some(99), None));
+            let scorer = Scorer::new(DistanceMetric::L2, false);
+            let query = [1.0, 1.0, 1.0];
+            for (i, distance) in blocks[1].distances(&scorer, &query).into_iter().enumerate() {
+                assert!((distance - scorer.distance(&query, memtable[&blocks[1].key(i)].data())).abs() < 1e-12);
+            }
+        }
+
+        // a block running past its section is corrupt
+        let format = TableFormat { column_block_vectors: 4, ..TableFormat::default() };
+        let mut data = Cursor::new(Vec::new());
+        write_table(&mut data, memtable.iter(), &BTreeSet::new(), &[], &format).unwrap();
+        let data = data.into_inner();
+        let footer = Footer::read(&data, data.len()).unwrap();
+        let section = &data[footer.column_block()];
+        let errors: Vec<io::Error> = column_blocks(&section[..section.len() - 8]).filter_map(Result::err).collect();
+        assert_eq!(errors.iter().map(|e| e.kind()).collect::<Vec<_>>(), vec![io::ErrorKind::InvalidData]);
+        assert!(column_blocks(&data[footer.range_tombstone_block()]).next().is_none());
+    }
+
+    #[test]
+    fn test_codes() {
+        let mut rng = rand::rng();
+        let mut memtable = BTreeMap::new();
+        for key in 0..20u64 {
+            memtable.insert(key, Vector::new(key, (0..4).map(|_| rng.random_range(-1.0..1.0)).collect()));
+        }
+        memtable.insert(30, Vector::new(30, vec![1.0]));
+        let sample: Vec<Vec<f64>> = memtable.values().filter(|v| v.data().len() == 4).map(|v| v.data().clone()).collect();
+        let quantizer = Arc::new(ProductQuantizer::train(&sample, 2, 8).unwrap());
+        let format = TableFormat { quantizer: Some(quantizer.clone()), column_block_vectors: 8, ..TableFormat::default() };
+        let mut data = Cursor::new(Vec::new());
+        write_table(&mut data, memtable.iter(), &BTreeSet::new(), &[], &format).unwrap();
+        let data = data.into_inner();
+        let footer = Footer::read(&data, data.len()).unwrap();
+        assert_eq!(footer.code_block(data.len()).start % 8, 0);
+        assert_eq!(column_blocks(&data[footer.column_block()]).count(), 4);
+
+        // every vector of the codebooks' dimension has its code, the rest none
+        let codes = Codes::parse(&data[footer.code_block(data.len())]).unwrap();
+        assert_eq!((codes.len(), codes.fingerprint), (20, quantizer.fingerprint()));
+        for i in 0..codes.len() {
+            assert_eq!(codes.code(i), quantizer.encode(memtable[&codes.key(i)].data()).unwrap());
+        }
+        assert!(Codes::parse(&data[footer.code_block(data.len())][..20]).is_err());
+
+        let dir = empty_test_dir("sstable_codes");
+        let path = dir.join("1.sst");
+        std::fs::write(&path, &data).unwrap();
+        let table = SSTable::open(&path, 1, ReadPath::Mmap).unwrap();
+        assert!(table.has_codes() && table.has_columns());
+        assert!(table.verify().is_empty());
+        std::fs::write(&path, table_bytes()).unwrap();
+        assert!(!SSTable::open(&path, 1, ReadPath::Mmap).unwrap().has_codes());
+    }
+
+    #[test]
+    fn test_footer_rejects_truncated_file() {
+        let data = table_bytes();
+        let err = Footer::read(&data[..data.len() - 1], data.len() - 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Footer::read(&data[..4], 4).is_err());
+    }
+
+    #[test]
+    fn test_footer_rejects_bad_version() {
+        let mut data = table_bytes();
+        let version_at = data.len() - MAGIC.len() - 4;
+        data[version_at..version_at + 4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let err = Footer::read(&data, data.len()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        // as is one written with a codec this version doesn't have
+        let mut data = table_bytes();
+        let codec_at = data.len() - MAGIC.len() - 8;
+        data[codec_at..codec_at + 4].copy_from_slice(&7u32.to_le_bytes());
+        assert_eq!(Footer::read(&data, data.len()).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_range_tombstones_roundtrip() {
+        let mut memtable = BTreeMap::new();
+        memtable.insert(1, Vector::new(1, vec![1.0]));
+        let mut buf = Cursor::new(Vec::new());
+        write_table(&mut buf, memtable.iter(), &BTreeSet::new(), &[10..20, 30..31], &TableFormat::default()).unwrap();
+        let data = buf.into_inner();
+
+        let footer = Footer::read(&data, data.len()).unwrap();
+        assert_eq!(read_range_tombstones(&data[footer.range_tombstone_block()]).unwrap(), vec![10..20, 30..31]);
+        assert_eq!(read_index(&data[footer.index_block()], &footer).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_tombstones_roundtrip() {
+        let mut memtable = BTreeMap::new();
+        memtable.insert(1, Vector::new(1, vec![1.0]));
+        let mut buf = Cursor::new(Vec::new());
+        write_table(&mut buf, memtable.iter(), &BTreeSet::from([4, 9, u64::MAX]), &[10..20, 30..31], &TableFormat::default()).unwrap();
+        let data = buf.into_inner();
+
+        let footer = Footer::read(&data, data.len()).unwrap();
+        assert_eq!(read_tombstones(&data[footer.tombstone_block()]).unwrap(), BTreeSet::from([4, 9, u64::MAX]));
+        assert_eq!(read_range_tombstones(&data[footer.range_tombstone_block()]).unwrap(), vec![10..20, 30..31]);
+        let dir = empty_test_dir("sstable_tombstones_roundtrip");
+        std::fs::write(dir.join("1.sdb"), &data).unwrap();
+        let table = SSTable::open(&dir.join("1.sdb"), 1, ReadPath::Mmap).unwrap();
+        assert_eq!(*table.tombstones, BTreeSet::from([4, 9, u64::MAX]));
+
+        // keys out of order are corrupt, and so is a block cut short
+        let block = footer.tombstone_block();
+        let mut swapped = data.clone();
+        swapped[block.start..block.start + 16].rotate_left(8);
+        assert_eq!(read_tombstones(&swapped[block.clone()]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_tombstones(&data[block.start..block.end - 1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_reads_version_1_tables() {
+        // a version 1 table: one entry, its index, an empty filter block and the short footer
+        let mut data = Vec::new();
+        let serialized = bson::to_vec(&Vector::new(7, vec![7.0])).unwrap();
+        data.write_u64::<LittleEndian>(7).unwrap();
+        data.write_u32::<LittleEndian>(serialized.len() as u32).unwrap();
+        data.extend_from_slice(&serialized);
+        let index_offset = data.len() as u64;
+        data.write_u64::<LittleEndian>(7).unwrap();
+        data.write_u64::<LittleEndian>(0).unwrap();
+        let filter_offset = data.len() as u64;
+        data.write_u64::<LittleEndian>(index_offset).unwrap();
+        data.write_u64::<LittleEndian>(filter_offset).unwrap();
+        data.write_u32::<LittleEndian>(1).unwrap();
+        data.extend_from_slice(&MAGIC);
+
+        let footer = Footer::read(&data, data.len()).unwrap();
+        assert_eq!(footer.version, 1);
+        assert!(read_range_tombstones(&data[footer.range_tombstone_block()]).unwrap().is_empty());
+        let index = read_index(&data[footer.index_block()], &footer).unwrap();
+        let (key, value) = read_versioned_entry(&mut Cursor::new(&data[index[&7]..]), 1).unwrap();
+        assert_eq!((key, value.data().clone()), (7, vec![7.0]));
+
+        // the entry headers have no flags byte
+        let dir = empty_test_dir("sstable_reads_version_1_tables");
+        let path = dir.join("1.sdb");
+        std::fs::write(&path, &data).unwrap();
+        let table = SSTable::open(&path, 1, ReadPath::Mmap).unwrap();
+        assert_eq!(table.entry_size(0).unwrap(), index_offset as usize);
+        assert_eq!(table.read_value(0).unwrap().1.data(), &vec![7.0]);
+        assert!(!table.is_expired(0, u64::MAX).unwrap());
+    }
+
+    #[test]
+    fn test_entry_flags() {
+        let mut expiring = Vector::new(1, vec![1.0]).with_metadata("tag", "a");
+        expiring.set_expires_at(100);
+        let mut data = Vec::new();
+        write_entry(&mut data, 1, &expiring).unwrap();
+        assert_eq!(data[8], entry::VALUE_TYPE_DENSE | entry::HAS_TTL | entry::HAS_PAYLOAD);
+        assert_eq!(read_entry(&mut Cursor::new(&data)).unwrap(), (1, expiring));
+
+        // a flag from a newer version is refused rather than misread
+        data[8] |= 1 << 7;
+        assert_eq!(read_versioned_entry(&mut Cursor::new(&data), FORMAT_VERSION).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        // and the checksum no longer matches
+        assert_eq!(read_entry(&mut Cursor::new(&data)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_dedup_vectors() {
+        let dir = empty_test_dir("sstable_dedup_vectors");
+        let mut memtable = BTreeMap::new();
+        for key in 0..20u64 {
+            let mut value = Vector::new(key, vec![(key % 2) as f64, 0.5, -1.0, 2.0]).with_metadata("key", key as i64);
+            if key == 7 {
+                value.set_expires_at(100);
+            }
+            memtable.insert(key, value);
+        }
+        // too short to be worth sharing
+        memtable.insert(30, Vector::new(30, vec![1.0]));
+        memtable.insert(31, Vector::new(31, vec![1.0]));
+
+        let mut sizes = Vec::new();
+        for (i, dedup_vectors) in [false, true].into_iter().enumerate() {
+            let path = dir.join(format!("{}.sdb", i));
+            let format = TableFormat { element: ElementType::F32, dedup_vectors, ..TableFormat::default() };
+            write_table(&mut File::create(&path).unwrap(), memtable.iter(), &BTreeSet::new(), &[], &format).unwrap();
+            let table = SSTable::open(&path, 1, ReadPath::Mmap).unwrap();
+            assert!(table.verify().is_empty(), "{:?}", table.verify());
+            for (key, offset) in table.entries(..).map(Result::unwrap) {
+                assert_eq!(table.read_value(offset).unwrap(), (key, memtable[&key].clone()));
+                table.with_entry(offset, |_, flags, serialized| {
+                    assert_eq!(flags & entry::SHARED_DATA, 0);
+                    assert_eq!(entry::decode(flags, serialized)?, memtable[&key]);
+                    Ok(())
+                }).unwrap();
+            }
+            let shared = table.raw_entry(table.offset_of(7).unwrap().unwrap()).unwrap()[8] & entry::SHARED_DATA != 0;
+            assert_eq!(shared, dedup_vectors);
+            assert!(table.is_expired(table.offset_of(7).unwrap().unwrap(), 200).unwrap());
+            assert!(table.raw_entry(table.offset_of(31).unwrap().unwrap()).unwrap()[8] & entry::SHARED_DATA == 0);
+            sizes.push(table.file_size());
+        }
+        // two of the twenty keep their 16 bytes of data, the other eighteen hold an offset
+        assert!(sizes[1] + 18 * 8 <= sizes[0], "{:?}", sizes);
+
+        // an entry pointing at itself or later is corrupt
+        let path = dir.join("bad.sdb");
+        let mut data = Vec::new();
+        let (flags, serialized) = entry::encode_shared(&memtable[&0], 0, ElementType::F64, PayloadCodec::Bson).unwrap();
+        write_encoded(&mut data, 0, flags, &serialized).unwrap();
+        let mut index = BTreeMap::new();
+        index.insert(0, 0);
+        let mut data = Cursor::new(data);
+        data.seek(SeekFrom::End(0)).unwrap();
+        let end = data.position();
+        write_blocks(&mut data, &index, end, &BTreeSet::new(), &[], &[], &[], &TableFormat::default()).unwrap();
+        std::fs::write(&path, data.into_inner()).unwrap();
+        let table = SSTable::open(&path, 1, ReadPath::Mmap).unwrap();
+        assert_eq!(table.read_value(0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_unmapped_reads_match_mapped() {
+        let dir = empty_test_dir("sstable_unmapped");
+        let path = dir.join("1.sdb");
+        let mut memtable = BTreeMap::new();
+        memtable.insert(1, Vector::new(1, vec![0.0, 1.0]));
+        let mut expiring = Vector::new(2, vec![2.0]);
+        expiring.set_expires_at(100);
+        memtable.insert(2, expiring);
+        let mut file = File::create(&path).unwrap();
+        write_table(&mut file, memtable.iter(), &BTreeSet::new(), &[5..9, 20..21], &TableFormat::default()).unwrap();
+
+        let (mapped, map_error) = SSTable::open_reporting(&path, 1, ReadPath::Mmap).unwrap();
+        assert!(mapped.is_mapped() && map_error.is_none());
+        let unmapped = SSTable::open(&path, 1, ReadPath::Pread).unwrap();
+        assert!(!unmapped.is_mapped());
+
+        assert_eq!(unmapped.index, mapped.index);
+        assert_eq!(unmapped.range_tombstones, mapped.range_tombstones);
+        assert_eq!(unmapped.file_size(), mapped.file_size());
+        for entry in mapped.entries(..) {
+            let offset = entry.unwrap().1;
+            assert_eq!(unmapped.read_value(offset).unwrap(), mapped.read_value(offset).unwrap());
+            assert_eq!(unmapped.entry_size(offset).unwrap(), mapped.entry_size(offset).unwrap());
+            assert_eq!(unmapped.is_expired(offset, 200).unwrap(), mapped.is_expired(offset, 200).unwrap());
+        }
+        assert!(unmapped.is_expired(mapped.offset_of(2).unwrap().unwrap(), 200).unwrap());
+        assert!(unmapped.read_value(unmapped.file_size() as usize).is_err());
+    }
+
+    #[test]
+    fn test_key_range_pruning() {
+        let memtable: BTreeMap<u64, Vector> = (10..=20).step_by(5).map(|i| (i, Vector::new(i, vec![i as f64]))).collect();
+        let mut data = Cursor::new(Vec::new());
+        write_table(&mut data, memtable.iter(), &BTreeSet::new(), &[0..5, 30..100], &TableFormat::default()).unwrap();
+        let table = SSTable::from_data(Arc::new(StoredTable(Arc::new(data.into_inner()))), 1).unwrap();
+        assert_eq!(table.key_range(), Some((10, 20)));
+        assert!(table.may_hold_key(10) && table.may_hold_key(12) && table.may_hold_key(20));
+        assert!(!table.may_hold_key(9) && !table.may_hold_key(21));
+
+        use Bound::*;
+        for (bounds, expected) in [
+            ((Unbounded, Unbounded), true),
+            ((Included(20), Unbounded), true),
+            ((Excluded(20), Unbounded), false),
+            ((Unbounded, Included(10)), true),
+            ((Unbounded, Excluded(10)), false),
+            ((Included(11), Excluded(15)), true),
+            ((Included(21), Included(30)), false),
+        ] {
+            assert_eq!(table.may_hold_range(bounds), expected, "{:?}", bounds);
+        }
+
+        // range tombstones alone don't put keys in range
+        let mut data = Cursor::new(Vec::new());
+        write_table(&mut data, BTreeMap::new().iter(), &BTreeSet::new(), &[0..5, 30..100], &TableFormat::default()).unwrap();
+        let table = SSTable::from_data(Arc::new(StoredTable(Arc::new(data.into_inner()))), 2).unwrap();
+        assert!(!table.may_hold_key(5) && !table.may_hold_range((Unbounded, Unbounded)));
+    }
+
+    #[test]
+    fn test_partitioned_index() {
+        let dir = empty_test_dir("sstable_partitioned_index");
+        let path = dir.join("1.sdb");
+        let memtable: BTreeMap<u64, Vector> = (0..10).map(|i| (i * 10, Vector::new(i, vec![i as f64]))).collect();
+        let format = TableFormat { index_partition_entries: 3, ..TableFormat::default() };
+        write_table(&mut File::create(&path).unwrap(), memtable.iter(), &BTreeSet::new(), &[], &format).unwrap();
+
+        let mut table = SSTable::open(&path, 1, ReadPath::Pread).unwrap();
+        let Index::Partitioned { partitions, entries } = &table.index else { panic!("index isn't partitioned") };
+        assert_eq!((partitions.len(), *entries), (4, 10));
+        assert_eq!((partitions[1].first, partitions[1].last), (30, 50));
+        assert_eq!(table.key_range(), Some((0, 90)));
+        assert!(table.verify().is_empty());
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        table.cache = Some(cache.clone());
+        for (&key, value) in memtable.iter() {
+            assert_eq!(table.read_value(table.offset_of(key).unwrap().unwrap()).unwrap().1, *value);
+            assert!(!table.contains_key(key + 1).unwrap());
+        }
+        // each partition was read once, then served by the cache
+        assert_eq!(cache.misses(), 4 + 10);
+        assert_eq!(table.offset_of(1000).unwrap(), None);
+
+        let keys = |bounds: (Bound<u64>, Bound<u64>)| table.entries(bounds).map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys((Bound::Included(25), Bound::Excluded(70))), vec![30, 40, 50, 60]);
+        assert_eq!(keys((Bound::Excluded(50), Bound::Unbounded)), vec![60, 70, 80, 90]);
+        assert_eq!(keys((Bound::Unbounded, Bound::Unbounded)).len(), 10);
+        assert!(keys((Bound::Included(91), Bound::Unbounded)).is_empty());
+        assert_eq!(table.first_key((Bound::Excluded(20), Bound::Unbounded), false).unwrap(), Some(30));
+        assert_eq!(table.first_key((Bound::Unbounded, Bound::Excluded(60)), true).unwrap(), Some(50));
+        assert_eq!(table.first_key((Bound::Included(31), Bound::Included(39)), false).unwrap(), None);
+        assert_eq!(table.first_key((Bound::Included(31), Bound::Included(39)), true).unwrap(), None);
+
+        // a top-level index naming keys its partition doesn't hold
+        let mut bytes = std::fs::read(&path).unwrap();
+        let footer = Footer::read(&bytes, bytes.len()).unwrap();
+        let top = footer.top_index_offset as usize;
+        bytes[top + PARTITION_ENTRY_SIZE..top + PARTITION_ENTRY_SIZE + 8].copy_from_slice(&25u64.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let table = SSTable::open(&path, 1, ReadPath::Mmap).unwrap();
+        assert_eq!(table.verify().len(), 1);
+        assert!(table.offset_of(30).unwrap().is_some());
+        // partitions out of order are refused when the table is opened
+        bytes[top + PARTITION_ENTRY_SIZE..top + PARTITION_ENTRY_SIZE + 8].copy_from_slice(&5u64.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(SSTable::open(&path, 1, ReadPath::Mmap).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_index_restart_points() {
+        let dir = empty_test_dir("sstable_index_restart_points");
+        let path = dir.join("1.sdb");
+        // runs of nearby keys, with jumps between them that share no bytes
+        let keys: Vec<u64> = (0..300).map(|i| ((i / 100) << 56) | ((1 << 40) + i * 3)).chain([u64::MAX]).collect();
+        let memtable: BTreeMap<u64, Vector> = keys.iter().map(|&key| (key, Vector::new(key, vec![1.0]))).collect();
+        for (interval, per_partition) in [(16, 0), (1, 0), (5, 64), (1000, 64)] {
+            let format = TableFormat { index_restart_interval: interval, index_partition_entries: per_partition, ..TableFormat::default() };
+            write_table(&mut File::create(&path).unwrap(), memtable.iter(), &BTreeSet::new(), &[], &format).unwrap();
+            let table = SSTable::open(&path, 1, ReadPath::Pread).unwrap();
+            assert_eq!((table.len(), table.is_partitioned()), (keys.len(), per_partition > 0));
+            assert!(table.verify().is_empty());
+
+            let mut expected = 0;
+            for (&key, value) in memtable.iter() {
+                let offset = table.offset_of(key).unwrap().unwrap();
+                assert_eq!(offset, expected);
+                assert_eq!(table.read_value(offset).unwrap().1, *value);
+                expected += table.entry_size(offset).unwrap();
+                assert!(!table.contains_key(key - 1).unwrap());
+            }
+            let range = |bounds: (Bound<u64>, Bound<u64>)| table.entries(bounds).map(|e| e.unwrap().0).collect::<Vec<_>>();
+            assert_eq!(range((Bound::Excluded(keys[98]), Bound::Included(keys[102]))), keys[99..=102].to_vec());
+            assert_eq!(range((Bound::Unbounded, Bound::Unbounded)), keys);
+            assert_eq!(table.first_key((Bound::Excluded(keys[150]), Bound::Unbounded), false).unwrap(), Some(keys[151]));
+            assert_eq!(table.first_key((Bound::Unbounded, Bound::Excluded(keys[200])), true).unwrap(), Some(keys[199]));
+            assert_eq!(table.first_key((Bound::Unbounded, Bound::Excluded(keys[0])), true).unwrap(), None);
+        }
+
+        // the keys take a byte or two of their eight and the offsets one, against 16 bytes
+        // an entry before
+        let footer = Footer::read(&std::fs::read(&path).unwrap(), std::fs::metadata(&path).unwrap().len() as usize).unwrap();
+        assert!(footer.index_block().len() < keys.len() * INDEX_ENTRY_SIZE / 4);
+
+        // a restart point sharing bytes with the key before it is corrupt
+        let format = TableFormat { index_restart_interval: 4, ..TableFormat::default() };
+        write_table(&mut File::create(&path).unwrap(), memtable.iter(), &BTreeSet::new(), &[], &format).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let footer = Footer::read(&bytes, bytes.len()).unwrap();
+        bytes[footer.index_offset as usize] = 3;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(SSTable::open(&path, 1, ReadPath::Mmap).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let empty = IndexBlock::new(&[], FORMAT_VERSION).err().unwrap();
+        assert_eq!(empty.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_scan_hint() {
+        let dir = empty_test_dir("sstable_scan_hint");
+        let path = dir.join("1.sdb");
+        let memtable: BTreeMap<u64, Vector> = (0..10).map(|i| (i * 10, Vector::new(i, vec![i as f64; 100]))).collect();
+        write_table(&mut File::create(&path).unwrap(), memtable.iter(), &BTreeSet::new(), &[], &TableFormat::default()).unwrap();
+
+        let mut table = SSTable::open(&path, 1, ReadPath::Mmap).unwrap();
+        assert!(table.scan_hint((Bound::Unbounded, Bound::Unbounded)).is_none());
+        table.use_access_hints();
+        let offset = |key| table.offset_of(key).unwrap().unwrap();
+        let range = |bounds| table.scan_hint(bounds).map(|hint| hint.range.clone());
+        assert_eq!(range((Bound::Included(20), Bound::Excluded(50))), Some(offset(20)..offset(50)));
+        assert_eq!(range((Bound::Excluded(20), Bound::Included(50))), Some(offset(30)..offset(60)));
+        assert_eq!(range((Bound::Included(85), Bound::Unbounded)), Some(offset(90)..table.data_end));
+        assert_eq!(range((Bound::Included(91), Bound::Unbounded)), None);
+        // reads are the same with the hints dropped or held
+        let hint = table.scan_hint((Bound::Unbounded, Bound::Unbounded)).unwrap();
+        assert_eq!(hint.range, 0..table.data_end);
+        for (&key, value) in memtable.iter() {
+            assert_eq!(table.read_value(offset(key)).unwrap().1, *value);
+        }
+    }
+}
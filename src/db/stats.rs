@@ -0,0 +1,376 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// Counters since the tree was opened, as returned by `LSMTree::stats`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    // point lookups, including the ones resolving merge operands
+    pub gets: u64,
+    // lookups answered by the active or a frozen memtable
+    pub memtable_hits: u64,
+    // SSTable indexes consulted by lookups
+    pub sstable_probes: u64,
+    // lookups of a prefix a table doesn't hold, which its prefix filter ruled out, and
+    // the ones it let through anyway
+    pub filter_negatives: u64,
+    pub filter_false_positives: u64,
+    // tables a lookup passed over because the key is outside their key range, before
+    // their filter or index was consulted
+    pub key_range_skips: u64,
+    // bytes appended to the WAL
+    pub bytes_written: u64,
+    // bytes of SSTables written by flushes and bulk loads
+    pub bytes_flushed: u64,
+    // bytes of SSTables written by compactions
+    pub bytes_compacted: u64,
+    pub flushes: u64,
+    pub compactions: u64,
+    // of those, the ones merging a range iterators found crowded, see
+    // `Options::read_compaction_skips`
+    pub read_compactions: u64,
+    // memtables frozen by `Options::flush_interval_millis` rather than by filling up
+    pub timed_flushes: u64,
+    // fsyncs of the WAL, and the write batches they made durable
+    pub wal_syncs: u64,
+    pub synced_commits: u64,
+    // live SSTables right now, and the bytes of their files
+    pub table_count: usize,
+    pub table_bytes: u64,
+    // queries answered by `knn`, `search_batch` and the index searches. Of those, the ones
+    // answered approximately, by `search`, `ivf_search`, `pq_search` and `search_filtered`
+    // walking the graph, and of these the unfiltered ones returning fewer than `k` results
+    // from a tree holding about `k` vectors or more: a sign the index misses vectors,
+    // for want of a larger `ef_search`, `nprobe` or `rerank`, or of `repair_graph`.
+    pub searches: u64,
+    pub approximate_searches: u64,
+    pub short_searches: u64,
+    // graph updates queued by writes that the indexer hasn't applied yet, see
+    // `Options::index_queue`
+    pub index_backlog: u64,
+    // table reads answered by the block cache and read from the table, and what it holds now
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    pub block_cache_bytes: usize,
+    // `LSMTree::get` calls answered by the row cache, and the ones that looked further
+    pub row_cache_hits: u64,
+    pub row_cache_misses: u64,
+    // time compactions have slept to stay within `Options::compaction_bytes_per_sec`, and
+    // whether one is sleeping now
+    pub compaction_throttled_micros: u64,
+    pub compaction_throttled: bool,
+    // time writes have slept to stay within `Options::write_bytes_per_sec` and
+    // `write_ops_per_sec`
+    pub write_throttled_micros: u64,
+    // writes slowed down and stopped by the thresholds in `Options`, the time they spent
+    // held back, and the writers stopped right now
+    pub write_slowdowns: u64,
+    pub write_stops: u64,
+    pub write_stall_micros: u64,
+    pub stalled_writers: u64,
+    // approximate bytes in memory right now, by what holds them: the active memtable, the
+    // frozen ones waiting to be flushed, and the tables' prefix filters and indexes, with
+    // the block cache's above. See `Options::memory_budget_bytes`.
+    pub memtable_bytes: usize,
+    pub frozen_memtable_bytes: usize,
+    pub filter_bytes: usize,
+    pub index_bytes: usize,
+    // memtables frozen early to stay within the memory budget
+    pub memory_flushes: u64,
+    // how long calls took, with `Options::latency_histograms`, empty without: `get`
+    // and `get`, writes of any kind, `flush` waiting for the memtables to be written, and
+    // `knn`, `search` and `search_filtered`
+    pub get_latency: Latency,
+    pub write_latency: Latency,
+    pub flush_latency: Latency,
+    pub search_latency: Latency,
+}
+
+// A histogram of latencies in microseconds. Buckets split each power of two in four, so a
+// percentile is the upper end of a bucket at most a quarter wider than its lower end.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Latency {
+    pub count: u64,
+    pub sum_micros: u64,
+    pub max_micros: u64,
+    buckets: Vec<u64>,
+}
+
+impl Latency {
+    pub fn mean_micros(&self) -> f64 {
+        ratio(self.sum_micros, self.count)
+    }
+
+    // The latency `percentile` percent of the calls took at most, 0 with none recorded
+    pub fn percentile_micros(&self, percentile: f64) -> u64 {
+        let rank = ((percentile / 100.0).clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return bucket_max(i).min(self.max_micros);
+            }
+        }
+        0
+    }
+}
+
+const BUCKETS: usize = 252;
+
+// Values below 4 have a bucket each; above, the power of two and the next two bits pick it
+fn bucket(micros: u64) -> usize {
+    if micros < 4 {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros() as usize;
+    4 * (exponent - 1) + ((micros >> (exponent - 2)) & 3) as usize
+}
+
+// The largest value in bucket `i`
+fn bucket_max(i: usize) -> u64 {
+    if i < 4 {
+        return i as u64;
+    }
+    let shift = i / 4 - 1;
+    ((4 + (i % 4) as u64) << shift) + ((1u64 << shift) - 1)
+}
+
+// Where a `Latency` is recorded, without taking any lock
+pub(crate) struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub(crate) fn record(&self, micros: u64) {
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    // Records the time until the returned timer is dropped, or nothing unless `enabled`,
+    // which then costs no clock read
+    pub(crate) fn time(&self, enabled: bool) -> Timer<'_> {
+        Timer(enabled.then(|| (self, Instant::now())))
+    }
+
+    fn read(&self) -> Latency {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return Latency::default();
+        }
+        Latency {
+            count,
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+            max_micros: self.max_micros.load(Ordering::Relaxed),
+            buckets: self.buckets.iter().map(|n| n.load(Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+pub(crate) struct Timer<'a>(Option<(&'a Histogram, Instant)>);
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        if let Some((histogram, started)) = self.0 {
+            histogram.record(started.elapsed().as_micros() as u64);
+        }
+    }
+}
+
+// One live SSTable, as returned by `LSMTree::describe`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TableInfo {
+    pub file_number: u64,
+    pub file_name: String,
+    pub file_size: u64,
+    pub format_version: u32,
+    // the smallest and largest key with an entry, None for a table of range tombstones only
+    pub key_range: Option<(u64, u64)>,
+    pub entries: usize,
+    // point deletes of the table's keys made since it was written
+    pub tombstones: usize,
+    // key ranges the table deletes from older tables
+    pub range_tombstones: usize,
+    // the tree keeps its tables in order, oldest first, and compacts adjacent tables, so
+    // the level is the place of the table's sorted run in that order: 0 for the oldest.
+    // The tables of one compaction split into sub-compactions share a level.
+    pub level: usize,
+    // kept in `Options::cold_directory`
+    pub cold: bool,
+}
+
+impl Stats {
+    // the memory the budget counts, in all
+    pub fn memory_bytes(&self) -> usize {
+        self.memtable_bytes + self.frozen_memtable_bytes + self.filter_bytes + self.index_bytes + self.block_cache_bytes
+    }
+
+    // SSTable probes per lookup
+    pub fn read_amplification(&self) -> f64 {
+        ratio(self.sstable_probes, self.gets)
+    }
+
+    // Write batches made durable per WAL sync, above 1 once a commit window coalesces them
+    pub fn commit_batch_size(&self) -> f64 {
+        ratio(self.synced_commits, self.wal_syncs)
+    }
+
+    // Share of lookups of absent prefixes that prefix filters failed to rule out
+    pub fn filter_false_positive_rate(&self) -> f64 {
+        ratio(self.filter_false_positives, self.filter_negatives + self.filter_false_positives)
+    }
+
+    // Share of table reads the block cache answered
+    pub fn block_cache_hit_rate(&self) -> f64 {
+        ratio(self.block_cache_hits, self.block_cache_hits + self.block_cache_misses)
+    }
+
+    // Bytes written to disk per byte of logged writes
+    pub fn write_amplification(&self) -> f64 {
+        ratio(self.bytes_written + self.bytes_flushed + self.bytes_compacted, self.bytes_written)
+    }
+}
+
+fn ratio(n: u64, d: u64) -> f64 {
+    if d == 0 { 0.0 } else { n as f64 / d as f64 }
+}
+
+// Shared by the tree and its snapshots, updated without taking any lock
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) gets: AtomicU64,
+    pub(crate) memtable_hits: AtomicU64,
+    pub(crate) sstable_probes: AtomicU64,
+    pub(crate) filter_negatives: AtomicU64,
+    pub(crate) filter_false_positives: AtomicU64,
+    pub(crate) key_range_skips: AtomicU64,
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) bytes_flushed: AtomicU64,
+    pub(crate) bytes_compacted: AtomicU64,
+    pub(crate) flushes: AtomicU64,
+    pub(crate) compactions: AtomicU64,
+    pub(crate) read_compactions: AtomicU64,
+    pub(crate) timed_flushes: AtomicU64,
+    pub(crate) wal_syncs: AtomicU64,
+    pub(crate) synced_commits: AtomicU64,
+    pub(crate) write_slowdowns: AtomicU64,
+    pub(crate) write_stops: AtomicU64,
+    pub(crate) write_stall_micros: AtomicU64,
+    pub(crate) stalled_writers: AtomicU64,
+    pub(crate) memory_flushes: AtomicU64,
+    pub(crate) searches: AtomicU64,
+    pub(crate) approximate_searches: AtomicU64,
+    pub(crate) short_searches: AtomicU64,
+    pub(crate) get_latency: Histogram,
+    pub(crate) write_latency: Histogram,
+    pub(crate) flush_latency: Histogram,
+    pub(crate) search_latency: Histogram,
+}
+
+impl Counters {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self, table_count: usize) -> Stats {
+        Stats {
+            gets: self.gets.load(Ordering::Relaxed),
+            memtable_hits: self.memtable_hits.load(Ordering::Relaxed),
+            sstable_probes: self.sstable_probes.load(Ordering::Relaxed),
+            filter_negatives: self.filter_negatives.load(Ordering::Relaxed),
+            filter_false_positives: self.filter_false_positives.load(Ordering::Relaxed),
+            key_range_skips: self.key_range_skips.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            bytes_flushed: self.bytes_flushed.load(Ordering::Relaxed),
+            bytes_compacted: self.bytes_compacted.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            read_compactions: self.read_compactions.load(Ordering::Relaxed),
+            timed_flushes: self.timed_flushes.load(Ordering::Relaxed),
+            wal_syncs: self.wal_syncs.load(Ordering::Relaxed),
+            synced_commits: self.synced_commits.load(Ordering::Relaxed),
+            write_slowdowns: self.write_slowdowns.load(Ordering::Relaxed),
+            write_stops: self.write_stops.load(Ordering::Relaxed),
+            write_stall_micros: self.write_stall_micros.load(Ordering::Relaxed),
+            stalled_writers: self.stalled_writers.load(Ordering::Relaxed),
+            memory_flushes: self.memory_flushes.load(Ordering::Relaxed),
+            searches: self.searches.load(Ordering::Relaxed),
+            approximate_searches: self.approximate_searches.load(Ordering::Relaxed),
+            short_searches: self.short_searches.load(Ordering::Relaxed),
+            get_latency: self.get_latency.read(),
+            write_latency: self.write_latency.read(),
+            flush_latency: self.flush_latency.read(),
+            search_latency: self.search_latency.read(),
+            table_count,
+            ..Stats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amplification() {
+        assert_eq!(Stats::default().read_amplification(), 0.0);
+        assert_eq!(Stats::default().write_amplification(), 0.0);
+        assert_eq!(Stats::default().commit_batch_size(), 0.0);
+        assert_eq!(Stats::default().block_cache_hit_rate(), 0.0);
+
+        let stats = Stats { gets: 4, sstable_probes: 6, bytes_written: 100, bytes_flushed: 100, bytes_compacted: 200, ..Stats::default() };
+        assert_eq!(stats.read_amplification(), 1.5);
+        assert_eq!(stats.write_amplification(), 4.0);
+        assert_eq!(Stats { wal_syncs: 2, synced_commits: 7, ..Stats::default() }.commit_batch_size(), 3.5);
+        assert_eq!(Stats { block_cache_hits: 3, block_cache_misses: 1, ..Stats::default() }.block_cache_hit_rate(), 0.75);
+    }
+
+    #[test]
+    fn test_buckets() {
+        // every value lands in a bucket whose range holds it, and the buckets are contiguous
+        for micros in (0..5000).chain([u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+            let i = bucket(micros);
+            assert!(micros <= bucket_max(i) && (i == 0 || micros > bucket_max(i - 1)), "{} in bucket {}", micros, i);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        assert_eq!(bucket_max(BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn test_latency() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.read(), Latency::default());
+        assert_eq!(histogram.read().percentile_micros(99.0), 0);
+        for micros in 1..=1000 {
+            histogram.record(micros);
+        }
+        let latency = histogram.read();
+        assert_eq!((latency.count, latency.max_micros), (1000, 1000));
+        assert_eq!(latency.mean_micros(), 500.5);
+        // within a quarter above the exact percentile, and never past the largest latency
+        for (percentile, exact) in [(50.0, 500), (90.0, 900), (99.0, 990), (100.0, 1000)] {
+            let estimate = latency.percentile_micros(percentile);
+            assert!(estimate >= exact && estimate <= exact + exact / 4, "p{} is {}", percentile, estimate);
+        }
+        assert_eq!(latency.percentile_micros(0.0), 1);
+
+        drop(histogram.time(false));
+        assert_eq!(histogram.read().count, 1000);
+        drop(histogram.time(true));
+        assert_eq!(histogram.read().count, 1001);
+    }
+}
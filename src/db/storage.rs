@@ -0,0 +1,295 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use crate::db::platform::{self, sync_dir};
+use crate::db::sstable::TableReader;
+
+// Where a tree keeps its tables when `Options::storage` is set, in place of its directory.
+// Names are flat, with no directories. The log, manifest and saved graph stay in the
+// tree's directory.
+pub trait Storage: Send + Sync {
+    // An empty file under `name`, replacing one already there, to be appended to
+    fn create(&self, name: &str) -> io::Result<Box<dyn WritableFile>>;
+
+    // NotFound if there is no file under `name`
+    fn open(&self, name: &str) -> io::Result<Arc<dyn ReadableFile>>;
+
+    // Replaces a file already under `to`
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    fn delete(&self, name: &str) -> io::Result<()>;
+
+    // Makes the creates, renames and deletes so far survive a crash
+    fn sync(&self) -> io::Result<()>;
+}
+
+pub trait WritableFile: Send {
+    fn append(&mut self, data: &[u8]) -> io::Result<()>;
+
+    // Makes what was appended so far survive a crash
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+pub trait ReadableFile: Send + Sync {
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Fills `buf` from `offset`, UnexpectedEof past the end
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+// Files in a directory, read with pread
+pub struct LocalStorage {
+    directory: PathBuf,
+}
+
+impl LocalStorage {
+    // Creates `directory` if it doesn't exist
+    pub fn open(directory: impl Into<PathBuf>) -> io::Result<LocalStorage> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        Ok(LocalStorage { directory })
+    }
+}
+
+impl Storage for LocalStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(self.directory.join(name))?;
+        Ok(Box::new(file))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Arc<dyn ReadableFile>> {
+        let file = File::open(self.directory.join(name))?;
+        let len = file.metadata()?.len();
+        Ok(Arc::new((file, len)))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(self.directory.join(from), self.directory.join(to))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        std::fs::remove_file(self.directory.join(name))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        sync_dir(&self.directory)
+    }
+}
+
+impl WritableFile for File {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+// a file and its length when opened
+impl ReadableFile for (File, u64) {
+    fn len(&self) -> u64 {
+        self.1
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        platform::read_exact_at(&self.0, buf, offset)
+    }
+}
+
+// Files held in memory, gone with the last handle to it. A tree reopened on the same
+// storage finds its tables, so it suits tests that would otherwise write them to disk.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, Arc<Mutex<Vec<u8>>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn file(&self, name: &str) -> io::Result<Arc<Mutex<Vec<u8>>>> {
+        match self.files.lock().unwrap().get(name) {
+            Some(file) => Ok(file.clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no file {} in memory", name))),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn WritableFile>> {
+        let file = Arc::new(Mutex::new(Vec::new()));
+        self.files.lock().unwrap().insert(name.to_string(), file.clone());
+        Ok(Box::new(MemoryFile(file)))
+    }
+
+    // reads what was appended by the time it is opened
+    fn open(&self, name: &str) -> io::Result<Arc<dyn ReadableFile>> {
+        let contents = self.file(name)?.lock().unwrap().clone();
+        Ok(Arc::new(contents))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let file = self.file(from)?;
+        let mut files = self.files.lock().unwrap();
+        files.remove(from);
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.file(name)?;
+        self.files.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct MemoryFile(Arc<Mutex<Vec<u8>>>);
+
+impl WritableFile for MemoryFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ReadableFile for Vec<u8> {
+    fn len(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = offset.min(self.len() as u64) as usize;
+        match self[start..].get(..buf.len()) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("read of {} bytes at {} is past the end of a {} byte file", buf.len(), offset, self.len()))),
+        }
+    }
+}
+
+// Appends through `Write`, for the table writer, which asks where it is
+pub(crate) struct Appender<'a> {
+    file: &'a mut dyn WritableFile,
+    position: u64,
+}
+
+impl Appender<'_> {
+    pub(crate) fn new(file: &mut dyn WritableFile) -> Appender<'_> {
+        Appender { file, position: 0 }
+    }
+}
+
+impl Write for Appender<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.append(buf)?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Appender<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "a storage file is only appended to")),
+        }
+    }
+}
+
+// A table read from a storage file
+pub(crate) struct StoredTable(pub(crate) Arc<dyn ReadableFile>);
+
+impl TableReader for StoredTable {
+    fn len(&self) -> usize {
+        self.0.len() as usize
+    }
+
+    fn read_within(&self, range: Range<usize>) -> io::Result<Cow<'_, [u8]>> {
+        let mut buf = vec![0; range.len()];
+        self.0.read_at(range.start as u64, &mut buf)?;
+        Ok(Cow::Owned(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+
+    fn exercise(storage: &dyn Storage) {
+        let mut file = storage.create("a.tmp").unwrap();
+        file.append(b"hello ").unwrap();
+        file.append(b"world").unwrap();
+        file.sync().unwrap();
+        drop(file);
+        storage.rename("a.tmp", "a").unwrap();
+        storage.sync().unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["a".to_string()]);
+
+        let file = storage.open("a").unwrap();
+        assert_eq!(file.len(), 11);
+        let mut buf = [0; 5];
+        file.read_at(6, &mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(file.read_at(8, &mut buf).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(StoredTable(file).read(0..5).unwrap().as_ref(), b"hello");
+
+        // creating again empties it
+        drop(storage.create("a").unwrap());
+        assert_eq!(storage.open("a").unwrap().len(), 0);
+        storage.delete("a").unwrap();
+        assert!(storage.list().unwrap().is_empty());
+        assert_eq!(storage.open("a").err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.delete("a").err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.rename("a", "b").err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_local_storage() {
+        let path = test_dir("storage_local");
+        exercise(&LocalStorage::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        exercise(&MemoryStorage::new());
+    }
+}
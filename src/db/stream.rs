@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use crate::db::batch::WriteBatch;
+use crate::db::error::LsmError;
+use crate::db::lsm::LSMTree;
+use crate::db::vector::Vector;
+
+// Ingests a stream of writes, applying them `batch_size` at a time. Every operation gets
+// a position in the stream, starting at 1, and two watermarks report how far along the
+// stream is: `acknowledged` operations are applied and visible to reads, `durable` ones
+// have also been synced to the WAL and survive a crash. A consumer commits its source
+// offset up to the durable watermark. Operations still buffered when the stream is
+// dropped are discarded, so they are simply redelivered from the last committed offset.
+pub struct WriteStream<'a> {
+    tree: &'a LSMTree,
+    batch: WriteBatch,
+    batch_size: usize,
+    accepted: u64,
+    acknowledged: u64,
+    durable: u64,
+    // (last position, last sequence number) of applied batches not yet known to be synced
+    unsynced: VecDeque<(u64, u64)>,
+}
+
+impl<'a> WriteStream<'a> {
+    pub(crate) fn new(tree: &'a LSMTree, batch_size: usize) -> WriteStream<'a> {
+        WriteStream {
+            tree,
+            batch: WriteBatch::new(),
+            batch_size: batch_size.max(1),
+            accepted: 0,
+            acknowledged: 0,
+            durable: 0,
+            unsynced: VecDeque::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: u64, value: Vector) -> Result<u64, LsmError> {
+        self.batch.put(key, value);
+        self.accepted()
+    }
+
+    pub fn delete(&mut self, key: u64) -> Result<u64, LsmError> {
+        self.batch.delete(key);
+        self.accepted()
+    }
+
+    pub fn delete_range(&mut self, start: u64, end: u64) -> Result<u64, LsmError> {
+        self.batch.delete_range(start, end);
+        self.accepted()
+    }
+
+    pub fn merge(&mut self, key: u64, operand: Vec<u8>) -> Result<u64, LsmError> {
+        self.batch.merge(key, operand);
+        self.accepted()
+    }
+
+    // Applies the buffered operations without waiting for the batch to fill up
+    pub fn flush(&mut self) -> Result<(), LsmError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let sequence = self.tree.write_sequenced(std::mem::take(&mut self.batch))?;
+        self.acknowledged = self.accepted;
+        self.unsynced.push_back((self.accepted, sequence));
+        self.advance_durable();
+        Ok(())
+    }
+
+    // Position of the last operation applied and visible to reads
+    pub fn acknowledged(&self) -> u64 {
+        self.acknowledged
+    }
+
+    // Position of the last operation synced to the WAL
+    pub fn durable(&mut self) -> u64 {
+        self.advance_durable();
+        self.durable
+    }
+
+    fn accepted(&mut self) -> Result<u64, LsmError> {
+        self.accepted += 1;
+        let position = self.accepted;
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(position)
+    }
+
+    fn advance_durable(&mut self) {
+        let synced = self.tree.synced_sequence();
+        while let Some(&(position, sequence)) = self.unsynced.front() {
+            if sequence > synced {
+                break;
+            }
+            self.durable = position;
+            self.unsynced.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+
+    #[test]
+    fn test_watermarks() {
+        let path = test_dir("stream_watermarks");
+        let lsm = LSMTree::new(&path).unwrap();
+        let mut stream = lsm.write_stream(3);
+        assert_eq!(stream.put(1, Vector::new(1, vec![1.0])).unwrap(), 1);
+        assert_eq!(stream.put(2, Vector::new(2, vec![2.0])).unwrap(), 2);
+        assert_eq!(stream.acknowledged(), 0);
+        assert!(lsm.get(1).unwrap().is_none());
+
+        // the third operation fills the batch
+        assert_eq!(stream.delete(1).unwrap(), 3);
+        assert_eq!(stream.acknowledged(), 3);
+        assert_eq!(stream.durable(), 3);
+        assert!(lsm.get(1).unwrap().is_none());
+        assert!(lsm.get(2).unwrap().is_some());
+
+        assert_eq!(stream.put(4, Vector::new(4, vec![4.0])).unwrap(), 4);
+        stream.flush().unwrap();
+        assert_eq!(stream.acknowledged(), 4);
+        assert_eq!(stream.durable(), 4);
+        // buffered operations are dropped with the stream
+        stream.put(5, Vector::new(5, vec![5.0])).unwrap();
+        drop(stream);
+        drop(lsm);
+
+        let lsm = LSMTree::new(&path).unwrap();
+        assert!(lsm.get(4).unwrap().is_some());
+        assert!(lsm.get(5).unwrap().is_none());
+    }
+}
@@ -0,0 +1,18 @@
+// Fixtures shared by the tests of every module
+use std::path::PathBuf;
+
+// /tmp/lsm/<name> for a test to open a tree in, removed if an earlier run left it
+// behind. Modules prefix their names with their own, e.g. "wal_", so their tests don't
+// share directories when they run in parallel.
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let path: PathBuf = format!("/tmp/lsm/{}", name).into();
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
+
+// `test_dir`, created empty, for tests that write files into it themselves
+pub(crate) fn empty_test_dir(name: &str) -> PathBuf {
+    let path = test_dir(name);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use crate::db::batch::WriteBatch;
+use crate::db::error::LsmError;
+use crate::db::lsm::{LSMTree, Snapshot};
+use crate::db::vector::Vector;
+
+// Optimistic transaction: reads come from a snapshot taken at `LSMTree::begin`, writes
+// are buffered, and `commit` applies them only if nothing the transaction read has changed
+// since. A conflicting commit fails with `ErrorKind::ResourceBusy` and can be retried.
+pub struct Transaction<'a> {
+    tree: &'a LSMTree,
+    snapshot: Snapshot,
+    // value seen by each read, checked again at commit
+    reads: BTreeMap<u64, Option<Vector>>,
+    // pending writes, so the transaction reads its own writes
+    writes: BTreeMap<u64, Option<Vector>>,
+    batch: WriteBatch,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(tree: &'a LSMTree, snapshot: Snapshot) -> Transaction<'a> {
+        Transaction { tree, snapshot, reads: BTreeMap::new(), writes: BTreeMap::new(), batch: WriteBatch::new() }
+    }
+
+    pub fn get(&mut self, key: u64) -> Result<Option<Vector>, LsmError> {
+        if let Some(written) = self.writes.get(&key) {
+            return Ok(written.clone());
+        }
+        let value = self.snapshot.get(key)?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    pub fn put(&mut self, key: u64, value: Vector) {
+        self.writes.insert(key, Some(value.clone()));
+        self.batch.put(key, value);
+    }
+
+    pub fn delete(&mut self, key: u64) {
+        self.writes.insert(key, None);
+        self.batch.delete(key);
+    }
+
+    pub fn commit(self) -> Result<(), LsmError> {
+        Ok(self.tree.write_if_unchanged(&self.reads, self.batch)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::test_dir;
+
+    #[test]
+    fn test_commit_applies_writes() {
+        let path = test_dir("transaction_commit_applies_writes");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+
+        let mut txn = lsm.begin();
+        let value = txn.get(1).unwrap();
+        txn.put(2, Vector::new(2, vec![value.unwrap().data()[0] + 1.0]));
+        txn.delete(1);
+        assert!(txn.get(1).unwrap().is_none());
+        assert_eq!(txn.get(2).unwrap().unwrap().data(), &vec![2.0]);
+        // nothing is visible before commit
+        assert!(lsm.get(2).unwrap().is_none());
+        txn.commit().unwrap();
+
+        assert!(lsm.get(1).unwrap().is_none());
+        assert_eq!(lsm.get(2).unwrap().unwrap().data(), &vec![2.0]);
+    }
+
+    #[test]
+    fn test_conflicting_commit_fails() {
+        let path = test_dir("transaction_conflicting_commit_fails");
+        let lsm = LSMTree::new(&path).unwrap();
+        lsm.insert(1, Vector::new(1, vec![1.0])).unwrap();
+
+        let mut first = lsm.begin();
+        let mut second = lsm.begin();
+        assert!(first.get(1).unwrap().is_some());
+        assert!(second.get(1).unwrap().is_some());
+        // keys that were never read don't conflict
+        assert!(second.get(5).unwrap().is_none());
+        lsm.insert(6, Vector::new(6, vec![6.0])).unwrap();
+
+        first.put(1, Vector::new(1, vec![10.0]));
+        second.put(1, Vector::new(1, vec![20.0]));
+        first.commit().unwrap();
+        let err = second.commit().unwrap_err();
+        assert!(matches!(err, LsmError::Io(ref e) if e.kind() == std::io::ErrorKind::ResourceBusy));
+        assert_eq!(lsm.get(1).unwrap().unwrap().data(), &vec![10.0]);
+
+        // a read of a missing key conflicts with a concurrent insert of it
+        let mut txn = lsm.begin();
+        assert!(txn.get(7).unwrap().is_none());
+        lsm.insert(7, Vector::new(7, vec![7.0])).unwrap();
+        txn.put(8, Vector::new(8, vec![8.0]));
+        assert!(txn.commit().is_err());
+        assert!(lsm.get(8).unwrap().is_none());
+    }
+}
@@ -1,24 +1,118 @@
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use std::io::Write;
-use std::fs::File;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::ffi::CString;
-use libc::{open, O_CREAT, O_WRONLY, O_TRUNC, mode_t};
-use libc::{c_uint, c_int};
-use std::os::fd::{RawFd, FromRawFd};
 use std::time::Duration;
+use crate::db::binary;
+use crate::db::direct::{self, DirectWriter};
+use crate::db::entry;
+use crate::db::half;
 
 const MACHINE_ID: u32 = 1234567890;
 
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Vector {
     id: u64,
-    data: Vec<f64>
+    data: Vec<f64>,
+    // milliseconds since the epoch after which the record reads as absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    // fields stored and returned with the record, like a document id or tags
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, MetadataValue>,
+}
+
+// Serialized as the plain bson value, so records stay readable by other tools
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MetadataValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl MetadataValue {
+    // Bytes the value takes in memory beyond the enum itself
+    fn heap_size(&self) -> usize {
+        match self {
+            MetadataValue::String(s) => s.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl From<bool> for MetadataValue {
+    fn from(v: bool) -> MetadataValue {
+        MetadataValue::Bool(v)
+    }
+}
+
+impl From<i64> for MetadataValue {
+    fn from(v: i64) -> MetadataValue {
+        MetadataValue::Int(v)
+    }
+}
+
+impl From<f64> for MetadataValue {
+    fn from(v: f64) -> MetadataValue {
+        MetadataValue::Float(v)
+    }
+}
+
+impl From<&str> for MetadataValue {
+    fn from(v: &str) -> MetadataValue {
+        MetadataValue::String(v.to_string())
+    }
+}
+
+impl From<String> for MetadataValue {
+    fn from(v: String) -> MetadataValue {
+        MetadataValue::String(v)
+    }
 }
 
 impl Vector {
     pub fn new(id: u64, data: Vec<f64>) -> Vector {
-        Vector{id, data}
+        Vector{id, data, expires_at: None, metadata: BTreeMap::new()}
+    }
+
+    pub fn with_metadata(mut self, field: impl Into<String>, value: impl Into<MetadataValue>) -> Vector {
+        self.metadata.insert(field.into(), value.into());
+        self
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, MetadataValue> {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut BTreeMap<String, MetadataValue> {
+        &mut self.metadata
+    }
+
+    // Approximate memory held by the metadata, for memtable accounting
+    pub(crate) fn metadata_size(&self) -> usize {
+        self.metadata.iter().map(|(field, value)| field.len() + std::mem::size_of::<MetadataValue>() + value.heap_size()).sum()
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub(crate) fn set_expires_at(&mut self, expires_at: u64) {
+        self.expires_at = Some(expires_at);
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    pub(crate) fn set_data(&mut self, data: Vec<f64>) {
+        self.data = data;
     }
 
     pub fn data(&self) -> &Vec<f64> {
@@ -32,31 +126,247 @@ impl Vector {
     fn to_json(&self) -> String {
         format!("{{\"id\": {:?}, \"data\": {:?}, \"type\": \"upsert\"}}\n", self.id, self.data)
     }
+}
+
+// How vector data is stored on disk. Vectors are f64 in memory whatever the type: a
+// tree storing f32 rounds the data to f32 as it is written, so reads agree before and
+// after a flush, and a binary tree keeps only the sign bits, reading back 1.0 for a
+// positive component and 0.0 for any other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ElementType {
+    #[default]
+    F64,
+    F32,
+    // half of f32 again: f16 keeps 11 bits of precision but only reaches 65504, bf16
+    // keeps f32's range with 8 bits of precision
+    F16,
+    BF16,
+    // a bit per element, packed eight to a byte, see `binary`
+    Binary,
+}
+
+impl ElementType {
+    // Bits per element on disk
+    pub fn bits(self) -> usize {
+        match self {
+            ElementType::F64 => 64,
+            ElementType::F32 => 32,
+            ElementType::F16 | ElementType::BF16 => 16,
+            ElementType::Binary => 1,
+        }
+    }
+
+    // Bytes the data of a vector of `dimension` elements takes on disk
+    pub fn data_len(self, dimension: usize) -> usize {
+        (dimension * self.bits()).div_ceil(8)
+    }
+
+    // The data as it reads back once stored as this type
+    pub fn round(self, data: &mut [f64]) {
+        if self != ElementType::F64 {
+            for x in data.iter_mut() {
+                *x = self.round_one(*x);
+            }
+        }
+    }
 
-    fn create_direct_io_file(path: &str) -> std::io::Result<File> {
-        let c_path = CString::new(path).unwrap();
-        unsafe {
-            let fd: RawFd = open(
-                c_path.as_ptr(),
-                O_WRONLY | O_CREAT | O_TRUNC | 0o4000 as mode_t as c_int, 0o644 as mode_t as c_uint,
-            );
+    fn round_one(self, x: f64) -> f64 {
+        match self {
+            ElementType::F64 => x,
+            ElementType::F32 => x as f32 as f64,
+            ElementType::F16 => half::f16_to_f64(half::f16_from_f64(x)),
+            ElementType::BF16 => half::bf16_to_f64(half::bf16_from_f64(x)),
+            ElementType::Binary => if x > 0.0 { 1.0 } else { 0.0 },
+        }
+    }
+
+    pub(crate) fn is_exact(self, data: &[f64]) -> bool {
+        // a NaN stays NaN in every float type, but has no sign bit to keep
+        self == ElementType::F64 || data.iter().all(|&x| self.round_one(x) == x || (x.is_nan() && self != ElementType::Binary))
+    }
 
-            if fd < 0 {
-                return Err(std::io::Error::last_os_error());
+    pub(crate) fn write<W: Write>(self, out: &mut W, data: &[f64]) -> std::io::Result<()> {
+        if self == ElementType::Binary {
+            return out.write_all(&binary::pack(data));
+        }
+        for &x in data {
+            match self {
+                ElementType::F64 => out.write_all(&x.to_le_bytes())?,
+                ElementType::F32 => out.write_all(&(x as f32).to_le_bytes())?,
+                ElementType::F16 => out.write_all(&half::f16_from_f64(x).to_le_bytes())?,
+                ElementType::BF16 => out.write_all(&half::bf16_from_f64(x).to_le_bytes())?,
+                ElementType::Binary => unreachable!("packed above"),
             }
+        }
+        Ok(())
+    }
+
+    // Decodes element `i` of `data`, which holds little endian elements of this type
+    pub(crate) fn read(self, data: &[u8], i: usize) -> Option<f64> {
+        if self == ElementType::Binary {
+            return data.get(i / 8).map(|byte| (byte >> (i % 8) & 1) as f64);
+        }
+        let size = self.bits() / 8;
+        let bytes = data.get(i * size..i * size + size)?;
+        Some(match self {
+            ElementType::F64 => f64::from_le_bytes(bytes.try_into().unwrap()),
+            ElementType::F32 => f32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            ElementType::F16 => half::f16_to_f64(u16::from_le_bytes(bytes.try_into().unwrap())),
+            ElementType::BF16 => half::bf16_to_f64(u16::from_le_bytes(bytes.try_into().unwrap())),
+            ElementType::Binary => unreachable!("read above"),
+        })
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            ElementType::F64 => 0,
+            ElementType::F32 => 1,
+            ElementType::F16 => 2,
+            ElementType::BF16 => 3,
+            ElementType::Binary => 4,
+        }
+    }
+
+    pub(crate) fn from_u8(tag: u8) -> std::io::Result<ElementType> {
+        match tag {
+            0 => Ok(ElementType::F64),
+            1 => Ok(ElementType::F32),
+            2 => Ok(ElementType::F16),
+            3 => Ok(ElementType::BF16),
+            4 => Ok(ElementType::Binary),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown element type {}", tag))),
+        }
+    }
+}
+
+// A stored vector read where it lies, without decoding or copying its data, see
+// `LSMTree::get_with`
+#[derive(Debug, Clone, Copy)]
+pub struct ValueRef<'a> {
+    inner: Inner<'a>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Inner<'a> {
+    // decoded because it was merged or written before dense values
+    Decoded(&'a Vector),
+    Dense { flags: u8, serialized: &'a [u8], dense: entry::Dense<'a> },
+}
+
+impl<'a> ValueRef<'a> {
+    pub(crate) fn decoded(value: &'a Vector) -> ValueRef<'a> {
+        ValueRef { inner: Inner::Decoded(value) }
+    }
+
+    pub(crate) fn dense(flags: u8, serialized: &'a [u8]) -> std::io::Result<ValueRef<'a>> {
+        let dense = entry::Dense::split(flags, serialized)?;
+        Ok(ValueRef { inner: Inner::Dense { flags, serialized, dense } })
+    }
+
+    pub fn id(&self) -> u64 {
+        match self.inner {
+            Inner::Decoded(value) => value.id,
+            Inner::Dense { dense, .. } => dense.id,
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        match self.inner {
+            Inner::Decoded(value) => value.expires_at,
+            Inner::Dense { dense, .. } => dense.expires_at,
+        }
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at().is_some_and(|at| now >= at)
+    }
+
+    pub fn len(&self) -> usize {
+        match self.inner {
+            Inner::Decoded(value) => value.data.len(),
+            Inner::Dense { dense, .. } => dense.dimension,
+        }
+    }
+
+    pub fn element_type(&self) -> ElementType {
+        match self.inner {
+            Inner::Decoded(_) => ElementType::F64,
+            Inner::Dense { dense, .. } => dense.element,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<f64> {
+        match self.inner {
+            Inner::Decoded(value) => value.data.get(i).copied(),
+            // a binary value's last byte has padding bits past its dimension
+            Inner::Dense { dense, .. } if i < dense.dimension => dense.element.read(dense.data, i),
+            Inner::Dense { .. } => None,
+        }
+    }
 
-            Ok(File::from_raw_fd(fd))
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = f64> + 'a {
+        let value = *self;
+        (0..self.len()).map(move |i| value.get(i).unwrap())
+    }
+
+    // The data as a slice, when it can be lent out as one: it is stored as f64, little
+    // endian like the machine, and on an 8 byte boundary, which entries of a mapped
+    // table always are
+    pub fn as_slice(&self) -> Option<&'a [f64]> {
+        match self.inner {
+            Inner::Decoded(value) => Some(&value.data),
+            Inner::Dense { dense, .. } if dense.element == ElementType::F64 => cast(dense.data),
+            Inner::Dense { .. } => None,
+        }
+    }
+
+    // Like `as_slice`, for data stored as f32
+    pub fn as_f32_slice(&self) -> Option<&'a [f32]> {
+        match self.inner {
+            Inner::Dense { dense, .. } if dense.element == ElementType::F32 => cast(dense.data),
+            _ => None,
+        }
+    }
+
+    // The packed bits of data stored as `ElementType::Binary`, bit i of the vector being
+    // bit i % 8 of byte i / 8
+    pub fn as_bits(&self) -> Option<&'a [u8]> {
+        match self.inner {
+            Inner::Dense { dense, .. } if dense.element == ElementType::Binary => Some(dense.data),
+            _ => None,
+        }
+    }
+
+    // The whole record, metadata included, decoded into a vector of its own
+    pub fn to_vector(&self) -> std::io::Result<Vector> {
+        match self.inner {
+            Inner::Decoded(value) => Ok(value.clone()),
+            Inner::Dense { flags, serialized, .. } => entry::decode(flags, serialized),
         }
     }
 }
 
+// Little endian floats as a slice, if they are laid out like the machine's
+pub(crate) fn cast<T: Copy>(bytes: &[u8]) -> Option<&[T]> {
+    if !cfg!(target_endian = "little") {
+        return None;
+    }
+    // only called for f32 and f64, for which every bit pattern is a value, so only the
+    // alignment can rule this out
+    let (before, data, after) = unsafe { bytes.align_to::<T>() };
+    (before.is_empty() && after.is_empty()).then_some(data)
+}
+
 impl Write for Vector {
     fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
         let payload = self.to_json();
         println!("payload: {}", payload);
         let bytes = payload.into_bytes();
-        const BLOCK_SIZE: usize = 4096;
-        let padded_len = ((bytes.len() + BLOCK_SIZE - 1) / BLOCK_SIZE) * BLOCK_SIZE;
+        let padded_len = bytes.len().div_ceil(direct::BLOCK_SIZE) * direct::BLOCK_SIZE;
         println!("padding: {}", padded_len);
 
         let mut padded_bytes = vec![0u8; padded_len];
@@ -68,8 +378,9 @@ impl Write for Vector {
         // simulate network call for testing
         std::thread::sleep(Duration::from_millis(100));
 
-        let mut file = Vector::create_direct_io_file(&file_path)?;
+        let mut file = DirectWriter::create(std::path::Path::new(&file_path))?;
         file.write_all(&padded_bytes)?;
+        file.finish()?;
 
         Ok(padded_bytes.len())
     }
@@ -0,0 +1,42 @@
+// What `LSMTree::verify` and `LSMTree::repair` found, one report per live table
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VerifyReport {
+    pub tables: Vec<TableReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TableReport {
+    pub file_number: u64,
+    pub file_size: u64,
+    pub entries: usize,
+    // empty for a table that passed
+    pub errors: Vec<String>,
+    // set by `repair`: the table the readable entries were salvaged into, and how many
+    // entries it couldn't read
+    pub repaired_as: Option<u64>,
+    pub lost_entries: usize,
+}
+
+impl VerifyReport {
+    pub fn passed(&self) -> bool {
+        self.tables.iter().all(|table| table.errors.is_empty())
+    }
+
+    pub fn damaged(&self) -> impl Iterator<Item = &TableReport> {
+        self.tables.iter().filter(|table| !table.errors.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passed() {
+        let mut report = VerifyReport { tables: vec![TableReport { file_number: 1, ..TableReport::default() }] };
+        assert!(report.passed());
+        report.tables.push(TableReport { file_number: 2, errors: vec!["bad".to_string()], ..TableReport::default() });
+        assert!(!report.passed());
+        assert_eq!(report.damaged().map(|t| t.file_number).collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,295 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use crate::db::checksum;
+
+// When writes and new tables are fsynced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    // a write returns once its WAL record is synced, alone or with others inside
+    // `Options::commit_window_micros`
+    #[default]
+    Always,
+    // the WAL is synced when the last sync is more than this many milliseconds old, by the
+    // next write or else by a background timer, so a crash loses at most about that much
+    // of the writes. Without worker threads (`Executor::Manual`) there is no timer, and the
+    // writes before a quiet spell stay unsynced until the next write, flush or close.
+    EveryNMillis(u64),
+    // nothing is synced and the OS writes data back when it likes. A crash can lose recent
+    // writes and tables flushed shortly before it, so only for data that can be rebuilt.
+    Never,
+}
+
+// What opening a tree does with the logs after one whose tail was torn or corrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalRecovery {
+    // the tree comes back as of the last good record: later logs hold writes made after
+    // ones that were lost, so they are dropped rather than replayed over the gap
+    #[default]
+    PointInTime,
+    // every log is replayed, each up to its own first bad frame, keeping writes after
+    // lost ones
+    TolerateCorruption,
+}
+
+// the second bit from the top of a frame's length, set on every frame, marks the record
+// type and checksum after the length. A header without it is damaged. The top bit is
+// unused.
+const CHECKSUMMED: u32 = 1 << 30;
+const LENGTH: u32 = CHECKSUMMED - 1;
+
+// What a record holds: a batch of one put, delete or range delete, or any other batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordType {
+    Put = 1,
+    Delete = 2,
+    RangeDelete = 3,
+    BatchCommit = 4,
+}
+
+impl RecordType {
+    fn from_u8(tag: u8) -> Option<RecordType> {
+        match tag {
+            1 => Some(RecordType::Put),
+            2 => Some(RecordType::Delete),
+            3 => Some(RecordType::RangeDelete),
+            4 => Some(RecordType::BatchCommit),
+            _ => None,
+        }
+    }
+}
+
+// Append-only log of length-prefixed records, one per committed write batch. A frame is
+// the length (u32, with the flags above), the record type (u8), a CRC-32 of the type and
+// record, then the record, so a frame torn by a crash is found on replay instead of being
+// decoded.
+pub(crate) struct Wal {
+    number: u64,
+    file: File,
+}
+
+impl Wal {
+    pub(crate) fn create(directory: &Path, number: u64) -> io::Result<Wal> {
+        let path = directory.join(wal_file_name(number));
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(Wal { number, file })
+    }
+
+    // Reads the records up to the first torn or corrupt frame and truncates the log there so
+    // appends continue cleanly, with the number of bytes dropped
+    pub(crate) fn replay(directory: &Path, number: u64) -> io::Result<(Wal, Vec<Vec<u8>>, usize)> {
+        let path = directory.join(wal_file_name(number));
+        let mut file = OpenOptions::new().read(true).append(true).open(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let (records, valid_len) = split_records(&contents);
+        let wal = Wal { number, file };
+        if valid_len < contents.len() {
+            wal.file.set_len(valid_len as u64)?;
+        }
+
+        Ok((wal, records, contents.len() - valid_len))
+    }
+
+    // Like `replay` without touching the file, which a live writer may still be appending to
+    pub(crate) fn read(directory: &Path, number: u64) -> io::Result<(Wal, Vec<Vec<u8>>, usize)> {
+        let mut file = File::open(directory.join(wal_file_name(number)))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let (records, valid_len) = split_records(&contents);
+        Ok((Wal { number, file }, records, contents.len() - valid_len))
+    }
+
+    pub(crate) fn number(&self) -> u64 {
+        self.number
+    }
+
+    // The whole frame goes out in one write and is synced before returning. Returns the
+    // frame's size in bytes.
+    #[cfg(test)]
+    pub(crate) fn append(&mut self, kind: RecordType, record: &[u8]) -> io::Result<usize> {
+        let appended = self.append_unsynced(kind, record)?;
+        self.file.sync_data()?;
+        Ok(appended)
+    }
+
+    // Like `append`, leaving the sync to a later `sync` or `sync_handle`
+    pub(crate) fn append_unsynced(&mut self, kind: RecordType, record: &[u8]) -> io::Result<usize> {
+        let frame = self.frame(kind, record)?;
+        self.file.write_all(&frame)?;
+        Ok(frame.len())
+    }
+
+    fn frame(&self, kind: RecordType, record: &[u8]) -> io::Result<Vec<u8>> {
+        if record.len() as u64 > LENGTH as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} byte write batch is too large for a WAL record", record.len())));
+        }
+        let mut frame = Vec::with_capacity(9 + record.len());
+        frame.write_u32::<LittleEndian>(record.len() as u32 | CHECKSUMMED)?;
+        frame.write_u8(kind as u8)?;
+        frame.write_u32::<LittleEndian>(checksum::extend(checksum::crc32(&[kind as u8]), record))?;
+        frame.extend_from_slice(record);
+        Ok(frame)
+    }
+
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    // A second handle on the log, so it can be synced without holding the writer lock
+    pub(crate) fn sync_handle(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+}
+
+// The records of the frames before the first torn or corrupt one, and the length of the
+// log they take up. Everything from a bad frame on is dropped, as what follows it can't be
+// told apart from garbage; zeroes past the last frame, as from a file extended by a crash
+// before its data was written, end the log the same way, as does a header without
+// `CHECKSUMMED`.
+fn split_records(contents: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos + 9 <= contents.len() && contents[pos..].iter().any(|&byte| byte != 0) {
+        let header = u32::from_le_bytes(contents[pos..pos + 4].try_into().unwrap());
+        if header & !LENGTH != CHECKSUMMED {
+            break;
+        }
+        let start = pos + 9;
+        let len = (header & LENGTH) as usize;
+        let Some(record) = contents.get(start..start + len) else {
+            break;
+        };
+        let kind = contents[pos + 4];
+        let crc = u32::from_le_bytes(contents[pos + 5..pos + 9].try_into().unwrap());
+        if RecordType::from_u8(kind).is_none() || checksum::extend(checksum::crc32(&[kind]), record) != crc {
+            break;
+        }
+        records.push(record.to_vec());
+        pos = start + len;
+    }
+    (records, pos)
+}
+
+pub(crate) fn wal_file_name(number: u64) -> String {
+    format!("wal_{}.log", number)
+}
+
+pub(crate) fn parse_wal_file_name(name: &str) -> Option<u64> {
+    name.strip_prefix("wal_")?.strip_suffix(".log")?.parse().ok()
+}
+
+// WAL file numbers found in the directory, ascending
+pub(crate) fn list_wals(directory: &Path) -> io::Result<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        if let Some(number) = parse_wal_file_name(&entry?.file_name().to_string_lossy()) {
+            numbers.push(number);
+        }
+    }
+    numbers.sort();
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_util::empty_test_dir;
+
+    #[test]
+    fn test_append_replay() {
+        let path = empty_test_dir("wal_append_replay");
+        let mut wal = Wal::create(&path, 3).unwrap();
+        wal.append(RecordType::BatchCommit, b"first").unwrap();
+        wal.append(RecordType::BatchCommit, b"second").unwrap();
+        drop(wal);
+
+        let (_, records, _) = Wal::replay(&path, 3).unwrap();
+        assert_eq!(records, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(list_wals(&path).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn test_replay_drops_torn_record() {
+        let path = empty_test_dir("wal_torn");
+        let mut wal = Wal::create(&path, 0).unwrap();
+        wal.append(RecordType::BatchCommit, b"complete").unwrap();
+        drop(wal);
+
+        let mut file = OpenOptions::new().append(true).open(path.join(wal_file_name(0))).unwrap();
+        file.write_all(&[20, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let (mut wal, records, _) = Wal::replay(&path, 0).unwrap();
+        assert_eq!(records, vec![b"complete".to_vec()]);
+        wal.append(RecordType::BatchCommit, b"after").unwrap();
+        drop(wal);
+
+        let (_, records, _) = Wal::replay(&path, 0).unwrap();
+        assert_eq!(records, vec![b"complete".to_vec(), b"after".to_vec()]);
+    }
+
+    #[test]
+    fn test_replay_stops_at_bad_checksum() {
+        let path = empty_test_dir("wal_bad_checksum");
+        let mut wal = Wal::create(&path, 0).unwrap();
+        wal.append(RecordType::Put, b"first").unwrap();
+        wal.append(RecordType::Delete, b"second").unwrap();
+        wal.append(RecordType::RangeDelete, b"third").unwrap();
+        drop(wal);
+
+        // a complete frame whose record was torn on the way to disk
+        let log = path.join(wal_file_name(0));
+        let mut contents = std::fs::read(&log).unwrap();
+        let len = contents.len();
+        contents[len - 2] ^= 1;
+        std::fs::write(&log, &contents).unwrap();
+        let (_, records, dropped) = Wal::read(&path, 0).unwrap();
+        assert_eq!((records, dropped), (vec![b"first".to_vec(), b"second".to_vec()], 14));
+
+        // and the frames after a bad one go with it
+        contents[len - 2] ^= 1;
+        contents[9] = b'F';
+        std::fs::write(&log, &contents).unwrap();
+        let (mut wal, records, dropped) = Wal::replay(&path, 0).unwrap();
+        assert_eq!((records.len(), dropped), (0, len));
+        wal.append(RecordType::BatchCommit, b"after").unwrap();
+        drop(wal);
+        let (_, records, dropped) = Wal::replay(&path, 0).unwrap();
+        assert_eq!((records, dropped), (vec![b"after".to_vec()], 0));
+
+        // as do an unknown record type and zeroes past the end
+        let mut wal = Wal::create(&path, 1).unwrap();
+        wal.append(RecordType::Put, b"kept").unwrap();
+        drop(wal);
+        let mut contents = std::fs::read(path.join(wal_file_name(1))).unwrap();
+        contents.extend_from_slice(&[0; 64]);
+        std::fs::write(path.join(wal_file_name(1)), &contents).unwrap();
+        let (_, records, dropped) = Wal::read(&path, 1).unwrap();
+        assert_eq!((records, dropped), (vec![b"kept".to_vec()], 64));
+        contents[4] = 9;
+        std::fs::write(path.join(wal_file_name(1)), &contents).unwrap();
+        let (_, records, _) = Wal::read(&path, 1).unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn test_requires_checksums() {
+        let path = empty_test_dir("wal_unchecksummed");
+        let mut wal = Wal::create(&path, 2).unwrap();
+        assert_eq!(wal.append(RecordType::Put, b"first").unwrap(), 14);
+        drop(wal);
+
+        // a header without the checksum bit, as a torn one can be, ends the log
+        let mut contents = std::fs::read(path.join(wal_file_name(2))).unwrap();
+        contents.extend_from_slice(&3u32.to_le_bytes());
+        contents.extend_from_slice(b"bad");
+        std::fs::write(path.join(wal_file_name(2)), &contents).unwrap();
+        let (_, records, dropped) = Wal::read(&path, 2).unwrap();
+        assert_eq!((records, dropped), (vec![b"first".to_vec()], 7));
+        std::fs::write(path.join(wal_file_name(2)), &contents[14..]).unwrap();
+        let (_, records, dropped) = Wal::read(&path, 2).unwrap();
+        assert_eq!((records.len(), dropped), (0, 7));
+    }
+}